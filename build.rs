@@ -0,0 +1,19 @@
+//! Writes the C header of the `ffi` feature to `$OUT_DIR/librtraceroute.h`.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "ffi")]
+    {
+        use std::env;
+        use std::path::PathBuf;
+
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("librtraceroute.h");
+        cbindgen::Builder::new()
+            .with_src("src/ffi.rs")
+            .with_language(cbindgen::Language::C)
+            .with_include_guard("LIBRTRACEROUTE_H")
+            .generate()
+            .expect("could not generate the C header")
+            .write_to_file(out);
+    }
+}
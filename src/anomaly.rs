@@ -0,0 +1,148 @@
+//! Signs of a middlebox rewriting the TTLs of probes.
+use crate::{HopFound, PathAnomaly};
+use std::net::IpAddr;
+
+/// This struct watches the hops of a trace for paths no router could produce.
+///
+/// A proxy or security device rewriting TTLs makes the destination answer too
+/// early, or makes routers answer probes that should have reached it. The check
+/// takes every hop reported, and the ones a trace dropped above its end, and tells
+/// the anomalies once the trace is over.
+pub(crate) struct PathCheck {
+    destination: IpAddr,
+    /// TTL and address of the hop that ended the trace, the first answer of the
+    /// destination when the trace goes on past it.
+    terminal: Option<(u8, IpAddr)>,
+    /// TTL and address of every router that answered.
+    routers: Vec<(u8, IpAddr)>,
+}
+
+impl PathCheck {
+    /// Creates new PathCheck for a trace of `destination`.
+    pub fn new(destination: IpAddr) -> PathCheck {
+        PathCheck {
+            destination,
+            terminal: None,
+            routers: Vec::new(),
+        }
+    }
+
+    /// Takes a hop of the trace.
+    pub fn hop(&mut self, hop: &HopFound) {
+        let addr = match hop.addr {
+            Some(addr) => addr,
+            None => return,
+        };
+        if hop.completion.is_some() {
+            // Hops dropped above the end may come before it.
+            if self.terminal.is_none_or(|(ttl, _)| hop.hop_count < ttl) {
+                self.terminal = Some((hop.hop_count, addr));
+            }
+            return;
+        }
+        // The destination answers again at every TTL past it.
+        let repeated = matches!(self.terminal, Some((_, end)) if end == addr);
+        if !(hop.beyond_destination && repeated) {
+            self.routers.push((hop.hop_count, addr));
+        }
+    }
+
+    /// Returns the anomalies of the hops taken so far.
+    pub fn anomalies(&self) -> Vec<PathAnomaly> {
+        let (ttl, end) = match self.terminal {
+            Some(terminal) => terminal,
+            None => return Vec::new(),
+        };
+        let mut anomalies = Vec::new();
+        let above: Vec<(u8, IpAddr)> = self
+            .routers
+            .iter()
+            .filter(|&&(hop, addr)| hop > ttl && addr != end)
+            .copied()
+            .collect();
+        if end == self.destination && !above.is_empty() {
+            anomalies.push(PathAnomaly::DestinationBelowHops { ttl, hops: above });
+        }
+        let hop_ttls: Vec<u8> = self
+            .routers
+            .iter()
+            .filter(|&&(_, addr)| addr == end)
+            .map(|&(hop, _)| hop)
+            .collect();
+        if !hop_ttls.is_empty() {
+            anomalies.push(PathAnomaly::TerminalAlsoIntermediate {
+                addr: end,
+                ttl,
+                hop_ttls,
+            });
+        }
+        anomalies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::test_net_v4;
+
+    fn check(hops: &[HopFound]) -> Vec<PathAnomaly> {
+        let mut check = PathCheck::new(test_net_v4(100));
+        for hop in hops {
+            check.hop(hop);
+        }
+        check.anomalies()
+    }
+
+    fn router(ttl: u8, n: u8) -> HopFound {
+        HopFound::new(ttl, Some(test_net_v4(n)), 0, false, None)
+    }
+
+    fn end(ttl: u8, n: u8) -> HopFound {
+        HopFound::new(ttl, Some(test_net_v4(n)), 0, true, None)
+    }
+
+    #[test]
+    fn plain_paths_have_no_anomalies() {
+        assert!(check(&[router(1, 1), router(2, 2), end(3, 100)]).is_empty());
+        assert!(check(&[router(1, 1), HopFound::timed_out(2, 3)]).is_empty());
+        // A destination answering every TTL past it is not a router.
+        let mut again = HopFound::new(4, Some(test_net_v4(100)), 0, false, None);
+        again.beyond_destination = true;
+        let mut reached = end(3, 100);
+        reached.is_last = false;
+        assert!(check(&[router(1, 1), router(2, 2), reached, again]).is_empty());
+    }
+
+    #[test]
+    fn destination_below_routers() {
+        // The destination answers at TTL 3, routers still answer at 4 to 5.
+        let mut reached = end(3, 100);
+        reached.is_last = false;
+        let mut beyond = vec![router(4, 4), router(5, 5)];
+        for hop in &mut beyond {
+            hop.beyond_destination = true;
+        }
+        let hops = [vec![router(1, 1), router(2, 2), reached], beyond].concat();
+        assert_eq!(
+            check(&hops),
+            vec![PathAnomaly::DestinationBelowHops {
+                ttl: 3,
+                hops: vec![(4, test_net_v4(4)), (5, test_net_v4(5))],
+            }]
+        );
+        // A router ending the trace early is a filter, not the destination.
+        assert!(check(&[router(1, 1), end(2, 2), router(3, 3)]).is_empty());
+    }
+
+    #[test]
+    fn terminal_address_answering_as_router() {
+        assert_eq!(
+            check(&[router(1, 1), router(2, 100), router(3, 100), end(4, 100)]),
+            vec![PathAnomaly::TerminalAlsoIntermediate {
+                addr: test_net_v4(100),
+                ttl: 4,
+                hop_ttls: vec![2, 3],
+            }]
+        );
+    }
+}
@@ -0,0 +1,363 @@
+//! Destinations answered by different instances over the rounds of a session.
+use crate::HopFound;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// This struct is what an address of an instance belongs to, see `OriginFn`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Origin {
+    /// Reverse DNS name of the address.
+    pub name: Option<String>,
+    /// Number of the autonomous system announcing the address.
+    pub asn: Option<u32>,
+}
+
+impl Origin {
+    /// Creates new Origin.
+    pub fn new(name: Option<String>, asn: Option<u32>) -> Origin {
+        Origin { name, asn }
+    }
+}
+
+/// This struct looks up the origin of the address of an instance, once per
+/// address, e.g. from a reverse DNS resolver and an IP to AS table.
+///
+/// Options holding it compare equal only if they share the same function.
+#[derive(Clone)]
+pub struct OriginFn(Arc<dyn Fn(IpAddr) -> Origin + Send + Sync>);
+
+impl OriginFn {
+    /// Creates new OriginFn calling `f`.
+    pub fn new<F: Fn(IpAddr) -> Origin + Send + Sync + 'static>(f: F) -> OriginFn {
+        OriginFn(Arc::new(f))
+    }
+
+    /// Returns the origin of `addr`.
+    pub fn origin(&self, addr: IpAddr) -> Origin {
+        (self.0)(addr)
+    }
+}
+
+impl fmt::Debug for OriginFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OriginFn")
+    }
+}
+
+impl PartialEq for OriginFn {
+    fn eq(&self, other: &OriginFn) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// This struct tells when the instances a session ends at are taken for the
+/// sites of an anycast destination.
+///
+/// Instances sharing the prefix and, if known, the AS number are taken for the
+/// load balanced last hops of one site unless `strict` is set.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct AnycastOptions {
+    /// Takes any two instances for different sites.
+    pub strict: bool,
+    /// Prefix lengths instances of one site share.
+    pub prefix_v4: u8,
+    pub prefix_v6: u8,
+    /// Looks up the reverse DNS name and AS number of the instances. None tells
+    /// sites apart by their prefix alone.
+    pub origin: Option<OriginFn>,
+}
+
+impl Default for AnycastOptions {
+    fn default() -> AnycastOptions {
+        AnycastOptions {
+            strict: false,
+            prefix_v4: 24,
+            prefix_v6: 48,
+            origin: None,
+        }
+    }
+}
+
+/// This struct is one instance a session ended at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct AnycastInstance {
+    pub addr: IpAddr,
+    /// Reverse DNS name, if `AnycastOptions::origin` found one.
+    pub name: Option<String>,
+    /// AS number, if `AnycastOptions::origin` found one.
+    pub asn: Option<u32>,
+    /// Rounds that ended at the instance.
+    pub rounds: u64,
+    /// Number of the last round that ended at the instance, from 1.
+    pub last_round: u64,
+}
+
+/// This struct is the instances a session ended at so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct AnycastReport {
+    /// True once the session ended at instances of different sites.
+    pub anycast_suspected: bool,
+    /// Instances in the order they were first seen.
+    pub instances: Vec<AnycastInstance>,
+    /// Rounds that ended at another site than the round before.
+    pub switches: u64,
+}
+
+/// This struct is a round ending at another site than the round before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct InstanceSwitch {
+    /// Number of the round, from 1.
+    pub round: u64,
+    pub from: IpAddr,
+    pub to: IpAddr,
+}
+
+/// This struct follows the instance each round of a session to one destination
+/// ends at.
+///
+/// The instance of a round is the address that answered for the destination if
+/// it is not the destination itself, like the unicast address of an anycast
+/// node, otherwise the last hop that answered in front of the destination.
+/// Rounds that did not reach the destination are not counted.
+#[derive(Debug, Clone)]
+pub struct AnycastTracker {
+    destination: IpAddr,
+    options: AnycastOptions,
+    rounds: u64,
+    instances: Vec<AnycastInstance>,
+    /// Instance of the last round that had one.
+    last: Option<usize>,
+    switches: u64,
+}
+
+impl AnycastTracker {
+    /// Creates new AnycastTracker for rounds tracing `destination`.
+    pub fn new(destination: IpAddr, options: AnycastOptions) -> AnycastTracker {
+        AnycastTracker {
+            destination,
+            options,
+            rounds: 0,
+            instances: Vec::new(),
+            last: None,
+            switches: 0,
+        }
+    }
+
+    /// Adds the hops of one round, returns the switch if it ended at another site
+    /// than the last round that reached the destination.
+    pub fn record_round(&mut self, hops: &[HopFound]) -> Option<InstanceSwitch> {
+        self.rounds += 1;
+        let addr = self.instance_of(hops)?;
+        let index = match self.instances.iter().position(|known| known.addr == addr) {
+            Some(index) => index,
+            None => {
+                let origin = match &self.options.origin {
+                    Some(origin) => origin.origin(addr),
+                    None => Origin::default(),
+                };
+                self.instances.push(AnycastInstance {
+                    addr,
+                    name: origin.name,
+                    asn: origin.asn,
+                    rounds: 0,
+                    last_round: 0,
+                });
+                self.instances.len() - 1
+            }
+        };
+        let instance = &mut self.instances[index];
+        instance.rounds += 1;
+        instance.last_round = self.rounds;
+        let last = self.last.replace(index)?;
+        if self.same_site(&self.instances[last], &self.instances[index]) {
+            return None;
+        }
+        self.switches += 1;
+        Some(InstanceSwitch {
+            round: self.rounds,
+            from: self.instances[last].addr,
+            to: addr,
+        })
+    }
+
+    /// Returns true once the session ended at instances of different sites.
+    pub fn anycast_suspected(&self) -> bool {
+        self.instances.iter().enumerate().any(|(i, a)| {
+            self.instances[i + 1..]
+                .iter()
+                .any(|b| !self.same_site(a, b))
+        })
+    }
+
+    /// Returns the instances seen so far and the verdict on them.
+    pub fn report(&self) -> AnycastReport {
+        AnycastReport {
+            anycast_suspected: self.anycast_suspected(),
+            instances: self.instances.clone(),
+            switches: self.switches,
+        }
+    }
+
+    fn instance_of(&self, hops: &[HopFound]) -> Option<IpAddr> {
+        let end = hops.iter().position(|hop| {
+            hop.is_last && hop.addr.is_some() && hop.completion.is_some() && !hop.beyond_destination
+        })?;
+        match hops[end].addr {
+            Some(addr) if addr != self.destination => Some(addr),
+            _ => hops[..end]
+                .iter()
+                .rev()
+                .find_map(|hop| hop.addr.filter(|&addr| addr != self.destination)),
+        }
+    }
+
+    /// Returns true if `a` and `b` are taken for the last hops of one site.
+    fn same_site(&self, a: &AnycastInstance, b: &AnycastInstance) -> bool {
+        if a.addr == b.addr {
+            return true;
+        }
+        if self.options.strict {
+            return false;
+        }
+        let asn = match (a.asn, b.asn) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        asn && match (a.addr, b.addr) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                let prefix = u32::from(self.options.prefix_v4.min(32));
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                u32::from(a) & mask == u32::from(b) & mask
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                let prefix = u32::from(self.options.prefix_v6.min(128));
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                u128::from(a) & mask == u128::from(b) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompletionReason;
+
+    const DESTINATION: [u8; 4] = [198, 51, 100, 53];
+
+    /// Builds a round reaching the destination behind the hop `last`.
+    fn round(last: [u8; 4]) -> Vec<HopFound> {
+        let mut end = HopFound::new(3, Some(IpAddr::from(DESTINATION)), 1, true, None);
+        end.completion = Some(CompletionReason::Reached);
+        vec![
+            HopFound::new(1, Some(IpAddr::from([192, 0, 2, 1])), 1, false, None),
+            HopFound::new(2, Some(IpAddr::from(last)), 1, false, None),
+            end,
+        ]
+    }
+
+    fn tracker(options: AnycastOptions) -> AnycastTracker {
+        AnycastTracker::new(IpAddr::from(DESTINATION), options)
+    }
+
+    #[test]
+    fn sites_taking_turns_are_anycast() {
+        let mut tracker = tracker(AnycastOptions::default());
+        let script = [
+            [203, 0, 113, 1],
+            [203, 0, 113, 1],
+            [192, 0, 2, 200],
+            [203, 0, 113, 1],
+        ];
+        let switches: Vec<_> = script
+            .iter()
+            .filter_map(|&last| tracker.record_round(&round(last)))
+            .collect();
+        assert_eq!(
+            switches
+                .iter()
+                .map(|switch| switch.round)
+                .collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert_eq!(switches[0].to, IpAddr::from([192, 0, 2, 200]));
+        let report = tracker.report();
+        assert!(report.anycast_suspected);
+        assert_eq!(report.switches, 2);
+        let rounds: Vec<_> = report
+            .instances
+            .iter()
+            .map(|instance| (instance.addr, instance.rounds, instance.last_round))
+            .collect();
+        assert_eq!(
+            rounds,
+            vec![
+                (IpAddr::from([203, 0, 113, 1]), 3, 4),
+                (IpAddr::from([192, 0, 2, 200]), 1, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn load_balanced_last_hops_are_one_site() {
+        let origin = OriginFn::new(|_| Origin::new(None, Some(64500)));
+        let mut tracker = tracker(AnycastOptions {
+            origin: Some(origin.clone()),
+            ..AnycastOptions::default()
+        });
+        let mut strict = self::tracker(AnycastOptions {
+            strict: true,
+            origin: Some(origin),
+            ..AnycastOptions::default()
+        });
+        for &last in &[[203, 0, 113, 1], [203, 0, 113, 2], [203, 0, 113, 1]] {
+            assert_eq!(tracker.record_round(&round(last)), None);
+            strict.record_round(&round(last));
+        }
+        assert!(!tracker.anycast_suspected());
+        assert_eq!(tracker.report().instances.len(), 2);
+        assert!(strict.anycast_suspected());
+        assert_eq!(strict.report().switches, 2);
+    }
+
+    #[test]
+    fn one_prefix_of_two_systems_is_anycast() {
+        let origin = OriginFn::new(|addr| match addr {
+            IpAddr::V4(v4) if v4.octets()[3] == 1 => Origin::new(Some("a.example".into()), Some(1)),
+            _ => Origin::new(None, Some(2)),
+        });
+        let mut tracker = tracker(AnycastOptions {
+            origin: Some(origin),
+            ..AnycastOptions::default()
+        });
+        tracker.record_round(&round([203, 0, 113, 1]));
+        assert!(tracker.record_round(&round([203, 0, 113, 2])).is_some());
+        let report = tracker.report();
+        assert!(report.anycast_suspected);
+        assert_eq!(report.instances[0].name.as_deref(), Some("a.example"));
+        assert_eq!(report.instances[1].asn, Some(2));
+    }
+
+    #[test]
+    fn the_answering_address_is_the_instance() {
+        let mut tracker = tracker(AnycastOptions::default());
+        let mut hops = round([203, 0, 113, 1]);
+        hops[2].addr = Some(IpAddr::from([192, 0, 2, 77]));
+        tracker.record_round(&hops);
+        let unreached = vec![HopFound::end_of_trace(3, 1, CompletionReason::NotReached)];
+        assert_eq!(tracker.record_round(&unreached), None);
+        let report = tracker.report();
+        assert_eq!(report.instances.len(), 1);
+        assert_eq!(report.instances[0].addr, IpAddr::from([192, 0, 2, 77]));
+    }
+}
@@ -0,0 +1,1332 @@
+//! Socket abstraction used by the probing worker.
+use crate::clock::{Clock, SystemClock};
+use crate::error::TraceRouteError;
+use crate::TraceRouteProtocol;
+#[cfg(not(target_os = "linux"))]
+use pnet::packet::icmp::IcmpPacket;
+use pnet::packet::icmpv6::Icmpv6Packet;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::Packet;
+#[cfg(not(target_os = "linux"))]
+use pnet::transport::icmp_packet_iter;
+use pnet::transport::{icmpv6_packet_iter, ipv4_packet_iter};
+use pnet::transport::{TransportReceiver, TransportSender};
+#[cfg(target_os = "linux")]
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(target_os = "linux")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// This enum tells what kind of packet a backend received.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ReplyKind {
+    /// An ICMP or ICMPv6 message.
+    Icmp,
+    /// A packet of the probe's own transport protocol, like a DCCP Reset.
+    Transport,
+}
+
+/// This enum tells who timed a reply, from the most to the least precise.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum TimestampSource {
+    /// The kernel, as the reply came in, from `SO_TIMESTAMPNS`.
+    Kernel,
+    /// The backend, as it read the reply off the socket.
+    Socket,
+    /// The trace, once the backend handed the reply over.
+    Userspace,
+}
+
+/// This enum names the kinds of sockets a trace can probe over.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum BackendKind {
+    /// Raw sockets, which need root or CAP_NET_RAW.
+    Raw,
+    /// Unprivileged ICMP echo sockets, Linux only.
+    DgramIcmp,
+    /// Unprivileged UDP sockets reading ICMP errors from their error queue, Linux only.
+    UdpErrqueue,
+    /// Ethernet frames written to the device, replies read from raw sockets, see
+    /// `TraceRouteConfig::datalink`. Needs root or CAP_NET_RAW.
+    Datalink,
+}
+
+impl BackendKind {
+    /// Returns true if the sockets can send probes of `protocol`.
+    pub fn carries(self, protocol: TraceRouteProtocol) -> bool {
+        match self {
+            BackendKind::Raw | BackendKind::Datalink => true,
+            BackendKind::DgramIcmp => protocol == TraceRouteProtocol::Icmp,
+            BackendKind::UdpErrqueue => protocol == TraceRouteProtocol::Udp,
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BackendKind::Raw => "raw",
+            BackendKind::DgramIcmp => "dgram-icmp",
+            BackendKind::UdpErrqueue => "udp-errqueue",
+            BackendKind::Datalink => "datalink",
+        })
+    }
+}
+
+/// Returns true for a send error that may not happen again, like a full socket
+/// buffer or a firewall rate limiting the probes, and false for one that will.
+pub(crate) fn is_transient(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(errno) => [libc::ENOBUFS, libc::EAGAIN, libc::EINTR, libc::EPERM].contains(&errno),
+        None => matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+        ),
+    }
+}
+
+/// This trait abstracts the sockets a trace sends probes and receives replies on.
+///
+/// The worker hands over complete IP packets and expects ICMP or ICMPv6 messages,
+/// without their IP header, back together with the address that sent them.
+pub trait ProbeBackend: Send {
+    /// Sends an IP packet to the destination.
+    fn send_to(&mut self, packet: &[u8], destination: IpAddr) -> io::Result<usize>;
+
+    /// Sends IP packets due at the same time, returns how many were sent.
+    ///
+    /// The default sends them one by one and stops at the first failure, which is
+    /// only returned when nothing was sent.
+    fn send_batch(&mut self, packets: &[(&[u8], IpAddr)]) -> io::Result<usize> {
+        send_each(self, packets)
+    }
+
+    /// Waits up to `timeout` for the next ICMP message, returns `None` on timeout.
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>>;
+
+    /// Waits up to `timeout` for the next ICMP message or transport reply.
+    ///
+    /// Transport packets come without their IP header. Backends that only listen
+    /// for ICMP can rely on the default, which calls `recv_timeout`.
+    fn recv_reply(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<(ReplyKind, Vec<u8>, IpAddr)>> {
+        Ok(self
+            .recv_timeout(timeout)?
+            .map(|(message, addr)| (ReplyKind::Icmp, message, addr)))
+    }
+
+    /// Returns the options of the IPv4 header the last ICMP message came in.
+    ///
+    /// Backends that do not keep the header can rely on the default, which has none.
+    fn reply_options(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Returns the IP header the last ICMP message came in, if the backend kept it.
+    fn reply_header(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Returns when the last ICMP message was read off the socket.
+    ///
+    /// Backends reading several messages at once keep it for each, so the ones
+    /// waiting in a batch are not timed late. The default leaves it to the caller.
+    fn reply_received_at(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Returns who timed the last ICMP message, only asked when
+    /// `reply_received_at` has a time.
+    fn reply_timestamp_source(&self) -> TimestampSource {
+        TimestampSource::Socket
+    }
+
+    /// Returns the clock `recv_reply` waits by, the trace times its probes with it.
+    ///
+    /// Backends on real sockets can rely on the default, the system clock.
+    fn clock(&self) -> Arc<dyn Clock> {
+        Arc::new(SystemClock)
+    }
+
+    /// Returns the sockets replies come in on, for an event loop to wait on.
+    ///
+    /// Backends without sockets can rely on the default, they are read whenever a
+    /// deadline of their trace expires.
+    #[cfg(target_os = "linux")]
+    fn reply_fds(&self) -> Vec<libc::c_int> {
+        Vec::new()
+    }
+}
+
+/// Lends a backend to one trace attempt after another.
+impl<B: ProbeBackend + ?Sized> ProbeBackend for &mut B {
+    fn send_to(&mut self, packet: &[u8], destination: IpAddr) -> io::Result<usize> {
+        (**self).send_to(packet, destination)
+    }
+
+    fn send_batch(&mut self, packets: &[(&[u8], IpAddr)]) -> io::Result<usize> {
+        (**self).send_batch(packets)
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+        (**self).recv_timeout(timeout)
+    }
+
+    fn recv_reply(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<(ReplyKind, Vec<u8>, IpAddr)>> {
+        (**self).recv_reply(timeout)
+    }
+
+    fn reply_options(&self) -> Vec<u8> {
+        (**self).reply_options()
+    }
+
+    fn reply_header(&self) -> Option<Vec<u8>> {
+        (**self).reply_header()
+    }
+
+    fn reply_received_at(&self) -> Option<Instant> {
+        (**self).reply_received_at()
+    }
+
+    fn reply_timestamp_source(&self) -> TimestampSource {
+        (**self).reply_timestamp_source()
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        (**self).clock()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn reply_fds(&self) -> Vec<libc::c_int> {
+        (**self).reply_fds()
+    }
+}
+
+/// Probes over a backend chosen at runtime.
+impl<B: ProbeBackend + ?Sized> ProbeBackend for Box<B> {
+    fn send_to(&mut self, packet: &[u8], destination: IpAddr) -> io::Result<usize> {
+        (**self).send_to(packet, destination)
+    }
+
+    fn send_batch(&mut self, packets: &[(&[u8], IpAddr)]) -> io::Result<usize> {
+        (**self).send_batch(packets)
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+        (**self).recv_timeout(timeout)
+    }
+
+    fn recv_reply(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<(ReplyKind, Vec<u8>, IpAddr)>> {
+        (**self).recv_reply(timeout)
+    }
+
+    fn reply_options(&self) -> Vec<u8> {
+        (**self).reply_options()
+    }
+
+    fn reply_header(&self) -> Option<Vec<u8>> {
+        (**self).reply_header()
+    }
+
+    fn reply_received_at(&self) -> Option<Instant> {
+        (**self).reply_received_at()
+    }
+
+    fn reply_timestamp_source(&self) -> TimestampSource {
+        (**self).reply_timestamp_source()
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        (**self).clock()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn reply_fds(&self) -> Vec<libc::c_int> {
+        (**self).reply_fds()
+    }
+}
+
+/// Sockets waiting for the next trace, with the address they send from and
+/// their kind, None for a given backend.
+type Parked = (Box<dyn ProbeBackend>, IpAddr, Option<BackendKind>);
+
+/// This struct keeps the sockets of a `TraceRoute` open between its traces, so
+/// repeated runs do not open them again.
+///
+/// A trace borrows the sockets for as long as it runs, a trace started meanwhile
+/// opens sockets of its own. Sockets that failed are dropped instead of kept.
+#[derive(Clone, Default)]
+pub(crate) struct SocketCache(Arc<Mutex<Option<Parked>>>);
+
+impl SocketCache {
+    /// Lends the kept sockets, opened with `open` if there are none.
+    pub fn lend<F>(&self, open: F) -> Result<(Lent, IpAddr, Option<BackendKind>), TraceRouteError>
+    where
+        F: FnOnce() -> Result<Parked, TraceRouteError>,
+    {
+        let parked = self.0.lock().unwrap().take();
+        let (backend, source, kind) = match parked {
+            Some(parked) => parked,
+            None => open()?,
+        };
+        let lent = Lent {
+            backend: Some(backend),
+            source,
+            kind,
+            home: self.clone(),
+            broken: false,
+        };
+        Ok((lent, source, kind))
+    }
+
+    /// Opens sockets with `open` unless some are kept already.
+    pub fn fill<F>(&self, open: F) -> Result<(), TraceRouteError>
+    where
+        F: FnOnce() -> Result<Parked, TraceRouteError>,
+    {
+        let mut parked = self.0.lock().unwrap();
+        if parked.is_none() {
+            *parked = Some(open()?);
+        }
+        Ok(())
+    }
+}
+
+/// This struct is the sockets of a `SocketCache` lent to a trace, given back
+/// when it is dropped unless they failed for good.
+pub(crate) struct Lent {
+    backend: Option<Box<dyn ProbeBackend>>,
+    source: IpAddr,
+    kind: Option<BackendKind>,
+    home: SocketCache,
+    broken: bool,
+}
+
+impl Lent {
+    fn backend(&mut self) -> &mut Box<dyn ProbeBackend> {
+        self.backend.as_mut().unwrap()
+    }
+
+    /// Remembers whether an operation failed in a way that will not pass, like
+    /// the interface going away.
+    fn check<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        if let Err(e) = &result {
+            if !is_transient(e) {
+                debug!("dropping the sockets after: {}", e);
+                self.broken = true;
+            }
+        }
+        result
+    }
+}
+
+impl ProbeBackend for Lent {
+    fn send_to(&mut self, packet: &[u8], destination: IpAddr) -> io::Result<usize> {
+        let result = self.backend().send_to(packet, destination);
+        self.check(result)
+    }
+
+    fn send_batch(&mut self, packets: &[(&[u8], IpAddr)]) -> io::Result<usize> {
+        let result = self.backend().send_batch(packets);
+        self.check(result)
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+        let result = self.backend().recv_timeout(timeout);
+        self.check(result)
+    }
+
+    fn recv_reply(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<(ReplyKind, Vec<u8>, IpAddr)>> {
+        let result = self.backend().recv_reply(timeout);
+        self.check(result)
+    }
+
+    fn reply_options(&self) -> Vec<u8> {
+        self.backend.as_ref().unwrap().reply_options()
+    }
+
+    fn reply_header(&self) -> Option<Vec<u8>> {
+        self.backend.as_ref().unwrap().reply_header()
+    }
+
+    fn reply_received_at(&self) -> Option<Instant> {
+        self.backend.as_ref().unwrap().reply_received_at()
+    }
+
+    fn reply_timestamp_source(&self) -> TimestampSource {
+        self.backend.as_ref().unwrap().reply_timestamp_source()
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.backend.as_ref().unwrap().clock()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn reply_fds(&self) -> Vec<libc::c_int> {
+        self.backend.as_ref().unwrap().reply_fds()
+    }
+}
+
+impl Drop for Lent {
+    fn drop(&mut self) {
+        let backend = match self.backend.take() {
+            Some(backend) if !self.broken && !thread::panicking() => backend,
+            _ => return,
+        };
+        let mut parked = self.home.0.lock().unwrap_or_else(|e| e.into_inner());
+        if parked.is_none() {
+            *parked = Some((backend, self.source, self.kind));
+        }
+    }
+}
+
+/// Sends `packets` with one `send_to` each, see `ProbeBackend::send_batch`.
+fn send_each<B: ProbeBackend + ?Sized>(
+    backend: &mut B,
+    packets: &[(&[u8], IpAddr)],
+) -> io::Result<usize> {
+    for (sent, &(packet, destination)) in packets.iter().enumerate() {
+        if let Err(e) = backend.send_to(packet, destination) {
+            if sent == 0 {
+                return Err(e);
+            }
+            debug!("sent {} of {} packets: {}", sent, packets.len(), e);
+            return Ok(sent);
+        }
+    }
+    Ok(packets.len())
+}
+
+/// This struct is the default backend built on pnet transport channels.
+///
+/// IPv4 probes are sent whole over a `Layer3` channel. IPv6 has no header include
+/// option, so IPv6 probes go over a `Layer4` channel with the hop limit and traffic
+/// class taken from the probe header and applied to the socket. ICMPv6 probes can
+/// be routed to a separate echo sender when the main one carries another protocol.
+pub struct PnetBackend {
+    sender: TransportSender,
+    receiver: TransportReceiver,
+    v4: bool,
+    hop_limit: Option<u8>,
+    traffic_class: Option<u8>,
+    echo_sender: Option<TransportSender>,
+    echo_hop_limit: Option<u8>,
+    echo_traffic_class: Option<u8>,
+    /// Extension headers set on the sockets, with their next header value.
+    extension: Option<(u8, Vec<u8>)>,
+    echo_extension: Option<(u8, Vec<u8>)>,
+    transport_receiver: Option<TransportReceiver>,
+    reply_header: Option<Vec<u8>>,
+    #[cfg(target_os = "linux")]
+    batch: BatchReceiver,
+    reply_received_at: Option<Instant>,
+    reply_timestamp_source: TimestampSource,
+}
+
+impl PnetBackend {
+    /// Creates new PnetBackend from a sender and an ICMP receiver.
+    ///
+    /// On Linux the kernel is asked to time the replies as they come in, those it
+    /// does not time are timed as they are read.
+    pub fn new(sender: TransportSender, receiver: TransportReceiver, v4: bool) -> PnetBackend {
+        #[cfg(target_os = "linux")]
+        let batch = BatchReceiver::new(RECV_BATCH, receiver.buffer.len());
+        #[cfg(target_os = "linux")]
+        {
+            let on: libc::c_int = 1;
+            if let Err(e) =
+                set_socket_option(receiver.socket.fd, libc::SO_TIMESTAMPNS, &on.to_ne_bytes())
+            {
+                debug!("replies are not timed by the kernel: {}", e);
+            }
+        }
+        PnetBackend {
+            sender,
+            receiver,
+            v4,
+            hop_limit: None,
+            traffic_class: None,
+            echo_sender: None,
+            echo_hop_limit: None,
+            echo_traffic_class: None,
+            extension: None,
+            echo_extension: None,
+            transport_receiver: None,
+            reply_header: None,
+            #[cfg(target_os = "linux")]
+            batch,
+            reply_received_at: None,
+            reply_timestamp_source: TimestampSource::Socket,
+        }
+    }
+
+    /// Sends ICMPv6 probes over `sender` instead of the main sender.
+    pub fn with_echo_sender(mut self, sender: TransportSender) -> PnetBackend {
+        self.echo_sender = Some(sender);
+        self
+    }
+
+    /// Also listens on `receiver` for replies of the probe protocol itself.
+    ///
+    /// IPv4 receivers are expected to be `Layer3` channels, IPv6 ones `Layer4`.
+    pub fn with_transport_receiver(mut self, receiver: TransportReceiver) -> PnetBackend {
+        self.transport_receiver = Some(receiver);
+        self
+    }
+
+    /// Binds every socket of the backend to the network device `interface`, so
+    /// probes leave through it whatever the routing table says.
+    #[cfg(target_os = "linux")]
+    pub fn bind_to_device(&self, interface: &str) -> io::Result<()> {
+        apply_to_fds(&self.fds(), &device_name(interface), |fd, value| {
+            set_socket_option(fd, libc::SO_BINDTODEVICE, value)
+        })
+    }
+
+    /// Marks the probes of the backend with `mark` for policy routing, which needs
+    /// CAP_NET_ADMIN.
+    #[cfg(target_os = "linux")]
+    pub fn set_mark(&self, mark: u32) -> io::Result<()> {
+        apply_to_fds(&self.sender_fds(), &mark.to_ne_bytes(), |fd, value| {
+            set_socket_option(fd, libc::SO_MARK, value)
+        })
+    }
+
+    /// Returns the descriptors of the sockets the backend sends on.
+    #[cfg(target_os = "linux")]
+    fn sender_fds(&self) -> Vec<libc::c_int> {
+        let mut fds = vec![self.sender.socket.fd];
+        fds.extend(self.echo_sender.iter().map(|sender| sender.socket.fd));
+        fds
+    }
+
+    /// Returns the descriptors of all sockets of the backend.
+    #[cfg(target_os = "linux")]
+    fn fds(&self) -> Vec<libc::c_int> {
+        let mut fds = self.sender_fds();
+        fds.push(self.receiver.socket.fd);
+        fds.extend(
+            self.transport_receiver
+                .iter()
+                .map(|receiver| receiver.socket.fd),
+        );
+        fds
+    }
+
+    /// Returns true if messages read along with the last one are left.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn has_pending(&self) -> bool {
+        self.batch.has_pending()
+    }
+
+    fn recv_transport(&mut self) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+        let receiver = match &mut self.transport_receiver {
+            Some(receiver) => receiver,
+            None => return Ok(None),
+        };
+        if self.v4 {
+            let mut iter = ipv4_packet_iter(receiver);
+            Ok(iter
+                .next_with_timeout(READY_TIMEOUT)?
+                .map(|(packet, addr): (Ipv4Packet, IpAddr)| (packet.payload().to_vec(), addr)))
+        } else {
+            // Layer4 IPv6 receivers hand out the bytes as they came, the iterator
+            // type only decides what they are wrapped in.
+            let mut iter = icmpv6_packet_iter(receiver);
+            Ok(iter
+                .next_with_timeout(READY_TIMEOUT)?
+                .map(|(packet, addr): (Icmpv6Packet, IpAddr)| (packet.packet().to_vec(), addr)))
+        }
+    }
+}
+
+/// Receive timeout for sockets `poll` found readable, a zero timeout blocks forever.
+pub(crate) const READY_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// Waits until one of `fds` is readable, returns the readable ones.
+pub(crate) fn poll_readable(fds: &[libc::c_int], timeout: Duration) -> io::Result<Vec<bool>> {
+    let mut pollfds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    let res = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, millis) };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(pollfds
+        .iter()
+        .map(|pollfd| pollfd.revents & libc::POLLIN != 0)
+        .collect())
+}
+
+/// Returns the IPv4 header at the start of `buffer`.
+fn ipv4_header(buffer: &[u8]) -> Option<Vec<u8>> {
+    let header_len = usize::from(buffer.first()? & 0x0f) * 4;
+    buffer.get(..header_len.max(20)).map(<[u8]>::to_vec)
+}
+
+/// Applies an IPv6 socket option to the socket of `sender`, unless `value` is
+/// already `cached`.
+fn set_ipv6_option(
+    sender: &TransportSender,
+    cached: &mut Option<u8>,
+    option: libc::c_int,
+    value: u8,
+) -> io::Result<()> {
+    if *cached == Some(value) {
+        return Ok(());
+    }
+    let raw = libc::c_int::from(value);
+    let res = unsafe {
+        libc::setsockopt(
+            sender.socket.fd,
+            libc::IPPROTO_IPV6,
+            option,
+            &raw as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    *cached = Some(value);
+    Ok(())
+}
+
+/// Has the kernel insert the extension header `extension`, given by its next
+/// header value and bytes, into the packets of `sender`, or none. The header is
+/// set as a sticky option, unless it is already `cached`.
+fn set_ipv6_extension(
+    sender: &TransportSender,
+    cached: &mut Option<(u8, Vec<u8>)>,
+    extension: Option<(u8, &[u8])>,
+) -> io::Result<()> {
+    if cached
+        .as_ref()
+        .map(|(kind, header)| (*kind, header.as_slice()))
+        == extension
+    {
+        return Ok(());
+    }
+    let set = |kind: u8, header: &[u8]| {
+        let res = unsafe {
+            libc::setsockopt(
+                sender.socket.fd,
+                libc::IPPROTO_IPV6,
+                extension_option(kind)?,
+                header.as_ptr() as *const libc::c_void,
+                header.len() as libc::socklen_t,
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    if let Some((kind, _)) = cached.take() {
+        set(kind, &[])?;
+    }
+    if let Some((kind, header)) = extension {
+        set(kind, header)?;
+        *cached = Some((kind, header.to_vec()));
+    }
+    Ok(())
+}
+
+/// Returns the socket option setting the extension header of next header value
+/// `kind`.
+#[cfg(target_os = "linux")]
+fn extension_option(kind: u8) -> io::Result<libc::c_int> {
+    Ok(match kind {
+        0 => libc::IPV6_HOPOPTS,
+        _ => libc::IPV6_DSTOPTS,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn extension_option(_kind: u8) -> io::Result<libc::c_int> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "IPv6 extension headers are only sent on Linux",
+    ))
+}
+
+/// Returns the NUL terminated name of a network device.
+pub(crate) fn device_name(interface: &str) -> Vec<u8> {
+    let mut name = interface.as_bytes().to_vec();
+    name.push(0);
+    name
+}
+
+/// Hands `value` to `setsockopt` for every socket in `fds`, stopping at the first
+/// failure.
+pub(crate) fn apply_to_fds<F>(
+    fds: &[libc::c_int],
+    value: &[u8],
+    mut setsockopt: F,
+) -> io::Result<()>
+where
+    F: FnMut(libc::c_int, &[u8]) -> io::Result<()>,
+{
+    for &fd in fds {
+        setsockopt(fd, value)?;
+    }
+    Ok(())
+}
+
+/// Applies the socket level `option` with `value` to `fd`.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_socket_option(
+    fd: libc::c_int,
+    option: libc::c_int,
+    value: &[u8],
+) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            value.as_ptr() as *const libc::c_void,
+            value.len() as libc::socklen_t,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns the socket address of `addr` and `port`.
+#[cfg(target_os = "linux")]
+pub(crate) fn sockaddr(addr: IpAddr, port: u16) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        IpAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: port.to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe { std::ptr::write(&mut name as *mut _ as *mut libc::sockaddr_in, sin) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        IpAddr::V6(addr) => {
+            let mut sin6: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = port.to_be();
+            sin6.sin6_addr.s6_addr = addr.octets();
+            unsafe { std::ptr::write(&mut name as *mut _ as *mut libc::sockaddr_in6, sin6) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (name, len as libc::socklen_t)
+}
+
+/// Returns the address and port of a socket address.
+#[cfg(target_os = "linux")]
+pub(crate) fn sockaddr_addr(name: &libc::sockaddr_storage) -> Option<(IpAddr, u16)> {
+    match libc::c_int::from(name.ss_family) {
+        libc::AF_INET => {
+            let sin = unsafe { &*(name as *const _ as *const libc::sockaddr_in) };
+            let addr = std::net::Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+            Some((IpAddr::V4(addr), u16::from_be(sin.sin_port)))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(name as *const _ as *const libc::sockaddr_in6) };
+            let addr = std::net::Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            Some((IpAddr::V6(addr), u16::from_be(sin6.sin6_port)))
+        }
+        _ => None,
+    }
+}
+
+/// Messages read per `recvmmsg` call.
+#[cfg(target_os = "linux")]
+const RECV_BATCH: usize = 16;
+
+/// This struct hands out one at a time the packets read in batches.
+#[cfg(target_os = "linux")]
+pub(crate) struct BatchReceiver {
+    buffers: Vec<Vec<u8>>,
+    queue: VecDeque<(Vec<u8>, IpAddr, Instant, TimestampSource)>,
+}
+
+#[cfg(target_os = "linux")]
+impl BatchReceiver {
+    /// Creates new BatchReceiver reading up to `batch` packets of `size` bytes at once.
+    pub(crate) fn new(batch: usize, size: usize) -> BatchReceiver {
+        BatchReceiver {
+            buffers: vec![vec![0; size]; batch],
+            queue: VecDeque::with_capacity(batch),
+        }
+    }
+
+    /// Returns true if packets of the last batch are left.
+    pub(crate) fn has_pending(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// Returns the next packet with the time it came in, calling `read` for a new
+    /// batch once the last one is used up. `read` gets the buffers to read into
+    /// and returns the packets read, in order, with the time the kernel stamped
+    /// them if it did. The others are timed when their batch was read.
+    pub(crate) fn next<F>(
+        &mut self,
+        read: F,
+    ) -> io::Result<Option<(Vec<u8>, IpAddr, Instant, TimestampSource)>>
+    where
+        F: FnOnce(&mut [Vec<u8>]) -> io::Result<Vec<(Vec<u8>, IpAddr, Option<SystemTime>)>>,
+    {
+        if self.queue.is_empty() {
+            let packets = read(&mut self.buffers)?;
+            let (read_at, wall) = (Instant::now(), SystemTime::now());
+            self.queue.extend(
+                packets
+                    .into_iter()
+                    .map(|(packet, from, stamp)| match stamp {
+                        Some(stamp) => {
+                            let age = wall.duration_since(stamp).unwrap_or_default();
+                            let at = read_at.checked_sub(age).unwrap_or(read_at);
+                            (packet, from, at, TimestampSource::Kernel)
+                        }
+                        None => (packet, from, read_at, TimestampSource::Socket),
+                    }),
+            );
+        }
+        Ok(self.queue.pop_front())
+    }
+}
+
+/// Room for the control messages of one packet, enough for a `timespec`.
+#[cfg(target_os = "linux")]
+const CONTROL_LEN: usize = 64;
+
+/// Reads up to one packet into each of `buffers` with one `recvmmsg`, without
+/// waiting for any. Packets come with the time the kernel stamped them, if the
+/// socket has `SO_TIMESTAMPNS` on.
+#[cfg(target_os = "linux")]
+pub(crate) fn recv_many(
+    fd: libc::c_int,
+    buffers: &mut [Vec<u8>],
+) -> io::Result<Vec<(Vec<u8>, IpAddr, Option<SystemTime>)>> {
+    let mut names: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; buffers.len()];
+    // u64 words keep the control messages aligned.
+    let mut controls = vec![[0u64; CONTROL_LEN / 8]; buffers.len()];
+    let mut iovecs: Vec<libc::iovec> = buffers
+        .iter_mut()
+        .map(|buffer| libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        })
+        .collect();
+    let mut headers: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(names.iter_mut())
+        .zip(controls.iter_mut())
+        .map(|((iovec, name), control)| {
+            let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+            header.msg_hdr.msg_name = name as *mut libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_hdr.msg_namelen =
+                mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+            header.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            header.msg_hdr.msg_controllen = CONTROL_LEN as _;
+            header
+        })
+        .collect();
+    let res = unsafe {
+        libc::recvmmsg(
+            fd,
+            headers.as_mut_ptr(),
+            headers.len() as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            std::ptr::null_mut(),
+        )
+    };
+    if res == -1 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::WouldBlock {
+            return Ok(Vec::new());
+        }
+        return Err(e);
+    }
+    let read = res as usize;
+    Ok(headers[..read]
+        .iter()
+        .zip(&names)
+        .zip(buffers.iter())
+        .filter_map(|((header, name), buffer)| {
+            let len = (header.msg_len as usize).min(buffer.len());
+            let stamp = kernel_timestamp(&header.msg_hdr);
+            Some((buffer[..len].to_vec(), sockaddr_addr(name)?.0, stamp))
+        })
+        .collect())
+}
+
+/// Returns the `SCM_TIMESTAMPNS` time among the control messages of `header`.
+#[cfg(target_os = "linux")]
+fn kernel_timestamp(header: &libc::msghdr) -> Option<SystemTime> {
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(header);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPNS
+            {
+                let stamp =
+                    std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec);
+                let since = Duration::new(stamp.tv_sec as u64, stamp.tv_nsec as u32);
+                return Some(UNIX_EPOCH + since);
+            }
+            cmsg = libc::CMSG_NXTHDR(header, cmsg);
+        }
+    }
+    None
+}
+
+/// Sends `packets` to `port` of their destinations with one `sendmmsg`, returns how
+/// many the kernel took.
+#[cfg(target_os = "linux")]
+pub(crate) fn send_many(
+    fd: libc::c_int,
+    packets: &[(&[u8], IpAddr)],
+    port: u16,
+) -> io::Result<usize> {
+    let names: Vec<_> = packets
+        .iter()
+        .map(|&(_, destination)| sockaddr(destination, port))
+        .collect();
+    let mut iovecs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|&(packet, _)| libc::iovec {
+            iov_base: packet.as_ptr() as *mut libc::c_void,
+            iov_len: packet.len(),
+        })
+        .collect();
+    let mut headers: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(&names)
+        .map(|(iovec, (name, name_len))| {
+            let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+            header.msg_hdr.msg_name = name as *const libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = *name_len;
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+            header
+        })
+        .collect();
+    let res = unsafe { libc::sendmmsg(fd, headers.as_mut_ptr(), headers.len() as libc::c_uint, 0) };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(res as usize)
+}
+
+/// Payload of an IPv6 probe, handed to a `Layer4` sender.
+struct RawPayload<'p>(&'p [u8]);
+
+impl<'p> Packet for RawPayload<'p> {
+    fn packet(&self) -> &[u8] {
+        self.0
+    }
+
+    fn payload(&self) -> &[u8] {
+        &[]
+    }
+}
+
+impl ProbeBackend for PnetBackend {
+    fn send_to(&mut self, packet: &[u8], destination: IpAddr) -> io::Result<usize> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "truncated IP packet");
+        if self.v4 {
+            let packet = Ipv4Packet::new(packet).ok_or_else(invalid)?;
+            self.sender.send_to(packet, destination)
+        } else {
+            let packet = Ipv6Packet::new(packet).ok_or_else(invalid)?;
+            // The kernel writes the IPv6 header, an extension header goes in as a
+            // socket option.
+            let (extension, protocol, payload) = match packet.get_next_header().0 {
+                kind @ (0 | 60) => {
+                    let payload = packet.payload();
+                    let len = payload
+                        .get(1)
+                        .map(|words| (usize::from(*words) + 1) * 8)
+                        .filter(|&len| len <= payload.len())
+                        .ok_or_else(invalid)?;
+                    (Some((kind, &payload[..len])), payload[0], &payload[len..])
+                }
+                44 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "raw IPv6 sockets cannot send fragments",
+                    ))
+                }
+                protocol => (None, protocol, packet.payload()),
+            };
+            let (sender, hop_limit, traffic_class, cached_extension) = match &mut self.echo_sender {
+                Some(echo) if protocol == IpNextHeaderProtocols::Icmpv6.0 => (
+                    echo,
+                    &mut self.echo_hop_limit,
+                    &mut self.echo_traffic_class,
+                    &mut self.echo_extension,
+                ),
+                _ => (
+                    &mut self.sender,
+                    &mut self.hop_limit,
+                    &mut self.traffic_class,
+                    &mut self.extension,
+                ),
+            };
+            let hops = packet.get_hop_limit();
+            set_ipv6_option(sender, hop_limit, libc::IPV6_UNICAST_HOPS, hops)?;
+            let class = packet.get_traffic_class();
+            set_ipv6_option(sender, traffic_class, libc::IPV6_TCLASS, class)?;
+            set_ipv6_extension(sender, cached_extension, extension)?;
+            sender.send_to(RawPayload(payload), destination)
+        }
+    }
+
+    /// IPv4 packets carry their own TTL, so they go out with a single `sendmmsg`.
+    /// IPv6 ones set socket options for each packet and are sent one by one.
+    #[cfg(target_os = "linux")]
+    fn send_batch(&mut self, packets: &[(&[u8], IpAddr)]) -> io::Result<usize> {
+        let whole = packets
+            .iter()
+            .all(|&(packet, _)| Ipv4Packet::new(packet).is_some());
+        if self.v4 && whole {
+            send_many(self.sender.socket.fd, packets, 0)
+        } else {
+            send_each(self, packets)
+        }
+    }
+
+    /// Replies are read with `recvmmsg`, up to `RECV_BATCH` of them per call.
+    #[cfg(target_os = "linux")]
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+        let deadline = Instant::now() + timeout;
+        let fd = self.receiver.socket.fd;
+        loop {
+            if !self.batch.has_pending() {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if !poll_readable(&[fd], left)?[0] {
+                    return Ok(None);
+                }
+            }
+            if let Some((packet, from, received_at, source)) =
+                self.batch.next(|buffers| recv_many(fd, buffers))?
+            {
+                self.reply_received_at = Some(received_at);
+                self.reply_timestamp_source = source;
+                if !self.v4 {
+                    return Ok(Some((packet, from)));
+                }
+                // Raw IPv4 sockets hand over the IP header, ICMPv6 ones do not.
+                if let Some(header) = ipv4_header(&packet) {
+                    let message = packet[header.len()..].to_vec();
+                    self.reply_header = Some(header);
+                    return Ok(Some((message, from)));
+                }
+            } else if Instant::now() >= deadline {
+                return Ok(None);
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+        if self.v4 {
+            let mut iter = icmp_packet_iter(&mut self.receiver);
+            let reply = iter
+                .next_with_timeout(timeout)?
+                .map(|(packet, addr): (IcmpPacket, IpAddr)| (packet.packet().to_vec(), addr));
+            if reply.is_some() {
+                // The iterator skips the IP header, it is still at the start of the buffer.
+                self.reply_header = ipv4_header(&self.receiver.buffer);
+            }
+            Ok(reply)
+        } else {
+            let mut iter = icmpv6_packet_iter(&mut self.receiver);
+            Ok(iter
+                .next_with_timeout(timeout)?
+                .map(|(packet, addr): (Icmpv6Packet, IpAddr)| (packet.packet().to_vec(), addr)))
+        }
+    }
+
+    fn recv_reply(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<(ReplyKind, Vec<u8>, IpAddr)>> {
+        let transport_fd = match &self.transport_receiver {
+            Some(receiver) => receiver.socket.fd,
+            None => {
+                return Ok(self
+                    .recv_timeout(timeout)?
+                    .map(|(message, addr)| (ReplyKind::Icmp, message, addr)))
+            }
+        };
+        #[cfg(target_os = "linux")]
+        {
+            if self.batch.has_pending() {
+                return Ok(self
+                    .recv_timeout(READY_TIMEOUT)?
+                    .map(|(message, addr)| (ReplyKind::Icmp, message, addr)));
+            }
+        }
+        let ready = poll_readable(&[self.receiver.socket.fd, transport_fd], timeout)?;
+        if ready[0] {
+            if let Some((message, addr)) = self.recv_timeout(READY_TIMEOUT)? {
+                return Ok(Some((ReplyKind::Icmp, message, addr)));
+            }
+        }
+        if ready[1] {
+            if let Some((packet, addr)) = self.recv_transport()? {
+                return Ok(Some((ReplyKind::Transport, packet, addr)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn reply_options(&self) -> Vec<u8> {
+        match &self.reply_header {
+            Some(header) => header[20..].to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    fn reply_header(&self) -> Option<Vec<u8>> {
+        self.reply_header.clone()
+    }
+
+    fn reply_received_at(&self) -> Option<Instant> {
+        self.reply_received_at
+    }
+
+    fn reply_timestamp_source(&self) -> TimestampSource {
+        self.reply_timestamp_source
+    }
+
+    #[cfg(target_os = "linux")]
+    fn reply_fds(&self) -> Vec<libc::c_int> {
+        let mut fds = vec![self.receiver.socket.fd];
+        fds.extend(
+            self.transport_receiver
+                .iter()
+                .map(|receiver| receiver.socket.fd),
+        );
+        fds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    /// Backend whose sends fail from the `fail_at`th on.
+    struct Failing {
+        sent: usize,
+        fail_at: usize,
+    }
+
+    impl ProbeBackend for Failing {
+        fn send_to(&mut self, packet: &[u8], _destination: IpAddr) -> io::Result<usize> {
+            if self.sent == self.fail_at {
+                return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+            }
+            self.sent += 1;
+            Ok(packet.len())
+        }
+
+        fn recv_timeout(&mut self, _timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn batches_stop_at_the_first_failure() {
+        let to = IpAddr::from([192, 0, 2, 1]);
+        let batch = [(&[1u8][..], to), (&[2u8][..], to), (&[3u8][..], to)];
+        let mut backend = Failing {
+            sent: 0,
+            fail_at: 2,
+        };
+        assert_eq!(backend.send_batch(&batch).unwrap(), 2);
+        let mut backend = Failing {
+            sent: 0,
+            fail_at: 0,
+        };
+        assert!(backend.send_batch(&batch).is_err());
+        assert_eq!(backend.send_batch(&[]).unwrap(), 0);
+    }
+
+    #[test]
+    fn batches_are_drained_one_by_one() {
+        let mut batch = BatchReceiver::new(4, 64);
+        let from = IpAddr::from([192, 0, 2, 1]);
+        let mut reads = 0;
+        let mut read = |buffers: &mut [Vec<u8>]| {
+            reads += 1;
+            assert_eq!((buffers.len(), buffers[0].len()), (4, 64));
+            Ok(match reads {
+                1 => vec![
+                    (vec![1], from, None),
+                    (vec![2], from, None),
+                    (vec![3], from, None),
+                ],
+                _ => Vec::new(),
+            })
+        };
+        let mut drained = Vec::new();
+        while let Some((packet, _, received_at, source)) = batch.next(&mut read).unwrap() {
+            assert_eq!(source, TimestampSource::Socket);
+            drained.push((packet[0], received_at));
+        }
+        assert_eq!(reads, 2);
+        let packets: Vec<u8> = drained.iter().map(|&(packet, _)| packet).collect();
+        assert_eq!(packets, vec![1, 2, 3]);
+        assert!(drained.iter().all(|&(_, at)| at == drained[0].1));
+        assert!(!batch.has_pending());
+    }
+
+    #[test]
+    fn kernel_stamps_date_packets_back() {
+        let mut batch = BatchReceiver::new(4, 64);
+        let from = IpAddr::from([192, 0, 2, 1]);
+        let stamp = SystemTime::now() - Duration::from_millis(50);
+        let read = |_: &mut [Vec<u8>]| Ok(vec![(vec![1], from, Some(stamp))]);
+        let (_, _, received_at, source) = batch.next(read).unwrap().unwrap();
+        assert_eq!(source, TimestampSource::Kernel);
+        assert!(received_at.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn batches_are_read_in_one_call() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut buffers = vec![vec![0u8; 16]; 4];
+        assert!(recv_many(receiver.as_raw_fd(), &mut buffers)
+            .unwrap()
+            .is_empty());
+        for datagram in &[&b"one"[..], b"two", b"three"] {
+            sender
+                .send_to(datagram, receiver.local_addr().unwrap())
+                .unwrap();
+        }
+        let read = recv_many(receiver.as_raw_fd(), &mut buffers).unwrap();
+        let datagrams: Vec<&[u8]> = read.iter().map(|(packet, _, _)| &packet[..]).collect();
+        assert_eq!(datagrams, vec![&b"one"[..], b"two", b"three"]);
+        assert!(read
+            .iter()
+            .all(|&(_, from, _)| from == IpAddr::from([127, 0, 0, 1])));
+        assert!(read.iter().all(|&(_, _, stamp)| stamp.is_none()));
+    }
+
+    #[test]
+    fn batches_carry_kernel_stamps() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let on: libc::c_int = 1;
+        set_socket_option(
+            receiver.as_raw_fd(),
+            libc::SO_TIMESTAMPNS,
+            &on.to_ne_bytes(),
+        )
+        .unwrap();
+        let before = SystemTime::now();
+        sender
+            .send_to(b"one", receiver.local_addr().unwrap())
+            .unwrap();
+        let mut buffers = vec![vec![0u8; 16]; 4];
+        let read = recv_many(receiver.as_raw_fd(), &mut buffers).unwrap();
+        let stamp = read[0].2.unwrap();
+        assert!(stamp >= before && stamp <= SystemTime::now());
+    }
+
+    #[test]
+    fn batches_go_out_in_one_call() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let port = receiver.local_addr().unwrap().port();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = IpAddr::from([127, 0, 0, 1]);
+        let batch = [(&b"one"[..], to), (&b"two"[..], to), (&b"three"[..], to)];
+        assert_eq!(send_many(sender.as_raw_fd(), &batch, port).unwrap(), 3);
+        let mut buffer = [0u8; 16];
+        for &(datagram, _) in &batch {
+            let len = receiver.recv(&mut buffer).unwrap();
+            assert_eq!(&buffer[..len], datagram);
+        }
+    }
+
+    #[test]
+    fn every_socket_gets_the_device_name() {
+        let mut calls = Vec::new();
+        apply_to_fds(&[3, 4, 7], &device_name("eth1"), |fd, value| {
+            calls.push((fd, value.to_vec()));
+            Ok(())
+        })
+        .unwrap();
+        let name = b"eth1\0".to_vec();
+        assert_eq!(calls, vec![(3, name.clone()), (4, name.clone()), (7, name)]);
+    }
+
+    #[test]
+    fn marks_are_passed_as_native_integers() {
+        let mut calls = Vec::new();
+        apply_to_fds(&[5], &0x2au32.to_ne_bytes(), |fd, value| {
+            calls.push((
+                fd,
+                u32::from_ne_bytes([value[0], value[1], value[2], value[3]]),
+            ));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(calls, vec![(5, 0x2a)]);
+    }
+
+    #[test]
+    fn setting_stops_at_the_first_failure() {
+        let mut calls = Vec::new();
+        let err = apply_to_fds(&[3, 4], &device_name("vrf-blue"), |fd, _| {
+            calls.push(fd);
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(calls, vec![3]);
+    }
+}
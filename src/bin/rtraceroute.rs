@@ -0,0 +1,463 @@
+//! Command line route tracer built on librtraceroute, with the `bin` feature.
+use ansi_term::Colour;
+use clap::{ArgGroup, Parser};
+use librtraceroute::{HopFound, InterfaceSel, TraceRoute, TraceRouteConfig, TraceRouteProtocol};
+use std::ffi::CStr;
+use std::fmt::Write;
+use std::mem;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::os::raw::c_char;
+use std::process;
+use std::time::Duration;
+
+/// The trace reached the destination.
+const EXIT_REACHED: i32 = 0;
+/// The trace ended without reaching the destination.
+const EXIT_NOT_REACHED: i32 = 1;
+/// The arguments were invalid.
+const EXIT_USAGE: i32 = 2;
+/// The trace could not be started.
+const EXIT_FAILED: i32 = 3;
+
+/// Print the route packets take to a network host.
+#[derive(Parser, Debug)]
+#[command(name = "rtraceroute", version)]
+#[command(group(ArgGroup::new("protocol").args(["icmp", "udp", "tcp", "dccp", "raw"])))]
+struct Options {
+    /// Use ICMP echo probes.
+    #[arg(short = 'I', long)]
+    icmp: bool,
+    /// Use UDP probes, the default.
+    #[arg(short = 'U', long)]
+    udp: bool,
+    /// Use TCP SYN probes, not supported by the library.
+    #[arg(short = 'T', long)]
+    tcp: bool,
+    /// Use DCCP Request probes.
+    #[arg(short = 'D', long)]
+    dccp: bool,
+    /// Use raw probes of the given IP protocol number.
+    #[arg(short = 'P', long, value_name = "PROTO")]
+    raw: Option<u8>,
+    /// Use IPv4 only.
+    #[arg(short = '4', conflicts_with = "ipv6")]
+    ipv4: bool,
+    /// Use IPv6 only.
+    #[arg(short = '6')]
+    ipv6: bool,
+    /// TTL of the first probe.
+    #[arg(short = 'f', long = "first", value_name = "TTL", default_value_t = 1)]
+    first_ttl: u8,
+    /// TTL of the last probe.
+    #[arg(
+        short = 'm',
+        long = "max-hops",
+        value_name = "TTL",
+        default_value_t = 30
+    )]
+    max_ttl: u8,
+    /// Probes per hop before it is given up.
+    #[arg(short = 'q', long = "queries", value_name = "N", default_value_t = 3)]
+    queries: u16,
+    /// Seconds to wait for a reply, `inf` waits until it comes.
+    #[arg(
+        short = 'w',
+        long = "wait",
+        value_name = "SECONDS",
+        default_value_t = 1.0
+    )]
+    wait: f64,
+    /// Network device to send the probes from.
+    #[arg(short = 'i', long, value_name = "DEVICE")]
+    interface: Option<String>,
+    /// Base destination port of UDP and DCCP probes.
+    #[arg(short = 'p', long, default_value_t = 33434)]
+    port: u16,
+    /// Print addresses without looking up their names.
+    #[arg(short = 'n')]
+    numeric: bool,
+    /// Print the trace as one JSON object.
+    #[arg(long)]
+    json: bool,
+    /// Host name or address to trace.
+    host: String,
+    /// Probe size in bytes.
+    #[arg(value_name = "PACKETLEN")]
+    size: Option<usize>,
+}
+
+impl Options {
+    fn protocol(&self) -> Result<TraceRouteProtocol, String> {
+        if self.tcp {
+            return Err("TCP probes are not supported, use -I, -U, -D or -P".to_string());
+        }
+        Ok(match (self.icmp, self.dccp, self.raw) {
+            (true, _, _) => TraceRouteProtocol::Icmp,
+            (_, true, _) => TraceRouteProtocol::Dccp,
+            (_, _, Some(number)) => TraceRouteProtocol::Raw(number),
+            _ => TraceRouteProtocol::Udp,
+        })
+    }
+
+    fn config(&self) -> Result<TraceRouteConfig, String> {
+        if self.wait.is_nan() || self.wait <= 0.0 {
+            return Err(format!("bad wait time {}", self.wait));
+        }
+        let mut config = TraceRouteConfig {
+            protocol: self.protocol()?,
+            begin_ttl: self.first_ttl,
+            max_ttl: self.max_ttl,
+            max_tries: self.queries,
+            timeout: match self.wait {
+                wait if wait.is_finite() => {
+                    Some(Duration::from_millis((wait * 1000.0).ceil() as u64))
+                }
+                _ => None,
+            },
+            port: self.port,
+            interface: self.interface.clone().map(InterfaceSel::Name),
+            ..TraceRouteConfig::default()
+        };
+        if let Some(size) = self.size {
+            config.size = size;
+        }
+        Ok(config)
+    }
+
+    /// Returns the address of the host, in the family asked for.
+    fn destination(&self) -> Result<IpAddr, String> {
+        let wanted =
+            |addr: &IpAddr| (!self.ipv4 || addr.is_ipv4()) && (!self.ipv6 || addr.is_ipv6());
+        let addrs: Vec<IpAddr> = match self.host.parse::<IpAddr>() {
+            Ok(addr) => vec![addr],
+            Err(_) => (self.host.as_str(), 0)
+                .to_socket_addrs()
+                .map_err(|e| format!("{}: {}", self.host, e))?
+                .map(|addr| addr.ip())
+                .collect(),
+        };
+        addrs
+            .into_iter()
+            .find(wanted)
+            .ok_or_else(|| format!("{}: no address of the requested family", self.host))
+    }
+}
+
+/// Returns the name `addr` resolves back to.
+fn reverse_name(addr: IpAddr) -> Option<String> {
+    let socket = SocketAddr::new(addr, 0);
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match socket {
+        SocketAddr::V4(v4) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: 0,
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(v4.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe { *(&mut storage as *mut _ as *mut libc::sockaddr_in) = sin };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(v6) => {
+            let mut sin6: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_addr.s6_addr = v6.ip().octets();
+            unsafe { *(&mut storage as *mut _ as *mut libc::sockaddr_in6) = sin6 };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    let mut host = [0 as c_char; libc::NI_MAXHOST as usize];
+    let res = unsafe {
+        libc::getnameinfo(
+            &storage as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if res != 0 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(host.as_ptr()) };
+    name.to_str().ok().map(str::to_string)
+}
+
+/// Formats a hop the way traceroute prints it, coloured for terminals.
+fn render(hop: &HopFound, name: Option<&str>, colour: bool) -> String {
+    let paint = |colour_of: Colour, text: String| {
+        if colour {
+            colour_of.paint(text).to_string()
+        } else {
+            text
+        }
+    };
+    let mut line = format!("{:>2}  ", hop.hop_count);
+    match (hop.addr, hop.local_hops) {
+        (_, Some(n)) => line += &paint(Colour::Blue, format!("local network ({} hops)", n)),
+        (Some(addr), None) => {
+            let text = match name {
+                Some(name) => format!("{} ({})", name, addr),
+                None => addr.to_string(),
+            };
+            let colour_of = if hop.is_last {
+                Colour::Green
+            } else {
+                Colour::White
+            };
+            line += &paint(colour_of, text);
+        }
+        (None, None) => line += &paint(Colour::Yellow, "*".to_string()),
+    }
+    if let Some(time) = hop.time {
+        let _ = write!(line, "  {:.3} ms", time.as_secs_f64() * 1000.0);
+    }
+    line
+}
+
+/// Escapes a string for JSON.
+fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Formats a hop as a JSON object.
+fn json_hop(hop: &HopFound, name: Option<&str>) -> String {
+    let or_null = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+    format!(
+        "{{\"hop\":{},\"addr\":{},\"name\":{},\"rtt_ms\":{},\"tries\":{},\"is_last\":{}}}",
+        hop.hop_count,
+        or_null(hop.addr.map(|addr| json_string(&addr.to_string()))),
+        or_null(name.map(json_string)),
+        or_null(
+            hop.time
+                .map(|time| format!("{:.3}", time.as_secs_f64() * 1000.0))
+        ),
+        hop.tries,
+        hop.is_last
+    )
+}
+
+/// Formats a whole trace as a JSON object.
+fn json_trace(host: &str, destination: IpAddr, hops: &[String], reached: bool) -> String {
+    format!(
+        "{{\"host\":{},\"destination\":{},\"reached\":{},\"hops\":[{}]}}",
+        json_string(host),
+        json_string(&destination.to_string()),
+        reached,
+        hops.join(",")
+    )
+}
+
+/// Returns the exit code of a trace that ended with `last`.
+fn exit_code(last: Option<&HopFound>) -> i32 {
+    match last {
+        Some(hop) if hop.addr.is_some() => EXIT_REACHED,
+        _ => EXIT_NOT_REACHED,
+    }
+}
+
+fn run(options: &Options) -> i32 {
+    let (destination, config) = match options
+        .destination()
+        .and_then(|destination| options.config().map(|config| (destination, config)))
+    {
+        Ok(found) => found,
+        Err(message) => {
+            eprintln!("rtraceroute: {}", message);
+            return EXIT_USAGE;
+        }
+    };
+    let (trace_route, receiver) = match TraceRoute::with_config(destination, config) {
+        Ok(created) => created,
+        Err(e) => {
+            eprintln!("rtraceroute: {}", e);
+            return EXIT_USAGE;
+        }
+    };
+    if let Err(e) = trace_route.run_trace_route() {
+        eprintln!("rtraceroute: {}", e);
+        return EXIT_FAILED;
+    }
+    drop(trace_route);
+    let colour = !options.json && unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+    if !options.json {
+        println!(
+            "traceroute to {} ({}), {} hops max",
+            options.host, destination, options.max_ttl
+        );
+    }
+    let mut json = Vec::new();
+    let mut last = None;
+    for hop in receiver.iter() {
+        // The record ending a trace that ran out of TTLs is no hop to print.
+        if !hop.is_end_of_trace() {
+            let name = match hop.addr {
+                Some(addr) if !options.numeric => reverse_name(addr),
+                _ => None,
+            };
+            if options.json {
+                json.push(json_hop(&hop, name.as_deref()));
+            } else {
+                println!("{}", render(&hop, name.as_deref(), colour));
+            }
+        }
+        if hop.is_last {
+            last = Some(hop);
+            break;
+        }
+    }
+    let code = exit_code(last.as_ref());
+    if options.json {
+        println!(
+            "{}",
+            json_trace(&options.host, destination, &json, code == EXIT_REACHED)
+        );
+    }
+    code
+}
+
+fn main() {
+    let options = match Options::try_parse() {
+        Ok(options) => options,
+        Err(e) => {
+            let _ = e.print();
+            process::exit(if e.use_stderr() { EXIT_USAGE } else { 0 });
+        }
+    };
+    process::exit(run(&options));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn parse(args: &[&str]) -> Result<Options, clap::Error> {
+        Options::try_parse_from(Some("rtraceroute").iter().chain(args))
+    }
+
+    #[test]
+    fn classic_options_map_to_the_config() {
+        let options = parse(&[
+            "-I",
+            "-f",
+            "2",
+            "-m",
+            "12",
+            "-q",
+            "1",
+            "-w",
+            "0.25",
+            "-n",
+            "192.0.2.1",
+            "100",
+        ])
+        .unwrap();
+        let config = options.config().unwrap();
+        assert_eq!(config.protocol, TraceRouteProtocol::Icmp);
+        assert_eq!((config.begin_ttl, config.max_ttl), (2, 12));
+        assert_eq!(config.max_tries, 1);
+        assert_eq!(config.timeout, Some(Duration::from_millis(250)));
+        assert_eq!(config.size, 100);
+        assert!(options.numeric);
+        assert_eq!(
+            options.destination().unwrap(),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+
+        let config = parse(&["-P", "47", "-p", "4000", "192.0.2.1"])
+            .unwrap()
+            .config()
+            .unwrap();
+        assert_eq!(config.protocol, TraceRouteProtocol::Raw(47));
+        assert_eq!(config.port, 4000);
+        let config = parse(&["192.0.2.1"]).unwrap().config().unwrap();
+        assert_eq!(config.protocol, TraceRouteProtocol::Udp);
+    }
+
+    #[test]
+    fn bad_arguments_are_rejected() {
+        assert!(parse(&["-I", "-U", "192.0.2.1"]).is_err());
+        assert!(parse(&["-4", "-6", "192.0.2.1"]).is_err());
+        assert!(parse(&["-m", "300", "192.0.2.1"]).is_err());
+        assert_eq!(
+            parse(&[]).unwrap_err().kind(),
+            clap::error::ErrorKind::MissingRequiredArgument
+        );
+        let tcp = parse(&["-T", "192.0.2.1"]).unwrap();
+        assert!(tcp.config().unwrap_err().contains("TCP"));
+        let wait = parse(&["-w", "0", "192.0.2.1"]).unwrap();
+        assert!(wait.config().is_err());
+        let wait = parse(&["-w", "inf", "192.0.2.1"]).unwrap();
+        assert_eq!(wait.config().unwrap().timeout, None);
+        let family = parse(&["-4", "2001:db8::1"]).unwrap();
+        assert!(family.destination().is_err());
+        assert_eq!(run(&family), EXIT_USAGE);
+        let multicast = parse(&["224.0.0.1"]).unwrap();
+        assert_eq!(run(&multicast), EXIT_USAGE);
+    }
+
+    #[test]
+    fn hops_render_like_traceroute() {
+        let addr = Some("192.0.2.1".parse().unwrap());
+        let hop = HopFound::new(3, addr, 0, false, Some(Duration::from_micros(12_345)));
+        assert_eq!(render(&hop, None, false), " 3  192.0.2.1  12.345 ms");
+        assert_eq!(
+            render(&hop, Some("gw.example"), false),
+            " 3  gw.example (192.0.2.1)  12.345 ms"
+        );
+        let silent = HopFound::new(12, None, 3, false, None);
+        assert_eq!(render(&silent, None, false), "12  *");
+        assert!(render(&silent, None, true).contains("\u{1b}["));
+    }
+
+    #[test]
+    fn hops_render_as_json() {
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        let hop = HopFound::new(1, Some(addr), 0, true, Some(Duration::from_micros(1_500)));
+        let json = json_hop(&hop, Some("a\"b"));
+        assert_eq!(
+            json,
+            r#"{"hop":1,"addr":"192.0.2.1","name":"a\"b","rtt_ms":1.500,"tries":0,"is_last":true}"#
+        );
+        let silent = json_hop(&HopFound::new(2, None, 1, false, None), None);
+        assert!(silent.contains(r#""addr":null,"name":null,"rtt_ms":null"#));
+        assert_eq!(
+            json_trace("h", addr, std::slice::from_ref(&json), true),
+            format!(
+                r#"{{"host":"h","destination":"192.0.2.1","reached":true,"hops":[{}]}}"#,
+                json
+            )
+        );
+    }
+
+    #[test]
+    fn exit_code_follows_the_last_hop() {
+        let addr = Some("192.0.2.1".parse().unwrap());
+        assert_eq!(
+            exit_code(Some(&HopFound::new(4, addr, 0, true, None))),
+            EXIT_REACHED
+        );
+        assert_eq!(
+            exit_code(Some(&HopFound::new(31, None, 0, true, None))),
+            EXIT_NOT_REACHED
+        );
+        assert_eq!(exit_code(None), EXIT_NOT_REACHED);
+    }
+}
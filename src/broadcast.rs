@@ -0,0 +1,140 @@
+//! Fan-out of the events of a trace to its subscribers, see `TraceHandle::subscribe`.
+use crate::TraceEvent;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+/// Events a subscriber may fall behind by before new ones are dropped for it.
+pub(crate) const SUBSCRIBER_BUFFER: usize = 1024;
+
+struct Subscriber {
+    tx: SyncSender<TraceEvent>,
+    dropped: u64,
+}
+
+struct Subscribers {
+    buffer: usize,
+    /// Every event published so far, for subscribers asking for a replay.
+    history: Vec<TraceEvent>,
+    subscribers: Vec<Subscriber>,
+    /// Events dropped for subscribers that have since gone.
+    gone_dropped: u64,
+    closed: bool,
+}
+
+/// This struct hands every event of a trace to any number of subscribers.
+///
+/// Each subscriber has a buffer of its own, a full one loses the events that do not
+/// fit instead of holding up the trace, they are counted in `dropped`. Clones share
+/// the same subscribers.
+#[derive(Clone)]
+pub(crate) struct Broadcast(Arc<Mutex<Subscribers>>);
+
+impl Broadcast {
+    /// Creates new Broadcast whose subscribers fall behind by at most `buffer` events.
+    pub fn new(buffer: usize) -> Broadcast {
+        Broadcast(Arc::new(Mutex::new(Subscribers {
+            buffer,
+            history: Vec::new(),
+            subscribers: Vec::new(),
+            gone_dropped: 0,
+            closed: false,
+        })))
+    }
+
+    /// Returns a receiver of the events published from now on, preceded by the
+    /// ones so far with `replay`. It disconnects once the trace is over.
+    pub fn subscribe(&self, replay: bool) -> Receiver<TraceEvent> {
+        let mut subscribers = self.0.lock().unwrap();
+        let replayed = if replay { subscribers.history.len() } else { 0 };
+        let (tx, rx) = sync_channel(subscribers.buffer + replayed);
+        for event in &subscribers.history[subscribers.history.len() - replayed..] {
+            let _ = tx.try_send(event.clone());
+        }
+        if !subscribers.closed {
+            subscribers.subscribers.push(Subscriber { tx, dropped: 0 });
+        }
+        rx
+    }
+
+    /// Hands `event` to every subscriber, returns true if any is still listening.
+    pub fn publish(&self, event: &TraceEvent) -> bool {
+        let mut subscribers = self.0.lock().unwrap();
+        let subscribers = &mut *subscribers;
+        subscribers.history.push(event.clone());
+        let gone_dropped = &mut subscribers.gone_dropped;
+        subscribers.subscribers.retain_mut(|subscriber| {
+            match subscriber.tx.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.dropped += 1;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    *gone_dropped += subscriber.dropped;
+                    false
+                }
+            }
+        });
+        !subscribers.subscribers.is_empty()
+    }
+
+    /// Returns how many events were dropped for subscribers falling behind.
+    pub fn dropped(&self) -> u64 {
+        let subscribers = self.0.lock().unwrap();
+        subscribers.gone_dropped
+            + subscribers
+                .subscribers
+                .iter()
+                .map(|subscriber| subscriber.dropped)
+                .sum::<u64>()
+    }
+
+    /// Ends the events, the subscribers disconnect once they took the ones left.
+    pub fn close(&self) {
+        let mut subscribers = self.0.lock().unwrap();
+        subscribers.closed = true;
+        let gone: u64 = subscribers
+            .subscribers
+            .drain(..)
+            .map(|subscriber| subscriber.dropped)
+            .sum();
+        subscribers.gone_dropped += gone;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PathAnomaly, TraceWarning};
+
+    fn event(ttl: u8) -> TraceEvent {
+        let anomaly = PathAnomaly::TimeExceededFromDestination { ttl };
+        TraceEvent::Warning(TraceWarning::PathAnomaly(anomaly))
+    }
+
+    #[test]
+    fn slow_subscribers_lose_events() {
+        let broadcast = Broadcast::new(2);
+        let slow = broadcast.subscribe(false);
+        for n in 1..=5 {
+            assert!(broadcast.publish(&event(n)));
+        }
+        assert_eq!(broadcast.dropped(), 3);
+        let late = broadcast.subscribe(true);
+        broadcast.close();
+        assert_eq!(slow.iter().collect::<Vec<_>>(), vec![event(1), event(2)]);
+        let replayed: Vec<TraceEvent> = late.iter().collect();
+        assert_eq!(replayed, (1..=5).map(event).collect::<Vec<_>>());
+        assert_eq!(broadcast.dropped(), 3);
+    }
+
+    #[test]
+    fn subscribers_leave() {
+        let broadcast = Broadcast::new(4);
+        drop(broadcast.subscribe(false));
+        assert!(!broadcast.publish(&event(1)));
+        broadcast.close();
+        let after = broadcast.subscribe(false);
+        assert!(after.recv().is_err());
+    }
+}
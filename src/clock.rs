@@ -0,0 +1,29 @@
+//! Where traces take the time from.
+use std::thread;
+use std::time::Instant;
+
+/// This trait is the time source of a trace, its probes are timed and its
+/// deadlines kept by it.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Blocks until `deadline` has passed.
+    fn sleep_until(&self, deadline: Instant);
+}
+
+/// This struct is the clock of the system, the one traces use by default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        if let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            thread::sleep(left);
+        }
+    }
+}
@@ -0,0 +1,1337 @@
+//! Options of a trace and their validation.
+use crate::backend::BackendKind;
+use crate::error::TraceRouteError;
+use crate::ids::IdSource;
+use crate::probe::InterfaceQuery;
+use crate::shared::SharedReceiver;
+use crate::stats::ResponseClassifier;
+use crate::TraceRouteProtocol;
+use pnet::datalink::{self, NetworkInterface};
+use pnet::ipnetwork::IpNetwork;
+use pnet::util::MacAddr;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Largest probe size, what is left of the 16 bit IPv4 total length after the header.
+pub const MAX_PROBE_SIZE: usize = 65535 - 20;
+
+/// Most gateways a loose source route can list, what fits the 40 bytes of IPv4 options.
+pub const MAX_GATEWAYS: usize = 9;
+
+/// Most addresses the Record Route option holds, what fits the 40 bytes of IPv4 options.
+pub const MAX_RECORDED_ROUTE: usize = 9;
+
+/// Most address and timestamp pairs the Timestamp option holds.
+pub const MAX_TIMESTAMPS: usize = 4;
+
+/// Largest DSCP, what fits the six bits of the field.
+pub const MAX_DSCP: u8 = 63;
+
+/// Longest preflight timeout and delay between candidates, in milliseconds.
+pub const MAX_PREFLIGHT_WAIT: u64 = 2000;
+
+/// This struct describes the ports UDP and DCCP probes fall back to when the
+/// ports after `TraceRouteConfig::port` are filtered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortFallback {
+    /// Destination ports tried in turn, e.g. 53, 123, 443 and 4500.
+    pub candidates: Vec<u16>,
+    /// Unanswered probes on the usual port before a TTL moves to the candidates.
+    pub trigger_after: u16,
+}
+
+/// This struct picks the destination port of every UDP and DCCP probe from its
+/// TTL and attempt, counted from 1, see `TraceRouteConfig::port_fn`.
+///
+/// Configurations holding it compare equal only if they share the same function.
+#[derive(Clone)]
+pub struct PortFn(Arc<dyn Fn(u8, u16) -> u16 + Send + Sync>);
+
+impl PortFn {
+    /// Creates new PortFn calling `f`.
+    pub fn new<F: Fn(u8, u16) -> u16 + Send + Sync + 'static>(f: F) -> PortFn {
+        PortFn(Arc::new(f))
+    }
+
+    /// Returns the port of the probe of `ttl` sent for the `attempt`th time.
+    pub fn port(&self, ttl: u8, attempt: u16) -> u16 {
+        (self.0)(ttl, attempt)
+    }
+}
+
+impl fmt::Debug for PortFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PortFn")
+    }
+}
+
+impl PartialEq for PortFn {
+    fn eq(&self, other: &PortFn) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// This struct lists the protocols a trace restarts with when the start of the
+/// path stays silent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProtocolFallback {
+    /// Protocols tried in turn after `TraceRouteConfig::protocol`.
+    pub order: Vec<TraceRouteProtocol>,
+    /// Timed out TTLs in a row, from `begin_ttl` on, after which the next protocol
+    /// is tried.
+    pub give_up_after_hops: u8,
+}
+
+/// This struct sets how often a trace none of whose TTLs answered is run again,
+/// see `TraceRouteConfig::retry_on_total_failure`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetrySpec {
+    /// Most reruns.
+    pub attempts: u16,
+    /// Wait before every rerun.
+    pub delay: Duration,
+}
+
+/// This enum sets the order in which the TTLs of a trace are probed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TtlOrder {
+    /// From `begin_ttl` up to `max_ttl`.
+    Sequential,
+    /// In a random order, which security appliances cannot tell from other traffic
+    /// as easily as rising TTLs. Hops are still reported in TTL order.
+    Shuffled {
+        /// Ends the trace once the destination answered instead of probing the TTLs
+        /// below it that were not probed yet, whose hops are then left out.
+        stop_at_destination: bool,
+    },
+}
+
+/// This enum names the IPv6 extension headers probes can carry, see
+/// `TraceRouteConfig::extension_header`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum ExtensionHeader {
+    /// Hop-by-Hop Options, examined by every router on the path.
+    HopByHop,
+    /// Destination Options, meant for the destination only.
+    DestinationOptions,
+}
+
+impl ExtensionHeader {
+    /// Returns the next header value naming the extension header.
+    pub fn number(self) -> u8 {
+        match self {
+            ExtensionHeader::HopByHop => 0,
+            ExtensionHeader::DestinationOptions => 60,
+        }
+    }
+}
+
+/// This enum numbers the sequence of the echo probes of a trace, so tools keyed
+/// on sequence numbers can tell which TTL and attempt a probe was.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SequencePlan {
+    /// `start`, then `stride` more for every probe sent, wrapping around.
+    Sequential { start: u16, stride: u16 },
+    /// `start + ttl * stride + attempt`, the attempt counted from 1. Needs a
+    /// stride above `max_tries` for the attempts of a TTL to keep apart.
+    PerTtl { start: u16, stride: u16 },
+}
+
+impl SequencePlan {
+    /// Returns the sequence of the `count`th probe of the trace, counted from 0,
+    /// sent to `ttl` for the `attempt`th time.
+    pub fn sequence(&self, count: u16, ttl: u8, attempt: u16) -> u16 {
+        match *self {
+            SequencePlan::Sequential { start, stride } => {
+                start.wrapping_add(count.wrapping_mul(stride))
+            }
+            SequencePlan::PerTtl { start, stride } => start
+                .wrapping_add(u16::from(ttl).wrapping_mul(stride))
+                .wrapping_add(attempt),
+        }
+    }
+
+    /// Returns the TTL and attempt of a probe numbered `sequence`, None if the plan
+    /// does not tell them or no probe gets that number.
+    pub fn decode(&self, sequence: u16) -> Option<(u8, u16)> {
+        match *self {
+            SequencePlan::Sequential { .. } => None,
+            SequencePlan::PerTtl { start, stride } => {
+                let offset = sequence.wrapping_sub(start);
+                let ttl = u8::try_from(offset.checked_div(stride)?).ok()?;
+                let attempt = offset % stride;
+                if attempt == 0 {
+                    return None;
+                }
+                Some((ttl, attempt))
+            }
+        }
+    }
+}
+
+impl Default for SequencePlan {
+    /// The first probe numbered 1 and every other one more.
+    fn default() -> SequencePlan {
+        SequencePlan::Sequential {
+            start: 1,
+            stride: 1,
+        }
+    }
+}
+
+/// This enum tells who may send an ICMP message that ends a trace.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TerminalSource {
+    /// Only the traced address.
+    Destination,
+    /// The destination or any router on the way.
+    AnyHop,
+}
+
+/// This struct is an ICMP message that ends a trace, see `TerminalPolicy`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TerminalRule {
+    /// ICMP or ICMPv6 type, of the family of the traced address.
+    pub icmp_type: u8,
+    /// Code of the message, None for any.
+    pub code: Option<u8>,
+    pub from: TerminalSource,
+}
+
+/// This struct lists the ICMP messages that end a trace.
+///
+/// Replies to the current probe matching none of the rules, nor a time exceeded
+/// from a router, are unexpected and the probe is tried again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TerminalPolicy {
+    pub rules: Vec<TerminalRule>,
+}
+
+impl TerminalPolicy {
+    /// Returns the messages ending a trace of `protocol` unless a policy is set,
+    /// ICMPv6 ones with `v6`.
+    ///
+    /// UDP and DCCP traces end at any destination unreachable, ICMP ones at an echo
+    /// reply of the destination and raw ones at a protocol unreachable, which IPv6 reports as a
+    /// parameter problem pointing at the next header field.
+    pub fn default_for(protocol: TraceRouteProtocol, v6: bool) -> TerminalPolicy {
+        let (icmp_type, code) = match (protocol, v6) {
+            (TraceRouteProtocol::Udp, false) | (TraceRouteProtocol::Dccp, false) => (3, None),
+            (TraceRouteProtocol::Icmp, false) => (0, None),
+            (TraceRouteProtocol::Raw(_), false) => (3, Some(2)),
+            (TraceRouteProtocol::Udp, true) | (TraceRouteProtocol::Dccp, true) => (1, None),
+            (TraceRouteProtocol::Icmp, true) => (129, None),
+            (TraceRouteProtocol::Raw(_), true) => (4, Some(1)),
+        };
+        let from = match protocol {
+            TraceRouteProtocol::Icmp => TerminalSource::Destination,
+            _ => TerminalSource::AnyHop,
+        };
+        TerminalPolicy {
+            rules: vec![TerminalRule {
+                icmp_type,
+                code,
+                from,
+            }],
+        }
+    }
+
+    /// Returns true if a message of `icmp_type` and `code` ends the trace,
+    /// `from_destination` if the traced address sent it.
+    pub fn ends(&self, icmp_type: u8, code: u8, from_destination: bool) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.icmp_type == icmp_type
+                && rule.code.is_none_or(|rule_code| rule_code == code)
+                && (rule.from == TerminalSource::AnyHop || from_destination)
+        })
+    }
+}
+
+/// This enum picks a network device, by name or by the index the OS gave it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InterfaceSel {
+    Name(String),
+    /// Index of the device, as `if_nametoindex` or netlink tell it.
+    Index(u32),
+}
+
+impl InterfaceSel {
+    /// Returns the device of `devices` picked, None if none matches.
+    pub fn resolve<'d>(&self, devices: &'d [NetworkInterface]) -> Option<&'d NetworkInterface> {
+        devices.iter().find(|device| match self {
+            InterfaceSel::Name(name) => &device.name == name,
+            InterfaceSel::Index(index) => device.index == *index,
+        })
+    }
+}
+
+impl From<&str> for InterfaceSel {
+    fn from(name: &str) -> InterfaceSel {
+        InterfaceSel::Name(name.to_string())
+    }
+}
+
+impl From<String> for InterfaceSel {
+    fn from(name: String) -> InterfaceSel {
+        InterfaceSel::Name(name)
+    }
+}
+
+/// This struct sets the Ethernet layer of the frames `BackendKind::Datalink` sends.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DatalinkConfig {
+    /// Source MAC of the frames, the one of the device when None.
+    pub source_mac: Option<MacAddr>,
+    /// MAC the frames are sent to. None resolves the next hop with ARP or Neighbor
+    /// Discovery: the destination when it is on the link, the default gateway
+    /// otherwise.
+    pub gateway_mac: Option<MacAddr>,
+    /// Source address of the probes, for devices without one of the family.
+    pub source: Option<IpAddr>,
+}
+
+/// This struct sets how the addresses of a host name are probed before tracing
+/// one of them, see `TraceRoute::for_host`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PreflightConfig {
+    /// Most addresses probed, taken in RFC 8305 order.
+    pub candidates: usize,
+    /// Milliseconds between the probes of two candidates.
+    pub attempt_delay: u64,
+    /// Milliseconds a probe waits for an answer.
+    pub timeout: u64,
+}
+
+impl Default for PreflightConfig {
+    fn default() -> PreflightConfig {
+        PreflightConfig {
+            candidates: 4,
+            attempt_delay: 100,
+            timeout: 500,
+        }
+    }
+}
+
+/// This struct stores all options of a trace.
+///
+/// `TraceRoute::new` fills it from its arguments, `TraceRoute::with_config` takes it
+/// as is. Options are validated against the destination when the trace is created.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceRouteConfig {
+    pub max_ttl: u8,
+    pub begin_ttl: u8,
+    pub max_tries: u16,
+    pub port: u16,
+    /// How long a probe waits for its reply. None waits until a reply comes or the
+    /// trace is cancelled, see `TraceHandle::cancel`. A silent TTL is then never
+    /// retried, only TTLs answered with a Parameter Problem use up `max_tries`.
+    pub timeout: Option<Duration>,
+    pub size: usize,
+    pub protocol: TraceRouteProtocol,
+    /// Skips the checks rejecting unspecified, zero network, multicast and
+    /// broadcast destinations.
+    pub allow_special_destinations: bool,
+    /// Also rejects the directed broadcast address of every local IPv4 subnet.
+    pub reject_directed_broadcast: bool,
+    /// Opens sockets for destinations in the ranges reserved for documentation,
+    /// which no host answers on. Traces over a given backend, like a
+    /// `SimulatedBackend`, take them anyway.
+    pub allow_documentation_destinations: bool,
+    /// Traces IPv4-mapped and IPv4-compatible IPv6 destinations over IPv4.
+    pub unmap_ipv4: bool,
+    /// Sends echo probes to the destination when a UDP trace ends in silence. The
+    /// last hop is then completed as `ReachedButFiltered` if the destination
+    /// answered and `DestinationUnresponsive` if it did not.
+    pub confirm_silent_destination: bool,
+    /// Number of echo probes sent to confirm a silent destination.
+    pub confirm_probes: u16,
+    /// Also sends a TCP SYN to this port of a silent destination with every echo
+    /// probe, its SYN-ACK or reset confirms the destination as well. Only IPv4
+    /// traces reading their own sockets send them.
+    pub confirm_tcp_port: Option<u16>,
+    /// How long a confirmation probe waits for its answer, None waits as long as
+    /// the probes of the trace.
+    pub confirm_timeout: Option<Duration>,
+    /// Prefix length the last responding hop has to share with an IPv4 destination
+    /// for it to be confirmed.
+    pub confirm_prefix_v4: u8,
+    /// Prefix length the last responding hop has to share with an IPv6 destination
+    /// for it to be confirmed.
+    pub confirm_prefix_v6: u8,
+    /// Reports the private and shared address hops at the start of the path as one
+    /// placeholder hop. Its `hop_count` is where the local network ends, a later
+    /// run can start right after it with `begin_ttl`, as the rounds of
+    /// `TraceRoute::run_rounds` do.
+    pub hide_local_hops: bool,
+    /// Gateways IPv4 probes are loose source routed through, in order, like
+    /// `traceroute -g`. Empty sends probes without the option.
+    pub gateways: Vec<Ipv4Addr>,
+    /// Sends IPv4 probes with the Record Route option and reports the addresses
+    /// routers recorded in it, see `HopFound::recorded_route`.
+    pub record_route: bool,
+    /// Sends IPv4 probes with the Timestamp option asking routers for address and
+    /// timestamp pairs, see `HopFound::timestamps`. Can not be combined with
+    /// `record_route`.
+    pub timestamps: bool,
+    /// Attaches the bytes of every reply to its hop, see `HopFound::raw_reply`, and
+    /// prints them with unexpected replies.
+    pub capture_raw: bool,
+    /// Most bytes kept of a captured reply.
+    pub raw_capture_limit: usize,
+    /// Network device the sockets are bound to with `SO_BINDTODEVICE`, Linux only.
+    /// Probes leave through it whatever the routing table says, from one of its
+    /// addresses.
+    pub interface: Option<InterfaceSel>,
+    /// Firewall mark set on the sending sockets with `SO_MARK`, Linux only, for
+    /// `ip rule fwmark` policy routing. Needs CAP_NET_ADMIN.
+    pub fwmark: Option<u32>,
+    /// Differentiated services code point of the probes, written into the IPv4 TOS
+    /// byte or the IPv6 traffic class.
+    pub dscp: u8,
+    /// Id stamped on every hop of the trace, see `HopFound::trace_id`. None takes
+    /// the next id of the process.
+    pub trace_id: Option<u64>,
+    /// Ports UDP and DCCP probes of a TTL switch to once it went unanswered, see
+    /// `HopFound::port`. Destination unreachables from routers then count as
+    /// unanswered probes instead of ending the trace.
+    pub port_fallback: Option<PortFallback>,
+    /// Picks the destination port of every UDP and DCCP probe instead of `port`
+    /// and `port_fallback`, e.g. to encode the TTL in ports firewall logs show. A
+    /// probe it gives port 0 fails the trace with `TraceRouteError::ZeroPort`.
+    pub port_fn: Option<PortFn>,
+    /// Probes the addresses of a host name and traces the first to answer, only
+    /// used by `TraceRoute::for_host`. None traces the first address.
+    pub preflight: Option<PreflightConfig>,
+    /// Restarts the trace with other protocols while its first TTLs all time out,
+    /// see `HopFound::protocol`. The sockets stay the ones opened for `protocol`,
+    /// IPv4 ones send any protocol, IPv6 ones ICMPv6 besides their own.
+    pub protocol_fallback: Option<ProtocolFallback>,
+    /// Forces the kind of sockets `run_trace_route` opens. None tries raw sockets
+    /// first and falls back to unprivileged ones for plain ICMP and UDP traces.
+    pub backend: Option<BackendKind>,
+    /// Sends an unreported probe before the first try of every TTL, so address
+    /// resolution and route cache misses do not inflate the first round trip time.
+    pub warmup: bool,
+    /// Probes the first hop every this many milliseconds between the probes of the
+    /// trace and reports its latency in `TraceMetrics::first_hop`.
+    pub first_hop_interval: Option<u64>,
+    /// Keeps probing up to `max_ttl` after the destination answered, for studying
+    /// whatever answers beyond it.
+    pub continue_past_destination: bool,
+    /// ICMP messages that end the trace, the defaults of the protocol when None,
+    /// see `TerminalPolicy::default_for`.
+    pub terminal_policy: Option<TerminalPolicy>,
+    /// Ethernet layer of the probes, only used with `BackendKind::Datalink`. None
+    /// sends from the MAC of the device to the resolved next hop.
+    pub datalink: Option<DatalinkConfig>,
+    /// Runs the trace again when none of its TTLs answered, like after an
+    /// interface flap. Silent hops are held back until the trace is known not to
+    /// rerun, a trace with any answer is never rerun.
+    pub retry_on_total_failure: Option<RetrySpec>,
+    /// Order in which the TTLs are probed, a shuffled order cannot be combined with
+    /// `continue_past_destination`.
+    pub ttl_order: TtlOrder,
+    /// Sequence numbers of the echo probes, ICMP ones of ICMP traces and the
+    /// confirmation probes. Replies are matched on the numbers sent whatever the
+    /// plan.
+    pub sequence_plan: SequencePlan,
+    /// Labels the TTLs of `TraceRoute::run_rounds` as responsive, rate limited or
+    /// silent, see `HopStats::response`.
+    pub response_classifier: ResponseClassifier,
+    /// Hands out the identifier and source ports of the trace, so traces of
+    /// several processes keep apart. None takes the next identifier of the
+    /// process and random source ports.
+    pub id_allocator: Option<IdSource>,
+    /// Probes up to this many TTLs at once, a reply or timeout of one makes room
+    /// for the next. None probes one TTL after the other. It does not go with
+    /// warmup, protocol fallback, reruns, confirmation probes or probing past the
+    /// destination, and raw protocols probe one TTL at a time. Traces of a
+    /// `TracePool` always probe one TTL after the other.
+    pub window: Option<u8>,
+    /// Reports a reply coming at most this long after its TTL was reported as
+    /// unanswered in `TraceEvent::HopCorrected`, the trace waits that long at its
+    /// end for the replies still missing. None counts such replies as stale.
+    pub correct_late_replies: Option<Duration>,
+    /// Probes the TTLs none of whose probes was answered once more after the trace
+    /// reached its end, with `max_tries` fresh probes each. A TTL answering then is
+    /// reported in `TraceEvent::HopCorrected`, on a hops channel its hop is filled
+    /// in instead, and the hops from the first unanswered one on come once the
+    /// second pass is over.
+    pub second_pass: bool,
+    /// Time the host itself takes to send a probe and read its reply, taken off
+    /// every round trip time, see `TraceRoute::calibrate`.
+    pub rtt_offset: Duration,
+    /// Reads the replies of the trace on the thread of this receiver, shared with
+    /// the other traces holding it, see `SharedReceiver::global`. Traces with DCCP
+    /// probes, whose answers come in on sockets of their own, read their sockets
+    /// anyway. Traces of a `TracePool` only take the replies at their deadlines.
+    pub shared_receiver: Option<SharedReceiver>,
+    /// The interface `TraceRoute::probe_interface` asks the traced address about.
+    pub interface_query: Option<InterfaceQuery>,
+    /// Inserts this extension header, holding a single PadN option, into IPv6
+    /// probes. Probes lost behind a hop dropping packets with extension headers
+    /// point it out, see `TraceRoute::run_extension_header_comparison`.
+    pub extension_header: Option<ExtensionHeader>,
+    /// Sends every probe in two fragments, the first one carrying the transport
+    /// header. Probes lost behind a hop dropping fragments point it out, see
+    /// `TraceRoute::run_fragment_comparison`. IPv6 fragments are sent over
+    /// `BackendKind::Datalink`, as raw IPv6 sockets leave the header to the kernel.
+    pub fragment: bool,
+}
+
+impl Default for TraceRouteConfig {
+    fn default() -> TraceRouteConfig {
+        TraceRouteConfig {
+            max_ttl: 30,
+            begin_ttl: 1,
+            max_tries: 4,
+            port: 33434,
+            timeout: Some(Duration::from_millis(200)),
+            size: 64,
+            protocol: TraceRouteProtocol::Udp,
+            allow_special_destinations: false,
+            reject_directed_broadcast: false,
+            allow_documentation_destinations: false,
+            unmap_ipv4: true,
+            confirm_silent_destination: false,
+            confirm_probes: 2,
+            confirm_tcp_port: None,
+            confirm_timeout: None,
+            confirm_prefix_v4: 24,
+            confirm_prefix_v6: 48,
+            hide_local_hops: false,
+            gateways: Vec::new(),
+            record_route: false,
+            timestamps: false,
+            capture_raw: false,
+            raw_capture_limit: 256,
+            interface: None,
+            fwmark: None,
+            dscp: 0,
+            trace_id: None,
+            port_fallback: None,
+            port_fn: None,
+            preflight: None,
+            protocol_fallback: None,
+            backend: None,
+            warmup: false,
+            first_hop_interval: None,
+            continue_past_destination: false,
+            terminal_policy: None,
+            datalink: None,
+            retry_on_total_failure: None,
+            ttl_order: TtlOrder::Sequential,
+            sequence_plan: SequencePlan::default(),
+            response_classifier: ResponseClassifier::default(),
+            id_allocator: None,
+            window: None,
+            correct_late_replies: None,
+            second_pass: false,
+            rtt_offset: Duration::from_secs(0),
+            shared_receiver: None,
+            interface_query: None,
+            extension_header: None,
+            fragment: false,
+        }
+    }
+}
+
+impl TraceRouteConfig {
+    /// Returns the shared receiver the replies of the trace come from, None if
+    /// the trace reads its own sockets.
+    pub(crate) fn shared_replies(&self) -> Option<&SharedReceiver> {
+        let dccp = self.protocol == TraceRouteProtocol::Dccp
+            || self
+                .protocol_fallback
+                .as_ref()
+                .is_some_and(|fallback| fallback.order.contains(&TraceRouteProtocol::Dccp));
+        self.shared_receiver.as_ref().filter(|_| !dccp)
+    }
+
+    /// Returns the port a silent destination at `address` is sent TCP SYNs to, None
+    /// if it is only confirmed by echo.
+    pub(crate) fn confirm_tcp(&self, address: IpAddr) -> Option<u16> {
+        let by_tcp = self.confirm_silent_destination
+            && self.protocol == TraceRouteProtocol::Udp
+            && address.is_ipv4()
+            && self.shared_replies().is_none();
+        self.confirm_tcp_port.filter(|_| by_tcp)
+    }
+
+    /// Returns the name of the device of `interface`, None if it is not set.
+    pub(crate) fn device(&self) -> Result<Option<String>, TraceRouteError> {
+        let interface = match &self.interface {
+            Some(interface) => interface,
+            None => return Ok(None),
+        };
+        match interface.resolve(&datalink::interfaces()) {
+            Some(device) => Ok(Some(device.name.clone())),
+            None => Err(TraceRouteError::BadInterface(interface.clone())),
+        }
+    }
+
+    /// Returns the ICMP messages that end a trace of `protocol` to `address`.
+    pub(crate) fn terminal_policy(
+        &self,
+        protocol: TraceRouteProtocol,
+        address: IpAddr,
+    ) -> TerminalPolicy {
+        match &self.terminal_policy {
+            Some(policy) => policy.clone(),
+            None => TerminalPolicy::default_for(protocol, address.is_ipv6()),
+        }
+    }
+
+    /// Checks the options and the destination they are going to be used with.
+    pub fn validate(&self, address: IpAddr) -> Result<(), TraceRouteError> {
+        self.check_options()?;
+        if !self.gateways.is_empty() && (address.is_ipv6() || self.gateways.len() > MAX_GATEWAYS) {
+            return Err(TraceRouteError::BadGateways { max: MAX_GATEWAYS });
+        }
+        if self.record_route && (address.is_ipv6() || self.record_route_slots() == 0) {
+            return Err(TraceRouteError::BadRecordRoute);
+        }
+        if self.timestamps
+            && (address.is_ipv6() || self.record_route || self.timestamp_slots() == 0)
+        {
+            return Err(TraceRouteError::BadTimestamps);
+        }
+        if self.extension_header.is_some() && address.is_ipv4() {
+            return Err(TraceRouteError::BadExtensionHeader);
+        }
+        let max = MAX_PROBE_SIZE - self.header_extra();
+        if self.size > max {
+            return Err(TraceRouteError::SizeTooLarge { max });
+        }
+        if self.fragment {
+            match (self.backend, address) {
+                (None, _) | (Some(BackendKind::Datalink), _) => {}
+                (Some(BackendKind::Raw), IpAddr::V4(_)) => {}
+                (Some(kind), _) => return Err(TraceRouteError::BadFragment(kind)),
+            }
+        }
+        if !self.allow_special_destinations {
+            check_destination(address)?;
+            if self.reject_directed_broadcast {
+                if let IpAddr::V4(v4) = address {
+                    if is_directed_broadcast(v4, &local_v4_networks()) {
+                        return Err(TraceRouteError::BroadcastDestination(address));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the options that do not depend on the destination, `validate` checks
+    /// them too.
+    pub fn check_options(&self) -> Result<(), TraceRouteError> {
+        if self.max_ttl < 1 {
+            return Err(TraceRouteError::BadMaxTtl);
+        }
+        if self.begin_ttl < 1 || self.begin_ttl > self.max_ttl {
+            return Err(TraceRouteError::BadBeginTtl);
+        }
+        if self.size < 12 {
+            return Err(TraceRouteError::BadSize);
+        }
+        if self.size > MAX_PROBE_SIZE {
+            return Err(TraceRouteError::SizeTooLarge {
+                max: MAX_PROBE_SIZE,
+            });
+        }
+        if self.timeout == Some(Duration::from_millis(0)) {
+            return Err(TraceRouteError::BadTimeout);
+        }
+        self.device()?;
+        if self.dscp > MAX_DSCP {
+            return Err(TraceRouteError::BadDscp { max: MAX_DSCP });
+        }
+        self.check_preflight()?;
+        if let Some(kind) = self.backend {
+            if !kind.carries(self.protocol) {
+                return Err(TraceRouteError::BadBackend(kind));
+            }
+        }
+        if let Some(fallback) = &self.protocol_fallback {
+            if fallback.order.is_empty() || fallback.give_up_after_hops == 0 {
+                return Err(TraceRouteError::BadProtocolFallback);
+            }
+        }
+        if let Some(fallback) = &self.port_fallback {
+            if fallback.candidates.is_empty() || fallback.trigger_after >= self.max_tries {
+                return Err(TraceRouteError::BadPortFallback);
+            }
+        }
+        if self.continue_past_destination && self.ttl_order != TtlOrder::Sequential {
+            return Err(TraceRouteError::BadTtlOrder);
+        }
+        if let SequencePlan::PerTtl { stride, .. } = self.sequence_plan {
+            if stride <= self.max_tries {
+                return Err(TraceRouteError::BadSequencePlan);
+            }
+        }
+        if let Some(window) = self.window {
+            let sequential_only = self.warmup
+                || self.protocol_fallback.is_some()
+                || self.retry_on_total_failure.is_some()
+                || self.confirm_silent_destination
+                || self.continue_past_destination;
+            // Replies to raw probes quote nothing telling the TTLs in flight apart.
+            let unkeyed = window > 1 && matches!(self.protocol, TraceRouteProtocol::Raw(_));
+            if window == 0 || self.ttl_order != TtlOrder::Sequential || sequential_only || unkeyed {
+                return Err(TraceRouteError::BadWindow);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that sockets may be opened for `address`, see
+    /// `allow_documentation_destinations`.
+    pub fn check_documentation(&self, address: IpAddr) -> Result<(), TraceRouteError> {
+        if !self.allow_documentation_destinations && is_documentation(address) {
+            return Err(TraceRouteError::DocumentationDestination(address));
+        }
+        Ok(())
+    }
+
+    /// Checks the preflight options, which are used before there is a destination.
+    pub fn check_preflight(&self) -> Result<(), TraceRouteError> {
+        match &self.preflight {
+            Some(preflight)
+                if preflight.candidates == 0
+                    || preflight.timeout == 0
+                    || preflight.timeout > MAX_PREFLIGHT_WAIT
+                    || preflight.attempt_delay > MAX_PREFLIGHT_WAIT =>
+            {
+                Err(TraceRouteError::BadPreflight {
+                    max: MAX_PREFLIGHT_WAIT,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns how many addresses the Record Route option has room for next to
+    /// the loose source route.
+    pub fn record_route_slots(&self) -> usize {
+        (self.room_after_gateways().saturating_sub(4) / 4).min(MAX_RECORDED_ROUTE)
+    }
+
+    /// Returns how many address and timestamp pairs the Timestamp option has room
+    /// for next to the loose source route.
+    pub fn timestamp_slots(&self) -> usize {
+        (self.room_after_gateways().saturating_sub(4) / 8).min(MAX_TIMESTAMPS)
+    }
+
+    /// Returns how many bytes the IPv4 options and the IPv6 extension header turned
+    /// on add to a probe, padding included.
+    fn header_extra(&self) -> usize {
+        let gateways = match self.gateways.len() {
+            0 => 0,
+            n => 4 + 4 * n,
+        };
+        let recorded = if self.record_route {
+            (3 + 4 * self.record_route_slots()).div_ceil(4) * 4
+        } else if self.timestamps {
+            4 + 8 * self.timestamp_slots()
+        } else {
+            0
+        };
+        let extension = if self.extension_header.is_some() {
+            8
+        } else {
+            0
+        };
+        gateways + recorded + extension
+    }
+
+    /// Returns how many bytes of the 40 bytes of IPv4 options the padded loose
+    /// source route leaves.
+    fn room_after_gateways(&self) -> usize {
+        match self.gateways.len() {
+            0 => 40,
+            n => 40usize.saturating_sub(4 + 4 * n),
+        }
+    }
+
+    /// Returns true if `hop` is close enough to `destination` to confirm the latter.
+    ///
+    /// Both have to share the configured prefix, prefix lengths past the address
+    /// width compare the whole address.
+    pub fn is_close(&self, hop: IpAddr, destination: IpAddr) -> bool {
+        match (hop, destination) {
+            (IpAddr::V4(hop), IpAddr::V4(destination)) => {
+                let prefix = u32::from(self.confirm_prefix_v4.min(32));
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                u32::from(hop) & mask == u32::from(destination) & mask
+            }
+            (IpAddr::V6(hop), IpAddr::V6(destination)) => {
+                let prefix = u32::from(self.confirm_prefix_v6.min(128));
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                u128::from(hop) & mask == u128::from(destination) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Rejects destinations that can not be traced as a single host.
+fn check_destination(address: IpAddr) -> Result<(), TraceRouteError> {
+    if address.is_unspecified() {
+        return Err(TraceRouteError::UnspecifiedDestination(address));
+    }
+    if let IpAddr::V4(v4) = address {
+        if v4.octets()[0] == 0 {
+            return Err(TraceRouteError::ZeroNetworkDestination(address));
+        }
+    }
+    if address.is_multicast() {
+        return Err(TraceRouteError::MulticastDestination(address));
+    }
+    if let IpAddr::V4(v4) = address {
+        if v4.is_broadcast() {
+            return Err(TraceRouteError::BroadcastDestination(address));
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if `address` is in a range reserved for documentation, RFC 5737,
+/// RFC 3849 and RFC 9637.
+fn is_documentation(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(v4) => matches!(
+            v4.octets(),
+            [192, 0, 2, _] | [198, 51, 100, _] | [203, 0, 113, _]
+        ),
+        IpAddr::V6(v6) => match v6.segments() {
+            [0x2001, 0x0db8, ..] => true,
+            // 3fff::/20
+            [0x3fff, second, ..] => second < 0x1000,
+            _ => false,
+        },
+    }
+}
+
+/// Returns the IPv4 address carried by an IPv4-mapped or IPv4-compatible address.
+///
+/// `::` and `::1` are compatible by the bit pattern only and are left alone.
+pub(crate) fn embedded_ipv4(address: Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(v4) = address.to_ipv4_mapped() {
+        return Some(v4);
+    }
+    match address.segments() {
+        [0, 0, 0, 0, 0, 0, hi, lo] if hi != 0 => {
+            Some(Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo)))
+        }
+        _ => None,
+    }
+}
+
+fn local_v4_networks() -> Vec<(Ipv4Addr, u8)> {
+    datalink::interfaces()
+        .into_iter()
+        .flat_map(|iface| iface.ips)
+        .filter_map(|network| match network {
+            IpNetwork::V4(network) => Some((network.ip(), network.prefix())),
+            IpNetwork::V6(_) => None,
+        })
+        .collect()
+}
+
+/// Returns true if `address` is the broadcast address of one of `networks`.
+///
+/// /31 and /32 networks have no broadcast address and are never matched.
+fn is_directed_broadcast(address: Ipv4Addr, networks: &[(Ipv4Addr, u8)]) -> bool {
+    networks.iter().any(|&(ip, prefix)| {
+        if prefix >= 31 {
+            return false;
+        }
+        let host_mask = u32::MAX >> prefix;
+        u32::from(address) == u32::from(ip) | host_mask
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interfaces_resolve_by_name_and_index() {
+        let device = |name: &str, index| NetworkInterface {
+            name: name.to_string(),
+            description: String::new(),
+            index,
+            mac: None,
+            ips: Vec::new(),
+            flags: 0,
+        };
+        let devices = [device("lo", 1), device("eth0", 2), device("eth0.100", 7)];
+        let name_of = |sel: InterfaceSel| sel.resolve(&devices).map(|device| device.name.as_str());
+        assert_eq!(name_of("eth0".into()), Some("eth0"));
+        assert_eq!(name_of(InterfaceSel::Index(7)), Some("eth0.100"));
+        assert_eq!(name_of("eth1".into()), None);
+        // The device of a stale index was removed or renamed and got a new one.
+        assert_eq!(name_of(InterfaceSel::Index(3)), None);
+    }
+
+    #[test]
+    fn rejects_multicast_and_broadcast() {
+        let config = TraceRouteConfig::default();
+        for addr in &["224.0.0.1", "239.255.255.250", "ff02::1", "ff05::1:3"] {
+            let addr: IpAddr = addr.parse().unwrap();
+            assert_eq!(
+                config.validate(addr),
+                Err(TraceRouteError::MulticastDestination(addr))
+            );
+        }
+        let broadcast = IpAddr::from([255, 255, 255, 255]);
+        assert_eq!(
+            config.validate(broadcast),
+            Err(TraceRouteError::BroadcastDestination(broadcast))
+        );
+    }
+
+    #[test]
+    fn allows_unicast_and_escape_hatch() {
+        let config = TraceRouteConfig::default();
+        for addr in &["93.184.216.34", "223.255.255.255", "2606:2800:220:1::1"] {
+            assert_eq!(config.validate(addr.parse().unwrap()), Ok(()));
+        }
+        let config = TraceRouteConfig {
+            allow_special_destinations: true,
+            ..TraceRouteConfig::default()
+        };
+        for addr in &["224.0.0.1", "255.255.255.255", "ff02::1"] {
+            assert_eq!(config.validate(addr.parse().unwrap()), Ok(()));
+        }
+    }
+
+    #[test]
+    fn rejects_unspecified_and_zero_network() {
+        let config = TraceRouteConfig::default();
+        for addr in &["0.0.0.0", "::"] {
+            let addr: IpAddr = addr.parse().unwrap();
+            assert_eq!(
+                config.validate(addr),
+                Err(TraceRouteError::UnspecifiedDestination(addr))
+            );
+        }
+        for addr in &["0.0.0.1", "0.255.255.255"] {
+            let addr: IpAddr = addr.parse().unwrap();
+            assert_eq!(
+                config.validate(addr),
+                Err(TraceRouteError::ZeroNetworkDestination(addr))
+            );
+        }
+        assert_eq!(config.validate("1.0.0.1".parse().unwrap()), Ok(()));
+        let config = TraceRouteConfig {
+            allow_special_destinations: true,
+            ..TraceRouteConfig::default()
+        };
+        for addr in &["0.0.0.0", "0.1.2.3", "::"] {
+            assert_eq!(config.validate(addr.parse().unwrap()), Ok(()));
+        }
+    }
+
+    #[test]
+    fn documentation_destinations_need_opting_in() {
+        let config = TraceRouteConfig::default();
+        let documentation = [
+            "192.0.2.1",
+            "198.51.100.7",
+            "203.0.113.255",
+            "2001:db8::1",
+            "3fff:fff::1",
+        ];
+        for addr in &documentation {
+            let addr: IpAddr = addr.parse().unwrap();
+            // Traces over a given backend may use them.
+            assert_eq!(config.validate(addr), Ok(()));
+            assert_eq!(
+                config.check_documentation(addr),
+                Err(TraceRouteError::DocumentationDestination(addr))
+            );
+        }
+        for addr in &["192.0.3.1", "203.0.114.1", "2001:db9::1", "3fff:1000::1"] {
+            assert_eq!(config.check_documentation(addr.parse().unwrap()), Ok(()));
+        }
+        let config = TraceRouteConfig {
+            allow_documentation_destinations: true,
+            ..TraceRouteConfig::default()
+        };
+        for addr in &documentation {
+            assert_eq!(config.check_documentation(addr.parse().unwrap()), Ok(()));
+        }
+    }
+
+    #[test]
+    fn begin_ttl_starts_at_one() {
+        let address = IpAddr::from([93, 184, 216, 34]);
+        let config = TraceRouteConfig {
+            begin_ttl: 0,
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(config.validate(address), Err(TraceRouteError::BadBeginTtl));
+        let config = TraceRouteConfig {
+            begin_ttl: 1,
+            max_ttl: 1,
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(config.validate(address), Ok(()));
+    }
+
+    #[test]
+    fn sequence_plans_number_and_decode() {
+        let sequential = SequencePlan::Sequential {
+            start: 65534,
+            stride: 2,
+        };
+        assert_eq!(sequential.sequence(0, 1, 1), 65534);
+        assert_eq!(sequential.sequence(1, 1, 2), 0);
+        assert_eq!(sequential.decode(0), None);
+        let plan = SequencePlan::PerTtl {
+            start: 1000,
+            stride: 10,
+        };
+        assert_eq!(plan.sequence(7, 3, 2), 1032);
+        assert_eq!(plan.decode(1032), Some((3, 2)));
+        assert_eq!(plan.decode(1030), None);
+        assert_eq!(plan.decode(999), None);
+
+        let address = IpAddr::from([93, 184, 216, 34]);
+        let config = TraceRouteConfig {
+            sequence_plan: SequencePlan::PerTtl {
+                start: 0,
+                stride: 4,
+            },
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(
+            config.validate(address),
+            Err(TraceRouteError::BadSequencePlan)
+        );
+        let config = TraceRouteConfig {
+            max_tries: 3,
+            ..config
+        };
+        assert_eq!(config.validate(address), Ok(()));
+    }
+
+    #[test]
+    fn size_bounds() {
+        let address = IpAddr::from([93, 184, 216, 34]);
+        let mut config = TraceRouteConfig {
+            size: MAX_PROBE_SIZE,
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(config.validate(address), Ok(()));
+        config.size = 70000;
+        assert_eq!(
+            config.validate(address),
+            Err(TraceRouteError::SizeTooLarge {
+                max: MAX_PROBE_SIZE
+            })
+        );
+        config.size = 11;
+        assert_eq!(config.validate(address), Err(TraceRouteError::BadSize));
+        config.size = MAX_PROBE_SIZE;
+        config.record_route = true;
+        assert_eq!(
+            config.validate(address),
+            Err(TraceRouteError::SizeTooLarge {
+                max: MAX_PROBE_SIZE - 40
+            })
+        );
+        config.size = MAX_PROBE_SIZE - 40;
+        assert_eq!(config.validate(address), Ok(()));
+    }
+
+    #[test]
+    fn zero_timeouts_are_spelled_none() {
+        let address = IpAddr::from([93, 184, 216, 34]);
+        let mut config = TraceRouteConfig {
+            timeout: None,
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(config.validate(address), Ok(()));
+        config.timeout = Some(Duration::from_millis(0));
+        assert_eq!(config.validate(address), Err(TraceRouteError::BadTimeout));
+    }
+
+    #[test]
+    fn gateway_bounds() {
+        let address = IpAddr::from([93, 184, 216, 34]);
+        let mut config = TraceRouteConfig {
+            gateways: vec![Ipv4Addr::new(192, 0, 2, 1); MAX_GATEWAYS],
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(config.validate(address), Ok(()));
+        let bad = Err(TraceRouteError::BadGateways { max: MAX_GATEWAYS });
+        assert_eq!(config.validate("2001:db8::1".parse().unwrap()), bad);
+        config.gateways.push(Ipv4Addr::new(192, 0, 2, 2));
+        assert_eq!(config.validate(address), bad);
+    }
+
+    #[test]
+    fn port_fallback_bounds() {
+        let address = IpAddr::from([93, 184, 216, 34]);
+        let mut fallback = PortFallback {
+            candidates: vec![53, 443],
+            trigger_after: 3,
+        };
+        let config = |fallback: &PortFallback| TraceRouteConfig {
+            max_tries: 4,
+            port_fallback: Some(fallback.clone()),
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(config(&fallback).validate(address), Ok(()));
+        fallback.trigger_after = 4;
+        let bad = Err(TraceRouteError::BadPortFallback);
+        assert_eq!(config(&fallback).validate(address), bad);
+        fallback.trigger_after = 1;
+        fallback.candidates.clear();
+        assert_eq!(config(&fallback).validate(address), bad);
+    }
+
+    #[test]
+    fn protocol_fallback_bounds() {
+        let address = IpAddr::from([93, 184, 216, 34]);
+        let mut config = TraceRouteConfig {
+            protocol_fallback: Some(ProtocolFallback {
+                order: vec![TraceRouteProtocol::Icmp],
+                give_up_after_hops: 3,
+            }),
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(config.validate(address), Ok(()));
+        let bad = Err(TraceRouteError::BadProtocolFallback);
+        if let Some(fallback) = &mut config.protocol_fallback {
+            fallback.give_up_after_hops = 0;
+        }
+        assert_eq!(config.validate(address), bad);
+        if let Some(fallback) = &mut config.protocol_fallback {
+            fallback.give_up_after_hops = 3;
+            fallback.order.clear();
+        }
+        assert_eq!(config.validate(address), bad);
+    }
+
+    #[test]
+    fn windows_leave_out_sequential_options() {
+        let address = IpAddr::from([93, 184, 216, 34]);
+        let windowed = TraceRouteConfig {
+            window: Some(4),
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(windowed.validate(address), Ok(()));
+        let conflicting = [
+            TraceRouteConfig {
+                warmup: true,
+                ..windowed.clone()
+            },
+            TraceRouteConfig {
+                protocol_fallback: Some(ProtocolFallback {
+                    order: vec![TraceRouteProtocol::Icmp],
+                    give_up_after_hops: 3,
+                }),
+                ..windowed.clone()
+            },
+            TraceRouteConfig {
+                retry_on_total_failure: Some(RetrySpec {
+                    attempts: 1,
+                    delay: Duration::from_millis(100),
+                }),
+                ..windowed.clone()
+            },
+            TraceRouteConfig {
+                confirm_silent_destination: true,
+                ..windowed.clone()
+            },
+            TraceRouteConfig {
+                continue_past_destination: true,
+                ..windowed.clone()
+            },
+            TraceRouteConfig {
+                protocol: TraceRouteProtocol::Raw(47),
+                ..windowed.clone()
+            },
+        ];
+        for config in &conflicting {
+            assert_eq!(
+                config.validate(address),
+                Err(TraceRouteError::BadWindow),
+                "{:?}",
+                config
+            );
+            // The same options run sequentially.
+            let sequential = TraceRouteConfig {
+                window: None,
+                ..config.clone()
+            };
+            assert_eq!(sequential.validate(address), Ok(()));
+        }
+        // A window of one sends raw probes like a sequential trace.
+        let raw = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Raw(47),
+            window: Some(1),
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(raw.validate(address), Ok(()));
+        let dccp = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Dccp,
+            ..windowed
+        };
+        assert_eq!(dccp.validate(address), Ok(()));
+    }
+
+    #[test]
+    fn forced_backend_carries_the_protocol() {
+        let address = IpAddr::from([93, 184, 216, 34]);
+        let mut config = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Icmp,
+            backend: Some(BackendKind::DgramIcmp),
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(config.validate(address), Ok(()));
+        config.backend = Some(BackendKind::UdpErrqueue);
+        assert_eq!(
+            config.validate(address),
+            Err(TraceRouteError::BadBackend(BackendKind::UdpErrqueue))
+        );
+    }
+
+    #[test]
+    fn record_route_room() {
+        let address = IpAddr::from([93, 184, 216, 34]);
+        let mut config = TraceRouteConfig {
+            record_route: true,
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(config.record_route_slots(), MAX_RECORDED_ROUTE);
+        assert_eq!(config.validate(address), Ok(()));
+        assert_eq!(
+            config.validate("2001:db8::1".parse().unwrap()),
+            Err(TraceRouteError::BadRecordRoute)
+        );
+        config.gateways = vec![Ipv4Addr::new(192, 0, 2, 1); 7];
+        assert_eq!(config.record_route_slots(), 1);
+        assert_eq!(config.validate(address), Ok(()));
+        config.gateways.push(Ipv4Addr::new(192, 0, 2, 2));
+        assert_eq!(
+            config.validate(address),
+            Err(TraceRouteError::BadRecordRoute)
+        );
+    }
+
+    #[test]
+    fn timestamp_room() {
+        let address = IpAddr::from([93, 184, 216, 34]);
+        let mut config = TraceRouteConfig {
+            timestamps: true,
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(config.timestamp_slots(), MAX_TIMESTAMPS);
+        assert_eq!(config.validate(address), Ok(()));
+        config.gateways = vec![Ipv4Addr::new(192, 0, 2, 1); 6];
+        assert_eq!(config.timestamp_slots(), 1);
+        assert_eq!(config.validate(address), Ok(()));
+        config.gateways.push(Ipv4Addr::new(192, 0, 2, 2));
+        assert_eq!(
+            config.validate(address),
+            Err(TraceRouteError::BadTimestamps)
+        );
+        config.gateways.clear();
+        config.record_route = true;
+        assert_eq!(
+            config.validate(address),
+            Err(TraceRouteError::BadTimestamps)
+        );
+    }
+
+    #[test]
+    fn unmaps_embedded_ipv4() {
+        let cases = [
+            (
+                "::ffff:93.184.216.34",
+                Some(Ipv4Addr::new(93, 184, 216, 34)),
+            ),
+            ("::93.184.216.34", Some(Ipv4Addr::new(93, 184, 216, 34))),
+            ("::1", None),
+            ("::", None),
+            ("::1:0:1", None),
+            ("2001:db8::5db8:d822", None),
+        ];
+        for (addr, expected) in &cases {
+            assert_eq!(embedded_ipv4(addr.parse().unwrap()), *expected, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn closeness_by_prefix() {
+        let mut config = TraceRouteConfig::default();
+        let destination = IpAddr::from([198, 51, 100, 7]);
+        assert!(config.is_close(IpAddr::from([198, 51, 100, 1]), destination));
+        assert!(!config.is_close(IpAddr::from([198, 51, 101, 1]), destination));
+        config.confirm_prefix_v4 = 0;
+        assert!(config.is_close(IpAddr::from([10, 0, 0, 1]), destination));
+        let destination: IpAddr = "2001:db8:1:2::7".parse().unwrap();
+        assert!(config.is_close("2001:db8:1:ff::1".parse().unwrap(), destination));
+        assert!(!config.is_close("2001:db8:2::1".parse().unwrap(), destination));
+        assert!(!config.is_close(IpAddr::from([198, 51, 100, 1]), destination));
+    }
+
+    #[test]
+    fn directed_broadcast_math() {
+        let networks = [
+            (Ipv4Addr::new(192, 168, 1, 20), 24),
+            (Ipv4Addr::new(10, 1, 2, 3), 31),
+        ];
+        assert!(is_directed_broadcast(
+            Ipv4Addr::new(192, 168, 1, 255),
+            &networks
+        ));
+        assert!(!is_directed_broadcast(
+            Ipv4Addr::new(192, 168, 1, 254),
+            &networks
+        ));
+        assert!(!is_directed_broadcast(
+            Ipv4Addr::new(10, 1, 2, 3),
+            &networks
+        ));
+        assert!(!is_directed_broadcast(
+            Ipv4Addr::new(192, 168, 2, 255),
+            &networks
+        ));
+    }
+
+    #[test]
+    fn terminal_policy_rules() {
+        let udp = TerminalPolicy::default_for(TraceRouteProtocol::Udp, false);
+        assert!(udp.ends(3, 3, true));
+        assert!(udp.ends(3, 13, false));
+        assert!(!udp.ends(11, 0, false));
+        let raw = TerminalPolicy::default_for(TraceRouteProtocol::Raw(253), true);
+        assert!(raw.ends(4, 1, true));
+        assert!(!raw.ends(4, 0, true));
+        let icmp = TerminalPolicy::default_for(TraceRouteProtocol::Icmp, false);
+        assert!(icmp.ends(0, 0, true));
+        assert!(!icmp.ends(0, 0, false));
+
+        let policy = TerminalPolicy {
+            rules: vec![TerminalRule {
+                icmp_type: 3,
+                code: Some(13),
+                from: TerminalSource::Destination,
+            }],
+        };
+        assert!(policy.ends(3, 13, true));
+        assert!(!policy.ends(3, 13, false));
+        assert!(!policy.ends(3, 3, true));
+    }
+}
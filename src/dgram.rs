@@ -0,0 +1,517 @@
+//! Backend built on unprivileged datagram sockets.
+//!
+//! Linux lets any user in `net.ipv4.ping_group_range` open ICMP datagram sockets,
+//! and lets everyone read the ICMP errors UDP sockets get through `IP_RECVERR`.
+//! The kernel owns the IP header then, so the probes lose their options.
+use crate::backend::{
+    apply_to_fds, device_name, set_socket_option, sockaddr, sockaddr_addr, ProbeBackend, ReplyKind,
+};
+use pnet::util;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+/// Number of sent probes kept to rebuild the messages quoting them.
+const SENT_KEPT: usize = 64;
+
+/// `SO_EE_ORIGIN_ICMP` and `SO_EE_ORIGIN_ICMP6` of `linux/errqueue.h`.
+const ORIGIN_ICMP: u8 = 2;
+const ORIGIN_ICMP6: u8 = 3;
+
+/// `struct sock_extended_err` of `linux/errqueue.h`, the offending address follows it.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ExtendedErr {
+    errno: u32,
+    origin: u8,
+    kind: u8,
+    code: u8,
+    pad: u8,
+    info: u32,
+    data: u32,
+}
+
+/// This enum tells which datagram sockets a `DgramBackend` opens.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum DgramProtocol {
+    /// ICMP or ICMPv6 echo sockets.
+    Icmp,
+    /// UDP sockets reading ICMP errors from their error queue.
+    Udp,
+}
+
+/// This struct is a backend sending probes over one datagram socket.
+///
+/// The TTL and traffic class are taken from each probe and applied to the socket.
+/// Errors of the error queue are turned back into ICMP messages quoting the probe
+/// they were about, so the worker can match them like raw socket replies.
+pub(crate) struct DgramBackend {
+    fd: libc::c_int,
+    v4: bool,
+    protocol: DgramProtocol,
+    hop_limit: Option<u8>,
+    traffic_class: Option<u8>,
+    sent: VecDeque<Vec<u8>>,
+}
+
+impl DgramBackend {
+    /// Opens a datagram socket of the given family and protocol.
+    pub(crate) fn open(v4: bool, protocol: DgramProtocol) -> io::Result<DgramBackend> {
+        let (domain, number) = match (v4, protocol) {
+            (true, DgramProtocol::Icmp) => (libc::AF_INET, libc::IPPROTO_ICMP),
+            (false, DgramProtocol::Icmp) => (libc::AF_INET6, libc::IPPROTO_ICMPV6),
+            (true, DgramProtocol::Udp) => (libc::AF_INET, libc::IPPROTO_UDP),
+            (false, DgramProtocol::Udp) => (libc::AF_INET6, libc::IPPROTO_UDP),
+        };
+        let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, number) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let backend = DgramBackend {
+            fd,
+            v4,
+            protocol,
+            hop_limit: None,
+            traffic_class: None,
+            sent: VecDeque::with_capacity(SENT_KEPT),
+        };
+        let (level, recverr) = backend.ip_level(libc::IP_RECVERR, libc::IPV6_RECVERR);
+        set_option(fd, level, recverr, 1)?;
+        Ok(backend)
+    }
+
+    /// Binds the socket to the network device `interface`.
+    pub(crate) fn bind_to_device(&self, interface: &str) -> io::Result<()> {
+        apply_to_fds(&[self.fd], &device_name(interface), |fd, value| {
+            set_socket_option(fd, libc::SO_BINDTODEVICE, value)
+        })
+    }
+
+    /// Marks the probes with `mark` for policy routing.
+    pub(crate) fn set_mark(&self, mark: u32) -> io::Result<()> {
+        apply_to_fds(&[self.fd], &mark.to_ne_bytes(), |fd, value| {
+            set_socket_option(fd, libc::SO_MARK, value)
+        })
+    }
+
+    /// Returns the option level of the socket family and the option for it.
+    fn ip_level(&self, v4: libc::c_int, v6: libc::c_int) -> (libc::c_int, libc::c_int) {
+        if self.v4 {
+            (libc::IPPROTO_IP, v4)
+        } else {
+            (libc::IPPROTO_IPV6, v6)
+        }
+    }
+
+    /// Applies the hop limit and traffic class of a probe, unless they are set already.
+    fn apply_header(&mut self, hop_limit: u8, traffic_class: u8) -> io::Result<()> {
+        if self.hop_limit != Some(hop_limit) {
+            let (level, option) = self.ip_level(libc::IP_TTL, libc::IPV6_UNICAST_HOPS);
+            set_option(self.fd, level, option, hop_limit.into())?;
+            self.hop_limit = Some(hop_limit);
+        }
+        if self.traffic_class != Some(traffic_class) {
+            let (level, option) = self.ip_level(libc::IP_TOS, libc::IPV6_TCLASS);
+            set_option(self.fd, level, option, traffic_class.into())?;
+            self.traffic_class = Some(traffic_class);
+        }
+        Ok(())
+    }
+
+    /// Returns the latest probe sent whose transport header `matches`.
+    fn sent_probe<F: Fn(&[u8]) -> bool>(&self, matches: F) -> Option<&Vec<u8>> {
+        let header = if self.v4 { 20 } else { 40 };
+        self.sent
+            .iter()
+            .rev()
+            .find(|probe| probe.get(header..).is_some_and(&matches))
+    }
+
+    /// Reads one error of the error queue and rebuilds the ICMP message it came in.
+    fn recv_error(&mut self) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+        let mut data = [0u8; 2048];
+        let mut control = [0u8; 512];
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+        let len =
+            unsafe { libc::recvmsg(self.fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+        if len == -1 {
+            return would_block(io::Error::last_os_error());
+        }
+        let (err, offender) = match extended_err(&msg, &control) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        if err.origin != ORIGIN_ICMP && err.origin != ORIGIN_ICMP6 {
+            return Ok(None);
+        }
+        let payload = &data[..len as usize];
+        let probe = match self.protocol {
+            // The payload is the echo request the kernel sent.
+            DgramProtocol::Icmp => {
+                let sequence = payload.get(6..8);
+                self.sent_probe(|transport| transport.get(6..8) == sequence)
+            }
+            // The payload is what followed the UDP header, the port tells the probe.
+            DgramProtocol::Udp => {
+                let port = sockaddr_addr(&name).map(|(_, port)| port);
+                self.sent_probe(|transport| {
+                    transport
+                        .get(2..4)
+                        .map(|p| u16::from_be_bytes([p[0], p[1]]))
+                        == port
+                })
+            }
+        };
+        match (probe, offender) {
+            (Some(probe), Some(from)) => Ok(Some((icmp_message(&err, probe, self.v4), from))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Reads one datagram, only echo replies are kept.
+    fn recv_data(&mut self) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+        let mut data = [0u8; 2048];
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut name_len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let len = unsafe {
+            libc::recvfrom(
+                self.fd,
+                data.as_mut_ptr() as *mut libc::c_void,
+                data.len(),
+                libc::MSG_DONTWAIT,
+                &mut name as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                &mut name_len,
+            )
+        };
+        if len == -1 {
+            return would_block(io::Error::last_os_error());
+        }
+        let mut message = data[..len as usize].to_vec();
+        let from = match sockaddr_addr(&name) {
+            Some((from, _)) if self.protocol == DgramProtocol::Icmp => from,
+            _ => return Ok(None),
+        };
+        if message.len() < 8 {
+            return Ok(None);
+        }
+        // The kernel replaced the identifier of the probe with the socket's port.
+        let sequence = message[6..8].to_vec();
+        let identifier = self
+            .sent_probe(|transport| transport.get(6..8) == Some(&sequence[..]))
+            .map(|probe| {
+                let header = if self.v4 { 20 } else { 40 };
+                [probe[header + 4], probe[header + 5]]
+            });
+        match identifier {
+            Some(identifier) => {
+                message[4..6].copy_from_slice(&identifier);
+                if self.v4 {
+                    set_checksum(&mut message);
+                }
+                Ok(Some((message, from)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Drop for DgramBackend {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl ProbeBackend for DgramBackend {
+    fn send_to(&mut self, packet: &[u8], destination: IpAddr) -> io::Result<usize> {
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let (header, protocol, hop_limit, traffic_class) = if self.v4 {
+            let header = usize::from(packet.first().copied().unwrap_or(0) & 0x0f) * 4;
+            if header > 20 {
+                return Err(invalid("IPv4 options need raw sockets"));
+            }
+            let fields = packet
+                .get(..20)
+                .ok_or_else(|| invalid("truncated IP packet"))?;
+            (20, fields[9], fields[8], fields[1])
+        } else {
+            let fields = packet
+                .get(..40)
+                .ok_or_else(|| invalid("truncated IP packet"))?;
+            (
+                40,
+                fields[6],
+                fields[7],
+                (fields[0] << 4) | (fields[1] >> 4),
+            )
+        };
+        let expected = match (self.protocol, self.v4) {
+            (DgramProtocol::Icmp, true) => libc::IPPROTO_ICMP,
+            (DgramProtocol::Icmp, false) => libc::IPPROTO_ICMPV6,
+            (DgramProtocol::Udp, _) => libc::IPPROTO_UDP,
+        };
+        if libc::c_int::from(protocol) != expected {
+            return Err(invalid("the socket carries another protocol"));
+        }
+        let transport = packet
+            .get(header..header + 8)
+            .ok_or_else(|| invalid("truncated IP packet"))?;
+        let (data, port) = match self.protocol {
+            DgramProtocol::Icmp => (&packet[header..], 0),
+            DgramProtocol::Udp => (
+                &packet[header + 8..],
+                u16::from_be_bytes([transport[2], transport[3]]),
+            ),
+        };
+        self.apply_header(hop_limit, traffic_class)?;
+        let (name, name_len) = sockaddr(destination, port);
+        let res = unsafe {
+            libc::sendto(
+                self.fd,
+                data.as_ptr() as *const libc::c_void,
+                data.len(),
+                0,
+                &name as *const libc::sockaddr_storage as *const libc::sockaddr,
+                name_len,
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if self.sent.len() == SENT_KEPT {
+            self.sent.pop_front();
+        }
+        self.sent.push_back(packet.to_vec());
+        Ok(packet.len())
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let mut pollfd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let millis = left
+                .as_micros()
+                .div_ceil(1000)
+                .min(libc::c_int::MAX as u128);
+            let res = unsafe { libc::poll(&mut pollfd, 1, millis as libc::c_int) };
+            if res == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            if res == 0 {
+                return Ok(None);
+            }
+            let reply = if pollfd.revents & libc::POLLERR != 0 {
+                self.recv_error()?
+            } else {
+                self.recv_data()?
+            };
+            if reply.is_some() {
+                return Ok(reply);
+            }
+            if left.is_zero() {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn recv_reply(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<(ReplyKind, Vec<u8>, IpAddr)>> {
+        Ok(self
+            .recv_timeout(timeout)?
+            .map(|(message, addr)| (ReplyKind::Icmp, message, addr)))
+    }
+
+    fn reply_fds(&self) -> Vec<libc::c_int> {
+        vec![self.fd]
+    }
+}
+
+/// Sets the integer socket option `option` of `level` on `fd`.
+fn set_option(
+    fd: libc::c_int,
+    level: libc::c_int,
+    option: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Turns an empty non-blocking read into `None`.
+fn would_block<T>(err: io::Error) -> io::Result<Option<T>> {
+    match err.kind() {
+        io::ErrorKind::WouldBlock => Ok(None),
+        _ => Err(err),
+    }
+}
+
+/// Finds the extended error of `msg` and the address of the router that sent it.
+fn extended_err(msg: &libc::msghdr, control: &[u8]) -> Option<(ExtendedErr, Option<IpAddr>)> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        let is_err = (header.cmsg_level == libc::IPPROTO_IP
+            && header.cmsg_type == libc::IP_RECVERR)
+            || (header.cmsg_level == libc::IPPROTO_IPV6 && header.cmsg_type == libc::IPV6_RECVERR);
+        if is_err {
+            let data = unsafe { libc::CMSG_DATA(cmsg) };
+            let start = data as usize - control.as_ptr() as usize;
+            let end = (cmsg as usize - control.as_ptr() as usize) + header.cmsg_len as usize;
+            let bytes = control.get(start..end.min(control.len()))?;
+            return parse_extended_err(bytes);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(msg, cmsg) };
+    }
+    None
+}
+
+/// Parses a `sock_extended_err` and the offender address following it.
+fn parse_extended_err(bytes: &[u8]) -> Option<(ExtendedErr, Option<IpAddr>)> {
+    let size = mem::size_of::<ExtendedErr>();
+    if bytes.len() < size {
+        return None;
+    }
+    let err = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const ExtendedErr) };
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let offender = &bytes[size..];
+    let len = offender.len().min(mem::size_of::<libc::sockaddr_storage>());
+    unsafe {
+        ptr::copy_nonoverlapping(
+            offender.as_ptr(),
+            &mut name as *mut libc::sockaddr_storage as *mut u8,
+            len,
+        );
+    }
+    Some((err, sockaddr_addr(&name).map(|(addr, _)| addr)))
+}
+
+/// Rebuilds the ICMP or ICMPv6 message of `err` quoting `probe`.
+fn icmp_message(err: &ExtendedErr, probe: &[u8], v4: bool) -> Vec<u8> {
+    let mut message = vec![err.kind, err.code, 0, 0, 0, 0, 0, 0];
+    // The next hop MTU of "fragmentation needed" and "packet too big".
+    if v4 && err.kind == 3 && err.code == 4 {
+        message[6..8].copy_from_slice(&(err.info as u16).to_be_bytes());
+    } else if !v4 && err.kind == 2 {
+        message[4..8].copy_from_slice(&err.info.to_be_bytes());
+    }
+    message.extend_from_slice(probe);
+    if v4 {
+        set_checksum(&mut message);
+    }
+    message
+}
+
+/// Writes the ICMP checksum of `message`, ICMPv6 ones are left to the kernel.
+fn set_checksum(message: &mut [u8]) {
+    let checksum = util::checksum(message, 1);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn socket_addresses_round_trip() {
+        for &(addr, port) in &[
+            (IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7)), 33434),
+            (IpAddr::V6("2001:db8::7".parse().unwrap()), 53),
+        ] {
+            let (name, _) = sockaddr(addr, port);
+            assert_eq!(sockaddr_addr(&name), Some((addr, port)));
+        }
+    }
+
+    #[test]
+    fn errors_become_icmp_messages() {
+        let (offender, _) = sockaddr(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 0);
+        let err = ExtendedErr {
+            errno: libc::EMSGSIZE as u32,
+            origin: ORIGIN_ICMP,
+            kind: 3,
+            code: 4,
+            pad: 0,
+            info: 1400,
+            data: 0,
+        };
+        let mut bytes = unsafe {
+            std::slice::from_raw_parts(
+                &err as *const ExtendedErr as *const u8,
+                mem::size_of::<ExtendedErr>(),
+            )
+        }
+        .to_vec();
+        bytes.extend_from_slice(unsafe {
+            std::slice::from_raw_parts(
+                &offender as *const _ as *const u8,
+                mem::size_of::<libc::sockaddr_in>(),
+            )
+        });
+        let (parsed, from) = parse_extended_err(&bytes).unwrap();
+        assert_eq!(from, Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))));
+        let probe = [0x45u8; 28];
+        let message = icmp_message(&parsed, &probe, true);
+        assert_eq!(&message[..2], &[3, 4]);
+        assert_eq!(&message[4..8], &[0, 0, 0x05, 0x78]);
+        assert_eq!(crate::reply::malformed_v4(&message), None);
+        assert_eq!(&message[8..], &probe[..]);
+    }
+
+    #[test]
+    fn udp_probes_to_closed_loopback_ports_are_reported() {
+        let mut backend = DgramBackend::open(true, DgramProtocol::Udp).unwrap();
+        let mut probe = vec![0u8; 36];
+        probe[0] = 0x45;
+        probe[8] = 64;
+        probe[9] = 17;
+        probe[20..24].copy_from_slice(&[0x82, 0x9a, 0x82, 0x9b]);
+        probe[24..26].copy_from_slice(&16u16.to_be_bytes());
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        backend.send_to(&probe, loopback).unwrap();
+        let (message, from) = backend
+            .recv_timeout(Duration::from_secs(1))
+            .unwrap()
+            .expect("the port unreachable");
+        assert_eq!(from, loopback);
+        assert_eq!(&message[..2], &[3, 3]);
+        assert_eq!(&message[8..], &probe[..]);
+    }
+}
@@ -0,0 +1,394 @@
+//! Differences between two traces of one destination.
+use crate::HopFound;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// This struct tells `diff_traces` which differences count.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DiffOptions {
+    /// Round trip times of a TTL closer than this are not reported.
+    pub min_rtt_delta: Duration,
+    /// Leaves out TTLs that only went silent or started answering, which rate
+    /// limiting routers do between any two traces.
+    pub ignore_silence: bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> DiffOptions {
+        DiffOptions {
+            min_rtt_delta: Duration::from_millis(10),
+            ignore_silence: false,
+        }
+    }
+}
+
+/// This enum is a difference at one TTL of two traces.
+///
+/// Addresses are those that answered the TTL, several for traces whose hops
+/// were found over more than one path, in address order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HopChange {
+    /// Only the second trace probed the TTL.
+    Added { ttl: u8, addrs: Vec<IpAddr> },
+    /// Only the first trace probed the TTL.
+    Removed { ttl: u8, addrs: Vec<IpAddr> },
+    /// Both traces were answered at the TTL, by other addresses.
+    Changed {
+        ttl: u8,
+        before: Vec<IpAddr>,
+        after: Vec<IpAddr>,
+    },
+    /// The TTL answered in the first trace only.
+    Silenced { ttl: u8, before: Vec<IpAddr> },
+    /// The TTL answered in the second trace only.
+    Answered { ttl: u8, after: Vec<IpAddr> },
+    /// The same addresses answered the TTL, the fastest reply took longer or
+    /// shorter by at least `DiffOptions::min_rtt_delta`.
+    Rtt {
+        ttl: u8,
+        before: Duration,
+        after: Duration,
+    },
+}
+
+impl HopChange {
+    /// Returns the TTL the change is at.
+    pub fn ttl(&self) -> u8 {
+        match self {
+            HopChange::Added { ttl, .. }
+            | HopChange::Removed { ttl, .. }
+            | HopChange::Changed { ttl, .. }
+            | HopChange::Silenced { ttl, .. }
+            | HopChange::Answered { ttl, .. }
+            | HopChange::Rtt { ttl, .. } => *ttl,
+        }
+    }
+}
+
+/// Formats a change as one line, e.g. `~ 3  192.0.2.3 -> 192.0.2.7`.
+impl fmt::Display for HopChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = |time: &Duration| time.as_secs_f64() * 1000.0;
+        match self {
+            HopChange::Added { ttl, addrs } => write!(f, "+ {}  {}", ttl, Addrs(addrs)),
+            HopChange::Removed { ttl, addrs } => write!(f, "- {}  {}", ttl, Addrs(addrs)),
+            HopChange::Changed { ttl, before, after } => {
+                write!(f, "~ {}  {} -> {}", ttl, Addrs(before), Addrs(after))
+            }
+            HopChange::Silenced { ttl, before } => write!(f, "~ {}  {} -> *", ttl, Addrs(before)),
+            HopChange::Answered { ttl, after } => write!(f, "~ {}  * -> {}", ttl, Addrs(after)),
+            HopChange::Rtt { ttl, before, after } => write!(
+                f,
+                "~ {}  {:.1}ms -> {:.1}ms",
+                ttl,
+                millis(before),
+                millis(after)
+            ),
+        }
+    }
+}
+
+/// Addresses of a TTL, joined by spaces, `*` if none answered.
+struct Addrs<'a>(&'a [IpAddr]);
+
+impl fmt::Display for Addrs<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("*");
+        }
+        for (n, addr) in self.0.iter().enumerate() {
+            if n > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+/// This struct lists the differences of two traces, see `diff_traces`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TraceDiff {
+    /// Differences by TTL, at most one address change and one RTT change each.
+    pub changes: Vec<HopChange>,
+    /// True when both traces were answered but no address answered both.
+    pub disjoint: bool,
+}
+
+impl TraceDiff {
+    /// Returns true if the traces did not differ.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Returns the first TTL whose addresses differ, RTT changes do not count.
+    pub fn first_changed_ttl(&self) -> Option<u8> {
+        self.changes
+            .iter()
+            .find(|change| !matches!(change, HopChange::Rtt { .. }))
+            .map(HopChange::ttl)
+    }
+}
+
+/// Formats the changes one per line, `disjoint paths` first if no address is shared.
+impl fmt::Display for TraceDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.disjoint {
+            writeln!(f, "disjoint paths")?;
+        }
+        for change in &self.changes {
+            writeln!(f, "{}", change)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a trace found at one TTL.
+#[derive(Default)]
+struct Probed {
+    addrs: BTreeSet<IpAddr>,
+    fastest: Option<Duration>,
+}
+
+/// Gathers the hops of a trace by TTL.
+///
+/// Terminal hops without an address only mark the end of a trace and placeholders
+/// of hidden local hops were never probed, neither gets a TTL.
+fn by_ttl(hops: &[HopFound]) -> BTreeMap<u8, Probed> {
+    let mut ttls: BTreeMap<u8, Probed> = BTreeMap::new();
+    for hop in hops {
+        if hop.is_end_of_trace() || hop.local_hops.is_some() {
+            continue;
+        }
+        let probed = ttls.entry(hop.hop_count).or_default();
+        if let Some(addr) = hop.addr {
+            probed.addrs.insert(addr);
+            if let Some(time) = hop.time {
+                probed.fastest = Some(probed.fastest.map_or(time, |fastest| fastest.min(time)));
+            }
+        }
+    }
+    ttls
+}
+
+/// Compares two traces of one destination TTL by TTL, `a` before `b`.
+///
+/// Hops of either may come from several rounds or paths, the addresses of a TTL
+/// are compared as a set and its fastest replies against each other.
+pub fn diff_traces(a: &[HopFound], b: &[HopFound], options: &DiffOptions) -> TraceDiff {
+    let (before, after) = (by_ttl(a), by_ttl(b));
+    let ttls: BTreeSet<u8> = before.keys().chain(after.keys()).cloned().collect();
+    let list = |addrs: &BTreeSet<IpAddr>| addrs.iter().cloned().collect::<Vec<_>>();
+    let mut changes = Vec::new();
+    for ttl in ttls {
+        let change = match (before.get(&ttl), after.get(&ttl)) {
+            (Some(old), None) => Some(HopChange::Removed {
+                ttl,
+                addrs: list(&old.addrs),
+            }),
+            (None, Some(new)) => Some(HopChange::Added {
+                ttl,
+                addrs: list(&new.addrs),
+            }),
+            (Some(old), Some(new)) if old.addrs == new.addrs => {
+                if let (Some(was), Some(is)) = (old.fastest, new.fastest) {
+                    let delta = was.abs_diff(is);
+                    if delta >= options.min_rtt_delta && delta > Duration::from_secs(0) {
+                        changes.push(HopChange::Rtt {
+                            ttl,
+                            before: was,
+                            after: is,
+                        });
+                    }
+                }
+                None
+            }
+            (Some(old), Some(new)) if new.addrs.is_empty() => Some(HopChange::Silenced {
+                ttl,
+                before: list(&old.addrs),
+            })
+            .filter(|_| !options.ignore_silence),
+            (Some(old), Some(new)) if old.addrs.is_empty() => Some(HopChange::Answered {
+                ttl,
+                after: list(&new.addrs),
+            })
+            .filter(|_| !options.ignore_silence),
+            (Some(old), Some(new)) => Some(HopChange::Changed {
+                ttl,
+                before: list(&old.addrs),
+                after: list(&new.addrs),
+            }),
+            (None, None) => None,
+        };
+        changes.extend(change);
+    }
+    let answered = |ttls: &BTreeMap<u8, Probed>| -> BTreeSet<IpAddr> {
+        ttls.values()
+            .flat_map(|probed| probed.addrs.iter().cloned())
+            .collect()
+    };
+    let (seen_before, seen_after) = (answered(&before), answered(&after));
+    TraceDiff {
+        changes,
+        disjoint: !seen_before.is_empty()
+            && !seen_after.is_empty()
+            && seen_before.is_disjoint(&seen_after),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scripted_trace, test_net_v4};
+    use crate::CompletionReason;
+
+    const RTT: Option<Duration> = Some(Duration::from_millis(5));
+
+    fn addrs(hosts: &[u8]) -> Vec<IpAddr> {
+        hosts.iter().cloned().map(test_net_v4).collect()
+    }
+
+    #[test]
+    fn same_traces_do_not_differ() {
+        let a = scripted_trace(&[Some(1), None, Some(100)], RTT);
+        let diff = diff_traces(&a, &a, &DiffOptions::default());
+        assert!(diff.is_empty());
+        assert!(!diff.disjoint);
+        assert_eq!(diff.first_changed_ttl(), None);
+        assert_eq!(diff.to_string(), "");
+    }
+
+    #[test]
+    fn longer_and_shorter_traces_add_and_remove_ttls() {
+        let short = scripted_trace(&[Some(1), Some(100)], RTT);
+        let mut long = scripted_trace(&[Some(1), Some(2), Some(3), Some(100)], RTT);
+        long.push(HopFound::end_of_trace(5, 1, CompletionReason::NotReached));
+        let diff = diff_traces(&short, &long, &DiffOptions::default());
+        assert_eq!(
+            diff.changes,
+            vec![
+                HopChange::Changed {
+                    ttl: 2,
+                    before: addrs(&[100]),
+                    after: addrs(&[2]),
+                },
+                HopChange::Added {
+                    ttl: 3,
+                    addrs: addrs(&[3]),
+                },
+                HopChange::Added {
+                    ttl: 4,
+                    addrs: addrs(&[100]),
+                },
+            ]
+        );
+        assert_eq!(diff.first_changed_ttl(), Some(2));
+        let back = diff_traces(&long, &short, &DiffOptions::default());
+        assert_eq!(
+            back.changes[2],
+            HopChange::Removed {
+                ttl: 4,
+                addrs: addrs(&[100]),
+            }
+        );
+    }
+
+    #[test]
+    fn silence_is_told_from_a_changed_address() {
+        let a = scripted_trace(&[Some(1), Some(2), None, Some(100)], RTT);
+        let b = scripted_trace(&[Some(1), None, Some(3), Some(100)], RTT);
+        let diff = diff_traces(&a, &b, &DiffOptions::default());
+        assert_eq!(
+            diff.changes,
+            vec![
+                HopChange::Silenced {
+                    ttl: 2,
+                    before: addrs(&[2]),
+                },
+                HopChange::Answered {
+                    ttl: 3,
+                    after: addrs(&[3]),
+                },
+            ]
+        );
+        let options = DiffOptions {
+            ignore_silence: true,
+            ..DiffOptions::default()
+        };
+        assert!(diff_traces(&a, &b, &options).is_empty());
+    }
+
+    #[test]
+    fn small_rtt_deltas_are_ignored() {
+        let a = scripted_trace(&[Some(1), Some(100)], RTT);
+        assert!(diff_traces(
+            &a,
+            &scripted_trace(&[Some(1), Some(100)], Some(Duration::from_millis(14))),
+            &DiffOptions::default()
+        )
+        .is_empty());
+        let diff = diff_traces(
+            &a,
+            &scripted_trace(&[Some(1), Some(100)], Some(Duration::from_millis(40))),
+            &DiffOptions::default(),
+        );
+        assert_eq!(diff.changes.len(), 2);
+        assert_eq!(
+            diff.changes[0],
+            HopChange::Rtt {
+                ttl: 1,
+                before: Duration::from_millis(5),
+                after: Duration::from_millis(40),
+            }
+        );
+        assert_eq!(diff.first_changed_ttl(), None);
+        assert_eq!(
+            diff.to_string(),
+            "~ 1  5.0ms -> 40.0ms\n~ 2  5.0ms -> 40.0ms\n"
+        );
+    }
+
+    #[test]
+    fn multipath_ttls_compare_as_sets() {
+        let mut a = scripted_trace(&[Some(1), Some(2), Some(100)], RTT);
+        a.push(HopFound::new(2, Some(test_net_v4(12)), 0, false, None));
+        let mut b = scripted_trace(&[Some(1), Some(12), Some(100)], RTT);
+        b.insert(0, HopFound::new(2, Some(test_net_v4(2)), 0, false, None));
+        assert!(diff_traces(&a, &b, &DiffOptions::default()).is_empty());
+
+        b.retain(|hop| hop.addr != Some(test_net_v4(2)));
+        let diff = diff_traces(&a, &b, &DiffOptions::default());
+        assert_eq!(
+            diff.changes,
+            vec![HopChange::Changed {
+                ttl: 2,
+                before: addrs(&[2, 12]),
+                after: addrs(&[12]),
+            }]
+        );
+        assert_eq!(
+            diff.to_string(),
+            "~ 2  192.0.2.2 192.0.2.12 -> 192.0.2.12\n"
+        );
+    }
+
+    #[test]
+    fn disjoint_paths_are_flagged() {
+        let a = scripted_trace(&[Some(1), Some(2), Some(100)], RTT);
+        let b = scripted_trace(&[Some(11), Some(12), None], RTT);
+        let diff = diff_traces(&a, &b, &DiffOptions::default());
+        assert!(diff.disjoint);
+        assert_eq!(diff.changes.len(), 3);
+        assert!(diff
+            .to_string()
+            .starts_with("disjoint paths\n~ 1  192.0.2.1 -> 192.0.2.11\n"));
+
+        let silent = scripted_trace(&[None, None], RTT);
+        assert!(!diff_traces(&a, &silent, &DiffOptions::default()).disjoint);
+    }
+}
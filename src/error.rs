@@ -0,0 +1,254 @@
+//! Error type shared by configuration, setup and the probing worker.
+use crate::backend::BackendKind;
+use crate::config::InterfaceSel;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+
+/// This enum represents everything that can go wrong while configuring, starting or
+/// running a trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TraceRouteError {
+    /// `max_ttl` was zero.
+    BadMaxTtl,
+    /// `begin_ttl` was zero or greater than `max_ttl`.
+    BadBeginTtl,
+    /// `size` was below the minimum probe size.
+    BadSize,
+    /// `size` does not fit the 16 bit length fields of the probe headers, next to
+    /// the IPv4 options or IPv6 extension header turned on.
+    SizeTooLarge { max: usize },
+    /// `timeout` was zero, None waits without one.
+    BadTimeout,
+    /// `gateways` had more than `max` entries or was set for an IPv6 destination.
+    BadGateways { max: usize },
+    /// `record_route` was set for an IPv6 destination or with too many gateways to
+    /// leave it room.
+    BadRecordRoute,
+    /// `timestamps` was set for an IPv6 destination, together with `record_route`
+    /// or with too many gateways to leave it room.
+    BadTimestamps,
+    /// `extension_header` was set for an IPv4 destination.
+    BadExtensionHeader,
+    /// `fragment` was set with `backend` forcing sockets that cannot send the
+    /// fragments, as they leave the IP header to the kernel.
+    BadFragment(BackendKind),
+    /// `interface` picks no network device of this machine.
+    BadInterface(InterfaceSel),
+    /// `dscp` was greater than `max`.
+    BadDscp { max: u8 },
+    /// `port_fallback` had no candidates or would only start after the last try.
+    BadPortFallback,
+    /// `port_fn` gave port 0 to the probe of `ttl` sent for the `attempt`th time.
+    ZeroPort { ttl: u8, attempt: u16 },
+    /// `ttl_order` shuffled the TTLs of a trace continuing past its destination.
+    BadTtlOrder,
+    /// `window` was zero, set for a shuffled trace or with an option only
+    /// sequential traces have, or above 1 for a raw protocol.
+    BadWindow,
+    /// `sequence_plan` numbered the TTLs with a stride not above `max_tries`.
+    BadSequencePlan,
+    /// `protocol_fallback` had no protocols or gave up after zero hops.
+    BadProtocolFallback,
+    /// `preflight` probed no candidate or waited more than `max` milliseconds.
+    BadPreflight { max: u64 },
+    /// `backend` forces sockets that cannot carry the probe protocol.
+    BadBackend(BackendKind),
+    /// The host name did not resolve to an address of the wanted family.
+    Unresolved { host: String, message: String },
+    /// The destination is a multicast address.
+    MulticastDestination(IpAddr),
+    /// The destination is the limited or a directed broadcast address.
+    BroadcastDestination(IpAddr),
+    /// The destination is `0.0.0.0` or `::`.
+    UnspecifiedDestination(IpAddr),
+    /// The destination is in `0.0.0.0/8`, which only stands for this network.
+    ZeroNetworkDestination(IpAddr),
+    /// The destination is reserved for documentation, see
+    /// `TraceRouteConfig::allow_documentation_destinations`.
+    DocumentationDestination(IpAddr),
+    /// No interface that is up has an address of the needed family.
+    NoInterface,
+    /// The next hop of the frames did not answer ARP or Neighbor Discovery, or
+    /// there is none for the address.
+    NoNeighbor(IpAddr),
+    /// The probe sent to find the first hop was not answered.
+    NoFirstHop,
+    /// Opening raw sockets was refused, the process needs root or CAP_NET_RAW.
+    PermissionDenied,
+    /// Setting `fwmark` on the sockets was refused, the process needs CAP_NET_ADMIN.
+    MarkDenied,
+    /// No kind of sockets could be opened, each tried kind is listed with its error.
+    NoBackend {
+        tried: Vec<(BackendKind, TraceRouteError)>,
+    },
+    /// Opening a transport channel failed for any other reason.
+    Channel {
+        kind: io::ErrorKind,
+        message: String,
+    },
+    /// Sending a probe failed while the trace was running.
+    Send {
+        kind: io::ErrorKind,
+        message: String,
+    },
+    /// The worker stopped without reporting the terminal hop or an error.
+    WorkerStopped,
+    /// No hop came within the timeout of a `HopIter`.
+    HopTimeout,
+    /// `id_allocator` had no range left for the trace.
+    NoIdentifiers,
+    /// None of the probes `TraceRoute::calibrate` sent to the loopback address
+    /// was answered.
+    NoCalibration,
+    /// A configuration file could not be read, or holds options that are not valid,
+    /// see `ConfigFile`.
+    BadConfigFile { message: String },
+    /// `TraceRoute::probe_interface` was called without
+    /// `TraceRouteConfig::interface_query`.
+    NoInterfaceQuery,
+}
+
+impl TraceRouteError {
+    /// Maps an io error returned while opening a transport channel.
+    pub(crate) fn from_channel(err: io::Error) -> TraceRouteError {
+        warn!("could not open a transport channel: {}", err);
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => TraceRouteError::PermissionDenied,
+            kind => TraceRouteError::Channel {
+                kind,
+                message: err.to_string(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for TraceRouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceRouteError::BadMaxTtl => f.write_str("BAD MAX TTL"),
+            TraceRouteError::BadBeginTtl => f.write_str("BAD START TTL"),
+            TraceRouteError::BadSize => f.write_str("BAD SIZE - MIN=12"),
+            TraceRouteError::SizeTooLarge { max } => write!(f, "BAD SIZE - MAX={}", max),
+            TraceRouteError::BadTimeout => f.write_str("BAD TIMEOUT"),
+            TraceRouteError::BadGateways { max } => {
+                write!(f, "BAD GATEWAYS - IPv4 only, MAX={}", max)
+            }
+            TraceRouteError::BadRecordRoute => {
+                f.write_str("BAD RECORD ROUTE - IPv4 only, no room next to the gateways")
+            }
+            TraceRouteError::BadTimestamps => f.write_str(
+                "BAD TIMESTAMPS - IPv4 only, not with record route, no room next to the gateways",
+            ),
+            TraceRouteError::BadExtensionHeader => f.write_str("BAD EXTENSION HEADER - IPv6 only"),
+            TraceRouteError::BadFragment(kind) => {
+                write!(f, "BAD FRAGMENT - {} sockets cannot send fragments", kind)
+            }
+            TraceRouteError::BadInterface(InterfaceSel::Name(name)) => {
+                write!(f, "BAD INTERFACE - no device named {}", name)
+            }
+            TraceRouteError::BadInterface(InterfaceSel::Index(index)) => {
+                write!(f, "BAD INTERFACE - no device with index {}", index)
+            }
+            TraceRouteError::BadDscp { max } => write!(f, "BAD DSCP - MAX={}", max),
+            TraceRouteError::BadProtocolFallback => f.write_str(
+                "BAD PROTOCOL FALLBACK - needs protocols and give_up_after_hops above 0",
+            ),
+            TraceRouteError::BadPreflight { max } => {
+                write!(
+                    f,
+                    "BAD PREFLIGHT - needs candidates and waits up to {}ms",
+                    max
+                )
+            }
+            TraceRouteError::BadBackend(kind) => {
+                write!(f, "BAD BACKEND - {} sockets cannot carry the probes", kind)
+            }
+            TraceRouteError::Unresolved { host, message } => {
+                write!(f, "Could not resolve {}, Error<{}>", host, message)
+            }
+            TraceRouteError::ZeroPort { ttl, attempt } => write!(
+                f,
+                "BAD PORT - port_fn gave port 0 to ttl {} attempt {}",
+                ttl, attempt
+            ),
+            TraceRouteError::BadTtlOrder => {
+                f.write_str("BAD TTL ORDER - shuffled TTLs cannot continue past the destination")
+            }
+            TraceRouteError::BadWindow => {
+                f.write_str("BAD WINDOW - needs 1 TTL or more, 1 for raw protocols and a plain sequential trace")
+            }
+            TraceRouteError::BadSequencePlan => {
+                f.write_str("BAD SEQUENCE PLAN - the stride must exceed the tries of a TTL")
+            }
+            TraceRouteError::BadPortFallback => f.write_str(
+                "BAD PORT FALLBACK - needs candidates and trigger_after below max_tries",
+            ),
+            TraceRouteError::MulticastDestination(addr) => {
+                write!(f, "BAD ADDRESS - {} is a multicast address", addr)
+            }
+            TraceRouteError::BroadcastDestination(addr) => {
+                write!(f, "BAD ADDRESS - {} is a broadcast address", addr)
+            }
+            TraceRouteError::UnspecifiedDestination(addr) => {
+                write!(f, "BAD ADDRESS - {} is the unspecified address", addr)
+            }
+            TraceRouteError::ZeroNetworkDestination(addr) => {
+                write!(f, "BAD ADDRESS - {} is in the zero network", addr)
+            }
+            TraceRouteError::DocumentationDestination(addr) => {
+                write!(f, "BAD ADDRESS - {} is reserved for documentation", addr)
+            }
+            TraceRouteError::NoInterface => {
+                f.write_str("No <UP> interface was found, please connect to internet.")
+            }
+            TraceRouteError::NoFirstHop => f.write_str("First hop did not answer"),
+            TraceRouteError::NoNeighbor(addr) => {
+                write!(
+                    f,
+                    "Could not resolve the MAC address of the next hop to {}",
+                    addr
+                )
+            }
+            TraceRouteError::PermissionDenied => f.write_str(
+                "Could not open raw socket, make sure this program has needed privilages",
+            ),
+            TraceRouteError::MarkDenied => f.write_str(
+                "Could not set the firewall mark, make sure this program has CAP_NET_ADMIN",
+            ),
+            TraceRouteError::NoBackend { tried } => {
+                f.write_str("Could not open any sockets")?;
+                for (i, (kind, err)) in tried.iter().enumerate() {
+                    let separator = if i == 0 { " - " } else { "; " };
+                    write!(f, "{}{}: {}", separator, kind, err)?;
+                }
+                Ok(())
+            }
+            TraceRouteError::Channel { message, .. } => {
+                write!(f, "Could not open transport channel, Error<{}>", message)
+            }
+            TraceRouteError::WorkerStopped => f.write_str("The trace stopped before its last hop"),
+            TraceRouteError::HopTimeout => f.write_str("No hop came in time"),
+            TraceRouteError::NoIdentifiers => {
+                f.write_str("No probe identifiers left, too many traces are running")
+            }
+            TraceRouteError::BadConfigFile { message } => {
+                write!(f, "Invalid configuration file: {}", message)
+            }
+            TraceRouteError::NoInterfaceQuery => {
+                f.write_str("BAD INTERFACE QUERY - extended echo probes need interface_query")
+            }
+            TraceRouteError::NoCalibration => {
+                f.write_str("The loopback address did not answer the calibration probes")
+            }
+            TraceRouteError::Send { message, .. } => write!(
+                f,
+                "Could not send packet, make sure this program has needed privilages, Error<{}>",
+                message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TraceRouteError {}
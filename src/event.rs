@@ -0,0 +1,134 @@
+//! Everything a trace reports on an event channel.
+use crate::metrics::TraceMetrics;
+use crate::reply::{MalformedReply, ParameterProblem, ProbeToken};
+use crate::{CompletionReason, HopFound, TraceMetadata, TraceRouteError, TraceRouteProtocol};
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// This enum is an event of a trace started with `TraceRoute::run_with_events`.
+///
+/// A trace starts with `Started`, reports every probe it sends and every hop it
+/// finds, and ends with `Completed` unless it failed or its receiver went away.
+// Hops are most of the events, boxing them would not save anything.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TraceEvent {
+    Started(TraceMetadata),
+    ProbeSent(ProbeSent),
+    Hop(HopFound),
+    Warning(TraceWarning),
+    /// The trace could not go on, nothing follows.
+    Error(TraceRouteError),
+    /// No TTL of the trace answered, it runs again. The hops of the silent run
+    /// are not reported.
+    Retrying(TraceRetry),
+    /// A TTL reported earlier was answered late.
+    HopCorrected(HopCorrection),
+    Completed(TraceComplete),
+}
+
+/// This struct describes a probe of a TTL the trace sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ProbeSent {
+    pub ttl: u8,
+    /// Number of the try for `ttl`, from 1.
+    pub attempt: u16,
+    pub protocol: TraceRouteProtocol,
+    /// Destination port of UDP and DCCP probes.
+    pub port: Option<u16>,
+    /// True for a warm-up probe, whose reply is not reported.
+    pub warmup: bool,
+    /// Fields of the probe as it went on the wire, to find it in packet captures.
+    pub token: Option<ProbeToken>,
+}
+
+/// This struct is a rerun of a trace none of whose TTLs answered, see
+/// `TraceRouteConfig::retry_on_total_failure`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TraceRetry {
+    /// Number of the rerun, from 1.
+    pub attempt: u16,
+    /// Wait before the rerun starts.
+    pub delay: Duration,
+}
+
+/// This struct is a reply to a TTL that was already reported, see
+/// `TraceRouteConfig::correct_late_replies` and `TraceRouteConfig::second_pass`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HopCorrection {
+    pub ttl: u8,
+    /// Address that answered.
+    pub addr: IpAddr,
+    /// Round trip time of the probe answered.
+    pub rtt: Duration,
+    /// What the TTL was reported as.
+    pub previously: PreviousOutcome,
+}
+
+/// This enum is how a TTL was reported before a `HopCorrection`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PreviousOutcome {
+    /// None of the probes of the TTL was answered in time.
+    Timeout,
+}
+
+/// This enum is something the trace noticed and went on with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TraceWarning {
+    /// A reply to the probe of the current TTL that neither answers it nor tells
+    /// that it was blocked.
+    UnexpectedReply {
+        from: IpAddr,
+        icmp_type: u8,
+        code: u8,
+        /// Bytes of the reply, only kept with `TraceRouteConfig::capture_raw`.
+        raw_reply: Option<Vec<u8>>,
+    },
+    /// A received message that could not be classified.
+    MalformedReply(MalformedReply),
+    /// A hop objected to a field of the probe of `ttl`, the probe counts as
+    /// unanswered.
+    ParameterProblem { ttl: u8, problem: ParameterProblem },
+    /// Receiving replies failed, the probe waiting for one counts as unanswered.
+    ReceiveFailed {
+        kind: io::ErrorKind,
+        message: String,
+    },
+    /// The hops of the trace could not come from routers forwarding probes, a
+    /// middlebox likely rewrites their TTLs. Reported before `Completed`.
+    PathAnomaly(PathAnomaly),
+}
+
+/// This enum is a path no router could produce, with the hops telling it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PathAnomaly {
+    /// The destination answered at `ttl`, while the routers of `hops` answered at
+    /// higher TTLs, with their addresses.
+    DestinationBelowHops { ttl: u8, hops: Vec<(u8, IpAddr)> },
+    /// `addr` ended the trace at `ttl`, and answered as a router at `hop_ttls`.
+    TerminalAlsoIntermediate {
+        addr: IpAddr,
+        ttl: u8,
+        hop_ttls: Vec<u8>,
+    },
+    /// The destination answered the probe of `ttl` with a time exceeded.
+    TimeExceededFromDestination { ttl: u8 },
+}
+
+/// This struct is the end of a trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TraceComplete {
+    /// How the trace ended, None if the last protocol it tried was abandoned.
+    pub completion: Option<CompletionReason>,
+    /// Final counters of the trace.
+    pub metrics: TraceMetrics,
+}
@@ -0,0 +1,334 @@
+//! Prometheus metrics of repeated traces, see `PrometheusExporter`.
+use crate::HopStats;
+use ::prometheus::{GaugeVec, IntCounterVec, Opts, Registry};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Labels of every series, the hop is the TTL.
+const LABELS: [&str; 3] = ["target", "hop", "address"];
+
+/// Address label of TTLs that never answered.
+const SILENT: &str = "*";
+
+/// This struct holds the options of a `PrometheusExporter`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ExporterOptions {
+    /// Prefix of the metric names.
+    pub namespace: String,
+    /// Only TTLs up to this one are exported, so a path running into a loop does
+    /// not grow the series without end.
+    pub max_hops: u8,
+}
+
+impl Default for ExporterOptions {
+    fn default() -> ExporterOptions {
+        ExporterOptions {
+            namespace: "traceroute".to_string(),
+            max_hops: 30,
+        }
+    }
+}
+
+/// Last values exported for a TTL of a target.
+struct Series {
+    address: String,
+    sent: u64,
+    received: u64,
+    /// False once the TTL went unprobed and its series were dropped, its counts
+    /// are kept so it is not exported anew while the statistics still list it.
+    exported: bool,
+}
+
+/// This struct exports the statistics of repeated traces as Prometheus metrics,
+/// one series per target, hop and address.
+///
+/// The gauges `<namespace>_hop_rtt_{last,avg,best,worst}_seconds` and
+/// `<namespace>_hop_loss_ratio` and the counters `<namespace>_hop_sent_total` and
+/// `<namespace>_hop_received_total` are registered with the registry given, and
+/// updated by `record` after every round, from the `on_round` callback of
+/// `TraceRoute::run_rounds` for example.
+///
+/// The series of a hop answering from another address, or no longer probed as the
+/// path got shorter, are dropped.
+pub struct PrometheusExporter {
+    options: ExporterOptions,
+    rtt_last: GaugeVec,
+    rtt_avg: GaugeVec,
+    rtt_best: GaugeVec,
+    rtt_worst: GaugeVec,
+    loss: GaugeVec,
+    sent: IntCounterVec,
+    received: IntCounterVec,
+    series: BTreeMap<(String, u8), Series>,
+}
+
+impl PrometheusExporter {
+    /// Creates new PrometheusExporter and registers its metrics with `registry`.
+    pub fn new(
+        registry: &Registry,
+        options: ExporterOptions,
+    ) -> Result<PrometheusExporter, ::prometheus::Error> {
+        let opts = |name: &str, help: &str| {
+            Opts::new(format!("hop_{}", name), help).namespace(options.namespace.clone())
+        };
+        let gauge = |name: &str, help: &str| -> Result<GaugeVec, ::prometheus::Error> {
+            let gauge = GaugeVec::new(opts(name, help), &LABELS)?;
+            registry.register(Box::new(gauge.clone()))?;
+            Ok(gauge)
+        };
+        let counter = |name: &str, help: &str| -> Result<IntCounterVec, ::prometheus::Error> {
+            let counter = IntCounterVec::new(opts(name, help), &LABELS)?;
+            registry.register(Box::new(counter.clone()))?;
+            Ok(counter)
+        };
+        Ok(PrometheusExporter {
+            rtt_last: gauge("rtt_last_seconds", "Round trip time of the last answer")?,
+            rtt_avg: gauge("rtt_avg_seconds", "Mean round trip time")?,
+            rtt_best: gauge("rtt_best_seconds", "Shortest round trip time")?,
+            rtt_worst: gauge("rtt_worst_seconds", "Longest round trip time")?,
+            loss: gauge("loss_ratio", "Share of rounds the hop did not answer")?,
+            sent: counter("sent_total", "Rounds that probed the hop")?,
+            received: counter("received_total", "Rounds the hop answered")?,
+            options,
+            series: BTreeMap::new(),
+        })
+    }
+
+    /// Updates the series of `target` with the statistics after a round, as
+    /// `TraceRoute::run_rounds` hands them over.
+    pub fn record(&mut self, target: &str, stats: &[HopStats]) {
+        let gone: Vec<u8> = self
+            .series
+            .range((target.to_string(), 0)..=(target.to_string(), u8::MAX))
+            .map(|((_, ttl), _)| *ttl)
+            .filter(|ttl| !stats.iter().any(|hop| hop.ttl == *ttl))
+            .collect();
+        let mut unprobed = Vec::new();
+        for hop in stats.iter().filter(|hop| hop.ttl <= self.options.max_hops) {
+            let address = hop
+                .addr
+                .map_or_else(|| SILENT.to_string(), |addr| addr.to_string());
+            let key = (target.to_string(), hop.ttl);
+            let (sent, received) = match self.series.get(&key) {
+                Some(series) if hop.sent == series.sent => {
+                    // Not probed since, the path got shorter.
+                    if series.exported {
+                        unprobed.push(hop.ttl);
+                    }
+                    continue;
+                }
+                Some(series) => {
+                    let deltas = (
+                        hop.sent.saturating_sub(series.sent),
+                        hop.received.saturating_sub(series.received),
+                    );
+                    if series.exported && series.address != address {
+                        self.remove(target, hop.ttl, &series.address);
+                    }
+                    deltas
+                }
+                None => (hop.sent, hop.received),
+            };
+            let hop_label = hop.ttl.to_string();
+            let labels = [target, hop_label.as_str(), address.as_str()];
+            let seconds = |rtt: Option<Duration>| rtt.map_or(f64::NAN, |rtt| rtt.as_secs_f64());
+            self.rtt_last
+                .with_label_values(&labels)
+                .set(seconds(hop.last));
+            self.rtt_avg
+                .with_label_values(&labels)
+                .set(seconds(hop.mean));
+            self.rtt_best
+                .with_label_values(&labels)
+                .set(seconds(hop.best));
+            self.rtt_worst
+                .with_label_values(&labels)
+                .set(seconds(hop.worst));
+            self.loss.with_label_values(&labels).set(hop.loss());
+            self.sent.with_label_values(&labels).inc_by(sent);
+            self.received.with_label_values(&labels).inc_by(received);
+            let series = Series {
+                address,
+                sent: hop.sent,
+                received: hop.received,
+                exported: true,
+            };
+            self.series.insert(key, series);
+        }
+        for ttl in unprobed {
+            let series = self.series.get_mut(&(target.to_string(), ttl)).unwrap();
+            series.exported = false;
+            let address = series.address.clone();
+            self.remove(target, ttl, &address);
+        }
+        for ttl in gone {
+            if let Some(series) = self.series.remove(&(target.to_string(), ttl)) {
+                self.remove(target, ttl, &series.address);
+            }
+        }
+    }
+
+    /// Drops every series of `target`, for a target no longer traced.
+    pub fn remove_target(&mut self, target: &str) {
+        let ttls: Vec<u8> = self
+            .series
+            .keys()
+            .filter(|(name, _)| name == target)
+            .map(|(_, ttl)| *ttl)
+            .collect();
+        for ttl in ttls {
+            let series = self.series.remove(&(target.to_string(), ttl)).unwrap();
+            self.remove(target, ttl, &series.address);
+        }
+    }
+
+    fn remove(&self, target: &str, ttl: u8, address: &str) {
+        let hop_label = ttl.to_string();
+        let labels = [target, hop_label.as_str(), address];
+        for gauge in &[
+            &self.rtt_last,
+            &self.rtt_avg,
+            &self.rtt_best,
+            &self.rtt_worst,
+            &self.loss,
+        ] {
+            let _ = gauge.remove_label_values(&labels);
+        }
+        for counter in &[&self.sent, &self.received] {
+            let _ = counter.remove_label_values(&labels);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_net_v4, MockClock, SimulatedBackend};
+    use crate::{HopFound, PathStats, TraceRoute, TraceRouteConfig};
+    use ::prometheus::proto::MetricFamily;
+    use std::net::IpAddr;
+
+    /// Returns the value of `name` for `hop` and `address` of `target`.
+    fn value(families: &[MetricFamily], name: &str, hop: &str, address: &str) -> Option<f64> {
+        let family = families.iter().find(|family| family.get_name() == name)?;
+        let metric = family.get_metric().iter().find(|metric| {
+            let labels: Vec<(&str, &str)> = metric
+                .get_label()
+                .iter()
+                .map(|pair| (pair.get_name(), pair.get_value()))
+                .collect();
+            labels.contains(&("hop", hop))
+                && labels.contains(&("address", address))
+                && labels.contains(&("target", "192.0.2.100"))
+        })?;
+        Some(if metric.has_counter() {
+            metric.get_counter().get_value()
+        } else {
+            metric.get_gauge().get_value()
+        })
+    }
+
+    #[test]
+    fn rounds_are_exported() {
+        let registry = Registry::new();
+        let mut exporter = PrometheusExporter::new(&registry, ExporterOptions::default()).unwrap();
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, _) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let backend = SimulatedBackend::new(
+            vec![Some(test_net_v4(1)), None, Some(test_net_v4(3))],
+            test_net_v4(100),
+        )
+        .with_clock(MockClock::new())
+        .with_reply_delay(Duration::from_millis(5));
+        trace_route
+            .run_rounds_with_backend(
+                3,
+                |stats| exporter.record("192.0.2.100", stats),
+                backend,
+                test_net_v4(254),
+            )
+            .unwrap();
+        let families = registry.gather();
+        let names: Vec<&str> = families.iter().map(|family| family.get_name()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "traceroute_hop_loss_ratio",
+                "traceroute_hop_received_total",
+                "traceroute_hop_rtt_avg_seconds",
+                "traceroute_hop_rtt_best_seconds",
+                "traceroute_hop_rtt_last_seconds",
+                "traceroute_hop_rtt_worst_seconds",
+                "traceroute_hop_sent_total",
+            ]
+        );
+        let first = |name| value(&families, name, "1", "192.0.2.1");
+        assert_eq!(first("traceroute_hop_rtt_last_seconds"), Some(0.005));
+        assert_eq!(first("traceroute_hop_rtt_best_seconds"), Some(0.005));
+        assert_eq!(first("traceroute_hop_sent_total"), Some(3.0));
+        assert_eq!(first("traceroute_hop_received_total"), Some(3.0));
+        assert_eq!(first("traceroute_hop_loss_ratio"), Some(0.0));
+        let silent = |name| value(&families, name, "2", SILENT);
+        assert_eq!(silent("traceroute_hop_loss_ratio"), Some(1.0));
+        assert_eq!(silent("traceroute_hop_received_total"), Some(0.0));
+        assert!(silent("traceroute_hop_rtt_last_seconds").unwrap().is_nan());
+        let last = value(&families, "traceroute_hop_sent_total", "4", "192.0.2.100");
+        assert_eq!(last, Some(3.0));
+    }
+
+    #[test]
+    fn stale_series_are_dropped() {
+        let registry = Registry::new();
+        let options = ExporterOptions {
+            max_hops: 2,
+            ..ExporterOptions::default()
+        };
+        let mut exporter = PrometheusExporter::new(&registry, options).unwrap();
+        let hop = |ttl: u8, addr: IpAddr| HopFound::new(ttl, Some(addr), 1, false, None);
+        let mut stats = PathStats::new();
+        let round = stats.record_round(&[
+            hop(1, test_net_v4(1)),
+            hop(2, test_net_v4(2)),
+            hop(3, test_net_v4(3)),
+        ]);
+        exporter.record("192.0.2.100", &round);
+        let families = registry.gather();
+        let sent = |families: &[MetricFamily], hop, address| {
+            value(families, "traceroute_hop_sent_total", hop, address)
+        };
+        assert_eq!(sent(&families, "2", "192.0.2.2"), Some(1.0));
+        // Hops above the cap are left out.
+        assert_eq!(sent(&families, "3", "192.0.2.3"), None);
+
+        // The second hop moved, the first is no longer probed.
+        let mut moved = PathStats::new();
+        moved.record_round(&[hop(1, test_net_v4(1)), hop(2, test_net_v4(2))]);
+        let round = moved.record_round(&[hop(2, test_net_v4(20))]);
+        exporter.record("192.0.2.100", &round);
+        let families = registry.gather();
+        assert_eq!(sent(&families, "2", "192.0.2.2"), None);
+        assert_eq!(sent(&families, "2", "192.0.2.20"), Some(1.0));
+        assert_eq!(sent(&families, "1", "192.0.2.1"), None);
+        // Still listed by the statistics, the first hop stays dropped until it
+        // is probed again.
+        let round = moved.record_round(&[hop(2, test_net_v4(20))]);
+        exporter.record("192.0.2.100", &round);
+        let families = registry.gather();
+        assert_eq!(sent(&families, "1", "192.0.2.1"), None);
+        assert_eq!(sent(&families, "2", "192.0.2.20"), Some(2.0));
+        let round = moved.record_round(&[hop(1, test_net_v4(1)), hop(2, test_net_v4(20))]);
+        exporter.record("192.0.2.100", &round);
+        let families = registry.gather();
+        assert_eq!(sent(&families, "1", "192.0.2.1"), Some(1.0));
+
+        exporter.remove_target("192.0.2.100");
+        assert!(registry
+            .gather()
+            .iter()
+            .all(|family| family.get_metric().is_empty()));
+    }
+}
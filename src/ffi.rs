@@ -0,0 +1,742 @@
+//! C interface, with the `ffi` feature.
+//!
+//! The build writes the matching header to `$OUT_DIR/librtraceroute.h`, a C library
+//! can be built with `cargo rustc --features ffi --crate-type cdylib`.
+//!
+//! A trace is configured on an `rtr_config_t`, started with `rtr_trace_start` and
+//! reports its hops to a callback. Functions returning `int` return `RTR_OK` or one
+//! of the negative error codes, `rtr_last_error` describes the last failure of the
+//! calling thread. Panics never cross the boundary, they are reported as
+//! `RTR_ERR_PANIC`. Strings are UTF-8, those handed out are freed with
+//! `rtr_string_free`.
+#![allow(non_camel_case_types)]
+use crate::{CompletionReason, TraceRouteProtocol};
+use crate::{HopFound, TraceHandle, TraceRoute, TraceRouteConfig, TraceRouteError};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The call succeeded.
+pub const RTR_OK: c_int = 0;
+/// A pointer argument was null.
+pub const RTR_ERR_NULL: c_int = -1;
+/// A string argument was not UTF-8 or could not be parsed.
+pub const RTR_ERR_INVALID: c_int = -2;
+/// The configuration was rejected for the destination.
+pub const RTR_ERR_CONFIG: c_int = -3;
+/// Opening raw sockets was refused, the process needs root or CAP_NET_RAW.
+pub const RTR_ERR_PERMISSION: c_int = -4;
+/// The trace could not be started for any other reason.
+pub const RTR_ERR_START: c_int = -5;
+/// The library panicked, the call or the trace was abandoned.
+pub const RTR_ERR_PANIC: c_int = -6;
+/// The trace stopped on an error, `rtr_last_error` tells which.
+pub const RTR_ERR_TRACE: c_int = -7;
+
+/// The hop is the last one of the trace.
+pub const RTR_HOP_LAST: u32 = 1;
+/// The destination answered, set together with `RTR_HOP_LAST`.
+pub const RTR_HOP_REACHED: u32 = 2;
+/// The hop stands for hidden local hops, see `rtr_config_set_hide_local_hops`.
+pub const RTR_HOP_LOCAL: u32 = 4;
+/// All probes of the TTL went unanswered.
+pub const RTR_HOP_TIMEOUT: u32 = 8;
+/// The hop only ends a trace no hop ended, like one that ran out of TTLs, and
+/// stands for no hop of the path. Set together with `RTR_HOP_LAST`.
+pub const RTR_HOP_END: u32 = 16;
+
+/// Size of the address buffer of a hop, fits any IPv6 address and its NUL.
+pub const RTR_ADDR_LEN: usize = 46;
+
+/// A hop as handed to the callback, only valid during the call.
+#[repr(C)]
+pub struct rtr_hop_t {
+    /// Address that answered, NUL terminated, empty when nothing answered.
+    pub addr: [c_char; RTR_ADDR_LEN],
+    /// TTL the probe was sent with.
+    pub hop: u8,
+    /// Probes sent before the one answered.
+    pub tries: u16,
+    /// Round trip time in microseconds, -1 when nothing answered.
+    pub rtt_us: i64,
+    /// `RTR_HOP_*` flags.
+    pub flags: u32,
+}
+
+/// Called for every hop, on a thread of the trace.
+pub type rtr_hop_cb = Option<unsafe extern "C" fn(hop: *const rtr_hop_t, user_data: *mut c_void)>;
+
+/// Options and destination of a trace.
+pub struct rtr_config_t {
+    destination: Option<IpAddr>,
+    config: TraceRouteConfig,
+}
+
+/// A started trace.
+pub struct rtr_trace_t {
+    cancelled: Arc<AtomicBool>,
+    forwarder: JoinHandle<()>,
+    handle: TraceHandle,
+    /// The error that stopped the worker, shared with its sink.
+    failure: Arc<Mutex<Option<TraceRouteError>>>,
+}
+
+/// Pointer handed back to the callback, the caller vouches it can be used from the
+/// forwarding thread.
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+/// How often the forwarding thread looks for a cancellation.
+const CANCEL_POLL: Duration = Duration::from_millis(50);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Remembers `message` as the last error of this thread and returns `code`.
+fn fail(code: c_int, message: impl Into<String>) -> c_int {
+    let message = message.into();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    code
+}
+
+/// Runs `body`, turning a panic into `RTR_ERR_PANIC`.
+fn guard<F: FnOnce() -> Result<(), c_int>>(body: F) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => RTR_OK,
+        Ok(Err(code)) => code,
+        Err(_) => fail(RTR_ERR_PANIC, "librtraceroute panicked"),
+    }
+}
+
+/// Maps an error of the library to a code, remembering its message.
+fn code_of(err: TraceRouteError) -> c_int {
+    let code = match err {
+        TraceRouteError::PermissionDenied | TraceRouteError::MarkDenied => RTR_ERR_PERMISSION,
+        TraceRouteError::NoInterface
+        | TraceRouteError::NoNeighbor(_)
+        | TraceRouteError::NoBackend { .. }
+        | TraceRouteError::Channel { .. }
+        | TraceRouteError::Send { .. }
+        | TraceRouteError::WorkerStopped
+        | TraceRouteError::HopTimeout => RTR_ERR_START,
+        _ => RTR_ERR_CONFIG,
+    };
+    fail(code, err.to_string())
+}
+
+/// Reads a UTF-8 string argument.
+unsafe fn read_str<'a>(value: *const c_char) -> Result<&'a str, c_int> {
+    if value.is_null() {
+        return Err(fail(RTR_ERR_NULL, "string argument is null"));
+    }
+    CStr::from_ptr(value)
+        .to_str()
+        .map_err(|_| fail(RTR_ERR_INVALID, "string argument is not UTF-8"))
+}
+
+/// Applies `set` to the configuration behind `config`.
+unsafe fn update<F>(config: *mut rtr_config_t, set: F) -> c_int
+where
+    F: FnOnce(&mut rtr_config_t) -> Result<(), c_int>,
+{
+    guard(|| match config.as_mut() {
+        Some(config) => set(config),
+        None => Err(fail(RTR_ERR_NULL, "config is null")),
+    })
+}
+
+/// Returns a new configuration with the defaults of `TraceRouteConfig`.
+///
+/// It has no destination yet, free it with `rtr_config_free`.
+#[no_mangle]
+pub extern "C" fn rtr_config_new() -> *mut rtr_config_t {
+    Box::into_raw(Box::new(rtr_config_t {
+        destination: None,
+        config: TraceRouteConfig::default(),
+    }))
+}
+
+/// Frees a configuration, null is ignored.
+///
+/// # Safety
+///
+/// `config` must come from `rtr_config_new` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_config_free(config: *mut rtr_config_t) {
+    if !config.is_null() {
+        drop(Box::from_raw(config));
+    }
+}
+
+/// Sets the address to trace, an IPv4 or IPv6 literal.
+///
+/// # Safety
+///
+/// `config` must come from `rtr_config_new`, `destination` must be a NUL
+/// terminated string or null.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_config_set_destination(
+    config: *mut rtr_config_t,
+    destination: *const c_char,
+) -> c_int {
+    update(config, |config| {
+        let text = read_str(destination)?;
+        let addr = text
+            .parse::<IpAddr>()
+            .map_err(|e| fail(RTR_ERR_INVALID, format!("{}: {}", text, e)))?;
+        config.destination = Some(addr);
+        Ok(())
+    })
+}
+
+/// Sets the probe protocol, one of `icmp`, `udp`, `dccp` or `raw:<proto>`.
+///
+/// # Safety
+///
+/// `config` must come from `rtr_config_new`, `protocol` must be a NUL terminated
+/// string or null.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_config_set_protocol(
+    config: *mut rtr_config_t,
+    protocol: *const c_char,
+) -> c_int {
+    update(config, |config| {
+        config.config.protocol = read_str(protocol)?
+            .parse::<TraceRouteProtocol>()
+            .map_err(|e| fail(RTR_ERR_INVALID, e.to_string()))?;
+        Ok(())
+    })
+}
+
+/// Sets the TTL of the first probe.
+///
+/// # Safety
+///
+/// `config` must come from `rtr_config_new`.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_config_set_begin_ttl(config: *mut rtr_config_t, ttl: u8) -> c_int {
+    update(config, |config| {
+        config.config.begin_ttl = ttl;
+        Ok(())
+    })
+}
+
+/// Sets the TTL of the last probe.
+///
+/// # Safety
+///
+/// `config` must come from `rtr_config_new`.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_config_set_max_ttl(config: *mut rtr_config_t, ttl: u8) -> c_int {
+    update(config, |config| {
+        config.config.max_ttl = ttl;
+        Ok(())
+    })
+}
+
+/// Sets how many probes are sent per TTL before it is given up.
+///
+/// # Safety
+///
+/// `config` must come from `rtr_config_new`.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_config_set_max_tries(config: *mut rtr_config_t, tries: u16) -> c_int {
+    update(config, |config| {
+        config.config.max_tries = tries;
+        Ok(())
+    })
+}
+
+/// Sets the base destination port of UDP and DCCP probes.
+///
+/// # Safety
+///
+/// `config` must come from `rtr_config_new`.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_config_set_port(config: *mut rtr_config_t, port: u16) -> c_int {
+    update(config, |config| {
+        config.config.port = port;
+        Ok(())
+    })
+}
+
+/// Sets how long to wait for a reply, in milliseconds.
+///
+/// # Safety
+///
+/// `config` must come from `rtr_config_new`.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_config_set_timeout_ms(
+    config: *mut rtr_config_t,
+    timeout_ms: u64,
+) -> c_int {
+    update(config, |config| {
+        config.config.timeout = Some(Duration::from_millis(timeout_ms));
+        Ok(())
+    })
+}
+
+/// Makes probes wait for their reply until the trace is cancelled.
+///
+/// # Safety
+///
+/// `config` must come from `rtr_config_new`.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_config_set_no_timeout(config: *mut rtr_config_t) -> c_int {
+    update(config, |config| {
+        config.config.timeout = None;
+        Ok(())
+    })
+}
+
+/// Sets the probe size in bytes.
+///
+/// # Safety
+///
+/// `config` must come from `rtr_config_new`.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_config_set_size(config: *mut rtr_config_t, size: usize) -> c_int {
+    update(config, |config| {
+        config.config.size = size;
+        Ok(())
+    })
+}
+
+/// Reports the local hops at the start of the path as one hop with `RTR_HOP_LOCAL`.
+///
+/// # Safety
+///
+/// `config` must come from `rtr_config_new`.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_config_set_hide_local_hops(
+    config: *mut rtr_config_t,
+    hide: bool,
+) -> c_int {
+    update(config, |config| {
+        config.config.hide_local_hops = hide;
+        Ok(())
+    })
+}
+
+/// Sends echo probes to the destination when a UDP trace ends in silence.
+///
+/// # Safety
+///
+/// `config` must come from `rtr_config_new`.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_config_set_confirm_silent_destination(
+    config: *mut rtr_config_t,
+    confirm: bool,
+) -> c_int {
+    update(config, |config| {
+        config.config.confirm_silent_destination = confirm;
+        Ok(())
+    })
+}
+
+/// Appends a gateway IPv4 probes are loose source routed through.
+///
+/// # Safety
+///
+/// `config` must come from `rtr_config_new`, `gateway` must be a NUL terminated
+/// string or null.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_config_add_gateway(
+    config: *mut rtr_config_t,
+    gateway: *const c_char,
+) -> c_int {
+    update(config, |config| {
+        let text = read_str(gateway)?;
+        let addr = text
+            .parse::<Ipv4Addr>()
+            .map_err(|e| fail(RTR_ERR_INVALID, format!("{}: {}", text, e)))?;
+        config.config.gateways.push(addr);
+        Ok(())
+    })
+}
+
+/// Converts a hop for the callback.
+fn hop_of(hop: &HopFound) -> rtr_hop_t {
+    let mut addr = [0 as c_char; RTR_ADDR_LEN];
+    if let Some(found) = hop.addr {
+        for (slot, byte) in addr.iter_mut().zip(found.to_string().bytes()) {
+            *slot = byte as c_char;
+        }
+    }
+    let mut flags = 0;
+    if hop.is_last {
+        flags |= RTR_HOP_LAST;
+    }
+    if hop.is_timeout() {
+        flags |= RTR_HOP_TIMEOUT;
+    }
+    if hop.is_end_of_trace() {
+        flags |= RTR_HOP_END;
+    }
+    if matches!(
+        hop.completion,
+        Some(CompletionReason::Reached) | Some(CompletionReason::ReachedButFiltered)
+    ) {
+        flags |= RTR_HOP_REACHED;
+    }
+    if hop.local_hops.is_some() {
+        flags |= RTR_HOP_LOCAL;
+    }
+    rtr_hop_t {
+        addr,
+        hop: hop.hop_count,
+        tries: hop.tries,
+        rtt_us: hop.time.map_or(-1, |time| time.as_micros() as i64),
+        flags,
+    }
+}
+
+/// Hands the hops of `receiver` to `callback` until the last one or a cancellation.
+fn forward(
+    receiver: Receiver<HopFound>,
+    callback: unsafe extern "C" fn(*const rtr_hop_t, *mut c_void),
+    user_data: UserData,
+    cancelled: Arc<AtomicBool>,
+) {
+    while !cancelled.load(Ordering::SeqCst) {
+        match receiver.recv_timeout(CANCEL_POLL) {
+            Ok(hop) => {
+                let flat = hop_of(&hop);
+                unsafe { callback(&flat, user_data.0) };
+                if hop.is_last {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// Starts a trace of `config` with `run`, which starts the worker.
+fn start<F>(
+    config: &rtr_config_t,
+    callback: rtr_hop_cb,
+    user_data: *mut c_void,
+    run: F,
+) -> Result<Box<rtr_trace_t>, c_int>
+where
+    F: FnOnce(&TraceRoute) -> Result<TraceHandle, TraceRouteError>,
+{
+    let callback = callback.ok_or_else(|| fail(RTR_ERR_NULL, "callback is null"))?;
+    let destination = config
+        .destination
+        .ok_or_else(|| fail(RTR_ERR_INVALID, "no destination was set"))?;
+    let (trace_route, receiver) =
+        TraceRoute::with_config(destination, config.config.clone()).map_err(code_of)?;
+    let handle = run(&trace_route).map_err(code_of)?;
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let flag = cancelled.clone();
+    let user_data = UserData(user_data);
+    let forwarder = thread::spawn(move || forward(receiver, callback, user_data, flag));
+    let failure = handle.failure.clone();
+    Ok(Box::new(rtr_trace_t {
+        cancelled,
+        forwarder,
+        handle,
+        failure,
+    }))
+}
+
+/// Starts tracing on worker threads, storing the trace in `*trace`.
+///
+/// `callback` gets every hop with `user_data`, on a thread of the trace, the last
+/// one has `RTR_HOP_LAST` set. The configuration is copied and can be freed right
+/// away. The trace must be ended with `rtr_trace_join`.
+///
+/// # Safety
+///
+/// `config` must come from `rtr_config_new`, `trace` must point to writable memory,
+/// and `user_data` must stay usable by `callback` until the trace is joined.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_trace_start(
+    config: *const rtr_config_t,
+    callback: rtr_hop_cb,
+    user_data: *mut c_void,
+    trace: *mut *mut rtr_trace_t,
+) -> c_int {
+    guard(|| {
+        let config = config
+            .as_ref()
+            .ok_or_else(|| fail(RTR_ERR_NULL, "config is null"))?;
+        let trace = trace
+            .as_mut()
+            .ok_or_else(|| fail(RTR_ERR_NULL, "trace is null"))?;
+        let started = start(config, callback, user_data, TraceRoute::run_trace_route)?;
+        *trace = Box::into_raw(started);
+        Ok(())
+    })
+}
+
+/// Stops handing hops to the callback and stops the worker, the trace still has
+/// to be joined.
+///
+/// The worker stops at its next wake, so joining can take up to the timeout of the
+/// probe waited for, or 100 milliseconds without timeout.
+///
+/// # Safety
+///
+/// `trace` must come from `rtr_trace_start` and not be joined yet.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_trace_cancel(trace: *mut rtr_trace_t) -> c_int {
+    guard(|| match trace.as_ref() {
+        Some(trace) => {
+            trace.cancelled.store(true, Ordering::SeqCst);
+            trace.handle.cancel();
+            Ok(())
+        }
+        None => Err(fail(RTR_ERR_NULL, "trace is null")),
+    })
+}
+
+/// Waits until the trace stopped, then frees it.
+///
+/// Returns `RTR_ERR_PANIC` if the worker or the forwarding thread panicked, and
+/// `RTR_ERR_TRACE` if the trace stopped on an error, like a probe that could not
+/// be sent, which `rtr_last_error` then describes.
+///
+/// # Safety
+///
+/// `trace` must come from `rtr_trace_start` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_trace_join(trace: *mut rtr_trace_t) -> c_int {
+    guard(|| {
+        if trace.is_null() {
+            return Err(fail(RTR_ERR_NULL, "trace is null"));
+        }
+        let trace = Box::from_raw(trace);
+        let forwarded = trace.forwarder.join();
+        let worked = trace.handle.join();
+        if forwarded.is_err() || worked.is_err() {
+            return Err(fail(RTR_ERR_PANIC, "the trace stopped with a panic"));
+        }
+        match trace.failure.lock().unwrap().take() {
+            Some(error) => Err(fail(RTR_ERR_TRACE, error.to_string())),
+            None => Ok(()),
+        }
+    })
+}
+
+/// Returns the message of the last error on this thread, or null.
+///
+/// The string is freed with `rtr_string_free`.
+#[no_mangle]
+pub extern "C" fn rtr_last_error() -> *mut c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => CString::new(message.replace('\0', ""))
+            .map(CString::into_raw)
+            .unwrap_or(ptr::null_mut()),
+        None => ptr::null_mut(),
+    })
+}
+
+/// Frees a string handed out by the library, null is ignored.
+///
+/// # Safety
+///
+/// `value` must come from this library and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn rtr_string_free(value: *mut c_char) {
+    if !value.is_null() {
+        drop(CString::from_raw(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_net_v4, SimulatedBackend};
+
+    fn last_error() -> String {
+        let raw = rtr_last_error();
+        assert!(!raw.is_null());
+        let message = unsafe { CStr::from_ptr(raw) }.to_str().unwrap().to_string();
+        unsafe { rtr_string_free(raw) };
+        message
+    }
+
+    unsafe extern "C" fn collect(hop: *const rtr_hop_t, user_data: *mut c_void) {
+        let hops = &*(user_data as *const Mutex<Vec<(String, u8, i64, u32)>>);
+        let hop = &*hop;
+        let addr = CStr::from_ptr(hop.addr.as_ptr())
+            .to_str()
+            .unwrap()
+            .to_string();
+        hops.lock()
+            .unwrap()
+            .push((addr, hop.hop, hop.rtt_us, hop.flags));
+    }
+
+    fn simulated_config() -> *mut rtr_config_t {
+        let config = rtr_config_new();
+        unsafe {
+            assert_eq!(
+                rtr_config_set_destination(config, b"192.0.2.100\0".as_ptr() as *const c_char),
+                RTR_OK
+            );
+            assert_eq!(rtr_config_set_max_tries(config, 1), RTR_OK);
+            assert_eq!(rtr_config_set_timeout_ms(config, 10), RTR_OK);
+        }
+        config
+    }
+
+    #[test]
+    fn hops_reach_the_callback() {
+        let config = simulated_config();
+        let hops: Mutex<Vec<(String, u8, i64, u32)>> = Mutex::new(Vec::new());
+        let backend = SimulatedBackend::new(vec![Some(test_net_v4(1)), None], test_net_v4(100));
+        let trace = start(
+            unsafe { &*config },
+            Some(collect),
+            &hops as *const _ as *mut c_void,
+            |trace_route| trace_route.run_with_backend(backend, test_net_v4(254)),
+        )
+        .unwrap();
+        unsafe {
+            rtr_config_free(config);
+            assert_eq!(rtr_trace_join(Box::into_raw(trace)), RTR_OK);
+        }
+
+        let hops = hops.into_inner().unwrap();
+        assert_eq!(hops.len(), 3);
+        assert_eq!(
+            (hops[0].0.as_str(), hops[0].1, hops[0].3),
+            ("192.0.2.1", 1, 0)
+        );
+        assert!(hops[0].2 >= 0);
+        assert_eq!(
+            (hops[1].0.as_str(), hops[1].2, hops[1].3),
+            ("", -1, RTR_HOP_TIMEOUT)
+        );
+        assert_eq!(hops[2].0, "192.0.2.100");
+        assert_eq!(hops[2].3, RTR_HOP_LAST | RTR_HOP_REACHED);
+    }
+
+    #[test]
+    fn failed_traces_are_error_codes() {
+        let config = simulated_config();
+        let hops: Mutex<Vec<(String, u8, i64, u32)>> = Mutex::new(Vec::new());
+        let backend = SimulatedBackend::new(vec![Some(test_net_v4(1))], test_net_v4(100))
+            .with_send_errors(vec![libc::ENETUNREACH]);
+        let trace = start(
+            unsafe { &*config },
+            Some(collect),
+            &hops as *const _ as *mut c_void,
+            |trace_route| trace_route.run_with_backend(backend, test_net_v4(254)),
+        )
+        .unwrap();
+        unsafe {
+            rtr_config_free(config);
+            assert_eq!(rtr_trace_join(Box::into_raw(trace)), RTR_ERR_TRACE);
+        }
+
+        assert!(last_error().starts_with("Could not send packet"));
+        assert!(hops.into_inner().unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_end_of_a_trace_is_no_timeout() {
+        let end = HopFound::end_of_trace(31, 0, CompletionReason::NotReached);
+        assert_eq!(hop_of(&end).flags, RTR_HOP_LAST | RTR_HOP_END);
+        assert_eq!(hop_of(&HopFound::timed_out(30, 3)).flags, RTR_HOP_TIMEOUT);
+    }
+
+    #[test]
+    fn cancelled_trace_stops_calling_back() {
+        let config = simulated_config();
+        unsafe { rtr_config_set_max_ttl(config, 30) };
+        let hops: Mutex<Vec<(String, u8, i64, u32)>> = Mutex::new(Vec::new());
+        let path = (1..=20).map(|n| Some(test_net_v4(n))).collect();
+        let backend = SimulatedBackend::new(path, test_net_v4(100))
+            .with_reply_delay(Duration::from_millis(20));
+        let trace = start(
+            unsafe { &*config },
+            Some(collect),
+            &hops as *const _ as *mut c_void,
+            |trace_route| trace_route.run_with_backend(backend, test_net_v4(254)),
+        )
+        .unwrap();
+        let trace = Box::into_raw(trace);
+        unsafe {
+            assert_eq!(rtr_trace_cancel(trace), RTR_OK);
+            assert_eq!(rtr_trace_join(trace), RTR_OK);
+            rtr_config_free(config);
+        }
+        assert!(hops.lock().unwrap().len() < 20);
+    }
+
+    #[test]
+    fn bad_arguments_are_error_codes() {
+        let config = rtr_config_new();
+        unsafe {
+            assert_eq!(rtr_config_set_max_ttl(ptr::null_mut(), 5), RTR_ERR_NULL);
+            assert_eq!(
+                rtr_config_set_protocol(config, b"sctp\0".as_ptr() as *const c_char),
+                RTR_ERR_INVALID
+            );
+            assert!(last_error().contains("sctp"));
+            assert_eq!(
+                rtr_config_set_destination(config, b"\xff\0".as_ptr() as *const c_char),
+                RTR_ERR_INVALID
+            );
+            assert_eq!(
+                rtr_config_set_destination(config, b"192.0.2.1\0".as_ptr() as *const c_char),
+                RTR_OK
+            );
+            assert_eq!(rtr_config_set_max_ttl(config, 0), RTR_OK);
+            let mut trace = ptr::null_mut();
+            assert_eq!(
+                rtr_trace_start(config, Some(collect), ptr::null_mut(), &mut trace),
+                RTR_ERR_CONFIG
+            );
+            assert_eq!(last_error(), "BAD MAX TTL");
+            assert!(trace.is_null());
+            assert_eq!(
+                rtr_trace_start(config, None, ptr::null_mut(), &mut trace),
+                RTR_ERR_NULL
+            );
+            assert_eq!(rtr_trace_join(ptr::null_mut()), RTR_ERR_NULL);
+            rtr_config_free(config);
+        }
+    }
+
+    #[test]
+    fn panics_become_error_codes() {
+        assert_eq!(guard(|| panic!("boom")), RTR_ERR_PANIC);
+        assert_eq!(last_error(), "librtraceroute panicked");
+    }
+
+    #[test]
+    fn header_declares_the_interface() {
+        let header = include_str!(concat!(env!("OUT_DIR"), "/librtraceroute.h"));
+        for name in &[
+            "rtr_hop_t",
+            "rtr_config_new",
+            "rtr_config_set_max_ttl",
+            "rtr_config_set_no_timeout",
+            "rtr_trace_start",
+            "rtr_trace_cancel",
+            "rtr_trace_join",
+            "rtr_string_free",
+            "RTR_ERR_PANIC",
+            "RTR_ERR_TRACE",
+        ] {
+            assert!(header.contains(name), "{} missing", name);
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//! Options of traces read from TOML and JSON files, see `ConfigFile`.
+use crate::config::InterfaceSel;
+use crate::{TraceRouteConfig, TraceRouteError, TraceRouteProtocol};
+use serde::de::{self, Deserialize, Deserializer};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::time::Duration;
+
+/// Options as written in a file, the ones left out keep the value they had.
+/// Durations are in milliseconds.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Overrides {
+    max_ttl: Option<u8>,
+    begin_ttl: Option<u8>,
+    max_tries: Option<u16>,
+    port: Option<u16>,
+    timeout: Option<u64>,
+    size: Option<usize>,
+    #[serde(default, deserialize_with = "protocol")]
+    protocol: Option<TraceRouteProtocol>,
+    allow_special_destinations: Option<bool>,
+    reject_directed_broadcast: Option<bool>,
+    allow_documentation_destinations: Option<bool>,
+    unmap_ipv4: Option<bool>,
+    confirm_silent_destination: Option<bool>,
+    confirm_probes: Option<u16>,
+    confirm_tcp_port: Option<u16>,
+    confirm_timeout: Option<u64>,
+    hide_local_hops: Option<bool>,
+    gateways: Option<Vec<Ipv4Addr>>,
+    record_route: Option<bool>,
+    timestamps: Option<bool>,
+    capture_raw: Option<bool>,
+    interface: Option<String>,
+    fwmark: Option<u32>,
+    dscp: Option<u8>,
+    warmup: Option<bool>,
+    first_hop_interval: Option<u64>,
+    continue_past_destination: Option<bool>,
+    window: Option<u8>,
+    correct_late_replies: Option<u64>,
+    second_pass: Option<bool>,
+    rtt_offset: Option<u64>,
+    fragment: Option<bool>,
+}
+
+/// Reads a protocol by its name, see `TraceRouteProtocol::from_str`.
+fn protocol<'de, D>(deserializer: D) -> Result<Option<TraceRouteProtocol>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(deserializer)?;
+    name.parse().map(Some).map_err(de::Error::custom)
+}
+
+impl Overrides {
+    /// Returns `base` with these options applied, once they passed the checks that
+    /// do not depend on the destination.
+    fn apply(&self, mut base: TraceRouteConfig) -> Result<TraceRouteConfig, TraceRouteError> {
+        let millis = |value: &Option<u64>| value.map(Duration::from_millis);
+        macro_rules! set {
+            ($($field:ident),*) => {
+                $(if let Some(value) = &self.$field {
+                    base.$field = value.clone();
+                })*
+            };
+        }
+        set!(
+            max_ttl,
+            begin_ttl,
+            max_tries,
+            port,
+            size,
+            protocol,
+            allow_special_destinations,
+            reject_directed_broadcast,
+            allow_documentation_destinations,
+            unmap_ipv4,
+            confirm_silent_destination,
+            confirm_probes,
+            hide_local_hops,
+            gateways,
+            record_route,
+            timestamps,
+            capture_raw,
+            dscp,
+            warmup,
+            continue_past_destination,
+            second_pass,
+            fragment
+        );
+        if let Some(timeout) = millis(&self.timeout) {
+            base.timeout = Some(timeout);
+        }
+        if let Some(timeout) = millis(&self.confirm_timeout) {
+            base.confirm_timeout = Some(timeout);
+        }
+        if let Some(grace) = millis(&self.correct_late_replies) {
+            base.correct_late_replies = Some(grace);
+        }
+        if let Some(offset) = millis(&self.rtt_offset) {
+            base.rtt_offset = offset;
+        }
+        if let Some(interface) = &self.interface {
+            base.interface = Some(InterfaceSel::Name(interface.clone()));
+        }
+        base.confirm_tcp_port = self.confirm_tcp_port.or(base.confirm_tcp_port);
+        base.fwmark = self.fwmark.or(base.fwmark);
+        base.first_hop_interval = self.first_hop_interval.or(base.first_hop_interval);
+        base.window = self.window.or(base.window);
+        base.check_options()?;
+        Ok(base)
+    }
+}
+
+/// Reads the options of a trace as written in a configuration file, the ones left
+/// out take their defaults, and checks the ones that do not depend on the
+/// destination.
+impl<'de> Deserialize<'de> for TraceRouteConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Overrides::deserialize(deserializer)?
+            .apply(TraceRouteConfig::default())
+            .map_err(de::Error::custom)
+    }
+}
+
+/// A target as written in a file.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Target {
+    host: String,
+    #[serde(default)]
+    overrides: Overrides,
+}
+
+/// A configuration file as written.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Written {
+    threads: Option<usize>,
+    #[serde(default)]
+    defaults: Overrides,
+    #[serde(default)]
+    targets: Vec<Target>,
+}
+
+/// This struct is a target of a configuration file with the options it is traced
+/// with, its overrides applied to the defaults of the file.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct TargetSpec {
+    /// Host name or address of the target.
+    pub host: String,
+    pub config: TraceRouteConfig,
+}
+
+/// This struct holds the traces a configuration file describes, for agents keeping
+/// their probe definitions in files.
+///
+/// The file sets `threads` for `TracePool::from_config_file`, options in
+/// `defaults` for all targets and a list of `targets`, each a `host` with the
+/// options it `overrides`. Option names are the ones of `TraceRouteConfig`, the
+/// protocol is given by its name and durations in milliseconds.
+///
+/// Unknown options are refused, and the options of every target are checked like
+/// `TraceRoute::with_config` would, those depending on the destination only for
+/// targets given by address.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct ConfigFile {
+    /// Threads of the pool the targets are traced on, None for one per target.
+    pub threads: Option<usize>,
+    /// Options of the targets without overrides.
+    pub defaults: TraceRouteConfig,
+    pub targets: Vec<TargetSpec>,
+}
+
+impl ConfigFile {
+    /// Reads a configuration written in TOML.
+    pub fn from_toml(text: &str) -> Result<ConfigFile, TraceRouteError> {
+        ConfigFile::checked(toml::from_str(text).map_err(bad_file)?)
+    }
+
+    /// Reads a configuration written in JSON.
+    pub fn from_json(text: &str) -> Result<ConfigFile, TraceRouteError> {
+        ConfigFile::checked(serde_json::from_str(text).map_err(bad_file)?)
+    }
+
+    /// Reads the configuration file at `path`, in JSON if its name ends in
+    /// `.json` and in TOML otherwise.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ConfigFile, TraceRouteError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| TraceRouteError::BadConfigFile {
+            message: format!("{}: {}", path.display(), e),
+        })?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => ConfigFile::from_json(&text),
+            _ => ConfigFile::from_toml(&text),
+        }
+    }
+
+    fn checked(written: Written) -> Result<ConfigFile, TraceRouteError> {
+        let in_defaults = |e: TraceRouteError| bad_file(format!("defaults: {}", e));
+        let defaults = written
+            .defaults
+            .apply(TraceRouteConfig::default())
+            .map_err(in_defaults)?;
+        let targets = written
+            .targets
+            .into_iter()
+            .map(|target| {
+                let in_target = |e: TraceRouteError| bad_file(format!("{}: {}", target.host, e));
+                let config = target
+                    .overrides
+                    .apply(defaults.clone())
+                    .map_err(in_target)?;
+                if let Ok(address) = target.host.parse::<IpAddr>() {
+                    config.validate(address).map_err(in_target)?;
+                }
+                Ok(TargetSpec {
+                    host: target.host,
+                    config,
+                })
+            })
+            .collect::<Result<Vec<_>, TraceRouteError>>()?;
+        Ok(ConfigFile {
+            threads: written.threads,
+            defaults,
+            targets,
+        })
+    }
+}
+
+fn bad_file<E: ToString>(e: E) -> TraceRouteError {
+    TraceRouteError::BadConfigFile {
+        message: e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_set_the_options_of_targets() {
+        let toml = ConfigFile::from_toml(include_str!("../tests/corpus/targets.toml")).unwrap();
+        let json = ConfigFile::from_json(include_str!("../tests/corpus/targets.json")).unwrap();
+        assert_eq!(toml, json);
+        assert_eq!(toml.threads, Some(2));
+        let defaults = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Icmp,
+            max_tries: 2,
+            timeout: Some(Duration::from_millis(500)),
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(toml.defaults, defaults);
+        let hosts: Vec<&str> = toml
+            .targets
+            .iter()
+            .map(|target| target.host.as_str())
+            .collect();
+        assert_eq!(hosts, vec!["10.0.0.1", "example.net"]);
+        assert_eq!(toml.targets[0].config, defaults);
+        let overridden = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Udp,
+            max_ttl: 20,
+            second_pass: true,
+            ..defaults
+        };
+        assert_eq!(toml.targets[1].config, overridden);
+
+        // Omitted options take their defaults.
+        let empty = ConfigFile::from_json("{}").unwrap();
+        assert_eq!(empty.defaults, TraceRouteConfig::default());
+        assert!(empty.targets.is_empty());
+    }
+
+    #[test]
+    fn bad_files_are_refused() {
+        let error = |result: Result<ConfigFile, TraceRouteError>| result.unwrap_err().to_string();
+        let unknown = error(ConfigFile::from_toml(include_str!(
+            "../tests/corpus/bad-field.toml"
+        )));
+        assert!(unknown.starts_with("Invalid configuration file: "));
+        assert!(unknown.contains("unknown field `max_hops`"));
+        let ttl = error(ConfigFile::from_json(include_str!(
+            "../tests/corpus/bad-ttl.json"
+        )));
+        assert!(ttl.contains("invalid value: integer `300`, expected u8"));
+        assert!(ttl.contains("line 4"));
+        let protocol = error(ConfigFile::from_toml(include_str!(
+            "../tests/corpus/bad-protocol.toml"
+        )));
+        assert!(protocol.contains("unknown protocol \"sctp\""));
+        // Values each in range can still make no sense together.
+        let order = error(ConfigFile::from_toml(
+            "[[targets]]\nhost = \"192.0.2.1\"\noverrides = { begin_ttl = 12, max_ttl = 10 }\n",
+        ));
+        assert_eq!(
+            order,
+            "Invalid configuration file: 192.0.2.1: BAD START TTL"
+        );
+        let family = error(ConfigFile::from_json(
+            r#"{"targets": [{"host": "2001:db8::1", "overrides": {"record_route": true}}]}"#,
+        ));
+        assert!(family.contains("2001:db8::1: BAD RECORD ROUTE"));
+        assert!(error(ConfigFile::load("tests/corpus/missing.toml")).contains("missing.toml"));
+    }
+
+    #[test]
+    fn configs_deserialize_alone() {
+        let config: TraceRouteConfig = serde_json::from_str(r#"{"protocol": "udp"}"#).unwrap();
+        assert_eq!(config, TraceRouteConfig::default());
+        let zero = serde_json::from_str::<TraceRouteConfig>(r#"{"max_ttl": 0}"#);
+        assert!(zero.unwrap_err().to_string().contains("BAD MAX TTL"));
+    }
+}
@@ -0,0 +1,117 @@
+//! Discovery of the first hop of the paths leaving this host.
+use crate::config::TraceRouteConfig;
+use crate::error::TraceRouteError;
+use crate::TraceRoute;
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// This enum represents an address family.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+/// Probes sent to find the first hop never get past it, any routable address works.
+const TARGET_V4: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+const TARGET_V6: Ipv6Addr = Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111);
+
+/// Returns the router answering a TTL=1 probe of the given family.
+///
+/// This opens raw sockets like `TraceRoute::run_trace_route` does, see
+/// `TraceRoute::first_hop` for the details.
+pub fn discover_first_hop(family: IpFamily) -> Result<IpAddr, TraceRouteError> {
+    let target = match family {
+        IpFamily::V4 => IpAddr::V4(TARGET_V4),
+        IpFamily::V6 => IpAddr::V6(TARGET_V6),
+    };
+    let (trace_route, _) = TraceRoute::with_config(target, TraceRouteConfig::default())?;
+    trace_route.first_hop()
+}
+
+/// Returns the gateway of the default route of the given family.
+///
+/// Read from the kernel routing table, handy to cross-check `discover_first_hop`.
+#[cfg(target_os = "linux")]
+pub fn default_gateway(family: IpFamily) -> Option<IpAddr> {
+    match family {
+        IpFamily::V4 => fs::read_to_string("/proc/net/route")
+            .ok()
+            .and_then(|table| parse_route_v4(&table))
+            .map(IpAddr::V4),
+        IpFamily::V6 => fs::read_to_string("/proc/net/ipv6_route")
+            .ok()
+            .and_then(|table| parse_route_v6(&table))
+            .map(IpAddr::V6),
+    }
+}
+
+/// Route flag marking routes through a gateway.
+#[cfg(target_os = "linux")]
+const RTF_GATEWAY: u32 = 0x2;
+
+/// Finds the default gateway in the text of `/proc/net/route`.
+///
+/// Addresses are printed as the hex of a native endian integer in network order.
+#[cfg(target_os = "linux")]
+fn parse_route_v4(table: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let hex = |n: usize| u32::from_str_radix(fields.get(n)?, 16).ok();
+        let (destination, gateway, flags, mask) = (hex(1)?, hex(2)?, hex(3)?, hex(7)?);
+        if destination == 0 && mask == 0 && flags & RTF_GATEWAY != 0 {
+            Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+        } else {
+            None
+        }
+    })
+}
+
+/// Finds the default gateway in the text of `/proc/net/ipv6_route`.
+#[cfg(target_os = "linux")]
+fn parse_route_v6(table: &str) -> Option<Ipv6Addr> {
+    table.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let destination = u128::from_str_radix(fields.first()?, 16).ok()?;
+        let prefix = u8::from_str_radix(fields.get(1)?, 16).ok()?;
+        let next_hop = u128::from_str_radix(fields.get(4)?, 16).ok()?;
+        let flags = u32::from_str_radix(fields.get(8)?, 16).ok()?;
+        if destination == 0 && prefix == 0 && next_hop != 0 && flags & RTF_GATEWAY != 0 {
+            Some(Ipv6Addr::from(next_hop))
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_route_v4() {
+        let gateway = Ipv4Addr::new(192, 168, 1, 1);
+        let table = format!(
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+             eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n\
+             eth0\t00000000\t{:08X}\t0003\t0\t0\t100\t00000000\t0\t0\t0\n",
+            u32::from_ne_bytes(gateway.octets())
+        );
+        assert_eq!(parse_route_v4(&table), Some(gateway));
+        let without_default: String = table.lines().take(2).collect::<Vec<_>>().join("\n");
+        assert_eq!(parse_route_v4(&without_default), None);
+    }
+
+    #[test]
+    fn default_route_v6() {
+        let table = "\
+fe800000000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001 eth0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe800000000000000000000000000001 00000400 00000001 00000000 00000003 eth0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200 lo
+";
+        assert_eq!(parse_route_v6(table), "fe80::1".parse().ok());
+        assert_eq!(parse_route_v6(table.lines().next().unwrap()), None);
+        assert_eq!(parse_route_v6(table.lines().nth(2).unwrap()), None);
+    }
+}
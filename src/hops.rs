@@ -0,0 +1,82 @@
+//! Iteration over the hops of a running trace.
+use crate::{HopFound, TraceHandle, TraceRouteError};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::time::{Duration, Instant};
+
+/// How long the iterator waits for a hop before checking on the worker.
+const WORKER_POLL: Duration = Duration::from_millis(50);
+
+/// This struct iterates over the hops of a trace as they are found, see
+/// `TraceHandle::hops`.
+///
+/// It ends after the terminal hop. A worker that stopped without reporting it
+/// ends the iteration with its error, and so does a wait longer than the
+/// timeout, if one is set.
+pub struct HopIter<'h> {
+    handle: &'h TraceHandle,
+    receiver: Receiver<HopFound>,
+    timeout: Option<Duration>,
+    done: bool,
+}
+
+impl<'h> HopIter<'h> {
+    /// Creates new HopIter over the hops `handle` reports on `receiver`.
+    pub(crate) fn new(handle: &'h TraceHandle, receiver: Receiver<HopFound>) -> HopIter<'h> {
+        HopIter {
+            handle,
+            receiver,
+            timeout: None,
+            done: false,
+        }
+    }
+
+    /// Gives up with `TraceRouteError::HopTimeout` when no hop came for `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> HopIter<'h> {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn hop(&mut self, hop: HopFound) -> Option<Result<HopFound, TraceRouteError>> {
+        self.done = hop.is_last;
+        Some(Ok(hop))
+    }
+
+    fn fail(&mut self, error: TraceRouteError) -> Option<Result<HopFound, TraceRouteError>> {
+        self.done = true;
+        Some(Err(error))
+    }
+}
+
+impl Iterator for HopIter<'_> {
+    type Item = Result<HopFound, TraceRouteError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let started = Instant::now();
+        loop {
+            let poll = match self.timeout {
+                Some(timeout) => match timeout.checked_sub(started.elapsed()) {
+                    Some(left) => left.min(WORKER_POLL),
+                    None => return self.fail(TraceRouteError::HopTimeout),
+                },
+                None => WORKER_POLL,
+            };
+            match self.receiver.recv_timeout(poll) {
+                Ok(hop) => return self.hop(hop),
+                Err(RecvTimeoutError::Disconnected) => return self.fail(self.handle.failure()),
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            if self.handle.is_finished() {
+                // The last hops may have been sent right before the worker stopped.
+                return match self.receiver.try_recv() {
+                    Ok(hop) => self.hop(hop),
+                    Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {
+                        self.fail(self.handle.failure())
+                    }
+                };
+            }
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! Identifiers and source ports of traces, handed out so that traces running at
+//! the same time, in one process or several, do not take each other's replies.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+/// This struct is what one trace may stamp on its probes, see `IdAllocator`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct IdRange {
+    /// Identifier of the ICMP echo probes, also folded into DCCP sequence numbers.
+    pub identifier: u16,
+    /// First source port of the UDP and DCCP probes.
+    pub first_port: u16,
+    /// Source ports from `first_port` on, the probes take them in turn.
+    pub ports: u16,
+}
+
+impl IdRange {
+    /// Creates new IdRange, at least one port wide.
+    pub fn new(identifier: u16, first_port: u16, ports: u16) -> IdRange {
+        IdRange {
+            identifier,
+            first_port,
+            ports: ports.max(1),
+        }
+    }
+
+    /// Returns the source port of the `sequence`th probe of the trace.
+    pub fn port(&self, sequence: u16) -> u16 {
+        self.first_port.wrapping_add(sequence % self.ports)
+    }
+
+    /// Returns true if `port` is one of the source ports of the range.
+    pub fn contains_port(&self, port: u16) -> bool {
+        port.wrapping_sub(self.first_port) < self.ports
+    }
+
+    /// Returns true if the two ranges share the identifier or a source port.
+    pub fn overlaps(&self, other: &IdRange) -> bool {
+        self.identifier == other.identifier
+            || self.contains_port(other.first_port)
+            || other.contains_port(self.first_port)
+    }
+}
+
+/// This trait hands out the identifier and source ports of every trace, see
+/// `TraceRouteConfig::id_allocator`.
+///
+/// Raw sockets see the ICMP replies of every process of the host, traces of
+/// several processes stay apart only if their allocators agree, e.g. through
+/// shared memory or a lock file. `LocalIdAllocator` covers a single process.
+pub trait IdAllocator: Send + Sync {
+    /// Returns a range no running trace holds, None when all are taken.
+    fn allocate(&self) -> Option<IdRange>;
+
+    /// Gives back the range of a trace that ended.
+    fn release(&self, range: IdRange);
+}
+
+/// This struct splits a span of source ports into equal blocks, one per running
+/// trace of the process.
+///
+/// Released blocks are handed out again only after every other free block, so
+/// late replies to an ended trace do not land in the next one.
+pub struct LocalIdAllocator {
+    first_port: u16,
+    ports: u16,
+    blocks: u16,
+    base: u16,
+    state: Mutex<Blocks>,
+}
+
+/// This struct is the blocks of a `LocalIdAllocator` held by traces.
+#[derive(Default)]
+struct Blocks {
+    taken: BTreeSet<u16>,
+    next: u16,
+}
+
+impl LocalIdAllocator {
+    /// Creates new LocalIdAllocator handing out `blocks` blocks of `ports` source
+    /// ports from `first_port` on, fewer if they would run past port 65535.
+    pub fn new(first_port: u16, ports: u16, blocks: u16) -> LocalIdAllocator {
+        let ports = ports.max(1);
+        let room = (65536 - u32::from(first_port)) / u32::from(ports);
+        LocalIdAllocator {
+            first_port,
+            ports,
+            blocks: u32::from(blocks).min(room) as u16,
+            base: (process::id() & 0xffff) as u16,
+            state: Mutex::new(Blocks::default()),
+        }
+    }
+
+    /// Returns the number of ranges held by traces.
+    pub fn in_use(&self) -> usize {
+        self.state.lock().unwrap().taken.len()
+    }
+
+    fn range(&self, block: u16) -> IdRange {
+        IdRange::new(
+            self.base.wrapping_add(block),
+            self.first_port + block * self.ports,
+            self.ports,
+        )
+    }
+}
+
+impl Default for LocalIdAllocator {
+    /// Blocks of 64 ports across the Linux ephemeral ports, 32768 to 61439.
+    fn default() -> LocalIdAllocator {
+        LocalIdAllocator::new(32768, 64, 448)
+    }
+}
+
+impl fmt::Debug for LocalIdAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalIdAllocator")
+            .field("first_port", &self.first_port)
+            .field("ports", &self.ports)
+            .field("blocks", &self.blocks)
+            .field("in_use", &self.in_use())
+            .finish()
+    }
+}
+
+impl IdAllocator for LocalIdAllocator {
+    fn allocate(&self) -> Option<IdRange> {
+        let mut state = self.state.lock().unwrap();
+        let block = (0..self.blocks)
+            .map(|offset| {
+                ((u32::from(state.next) + u32::from(offset)) % u32::from(self.blocks)) as u16
+            })
+            .find(|block| !state.taken.contains(block))?;
+        state.taken.insert(block);
+        state.next = (block + 1) % self.blocks;
+        Some(self.range(block))
+    }
+
+    fn release(&self, range: IdRange) {
+        if range.first_port < self.first_port {
+            return;
+        }
+        let block = (range.first_port - self.first_port) / self.ports;
+        if self.range(block) == range {
+            self.state.lock().unwrap().taken.remove(&block);
+        }
+    }
+}
+
+/// This struct is the allocator of `TraceRouteConfig::id_allocator`.
+///
+/// Configurations holding it compare equal only if they share the same allocator.
+#[derive(Clone)]
+pub struct IdSource(Arc<dyn IdAllocator>);
+
+impl IdSource {
+    /// Creates new IdSource handing out ranges of `allocator`.
+    pub fn new<A: IdAllocator + 'static>(allocator: A) -> IdSource {
+        IdSource(Arc::new(allocator))
+    }
+
+    /// Creates new IdSource sharing `allocator` with other owners, like other
+    /// configurations or the application looking at the ranges in use.
+    pub fn shared(allocator: Arc<dyn IdAllocator>) -> IdSource {
+        IdSource(allocator)
+    }
+
+    /// Takes a range for a trace, given back when the lease is dropped.
+    pub(crate) fn lease(&self) -> Option<IdLease> {
+        let range = self.0.allocate()?;
+        Some(IdLease {
+            allocator: self.0.clone(),
+            range,
+        })
+    }
+}
+
+impl fmt::Debug for IdSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("IdSource")
+    }
+}
+
+impl PartialEq for IdSource {
+    fn eq(&self, other: &IdSource) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// This struct is the range a running trace holds, released when it is dropped.
+pub(crate) struct IdLease {
+    allocator: Arc<dyn IdAllocator>,
+    range: IdRange,
+}
+
+impl IdLease {
+    pub(crate) fn range(&self) -> &IdRange {
+        &self.range
+    }
+}
+
+impl Drop for IdLease {
+    fn drop(&mut self) {
+        self.allocator.release(self.range);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_ranges_do_not_overlap_and_come_back() {
+        let allocator = LocalIdAllocator::new(40000, 16, 4);
+        let ranges: Vec<IdRange> = (0..4).map(|_| allocator.allocate().unwrap()).collect();
+        for (i, range) in ranges.iter().enumerate() {
+            assert_eq!(range.ports, 16);
+            assert!(range.contains_port(range.port(17)));
+            for other in &ranges[i + 1..] {
+                assert!(!range.overlaps(other));
+            }
+        }
+        assert_eq!(allocator.allocate(), None);
+        allocator.release(ranges[2]);
+        assert_eq!(allocator.in_use(), 3);
+        assert_eq!(allocator.allocate(), Some(ranges[2]));
+    }
+
+    #[test]
+    fn local_blocks_stop_at_the_last_port() {
+        let allocator = LocalIdAllocator::new(65500, 16, 10);
+        assert!(allocator.allocate().is_some());
+        assert!(allocator.allocate().is_some());
+        assert_eq!(allocator.allocate(), None);
+    }
+}
@@ -1,65 +1,424 @@
 //! Fast route tracing library.
 //!
 //! [`librtraceroute`]: https://github.com/toorajtaraz/librtraceroute
-extern crate pnet;
 extern crate ansi_term;
+extern crate pnet;
+
+#[macro_use]
+mod logging;
+
+mod anomaly;
+mod anycast;
+mod backend;
+mod broadcast;
+mod clock;
+mod config;
+#[cfg(target_os = "linux")]
+mod dgram;
+mod diff;
+mod error;
+mod event;
+#[cfg(feature = "prometheus")]
+mod exporter;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "serde")]
+mod file;
+mod gateway;
+mod hmac;
+mod hops;
+mod ids;
+mod link;
+mod machine;
+mod metrics;
+mod monitor;
+#[cfg(feature = "pcap")]
+mod pcap;
+mod ping;
+#[cfg(target_os = "linux")]
+mod pool;
+mod preflight;
+mod probe;
+#[cfg(feature = "python")]
+mod python;
+mod receiver;
+mod redact;
+mod registry;
+mod reply;
+mod report;
+mod scope;
+mod shared;
+mod sink;
+mod stats;
+mod sweep;
+pub mod testing;
+pub mod topology;
+
+pub use anycast::{
+    AnycastInstance, AnycastOptions, AnycastReport, AnycastTracker, InstanceSwitch, Origin,
+    OriginFn,
+};
+pub use backend::{BackendKind, PnetBackend, ProbeBackend, ReplyKind, TimestampSource};
+use backend::{Lent, SocketCache};
+use broadcast::Broadcast;
+pub use clock::{Clock, SystemClock};
+pub use config::{
+    DatalinkConfig, ExtensionHeader, InterfaceSel, PortFallback, PortFn, PreflightConfig,
+    ProtocolFallback, RetrySpec, SequencePlan, TerminalPolicy, TerminalRule, TerminalSource,
+    TraceRouteConfig, TtlOrder,
+};
+pub use diff::{diff_traces, DiffOptions, HopChange, TraceDiff};
+pub use error::TraceRouteError;
+pub use event::{
+    HopCorrection, PathAnomaly, PreviousOutcome, ProbeSent, TraceComplete, TraceEvent, TraceRetry,
+    TraceWarning,
+};
+#[cfg(feature = "prometheus")]
+pub use exporter::{ExporterOptions, PrometheusExporter};
+#[cfg(feature = "serde")]
+pub use file::{ConfigFile, TargetSpec};
+#[cfg(target_os = "linux")]
+pub use gateway::default_gateway;
+pub use gateway::{discover_first_hop, IpFamily};
+pub use hops::HopIter;
+pub use ids::{IdAllocator, IdRange, IdSource, LocalIdAllocator};
+pub use metrics::{TraceMetrics, TraceState, TraceStatus};
+#[cfg(feature = "pcap")]
+pub use pcap::PcapReplayBackend;
+pub use ping::PingStats;
+#[cfg(target_os = "linux")]
+pub use pool::TracePool;
+pub use preflight::{
+    Preflight, PreflightAttempt, PreflightChoice, PreflightOutcome, Resolver, SystemResolver,
+};
+pub use probe::{InterfaceQuery, InterfaceState, InterfaceStatus, NeighborState};
+pub use redact::{RedactPolicy, Redactor};
+pub use reply::{
+    InterfaceHint, InterfaceInfo, MalformedReason, MalformedReply, MangledField, ParameterProblem,
+    ProbeKey, ProbeToken,
+};
+pub use report::{ReplaySource, TraceReport};
+pub use scope::{addr_scope, embedded_v4, transition_tech, AddrScope, TransitionTech};
+pub use shared::SharedReceiver;
+pub use stats::{HopResponse, HopStats, PathStats, ResponseClassifier};
+pub use sweep::{
+    DscpSweep, ExtensionHeaderComparison, FragmentComparison, HopMtu, MtuSweep, ProtocolComparison,
+    SweepHop,
+};
+
+#[cfg(target_os = "linux")]
+use dgram::{DgramBackend, DgramProtocol};
+use link::LinkBackend;
+use machine::TraceMachine;
+use metrics::Metrics;
+use monitor::FirstHopMonitor;
+use registry::{ProbeRecord, ProbeRegistry};
+use sink::{Emitter, HopSink};
 
 use pnet::datalink;
-use pnet::packet::Packet;
-use pnet::packet::icmp::IcmpTypes;
 use pnet::packet::icmp::echo_request;
+use pnet::packet::icmp::IcmpTypes;
 use pnet::packet::icmpv6::{Icmpv6Types, MutableIcmpv6Packet};
-use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::{icmp, icmpv6, ipv4, ipv6, udp};
-use pnet::transport::TransportChannelType::{Layer3, Layer4};
-use pnet::transport::TransportProtocol::Ipv4;
-use pnet::transport::TransportSender;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::Packet;
+use pnet::packet::{icmpv6, ipv4, ipv6, udp};
 use pnet::transport::transport_channel;
-use pnet::transport::{icmp_packet_iter, icmpv6_packet_iter};
+use pnet::transport::TransportChannelType::{Layer3, Layer4};
+use pnet::transport::TransportProtocol::{Ipv4, Ipv6};
 use pnet::util;
 use pnet_macros_support::types::*;
 use rand::random;
 use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
-use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// This enum represents supported protocols for route tracing.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum TraceRouteProtocol {
     Icmp,
     Udp,
+    Dccp,
+    /// Minimal probes of any IP protocol, given by its number.
+    Raw(u8),
+}
+
+/// This struct is the error returned when parsing an unknown protocol name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseProtocolError(String);
+
+impl fmt::Display for ParseProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown protocol \"{}\", expected one of: {}",
+            self.0,
+            TraceRouteProtocol::NAMES.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for ParseProtocolError {}
+
+impl TraceRouteProtocol {
+    /// Names accepted by `from_str`, in the same order as the variants.
+    ///
+    /// Raw probes take the protocol number after a colon, like `raw:47`.
+    pub const NAMES: &'static [&'static str] = &["icmp", "udp", "dccp", "raw:<proto>"];
+
+    /// Returns the lowercase name of the protocol.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TraceRouteProtocol::Icmp => "icmp",
+            TraceRouteProtocol::Udp => "udp",
+            TraceRouteProtocol::Dccp => "dccp",
+            TraceRouteProtocol::Raw(_) => "raw",
+        }
+    }
+
+    /// Returns the IP protocol number carried by the probes.
+    pub fn number(&self) -> u8 {
+        match self {
+            TraceRouteProtocol::Icmp => 1,
+            TraceRouteProtocol::Udp => 17,
+            TraceRouteProtocol::Dccp => 33,
+            TraceRouteProtocol::Raw(number) => *number,
+        }
+    }
+}
+
+impl fmt::Display for TraceRouteProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceRouteProtocol::Raw(number) => write!(f, "raw:{}", number),
+            _ => f.write_str(self.name()),
+        }
+    }
+}
+
+impl FromStr for TraceRouteProtocol {
+    type Err = ParseProtocolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "icmp" => Ok(TraceRouteProtocol::Icmp),
+            "udp" => Ok(TraceRouteProtocol::Udp),
+            "dccp" => Ok(TraceRouteProtocol::Dccp),
+            name => match name.strip_prefix("raw:").map(str::parse) {
+                Some(Ok(number)) => Ok(TraceRouteProtocol::Raw(number)),
+                _ => Err(ParseProtocolError(s.to_string())),
+            },
+        }
+    }
+}
+
+/// This enum tells how a trace ended, it is set on the last hop only.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum CompletionReason {
+    /// The destination answered a probe.
+    Reached,
+    /// The trace ended in silence but the destination answered a confirmation probe.
+    ReachedButFiltered,
+    /// The trace ended in silence.
+    NotReached,
+    /// The trace ended in silence and the destination answered none of the
+    /// confirmation probes either.
+    DestinationUnresponsive,
+    /// Raw probes ran to the last TTL, the destination may have accepted them
+    /// without telling.
+    NoTerminalSignal,
+    /// The trace ended in silence while loose source routed, many routers drop
+    /// source routed packets.
+    NotReachedSourceRouted,
 }
 
 /// This struct stores all needed data for representing a hop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub struct HopFound {
     pub addr: Option<IpAddr>,
     pub tries: u16,
     pub hop_count: u8,
     pub is_last: bool,
     pub time: Option<Duration>,
+    pub completion: Option<CompletionReason>,
+    /// Scope of the responding address, see `addr_scope`.
+    pub addr_scope: Option<AddrScope>,
+    /// Transition technology of the responding address, see `transition_tech`.
+    pub transition: Option<TransitionTech>,
+    /// IPv4 address the responding 6to4 or Teredo address carries, see
+    /// `embedded_v4`.
+    pub embedded_v4: Option<Ipv4Addr>,
+    /// Set on the placeholder standing for this many hidden local hops.
+    pub local_hops: Option<u8>,
+    /// Addresses routers recorded in the answered probe, empty unless
+    /// `TraceRouteConfig::record_route` is on and the reply carried them back.
+    pub recorded_route: Vec<Ipv4Addr>,
+    /// Address and timestamp pairs routers recorded in the answered probe, empty
+    /// unless `TraceRouteConfig::timestamps` is on and the reply carried them back.
+    pub timestamps: Vec<(Ipv4Addr, u32)>,
+    /// Bytes of the reply, preceded by its IP header when the backend kept it.
+    /// Only set with `TraceRouteConfig::capture_raw`.
+    pub raw_reply: Option<Vec<u8>>,
+    /// Counters of the whole trace, only set on the last hop.
+    pub metrics: Option<TraceMetrics>,
+    /// Interface the probe came in on, if the router told in an RFC 5837 extension.
+    pub incoming_interface: Option<InterfaceInfo>,
+    /// Id of the trace the hop belongs to, tells traces sharing a channel apart.
+    pub trace_id: u64,
+    /// Fields of the probe changed on the way, by comparing the quote of the reply
+    /// with what was sent. `None` when the reply quoted no comparable probe.
+    pub mangling: Option<Vec<MangledField>>,
+    /// Destination port of the answered probe, only set with
+    /// `TraceRouteConfig::port_fallback`.
+    pub port: Option<u16>,
+    /// Protocol of the probes, only set with `TraceRouteConfig::protocol_fallback`.
+    pub protocol: Option<TraceRouteProtocol>,
+    /// Set on the hops probed after the destination answered, see
+    /// `TraceRouteConfig::continue_past_destination`.
+    pub beyond_destination: bool,
+    /// Bytes of the reply, without its IP header.
+    pub reply_len: Option<usize>,
+    /// Bytes of the probe the reply quoted back, None for replies that quote nothing.
+    pub quoted_len: Option<usize>,
+    /// Set on an unanswered hop whose every probe drew a Parameter Problem, the
+    /// last of them.
+    pub parameter_problem: Option<ParameterProblem>,
+    /// Probes of the hop that could not be sent for now and were sent again, each
+    /// counts as one of its tries.
+    pub send_retries: u16,
+    /// Fields of the probe answered as it went on the wire, see `ProbeSent::token`.
+    pub probe: Option<ProbeToken>,
+    /// MTU of the next link told by a fragmentation needed or packet too big
+    /// reply, see `TraceRoute::run_mtu_sweep`.
+    pub next_hop_mtu: Option<u16>,
+    /// How the answering address relates to the interface the probe came in on,
+    /// None for replies that quote no probe.
+    pub interface_hint: Option<InterfaceHint>,
+    /// Who timed the reply `time` was measured to, None for unanswered hops.
+    pub timestamp_source: Option<TimestampSource>,
+}
+
+impl HopFound {
+    /// Creates new HopFound, new fields are filled with their defaults.
+    pub fn new(
+        hop_count: u8,
+        addr: Option<IpAddr>,
+        tries: u16,
+        is_last: bool,
+        time: Option<Duration>,
+    ) -> HopFound {
+        let completion = match (is_last, addr) {
+            (true, Some(_)) => Some(CompletionReason::Reached),
+            (true, None) => Some(CompletionReason::NotReached),
+            (false, _) => None,
+        };
+        HopFound {
+            addr,
+            tries,
+            hop_count,
+            is_last,
+            time,
+            completion,
+            addr_scope: addr.map(addr_scope),
+            transition: addr.and_then(transition_tech),
+            embedded_v4: addr.and_then(embedded_v4),
+            local_hops: None,
+            recorded_route: Vec::new(),
+            timestamps: Vec::new(),
+            raw_reply: None,
+            metrics: None,
+            incoming_interface: None,
+            trace_id: 0,
+            mangling: None,
+            port: None,
+            protocol: None,
+            beyond_destination: false,
+            reply_len: None,
+            quoted_len: None,
+            parameter_problem: None,
+            send_retries: 0,
+            probe: None,
+            next_hop_mtu: None,
+            interface_hint: None,
+            timestamp_source: None,
+        }
+    }
+
+    /// Creates new HopFound for a TTL none of whose `tries` probes was answered.
+    pub fn timed_out(hop_count: u8, tries: u16) -> HopFound {
+        HopFound::new(hop_count, None, tries, false, None)
+    }
+
+    /// Creates new HopFound ending a trace no hop of which ended it, like one that
+    /// ran out of TTLs. Its `hop_count` is the TTL after the last one probed.
+    pub fn end_of_trace(hop_count: u8, tries: u16, completion: CompletionReason) -> HopFound {
+        let mut end = HopFound::new(hop_count, None, tries, true, None);
+        end.completion = Some(completion);
+        end
+    }
+
+    /// Returns true for a TTL whose probes all went unanswered.
+    pub fn is_timeout(&self) -> bool {
+        self.addr.is_none() && !self.is_last && self.local_hops.is_none()
+    }
+
+    /// Returns true for the record ending a trace that no hop ended, which stands
+    /// for no hop of the path, see `end_of_trace`.
+    pub fn is_end_of_trace(&self) -> bool {
+        self.addr.is_none() && self.is_last
+    }
+}
+
+/// Formats a hop the way traceroute prints it, e.g. `3  10.0.0.1  12.4ms`.
+impl fmt::Display for HopFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.addr, self.local_hops) {
+            (_, Some(n)) => write!(f, "{}  local network ({} hops)", self.hop_count, n)?,
+            (Some(addr), None) => write!(f, "{}  {}", self.hop_count, addr)?,
+            (None, None) => write!(f, "{}  *", self.hop_count)?,
+        }
+        if let Some(time) = self.time {
+            write!(f, "  {:.1}ms", time.as_secs_f64() * 1000.0)?;
+        }
+        Ok(())
+    }
 }
 
 /// This type is a Result consisting of TraceRoute struct and receiver handle.
-pub type TraceRouteRes = Result<(TraceRoute, Receiver<HopFound>), String>;
+pub type TraceRouteRes = Result<(TraceRoute, Receiver<HopFound>), TraceRouteError>;
 
 /// This struct stores all needed data for performing route tracing task.
 pub struct TraceRoute {
-    pub max_ttl: u8,
-    pub max_tries: u16,
-    pub begin_ttl: u8,
     pub address: IpAddr,
-    pub port: u16,
-    pub timeout: u64,
-    pub size: usize,
     pub results_sender: Sender<HopFound>,
-    pub protocol: TraceRouteProtocol,
+    pub config: TraceRouteConfig,
+    /// Id stamped on every hop, taken from `config.trace_id` or assigned in order.
+    pub trace_id: u64,
+    /// How `address` was chosen, set by `for_host` when it probed the candidates.
+    pub preflight: Option<Preflight>,
+    /// Sockets kept open between the traces of `run_trace_route` and the rounds of
+    /// `run_rounds`, see `warm_up`.
+    sockets: SocketCache,
 }
 
 /// This block implements TraceRoute struct.
 impl TraceRoute {
     /// Creates new TraceRoute and returns TraceRouteRes.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         max_ttl: Option<u8>,
         begin_ttl: Option<u8>,
@@ -70,607 +429,5781 @@ impl TraceRoute {
         addr: IpAddr,
         protocol: Option<TraceRouteProtocol>,
     ) -> TraceRouteRes {
-        let (send_handle, recieve_handle) = channel();
-
-        let mut trace_route = TraceRoute {
-            max_ttl: 30,
-            begin_ttl: 1,
-            max_tries: 4,
-            port: 33434,
-            timeout: 200,
-            address: addr,
-            size: 64,
-            results_sender: send_handle,
-            protocol: TraceRouteProtocol::Udp,
-        };
+        let mut config = TraceRouteConfig::default();
 
         if let Some(mt) = max_ttl {
-            if mt < 1 {
-               return Err(String::from("BAD MAX TTL")); 
-            }
-            trace_route.max_ttl = mt;
+            config.max_ttl = mt;
         }
 
         if let Some(bt) = begin_ttl {
-            if bt > trace_route.max_ttl {
-               return Err(String::from("BAD START TTL")); 
-            }
-            trace_route.begin_ttl = bt;
+            config.begin_ttl = bt;
         }
 
         if let Some(mt) = max_tries {
-            trace_route.max_tries = mt;
+            config.max_tries = mt;
         }
 
         if let Some(p) = port {
-            trace_route.port = p;
+            config.port = p;
         }
 
         if let Some(s) = size {
-            if s < 12 {
-               return Err(String::from("BAD SIZE - MIN=12")); 
-            }
-            trace_route.size = s;
+            config.size = s;
         }
 
         if let Some(to) = timeout {
-            if to == 0 {
-               return Err(String::from("BAD TIMEOUT")); 
-            }
-            trace_route.timeout = to;
+            config.timeout = Some(Duration::from_millis(to));
         }
 
         if let Some(p) = protocol {
-            trace_route.protocol = p;
+            config.protocol = p;
         }
 
-        Ok((trace_route, recieve_handle))
+        TraceRoute::with_config(addr, config)
     }
 
-    /// This function executes route tracing.
-    pub fn run_trace_route(&self) {
-        if self.address.is_ipv4() {
-            start_trace_route_on_v4(
-                self.results_sender.clone(),
-                self.begin_ttl,
-                self.max_ttl,
-                self.max_tries,
-                self.protocol,
-                self.port,
-                self.address,
-                self.timeout,
-                self.size,
-            );
-        } else {
-            start_trace_route_on_v6(
-                self.results_sender.clone(),
-                self.begin_ttl,
-                self.max_ttl,
-                self.max_tries,
-                self.protocol,
-                self.port,
-                self.address,
-                self.timeout,
-                self.size,
-            );
-        }
+    /// Returns the last TTL probed, `config.max_ttl`.
+    pub fn max_ttl(&self) -> u8 {
+        self.config.max_ttl
     }
-}
 
-fn build_udp_send_v4(
-    tx: &mut TransportSender,
-    addr: IpAddr,
-    size: usize,
-    port: u16,
-    ttl: u8,
-    my_ip: Ipv4Addr,
-) -> Result<usize, std::io::Error> {
-    let mut vec: Vec<u8> = vec![0; size];
-    let mut udp_packet = udp::MutableUdpPacket::new(&mut vec[..]).unwrap();
-    udp_packet.set_source(random::<u16>());
-    udp_packet.set_destination(port);
-    udp_packet.set_length(size as u16);
-    udp_packet.set_payload(&mut vec![0; size - 8]);
-    let csum = udp::ipv4_checksum(
-        &udp_packet.to_immutable(),
-        &get_ip_addr(true)
-            .unwrap()
-            .to_string()
-            .parse::<Ipv4Addr>()
-            .unwrap(),
-        &addr.to_string().parse::<Ipv4Addr>().unwrap(),
-    );
-    udp_packet.set_checksum(csum);
+    /// Returns the first TTL probed, `config.begin_ttl`.
+    pub fn begin_ttl(&self) -> u8 {
+        self.config.begin_ttl
+    }
 
-    let mut ipv4_vec: Vec<u8> = vec![0; ipv4::MutableIpv4Packet::minimum_packet_size() + vec.len()];
-    let mut ipv4_packet = ipv4::MutableIpv4Packet::new(&mut ipv4_vec[..]).unwrap();
-    ipv4_packet.set_header_length(5);
-    ipv4_packet.set_fragment_offset(16384);
-    ipv4_packet.set_identification(rand::random::<u16>());
-    ipv4_packet.set_version(4);
-    ipv4_packet.set_ttl(ttl);
-    ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
-    let ip = addr.to_string().parse::<Ipv4Addr>().unwrap();
-    ipv4_packet.set_source(my_ip);
-    ipv4_packet.set_destination(ip);
-    ipv4_packet
-        .set_total_length((ipv4::MutableIpv4Packet::minimum_packet_size() + vec.len()) as u16);
-    ipv4_packet.set_payload(&mut vec[..]);
+    /// Returns how many probes a TTL gets, `config.max_tries`.
+    pub fn max_tries(&self) -> u16 {
+        self.config.max_tries
+    }
 
-    let csum = ipv4::checksum(&ipv4_packet.to_immutable());
-    ipv4_packet.set_checksum(csum);
-    tx.send_to(ipv4_packet, addr)
-}
+    /// Returns the timeout in milliseconds as `new` takes it, 0 when the trace
+    /// waits without one.
+    pub fn timeout(&self) -> u64 {
+        self.config.timeout.map_or(0, |timeout| {
+            timeout.as_millis().min(u128::from(u64::MAX)) as u64
+        })
+    }
 
-fn build_udp_send_v6(
-    tx: &mut TransportSender,
-    addr: IpAddr,
-    size: usize,
-    port: u16,
-    ttl: u8,
-    my_ip: Ipv6Addr,
-) -> Result<usize, std::io::Error> {
-    let mut vec: Vec<u8> = vec![0; size];
-    let mut udp_packet = udp::MutableUdpPacket::new(&mut vec[..]).unwrap();
-    udp_packet.set_source(random::<u16>());
-    udp_packet.set_destination(port);
-    udp_packet.set_length(size as u16);
-    udp_packet.set_payload(&mut vec![0; size - 8]);
-    let csum = udp::ipv4_checksum(
-        &udp_packet.to_immutable(),
-        &get_ip_addr(true)
-            .unwrap()
-            .to_string()
-            .parse::<Ipv4Addr>()
-            .unwrap(),
-        &addr.to_string().parse::<Ipv4Addr>().unwrap(),
-    );
-    udp_packet.set_checksum(csum);
+    /// Returns the first destination port, `config.port`.
+    pub fn port(&self) -> u16 {
+        self.config.port
+    }
 
-    let mut ipv6_vec: Vec<u8> = vec![0; ipv6::MutableIpv6Packet::minimum_packet_size() + vec.len()];
-    let mut ipv6_packet = ipv6::MutableIpv6Packet::new(&mut ipv6_vec[..]).unwrap();
-    ipv6_packet.set_version(6);
-    ipv6_packet.set_hop_limit(ttl);
-    ipv6_packet.set_next_header(IpNextHeaderProtocols::Udp);
-    let ip = addr.to_string().parse::<Ipv6Addr>().unwrap();
-    ipv6_packet.set_source(my_ip);
-    ipv6_packet.set_destination(ip);
-    ipv6_packet.set_payload_length((vec.len()) as u16);
-    ipv6_packet.set_payload(&mut vec[..]);
+    /// Returns the probe size, `config.size`.
+    pub fn size(&self) -> usize {
+        self.config.size
+    }
 
-    tx.send_to(ipv6_packet, addr)
-}
+    /// Returns the probe protocol, `config.protocol`.
+    pub fn protocol(&self) -> TraceRouteProtocol {
+        self.config.protocol
+    }
 
-fn build_icmp_send_v4(
-    tx: &mut TransportSender,
-    addr: IpAddr,
-    size: usize,
-    ttl: u8,
-    my_ip: Ipv4Addr,
-) -> Result<usize, std::io::Error> {
-    let mut vec: Vec<u8> = vec![0; size];
-    let mut echo_packet = echo_request::MutableEchoRequestPacket::new(&mut vec[..]).unwrap();
-    echo_packet.set_sequence_number(random::<u16>());
-    echo_packet.set_identifier(random::<u16>());
-    echo_packet.set_icmp_type(IcmpTypes::EchoRequest);
+    /// Creates new TraceRoute from a complete configuration and returns TraceRouteRes.
+    ///
+    /// IPv4-mapped and IPv4-compatible IPv6 addresses are traced as the IPv4 address
+    /// they carry unless `config.unmap_ipv4` is turned off.
+    pub fn with_config(addr: IpAddr, config: TraceRouteConfig) -> TraceRouteRes {
+        let addr = match addr {
+            IpAddr::V6(v6) if config.unmap_ipv4 => match config::embedded_ipv4(v6) {
+                Some(v4) => IpAddr::V4(v4),
+                None => addr,
+            },
+            _ => addr,
+        };
+        config.validate(addr)?;
+        let (send_handle, recieve_handle) = channel();
+        let trace_route = TraceRoute {
+            address: addr,
+            results_sender: send_handle,
+            trace_id: config.trace_id.unwrap_or_else(next_trace_id),
+            config,
+            preflight: None,
+            sockets: SocketCache::default(),
+        };
+        Ok((trace_route, recieve_handle))
+    }
 
-    let csum = icmp_checksum(&echo_packet);
-    echo_packet.set_checksum(csum);
+    /// Creates new TraceRoute for a host name or address and returns TraceRouteRes.
+    ///
+    /// `family` restricts the addresses the name resolves to. With `config.preflight`
+    /// the candidates are probed first and the first to answer is traced, otherwise
+    /// the first address is.
+    pub fn for_host(
+        host: &str,
+        family: Option<IpFamily>,
+        config: TraceRouteConfig,
+    ) -> TraceRouteRes {
+        TraceRoute::for_host_with(host, family, config, &SystemResolver, |address, config| {
+            open_backend(address, config).map(|(backend, source, _)| (backend, source))
+        })
+    }
 
-    let mut ipv4_vec: Vec<u8> = vec![0; ipv4::MutableIpv4Packet::minimum_packet_size() + vec.len()];
-    let mut ipv4_packet = ipv4::MutableIpv4Packet::new(&mut ipv4_vec[..]).unwrap();
-    ipv4_packet.set_header_length(5);
-    ipv4_packet.set_fragment_offset(16384);
-    ipv4_packet.set_identification(rand::random::<u16>());
-    ipv4_packet.set_version(4);
-    ipv4_packet.set_ttl(ttl);
-    ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
-    let ip = addr.to_string().parse::<Ipv4Addr>().unwrap();
-    ipv4_packet.set_source(my_ip);
-    ipv4_packet.set_destination(ip);
-    ipv4_packet
-        .set_total_length((ipv4::MutableIpv4Packet::minimum_packet_size() + vec.len()) as u16);
-    ipv4_packet.set_payload(&mut vec[..]);
+    /// Same as `for_host`, resolving with `resolver` and probing the candidates over
+    /// the backend and source address `open` returns for them.
+    pub fn for_host_with<R, B, F>(
+        host: &str,
+        family: Option<IpFamily>,
+        config: TraceRouteConfig,
+        resolver: &R,
+        open: F,
+    ) -> TraceRouteRes
+    where
+        R: Resolver + ?Sized,
+        B: ProbeBackend + 'static,
+        F: FnMut(IpAddr, &TraceRouteConfig) -> Result<(B, IpAddr), TraceRouteError>,
+    {
+        let (address, preflight) = preflight::choose(host, family, &config, resolver, open)?;
+        let (mut trace_route, receiver) = TraceRoute::with_config(address, config)?;
+        trace_route.preflight = preflight;
+        Ok((trace_route, receiver))
+    }
 
-    let csum = ipv4::checksum(&ipv4_packet.to_immutable());
-    ipv4_packet.set_checksum(csum);
+    /// This function opens the sockets and starts route tracing on a worker thread.
+    ///
+    /// Source address selection and socket setup happen before the worker is spawned,
+    /// so a missing interface or missing privileges are reported here instead of
+    /// panicking later.
+    ///
+    /// Unless `config.backend` forces a kind, raw sockets are tried first. When they
+    /// are refused, plain ICMP and UDP traces fall back to unprivileged sockets.
+    pub fn run_trace_route(&self) -> Result<TraceHandle, TraceRouteError> {
+        let (backend, self_ip, kind) = self.lend_sockets()?;
+        self.run_on(backend, self_ip, kind)
+    }
 
-    tx.send_to(ipv4_packet, addr)
-}
+    /// Opens the sockets of the trace ahead of its first run, so it does not take
+    /// longer than the ones after it. The sockets stay open until the TraceRoute
+    /// is dropped, and are opened again after they failed.
+    pub fn warm_up(&self) -> Result<(), TraceRouteError> {
+        self.sockets.fill(|| self.open_sockets())
+    }
 
-fn build_icmp_send_v6(
-    tx: &mut TransportSender,
-    addr: IpAddr,
-    size: usize,
-    ttl: u8,
-    my_ip: Ipv6Addr,
-) -> Result<usize, std::io::Error> {
-    let mut vec: Vec<u8> = vec![0; size];
+    /// Lends the kept sockets, opening them if there are none.
+    fn lend_sockets(&self) -> Result<(Lent, IpAddr, Option<BackendKind>), TraceRouteError> {
+        self.sockets.lend(|| self.open_sockets())
+    }
 
-    let mut echo_packet = MutableIcmpv6Packet::new(&mut vec[..]).unwrap();
-    echo_packet.set_icmpv6_type(Icmpv6Types::EchoRequest);
+    fn open_sockets(
+        &self,
+    ) -> Result<(Box<dyn ProbeBackend>, IpAddr, Option<BackendKind>), TraceRouteError> {
+        let (backend, self_ip, kind) = open_backend(self.address, &self.config)?;
+        debug!(
+            "tracing {} from {} with {} probes over {} sockets",
+            self.address, self_ip, self.config.protocol, kind
+        );
+        Ok((backend, self_ip, Some(kind)))
+    }
 
-    let csum = icmpv6_checksum(&echo_packet);
-    echo_packet.set_checksum(csum);
+    /// Returns the router answering a TTL=1 probe towards the traced address.
+    ///
+    /// A single probe is sent with this trace's options and retried once, so it
+    /// times out quickly. Monitoring tools can call it to notice gateway changes.
+    pub fn first_hop(&self) -> Result<IpAddr, TraceRouteError> {
+        let (trace_route, receiver) = self.first_hop_tracer()?;
+        trace_route.run_trace_route()?;
+        first_hop_from(&receiver)
+    }
 
-    let mut ipv6_vec: Vec<u8> = vec![0; ipv6::MutableIpv6Packet::minimum_packet_size() + vec.len()];
-    let mut ipv6_packet = ipv6::MutableIpv6Packet::new(&mut ipv6_vec[..]).unwrap();
-    ipv6_packet.set_version(6);
-    ipv6_packet.set_hop_limit(ttl);
-    ipv6_packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
-    let ip = addr.to_string().parse::<Ipv6Addr>().unwrap();
-    ipv6_packet.set_source(my_ip);
-    ipv6_packet.set_destination(ip);
-    ipv6_packet.set_payload_length((vec.len()) as u16);
-    ipv6_packet.set_payload(&mut vec[..]);
+    /// Same as `first_hop`, over the given backend.
+    pub fn first_hop_with_backend<B: ProbeBackend + 'static>(
+        &self,
+        backend: B,
+        source: IpAddr,
+    ) -> Result<IpAddr, TraceRouteError> {
+        let (trace_route, receiver) = self.first_hop_tracer()?;
+        trace_route.run_with_backend(backend, source)?;
+        first_hop_from(&receiver)
+    }
 
-    tx.send_to(ipv6_packet, addr)
-}
+    fn first_hop_tracer(&self) -> TraceRouteRes {
+        let config = TraceRouteConfig {
+            begin_ttl: 1,
+            max_ttl: 1,
+            max_tries: 2,
+            confirm_silent_destination: false,
+            hide_local_hops: false,
+            ..self.config.clone()
+        };
+        TraceRoute::with_config(self.address, config)
+    }
 
-fn get_ip_addr(v4: bool) -> Option<IpAddr> {
-    for iface in datalink::interfaces() {
-        if !iface.is_loopback() && iface.is_up() {
-            for ip in iface.ips {
-                if ip.ip().is_ipv4() && v4 {
-                    return Some(ip.ip());
-                }
-                if ip.ip().is_ipv6() && !v4 {
-                    return Some(ip.ip());
-                }
-            }
-        }
+    /// Sends `count` echo probes to the traced address, one every `interval`, and
+    /// returns their statistics like ping, for checking the path a trace found.
+    ///
+    /// The probes have TTL 64 and the size of the probes of the trace. Replies
+    /// later than the timeout of the trace, or than `interval` without one, are
+    /// counted as lost.
+    pub fn ping_destination(
+        &self,
+        count: u16,
+        interval: Duration,
+    ) -> Result<PingStats, TraceRouteError> {
+        let config = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Icmp,
+            backend: self
+                .config
+                .backend
+                .filter(|kind| kind.carries(TraceRouteProtocol::Icmp)),
+            ..self.config.clone()
+        };
+        let (mut backend, self_ip, _) = open_backend(self.address, &config)?;
+        self.ping_over(&mut *backend, self_ip, count, interval)
     }
-    None
-}
 
-fn start_trace_route_on_v4(
-    tx: Sender<HopFound>,
-    begin_ttl: u8,
-    end_ttl: u8,
-    max_tries: u16,
-    trace_route_protocol: TraceRouteProtocol,
-    port: u16,
-    ip: IpAddr,
-    timeout: u64,
-    packet_size: usize,
-) {
-    let self_ip = match get_ip_addr(true) {
-        Some(ip) => ip.to_string().parse::<Ipv4Addr>().unwrap(),
-        None => {
-            panic!("No <UP> interface was found, please connect to internet.");
-        }
-    };
-    let mut seen: BTreeSet<IpAddr> = BTreeSet::new();
-    let protocol = Layer4(Ipv4(IpNextHeaderProtocols::Icmp));
-    let (_, transport_rx) = match transport_channel(4096, protocol) {
-        Ok((tx, rx)) => (tx, rx),
-        Err(_) => return,
-    };
-    thread::spawn(move || {
-        let ipv4_protocol = match trace_route_protocol {
-            TraceRouteProtocol::Udp => Layer3(IpNextHeaderProtocols::Udp),
-            TraceRouteProtocol::Icmp => Layer3(IpNextHeaderProtocols::Icmp),
+    /// Same as `ping_destination`, over the given backend.
+    pub fn ping_destination_with_backend<B: ProbeBackend>(
+        &self,
+        mut backend: B,
+        source: IpAddr,
+        count: u16,
+        interval: Duration,
+    ) -> Result<PingStats, TraceRouteError> {
+        self.ping_over(&mut backend, source, count, interval)
+    }
+
+    fn ping_over<B: ProbeBackend + ?Sized>(
+        &self,
+        backend: &mut B,
+        source: IpAddr,
+        count: u16,
+        interval: Duration,
+    ) -> Result<PingStats, TraceRouteError> {
+        let timeout = self.config.timeout.unwrap_or(interval);
+        ping::ping(
+            backend,
+            self.address,
+            source,
+            self.config.size,
+            timeout,
+            count,
+            interval,
+        )
+    }
+
+    /// Asks the traced address about the interface `TraceRouteConfig::interface_query`
+    /// names with RFC 8335 Extended Echo requests, which nodes may answer when they
+    /// do not answer echo probes.
+    ///
+    /// Up to `max_tries` requests are sent, one after the other went unanswered for
+    /// the timeout of the trace. None is returned if none was answered, many nodes
+    /// do not support the requests.
+    pub fn probe_interface(&self) -> Result<Option<InterfaceStatus>, TraceRouteError> {
+        let query = self.interface_query()?;
+        let config = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Icmp,
+            // Echo sockets only send echo requests.
+            backend: Some(match self.config.backend {
+                Some(BackendKind::Datalink) => BackendKind::Datalink,
+                _ => BackendKind::Raw,
+            }),
+            ..self.config.clone()
         };
-        let (mut ipv4_tx, _) = match transport_channel(4096, ipv4_protocol) {
-            Ok((tx, rx)) => (tx, rx),
-            Err(_) => return,
-        };
-
-        let mut receiver = transport_rx;
-        let mut iter = icmp_packet_iter(&mut receiver);
-        let mut i: u8 = begin_ttl;
-        let mut tries: u16 = 0;
-        let mut has_changed = false;
-        let mut timer;
-        loop {
-            if i > end_ttl {
-                tx.send(HopFound {
-                    addr: None,
-                    hop_count: i,
-                    tries,
-                    is_last: true,
-                    time: None,
-                })
-                .unwrap();
-                break;
-            }
-            match trace_route_protocol {
-                TraceRouteProtocol::Udp => {
-                    match build_udp_send_v4(
-                        &mut ipv4_tx,
-                        ip,
-                        packet_size,
-                        port + i as u16,
-                        i,
-                        self_ip,
-                    ) {
-                        Ok(_) => timer = Instant::now(),
-                        Err(e) => {
-                            panic!("Could not send packet, make sure this program has needed privilages, Error<{}>", e.to_string());
-                        }
-                    }
-                }
-                TraceRouteProtocol::Icmp => {
-                    match build_icmp_send_v4(&mut ipv4_tx, ip, 64, i, self_ip) {
-                        Ok(_) => timer = Instant::now(),
-                        Err(e) => {
-                            panic!("Could not send packet, make sure this program has needed privilages, Error<{}>", e.to_string());
-                        }
-                    }
-                }
+        let (mut backend, self_ip, _) = open_backend(self.address, &config)?;
+        self.probe_interface_over(&mut *backend, self_ip, query)
+    }
+
+    /// Same as `probe_interface`, over the given backend.
+    pub fn probe_interface_with_backend<B: ProbeBackend>(
+        &self,
+        mut backend: B,
+        source: IpAddr,
+    ) -> Result<Option<InterfaceStatus>, TraceRouteError> {
+        let query = self.interface_query()?;
+        self.probe_interface_over(&mut backend, source, query)
+    }
+
+    fn interface_query(&self) -> Result<&InterfaceQuery, TraceRouteError> {
+        self.config
+            .interface_query
+            .as_ref()
+            .ok_or(TraceRouteError::NoInterfaceQuery)
+    }
+
+    fn probe_interface_over<B: ProbeBackend + ?Sized>(
+        &self,
+        backend: &mut B,
+        source: IpAddr,
+        query: &InterfaceQuery,
+    ) -> Result<Option<InterfaceStatus>, TraceRouteError> {
+        probe::probe_interface(
+            backend,
+            self.address,
+            source,
+            query,
+            self.config.max_tries,
+            self.config.timeout.unwrap_or(PROBE_INTERFACE_TIMEOUT),
+        )
+    }
+
+    /// Measures how long the host itself takes to send a probe and read its reply
+    /// by pinging the loopback address of the traced family, and takes that off
+    /// the round trip times the trace reports from now on.
+    ///
+    /// The offset is the fastest of a few round trips, it is kept in
+    /// `TraceRouteConfig::rtt_offset` and returned.
+    pub fn calibrate(&mut self) -> Result<Duration, TraceRouteError> {
+        let loopback = loopback_of(self.address);
+        let config = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Icmp,
+            backend: self
+                .config
+                .backend
+                .filter(|kind| kind.carries(TraceRouteProtocol::Icmp)),
+            interface: None,
+            ..self.config.clone()
+        };
+        let (mut backend, self_ip, _) = open_backend(loopback, &config)?;
+        self.calibrate_over(&mut *backend, self_ip)
+    }
+
+    /// Same as `calibrate`, over the given backend.
+    pub fn calibrate_with_backend<B: ProbeBackend>(
+        &mut self,
+        mut backend: B,
+    ) -> Result<Duration, TraceRouteError> {
+        let loopback = loopback_of(self.address);
+        self.calibrate_over(&mut backend, loopback)
+    }
+
+    fn calibrate_over<B: ProbeBackend + ?Sized>(
+        &mut self,
+        backend: &mut B,
+        source: IpAddr,
+    ) -> Result<Duration, TraceRouteError> {
+        let stats = ping::ping(
+            backend,
+            loopback_of(self.address),
+            source,
+            self.config.size,
+            CALIBRATION_TIMEOUT,
+            CALIBRATION_PROBES,
+            CALIBRATION_INTERVAL,
+        )?;
+        let offset = stats.min.ok_or(TraceRouteError::NoCalibration)?;
+        debug!("taking {:?} off the round trip times", offset);
+        self.config.rtt_offset = offset;
+        Ok(offset)
+    }
+
+    /// Traces the address once per DSCP value, one after the other, and compares
+    /// the paths the classes took.
+    pub fn run_dscp_sweep(&self, values: &[u8]) -> Result<DscpSweep, TraceRouteError> {
+        self.dscp_sweep(values, TraceRoute::run_trace_route)
+    }
+
+    /// Same as `run_dscp_sweep`, over clones of the given backend.
+    pub fn run_dscp_sweep_with_backend<B: ProbeBackend + Clone + 'static>(
+        &self,
+        values: &[u8],
+        backend: B,
+        source: IpAddr,
+    ) -> Result<DscpSweep, TraceRouteError> {
+        self.dscp_sweep(values, |trace_route| {
+            trace_route.run_with_backend(backend.clone(), source)
+        })
+    }
+
+    fn dscp_sweep<F>(&self, values: &[u8], mut run: F) -> Result<DscpSweep, TraceRouteError>
+    where
+        F: FnMut(&TraceRoute) -> Result<TraceHandle, TraceRouteError>,
+    {
+        let mut traces = Vec::with_capacity(values.len());
+        for &dscp in values {
+            let config = TraceRouteConfig {
+                dscp,
+                ..self.config.clone()
             };
-            match iter.next_with_timeout(Duration::from_millis(timeout)) {
-                Ok(p) => match p {
-                    Some((packet, addr)) => match seen.get(&addr) {
-                        None => {
-                            seen.insert(addr);
-                            if packet.get_icmp_type() == icmp::IcmpType::new(11) {
-                                tx.send(HopFound {
-                                    addr: Some(addr),
-                                    hop_count: i,
-                                    tries,
-                                    is_last: false,
-                                    time: Some(Instant::now() - timer),
-                                })
-                                .unwrap();
-                                has_changed = true;
-                                i += 1;
-                                tries = 0;
-                            } else {
-                                match trace_route_protocol {
-                                    TraceRouteProtocol::Udp => {
-                                        if packet.get_icmp_type() == icmp::IcmpType::new(3) {
-                                            tx.send(HopFound {
-                                                addr: Some(addr),
-                                                hop_count: i,
-                                                tries,
-                                                is_last: true,
-                                                time: Some(Instant::now() - timer),
-                                            })
-                                            .unwrap();
-                                            break;
-                                        } else {
-                                            println!(
-                                                "UNEXPECTED ICMP PACKET WITH <{:?}>",
-                                                packet.get_icmp_type()
-                                            );
-                                        }
-                                    }
-                                    TraceRouteProtocol::Icmp => {
-                                        if packet.get_icmp_type() == icmp::IcmpType::new(0) {
-                                            tx.send(HopFound {
-                                                addr: Some(addr),
-                                                hop_count: i,
-                                                tries,
-                                                is_last: true,
-                                                time: Some(Instant::now() - timer),
-                                            })
-                                            .unwrap();
-                                            break;
-                                        } else {
-                                            println!(
-                                                "UNEXPECTED ICMP PACKET WITH <{:?}>",
-                                                packet.get_icmp_type()
-                                            );
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        _ => {
-                            if tries > 0 {
-                                tries -= 1;
-                            }
-                        }
-                    },
-                    _ => has_changed = false,
-                },
-                _ => has_changed = false,
-            }
-            tries += 1;
-            if tries >= max_tries && !has_changed {
-                tx.send(HopFound {
-                    addr: None,
-                    hop_count: i,
-                    tries,
-                    is_last: false,
-                    time: None,
-                })
-                .unwrap();
-                tries = 0;
-                i += 1;
-                has_changed = false;
-            }
+            traces.push(self.variant_hops(config, &mut run)?);
         }
-    });
-}
+        Ok(DscpSweep::compare(values, traces))
+    }
 
-fn start_trace_route_on_v6(
-    tx: Sender<HopFound>,
-    begin_ttl: u8,
-    end_ttl: u8,
-    max_tries: u16,
-    trace_route_protocol: TraceRouteProtocol,
-    port: u16,
-    ip: IpAddr,
-    timeout: u64,
-    packet_size: usize,
-) {
-    let self_ip = match get_ip_addr(false) {
-        Some(ip) => ip.to_string().parse::<Ipv6Addr>().unwrap(),
-        None => {
-            panic!("No <UP> interface was found, please connect to internet.");
-        }
+    /// Traces the IPv6 address once with plain probes and once with probes carrying
+    /// `header`, and finds where the latter start getting lost, to tell the hop
+    /// dropping packets with extension headers.
+    pub fn run_extension_header_comparison(
+        &self,
+        header: ExtensionHeader,
+    ) -> Result<ExtensionHeaderComparison, TraceRouteError> {
+        self.extension_header_comparison(header, TraceRoute::run_trace_route)
+    }
+
+    /// Same as `run_extension_header_comparison`, over clones of the given backend.
+    pub fn run_extension_header_comparison_with_backend<B: ProbeBackend + Clone + 'static>(
+        &self,
+        header: ExtensionHeader,
+        backend: B,
+        source: IpAddr,
+    ) -> Result<ExtensionHeaderComparison, TraceRouteError> {
+        self.extension_header_comparison(header, |trace_route| {
+            trace_route.run_with_backend(backend.clone(), source)
+        })
+    }
+
+    fn extension_header_comparison<F>(
+        &self,
+        header: ExtensionHeader,
+        mut run: F,
+    ) -> Result<ExtensionHeaderComparison, TraceRouteError>
+    where
+        F: FnMut(&TraceRoute) -> Result<TraceHandle, TraceRouteError>,
+    {
+        let mut trace = |extension_header| {
+            let config = TraceRouteConfig {
+                extension_header,
+                ..self.config.clone()
+            };
+            self.variant_hops(config, &mut run)
+        };
+        let plain = trace(None)?;
+        let extended = trace(Some(header))?;
+        Ok(ExtensionHeaderComparison::compare(header, plain, extended))
+    }
+
+    /// Traces the address once with whole probes and once with probes sent in two
+    /// fragments, and finds where the latter start getting lost, to tell the hop
+    /// dropping fragments.
+    pub fn run_fragment_comparison(&self) -> Result<FragmentComparison, TraceRouteError> {
+        self.fragment_comparison(TraceRoute::run_trace_route)
+    }
+
+    /// Same as `run_fragment_comparison`, over clones of the given backend.
+    pub fn run_fragment_comparison_with_backend<B: ProbeBackend + Clone + 'static>(
+        &self,
+        backend: B,
+        source: IpAddr,
+    ) -> Result<FragmentComparison, TraceRouteError> {
+        self.fragment_comparison(|trace_route| {
+            trace_route.run_with_backend(backend.clone(), source)
+        })
+    }
+
+    fn fragment_comparison<F>(&self, mut run: F) -> Result<FragmentComparison, TraceRouteError>
+    where
+        F: FnMut(&TraceRoute) -> Result<TraceHandle, TraceRouteError>,
+    {
+        let mut trace = |fragment| {
+            let config = TraceRouteConfig {
+                fragment,
+                ..self.config.clone()
+            };
+            self.variant_hops(config, &mut run)
+        };
+        let whole = trace(false)?;
+        let fragmented = trace(true)?;
+        Ok(FragmentComparison::compare(whole, fragmented))
+    }
+
+    /// Sends probes of every size in `sizes`, IP header included, with the don't
+    /// fragment bit to every TTL of `ttls`, and finds the largest each answered.
+    ///
+    /// Sizes are tried smallest first, a TTL is left at the first one it does not
+    /// answer. A TTL refused with a fragmentation needed telling the next hop MTU
+    /// is then probed once at exactly that size, if it was not tried. With `ttls`
+    /// None the address is traced first and every TTL that answered is swept. DCCP
+    /// probes have a size of their own and cannot be swept.
+    pub fn run_mtu_sweep(
+        &self,
+        ttls: Option<&[u8]>,
+        sizes: &[u16],
+    ) -> Result<MtuSweep, TraceRouteError> {
+        self.mtu_sweep(ttls, sizes, TraceRoute::run_trace_route)
+    }
+
+    /// Same as `run_mtu_sweep`, over clones of the given backend.
+    pub fn run_mtu_sweep_with_backend<B: ProbeBackend + Clone + 'static>(
+        &self,
+        ttls: Option<&[u8]>,
+        sizes: &[u16],
+        backend: B,
+        source: IpAddr,
+    ) -> Result<MtuSweep, TraceRouteError> {
+        self.mtu_sweep(ttls, sizes, |trace_route| {
+            trace_route.run_with_backend(backend.clone(), source)
+        })
+    }
+
+    fn mtu_sweep<F>(
+        &self,
+        ttls: Option<&[u8]>,
+        sizes: &[u16],
+        mut run: F,
+    ) -> Result<MtuSweep, TraceRouteError>
+    where
+        F: FnMut(&TraceRoute) -> Result<TraceHandle, TraceRouteError>,
+    {
+        let ttls: Vec<u8> = match ttls {
+            Some(ttls) => ttls.to_vec(),
+            None => {
+                let answered: BTreeSet<u8> = self
+                    .variant_hops(self.config.clone(), &mut run)?
+                    .iter()
+                    .filter(|hop| hop.addr.is_some() && hop.local_hops.is_none())
+                    .map(|hop| hop.hop_count)
+                    .collect();
+                answered.into_iter().collect()
+            }
+        };
+        let mut sizes = sizes.to_vec();
+        sizes.sort_unstable();
+        sizes.dedup();
+        let header = if self.address.is_ipv4() { 20 } else { 40 };
+        let mut hops = Vec::with_capacity(ttls.len());
+        for ttl in ttls {
+            let sized = |size: u16| TraceRouteConfig {
+                begin_ttl: ttl,
+                max_ttl: ttl,
+                size: usize::from(size).saturating_sub(header),
+                ..self.config.clone()
+            };
+            let mut hop = HopMtu::new(ttl);
+            for &size in &sizes {
+                if !hop.probed(size, &self.variant_hops(sized(size), &mut run)?) {
+                    break;
+                }
+            }
+            // The MTU a hop told is the answer, no smaller size needs a try.
+            if let Some(mtu) = hop.untried_mtu() {
+                hop.confirmed(mtu, &self.variant_hops(sized(mtu), &mut run)?);
+            }
+            hops.push(hop);
+        }
+        Ok(MtuSweep { sizes, hops })
+    }
+
+    /// Traces the address with `config`, a variant of this one, started by `run`,
+    /// and returns every hop it reported once its worker stopped.
+    fn variant_hops<F>(
+        &self,
+        config: TraceRouteConfig,
+        run: &mut F,
+    ) -> Result<Vec<HopFound>, TraceRouteError>
+    where
+        F: FnMut(&TraceRoute) -> Result<TraceHandle, TraceRouteError>,
+    {
+        let (handle, receiver) = {
+            let (trace_route, receiver) = TraceRoute::with_config(self.address, config)?;
+            (run(&trace_route)?, receiver)
+        };
+        let hops = receiver.iter().collect();
+        let _ = handle.join();
+        Ok(hops)
+    }
+
+    /// Traces the address with every protocol at once, on the threads of `pool`,
+    /// and compares the paths they took.
+    #[cfg(target_os = "linux")]
+    pub fn run_protocol_comparison(
+        &self,
+        pool: &TracePool,
+        protocols: &[TraceRouteProtocol],
+    ) -> Result<ProtocolComparison, TraceRouteError> {
+        self.protocol_comparison(protocols, |trace_route| trace_route.run_in_pool(pool))
+    }
+
+    /// Same as `run_protocol_comparison`, over the backend `open` returns for every
+    /// protocol.
+    #[cfg(target_os = "linux")]
+    pub fn run_protocol_comparison_with_backends<B, F>(
+        &self,
+        pool: &TracePool,
+        protocols: &[TraceRouteProtocol],
+        mut open: F,
+        source: IpAddr,
+    ) -> Result<ProtocolComparison, TraceRouteError>
+    where
+        B: ProbeBackend + 'static,
+        F: FnMut(TraceRouteProtocol) -> B,
+    {
+        self.protocol_comparison(protocols, |trace_route| {
+            let backend = open(trace_route.config.protocol);
+            trace_route.run_in_pool_with_backend(pool, backend, source)
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn protocol_comparison<F>(
+        &self,
+        protocols: &[TraceRouteProtocol],
+        mut run: F,
+    ) -> Result<ProtocolComparison, TraceRouteError>
+    where
+        F: FnMut(&TraceRoute) -> Result<TraceHandle, TraceRouteError>,
+    {
+        let mut started = Vec::with_capacity(protocols.len());
+        for &protocol in protocols {
+            let config = TraceRouteConfig {
+                protocol,
+                ..self.config.clone()
+            };
+            let (trace_route, receiver) = TraceRoute::with_config(self.address, config)?;
+            started.push((run(&trace_route)?, receiver));
+        }
+        let traces = started
+            .into_iter()
+            .map(|(handle, receiver)| {
+                let hops: Vec<HopFound> = receiver.iter().collect();
+                let _ = handle.join();
+                hops
+            })
+            .collect();
+        Ok(ProtocolComparison::compare(protocols, traces))
+    }
+
+    /// Traces the address `rounds` times, one after the other, like mtr.
+    ///
+    /// `on_round` gets the statistics of every TTL after each round, the statistics
+    /// after the last one are returned. Late replies reported with
+    /// `TraceRouteConfig::correct_late_replies` count as answers of their round.
+    /// With `TraceRouteConfig::hide_local_hops`, rounds after the one that found
+    /// where the local network ends begin right after it.
+    pub fn run_rounds<F>(
+        &self,
+        rounds: u32,
+        mut on_round: F,
+    ) -> Result<Vec<HopStats>, TraceRouteError>
+    where
+        F: FnMut(&[HopStats]),
+    {
+        self.rounds(
+            rounds,
+            |stats, _| on_round(stats),
+            TraceRoute::run_with_events,
+        )
+    }
+
+    /// Same as `run_rounds`, over clones of the given backend.
+    pub fn run_rounds_with_backend<B, F>(
+        &self,
+        rounds: u32,
+        mut on_round: F,
+        backend: B,
+        source: IpAddr,
+    ) -> Result<Vec<HopStats>, TraceRouteError>
+    where
+        B: ProbeBackend + Clone + 'static,
+        F: FnMut(&[HopStats]),
+    {
+        self.rounds(
+            rounds,
+            |stats, _| on_round(stats),
+            |trace_route, events| {
+                trace_route.run_with_events_and_backend(events, backend.clone(), source)
+            },
+        )
+    }
+
+    /// Same as `run_rounds`, over the backend `open` returns, opened once for all
+    /// rounds and again only after it failed.
+    pub fn run_rounds_with_opener<B, O, F>(
+        &self,
+        rounds: u32,
+        mut on_round: F,
+        mut open: O,
+    ) -> Result<Vec<HopStats>, TraceRouteError>
+    where
+        B: ProbeBackend + 'static,
+        O: FnMut() -> Result<(B, IpAddr), TraceRouteError>,
+        F: FnMut(&[HopStats]),
+    {
+        self.rounds(
+            rounds,
+            |stats, _| on_round(stats),
+            |trace_route, events| {
+                let (backend, source, _) = trace_route.sockets.lend(|| {
+                    let (backend, source) = open()?;
+                    Ok((Box::new(backend) as Box<dyn ProbeBackend>, source, None))
+                })?;
+                trace_route.run_with_events_and_backend(events, backend, source)
+            },
+        )
+    }
+
+    /// Same as `run_rounds`, also following the instance every round ends at, so
+    /// an anycast destination answered by several sites is not taken for a route
+    /// that keeps changing, see `AnycastTracker`.
+    ///
+    /// `on_switch` is called when a round ends at another site than the round
+    /// before. The statistics after the last round are returned with the instances.
+    pub fn run_anycast_rounds<F, S>(
+        &self,
+        rounds: u32,
+        options: AnycastOptions,
+        on_round: F,
+        on_switch: S,
+    ) -> Result<(Vec<HopStats>, AnycastReport), TraceRouteError>
+    where
+        F: FnMut(&[HopStats]),
+        S: FnMut(&InstanceSwitch),
+    {
+        self.anycast_rounds(
+            rounds,
+            options,
+            on_round,
+            on_switch,
+            TraceRoute::run_with_events,
+        )
+    }
+
+    /// Same as `run_anycast_rounds`, over clones of the given backend.
+    pub fn run_anycast_rounds_with_backend<B, F, S>(
+        &self,
+        rounds: u32,
+        options: AnycastOptions,
+        on_round: F,
+        on_switch: S,
+        backend: B,
+        source: IpAddr,
+    ) -> Result<(Vec<HopStats>, AnycastReport), TraceRouteError>
+    where
+        B: ProbeBackend + Clone + 'static,
+        F: FnMut(&[HopStats]),
+        S: FnMut(&InstanceSwitch),
+    {
+        self.anycast_rounds(
+            rounds,
+            options,
+            on_round,
+            on_switch,
+            |trace_route, events| {
+                trace_route.run_with_events_and_backend(events, backend.clone(), source)
+            },
+        )
+    }
+
+    fn anycast_rounds<F, S, R>(
+        &self,
+        rounds: u32,
+        options: AnycastOptions,
+        mut on_round: F,
+        mut on_switch: S,
+        run: R,
+    ) -> Result<(Vec<HopStats>, AnycastReport), TraceRouteError>
+    where
+        F: FnMut(&[HopStats]),
+        S: FnMut(&InstanceSwitch),
+        R: FnMut(&TraceRoute, Sender<TraceEvent>) -> Result<TraceHandle, TraceRouteError>,
+    {
+        let mut tracker = AnycastTracker::new(self.address, options);
+        let stats = self.rounds(
+            rounds,
+            |stats, hops| {
+                if let Some(switch) = tracker.record_round(hops) {
+                    on_switch(&switch);
+                }
+                on_round(stats);
+            },
+            run,
+        )?;
+        Ok((stats, tracker.report()))
+    }
+
+    fn rounds<F, R>(
+        &self,
+        rounds: u32,
+        mut on_round: F,
+        mut run: R,
+    ) -> Result<Vec<HopStats>, TraceRouteError>
+    where
+        F: FnMut(&[HopStats], &[HopFound]),
+        R: FnMut(&TraceRoute, Sender<TraceEvent>) -> Result<TraceHandle, TraceRouteError>,
+    {
+        let mut stats = PathStats::new().with_classifier(self.config.response_classifier);
+        let mut config = self.config.clone();
+        for _ in 0..rounds {
+            let (tx, events) = channel();
+            let handle = {
+                let (mut trace_route, _) = TraceRoute::with_config(self.address, config.clone())?;
+                trace_route.sockets = self.sockets.clone();
+                run(&trace_route, tx)?
+            };
+            let (mut hops, mut corrections) = (Vec::new(), Vec::new());
+            for event in events.iter() {
+                match event {
+                    TraceEvent::Hop(hop) => hops.push(hop),
+                    TraceEvent::HopCorrected(correction) => corrections.push(correction),
+                    _ => {}
+                }
+            }
+            let _ = handle.join();
+            let local_end = hops.iter().find(|hop| hop.local_hops.is_some());
+            if let Some(end) = local_end.filter(|end| end.hop_count < config.max_ttl) {
+                config.begin_ttl = end.hop_count + 1;
+            }
+            stats.record_round(&hops);
+            for correction in &corrections {
+                stats.correct(correction);
+            }
+            on_round(&stats.snapshot(), &hops);
+        }
+        Ok(stats.snapshot())
+    }
+
+    /// This function starts route tracing over the given backend.
+    ///
+    /// `source` is the address written into the probes, it has to be of the same
+    /// family as the traced address.
+    pub fn run_with_backend<B: ProbeBackend + 'static>(
+        &self,
+        backend: B,
+        source: IpAddr,
+    ) -> Result<TraceHandle, TraceRouteError> {
+        self.run_on(backend, source, None)
+    }
+
+    /// Same as `run_trace_route`, reporting every event of the trace on `events`
+    /// instead of only its hops on `results_sender`.
+    pub fn run_with_events(
+        &self,
+        events: Sender<TraceEvent>,
+    ) -> Result<TraceHandle, TraceRouteError> {
+        let (backend, self_ip, kind) = self.lend_sockets()?;
+        self.start(backend, self_ip, kind, Emitter::Events(events), spawn)
+    }
+
+    /// Same as `run_with_events`, over the given backend.
+    pub fn run_with_events_and_backend<B: ProbeBackend + 'static>(
+        &self,
+        events: Sender<TraceEvent>,
+        backend: B,
+        source: IpAddr,
+    ) -> Result<TraceHandle, TraceRouteError> {
+        self.start(backend, source, None, Emitter::Events(events), spawn)
+    }
+
+    /// Same as `run_trace_route`, on one of the threads of `pool` instead of a
+    /// thread of its own.
+    #[cfg(target_os = "linux")]
+    pub fn run_in_pool(&self, pool: &TracePool) -> Result<TraceHandle, TraceRouteError> {
+        let (backend, self_ip, kind) = open_backend(self.address, &self.config)?;
+        self.start(
+            backend,
+            self_ip,
+            Some(kind),
+            self.hops(),
+            |span, machine| Worker::Pooled(pool.submit(span, machine)),
+        )
+    }
+
+    /// Same as `run_with_backend`, on one of the threads of `pool`.
+    #[cfg(target_os = "linux")]
+    pub fn run_in_pool_with_backend<B: ProbeBackend + 'static>(
+        &self,
+        pool: &TracePool,
+        backend: B,
+        source: IpAddr,
+    ) -> Result<TraceHandle, TraceRouteError> {
+        self.start(backend, source, None, self.hops(), |span, machine| {
+            Worker::Pooled(pool.submit(span, machine))
+        })
+    }
+
+    fn run_on<B: ProbeBackend + 'static>(
+        &self,
+        backend: B,
+        source: IpAddr,
+        kind: Option<BackendKind>,
+    ) -> Result<TraceHandle, TraceRouteError> {
+        self.start(backend, source, kind, self.hops(), spawn)
+    }
+
+    /// Returns the channel of `results_sender`, which only takes hops.
+    fn hops(&self) -> Emitter {
+        Emitter::Hops(self.results_sender.clone())
+    }
+
+    /// Creates the state of the trace in its span and hands both to `spawn`.
+    fn start<B, S>(
+        &self,
+        backend: B,
+        source: IpAddr,
+        kind: Option<BackendKind>,
+        emitter: Emitter,
+        spawn: S,
+    ) -> Result<TraceHandle, TraceRouteError>
+    where
+        B: ProbeBackend + 'static,
+        S: FnOnce(logging::Span, TraceMachine<B>) -> Worker,
+    {
+        let config = self.config.clone();
+        let address = self.address;
+        let lease = match &config.id_allocator {
+            Some(ids) => Some(ids.lease().ok_or(TraceRouteError::NoIdentifiers)?),
+            None => None,
+        };
+        let identifier = lease
+            .as_ref()
+            .map_or_else(next_identifier, |lease| lease.range().identifier);
+        let trace_id = self.trace_id;
+        let metadata = TraceMetadata {
+            source,
+            destination: address,
+            protocol: config.protocol,
+            identifier,
+            trace_id,
+            fwmark: config.fwmark,
+            preflight: self.preflight.clone(),
+            backend: kind,
+            size: config.size,
+            started_at: SystemTime::now(),
+        };
+        let span = logging::trace_span(trace_id, address, config.protocol, config.max_ttl);
+        let metrics = Arc::new(Metrics::new());
+        let counters = metrics.clone();
+        if source.is_ipv4() != address.is_ipv4() {
+            return Err(TraceRouteError::NoInterface);
+        }
+        let (machine, failure, cancelled, subscribers) = span.in_scope(|| {
+            let sink = HopSink::new(emitter, config.hide_local_hops, counters.clone(), trace_id);
+            sink.report(TraceEvent::Started(metadata.clone()));
+            let (failure, cancelled) = (sink.failure(), sink.cancellation());
+            let subscribers = sink.subscribers();
+            let machine =
+                TraceMachine::new(config, address, source, backend, identifier, sink, counters);
+            let machine = machine.with_lease(lease);
+            (machine, failure, cancelled, subscribers)
+        });
+        Ok(TraceHandle {
+            worker: Some(spawn(span, machine)),
+            metadata,
+            metrics,
+            failure,
+            cancelled,
+            subscribers,
+        })
+    }
+}
+
+/// Runs a trace on a thread of its own.
+fn spawn<B: ProbeBackend + 'static>(span: logging::Span, machine: TraceMachine<B>) -> Worker {
+    Worker::Thread(logging::spawn_in(span, move || machine.run()))
+}
+
+/// Returns the address of the first hop reported on `receiver`.
+fn first_hop_from(receiver: &Receiver<HopFound>) -> Result<IpAddr, TraceRouteError> {
+    receiver
+        .recv()
+        .ok()
+        .and_then(|hop| hop.addr)
+        .ok_or(TraceRouteError::NoFirstHop)
+}
+
+/// This struct describes a started trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct TraceMetadata {
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub protocol: TraceRouteProtocol,
+    /// Identifier written into every ICMP echo probe of the trace.
+    pub identifier: u16,
+    /// Id stamped on every hop of the trace.
+    pub trace_id: u64,
+    /// Firewall mark of the probes, see `TraceRouteConfig::fwmark`.
+    pub fwmark: Option<u32>,
+    /// How the destination was chosen among the addresses of a host name, not
+    /// kept when serialized.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub preflight: Option<Preflight>,
+    /// Kind of sockets `run_trace_route` opened, None for a given backend.
+    pub backend: Option<BackendKind>,
+    /// Bytes of the probes, see `TraceRouteConfig::size`.
+    pub size: usize,
+    /// Wall clock time the trace started at.
+    pub started_at: SystemTime,
+}
+
+/// This enum is what runs a trace.
+enum Worker {
+    Thread(JoinHandle<()>),
+    #[cfg(target_os = "linux")]
+    Pooled(pool::Completion),
+}
+
+/// This struct is returned by a started trace and owns its worker thread, or its
+/// share of a pool thread.
+pub struct TraceHandle {
+    worker: Option<Worker>,
+    metadata: TraceMetadata,
+    metrics: Arc<Metrics>,
+    failure: Arc<Mutex<Option<TraceRouteError>>>,
+    cancelled: Arc<AtomicBool>,
+    subscribers: Broadcast,
+}
+
+/// This block implements TraceHandle struct.
+impl TraceHandle {
+    /// Returns the addresses and protocol the trace was started with.
+    pub fn metadata(&self) -> &TraceMetadata {
+        &self.metadata
+    }
+
+    /// Returns the id stamped on the hops of the trace.
+    pub fn trace_id(&self) -> u64 {
+        self.metadata.trace_id
+    }
+
+    /// Returns the counters of the trace so far, final once the worker has stopped.
+    pub fn metrics(&self) -> TraceMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// Returns how far the trace got, without taking any of its hops. Cheap enough
+    /// to poll, the worker is never waited for.
+    pub fn status(&self) -> TraceStatus {
+        let failed = matches!(self.failure.try_lock().as_deref(), Ok(Some(_)));
+        let stopped = if failed {
+            Some(TraceState::Failed)
+        } else if self.is_finished() {
+            Some(TraceState::Completed)
+        } else {
+            None
+        };
+        self.metrics.status(stopped)
+    }
+
+    /// Returns a receiver of every event the trace reports from now on, next to the
+    /// channel it was started with. Any number of subscribers can listen, each
+    /// gets every event, and the trace goes on while one listens even if its
+    /// channel is gone.
+    ///
+    /// A subscriber falling behind by 1024 events loses the next ones instead of
+    /// holding up the trace, see `dropped_events`. The receiver disconnects once
+    /// the trace is over.
+    pub fn subscribe(&self) -> Receiver<TraceEvent> {
+        self.subscribers.subscribe(false)
+    }
+
+    /// Same as `subscribe`, the receiver starts with the events reported so far.
+    pub fn subscribe_with_replay(&self) -> Receiver<TraceEvent> {
+        self.subscribers.subscribe(true)
+    }
+
+    /// Returns how many events subscribers lost by falling behind.
+    pub fn dropped_events(&self) -> u64 {
+        self.subscribers.dropped()
+    }
+
+    /// Returns an iterator over the hops the trace reports on `receiver`, the
+    /// receiver of the `TraceRoute` it was started from.
+    pub fn hops(&self, receiver: Receiver<HopFound>) -> HopIter<'_> {
+        HopIter::new(self, receiver)
+    }
+
+    /// Waits for the hops the trace reports on `receiver` and returns them with
+    /// its metadata, or the error that stopped it.
+    pub fn report(self, receiver: Receiver<HopFound>) -> Result<TraceReport, TraceRouteError> {
+        let hops = self.hops(receiver).collect::<Result<Vec<_>, _>>()?;
+        let metadata = self.metadata.clone();
+        let _ = self.join();
+        Ok(TraceReport::new(metadata, hops))
+    }
+
+    /// Returns the error that stopped the worker, `WorkerStopped` if it reported none.
+    fn failure(&self) -> TraceRouteError {
+        self.failure
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or(TraceRouteError::WorkerStopped)
+    }
+
+    /// Stops the worker at its next wake, without reporting the rest of the trace.
+    /// A probe waiting without timeout wakes at least every 100 milliseconds.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true once the worker has stopped probing.
+    pub fn is_finished(&self) -> bool {
+        match &self.worker {
+            Some(Worker::Thread(worker)) => worker.is_finished(),
+            #[cfg(target_os = "linux")]
+            Some(Worker::Pooled(completion)) => completion.is_done(),
+            None => true,
+        }
+    }
+
+    /// Blocks until the worker has stopped probing.
+    pub fn join(mut self) -> thread::Result<()> {
+        match self.worker.take() {
+            Some(Worker::Thread(worker)) => worker.join(),
+            #[cfg(target_os = "linux")]
+            Some(Worker::Pooled(completion)) => completion.wait(),
+            None => Ok(()),
+        }
+    }
+}
+
+static TRACE_COUNTER: AtomicU16 = AtomicU16::new(0);
+
+/// Returns the ICMP identifier of a new trace.
+///
+/// It is derived from the process ID like ping does, so other tools on the host do
+/// not take our replies, and mixed with a counter to tell traces of one process apart.
+fn next_identifier() -> u16 {
+    let pid = (process::id() & 0xffff) as u16;
+    pid ^ TRACE_COUNTER.fetch_add(1, Ordering::Relaxed)
+}
+
+static TRACE_IDS: AtomicU64 = AtomicU64::new(1);
+
+/// Returns the id of a new trace, ids of one process increase from 1.
+fn next_trace_id() -> u64 {
+    TRACE_IDS.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Converts a length for a 16 bit header field, refusing to truncate it.
+fn length_u16(len: usize) -> Result<u16, TraceRouteError> {
+    u16::try_from(len).map_err(|_| {
+        debug!("a probe of {} bytes does not fit a 16 bit length", len);
+        TraceRouteError::SizeTooLarge {
+            max: config::MAX_PROBE_SIZE,
+        }
+    })
+}
+
+fn build_udp_v4(
+    addr: IpAddr,
+    size: usize,
+    port: u16,
+    ttl: u8,
+    my_ip: Ipv4Addr,
+    source_port: u16,
+) -> Result<Vec<u8>, TraceRouteError> {
+    let mut vec: Vec<u8> = vec![0; size];
+    let mut udp_packet = udp::MutableUdpPacket::new(&mut vec[..]).unwrap();
+    udp_packet.set_source(source_port);
+    udp_packet.set_destination(port);
+    udp_packet.set_length(length_u16(size)?);
+    udp_packet.set_payload(&vec![0; size - 8]);
+    let csum = udp::ipv4_checksum(
+        &udp_packet.to_immutable(),
+        &my_ip,
+        &addr.to_string().parse::<Ipv4Addr>().unwrap(),
+    );
+    udp_packet.set_checksum(csum);
+
+    let mut ipv4_vec: Vec<u8> = vec![0; ipv4::MutableIpv4Packet::minimum_packet_size() + vec.len()];
+    let mut ipv4_packet = ipv4::MutableIpv4Packet::new(&mut ipv4_vec[..]).unwrap();
+    ipv4_packet.set_header_length(5);
+    ipv4_packet.set_fragment_offset(16384);
+    ipv4_packet.set_identification(rand::random::<u16>());
+    ipv4_packet.set_version(4);
+    ipv4_packet.set_ttl(ttl);
+    ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+    let ip = addr.to_string().parse::<Ipv4Addr>().unwrap();
+    ipv4_packet.set_source(my_ip);
+    ipv4_packet.set_destination(ip);
+    ipv4_packet.set_total_length(length_u16(
+        ipv4::MutableIpv4Packet::minimum_packet_size() + vec.len(),
+    )?);
+    ipv4_packet.set_payload(&vec[..]);
+
+    let csum = ipv4::checksum(&ipv4_packet.to_immutable());
+    ipv4_packet.set_checksum(csum);
+    Ok(ipv4_vec)
+}
+
+fn build_udp_v6(
+    addr: IpAddr,
+    size: usize,
+    port: u16,
+    ttl: u8,
+    my_ip: Ipv6Addr,
+    source_port: u16,
+) -> Result<Vec<u8>, TraceRouteError> {
+    let mut vec: Vec<u8> = vec![0; size];
+    let mut udp_packet = udp::MutableUdpPacket::new(&mut vec[..]).unwrap();
+    udp_packet.set_source(source_port);
+    udp_packet.set_destination(port);
+    udp_packet.set_length(length_u16(size)?);
+    udp_packet.set_payload(&vec![0; size - 8]);
+    let csum = udp::ipv6_checksum(
+        &udp_packet.to_immutable(),
+        &my_ip,
+        &addr.to_string().parse::<Ipv6Addr>().unwrap(),
+    );
+    udp_packet.set_checksum(csum);
+
+    let mut ipv6_vec: Vec<u8> = vec![0; ipv6::MutableIpv6Packet::minimum_packet_size() + vec.len()];
+    let mut ipv6_packet = ipv6::MutableIpv6Packet::new(&mut ipv6_vec[..]).unwrap();
+    ipv6_packet.set_version(6);
+    ipv6_packet.set_hop_limit(ttl);
+    ipv6_packet.set_next_header(IpNextHeaderProtocols::Udp);
+    let ip = addr.to_string().parse::<Ipv6Addr>().unwrap();
+    ipv6_packet.set_source(my_ip);
+    ipv6_packet.set_destination(ip);
+    ipv6_packet.set_payload_length(length_u16(vec.len())?);
+    ipv6_packet.set_payload(&vec[..]);
+    Ok(ipv6_vec)
+}
+
+fn build_icmp_v4(
+    addr: IpAddr,
+    size: usize,
+    ttl: u8,
+    my_ip: Ipv4Addr,
+    identifier: u16,
+    sequence: u16,
+) -> Result<Vec<u8>, TraceRouteError> {
+    let mut vec: Vec<u8> = vec![0; size];
+    let mut echo_packet = echo_request::MutableEchoRequestPacket::new(&mut vec[..]).unwrap();
+    echo_packet.set_sequence_number(sequence);
+    echo_packet.set_identifier(identifier);
+    echo_packet.set_icmp_type(IcmpTypes::EchoRequest);
+
+    let csum = icmp_checksum(&echo_packet);
+    echo_packet.set_checksum(csum);
+
+    let mut ipv4_vec: Vec<u8> = vec![0; ipv4::MutableIpv4Packet::minimum_packet_size() + vec.len()];
+    let mut ipv4_packet = ipv4::MutableIpv4Packet::new(&mut ipv4_vec[..]).unwrap();
+    ipv4_packet.set_header_length(5);
+    ipv4_packet.set_fragment_offset(16384);
+    ipv4_packet.set_identification(rand::random::<u16>());
+    ipv4_packet.set_version(4);
+    ipv4_packet.set_ttl(ttl);
+    ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+    let ip = addr.to_string().parse::<Ipv4Addr>().unwrap();
+    ipv4_packet.set_source(my_ip);
+    ipv4_packet.set_destination(ip);
+    ipv4_packet.set_total_length(length_u16(
+        ipv4::MutableIpv4Packet::minimum_packet_size() + vec.len(),
+    )?);
+    ipv4_packet.set_payload(&vec[..]);
+
+    let csum = ipv4::checksum(&ipv4_packet.to_immutable());
+    ipv4_packet.set_checksum(csum);
+    Ok(ipv4_vec)
+}
+
+fn build_icmp_v6(
+    addr: IpAddr,
+    size: usize,
+    ttl: u8,
+    my_ip: Ipv6Addr,
+    identifier: u16,
+    sequence: u16,
+) -> Result<Vec<u8>, TraceRouteError> {
+    let mut vec: Vec<u8> = vec![0; size];
+    vec[4..6].copy_from_slice(&identifier.to_be_bytes());
+    vec[6..8].copy_from_slice(&sequence.to_be_bytes());
+
+    let mut echo_packet = MutableIcmpv6Packet::new(&mut vec[..]).unwrap();
+    echo_packet.set_icmpv6_type(Icmpv6Types::EchoRequest);
+
+    let csum = icmpv6::checksum(
+        &echo_packet.to_immutable(),
+        &my_ip,
+        &addr.to_string().parse::<Ipv6Addr>().unwrap(),
+    );
+    echo_packet.set_checksum(csum);
+
+    let mut ipv6_vec: Vec<u8> = vec![0; ipv6::MutableIpv6Packet::minimum_packet_size() + vec.len()];
+    let mut ipv6_packet = ipv6::MutableIpv6Packet::new(&mut ipv6_vec[..]).unwrap();
+    ipv6_packet.set_version(6);
+    ipv6_packet.set_hop_limit(ttl);
+    ipv6_packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    let ip = addr.to_string().parse::<Ipv6Addr>().unwrap();
+    ipv6_packet.set_source(my_ip);
+    ipv6_packet.set_destination(ip);
+    ipv6_packet.set_payload_length(length_u16(vec.len())?);
+    ipv6_packet.set_payload(&vec[..]);
+    Ok(ipv6_vec)
+}
+
+/// Service code of our DCCP Requests, "trac" in ASCII.
+const DCCP_SERVICE_CODE: u32 = 0x7472_6163;
+
+/// Returns the 48 bit DCCP sequence number of a probe, made of the trace
+/// identifier and the probe sequence.
+fn dccp_sequence(identifier: u16, sequence: u16) -> u64 {
+    u64::from(identifier) << 32 | u64::from(sequence)
+}
+
+/// Builds a DCCP Request with extended sequence numbers and a zero checksum.
+///
+/// The checksum coverage field is left at zero, so the checksum covers the whole
+/// packet, which is just the 20 byte header.
+fn dccp_request(source_port: u16, port: u16, sequence: u64) -> Vec<u8> {
+    let mut vec: Vec<u8> = vec![0; 20];
+    vec[0..2].copy_from_slice(&source_port.to_be_bytes());
+    vec[2..4].copy_from_slice(&port.to_be_bytes());
+    vec[4] = 5;
+    vec[8] = 0x01;
+    vec[10..16].copy_from_slice(&sequence.to_be_bytes()[2..]);
+    vec[16..20].copy_from_slice(&DCCP_SERVICE_CODE.to_be_bytes());
+    vec
+}
+
+fn build_dccp_v4(
+    addr: IpAddr,
+    port: u16,
+    ttl: u8,
+    my_ip: Ipv4Addr,
+    source_port: u16,
+    sequence: u64,
+) -> Result<Vec<u8>, TraceRouteError> {
+    let mut vec = dccp_request(source_port, port, sequence);
+    let ip = addr.to_string().parse::<Ipv4Addr>().unwrap();
+    let csum = util::ipv4_checksum(&vec, 3, &[], &my_ip, &ip, IpNextHeaderProtocols::Dccp);
+    vec[6..8].copy_from_slice(&csum.to_be_bytes());
+
+    let mut ipv4_vec: Vec<u8> = vec![0; ipv4::MutableIpv4Packet::minimum_packet_size() + vec.len()];
+    let mut ipv4_packet = ipv4::MutableIpv4Packet::new(&mut ipv4_vec[..]).unwrap();
+    ipv4_packet.set_header_length(5);
+    ipv4_packet.set_fragment_offset(16384);
+    ipv4_packet.set_identification(rand::random::<u16>());
+    ipv4_packet.set_version(4);
+    ipv4_packet.set_ttl(ttl);
+    ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Dccp);
+    ipv4_packet.set_source(my_ip);
+    ipv4_packet.set_destination(ip);
+    ipv4_packet.set_total_length(length_u16(
+        ipv4::MutableIpv4Packet::minimum_packet_size() + vec.len(),
+    )?);
+    ipv4_packet.set_payload(&vec[..]);
+
+    let csum = ipv4::checksum(&ipv4_packet.to_immutable());
+    ipv4_packet.set_checksum(csum);
+    Ok(ipv4_vec)
+}
+
+fn build_dccp_v6(
+    addr: IpAddr,
+    port: u16,
+    ttl: u8,
+    my_ip: Ipv6Addr,
+    source_port: u16,
+    sequence: u64,
+) -> Result<Vec<u8>, TraceRouteError> {
+    let mut vec = dccp_request(source_port, port, sequence);
+    let ip = addr.to_string().parse::<Ipv6Addr>().unwrap();
+    let csum = util::ipv6_checksum(&vec, 3, &[], &my_ip, &ip, IpNextHeaderProtocols::Dccp);
+    vec[6..8].copy_from_slice(&csum.to_be_bytes());
+
+    let mut ipv6_vec: Vec<u8> = vec![0; ipv6::MutableIpv6Packet::minimum_packet_size() + vec.len()];
+    let mut ipv6_packet = ipv6::MutableIpv6Packet::new(&mut ipv6_vec[..]).unwrap();
+    ipv6_packet.set_version(6);
+    ipv6_packet.set_hop_limit(ttl);
+    ipv6_packet.set_next_header(IpNextHeaderProtocols::Dccp);
+    ipv6_packet.set_source(my_ip);
+    ipv6_packet.set_destination(ip);
+    ipv6_packet.set_payload_length(length_u16(vec.len())?);
+    ipv6_packet.set_payload(&vec[..]);
+    Ok(ipv6_vec)
+}
+
+/// Builds the IPv4 TCP SYN to `port` that confirms a silent destination, its
+/// sequence number made of the trace identifier and the probe sequence.
+fn build_tcp_syn_v4(
+    addr: Ipv4Addr,
+    port: u16,
+    my_ip: Ipv4Addr,
+    source_port: u16,
+    sequence: u32,
+) -> Result<Vec<u8>, TraceRouteError> {
+    let mut vec: Vec<u8> = vec![0; 20];
+    vec[0..2].copy_from_slice(&source_port.to_be_bytes());
+    vec[2..4].copy_from_slice(&port.to_be_bytes());
+    vec[4..8].copy_from_slice(&sequence.to_be_bytes());
+    vec[12] = 5 << 4;
+    vec[13] = reply::TCP_SYN;
+    vec[14..16].copy_from_slice(&1024u16.to_be_bytes());
+    let csum = util::ipv4_checksum(&vec, 8, &[], &my_ip, &addr, IpNextHeaderProtocols::Tcp);
+    vec[16..18].copy_from_slice(&csum.to_be_bytes());
+
+    let mut ipv4_vec: Vec<u8> = vec![0; ipv4::MutableIpv4Packet::minimum_packet_size() + vec.len()];
+    let mut ipv4_packet = ipv4::MutableIpv4Packet::new(&mut ipv4_vec[..]).unwrap();
+    ipv4_packet.set_header_length(5);
+    ipv4_packet.set_fragment_offset(16384);
+    ipv4_packet.set_identification(rand::random::<u16>());
+    ipv4_packet.set_version(4);
+    ipv4_packet.set_ttl(64);
+    ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+    ipv4_packet.set_source(my_ip);
+    ipv4_packet.set_destination(addr);
+    ipv4_packet.set_total_length(length_u16(
+        ipv4::MutableIpv4Packet::minimum_packet_size() + vec.len(),
+    )?);
+    ipv4_packet.set_payload(&vec[..]);
+
+    let csum = ipv4::checksum(&ipv4_packet.to_immutable());
+    ipv4_packet.set_checksum(csum);
+    Ok(ipv4_vec)
+}
+
+/// IPv4 option type of loose source and record route.
+const IPOPT_LSRR: u8 = 131;
+
+/// Flag of the IPv4 option types fragments past the first carry too.
+const IPOPT_COPIED: u8 = 0x80;
+
+/// Returns the loose source route option through `gateways` to `destination`,
+/// padded with end of options to a multiple of 4 bytes.
+///
+/// The probe itself is addressed to the first gateway, so the route data lists
+/// the other gateways and the final destination.
+fn lsrr_option(gateways: &[Ipv4Addr], destination: Ipv4Addr) -> Vec<u8> {
+    let route = gateways.iter().skip(1).chain(std::iter::once(&destination));
+    let mut option = vec![IPOPT_LSRR, (3 + 4 * gateways.len()) as u8, 4];
+    for hop in route {
+        option.extend_from_slice(&hop.octets());
+    }
+    option.resize(option.len().div_ceil(4) * 4, 0);
+    option
+}
+
+/// Returns an empty Record Route option with room for `slots` addresses.
+fn record_route_option(slots: usize) -> Vec<u8> {
+    let mut option = vec![reply::IPOPT_RR, (3 + 4 * slots) as u8, 4];
+    option.resize(3 + 4 * slots, 0);
+    option
+}
+
+/// Returns an empty Timestamp option with room for `slots` address and timestamp
+/// pairs.
+fn timestamp_option(slots: usize) -> Vec<u8> {
+    let mut option = vec![
+        reply::IPOPT_TS,
+        (4 + 8 * slots) as u8,
+        5,
+        reply::IPOPT_TS_TSANDADDR,
+    ];
+    option.resize(4 + 8 * slots, 0);
+    option
+}
+
+/// Inserts an option in front of the options of an IPv4 probe.
+///
+/// The option is padded with no-operation options to a multiple of 4 bytes, the
+/// header length, total length and checksum are fixed.
+fn insert_option_v4(probe: Vec<u8>, option: &[u8]) -> Result<Vec<u8>, TraceRouteError> {
+    let header_len = usize::from(ipv4::Ipv4Packet::new(&probe).unwrap().get_header_length()) * 4;
+    let padded = option.len().div_ceil(4) * 4;
+
+    let mut ipv4_vec = probe[..20].to_vec();
+    ipv4_vec.extend_from_slice(option);
+    ipv4_vec.resize(20 + padded, 1);
+    ipv4_vec.extend_from_slice(&probe[20..]);
+    let total_length = length_u16(ipv4_vec.len())?;
+    let mut ipv4_packet = ipv4::MutableIpv4Packet::new(&mut ipv4_vec[..]).unwrap();
+    ipv4_packet.set_header_length(((header_len + padded) / 4) as u8);
+    ipv4_packet.set_total_length(total_length);
+    let csum = ipv4::checksum(&ipv4_packet.to_immutable());
+    ipv4_packet.set_checksum(csum);
+    Ok(ipv4_vec)
+}
+
+/// Inserts a loose source route through `gateways` into an IPv4 probe.
+///
+/// The probe is readdressed to the first gateway, the transport checksums stay
+/// computed over the final destination.
+fn source_route_v4(mut probe: Vec<u8>, gateways: &[Ipv4Addr]) -> Result<Vec<u8>, TraceRouteError> {
+    let first = match gateways.first() {
+        Some(first) => *first,
+        None => return Ok(probe),
+    };
+    let mut header = ipv4::MutableIpv4Packet::new(&mut probe).unwrap();
+    let destination = header.get_destination();
+    header.set_destination(first);
+    insert_option_v4(probe, &lsrr_option(gateways, destination))
+}
+
+/// Adds the IPv4 options turned on in `config` to a probe.
+fn add_options_v4(probe: Vec<u8>, config: &TraceRouteConfig) -> Result<Vec<u8>, TraceRouteError> {
+    if !config.gateways.is_empty() {
+        debug!("source routing the probe through {:?}", config.gateways);
+    }
+    let probe = source_route_v4(probe, &config.gateways)?;
+    if config.record_route {
+        insert_option_v4(probe, &record_route_option(config.record_route_slots()))
+    } else if config.timestamps {
+        insert_option_v4(probe, &timestamp_option(config.timestamp_slots()))
+    } else {
+        Ok(probe)
+    }
+}
+
+/// Option type of PadN, RFC 8200 section 4.2.
+const IP6OPT_PADN: u8 = 1;
+
+/// Inserts an extension header of `kind` holding a PadN option of four bytes
+/// between the IPv6 header of a probe and its transport header.
+///
+/// The extension header takes the next header value of the probe and the probe
+/// that of the extension header. The transport checksum is computed again over
+/// the pseudo-header, which names the transport protocol and its length rather
+/// than what the IPv6 header chains to.
+fn insert_extension_v6(probe: Vec<u8>, kind: ExtensionHeader) -> Result<Vec<u8>, TraceRouteError> {
+    let (header, transport) = probe.split_at(ipv6::MutableIpv6Packet::minimum_packet_size());
+    let protocol = header[6];
+    let mut transport = transport.to_vec();
+    let checksum_word = match protocol {
+        58 => Some(1),
+        17 | 33 => Some(3),
+        _ => None,
+    };
+    if let (Some(word), true) = (checksum_word, transport.len() >= 8) {
+        let packet = ipv6::Ipv6Packet::new(header).unwrap();
+        let csum = util::ipv6_checksum(
+            &transport,
+            word,
+            &[],
+            &packet.get_source(),
+            &packet.get_destination(),
+            IpNextHeaderProtocol(protocol),
+        );
+        transport[word * 2..word * 2 + 2].copy_from_slice(&csum.to_be_bytes());
+    }
+    let mut ipv6_vec = header.to_vec();
+    ipv6_vec.extend_from_slice(&[protocol, 0, IP6OPT_PADN, 4, 0, 0, 0, 0]);
+    ipv6_vec.extend_from_slice(&transport);
+    let payload_length = length_u16(ipv6_vec.len() - header.len())?;
+    let mut ipv6_packet = ipv6::MutableIpv6Packet::new(&mut ipv6_vec[..]).unwrap();
+    ipv6_packet.set_next_header(IpNextHeaderProtocol(kind.number()));
+    ipv6_packet.set_payload_length(payload_length);
+    Ok(ipv6_vec)
+}
+
+/// Writes a DSCP into the IPv4 TOS byte or the IPv6 traffic class of a probe.
+fn with_dscp(mut probe: Vec<u8>, dscp: u8) -> Vec<u8> {
+    if dscp == 0 {
+        return probe;
+    }
+    match probe.first().map(|b| b >> 4) {
+        Some(4) => {
+            let mut header = ipv4::MutableIpv4Packet::new(&mut probe).unwrap();
+            header.set_dscp(dscp);
+            let csum = ipv4::checksum(&header.to_immutable());
+            header.set_checksum(csum);
+        }
+        Some(6) => {
+            let mut header = ipv6::MutableIpv6Packet::new(&mut probe).unwrap();
+            header.set_traffic_class(dscp << 2);
+        }
+        _ => {}
+    }
+    probe
+}
+
+/// Fragment header number of IPv6 next header fields.
+const IPV6_FRAGMENT: u8 = 44;
+
+/// Splits a probe into two fragments, the first one carrying its IP header, its
+/// transport header and about half its payload.
+///
+/// Transport checksums are computed over the whole datagram, so they stay as the
+/// probe has them. IPv4 fragments share the identification of the probe, IPv6 ones
+/// `identification`.
+fn fragment_probe(probe: &[u8], identification: u32) -> Vec<Vec<u8>> {
+    match probe.first().map(|b| b >> 4) {
+        Some(6) => fragment_v6(probe, identification),
+        _ => fragment_v4(probe),
+    }
+}
+
+/// Returns how many bytes of a fragmentable part of `len` bytes go in the first
+/// fragment: half of them, at least the first `keep`, in the 8 byte units fragment
+/// offsets count in.
+fn first_fragment_len(len: usize, keep: usize) -> usize {
+    (len / 2 / 8 * 8).max(keep.div_ceil(8) * 8)
+}
+
+/// Returns the options of an IPv4 header that fragments past the first carry, the
+/// ones with the copied flag like a loose source route, RFC 791.
+fn copied_options_v4(options: &[u8]) -> Vec<u8> {
+    let mut copied = Vec::new();
+    let mut at = 0;
+    while let Some(&kind) = options.get(at) {
+        let len = match kind {
+            reply::IPOPT_END => break,
+            reply::IPOPT_NOOP => 1,
+            _ => usize::from(options.get(at + 1).copied().unwrap_or(0)).max(2),
+        };
+        if kind & IPOPT_COPIED != 0 {
+            copied.extend_from_slice(&options[at..(at + len).min(options.len())]);
+        }
+        at += len;
+    }
+    copied.resize(copied.len().div_ceil(4) * 4, reply::IPOPT_NOOP);
+    copied
+}
+
+/// Splits an IPv4 probe into two fragments, with the don't fragment bit cleared.
+fn fragment_v4(probe: &[u8]) -> Vec<Vec<u8>> {
+    let header_len = usize::from(probe[0] & 0x0f) * 4;
+    let (header, payload) = probe.split_at(header_len);
+    let split = first_fragment_len(payload.len(), 8);
+    let later_header = [&header[..20], &copied_options_v4(&header[20..])[..]].concat();
+    [
+        (header, 0, &payload[..split]),
+        (&later_header[..], split, &payload[split..]),
+    ]
+    .iter()
+    .map(|&(header, offset, data)| {
+        let mut fragment = [header, data].concat();
+        let total_length = fragment.len() as u16;
+        let mut packet = ipv4::MutableIpv4Packet::new(&mut fragment).unwrap();
+        packet.set_header_length((header.len() / 4) as u8);
+        packet.set_total_length(total_length);
+        packet.set_flags(match offset {
+            0 => ipv4::Ipv4Flags::MoreFragments,
+            _ => 0,
+        });
+        packet.set_fragment_offset((offset / 8) as u16);
+        let csum = ipv4::checksum(&packet.to_immutable());
+        packet.set_checksum(csum);
+        fragment
+    })
+    .collect()
+}
+
+/// Splits an IPv6 probe into two fragments, each with a Fragment header.
+///
+/// The IPv6 header and a Hop-by-Hop Options header stay in front of the Fragment
+/// header in both, RFC 8200 section 4.5, whatever follows is fragmented.
+fn fragment_v6(probe: &[u8], identification: u32) -> Vec<Vec<u8>> {
+    let header_len = ipv6::MutableIpv6Packet::minimum_packet_size();
+    let (chained_at, unfragmentable) = match probe[6] {
+        0 => (
+            header_len,
+            header_len + (usize::from(probe[header_len + 1]) + 1) * 8,
+        ),
+        _ => (6, header_len),
+    };
+    let (header, fragmentable) = probe.split_at(unfragmentable);
+    let upper_layer = reply::upper_layer_v6(probe).map_or(unfragmentable, |(_, at)| at);
+    let split = first_fragment_len(fragmentable.len(), upper_layer - unfragmentable + 8);
+    [(0, &fragmentable[..split]), (split, &fragmentable[split..])]
+        .iter()
+        .map(|&(offset, data)| {
+            let more = u16::from(offset == 0);
+            let mut fragment = header.to_vec();
+            fragment[chained_at] = IPV6_FRAGMENT;
+            fragment.extend_from_slice(&[probe[chained_at], 0]);
+            fragment.extend_from_slice(&(offset as u16 | more).to_be_bytes());
+            fragment.extend_from_slice(&identification.to_be_bytes());
+            fragment.extend_from_slice(data);
+            let payload_length = (fragment.len() - header_len) as u16;
+            fragment[4..6].copy_from_slice(&payload_length.to_be_bytes());
+            fragment
+        })
+        .collect()
+}
+
+/// Returns the IPv4 options of the probe a reply answers.
+///
+/// ICMP errors quote the probe header, echo replies carry the options back in
+/// their own header.
+fn probe_options_of<B: ProbeBackend>(backend: &B, message: &[u8]) -> Vec<u8> {
+    match reply::quoted_options_v4(message) {
+        Some(options) => options.to_vec(),
+        None => backend.reply_options(),
+    }
+}
+
+/// Returns the bytes of a reply to attach to its hop, cut to the configured limit.
+///
+/// Nothing is copied unless `config.capture_raw` is on.
+fn raw_reply_of(
+    config: &TraceRouteConfig,
+    header: impl FnOnce() -> Option<Vec<u8>>,
+    message: &[u8],
+) -> Option<Vec<u8>> {
+    if !config.capture_raw {
+        return None;
+    }
+    let mut raw = header().unwrap_or_default();
+    raw.extend_from_slice(message);
+    raw.truncate(config.raw_capture_limit);
+    Some(raw)
+}
+
+/// Reports a reply the worker did not expect, with its bytes if they were captured.
+///
+/// It is logged as a warning with the `log` or `tracing` feature, and never
+/// written to stdout.
+fn report_unexpected(kind: impl fmt::Debug, raw_reply: Option<Vec<u8>>) {
+    match raw_reply {
+        Some(raw) => warn!("unexpected ICMP packet {:?}, bytes {:02x?}", kind, raw),
+        None => warn!("unexpected ICMP packet {:?}", kind),
+    }
+}
+
+/// Fills the fields of a hop reported from the options of the answered probe.
+fn fill_from_options(hop: &mut HopFound, options: &[u8]) {
+    hop.recorded_route = reply::recorded_route(options).unwrap_or_default();
+    hop.timestamps = reply::recorded_timestamps(options).unwrap_or_default();
+}
+
+/// Builds the payload of a raw probe: identifier, sequence and the send time in
+/// microseconds since the epoch, padded with zeros to `size`.
+fn raw_payload(size: usize, identifier: u16, sequence: u16) -> Vec<u8> {
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64);
+    let mut vec: Vec<u8> = vec![0; size.max(12)];
+    vec[0..2].copy_from_slice(&identifier.to_be_bytes());
+    vec[2..4].copy_from_slice(&sequence.to_be_bytes());
+    vec[4..12].copy_from_slice(&micros.to_be_bytes());
+    vec
+}
+
+fn build_raw_v4(
+    addr: IpAddr,
+    protocol: u8,
+    size: usize,
+    ttl: u8,
+    my_ip: Ipv4Addr,
+    identifier: u16,
+    sequence: u16,
+) -> Result<Vec<u8>, TraceRouteError> {
+    let vec = raw_payload(size, identifier, sequence);
+
+    let mut ipv4_vec: Vec<u8> = vec![0; ipv4::MutableIpv4Packet::minimum_packet_size() + vec.len()];
+    let mut ipv4_packet = ipv4::MutableIpv4Packet::new(&mut ipv4_vec[..]).unwrap();
+    ipv4_packet.set_header_length(5);
+    ipv4_packet.set_fragment_offset(16384);
+    ipv4_packet.set_identification(rand::random::<u16>());
+    ipv4_packet.set_version(4);
+    ipv4_packet.set_ttl(ttl);
+    ipv4_packet.set_next_level_protocol(IpNextHeaderProtocol::new(protocol));
+    let ip = addr.to_string().parse::<Ipv4Addr>().unwrap();
+    ipv4_packet.set_source(my_ip);
+    ipv4_packet.set_destination(ip);
+    ipv4_packet.set_total_length(length_u16(
+        ipv4::MutableIpv4Packet::minimum_packet_size() + vec.len(),
+    )?);
+    ipv4_packet.set_payload(&vec[..]);
+
+    let csum = ipv4::checksum(&ipv4_packet.to_immutable());
+    ipv4_packet.set_checksum(csum);
+    Ok(ipv4_vec)
+}
+
+fn build_raw_v6(
+    addr: IpAddr,
+    protocol: u8,
+    size: usize,
+    ttl: u8,
+    my_ip: Ipv6Addr,
+    identifier: u16,
+    sequence: u16,
+) -> Result<Vec<u8>, TraceRouteError> {
+    let vec = raw_payload(size, identifier, sequence);
+
+    let mut ipv6_vec: Vec<u8> = vec![0; ipv6::MutableIpv6Packet::minimum_packet_size() + vec.len()];
+    let mut ipv6_packet = ipv6::MutableIpv6Packet::new(&mut ipv6_vec[..]).unwrap();
+    ipv6_packet.set_version(6);
+    ipv6_packet.set_hop_limit(ttl);
+    ipv6_packet.set_next_header(IpNextHeaderProtocol::new(protocol));
+    let ip = addr.to_string().parse::<Ipv6Addr>().unwrap();
+    ipv6_packet.set_source(my_ip);
+    ipv6_packet.set_destination(ip);
+    ipv6_packet.set_payload_length(length_u16(vec.len())?);
+    ipv6_packet.set_payload(&vec[..]);
+    Ok(ipv6_vec)
+}
+
+/// Opens the sockets for tracing `address` and returns them with the source address
+/// of the probes and their kind.
+fn open_backend(
+    address: IpAddr,
+    config: &TraceRouteConfig,
+) -> Result<(Box<dyn ProbeBackend>, IpAddr, BackendKind), TraceRouteError> {
+    config.check_documentation(address)?;
+    let given = match (config.backend, &config.datalink) {
+        (Some(BackendKind::Datalink), Some(link)) => link.source,
+        _ => None,
+    };
+    let interface = config.device()?;
+    let self_ip = match given.or_else(|| select_source(address, interface.as_deref())) {
+        Some(ip) => ip,
+        None => return Err(TraceRouteError::NoInterface),
     };
-    let mut seen: BTreeSet<IpAddr> = BTreeSet::new();
-    let protocol = Layer4(Ipv4(IpNextHeaderProtocols::Icmpv6));
-    let (_, transport_rx) = match transport_channel(4096, protocol) {
-        Ok((tx, rx)) => (tx, rx),
-        Err(_) => return,
+    // Raw IPv6 sockets leave the header to the kernel, which only sends fragments
+    // it cut itself.
+    let chain = match address {
+        IpAddr::V6(_) if config.fragment && config.backend.is_none() => {
+            vec![BackendKind::Datalink]
+        }
+        _ => backend_chain(config),
     };
-    thread::spawn(move || {
-        let ipv6_protocol = match trace_route_protocol {
+    let (backend, kind) = select_backend(&chain, |kind| open_kind(kind, address, self_ip, config))?;
+    Ok((backend, self_ip, kind))
+}
+
+/// Returns the kinds of sockets tried for `config`, in order.
+///
+/// Unprivileged sockets only carry their own protocol and leave the IP header to
+/// the kernel, so they are skipped for traces that need more.
+fn backend_chain(config: &TraceRouteConfig) -> Vec<BackendKind> {
+    if let Some(kind) = config.backend {
+        return vec![kind];
+    }
+    let mut chain = vec![BackendKind::Raw];
+    let plain = config.gateways.is_empty()
+        && !config.record_route
+        && !config.timestamps
+        && config.extension_header.is_none()
+        && !config.fragment
+        && config.protocol_fallback.is_none();
+    if cfg!(target_os = "linux") && plain {
+        chain.extend(
+            [BackendKind::DgramIcmp, BackendKind::UdpErrqueue]
+                .iter()
+                .filter(|kind| kind.carries(config.protocol)),
+        );
+    }
+    chain
+}
+
+/// Opens the first kind of `chain` that works.
+///
+/// A chain of one kind fails with its own error, longer ones list every failure.
+fn select_backend<T, F>(
+    chain: &[BackendKind],
+    mut open: F,
+) -> Result<(T, BackendKind), TraceRouteError>
+where
+    F: FnMut(BackendKind) -> Result<T, TraceRouteError>,
+{
+    let mut tried = Vec::with_capacity(chain.len());
+    for &kind in chain {
+        match open(kind) {
+            Ok(backend) => return Ok((backend, kind)),
+            Err(e) => {
+                debug!("could not open {} sockets: {}", kind, e);
+                tried.push((kind, e));
+            }
+        }
+    }
+    match tried.len() {
+        1 => Err(tried.remove(0).1),
+        _ => Err(TraceRouteError::NoBackend { tried }),
+    }
+}
+
+/// Opens sockets of the given kind for probes from `source`.
+fn open_kind(
+    kind: BackendKind,
+    address: IpAddr,
+    source: IpAddr,
+    config: &TraceRouteConfig,
+) -> Result<Box<dyn ProbeBackend>, TraceRouteError> {
+    match kind {
+        BackendKind::Raw => Ok(Box::new(open_raw(address, config)?)),
+        BackendKind::Datalink => Ok(Box::new(open_link(address, source, config)?)),
+        #[cfg(target_os = "linux")]
+        BackendKind::DgramIcmp => Ok(Box::new(open_dgram(address, config, DgramProtocol::Icmp)?)),
+        #[cfg(target_os = "linux")]
+        BackendKind::UdpErrqueue => Ok(Box::new(open_dgram(address, config, DgramProtocol::Udp)?)),
+        #[cfg(not(target_os = "linux"))]
+        _ => Err(TraceRouteError::Channel {
+            kind: io::ErrorKind::Unsupported,
+            message: format!("{} sockets are only available on Linux", kind),
+        }),
+    }
+}
+
+/// Opens the raw sockets of a trace to `address`.
+fn open_raw(address: IpAddr, config: &TraceRouteConfig) -> Result<PnetBackend, TraceRouteError> {
+    let backend = if address.is_ipv4() {
+        let ipv4_protocol = match config.protocol {
             TraceRouteProtocol::Udp => Layer3(IpNextHeaderProtocols::Udp),
-            TraceRouteProtocol::Icmp => Layer3(IpNextHeaderProtocols::Icmpv6),
-        };
-        let (mut ipv6_tx, _) = match transport_channel(4096, ipv6_protocol) {
-            Ok((tx, rx)) => (tx, rx),
-            Err(_) => return,
-        };
-
-        let mut receiver = transport_rx;
-        let mut iter = icmpv6_packet_iter(&mut receiver);
-        let mut i: u8 = begin_ttl;
-        let mut tries: u16 = 0;
-        let mut has_changed = false;
-        let mut timer;
-        loop {
-            if i > end_ttl {
-                tx.send(HopFound {
-                    addr: None,
-                    hop_count: i,
-                    tries,
-                    is_last: true,
-                    time: None,
-                })
+            TraceRouteProtocol::Icmp => Layer3(IpNextHeaderProtocols::Icmp),
+            TraceRouteProtocol::Dccp => Layer3(IpNextHeaderProtocols::Dccp),
+            TraceRouteProtocol::Raw(number) => Layer3(IpNextHeaderProtocol::new(number)),
+        };
+        let (ipv4_tx, ipv4_rx) =
+            transport_channel(4096, ipv4_protocol).map_err(TraceRouteError::from_channel)?;
+        if config.shared_replies().is_some() {
+            // The shared receiver reads the replies, no ICMP socket of the trace is
+            // opened and the receiving half of the probe socket is never read.
+            PnetBackend::new(ipv4_tx, ipv4_rx, true)
+        } else {
+            let (_, transport_rx) =
+                transport_channel(4096, Layer4(Ipv4(IpNextHeaderProtocols::Icmp)))
+                    .map_err(TraceRouteError::from_channel)?;
+            let backend = PnetBackend::new(ipv4_tx, transport_rx, true);
+            if config.protocol == TraceRouteProtocol::Dccp {
+                backend.with_transport_receiver(ipv4_rx)
+            } else if config.confirm_tcp(address).is_some() {
+                let (_, tcp_rx) = transport_channel(4096, Layer3(IpNextHeaderProtocols::Tcp))
+                    .map_err(TraceRouteError::from_channel)?;
+                backend.with_transport_receiver(tcp_rx)
+            } else {
+                backend
+            }
+        }
+    } else {
+        let (echo_tx, transport_rx) =
+            transport_channel(4096, Layer4(Ipv6(IpNextHeaderProtocols::Icmpv6)))
+                .map_err(TraceRouteError::from_channel)?;
+        let ipv6_protocol = match config.protocol {
+            TraceRouteProtocol::Udp => Layer4(Ipv6(IpNextHeaderProtocols::Udp)),
+            TraceRouteProtocol::Icmp => Layer4(Ipv6(IpNextHeaderProtocols::Icmpv6)),
+            TraceRouteProtocol::Dccp => Layer4(Ipv6(IpNextHeaderProtocols::Dccp)),
+            TraceRouteProtocol::Raw(number) => Layer4(Ipv6(IpNextHeaderProtocol::new(number))),
+        };
+        let (ipv6_tx, ipv6_rx) =
+            transport_channel(4096, ipv6_protocol).map_err(TraceRouteError::from_channel)?;
+        let backend = PnetBackend::new(ipv6_tx, transport_rx, false).with_echo_sender(echo_tx);
+        if config.protocol == TraceRouteProtocol::Dccp {
+            backend.with_transport_receiver(ipv6_rx)
+        } else {
+            backend
+        }
+    };
+    let interface = config.device()?;
+    if let Some(interface) = &interface {
+        bind_interface(&backend, interface)?;
+    }
+    if let Some(mark) = config.fwmark {
+        mark_sockets(&backend, mark)?;
+    }
+    if let Some(interface) = &interface {
+        bind_interface(&backend, interface)?;
+    }
+    if let Some(mark) = config.fwmark {
+        mark_sockets(&backend, mark)?;
+    }
+    Ok(backend)
+}
+
+/// Time an Extended Echo request waits for its reply when the trace waits
+/// without end.
+const PROBE_INTERFACE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Echo probes `TraceRoute::calibrate` sends to the loopback address.
+const CALIBRATION_PROBES: u16 = 5;
+
+/// Time between the calibration probes.
+const CALIBRATION_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a calibration probe is waited for, the loopback answers at once.
+const CALIBRATION_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Returns the loopback address of the family of `address`.
+fn loopback_of(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+    }
+}
+
+/// How long the next hop of a trace waiting without timeout is looked up.
+const NEIGHBOR_WAIT: Duration = Duration::from_secs(1);
+
+/// Opens the datalink channel of a trace to `address` from `source`, next to the
+/// raw sockets its replies are read from.
+fn open_link(
+    address: IpAddr,
+    source: IpAddr,
+    config: &TraceRouteConfig,
+) -> Result<LinkBackend, TraceRouteError> {
+    let link = config.datalink.clone().unwrap_or_default();
+    let device =
+        link_device(source, config.device()?.as_deref()).ok_or(TraceRouteError::NoInterface)?;
+    let source_mac = link
+        .source_mac
+        .or(device.mac)
+        .ok_or(TraceRouteError::NoInterface)?;
+    let channel = datalink::Config {
+        read_timeout: Some(Duration::from_millis(100)),
+        ..datalink::Config::default()
+    };
+    let (mut tx, mut rx) = match datalink::channel(&device, channel) {
+        Ok(datalink::Channel::Ethernet(tx, rx)) => (tx, rx),
+        Ok(_) => {
+            return Err(TraceRouteError::Channel {
+                kind: io::ErrorKind::Unsupported,
+                message: "unsupported datalink channel".to_string(),
+            })
+        }
+        Err(e) => return Err(TraceRouteError::from_channel(e)),
+    };
+    let next_hop = match link.gateway_mac {
+        Some(mac) => mac,
+        None => {
+            let neighbor =
+                next_hop(&device, address).ok_or(TraceRouteError::NoNeighbor(address))?;
+            let timeout = config.timeout.unwrap_or(NEIGHBOR_WAIT);
+            link::resolve(&mut *tx, &mut *rx, source_mac, source, neighbor, timeout)
+                .map_err(TraceRouteError::from_channel)?
+                .ok_or(TraceRouteError::NoNeighbor(neighbor))?
+        }
+    };
+    Ok(LinkBackend::new(
+        tx,
+        source_mac,
+        next_hop,
+        open_raw(address, config)?,
+    ))
+}
+
+/// Returns the device probes from `source` leave through, `interface` if it is given.
+fn link_device(source: IpAddr, interface: Option<&str>) -> Option<datalink::NetworkInterface> {
+    datalink::interfaces()
+        .into_iter()
+        .find(|device| match interface {
+            Some(name) => device.name == name,
+            None => device.ips.iter().any(|ip| ip.ip() == source),
+        })
+}
+
+/// Returns the neighbor on `device` that frames to `address` go to: the address
+/// itself on the link, the default gateway otherwise.
+fn next_hop(device: &datalink::NetworkInterface, address: IpAddr) -> Option<IpAddr> {
+    if device.ips.iter().any(|network| network.contains(address)) {
+        return Some(address);
+    }
+    #[cfg(target_os = "linux")]
+    return default_gateway(if address.is_ipv4() {
+        IpFamily::V4
+    } else {
+        IpFamily::V6
+    });
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// Opens the unprivileged datagram socket of a trace to `address`.
+#[cfg(target_os = "linux")]
+fn open_dgram(
+    address: IpAddr,
+    config: &TraceRouteConfig,
+    protocol: DgramProtocol,
+) -> Result<DgramBackend, TraceRouteError> {
+    let backend =
+        DgramBackend::open(address.is_ipv4(), protocol).map_err(TraceRouteError::from_channel)?;
+    if let Some(interface) = &config.device()? {
+        device_bound(backend.bind_to_device(interface), interface)?;
+    }
+    if let Some(mark) = config.fwmark {
+        mark_set(backend.set_mark(mark))?;
+    }
+    Ok(backend)
+}
+
+/// Picks the source address for probes to `address`.
+///
+/// Loopback destinations and addresses of this machine are probed from themselves,
+/// so the probes never leave the host.
+fn select_source(address: IpAddr, interface: Option<&str>) -> Option<IpAddr> {
+    let is_own = datalink::interfaces()
+        .iter()
+        .any(|iface| iface.ips.iter().any(|ip| ip.ip() == address));
+    if address.is_loopback() || is_own {
+        return Some(address);
+    }
+    get_ip_addr(address.is_ipv4(), interface)
+}
+
+/// Returns an address of the wanted family, taken from `interface` if it is given.
+fn get_ip_addr(v4: bool, interface: Option<&str>) -> Option<IpAddr> {
+    for iface in datalink::interfaces() {
+        let wanted = match interface {
+            Some(name) => iface.name == name,
+            None => !iface.is_loopback(),
+        };
+        if wanted && iface.is_up() {
+            for ip in iface.ips {
+                if ip.ip().is_ipv4() && v4 {
+                    return Some(ip.ip());
+                }
+                if ip.ip().is_ipv6() && !v4 {
+                    return Some(ip.ip());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Binds the sockets of `backend` to `interface`.
+fn bind_interface(backend: &PnetBackend, interface: &str) -> Result<(), TraceRouteError> {
+    #[cfg(target_os = "linux")]
+    let bound = backend.bind_to_device(interface);
+    #[cfg(not(target_os = "linux"))]
+    let bound = {
+        let _ = backend;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_BINDTODEVICE is only available on Linux",
+        ))
+    };
+    device_bound(bound, interface)
+}
+
+/// Maps the result of binding to `interface`.
+///
+/// A refusal is only warned about, the probes then follow the routing table.
+fn device_bound(bound: io::Result<()>, interface: &str) -> Result<(), TraceRouteError> {
+    match bound {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            warn!(
+                "could not bind the sockets to {}, probes follow the routing table: {}",
+                interface, e
+            );
+            Ok(())
+        }
+        Err(e) => Err(TraceRouteError::Channel {
+            kind: e.kind(),
+            message: e.to_string(),
+        }),
+    }
+}
+
+/// Sets the firewall mark `mark` on the sending sockets of `backend`.
+fn mark_sockets(backend: &PnetBackend, mark: u32) -> Result<(), TraceRouteError> {
+    #[cfg(target_os = "linux")]
+    let marked = backend.set_mark(mark);
+    #[cfg(not(target_os = "linux"))]
+    let marked = {
+        let _ = (backend, mark);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_MARK is only available on Linux",
+        ))
+    };
+    mark_set(marked)
+}
+
+/// Maps the result of setting the firewall mark.
+fn mark_set(marked: io::Result<()>) -> Result<(), TraceRouteError> {
+    match marked {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            warn!("could not set the firewall mark: {}", e);
+            Err(TraceRouteError::MarkDenied)
+        }
+        Err(e) => Err(TraceRouteError::Channel {
+            kind: e.kind(),
+            message: e.to_string(),
+        }),
+    }
+}
+
+/// Sends the `packets` of a probe to `to` together with a first hop probe if one
+/// is due, returns when they left by `clock`.
+fn send_probes<B: ProbeBackend>(
+    backend: &mut B,
+    monitor: &mut Option<FirstHopMonitor>,
+    clock: &dyn Clock,
+    packets: &[Vec<u8>],
+    to: IpAddr,
+) -> io::Result<Instant> {
+    let first_hop = monitor
+        .as_mut()
+        .and_then(|monitor| monitor.due(clock.now()));
+    // The first hop probe goes first, as its reply usually comes back first.
+    let mut batch = Vec::with_capacity(packets.len() + 1);
+    if let Some(first_hop) = &first_hop {
+        batch.push((&first_hop[..], to));
+    }
+    batch.extend(packets.iter().map(|packet| (&packet[..], to)));
+    // Probes are timed right before the send, the time the kernel takes to put
+    // them on the wire is part of their round trip as measured at the reply.
+    let batch_at = clock.now();
+    let sent = match backend.send_batch(&batch) {
+        Ok(sent) => sent,
+        Err(e) if first_hop.is_some() => {
+            debug!("could not send a first hop probe: {}", e);
+            0
+        }
+        Err(e) => return Err(e),
+    };
+    let sent_at = if sent < batch.len() {
+        let sent_at = clock.now();
+        // Fragments already sent are not sent again.
+        let unsent = sent.max(batch.len() - packets.len());
+        for &(packet, to) in &batch[unsent..] {
+            backend.send_to(packet, to)?;
+        }
+        sent_at
+    } else {
+        batch_at
+    };
+    if let (Some(monitor), Some(first_hop)) = (monitor, first_hop) {
+        if sent > 0 {
+            monitor.sent(&first_hop, batch_at);
+        }
+    }
+    Ok(sent_at)
+}
+
+/// Reports the hop the destination answered at and returns true if the trace goes
+/// on probing past it, see `TraceRouteConfig::continue_past_destination`.
+///
+/// Only the first answer keeps the `Reached` completion, later ones are beyond it.
+fn destination_answered(
+    tx: &mut HopSink,
+    config: &TraceRouteConfig,
+    mut hop: HopFound,
+    reached: &mut bool,
+) -> bool {
+    if !config.continue_past_destination {
+        let _ = tx.send(hop);
+        return false;
+    }
+    hop.is_last = false;
+    if *reached {
+        hop.completion = None;
+        hop.beyond_destination = true;
+    }
+    *reached = true;
+    tx.send(hop).is_ok()
+}
+
+/// Returns true if a trace that ended in silence should confirm its destination.
+fn needs_confirmation(
+    config: &TraceRouteConfig,
+    last_responder: Option<IpAddr>,
+    destination: IpAddr,
+) -> bool {
+    config.confirm_silent_destination
+        && config.protocol == TraceRouteProtocol::Udp
+        && matches!(last_responder, Some(hop) if config.is_close(hop, destination))
+}
+
+/// Returns the destination port of the next probe for `ttl`, the one `port_fn`
+/// picks if set, else one of the fallback candidates in turn once `trigger_after`
+/// probes went unanswered.
+fn probe_port(config: &TraceRouteConfig, ttl: u8, tries: u16) -> u16 {
+    if let Some(port_fn) = &config.port_fn {
+        return port_fn.port(ttl, tries + 1);
+    }
+    match &config.port_fallback {
+        Some(fallback) if tries >= fallback.trigger_after && !fallback.candidates.is_empty() => {
+            let turn = usize::from(tries - fallback.trigger_after) % fallback.candidates.len();
+            fallback.candidates[turn]
+        }
+        _ => config.port + ttl as u16,
+    }
+}
+
+/// Returns the port to report on a hop, the one quoted back if the reply has it,
+/// else `sent`, only while ports fall back or come from `port_fn`.
+fn port_of(config: &TraceRouteConfig, key: Option<ProbeKey>, sent: u16) -> Option<u16> {
+    if config.port_fallback.is_none() && config.port_fn.is_none() {
+        return None;
+    }
+    match (config.protocol, key) {
+        (
+            TraceRouteProtocol::Udp | TraceRouteProtocol::Dccp,
+            Some(ProbeKey::Udp {
+                destination_port, ..
+            }),
+        ) => Some(destination_port),
+        (TraceRouteProtocol::Udp, _) | (TraceRouteProtocol::Dccp, _) => Some(sent),
+        _ => None,
+    }
+}
+
+/// Returns true if a destination unreachable came from a router in front of the
+/// destination while ports can fall back, so the probe counts as unanswered.
+fn is_blocked(config: &TraceRouteConfig, unreachable: bool, addr: IpAddr, ip: IpAddr) -> bool {
+    unreachable
+        && addr != ip
+        && config.port_fallback.is_some()
+        && matches!(
+            config.protocol,
+            TraceRouteProtocol::Udp | TraceRouteProtocol::Dccp
+        )
+}
+
+/// This enum tells which probe a reply answers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Attribution {
+    /// The probe of the TTL being traced.
+    Current,
+    /// A probe of this trace sent with another TTL.
+    Stale,
+    /// A probe of another trace or process.
+    Foreign,
+}
+
+/// Returns the record of the probe a reply answers, `None` for replies without a key.
+///
+/// A quote not sent from `source` crossed an address translator, which may have
+/// rewritten the source port or identifier of the key as well.
+fn lookup<'r>(
+    registry: &'r ProbeRegistry,
+    key: Option<ProbeKey>,
+    quoted: Option<&[u8]>,
+    source: IpAddr,
+) -> Option<Option<&'r ProbeRecord>> {
+    let key = key?;
+    let translated = quoted
+        .and_then(reply::quoted_source)
+        .is_some_and(|addr| addr != source);
+    Some(if translated {
+        registry.get_translated(&key)
+    } else {
+        registry.get(&key)
+    })
+}
+
+/// Attributes a reply by the record `lookup` found for it.
+///
+/// Replies without a key can not be told apart and are taken for the current probe.
+fn attribute(found: Option<Option<&ProbeRecord>>, ttl: u8) -> Attribution {
+    match found {
+        None => Attribution::Current,
+        Some(Some(record)) if record.ttl == ttl => Attribution::Current,
+        Some(Some(_)) => Attribution::Stale,
+        Some(None) => Attribution::Foreign,
+    }
+}
+
+/// Compares the probe quoted in an error message with the one sent.
+fn mangling_of(
+    registry: &ProbeRegistry,
+    key: Option<ProbeKey>,
+    quoted: Option<&[u8]>,
+    source: IpAddr,
+) -> Option<Vec<MangledField>> {
+    let record = lookup(registry, key, quoted, source)??;
+    reply::mangled_fields(&record.sent, quoted?)
+}
+
+fn icmp_checksum(packet: &echo_request::MutableEchoRequestPacket) -> u16be {
+    util::checksum(packet.packet(), 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        packet_port, packet_ttl, test_net_v4, MockClock, SimulatedBackend, SIMULATED_TIMESTAMP,
+    };
+    use std::collections::BTreeSet;
+    use std::sync::atomic::AtomicUsize;
+
+    fn simulated_path(hops: u8) -> SimulatedBackend {
+        SimulatedBackend::new(
+            (1..=hops).map(|n| Some(test_net_v4(n))).collect(),
+            test_net_v4(100),
+        )
+    }
+
+    /// Returns a trace route to `address` trying each TTL once, as the sweeps run it.
+    fn sweep_trace_route(address: IpAddr) -> TraceRoute {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        TraceRoute::with_config(address, config).unwrap().0
+    }
+
+    /// Builds an echo reply of another process.
+    fn foreign_echo(sequence: u8) -> Vec<u8> {
+        let mut message = vec![0, 0, 0, 0, 0x12, 0x34, 0, sequence];
+        let checksum = pnet::util::checksum(&message, 1);
+        message[2..4].copy_from_slice(&checksum.to_be_bytes());
+        message
+    }
+
+    #[test]
+    fn creating_new_tracer() {
+        let (_, _) = TraceRoute::new(
+            Some(128),
+            Some(12),
+            None,
+            None,
+            None,
+            None,
+            IpAddr::from([127, 0, 0, 1]),
+            None,
+        )
+        .unwrap();
+    }
+    #[test]
+    fn settings_of_new_are_kept() {
+        let (trace_route, _) = TraceRoute::new(
+            Some(20),
+            Some(2),
+            Some(3),
+            Some(500),
+            Some(4000),
+            Some(100),
+            IpAddr::from([93, 184, 216, 34]),
+            Some(TraceRouteProtocol::Icmp),
+        )
+        .unwrap();
+        assert_eq!((trace_route.max_ttl(), trace_route.begin_ttl()), (20, 2));
+        assert_eq!((trace_route.max_tries(), trace_route.timeout()), (3, 500));
+        assert_eq!((trace_route.port(), trace_route.size()), (4000, 100));
+        assert_eq!(trace_route.protocol(), TraceRouteProtocol::Icmp);
+    }
+
+    #[test]
+    #[should_panic]
+    fn creating_bad_tracer() {
+        let (_, _) = TraceRoute::new(
+            None,
+            Some(128),
+            None,
+            None,
+            None,
+            None,
+            IpAddr::from([127, 0, 0, 1]),
+            None,
+        )
+        .unwrap();
+    }
+    #[test]
+    fn run_fails_fast_without_privileges() {
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        let (trace_route, _) = TraceRoute::new(
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            IpAddr::from([127, 0, 0, 1]),
+            None,
+        )
+        .unwrap();
+        match trace_route.run_trace_route() {
+            Err(TraceRouteError::PermissionDenied) | Err(TraceRouteError::NoInterface) => {}
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("tracing started without privileges"),
+        }
+    }
+    #[test]
+    #[ignore]
+    fn run_with_privileges() {
+        let (trace_route, receiver) = TraceRoute::new(
+            Some(3),
+            None,
+            Some(1),
+            None,
+            None,
+            None,
+            IpAddr::from([8, 8, 8, 8]),
+            None,
+        )
+        .unwrap();
+        let handle = trace_route.run_trace_route().unwrap();
+        let hop = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(hop.hop_count, 1);
+        handle.join().unwrap();
+    }
+    #[test]
+    fn worker_stops_when_receiver_dropped_before_start() {
+        let (trace_route, receiver) = TraceRoute::new(
+            None,
+            None,
+            Some(1),
+            Some(10),
+            None,
+            None,
+            test_net_v4(100),
+            Some(TraceRouteProtocol::Icmp),
+        )
+        .unwrap();
+        drop(receiver);
+        let backend = simulated_path(10);
+        let handle = trace_route
+            .run_with_backend(backend.clone(), test_net_v4(254))
+            .unwrap();
+        assert!(handle.join().is_ok());
+        assert_eq!(backend.probes_sent(), 1);
+    }
+    #[test]
+    fn hops_are_iterated_until_the_last() {
+        let (trace_route, receiver) = TraceRoute::new(
+            None,
+            None,
+            Some(1),
+            Some(10),
+            None,
+            None,
+            test_net_v4(100),
+            Some(TraceRouteProtocol::Icmp),
+        )
+        .unwrap();
+        let handle = trace_route
+            .run_with_backend(simulated_path(3), test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = handle
+            .hops(receiver)
+            .with_timeout(Duration::from_secs(5))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(hops.len(), 4);
+        assert!(hops[3].is_last);
+        assert_eq!(hops[3].addr, Some(test_net_v4(100)));
+        assert!(handle.join().is_ok());
+    }
+
+    #[test]
+    fn report_collects_the_hops_of_a_trace() {
+        let config = TraceRouteConfig {
+            size: 40,
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(simulated_path(2), test_net_v4(254))
+            .unwrap();
+        let report = handle.report(receiver).unwrap();
+        assert_eq!(report.hops.len(), 3);
+        assert_eq!(report.metadata.size, 40);
+        assert!(report.ended_at >= report.metadata.started_at);
+        assert!(report
+            .to_atlas_json()
+            .contains("{\"hop\":3,\"result\":[{\"from\":\"192.0.2.100\""));
+    }
+
+    /// Backend whose sends fail with `send`, if set, and which never hears back.
+    struct Stalled {
+        send: Option<io::ErrorKind>,
+    }
+
+    impl ProbeBackend for Stalled {
+        fn send_to(&mut self, packet: &[u8], _destination: IpAddr) -> io::Result<usize> {
+            match self.send {
+                Some(kind) => Err(io::Error::from(kind)),
+                None => Ok(packet.len()),
+            }
+        }
+
+        fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+            thread::sleep(timeout);
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn hops_end_at_the_error_of_the_worker() {
+        let (trace_route, receiver) = TraceRoute::new(
+            None,
+            None,
+            Some(1),
+            Some(10),
+            None,
+            None,
+            test_net_v4(100),
+            Some(TraceRouteProtocol::Icmp),
+        )
+        .unwrap();
+        let backend = Stalled {
+            send: Some(io::ErrorKind::NetworkUnreachable),
+        };
+        let handle = trace_route
+            .run_with_backend(backend, test_net_v4(254))
+            .unwrap();
+        let mut hops = handle.hops(receiver).with_timeout(Duration::from_secs(5));
+        match hops.next() {
+            Some(Err(TraceRouteError::Send { kind, .. })) => {
+                assert_eq!(kind, io::ErrorKind::NetworkUnreachable)
+            }
+            hop => panic!("{:?}", hop),
+        }
+        assert!(hops.next().is_none());
+        assert!(handle.join().is_err());
+    }
+
+    #[test]
+    fn hops_time_out_when_the_worker_stalls() {
+        let (trace_route, receiver) = TraceRoute::new(
+            Some(2),
+            None,
+            Some(1),
+            Some(1000),
+            None,
+            None,
+            test_net_v4(100),
+            Some(TraceRouteProtocol::Icmp),
+        )
+        .unwrap();
+        let backend = Stalled { send: None };
+        let handle = trace_route
+            .run_with_backend(backend, test_net_v4(254))
+            .unwrap();
+        let mut hops = handle
+            .hops(receiver)
+            .with_timeout(Duration::from_millis(50));
+        assert!(matches!(
+            hops.next(),
+            Some(Err(TraceRouteError::HopTimeout))
+        ));
+        assert!(hops.next().is_none());
+    }
+
+    /// Backend that holds every send until the test lets it go.
+    struct Gated {
+        backend: SimulatedBackend,
+        sending: Sender<()>,
+        go: Receiver<()>,
+    }
+
+    impl ProbeBackend for Gated {
+        fn send_to(&mut self, packet: &[u8], destination: IpAddr) -> io::Result<usize> {
+            let _ = self.sending.send(());
+            let _ = self.go.recv();
+            self.backend.send_to(packet, destination)
+        }
+
+        fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+            self.backend.recv_timeout(timeout)
+        }
+    }
+
+    #[test]
+    fn status_follows_the_worker() {
+        let config = TraceRouteConfig {
+            max_tries: 2,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let backend = SimulatedBackend::new(vec![Some(test_net_v4(1)), None], test_net_v4(100))
+            .with_send_errors(vec![libc::ENOBUFS]);
+        let (sending_tx, sending) = channel();
+        let (go, go_rx) = channel();
+        let gated = Gated {
+            backend,
+            sending: sending_tx,
+            go: go_rx,
+        };
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(gated, test_net_v4(254))
+            .unwrap();
+        let mut step = || {
+            sending.recv().unwrap();
+            let status = handle.status();
+            go.send(()).unwrap();
+            (
+                status.current_ttl,
+                status.attempts_on_current,
+                status.hops_found,
+                status.probes_sent,
+                status.state,
+            )
+        };
+        // The first send fails for now and is tried again after a backoff.
+        assert_eq!(step(), (None, 0, 0, 0, TraceState::Running));
+        assert_eq!(step(), (None, 0, 0, 0, TraceState::Paused));
+        assert_eq!(step(), (Some(1), 2, 1, 1, TraceState::Running));
+        assert_eq!(step(), (Some(2), 1, 1, 2, TraceState::Running));
+        assert_eq!(step(), (Some(2), 2, 2, 3, TraceState::Running));
+        let hops: Vec<HopFound> = receiver.iter().take(3).collect();
+        assert!(hops[2].is_last);
+        while !handle.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let status = handle.status();
+        assert_eq!((status.hops_found, status.probes_sent), (3, 4));
+        assert_eq!(status.state, TraceState::Completed);
+    }
+
+    /// Traces `simulated_path(3)` with its first sends failing with `errors`.
+    fn trace_with_send_errors(
+        max_tries: u16,
+        errors: Vec<i32>,
+    ) -> (
+        Vec<Result<HopFound, TraceRouteError>>,
+        SimulatedBackend,
+        TraceHandle,
+    ) {
+        let config = TraceRouteConfig {
+            max_tries,
+            timeout: Some(Duration::from_millis(10)),
+            protocol: TraceRouteProtocol::Icmp,
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let backend = simulated_path(3).with_send_errors(errors);
+        let handle = trace_route
+            .run_with_backend(backend.clone(), test_net_v4(254))
+            .unwrap();
+        let hops = handle
+            .hops(receiver)
+            .with_timeout(Duration::from_secs(5))
+            .collect();
+        (hops, backend, handle)
+    }
+
+    #[test]
+    fn transient_send_errors_are_retried() {
+        for &errno in &[libc::ENOBUFS, libc::EPERM, libc::EAGAIN, libc::EINTR] {
+            let (hops, backend, _) = trace_with_send_errors(4, vec![errno, errno]);
+            let hops: Vec<HopFound> = hops.into_iter().collect::<Result<_, _>>().unwrap();
+            assert_eq!(hops.len(), 4, "errno {}", errno);
+            assert_eq!(hops[0].addr, Some(test_net_v4(1)));
+            assert_eq!((hops[0].tries, hops[0].send_retries), (2, 2));
+            assert!(hops[1..].iter().all(|hop| hop.send_retries == 0));
+            assert_eq!(hops[3].completion, Some(CompletionReason::Reached));
+            assert_eq!(backend.probes_sent(), 4);
+        }
+    }
+
+    #[test]
+    fn hops_that_could_not_be_sent_are_given_up() {
+        let (hops, backend, _) = trace_with_send_errors(3, vec![libc::ENOBUFS; 3]);
+        let hops: Vec<HopFound> = hops.into_iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(hops.len(), 4);
+        assert_eq!(hops[0].addr, None);
+        assert_eq!((hops[0].tries, hops[0].send_retries), (3, 3));
+        assert_eq!(hops[3].completion, Some(CompletionReason::Reached));
+        assert_eq!(backend.probes_sent(), 3);
+    }
+
+    #[test]
+    fn permanent_send_errors_fail_the_trace() {
+        let (hops, backend, handle) =
+            trace_with_send_errors(4, vec![libc::ENETUNREACH, libc::ENOBUFS]);
+        match &hops[..] {
+            [Err(TraceRouteError::Send { kind, .. })] => {
+                assert_eq!(*kind, io::ErrorKind::NetworkUnreachable)
+            }
+            hops => panic!("{:?}", hops),
+        }
+        assert_eq!(backend.probes_sent(), 0);
+        assert_eq!(handle.status().state, TraceState::Failed);
+        assert!(handle.join().is_ok());
+    }
+
+    #[test]
+    fn cancelling_interrupts_a_wait_without_timeout() {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: None,
+            protocol: TraceRouteProtocol::Icmp,
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(Stalled { send: None }, test_net_v4(254))
+            .unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert!(!handle.is_finished());
+        let cancelled_at = Instant::now();
+        handle.cancel();
+        let hops = handle.hops(receiver).collect::<Vec<_>>();
+        assert!(cancelled_at.elapsed() < Duration::from_secs(1));
+        assert!(matches!(hops[..], [Err(TraceRouteError::WorkerStopped)]));
+        assert!(handle.join().is_ok());
+    }
+    #[test]
+    fn worker_stops_when_receiver_dropped_mid_trace() {
+        let (trace_route, receiver) = TraceRoute::new(
+            None,
+            None,
+            Some(1),
+            Some(100),
+            None,
+            None,
+            test_net_v4(100),
+            Some(TraceRouteProtocol::Udp),
+        )
+        .unwrap();
+        let backend = simulated_path(20).with_reply_delay(Duration::from_millis(20));
+        let handle = trace_route
+            .run_with_backend(backend.clone(), test_net_v4(254))
+            .unwrap();
+        for ttl in 1..=2 {
+            assert_eq!(receiver.recv().unwrap().hop_count, ttl);
+        }
+        drop(receiver);
+        assert!(handle.join().is_ok());
+        assert!(backend.probes_sent() <= 4, "{}", backend.probes_sent());
+    }
+    #[test]
+    fn mapped_destination_runs_on_v4() {
+        let mapped: IpAddr = "::ffff:192.0.2.100".parse().unwrap();
+        let (trace_route, receiver) =
+            TraceRoute::new(Some(3), None, Some(1), Some(10), None, None, mapped, None).unwrap();
+        assert_eq!(trace_route.address, test_net_v4(100));
+        let handle = trace_route
+            .run_with_backend(simulated_path(2), test_net_v4(254))
+            .unwrap();
+        assert!(handle.metadata().source.is_ipv4());
+        assert_eq!(handle.metadata().destination, test_net_v4(100));
+        let last = receiver.iter().find(|hop| hop.is_last).unwrap();
+        assert_eq!(last.addr, Some(test_net_v4(100)));
+
+        let config = TraceRouteConfig {
+            unmap_ipv4: false,
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, _) = TraceRoute::with_config(mapped, config).unwrap();
+        assert_eq!(trace_route.address, mapped);
+    }
+    #[test]
+    fn builders_refuse_to_truncate_lengths() {
+        let src = Ipv4Addr::new(192, 0, 2, 254);
+        let probe = build_udp_v4(test_net_v4(1), 1000, 33434, 1, src, random()).unwrap();
+        assert_eq!(u16::from_be_bytes([probe[2], probe[3]]), 1020);
+        let too_large = build_udp_v4(test_net_v4(1), 70000, 33434, 1, src, random());
+        assert_eq!(
+            too_large,
+            Err(TraceRouteError::SizeTooLarge {
+                max: config::MAX_PROBE_SIZE
+            })
+        );
+        assert!(build_udp_v4(test_net_v4(1), 65516, 33434, 1, src, random()).is_err());
+        assert!(build_udp_v4(test_net_v4(1), 65515, 33434, 1, src, random()).is_ok());
+    }
+    #[test]
+    fn icmpv6_probe_carries_identifier_and_sequence() {
+        let src = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 254);
+        let dst = testing::test_net_v6(100);
+        let probe = build_icmp_v6(dst, 64, 5, src, 0xbeef, 42).unwrap();
+        let echo = &probe[40..];
+        assert_eq!(echo[0], 128);
+        assert_eq!(&echo[4..8], &[0xbe, 0xef, 0, 42]);
+        let packet = icmpv6::Icmpv6Packet::new(echo).unwrap();
+        assert_eq!(
+            icmpv6::checksum(&packet, &src, &dst.to_string().parse().unwrap()),
+            packet.get_checksum()
+        );
+    }
+    #[test]
+    fn icmpv6_replies_are_matched_by_identifier() {
+        let src = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 254);
+        let dst = testing::test_net_v6(100);
+        let probe = build_icmp_v6(dst, 64, 5, src, 0xbeef, 42).unwrap();
+        let mut registry = ProbeRegistry::default();
+        registry.insert(
+            ProbeKey::Echo {
+                identifier: 0xbeef,
+                sequence: 42,
+            },
+            ProbeRecord {
+                ttl: 5,
+                sent_at: Instant::now(),
+                sent: reply::snapshot(&probe),
+            },
+        );
+        let source = IpAddr::V6(src);
+        let found = |message: &[u8]| {
+            lookup(
+                &registry,
+                reply::probe_key_v6(message),
+                reply::quoted_v6(message),
+                source,
+            )
+        };
+        let echo = testing::echo_reply(&probe, dst);
+        assert_eq!(attribute(found(&echo), 5), Attribution::Current);
+        assert_eq!(attribute(found(&echo), 6), Attribution::Stale);
+        let foreign = build_icmp_v6(dst, 64, 5, src, 0xcafe, 42).unwrap();
+        let hop = testing::test_net_v6(1);
+        assert_eq!(
+            attribute(found(&testing::time_exceeded(&foreign, hop)), 5),
+            Attribution::Foreign
+        );
+        assert_eq!(attribute(None, 6), Attribution::Current);
+    }
+    #[test]
+    fn translated_quotes_are_matched_and_reported() {
+        let src = Ipv4Addr::new(192, 168, 1, 2);
+        let dst = test_net_v4(100);
+        let probe = build_icmp_v4(dst, 64, 3, src, 0xbeef, 7).unwrap();
+        let mut registry = ProbeRegistry::default();
+        registry.insert(
+            reply::sent_key(&probe).unwrap(),
+            ProbeRecord {
+                ttl: 3,
+                sent_at: Instant::now(),
+                sent: reply::snapshot(&probe),
+            },
+        );
+        let mut translated = probe.clone();
+        translated[12..16].copy_from_slice(&[203, 0, 113, 9]);
+        translated[24..26].copy_from_slice(&[0x12, 0x34]);
+        translated[22] ^= 0xff;
+        let message = testing::time_exceeded(&translated, test_net_v4(3));
+        let (key, quoted) = (reply::probe_key_v4(&message), reply::quoted_v4(&message));
+        let source = IpAddr::V4(src);
+        assert_eq!(
+            attribute(lookup(&registry, key, quoted, source), 3),
+            Attribution::Current
+        );
+        assert_eq!(
+            mangling_of(&registry, key, quoted, source),
+            Some(vec![
+                MangledField::SourceAddr,
+                MangledField::SourcePort,
+                MangledField::Checksum
+            ])
+        );
+        let untouched = testing::time_exceeded(&probe, test_net_v4(3));
+        assert_eq!(
+            mangling_of(
+                &registry,
+                reply::probe_key_v4(&untouched),
+                reply::quoted_v4(&untouched),
+                source
+            ),
+            Some(Vec::new())
+        );
+        let other = build_icmp_v4(dst, 64, 3, src, 0xcafe, 7).unwrap();
+        let message = testing::time_exceeded(&other, test_net_v4(3));
+        let found = lookup(
+            &registry,
+            reply::probe_key_v4(&message),
+            reply::quoted_v4(&message),
+            source,
+        );
+        assert_eq!(attribute(found, 3), Attribution::Foreign);
+    }
+    #[test]
+    fn foreign_echo_replies_are_discarded() {
+        let (trace_route, receiver) = TraceRoute::new(
+            None,
+            None,
+            Some(1),
+            Some(10),
+            None,
+            None,
+            test_net_v4(100),
+            Some(TraceRouteProtocol::Icmp),
+        )
+        .unwrap();
+        let backend = simulated_path(3);
+        let source = Ipv4Addr::new(192, 0, 2, 254);
+        let foreign_identifier = next_identifier();
+        let foreign =
+            build_icmp_v4(test_net_v4(100), 64, 1, source, foreign_identifier, 1).unwrap();
+        backend.inject(
+            testing::echo_reply(&foreign, test_net_v4(100)),
+            test_net_v4(100),
+        );
+        let handle = trace_route
+            .run_with_backend(backend.clone(), IpAddr::V4(source))
+            .unwrap();
+        let identifier = handle.metadata().identifier;
+        assert_ne!(identifier, foreign_identifier);
+        let hops: Vec<HopFound> = receiver.iter().take(4).collect();
+        assert_eq!(hops.len(), 4);
+        assert_eq!(hops[0].addr, Some(test_net_v4(1)));
+        assert_eq!(hops[3].addr, Some(test_net_v4(100)));
+        assert!(hops[3].is_last);
+        for probe in backend.sent_packets() {
+            assert_eq!(&probe[24..26], &identifier.to_be_bytes());
+        }
+    }
+    fn silent_udp_trace(backend: SimulatedBackend, confirm: bool) -> HopFound {
+        let config = TraceRouteConfig {
+            max_ttl: 4,
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            confirm_silent_destination: confirm,
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        trace_route
+            .run_with_backend(backend, test_net_v4(254))
+            .unwrap();
+        receiver.iter().find(|hop| hop.is_last).unwrap()
+    }
+    #[test]
+    fn timeouts_are_told_from_the_end_of_a_trace() {
+        let config = TraceRouteConfig {
+            max_ttl: 5,
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            protocol: TraceRouteProtocol::Icmp,
+            ..TraceRouteConfig::default()
+        };
+        let path = vec![Some(test_net_v4(1)), None, Some(test_net_v4(3))];
+        let backend =
+            SimulatedBackend::new(path, test_net_v4(100)).with_destination_filter(false, true);
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(backend, test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = handle.hops(receiver).collect::<Result<_, _>>().unwrap();
+        let timeouts: Vec<u8> = hops
+            .iter()
+            .filter(|hop| hop.is_timeout())
+            .map(|hop| hop.hop_count)
+            .collect();
+        assert_eq!(timeouts, [2, 4, 5]);
+        let (end, path) = hops.split_last().unwrap();
+        assert!(path.iter().all(|hop| !hop.is_end_of_trace()));
+        assert!(end.is_end_of_trace() && !end.is_timeout());
+        assert_eq!(end.hop_count, 6);
+        assert_eq!(end.completion, Some(CompletionReason::NotReached));
+
+        let last = silent_udp_trace(simulated_path(2), true);
+        assert!(!last.is_end_of_trace() && !last.is_timeout());
+    }
+    #[test]
+    fn silent_destination_confirmed_by_echo() {
+        let backend = simulated_path(2).with_destination_filter(true, false);
+        let last = silent_udp_trace(backend.clone(), true);
+        assert_eq!(last.addr, None);
+        assert_eq!(last.completion, Some(CompletionReason::ReachedButFiltered));
+        assert_eq!(backend.probes_sent(), 5);
+    }
+    #[test]
+    fn silent_destination_stays_unreached() {
+        let backend = simulated_path(2).with_destination_filter(true, true);
+        let last = silent_udp_trace(backend.clone(), true);
+        assert_eq!(
+            last.completion,
+            Some(CompletionReason::DestinationUnresponsive)
+        );
+        assert_eq!(backend.probes_sent(), 6);
+
+        let backend = simulated_path(2).with_destination_filter(true, false);
+        let last = silent_udp_trace(backend.clone(), false);
+        assert_eq!(last.completion, Some(CompletionReason::NotReached));
+        assert_eq!(backend.probes_sent(), 4);
+    }
+    #[test]
+    fn silent_destination_confirmed_by_tcp() {
+        let config = TraceRouteConfig {
+            max_ttl: 4,
+            max_tries: 1,
+            timeout: Some(Duration::from_secs(5)),
+            confirm_silent_destination: true,
+            confirm_probes: 1,
+            confirm_tcp_port: Some(443),
+            confirm_timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let trace = |backend: SimulatedBackend| {
+            let (trace_route, receiver) =
+                TraceRoute::with_config(test_net_v4(100), config.clone()).unwrap();
+            trace_route
+                .run_with_backend(backend, test_net_v4(254))
                 .unwrap();
-                break;
+            receiver.iter().find(|hop| hop.is_last).unwrap()
+        };
+        let clock = MockClock::new();
+        let backend = simulated_path(2)
+            .with_clock(clock.clone())
+            .with_destination_filter(true, true)
+            .with_tcp_port(443);
+        let last = trace(backend.clone());
+        assert_eq!(last.completion, Some(CompletionReason::ReachedButFiltered));
+        let syn = backend.sent_packets().pop().unwrap();
+        assert_eq!(syn[9], 6);
+        assert_eq!(&syn[22..24], &443u16.to_be_bytes());
+
+        // Closed ports answer with a reset, which tells the destination is up too.
+        let backend = simulated_path(2)
+            .with_clock(MockClock::new())
+            .with_destination_filter(true, true)
+            .with_tcp_port(80);
+        let last = trace(backend);
+        assert_eq!(last.completion, Some(CompletionReason::ReachedButFiltered));
+
+        // A silent destination is given up after the confirmation timeout.
+        let clock = MockClock::new();
+        let start = clock.now();
+        let backend = simulated_path(2)
+            .with_clock(clock.clone())
+            .with_destination_filter(true, true);
+        let last = trace(backend.clone());
+        assert_eq!(
+            last.completion,
+            Some(CompletionReason::DestinationUnresponsive)
+        );
+        assert_eq!(backend.probes_sent(), 6);
+        assert!(clock.now() - start < Duration::from_secs(11));
+    }
+    #[test]
+    fn reached_destination_completes() {
+        let last = silent_udp_trace(simulated_path(2), true);
+        assert_eq!(last.addr, Some(test_net_v4(100)));
+        assert_eq!(last.completion, Some(CompletionReason::Reached));
+    }
+    #[test]
+    fn first_hop_answers() {
+        let (trace_route, _) =
+            TraceRoute::with_config(test_net_v4(100), TraceRouteConfig::default()).unwrap();
+        let backend = simulated_path(3);
+        let hop = trace_route.first_hop_with_backend(backend.clone(), test_net_v4(254));
+        assert_eq!(hop, Ok(test_net_v4(1)));
+        assert_eq!(backend.probes_sent(), 1);
+        assert_eq!(testing::packet_ttl(&backend.sent_packets()[0]), Some(1));
+
+        let backend = SimulatedBackend::new(Vec::new(), test_net_v4(100));
+        let hop = trace_route.first_hop_with_backend(backend, test_net_v4(254));
+        assert_eq!(hop, Ok(test_net_v4(100)));
+    }
+    #[test]
+    fn silent_first_hop() {
+        let config = TraceRouteConfig {
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, _) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let backend = SimulatedBackend::new(vec![None], test_net_v4(100));
+        let hop = trace_route.first_hop_with_backend(backend.clone(), test_net_v4(254));
+        assert_eq!(hop, Err(TraceRouteError::NoFirstHop));
+        assert_eq!(backend.probes_sent(), 2);
+    }
+    #[test]
+    fn dccp_request_matches_capture() {
+        let src = Ipv4Addr::new(192, 0, 2, 254);
+        let probe = build_dccp_v4(
+            test_net_v4(100),
+            33435,
+            7,
+            src,
+            0xa000,
+            dccp_sequence(0x1234, 7),
+        )
+        .unwrap();
+        assert_eq!(probe.len(), 40);
+        assert_eq!(probe[9], 33);
+        assert_eq!(probe[8], 7);
+        assert_eq!(
+            &probe[20..],
+            &[
+                0xa0, 0x00, 0x82, 0x9b, 0x05, 0x00, 0x69, 0xba, 0x01, 0x00, 0x12, 0x34, 0x00, 0x00,
+                0x00, 0x07, 0x74, 0x72, 0x61, 0x63
+            ]
+        );
+
+        let src = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 254);
+        let probe = build_dccp_v6(
+            testing::test_net_v6(100),
+            33435,
+            7,
+            src,
+            0xa000,
+            dccp_sequence(0x1234, 7),
+        )
+        .unwrap();
+        assert_eq!(probe[6], 33);
+        assert_eq!(probe[7], 7);
+        assert_eq!(&probe[46..48], &[0x92, 0x49]);
+    }
+    fn dccp_trace(backend: SimulatedBackend) -> Vec<HopFound> {
+        let config = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Dccp,
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        trace_route
+            .run_with_backend(backend, test_net_v4(254))
+            .unwrap();
+        receiver.iter().take(3).collect()
+    }
+    #[test]
+    fn dccp_trace_ends_on_reset_or_unreachable() {
+        for backend in &[simulated_path(2).with_dccp_reset(), simulated_path(2)] {
+            let hops = dccp_trace(backend.clone());
+            assert_eq!(hops[0].addr, Some(test_net_v4(1)));
+            assert_eq!(hops[1].addr, Some(test_net_v4(2)));
+            assert_eq!(hops[2].addr, Some(test_net_v4(100)));
+            assert_eq!(hops[2].completion, Some(CompletionReason::Reached));
+            assert_eq!(backend.probes_sent(), 3);
+        }
+        let silent = simulated_path(2).with_destination_filter(true, false);
+        let hops = dccp_trace(silent);
+        assert_eq!(hops[2].addr, None);
+    }
+    #[test]
+    fn raw_probes_carry_protocol_and_key() {
+        let probe = build_raw_v4(
+            test_net_v4(100),
+            47,
+            4,
+            9,
+            Ipv4Addr::new(192, 0, 2, 254),
+            0x1234,
+            7,
+        )
+        .unwrap();
+        assert_eq!(probe.len(), 32);
+        assert_eq!(probe[8], 9);
+        assert_eq!(probe[9], 47);
+        assert_eq!(&probe[20..24], &[0x12, 0x34, 0x00, 0x07]);
+
+        let v6 = "2001:db8::64".parse().unwrap();
+        let source = "2001:db8::fe".parse().unwrap();
+        let probe = build_raw_v6(v6, 132, 64, 9, source, 0x1234, 7).unwrap();
+        assert_eq!(probe.len(), 104);
+        assert_eq!(probe[6], 132);
+        assert_eq!(&probe[40..44], &[0x12, 0x34, 0x00, 0x07]);
+    }
+    fn raw_trace(backend: SimulatedBackend) -> HopFound {
+        let config = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Raw(47),
+            max_tries: 1,
+            max_ttl: 3,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        trace_route
+            .run_with_backend(backend, test_net_v4(254))
+            .unwrap();
+        receiver.iter().find(|hop| hop.is_last).unwrap()
+    }
+    #[test]
+    fn raw_trace_ends_on_protocol_unreachable() {
+        let last = raw_trace(simulated_path(2));
+        assert_eq!(last.addr, Some(test_net_v4(100)));
+        assert_eq!(last.completion, Some(CompletionReason::Reached));
+
+        let last = raw_trace(simulated_path(2).with_destination_filter(true, false));
+        assert_eq!(last.addr, None);
+        assert_eq!(last.completion, Some(CompletionReason::NoTerminalSignal));
+    }
+    #[test]
+    fn lsrr_option_layout() {
+        let destination = Ipv4Addr::new(198, 51, 100, 7);
+        let gateways = [
+            Ipv4Addr::new(192, 0, 2, 1),
+            Ipv4Addr::new(192, 0, 2, 2),
+            Ipv4Addr::new(192, 0, 2, 3),
+        ];
+        assert_eq!(
+            lsrr_option(&gateways[..1], destination),
+            vec![131, 7, 4, 198, 51, 100, 7, 0]
+        );
+        assert_eq!(
+            lsrr_option(&gateways, destination),
+            vec![131, 15, 4, 192, 0, 2, 2, 192, 0, 2, 3, 198, 51, 100, 7, 0]
+        );
+    }
+    #[test]
+    fn source_routed_probe_headers() {
+        let source = Ipv4Addr::new(192, 0, 2, 254);
+        let destination = IpAddr::from([198, 51, 100, 7]);
+        let gateways = [
+            Ipv4Addr::new(192, 0, 2, 1),
+            Ipv4Addr::new(192, 0, 2, 2),
+            Ipv4Addr::new(192, 0, 2, 3),
+        ];
+        for (count, header_len) in &[(1, 28), (3, 36)] {
+            for probe in &[
+                build_udp_v4(destination, 64, 33434, 5, source, random()).unwrap(),
+                build_icmp_v4(destination, 64, 5, source, 0x1234, 7).unwrap(),
+            ] {
+                let routed = source_route_v4(probe.clone(), &gateways[..*count]).unwrap();
+                let packet = ipv4::Ipv4Packet::new(&routed).unwrap();
+                assert_eq!(usize::from(packet.get_header_length()) * 4, *header_len);
+                assert_eq!(usize::from(packet.get_total_length()), routed.len());
+                assert_eq!(routed.len(), probe.len() + header_len - 20);
+                assert_eq!(packet.get_destination(), gateways[0]);
+                assert_eq!(packet.get_checksum(), ipv4::checksum(&packet));
+                assert_eq!(&routed[*header_len..], &probe[20..]);
+                assert_eq!(&routed[header_len - 5..header_len - 1], &[198, 51, 100, 7]);
             }
-            match trace_route_protocol {
-                TraceRouteProtocol::Udp => {
-                    match build_udp_send_v6(
-                        &mut ipv6_tx,
-                        ip,
-                        packet_size,
-                        port + i as u16,
-                        i,
-                        self_ip,
-                    ) {
-                        Ok(_) => timer = Instant::now(),
-                        Err(e) => {
-                            panic!("Could not send packet, make sure this program has needed privilages, Error<{}>", e.to_string());
-                        }
+        }
+        let probe = build_udp_v4(destination, 64, 33434, 5, source, random()).unwrap();
+        assert_eq!(source_route_v4(probe.clone(), &[]).unwrap(), probe);
+    }
+    #[test]
+    fn silent_source_routed_trace_hints_at_filtering() {
+        let config = TraceRouteConfig {
+            max_ttl: 3,
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            gateways: vec![Ipv4Addr::new(192, 0, 2, 1)],
+            ..TraceRouteConfig::default()
+        };
+        let backend = simulated_path(2).with_destination_filter(true, false);
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        trace_route
+            .run_with_backend(backend, test_net_v4(254))
+            .unwrap();
+        let last = receiver.iter().find(|hop| hop.is_last).unwrap();
+        assert_eq!(
+            last.completion,
+            Some(CompletionReason::NotReachedSourceRouted)
+        );
+    }
+    #[test]
+    fn record_route_probe_headers() {
+        let source = Ipv4Addr::new(192, 0, 2, 254);
+        let destination = IpAddr::from([198, 51, 100, 7]);
+        let probe = build_icmp_v4(destination, 64, 5, source, 0x1234, 7).unwrap();
+        let mut config = TraceRouteConfig {
+            record_route: true,
+            ..TraceRouteConfig::default()
+        };
+        let recorded = add_options_v4(probe.clone(), &config).unwrap();
+        let packet = ipv4::Ipv4Packet::new(&recorded).unwrap();
+        assert_eq!(packet.get_header_length(), 15);
+        assert_eq!(usize::from(packet.get_total_length()), recorded.len());
+        assert_eq!(packet.get_checksum(), ipv4::checksum(&packet));
+        assert_eq!(&recorded[20..23], &[7, 39, 4]);
+        assert_eq!(recorded[59], 1);
+        assert_eq!(&recorded[60..], &probe[20..]);
+
+        config.gateways = vec![Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)];
+        let routed = add_options_v4(probe.clone(), &config).unwrap();
+        let packet = ipv4::Ipv4Packet::new(&routed).unwrap();
+        assert_eq!(packet.get_header_length(), 15);
+        assert_eq!(packet.get_destination(), config.gateways[0]);
+        assert_eq!(&routed[20..23], &[7, 27, 4]);
+        assert_eq!(&routed[48..51], &[131, 11, 4]);
+        assert_eq!(reply::recorded_route(&routed[20..60]), Some(Vec::new()));
+    }
+    #[test]
+    fn record_route_is_reported_per_hop() {
+        let config = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Icmp,
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            record_route: true,
+            ..TraceRouteConfig::default()
+        };
+        let backend = SimulatedBackend::new(
+            vec![Some(test_net_v4(1)), None, Some(test_net_v4(3))],
+            test_net_v4(100),
+        );
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        trace_route
+            .run_with_backend(backend, test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().take(4).collect();
+        let v4 = |n| Ipv4Addr::new(192, 0, 2, n);
+        assert!(hops[0].recorded_route.is_empty());
+        assert!(hops[1].recorded_route.is_empty());
+        assert_eq!(hops[2].recorded_route, vec![v4(1)]);
+        assert_eq!(hops[3].recorded_route, vec![v4(1), v4(3), v4(100)]);
+        assert!(hops[3].is_last);
+    }
+    #[test]
+    fn echo_probes_follow_the_sequence_plan() {
+        let plan = SequencePlan::PerTtl {
+            start: 1000,
+            stride: 10,
+        };
+        let config = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Icmp,
+            max_tries: 2,
+            timeout: Some(Duration::from_millis(10)),
+            sequence_plan: plan,
+            ..TraceRouteConfig::default()
+        };
+        let backend = SimulatedBackend::new(
+            vec![Some(test_net_v4(1)), None, Some(test_net_v4(3))],
+            test_net_v4(100),
+        );
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        trace_route
+            .run_with_backend(backend.clone(), test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().take(4).collect();
+        let addrs: Vec<_> = hops.iter().map(|hop| hop.addr).collect();
+        let expected = [Some(1), None, Some(3), Some(100)];
+        assert_eq!(
+            addrs,
+            expected
+                .iter()
+                .map(|n| n.map(test_net_v4))
+                .collect::<Vec<_>>()
+        );
+        assert!(hops[3].is_last);
+
+        let sequences: Vec<u16> = backend
+            .sent_packets()
+            .iter()
+            .map(|probe| {
+                let echo = echo_request::EchoRequestPacket::new(&probe[20..]).unwrap();
+                echo.get_sequence_number()
+            })
+            .collect();
+        assert_eq!(sequences, vec![1011, 1021, 1022, 1031, 1041]);
+        for (hop, sequence) in [(1, 1011), (3, 1031), (4, 1041)] {
+            let token = hops[hop - 1].probe.unwrap();
+            assert_eq!(token.icmp_sequence, Some(sequence));
+            assert_eq!(plan.decode(sequence), Some((hop as u8, 1)));
+        }
+    }
+    #[test]
+    fn timestamps_are_reported_per_hop() {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            timestamps: true,
+            ..TraceRouteConfig::default()
+        };
+        let backend = simulated_path(5);
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        trace_route
+            .run_with_backend(backend.clone(), test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().take(6).collect();
+        let stamp = |n| (Ipv4Addr::new(192, 0, 2, n), SIMULATED_TIMESTAMP);
+        assert!(hops[0].timestamps.is_empty());
+        assert_eq!(hops[2].timestamps, vec![stamp(1), stamp(2)]);
+        assert_eq!(hops[5].timestamps, (1..=4).map(stamp).collect::<Vec<_>>());
+        assert!(hops[5].recorded_route.is_empty());
+
+        let probe = &backend.sent_packets()[0];
+        assert_eq!(probe[0], 0x4e);
+        assert_eq!(&probe[20..24], &[68, 36, 5, 1]);
+    }
+    #[test]
+    fn metrics_count_a_scripted_trace() {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let backend = SimulatedBackend::new(
+            vec![Some(test_net_v4(1)), None, Some(test_net_v4(3))],
+            test_net_v4(100),
+        );
+        // An echo reply of another process, dropped without using up a try.
+        backend.inject(foreign_echo(1), test_net_v4(9));
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(backend.clone(), test_net_v4(254))
+            .unwrap();
+        let last = receiver.iter().find(|hop| hop.is_last).unwrap();
+        let metrics = handle.metrics();
+        assert_eq!(last.metrics, Some(metrics));
+        handle.join().unwrap();
+
+        assert_eq!(metrics.probes_sent, 4);
+        assert_eq!(metrics.replies_matched, 3);
+        assert_eq!(metrics.foreign_replies, 1);
+        assert_eq!(metrics.timeouts, 1);
+        let sent: usize = backend.sent_packets().iter().map(Vec::len).sum();
+        assert_eq!(metrics.bytes_sent, sent as u64);
+        assert_eq!(metrics.bytes_received, 8 + 3 * (8 + 20 + 8));
+    }
+
+    #[test]
+    fn duplicate_replies_do_not_add_tries() {
+        let config = TraceRouteConfig {
+            max_tries: 2,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let backend = SimulatedBackend::new(
+            vec![Some(test_net_v4(1)), None, Some(test_net_v4(3))],
+            test_net_v4(100),
+        )
+        .with_duplicate_replies(5);
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(backend.clone(), test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().take(4).collect();
+        let metrics = handle.metrics();
+        handle.join().unwrap();
+
+        assert_eq!(hops[1].addr, None);
+        assert_eq!(hops[1].tries, 2);
+        assert_eq!(hops[2].addr, Some(test_net_v4(3)));
+        assert_eq!(hops[3].completion, Some(CompletionReason::Reached));
+        let ttls: Vec<u8> = backend
+            .sent_packets()
+            .iter()
+            .filter_map(|probe| testing::packet_ttl(probe))
+            .collect();
+        assert_eq!(ttls, vec![1, 2, 2, 3, 4]);
+        assert_eq!(metrics.duplicate_replies, 10);
+    }
+
+    /// Backend handing over the replies of a simulated path in bursts.
+    #[cfg(target_os = "linux")]
+    struct Bursty {
+        inner: SimulatedBackend,
+        batch: backend::BatchReceiver,
+        reads: Arc<AtomicUsize>,
+    }
+
+    #[cfg(target_os = "linux")]
+    impl ProbeBackend for Bursty {
+        fn send_to(&mut self, packet: &[u8], destination: IpAddr) -> io::Result<usize> {
+            self.inner.send_to(packet, destination)
+        }
+
+        fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+            let (inner, reads) = (&mut self.inner, &self.reads);
+            let reply = self.batch.next(|buffers| {
+                reads.fetch_add(1, Ordering::Relaxed);
+                let mut read = Vec::new();
+                while read.len() < buffers.len() {
+                    match inner.recv_timeout(Duration::from_millis(0))? {
+                        Some((message, from)) => read.push((message, from, None)),
+                        None => break,
                     }
                 }
-                TraceRouteProtocol::Icmp => {
-                    match build_icmp_send_v6(&mut ipv6_tx, ip, 64, i, self_ip) {
-                        Ok(_) => timer = Instant::now(),
-                        Err(e) => {
-                            panic!("Could not send packet, make sure this program has needed privilages, Error<{}>", e.to_string());
-                        }
+                Ok(read)
+            })?;
+            match reply {
+                Some((message, from, _, _)) => Ok(Some((message, from))),
+                None => {
+                    thread::sleep(timeout);
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn replies_read_in_bursts_are_all_classified() {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let reads = Arc::new(AtomicUsize::new(0));
+        let backend = Bursty {
+            inner: simulated_path(3).with_duplicate_replies(3),
+            batch: backend::BatchReceiver::new(8, 1500),
+            reads: reads.clone(),
+        };
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(backend, test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().take(4).collect();
+        let metrics = handle.metrics();
+        handle.join().unwrap();
+
+        assert!(hops.iter().all(|hop| hop.addr.is_some() && hop.tries == 0));
+        assert!(hops[3].is_last);
+        // Three routers sending four copies each and the destination one.
+        assert_eq!(metrics.replies_matched + metrics.duplicate_replies, 13);
+        assert_eq!(metrics.replies_matched, 4);
+        assert!(reads.load(Ordering::Relaxed) < 13);
+    }
+
+    #[test]
+    fn filtered_ports_fall_back_to_candidates() {
+        let config = TraceRouteConfig {
+            max_tries: 4,
+            timeout: Some(Duration::from_millis(10)),
+            port_fallback: Some(PortFallback {
+                candidates: vec![53, 123, 443, 4500],
+                trigger_after: 1,
+            }),
+            ..TraceRouteConfig::default()
+        };
+        let backend = simulated_path(7).with_port_filter(5, vec![443]);
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(backend.clone(), test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().take(8).collect();
+        handle.join().unwrap();
+
+        assert_eq!(hops[4].addr, Some(test_net_v4(5)));
+        assert_eq!(hops[4].port, Some(33439));
+        assert_eq!(hops[5].addr, Some(test_net_v4(6)));
+        assert_eq!(hops[5].port, Some(443));
+        assert_eq!(hops[5].tries, 3);
+        assert_eq!(hops[7].addr, Some(test_net_v4(100)));
+        assert_eq!(hops[7].port, Some(443));
+        assert_eq!(hops[7].completion, Some(CompletionReason::Reached));
+        let ports: Vec<u16> = backend
+            .sent_packets()
+            .iter()
+            .filter(|probe| testing::packet_ttl(probe) == Some(6))
+            .filter_map(|probe| testing::packet_port(probe))
+            .collect();
+        assert_eq!(ports, vec![33440, 53, 123, 443]);
+    }
+
+    #[test]
+    fn silent_protocols_fall_back_to_the_next() {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            protocol_fallback: Some(ProtocolFallback {
+                order: vec![TraceRouteProtocol::Dccp, TraceRouteProtocol::Icmp],
+                give_up_after_hops: 3,
+            }),
+            ..TraceRouteConfig::default()
+        };
+        let backend = simulated_path(4).with_port_filter(0, Vec::new());
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(backend.clone(), test_net_v4(254))
+            .unwrap();
+        drop(trace_route);
+        let hops: Vec<HopFound> = receiver.iter().collect();
+        handle.join().unwrap();
+
+        assert_eq!(hops.len(), 5);
+        let ttls: Vec<u8> = hops.iter().map(|hop| hop.hop_count).collect();
+        assert_eq!(ttls, vec![1, 2, 3, 4, 5]);
+        assert!(hops
+            .iter()
+            .all(|hop| hop.protocol == Some(TraceRouteProtocol::Icmp)));
+        assert_eq!(hops[4].addr, Some(test_net_v4(100)));
+        assert_eq!(hops[4].completion, Some(CompletionReason::Reached));
+        let protocols: Vec<u8> = backend
+            .sent_packets()
+            .iter()
+            .map(|probe| probe[9])
+            .collect();
+        assert_eq!(protocols, vec![17, 17, 17, 33, 33, 33, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn warmup_probes_are_not_reported() {
+        let cold = Duration::from_millis(60);
+        let trace = |warmup: bool| {
+            let config = TraceRouteConfig {
+                warmup,
+                ..TraceRouteConfig::default()
+            };
+            let (trace_route, receiver) =
+                TraceRoute::with_config(test_net_v4(100), config).unwrap();
+            let backend = simulated_path(2)
+                .with_cold_start(cold)
+                .with_clock(MockClock::new());
+            let handle = trace_route
+                .run_with_backend(backend.clone(), test_net_v4(254))
+                .unwrap();
+            let hops: Vec<HopFound> = receiver.iter().take(3).collect();
+            let metrics = hops[2].metrics.unwrap();
+            handle.join().unwrap();
+            (hops, metrics, backend.probes_sent())
+        };
+
+        let (hops, metrics, sent) = trace(false);
+        assert_eq!(hops[0].time, Some(cold));
+        assert_eq!(metrics.warmup_probes, 0);
+        assert_eq!(sent, 3);
+
+        let (hops, metrics, sent) = trace(true);
+        let ttls: Vec<u8> = hops.iter().map(|hop| hop.hop_count).collect();
+        assert_eq!(ttls, vec![1, 2, 3]);
+        assert!(hops
+            .iter()
+            .all(|hop| hop.time == Some(Duration::from_millis(0))));
+        assert!(hops.iter().all(|hop| hop.tries == 0));
+        assert_eq!(hops[2].completion, Some(CompletionReason::Reached));
+        assert_eq!(metrics.warmup_probes, 3);
+        assert_eq!(metrics.probes_sent, 6);
+        assert_eq!(metrics.replies_matched, 3);
+        assert_eq!(sent, 6);
+    }
+
+    #[test]
+    fn destination_is_pinged_after_the_trace() {
+        let (trace_route, receiver) = TraceRoute::with_config(
+            test_net_v4(100),
+            TraceRouteConfig {
+                max_tries: 1,
+                ..TraceRouteConfig::default()
+            },
+        )
+        .unwrap();
+        let backend = simulated_path(2)
+            .with_clock(MockClock::new())
+            .with_reply_delay(Duration::from_millis(20));
+        let handle = trace_route
+            .run_with_backend(backend.clone(), test_net_v4(254))
+            .unwrap();
+        assert!(receiver.iter().take(3).last().unwrap().is_last);
+        handle.join().unwrap();
+        let traced = backend.probes_sent();
+
+        let interval = Duration::from_millis(50);
+        let stats = trace_route
+            .ping_destination_with_backend(backend.clone(), test_net_v4(254), 4, interval)
+            .unwrap();
+        assert_eq!((stats.sent, stats.received), (4, 4));
+        assert_eq!(stats.loss(), 0.0);
+        let rtt = Some(Duration::from_millis(20));
+        assert_eq!((stats.min, stats.avg, stats.max), (rtt, rtt, rtt));
+        assert_eq!(stats.std_dev, Some(Duration::from_millis(0)));
+        assert_eq!(stats.rtts, vec![rtt; 4]);
+
+        let pings = &backend.sent_packets()[traced..];
+        assert_eq!(pings.len(), 4);
+        for (n, ping) in pings.iter().enumerate() {
+            assert_eq!(packet_ttl(ping), Some(64));
+            let echo = echo_request::EchoRequestPacket::new(&ping[20..]).unwrap();
+            assert_eq!(echo.get_icmp_type(), IcmpTypes::EchoRequest);
+            assert_eq!(usize::from(echo.get_sequence_number()), n + 1);
+        }
+
+        let silent = simulated_path(2)
+            .with_clock(MockClock::new())
+            .with_destination_filter(false, true);
+        let stats = trace_route
+            .ping_destination_with_backend(silent, test_net_v4(254), 3, interval)
+            .unwrap();
+        assert_eq!((stats.sent, stats.received), (3, 0));
+        assert_eq!(stats.loss(), 1.0);
+        assert_eq!(stats.avg, None);
+    }
+
+    #[test]
+    fn first_hop_is_probed_alongside_the_trace() {
+        let config = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Icmp,
+            first_hop_interval: Some(0),
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let backend = simulated_path(3);
+        let handle = trace_route
+            .run_with_backend(backend.clone(), test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().take(4).collect();
+        let identifier = handle.metadata().identifier;
+        handle.join().unwrap();
+
+        let addrs: Vec<Option<IpAddr>> = hops.iter().map(|hop| hop.addr).collect();
+        assert_eq!(
+            addrs,
+            vec![
+                Some(test_net_v4(1)),
+                Some(test_net_v4(2)),
+                Some(test_net_v4(3)),
+                Some(test_net_v4(100))
+            ]
+        );
+        assert!(hops.iter().all(|hop| hop.tries == 0));
+        let metrics = hops[3].metrics.unwrap();
+        assert_eq!(metrics.probes_sent, 8);
+        // Each first hop probe goes out in the same call as a probe of the trace.
+        assert_eq!(metrics.send_calls, 4);
+        assert_eq!(metrics.replies_matched, 4);
+        assert_eq!(metrics.foreign_replies, 0);
+        let first_hop = metrics.first_hop.unwrap();
+        assert_eq!((first_hop.sent, first_hop.received), (4, 4));
+        assert_eq!(first_hop.addr, Some(test_net_v4(1)));
+        assert!(first_hop.best.is_some() && first_hop.worst.is_some());
+
+        let monitored: Vec<Vec<u8>> = backend
+            .sent_packets()
+            .into_iter()
+            .filter(|probe| packet_ttl(probe) == Some(1))
+            .collect();
+        assert_eq!(monitored.len(), 5);
+        let identifiers: BTreeSet<u16> = monitored
+            .iter()
+            .map(|probe| u16::from_be_bytes([probe[24], probe[25]]))
+            .collect();
+        assert_eq!(identifiers.len(), 2);
+        assert!(identifiers.contains(&identifier));
+    }
+
+    #[test]
+    fn silent_first_hops_are_lost() {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            first_hop_interval: Some(0),
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let backend = SimulatedBackend::new(vec![None, Some(test_net_v4(2))], test_net_v4(100))
+            .with_clock(MockClock::new());
+        let handle = trace_route
+            .run_with_backend(backend.clone(), test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().take(3).collect();
+        handle.join().unwrap();
+
+        assert_eq!(hops[0].addr, None);
+        assert_eq!(hops[2].addr, Some(test_net_v4(100)));
+        let first_hop = hops[2].metrics.unwrap().first_hop.unwrap();
+        assert_eq!(first_hop.received, 0);
+        // The ones sent with the last two probes were still waiting at the end.
+        assert_eq!(first_hop.sent, 1);
+        assert_eq!(first_hop.loss(), 1.0);
+        // The trace's own probes never go to the base port.
+        let ports: Vec<u16> = backend
+            .sent_packets()
+            .iter()
+            .filter_map(|probe| packet_port(probe))
+            .collect();
+        assert_eq!(ports.iter().filter(|&&port| port == 33434).count(), 3);
+    }
+
+    #[test]
+    fn probing_goes_on_past_the_destination() {
+        let trace = |continue_past_destination: bool, count: usize| {
+            let config = TraceRouteConfig {
+                max_ttl: 5,
+                continue_past_destination,
+                ..TraceRouteConfig::default()
+            };
+            let (trace_route, receiver) =
+                TraceRoute::with_config(test_net_v4(100), config).unwrap();
+            let handle = trace_route
+                .run_with_backend(simulated_path(2), test_net_v4(254))
+                .unwrap();
+            let hops: Vec<HopFound> = receiver.iter().take(count).collect();
+            handle.join().unwrap();
+            hops
+        };
+
+        let hops = trace(false, 3);
+        assert!(hops[2].is_last);
+        assert_eq!(hops[2].completion, Some(CompletionReason::Reached));
+        assert!(hops.iter().all(|hop| !hop.beyond_destination));
+
+        let hops = trace(true, 6);
+        let ttls: Vec<u8> = hops.iter().map(|hop| hop.hop_count).collect();
+        assert_eq!(ttls, vec![1, 2, 3, 4, 5, 6]);
+        let beyond: Vec<bool> = hops.iter().map(|hop| hop.beyond_destination).collect();
+        assert_eq!(beyond, vec![false, false, false, true, true, true]);
+        assert_eq!(hops[2].addr, Some(test_net_v4(100)));
+        assert_eq!(hops[2].completion, Some(CompletionReason::Reached));
+        assert!(!hops[2].is_last);
+        assert_eq!(hops[3].addr, Some(test_net_v4(100)));
+        assert_eq!(hops[4].addr, Some(test_net_v4(100)));
+        assert_eq!(hops[3].completion, None);
+        assert!(hops[5].is_last);
+        assert_eq!(hops[5].addr, None);
+        assert_eq!(hops[5].completion, Some(CompletionReason::Reached));
+        assert!(hops[..5].iter().all(|hop| !hop.is_last));
+    }
+
+    #[test]
+    fn hops_tell_the_reply_and_quote_lengths() {
+        let config = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Icmp,
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(simulated_path(1), test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().take(2).collect();
+        handle.join().unwrap();
+        // Time exceeded quotes the IP header and 8 bytes, the echo reply quotes nothing.
+        assert_eq!(hops[0].reply_len, Some(8 + 28));
+        assert_eq!(hops[0].quoted_len, Some(28));
+        assert_eq!(hops[1].reply_len, Some(64));
+        assert_eq!(hops[1].quoted_len, None);
+        let metrics = hops[1].metrics.unwrap();
+        assert_eq!(metrics.bytes_received, 36 + 64);
+    }
+
+    #[test]
+    fn rounds_accumulate_per_hop_statistics() {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, _receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let mut sent = Vec::new();
+        let stats = trace_route
+            .run_rounds_with_backend(
+                3,
+                |hops| sent.push(hops[0].sent),
+                simulated_path(2),
+                test_net_v4(254),
+            )
+            .unwrap();
+        assert_eq!(sent, vec![1, 2, 3]);
+        let ttls: Vec<u8> = stats.iter().map(|hop| hop.ttl).collect();
+        assert_eq!(ttls, vec![1, 2, 3]);
+        assert!(stats.iter().all(|hop| hop.received == 3));
+        assert_eq!(stats[2].addr, Some(test_net_v4(100)));
+        assert!(stats.iter().all(|hop| hop.jitter.is_some()));
+    }
+
+    #[test]
+    fn rounds_begin_past_the_local_network() {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            hide_local_hops: true,
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, _receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let backend = SimulatedBackend::new(
+            vec![
+                Some(IpAddr::from([10, 0, 0, 1])),
+                Some(IpAddr::from([10, 0, 0, 2])),
+                Some(test_net_v4(3)),
+            ],
+            test_net_v4(100),
+        );
+        let stats = trace_route
+            .run_rounds_with_backend(3, |_| {}, backend.clone(), test_net_v4(254))
+            .unwrap();
+        let ttls: Vec<u8> = backend
+            .sent_packets()
+            .iter()
+            .map(|probe| probe[8])
+            .collect();
+        assert_eq!(ttls, vec![1, 2, 3, 4, 3, 4, 3, 4]);
+        assert!(stats.iter().all(|hop| hop.ttl > 2 && hop.sent == 3));
+    }
+
+    #[test]
+    fn rounds_follow_anycast_instances() {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, _receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let other_site = IpAddr::from([203, 0, 113, 9]);
+        let sites = [
+            simulated_path(2),
+            SimulatedBackend::new(
+                vec![Some(test_net_v4(1)), Some(other_site)],
+                test_net_v4(100),
+            ),
+        ];
+        let mut round = 0;
+        let mut switches = Vec::new();
+        let (stats, report) = trace_route
+            .anycast_rounds(
+                4,
+                AnycastOptions::default(),
+                |_| {},
+                |switch| switches.push(*switch),
+                |trace_route, events| {
+                    round += 1;
+                    let site = sites[usize::from(round > 2)].clone();
+                    trace_route.run_with_events_and_backend(events, site, test_net_v4(254))
+                },
+            )
+            .unwrap();
+        assert_eq!(stats[1].addr, Some(other_site));
+        assert_eq!(switches.len(), 1);
+        assert_eq!((switches[0].round, switches[0].to), (3, other_site));
+        assert!(report.anycast_suspected);
+        let rounds: Vec<_> = report
+            .instances
+            .iter()
+            .map(|instance| (instance.addr, instance.rounds))
+            .collect();
+        assert_eq!(rounds, vec![(test_net_v4(2), 2), (other_site, 2)]);
+    }
+
+    #[test]
+    fn rounds_open_the_sockets_once() {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, _receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let path = simulated_path(2);
+        let opened = AtomicUsize::new(0);
+        let stats = trace_route
+            .run_rounds_with_opener(
+                5,
+                |_| {},
+                || {
+                    opened.fetch_add(1, Ordering::SeqCst);
+                    Ok((path.clone(), test_net_v4(254)))
+                },
+            )
+            .unwrap();
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+        assert!(stats.iter().all(|hop| hop.received == 5));
+    }
+
+    #[test]
+    fn failed_sockets_are_opened_again() {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, _receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let path = simulated_path(2).with_send_errors(vec![libc::ENODEV]);
+        let opened = AtomicUsize::new(0);
+        trace_route
+            .run_rounds_with_opener(
+                3,
+                |_| {},
+                || {
+                    opened.fetch_add(1, Ordering::SeqCst);
+                    Ok((path.clone(), test_net_v4(254)))
+                },
+            )
+            .unwrap();
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn unprivileged_sockets_follow_raw_ones() {
+        let udp = TraceRouteConfig::default();
+        let icmp = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Icmp,
+            ..TraceRouteConfig::default()
+        };
+        let dccp = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Dccp,
+            ..TraceRouteConfig::default()
+        };
+        let routed = TraceRouteConfig {
+            record_route: true,
+            ..TraceRouteConfig::default()
+        };
+        let forced = TraceRouteConfig {
+            backend: Some(BackendKind::UdpErrqueue),
+            ..TraceRouteConfig::default()
+        };
+        if cfg!(target_os = "linux") {
+            assert_eq!(
+                backend_chain(&udp),
+                vec![BackendKind::Raw, BackendKind::UdpErrqueue]
+            );
+            assert_eq!(
+                backend_chain(&icmp),
+                vec![BackendKind::Raw, BackendKind::DgramIcmp]
+            );
+        }
+        assert_eq!(backend_chain(&dccp), vec![BackendKind::Raw]);
+        assert_eq!(backend_chain(&routed), vec![BackendKind::Raw]);
+        assert_eq!(backend_chain(&forced), vec![BackendKind::UdpErrqueue]);
+    }
+
+    #[test]
+    fn refused_backends_fall_through_the_chain() {
+        let chain = [BackendKind::Raw, BackendKind::DgramIcmp];
+        let mut opened = Vec::new();
+        let selected = select_backend(&chain, |kind| {
+            opened.push(kind);
+            match kind {
+                BackendKind::Raw => Err(TraceRouteError::PermissionDenied),
+                _ => Ok("dgram"),
+            }
+        });
+        assert_eq!(selected, Ok(("dgram", BackendKind::DgramIcmp)));
+        assert_eq!(opened, chain);
+
+        let refused = TraceRouteError::Channel {
+            kind: io::ErrorKind::PermissionDenied,
+            message: "ping_group_range excludes the group".to_string(),
+        };
+        let err = select_backend::<(), _>(&chain, |kind| match kind {
+            BackendKind::Raw => Err(TraceRouteError::PermissionDenied),
+            _ => Err(refused.clone()),
+        })
+        .unwrap_err();
+        assert_eq!(
+            err,
+            TraceRouteError::NoBackend {
+                tried: vec![
+                    (BackendKind::Raw, TraceRouteError::PermissionDenied),
+                    (BackendKind::DgramIcmp, refused),
+                ]
+            }
+        );
+        let message = err.to_string();
+        assert!(message.contains("raw: Could not open raw socket"));
+        assert!(message.contains("; dgram-icmp: Could not open transport channel"));
+
+        let single =
+            select_backend::<(), _>(&chain[..1], |_| Err(TraceRouteError::PermissionDenied));
+        assert_eq!(single, Err(TraceRouteError::PermissionDenied));
+    }
+
+    #[test]
+    fn given_backends_have_no_kind() {
+        let (trace_route, _receiver) =
+            TraceRoute::with_config(test_net_v4(100), TraceRouteConfig::default()).unwrap();
+        let handle = trace_route
+            .run_on(
+                simulated_path(1),
+                test_net_v4(254),
+                Some(BackendKind::UdpErrqueue),
+            )
+            .unwrap();
+        assert_eq!(handle.metadata().backend, Some(BackendKind::UdpErrqueue));
+        let handle = trace_route
+            .run_with_backend(simulated_path(1), test_net_v4(254))
+            .unwrap();
+        assert_eq!(handle.metadata().backend, None);
+    }
+
+    #[test]
+    fn port_fn_picks_the_port_of_every_probe() {
+        let config = TraceRouteConfig {
+            max_tries: 2,
+            timeout: Some(Duration::from_millis(10)),
+            port_fn: Some(PortFn::new(|ttl, attempt| {
+                40000 + u16::from(ttl) * 10 + attempt
+            })),
+            ..TraceRouteConfig::default()
+        };
+        let backend = SimulatedBackend::new(vec![Some(test_net_v4(1)), None], test_net_v4(100));
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(backend.clone(), test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().collect();
+        handle.join().unwrap();
+
+        let ports: Vec<Option<u16>> = backend
+            .sent_packets()
+            .iter()
+            .map(|probe| testing::packet_port(probe))
+            .collect();
+        assert_eq!(ports, [Some(40011), Some(40021), Some(40022), Some(40031)]);
+        let reported: Vec<Option<u16>> = hops.iter().map(|hop| hop.port).collect();
+        assert_eq!(reported, [Some(40011), None, Some(40031)]);
+        assert_eq!(hops[2].addr, Some(test_net_v4(100)));
+    }
+
+    #[test]
+    fn port_fn_giving_port_zero_fails_the_trace() {
+        let config = TraceRouteConfig {
+            port_fn: Some(PortFn::new(|ttl, _| if ttl < 2 { 40000 } else { 0 })),
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(simulated_path(3), test_net_v4(254))
+            .unwrap();
+        let mut hops = handle.hops(receiver).with_timeout(Duration::from_secs(5));
+        assert_eq!(hops.next().unwrap().unwrap().addr, Some(test_net_v4(1)));
+        let zero = TraceRouteError::ZeroPort { ttl: 2, attempt: 1 };
+        assert_eq!(hops.next(), Some(Err(zero)));
+        assert!(hops.next().is_none());
+        assert!(handle.join().is_err());
+    }
+
+    #[test]
+    fn fallback_ports_rotate_and_router_unreachables_block() {
+        let mut config = TraceRouteConfig {
+            port_fallback: Some(PortFallback {
+                candidates: vec![53, 443],
+                trigger_after: 2,
+            }),
+            ..TraceRouteConfig::default()
+        };
+        let ports: Vec<u16> = (0..5).map(|tries| probe_port(&config, 3, tries)).collect();
+        assert_eq!(ports, vec![33437, 33437, 53, 443, 53]);
+        let destination = test_net_v4(100);
+        assert!(is_blocked(&config, true, test_net_v4(4), destination));
+        assert!(!is_blocked(&config, true, destination, destination));
+        assert!(!is_blocked(&config, false, test_net_v4(4), destination));
+        config.protocol = TraceRouteProtocol::Icmp;
+        assert!(!is_blocked(&config, true, test_net_v4(4), destination));
+        config.port_fallback = None;
+        config.protocol = TraceRouteProtocol::Udp;
+        assert_eq!(probe_port(&config, 3, 3), 33437);
+        assert!(!is_blocked(&config, true, test_net_v4(4), destination));
+    }
+
+    #[test]
+    fn every_reply_of_a_window_is_attributed() {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let backend = simulated_path(3).with_late_hop(2);
+        // Queued ahead of the first answer: an echo reply of another process and
+        // a redirect, which answers no probe.
+        backend.inject(foreign_echo(1), test_net_v4(9));
+        backend.inject(vec![5, 1, 0x38, 0xf7, 192, 0, 2, 7], test_net_v4(8));
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(backend.clone(), test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().take(4).collect();
+        let metrics = handle.metrics();
+        handle.join().unwrap();
+
+        let addrs: Vec<Option<IpAddr>> = hops.iter().map(|hop| hop.addr).collect();
+        assert_eq!(
+            addrs,
+            vec![
+                Some(test_net_v4(1)),
+                None,
+                Some(test_net_v4(3)),
+                Some(test_net_v4(100))
+            ]
+        );
+        assert_eq!(hops[0].tries, 0);
+        assert_eq!(backend.probes_sent(), 4);
+        assert_eq!(metrics.foreign_replies, 2);
+        assert_eq!(metrics.stale_replies, 1);
+        assert_eq!(metrics.duplicate_replies, 0);
+        assert_eq!(metrics.replies_matched, 3);
+    }
+
+    #[test]
+    fn malformed_replies_do_not_use_up_a_try() {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let backend = simulated_path(2);
+        let mut corrupt = foreign_echo(1);
+        corrupt[7] ^= 0xff;
+        backend.inject(corrupt, test_net_v4(9));
+        backend.inject(vec![11, 0, 0], test_net_v4(9));
+        // A time exceeded message quoting too little of the probe.
+        let mut short = vec![11, 0, 0, 0, 0, 0, 0, 0, 0x45, 0, 0, 28];
+        let checksum = pnet::util::checksum(&short, 1);
+        short[2..4].copy_from_slice(&checksum.to_be_bytes());
+        backend.inject(short, test_net_v4(9));
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(backend, test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().take(3).collect();
+        let metrics = handle.metrics();
+        handle.join().unwrap();
+
+        assert_eq!(hops[0].addr, Some(test_net_v4(1)));
+        assert_eq!(hops[0].tries, 0);
+        assert!(hops[2].is_last);
+        assert_eq!(metrics.malformed_replies, 3);
+        assert_eq!(metrics.foreign_replies, 0);
+        assert_eq!(metrics.replies_matched, 3);
+    }
+
+    /// Traces ICMP probes to a destination behind a firewall at hop 2 over either
+    /// family, returns the hops.
+    fn filtered_echo_trace(policy: impl Fn(bool) -> Option<TerminalPolicy>) -> Vec<Vec<HopFound>> {
+        [false, true]
+            .iter()
+            .map(|&v6| {
+                let address = |n: u8| {
+                    if v6 {
+                        testing::test_net_v6(n.into())
+                    } else {
+                        test_net_v4(n)
                     }
+                };
+                let config = TraceRouteConfig {
+                    protocol: TraceRouteProtocol::Icmp,
+                    max_ttl: 4,
+                    max_tries: 1,
+                    timeout: Some(Duration::from_millis(10)),
+                    terminal_policy: policy(v6),
+                    ..TraceRouteConfig::default()
+                };
+                let hops = (1..=3).map(|n| Some(address(n))).collect();
+                let backend = SimulatedBackend::new(hops, address(100))
+                    .with_filtering_hop(2)
+                    .with_clock(MockClock::new());
+                let (trace_route, receiver) =
+                    TraceRoute::with_config(address(100), config).unwrap();
+                trace_route.run_with_backend(backend, address(254)).unwrap();
+                drop(trace_route);
+                receiver.iter().collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn admin_prohibited_can_end_a_trace() {
+        for hops in filtered_echo_trace(|_| None) {
+            assert_eq!(hops.len(), 5);
+            assert!(hops[1..4].iter().all(|hop| hop.addr.is_none()));
+            assert_eq!(hops[4].completion, Some(CompletionReason::NotReached));
+        }
+
+        let arrived = |v6: bool| {
+            let mut policy = TerminalPolicy::default_for(TraceRouteProtocol::Icmp, v6);
+            policy.rules.push(TerminalRule {
+                icmp_type: if v6 { 1 } else { 3 },
+                code: Some(if v6 { 1 } else { 13 }),
+                from: TerminalSource::AnyHop,
+            });
+            Some(policy)
+        };
+        for hops in filtered_echo_trace(arrived) {
+            assert_eq!(hops.len(), 2);
+            assert!(hops[1].addr.is_some());
+            assert!(hops[1].is_last);
+        }
+    }
+
+    #[test]
+    fn spoofed_echo_replies_do_not_end_a_trace() {
+        for v6 in [false, true] {
+            let address = |n: u8| {
+                if v6 {
+                    testing::test_net_v6(n.into())
+                } else {
+                    test_net_v4(n)
                 }
             };
-            match iter.next_with_timeout(Duration::from_millis(timeout)) {
-                Ok(p) => match p {
-                    Some((packet, addr)) => match seen.get(&addr) {
-                        None => {
-                            seen.insert(addr);
-                            if packet.get_icmpv6_type() == icmpv6::Icmpv6Type::new(0) && addr != ip
-                            {
-                                tx.send(HopFound {
-                                    addr: Some(addr),
-                                    hop_count: i,
-                                    tries,
-                                    is_last: false,
-                                    time: Some(Instant::now() - timer),
-                                })
-                                .unwrap();
-                                has_changed = true;
-                                i += 1;
-                                tries = 0;
-                            } else {
-                                match trace_route_protocol {
-                                    TraceRouteProtocol::Udp => {
-                                        if packet.get_icmpv6_type() == icmpv6::Icmpv6Type::new(4) {
-                                            tx.send(HopFound {
-                                                addr: Some(addr),
-                                                hop_count: i,
-                                                tries,
-                                                is_last: true,
-                                                time: Some(Instant::now() - timer),
-                                            })
-                                            .unwrap();
-                                            break;
-                                        } else {
-                                            println!(
-                                                "UNEXPECTED ICMP PACKET WITH <{:?}>",
-                                                packet.get_icmpv6_type()
-                                            );
-                                        }
-                                    }
-                                    TraceRouteProtocol::Icmp => {
-                                        if packet.get_icmpv6_type() == icmpv6::Icmpv6Type::new(0) {
-                                            tx.send(HopFound {
-                                                addr: Some(addr),
-                                                hop_count: i,
-                                                tries,
-                                                is_last: true,
-                                                time: Some(Instant::now() - timer),
-                                            })
-                                            .unwrap();
-                                            break;
-                                        } else {
-                                            println!(
-                                                "UNEXPECTED ICMP PACKET WITH <{:?}>",
-                                                packet.get_icmpv6_type()
-                                            );
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        _ => {
-                            tries -= 1;
-                        }
-                    },
-                    _ => has_changed = false,
-                },
-                _ => has_changed = false,
+            let config = TraceRouteConfig {
+                protocol: TraceRouteProtocol::Icmp,
+                max_ttl: 5,
+                max_tries: 1,
+                timeout: Some(Duration::from_millis(10)),
+                ..TraceRouteConfig::default()
+            };
+            let hops = (1..=3).map(|n| Some(address(n))).collect();
+            let backend = SimulatedBackend::new(hops, address(100))
+                .with_spoofed_echo(2, address(50))
+                .with_clock(MockClock::new());
+            let (trace_route, receiver) = TraceRoute::with_config(address(100), config).unwrap();
+            let handle = trace_route.run_with_backend(backend, address(254)).unwrap();
+            drop(trace_route);
+            let hops: Vec<HopFound> = receiver.iter().collect();
+            let metrics = handle.metrics();
+            handle.join().unwrap();
+
+            let addrs: Vec<Option<IpAddr>> = hops.iter().map(|hop| hop.addr).collect();
+            let expected: Vec<Option<IpAddr>> =
+                [1, 2, 3, 100].iter().map(|&n| Some(address(n))).collect();
+            assert_eq!(addrs, expected);
+            assert_eq!(hops[3].completion, Some(CompletionReason::Reached));
+            assert_eq!(metrics.foreign_replies, 1);
+        }
+    }
+
+    #[test]
+    fn echo_replies_can_be_ignored() {
+        let config = |v6: bool| TraceRouteConfig {
+            protocol: TraceRouteProtocol::Icmp,
+            max_ttl: 3,
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            terminal_policy: Some(TerminalPolicy {
+                rules: vec![TerminalRule {
+                    icmp_type: if v6 { 1 } else { 3 },
+                    code: None,
+                    from: TerminalSource::Destination,
+                }],
+            }),
+            ..TraceRouteConfig::default()
+        };
+        for (destination, source, v6) in [
+            (test_net_v4(100), test_net_v4(254), false),
+            (testing::test_net_v6(100), testing::test_net_v6(254), true),
+        ] {
+            let backend =
+                SimulatedBackend::new(Vec::new(), destination).with_clock(MockClock::new());
+            let (trace_route, receiver) = TraceRoute::with_config(destination, config(v6)).unwrap();
+            let handle = trace_route.run_with_backend(backend, source).unwrap();
+            drop(trace_route);
+            let hops: Vec<HopFound> = receiver.iter().collect();
+            let metrics = handle.metrics();
+            handle.join().unwrap();
+
+            assert_eq!(hops.len(), 4);
+            assert!(hops.iter().all(|hop| hop.addr.is_none()));
+            assert_eq!(hops[3].completion, Some(CompletionReason::NotReached));
+            assert_eq!(metrics.foreign_replies, 3);
+        }
+    }
+
+    /// Traces `backend` with up to two reruns, returns the events and the hops.
+    fn rerun_trace(backend: SimulatedBackend) -> (Vec<TraceEvent>, Vec<HopFound>) {
+        let config = TraceRouteConfig {
+            max_ttl: 4,
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            retry_on_total_failure: Some(RetrySpec {
+                attempts: 2,
+                delay: Duration::from_millis(50),
+            }),
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, _) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let (tx, events) = channel();
+        let handle = trace_route
+            .run_with_events_and_backend(tx, backend.with_clock(MockClock::new()), test_net_v4(254))
+            .unwrap();
+        let events: Vec<TraceEvent> = events.iter().collect();
+        handle.join().unwrap();
+        let hops = events
+            .iter()
+            .filter_map(|event| match event {
+                TraceEvent::Hop(hop) => Some(hop.clone()),
+                _ => None,
+            })
+            .collect();
+        (events, hops)
+    }
+
+    /// Returns the reruns announced among `events`.
+    fn reruns(events: &[TraceEvent]) -> Vec<u16> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                TraceEvent::Retrying(retry) => {
+                    assert_eq!(retry.delay, Duration::from_millis(50));
+                    Some(retry.attempt)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn silent_traces_are_run_again() {
+        let (events, hops) = rerun_trace(simulated_path(2).with_outage(4));
+        assert_eq!(reruns(&events), [1]);
+        let addrs: Vec<Option<IpAddr>> = hops.iter().map(|hop| hop.addr).collect();
+        assert_eq!(
+            addrs,
+            [
+                Some(test_net_v4(1)),
+                Some(test_net_v4(2)),
+                Some(test_net_v4(100))
+            ]
+        );
+        assert_eq!(hops[2].completion, Some(CompletionReason::Reached));
+        assert!(matches!(events.last(), Some(TraceEvent::Completed(_))));
+    }
+
+    #[test]
+    fn reruns_end_with_the_last_silent_run() {
+        let (events, hops) = rerun_trace(simulated_path(2).with_outage(100));
+        assert_eq!(reruns(&events), [1, 2]);
+        assert_eq!(hops.len(), 5);
+        assert!(hops[..4].iter().all(|hop| hop.is_timeout()));
+        assert!(hops[4].is_end_of_trace());
+    }
+
+    #[test]
+    fn partial_results_are_not_run_again() {
+        let backend = SimulatedBackend::new(vec![Some(test_net_v4(1)), None], test_net_v4(100))
+            .with_destination_filter(true, true);
+        let (events, hops) = rerun_trace(backend);
+        assert!(reruns(&events).is_empty());
+        assert_eq!(hops.len(), 5);
+        assert_eq!(hops[0].addr, Some(test_net_v4(1)));
+        assert!(hops[1..4].iter().all(|hop| hop.is_timeout()));
+    }
+
+    /// Traces a path with a silent second hop and the destination at TTL 5 in a
+    /// shuffled order, returns the TTLs probed and the hops.
+    fn shuffled_trace(stop_at_destination: bool) -> (Vec<u8>, Vec<HopFound>) {
+        let config = TraceRouteConfig {
+            max_ttl: 12,
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            ttl_order: TtlOrder::Shuffled {
+                stop_at_destination,
+            },
+            ..TraceRouteConfig::default()
+        };
+        let path = vec![
+            Some(test_net_v4(1)),
+            None,
+            Some(test_net_v4(3)),
+            Some(test_net_v4(4)),
+        ];
+        let backend = SimulatedBackend::new(path, test_net_v4(100)).with_clock(MockClock::new());
+        let (trace_route, _) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let (tx, events) = channel();
+        let handle = trace_route
+            .run_with_events_and_backend(tx, backend, test_net_v4(254))
+            .unwrap();
+        let events: Vec<TraceEvent> = events.iter().collect();
+        handle.join().unwrap();
+        let probed = events
+            .iter()
+            .filter_map(|event| match event {
+                TraceEvent::ProbeSent(sent) => Some(sent.ttl),
+                _ => None,
+            })
+            .collect();
+        let hops = events
+            .iter()
+            .filter_map(|event| match event {
+                TraceEvent::Hop(hop) => Some(hop.clone()),
+                _ => None,
+            })
+            .collect();
+        (probed, hops)
+    }
+
+    #[test]
+    fn shuffled_ttls_are_reported_in_order() {
+        let mut shuffled = false;
+        for _ in 0..8 {
+            let (probed, hops) = shuffled_trace(false);
+            shuffled |= probed.windows(2).any(|pair| pair[0] > pair[1]);
+            let counts: Vec<u8> = hops.iter().map(|hop| hop.hop_count).collect();
+            assert_eq!(counts, [1, 2, 3, 4, 5]);
+            assert_eq!(hops[0].addr, Some(test_net_v4(1)));
+            assert!(hops[1].is_timeout());
+            assert_eq!(hops[3].addr, Some(test_net_v4(4)));
+            assert_eq!(hops[4].addr, Some(test_net_v4(100)));
+            assert!(hops[4].is_last);
+            assert_eq!(hops[4].completion, Some(CompletionReason::Reached));
+            // TTLs above the destination are no longer probed once it answered.
+            let mut lowest_answer = u8::MAX;
+            for &ttl in &probed {
+                assert!(ttl < lowest_answer);
+                if ttl >= 5 {
+                    lowest_answer = ttl;
+                }
             }
-            tries += 1;
-            if tries >= max_tries && !has_changed {
-                tx.send(HopFound {
-                    addr: None,
-                    hop_count: i,
-                    tries,
-                    is_last: false,
-                    time: None,
-                })
+        }
+        assert!(shuffled);
+    }
+
+    #[test]
+    fn shuffled_traces_may_stop_at_the_destination() {
+        for _ in 0..8 {
+            let (probed, hops) = shuffled_trace(true);
+            let reached = probed.iter().position(|&ttl| ttl >= 5).unwrap();
+            assert_eq!(probed.len(), reached + 1);
+            let counts: Vec<u8> = hops.iter().map(|hop| hop.hop_count).collect();
+            let mut expected: Vec<u8> = probed.iter().copied().filter(|&ttl| ttl < 5).collect();
+            expected.sort_unstable();
+            expected.push(probed[reached]);
+            assert_eq!(counts, expected);
+            assert!(hops.last().unwrap().is_last);
+        }
+    }
+
+    #[test]
+    fn shuffled_ttls_cannot_continue_past_the_destination() {
+        let config = TraceRouteConfig {
+            continue_past_destination: true,
+            ttl_order: TtlOrder::Shuffled {
+                stop_at_destination: false,
+            },
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(
+            config.validate(test_net_v4(100)).unwrap_err(),
+            TraceRouteError::BadTtlOrder
+        );
+    }
+
+    #[test]
+    fn probe_tokens_match_the_wire() {
+        let be16 = |bytes: &[u8], at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        for &protocol in &[TraceRouteProtocol::Udp, TraceRouteProtocol::Icmp] {
+            let config = TraceRouteConfig {
+                protocol,
+                timeout: Some(Duration::from_millis(10)),
+                ..TraceRouteConfig::default()
+            };
+            let backend = simulated_path(2);
+            let (trace_route, _hops) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+            let (tx, events) = channel();
+            let handle = trace_route
+                .run_with_events_and_backend(tx, backend.clone(), test_net_v4(254))
                 .unwrap();
-                tries = 0;
-                i += 1;
-                has_changed = false;
+            drop(trace_route);
+            let events: Vec<TraceEvent> = events.iter().collect();
+            let identifier = handle.metadata().identifier;
+            handle.join().unwrap();
+
+            let tokens: Vec<ProbeToken> = events
+                .iter()
+                .filter_map(|event| match event {
+                    TraceEvent::ProbeSent(sent) => sent.token,
+                    _ => None,
+                })
+                .collect();
+            let wire = backend.sent_packets();
+            assert_eq!(tokens.len(), wire.len());
+            for (token, probe) in tokens.iter().zip(&wire) {
+                assert_eq!(Some(token.ttl), testing::packet_ttl(probe));
+                assert_eq!(token.ip_id, Some(be16(probe, 4)));
+                if protocol == TraceRouteProtocol::Udp {
+                    assert_eq!(token.source_port, Some(be16(probe, 20)));
+                    assert_eq!(token.destination_port, testing::packet_port(probe));
+                    assert_eq!(token.icmp_identifier, None);
+                } else {
+                    assert_eq!(token.icmp_identifier, Some(identifier));
+                    assert_eq!(token.icmp_sequence, Some(be16(probe, 26)));
+                    assert_eq!(token.destination_port, None);
+                }
             }
+            let answered: Vec<Option<ProbeToken>> = events
+                .iter()
+                .filter_map(|event| match event {
+                    TraceEvent::Hop(hop) => Some(hop.probe),
+                    _ => None,
+                })
+                .collect();
+            let expected: Vec<Option<ProbeToken>> = tokens.iter().cloned().map(Some).collect();
+            assert_eq!(answered, expected);
         }
-    });
-}
+    }
 
-fn icmp_checksum(packet: &echo_request::MutableEchoRequestPacket) -> u16be {
-    util::checksum(packet.packet(), 1)
-}
+    #[test]
+    fn traces_sharing_an_allocator_keep_apart() {
+        let allocator = Arc::new(LocalIdAllocator::new(40000, 8, 2));
+        let be16 = |bytes: &[u8], at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        // Two "processes", each with a configuration of its own.
+        let runs: Vec<_> = (0..2)
+            .map(|_| {
+                let config = TraceRouteConfig {
+                    timeout: Some(Duration::from_millis(200)),
+                    id_allocator: Some(IdSource::shared(allocator.clone())),
+                    ..TraceRouteConfig::default()
+                };
+                // Slow replies keep both traces running while the third starts.
+                let backend = simulated_path(3).with_reply_delay(Duration::from_millis(50));
+                let (trace_route, hops) =
+                    TraceRoute::with_config(test_net_v4(100), config).unwrap();
+                let handle = trace_route
+                    .run_with_backend(backend.clone(), test_net_v4(254))
+                    .unwrap();
+                (handle, hops, backend)
+            })
+            .collect();
+        assert_eq!(allocator.in_use(), 2);
+        let third = TraceRoute::with_config(
+            test_net_v4(100),
+            TraceRouteConfig {
+                id_allocator: Some(IdSource::shared(allocator.clone())),
+                ..TraceRouteConfig::default()
+            },
+        )
+        .unwrap()
+        .0;
+        assert_eq!(
+            third
+                .run_with_backend(simulated_path(3), test_net_v4(254))
+                .err(),
+            Some(TraceRouteError::NoIdentifiers)
+        );
 
-fn icmpv6_checksum(packet: &MutableIcmpv6Packet) -> u16be {
-    util::checksum(packet.packet(), 1)
-}
+        let mut ranges = Vec::new();
+        for (handle, hops, backend) in runs {
+            let identifier = handle.metadata().identifier;
+            handle.join().unwrap();
+            assert_eq!(hops.iter().count(), 3);
+            let ports: BTreeSet<u16> = backend
+                .sent_packets()
+                .iter()
+                .map(|probe| be16(probe, 20))
+                .collect();
+            let range = IdRange::new(identifier, *ports.iter().next().unwrap(), 8);
+            assert!(ports.iter().all(|&port| range.contains_port(port)));
+            ranges.push(range);
+        }
+        assert!(!ranges[0].overlaps(&ranges[1]));
+        assert_eq!(allocator.in_use(), 0);
+    }
 
+    #[test]
+    fn window_of_one_probes_like_a_sequential_trace() {
+        let path = || {
+            let mut hops: Vec<Option<IpAddr>> = (1..=5).map(|n| Some(test_net_v4(n))).collect();
+            hops[2] = None;
+            SimulatedBackend::new(hops, test_net_v4(100))
+        };
+        let trace = |window: Option<u8>| {
+            let config = TraceRouteConfig {
+                max_tries: 2,
+                timeout: Some(Duration::from_millis(10)),
+                window,
+                ..TraceRouteConfig::default()
+            };
+            let backend = path();
+            let (trace_route, hops) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+            let handle = trace_route
+                .run_with_backend(backend.clone(), test_net_v4(254))
+                .unwrap();
+            drop(trace_route);
+            let hops: Vec<_> = hops
+                .iter()
+                .map(|hop| {
+                    (
+                        hop.hop_count,
+                        hop.addr,
+                        hop.tries,
+                        hop.is_last,
+                        hop.completion,
+                    )
+                })
+                .collect();
+            handle.join().unwrap();
+            let wire: Vec<_> = backend
+                .sent_packets()
+                .iter()
+                .map(|probe| (packet_ttl(probe), packet_port(probe)))
+                .collect();
+            (hops, wire)
+        };
+        let sequential = trace(None);
+        assert_eq!(sequential.0.len(), 6);
+        assert_eq!(trace(Some(1)), sequential);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
     #[test]
-    fn creating_new_tracer() {
-        let (_, _) = TraceRoute::new(
-            Some(128), Some(12), None, None, None, None, IpAddr::from([127, 0, 0, 1]), None,
+    fn pipelined_dccp_replies_find_their_ttl() {
+        let config = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Dccp,
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(200)),
+            window: Some(4),
+            ..TraceRouteConfig::default()
+        };
+        // The first TTL stays silent while the ones above it answer.
+        let backend = SimulatedBackend::new(
+            vec![None, Some(test_net_v4(2)), Some(test_net_v4(3))],
+            test_net_v4(100),
         )
-        .unwrap();
+        .with_reply_delay(Duration::from_millis(2));
+        let (trace_route, hops) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(backend, test_net_v4(254))
+            .unwrap();
+        drop(trace_route);
+        let hops: Vec<HopFound> = hops.iter().collect();
+        handle.join().unwrap();
+        let addrs: Vec<Option<IpAddr>> = hops.iter().map(|hop| hop.addr).collect();
+        assert_eq!(
+            addrs,
+            vec![
+                None,
+                Some(test_net_v4(2)),
+                Some(test_net_v4(3)),
+                Some(test_net_v4(100))
+            ]
+        );
+        assert!(hops[3].is_last);
     }
+
     #[test]
-    #[should_panic]
-    fn creating_bad_tracer() {
-        let (_, _) = TraceRoute::new(
-            None, Some(128), None, None, None, None, IpAddr::from([127, 0, 0, 1]), None,
+    fn window_bounds_the_probes_in_flight() {
+        for &window in &[1u8, 2, 4, 16] {
+            let config = TraceRouteConfig {
+                timeout: Some(Duration::from_millis(500)),
+                window: Some(window),
+                ..TraceRouteConfig::default()
+            };
+            let backend = simulated_path(10).with_reply_delay(Duration::from_millis(2));
+            let (trace_route, hops) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+            let handle = trace_route
+                .run_with_backend(backend.clone(), test_net_v4(254))
+                .unwrap();
+            drop(trace_route);
+            let hops: Vec<HopFound> = hops.iter().collect();
+            handle.join().unwrap();
+
+            let counts: Vec<u8> = hops.iter().map(|hop| hop.hop_count).collect();
+            assert_eq!(counts, (1..=11).collect::<Vec<u8>>());
+            assert_eq!(hops[10].addr, Some(test_net_v4(100)));
+            assert!(hops[10].is_last);
+            // Every hop answers, so all the window is in flight once it filled.
+            assert_eq!(
+                backend.most_in_flight(),
+                usize::from(window),
+                "window {}",
+                window
+            );
+        }
+        let config = TraceRouteConfig {
+            window: Some(0),
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(
+            TraceRoute::with_config(test_net_v4(100), config).err(),
+            Some(TraceRouteError::BadWindow)
+        );
+    }
+
+    #[test]
+    fn subscribers_get_every_event() {
+        let (trace_route, receiver) =
+            TraceRoute::with_config(test_net_v4(100), TraceRouteConfig::default()).unwrap();
+        let backend = simulated_path(5)
+            .with_clock(MockClock::new())
+            .with_reply_delay(Duration::from_millis(1));
+        let handle = trace_route
+            .run_with_backend(backend, test_net_v4(254))
+            .unwrap();
+        let full = handle.subscribe_with_replay();
+        let leaving = handle.subscribe();
+        let hops: Vec<HopFound> = receiver.iter().take(6).collect();
+        let _ = leaving.recv();
+        drop(leaving);
+        handle.join().unwrap();
+        let events: Vec<TraceEvent> = full.iter().collect();
+        assert!(matches!(events.first(), Some(TraceEvent::Started(_))));
+        assert!(matches!(events.last(), Some(TraceEvent::Completed(_))));
+        let reported: Vec<HopFound> = events
+            .into_iter()
+            .filter_map(|event| match event {
+                TraceEvent::Hop(hop) => Some(hop),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reported, hops);
+    }
+
+    #[test]
+    fn round_trips_are_timed_from_before_the_send() {
+        let trace = |rtt_offset: Duration| {
+            let config = TraceRouteConfig {
+                max_tries: 1,
+                rtt_offset,
+                ..TraceRouteConfig::default()
+            };
+            let (trace_route, receiver) =
+                TraceRoute::with_config(test_net_v4(100), config).unwrap();
+            let backend = simulated_path(2)
+                .with_clock(MockClock::new())
+                .with_send_delay(Duration::from_millis(3))
+                .with_reply_delay(Duration::from_millis(5));
+            let handle = trace_route
+                .run_with_backend(backend, test_net_v4(254))
+                .unwrap();
+            let hops: Vec<HopFound> = receiver.iter().collect();
+            handle.join().unwrap();
+            hops
+        };
+        // The send counts for the round trip, it was timed before the send.
+        let hops = trace(Duration::from_millis(0));
+        assert_eq!(hops.len(), 3);
+        assert!(hops
+            .iter()
+            .all(|hop| hop.time == Some(Duration::from_millis(8))));
+        assert!(hops
+            .iter()
+            .all(|hop| hop.timestamp_source == Some(TimestampSource::Userspace)));
+
+        let hops = trace(Duration::from_millis(2));
+        assert!(hops
+            .iter()
+            .all(|hop| hop.time == Some(Duration::from_millis(6))));
+    }
+
+    #[test]
+    fn calibration_measures_the_loopback() {
+        let (mut trace_route, _) =
+            TraceRoute::with_config(test_net_v4(100), TraceRouteConfig::default()).unwrap();
+        let loopback = SimulatedBackend::new(Vec::new(), IpAddr::from([127, 0, 0, 1]))
+            .with_clock(MockClock::new())
+            .with_send_delay(Duration::from_millis(1))
+            .with_reply_delay(Duration::from_millis(2));
+        let offset = trace_route.calibrate_with_backend(loopback.clone());
+        assert_eq!(offset, Ok(Duration::from_millis(3)));
+        assert_eq!(trace_route.config.rtt_offset, Duration::from_millis(3));
+        assert_eq!(loopback.probes_sent(), usize::from(CALIBRATION_PROBES));
+
+        let silent = SimulatedBackend::new(Vec::new(), IpAddr::from([127, 0, 0, 1]))
+            .with_clock(MockClock::new())
+            .with_destination_filter(false, true);
+        let offset = trace_route.calibrate_with_backend(silent);
+        assert_eq!(offset, Err(TraceRouteError::NoCalibration));
+        assert_eq!(trace_route.config.rtt_offset, Duration::from_millis(3));
+    }
+
+    #[test]
+    fn interface_probes_need_a_query() {
+        let backend = || {
+            SimulatedBackend::new(vec![Some(test_net_v4(1))], test_net_v4(100))
+                .with_clock(MockClock::new())
+                .with_probed_interfaces(vec!["eth0".to_string()])
+        };
+        let (trace_route, _) =
+            TraceRoute::with_config(test_net_v4(100), TraceRouteConfig::default()).unwrap();
+        let status = trace_route.probe_interface_with_backend(backend(), test_net_v4(254));
+        assert_eq!(status, Err(TraceRouteError::NoInterfaceQuery));
+
+        let config = TraceRouteConfig {
+            interface_query: Some(InterfaceQuery::Name("eth0".to_string())),
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, _) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let status = trace_route
+            .probe_interface_with_backend(backend(), test_net_v4(254))
+            .unwrap()
+            .unwrap();
+        assert_eq!(status.interface_state, InterfaceState::Active);
+        // Nodes without support stay silent.
+        let unsupported =
+            SimulatedBackend::new(Vec::new(), test_net_v4(100)).with_clock(MockClock::new());
+        let status =
+            trace_route.probe_interface_with_backend(unsupported.clone(), test_net_v4(254));
+        assert_eq!(status, Ok(None));
+        assert_eq!(unsupported.probes_sent(), 4);
+    }
+
+    #[test]
+    fn second_pass_recovers_lost_hops() {
+        // Hops 4 and 9 lose the first probe, they answer the second pass.
+        let backend = || {
+            simulated_path(10)
+                .with_clock(MockClock::new())
+                .with_lossy_hop(4, 1)
+                .with_lossy_hop(9, 1)
+        };
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(20)),
+            second_pass: true,
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, receiver) =
+            TraceRoute::with_config(test_net_v4(100), config.clone()).unwrap();
+        let path = backend();
+        let handle = trace_route
+            .run_with_backend(path.clone(), test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().collect();
+        handle.join().unwrap();
+        let addrs: Vec<(u8, Option<IpAddr>)> =
+            hops.iter().map(|hop| (hop.hop_count, hop.addr)).collect();
+        let mut expected: Vec<(u8, Option<IpAddr>)> =
+            (1..=10).map(|n| (n, Some(test_net_v4(n)))).collect();
+        expected.push((11, Some(test_net_v4(100))));
+        assert_eq!(addrs, expected);
+        assert!(hops[10].is_last);
+        assert!(hops[..10].iter().all(|hop| !hop.is_last));
+        // The destination at TTL 11 ended the first pass, 4 and 9 were probed again.
+        let ttls: Vec<u8> = path.sent_packets().iter().map(|probe| probe[8]).collect();
+        assert_eq!(&ttls[11..], &[4, 9]);
+
+        let (trace_route, _) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let (tx, events) = channel();
+        let handle = trace_route
+            .run_with_events_and_backend(tx, backend(), test_net_v4(254))
+            .unwrap();
+        drop(trace_route);
+        let events: Vec<TraceEvent> = events.iter().collect();
+        handle.join().unwrap();
+        let silent: Vec<u8> = events
+            .iter()
+            .filter_map(|event| match event {
+                TraceEvent::Hop(hop) if hop.addr.is_none() => Some(hop.hop_count),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(silent, vec![4, 9]);
+        let corrections: Vec<(u8, IpAddr)> = events
+            .iter()
+            .filter_map(|event| match event {
+                TraceEvent::HopCorrected(correction) => {
+                    assert_eq!(correction.previously, PreviousOutcome::Timeout);
+                    Some((correction.ttl, correction.addr))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(corrections, vec![(4, test_net_v4(4)), (9, test_net_v4(9))]);
+        assert!(matches!(events.last(), Some(TraceEvent::Completed(_))));
+    }
+
+    #[test]
+    fn ttl_rewriting_is_reported() {
+        // A middlebox answers for the destination at TTL 2, with a time exceeded.
+        let backend = SimulatedBackend::new(
+            vec![
+                Some(test_net_v4(1)),
+                Some(test_net_v4(100)),
+                Some(test_net_v4(3)),
+            ],
+            test_net_v4(100),
         )
-        .unwrap();
+        .with_clock(MockClock::new());
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, _) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let (tx, events) = channel();
+        let handle = trace_route
+            .run_with_events_and_backend(tx, backend, test_net_v4(254))
+            .unwrap();
+        drop(trace_route);
+        let events: Vec<TraceEvent> = events.iter().collect();
+        handle.join().unwrap();
+        let anomalies: Vec<&PathAnomaly> = events
+            .iter()
+            .filter_map(|event| match event {
+                TraceEvent::Warning(TraceWarning::PathAnomaly(anomaly)) => Some(anomaly),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            anomalies,
+            vec![
+                &PathAnomaly::TimeExceededFromDestination { ttl: 2 },
+                &PathAnomaly::TerminalAlsoIntermediate {
+                    addr: test_net_v4(100),
+                    ttl: 4,
+                    hop_ttls: vec![2],
+                },
+            ]
+        );
+        let n = events.len();
+        assert!(matches!(
+            events[n - 2],
+            TraceEvent::Warning(TraceWarning::PathAnomaly(_))
+        ));
+        assert!(matches!(events[n - 1], TraceEvent::Completed(_)));
+    }
+
+    #[test]
+    fn late_replies_are_reported_as_corrections() {
+        // The reply for ttl 2 comes only with the probe after it, a timeout late.
+        let backend = || simulated_path(4).with_late_hop(2);
+        let config = |grace: Option<Duration>| TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(20)),
+            correct_late_replies: grace,
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, _hops) =
+            TraceRoute::with_config(test_net_v4(100), config(Some(Duration::from_secs(1))))
+                .unwrap();
+        let (tx, events) = channel();
+        let handle = trace_route
+            .run_with_events_and_backend(tx, backend(), test_net_v4(254))
+            .unwrap();
+        drop(trace_route);
+        let events: Vec<TraceEvent> = events.iter().collect();
+        handle.join().unwrap();
+        let silent = events
+            .iter()
+            .position(|event| matches!(event, TraceEvent::Hop(hop) if hop.hop_count == 2))
+            .unwrap();
+        let corrected = events
+            .iter()
+            .position(|event| matches!(event, TraceEvent::HopCorrected(_)))
+            .unwrap();
+        assert!(silent < corrected);
+        match &events[corrected] {
+            TraceEvent::HopCorrected(correction) => {
+                assert_eq!(correction.ttl, 2);
+                assert_eq!(correction.addr, test_net_v4(2));
+                assert_eq!(correction.previously, PreviousOutcome::Timeout);
+                assert!(correction.rtt >= Duration::from_millis(20));
+            }
+            event => panic!("{:?}", event),
+        }
+
+        for &(grace, received) in &[(None, 0), (Some(Duration::from_secs(1)), 2)] {
+            let (trace_route, _) =
+                TraceRoute::with_config(test_net_v4(100), config(grace)).unwrap();
+            let stats = trace_route
+                .run_rounds_with_backend(2, |_| {}, backend(), test_net_v4(254))
+                .unwrap();
+            assert_eq!((stats[1].sent, stats[1].received), (2, received));
+            assert_eq!(stats[1].addr, grace.map(|_| test_net_v4(2)));
+        }
+    }
+
+    #[test]
+    fn events_of_a_trace_come_in_order() {
+        let config = TraceRouteConfig {
+            max_tries: 2,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let backend = SimulatedBackend::new(vec![Some(test_net_v4(1)), None], test_net_v4(100))
+            .with_clock(MockClock::new());
+        backend.inject(vec![11, 0], test_net_v4(9));
+        let (trace_route, hops) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let (tx, events) = channel();
+        let handle = trace_route
+            .run_with_events_and_backend(tx, backend, test_net_v4(254))
+            .unwrap();
+        drop(trace_route);
+        let events: Vec<TraceEvent> = events.iter().collect();
+        let metadata = handle.metadata().clone();
+        handle.join().unwrap();
+
+        let described: Vec<String> = events
+            .iter()
+            .map(|event| match event {
+                TraceEvent::Started(started) => {
+                    assert_eq!(started, &metadata);
+                    "started".to_string()
+                }
+                TraceEvent::ProbeSent(probe) => format!("probe {}/{}", probe.ttl, probe.attempt),
+                TraceEvent::Hop(hop) => format!("hop {} {:?}", hop.hop_count, hop.addr),
+                TraceEvent::Warning(TraceWarning::MalformedReply(malformed)) => {
+                    format!("malformed {}", malformed.reason)
+                }
+                TraceEvent::Completed(complete) => format!(
+                    "completed {:?} {}",
+                    complete.completion, complete.metrics.probes_sent
+                ),
+                event => panic!("{:?}", event),
+            })
+            .collect();
+        assert_eq!(
+            described,
+            vec![
+                "started",
+                "probe 1/1",
+                "malformed truncated",
+                "hop 1 Some(192.0.2.1)",
+                "probe 2/1",
+                "probe 2/2",
+                "hop 2 None",
+                "probe 3/1",
+                "hop 3 Some(192.0.2.100)",
+                "completed Some(Reached) 4",
+            ]
+        );
+        // The results channel of the trace takes no part.
+        assert!(hops.try_recv().is_err());
+    }
+
+    #[test]
+    fn parameter_problems_advance_the_trace() {
+        for v6 in [false, true] {
+            let address = |n: u8| {
+                if v6 {
+                    testing::test_net_v6(n.into())
+                } else {
+                    test_net_v4(n)
+                }
+            };
+            let config = TraceRouteConfig {
+                max_ttl: 3,
+                max_tries: 2,
+                timeout: Some(Duration::from_millis(10)),
+                ..TraceRouteConfig::default()
+            };
+            let hops = (1..=3).map(|n| Some(address(n))).collect();
+            let backend = SimulatedBackend::new(hops, address(100))
+                .with_objecting_hop(2, 20)
+                .with_clock(MockClock::new());
+            let (trace_route, _) = TraceRoute::with_config(address(100), config).unwrap();
+            let (tx, events) = channel();
+            let handle = trace_route
+                .run_with_events_and_backend(tx, backend, address(254))
+                .unwrap();
+            drop(trace_route);
+            let events: Vec<TraceEvent> = events.iter().collect();
+            handle.join().unwrap();
+
+            let mut problems = Vec::new();
+            let mut hops = Vec::new();
+            let mut timeouts = None;
+            for event in events {
+                match event {
+                    TraceEvent::Warning(TraceWarning::ParameterProblem { ttl, problem }) => {
+                        assert_eq!(problem.from, address(2));
+                        assert_eq!(problem.pointer, 20);
+                        assert!(reply::sent_key(&problem.quoted).is_some());
+                        problems.push(ttl);
+                    }
+                    TraceEvent::Hop(hop) => hops.push(hop),
+                    TraceEvent::Completed(complete) => timeouts = Some(complete.metrics.timeouts),
+                    _ => {}
+                }
+            }
+            assert_eq!(problems, vec![2, 2, 3, 3]);
+            assert_eq!(timeouts, Some(0));
+            assert_eq!(hops.len(), 4);
+            assert_eq!(hops[0].addr, Some(address(1)));
+            assert!(hops[0].parameter_problem.is_none());
+            for hop in &hops[1..3] {
+                assert_eq!(hop.addr, None);
+                let problem = hop.parameter_problem.as_ref().unwrap();
+                assert_eq!((problem.from, problem.pointer), (address(2), 20));
+            }
+            assert_eq!(hops[3].completion, Some(CompletionReason::NotReached));
+        }
+    }
+
+    #[test]
+    fn foreign_replies_do_not_extend_the_wait() {
+        let config = TraceRouteConfig {
+            max_ttl: 1,
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(50)),
+            ..TraceRouteConfig::default()
+        };
+        let clock = MockClock::new();
+        let backend = SimulatedBackend::new(vec![None], test_net_v4(100))
+            .with_reply_delay(Duration::from_millis(10))
+            .with_clock(clock.clone());
+        for sequence in 0..20 {
+            backend.inject(foreign_echo(sequence), test_net_v4(9));
+        }
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let started = clock.now();
+        let handle = trace_route
+            .run_with_backend(backend, test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().take(2).collect();
+        let metrics = handle.metrics();
+        handle.join().unwrap();
+
+        assert_eq!(hops[0].addr, None);
+        assert!(hops[1].is_last);
+        assert_eq!(clock.now() - started, Duration::from_millis(50));
+        // One arrives every 10 milliseconds of the wait.
+        assert_eq!(metrics.foreign_replies, 5);
+        assert_eq!(metrics.timeouts, 1);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn protocol_comparison_finds_where_one_protocol_diverges() {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let tcp = TraceRouteProtocol::Raw(6);
+        let protocols = [TraceRouteProtocol::Icmp, TraceRouteProtocol::Udp, tcp];
+        let pool = TracePool::new(2).unwrap();
+        let (trace_route, _) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let comparison = trace_route
+            .run_protocol_comparison_with_backends(
+                &pool,
+                &protocols,
+                |protocol| match protocol {
+                    TraceRouteProtocol::Raw(6) => SimulatedBackend::new(
+                        (1..=5)
+                            .map(|n| Some(test_net_v4(n)))
+                            .chain(vec![Some(test_net_v4(16)), Some(test_net_v4(17)), None])
+                            .collect(),
+                        test_net_v4(100),
+                    ),
+                    _ => simulated_path(7),
+                },
+                test_net_v4(254),
+            )
+            .unwrap();
+
+        assert_eq!(comparison.protocols, protocols);
+        assert_eq!(comparison.first_divergent_ttl, Some(6));
+        assert!(comparison.hops[..5].iter().all(|row| !row.is_divergent()));
+        assert_eq!(
+            comparison.hops[5].addrs(),
+            vec![
+                Some(test_net_v4(6)),
+                Some(test_net_v4(6)),
+                Some(test_net_v4(16))
+            ]
+        );
+        assert_eq!(
+            comparison.hops[7].addrs(),
+            vec![Some(test_net_v4(100)), Some(test_net_v4(100)), None]
+        );
+        assert!(comparison.hops[7].is_partially_silent());
+        assert_eq!(comparison.hops[8].addrs()[2], Some(test_net_v4(100)));
+        assert!(comparison.hops[8].hops[0].is_none());
+        assert_eq!(comparison.reached, vec![Some(8), Some(8), Some(9)]);
+        assert_eq!(comparison.reached_by(), protocols);
+    }
+
+    #[test]
+    fn dscp_sweep_reports_where_classes_diverge() {
+        let backend = simulated_path(3)
+            .with_class_path(46, vec![Some(test_net_v4(1)), Some(test_net_v4(5)), None]);
+        let sweep = sweep_trace_route(test_net_v4(100))
+            .run_dscp_sweep_with_backend(&[0, 34, 46], backend.clone(), test_net_v4(254))
+            .unwrap();
+
+        assert_eq!(sweep.classes, vec![0, 34, 46]);
+        assert_eq!(sweep.first_divergent_ttl, Some(2));
+        assert_eq!(
+            sweep.hops[1].addrs(),
+            vec![
+                Some(test_net_v4(2)),
+                Some(test_net_v4(2)),
+                Some(test_net_v4(5))
+            ]
+        );
+        assert!(sweep.hops[2].is_partially_silent());
+        assert_eq!(sweep.hops[3].addrs(), vec![Some(test_net_v4(100)); 3]);
+        let classes: BTreeSet<u8> = backend
+            .sent_packets()
+            .iter()
+            .filter_map(|probe| testing::packet_dscp(probe))
+            .collect();
+        assert_eq!(classes, [0, 34, 46].iter().cloned().collect());
+    }
+
+    #[test]
+    fn extension_headers_chain_to_the_transport() {
+        let source = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 254);
+        let udp = build_udp_v6(testing::test_net_v6(100), 64, 33434, 3, source, 4321).unwrap();
+        let probe = insert_extension_v6(udp.clone(), ExtensionHeader::DestinationOptions).unwrap();
+        assert_eq!(probe.len(), udp.len() + 8);
+        // IPv6 header, Destination Options, UDP.
+        assert_eq!(probe[6], 60);
+        assert_eq!(u16::from_be_bytes([probe[4], probe[5]]), 72);
+        assert_eq!(probe[40..48], [17, 0, 1, 4, 0, 0, 0, 0]);
+        assert_eq!(probe[48..], udp[40..]);
+        let csum = util::ipv6_checksum(
+            &probe[48..],
+            3,
+            &[],
+            &source,
+            &Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 100),
+            IpNextHeaderProtocols::Udp,
+        );
+        assert_eq!(probe[54..56], csum.to_be_bytes());
+        assert_eq!(
+            reply::sent_key(&probe),
+            Some(ProbeKey::Udp {
+                source_port: 4321,
+                destination_port: 33434
+            })
+        );
+
+        let echo = build_icmp_v6(testing::test_net_v6(100), 64, 3, source, 7, 9).unwrap();
+        let probe = insert_extension_v6(echo, ExtensionHeader::HopByHop).unwrap();
+        assert_eq!((probe[6], probe[40]), (0, 58));
+        assert_eq!(
+            reply::sent_key(&probe),
+            Some(ProbeKey::Echo {
+                identifier: 7,
+                sequence: 9
+            })
+        );
+        assert_eq!(reply::snapshot(&probe).len(), 56);
+
+        let config = TraceRouteConfig {
+            extension_header: Some(ExtensionHeader::HopByHop),
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(
+            TraceRoute::with_config(test_net_v4(100), config).err(),
+            Some(TraceRouteError::BadExtensionHeader)
+        );
+    }
+
+    #[test]
+    fn probes_split_into_two_fragments() {
+        let source = Ipv4Addr::new(192, 0, 2, 254);
+        let udp = build_udp_v4(test_net_v4(100), 40, 33434, 3, source, 4321).unwrap();
+        let fragments = fragment_probe(&udp, 0);
+        assert_eq!(fragments.len(), 2);
+        let (first, second) = (&fragments[0], &fragments[1]);
+        // Half the 40 byte payload, in 8 byte units.
+        assert_eq!(first.len(), 36);
+        assert_eq!(second.len(), 44);
+        assert_eq!(first[2..4], 36u16.to_be_bytes());
+        assert_eq!(first[6..8], [0x20, 0x00]);
+        assert_eq!(second[6..8], [0x00, 0x02]);
+        assert_eq!(first[4..6], udp[4..6]);
+        assert_eq!(second[4..6], udp[4..6]);
+        for fragment in &fragments {
+            let packet = ipv4::Ipv4Packet::new(fragment).unwrap();
+            assert_eq!(ipv4::checksum(&packet), packet.get_checksum());
+        }
+        assert_eq!([&first[20..], &second[20..]].concat(), udp[20..]);
+        assert_eq!(reply::fragment(first), Some((0, true)));
+        assert_eq!(reply::fragment(second), Some((16, false)));
+        assert_eq!(reply::fragment(&udp), None);
+        assert_eq!(
+            reply::sent_key(first),
+            Some(ProbeKey::Udp {
+                source_port: 4321,
+                destination_port: 33434
+            })
+        );
+        assert_eq!(reply::sent_key(second), None);
+        // Only the source route goes in fragments past the first.
+        let options = [reply::IPOPT_NOOP, reply::IPOPT_RR, 3, 4, IPOPT_LSRR, 3, 4];
+        assert_eq!(
+            copied_options_v4(&options),
+            vec![IPOPT_LSRR, 3, 4, reply::IPOPT_NOOP]
+        );
+
+        let source = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 254);
+        let echo = build_icmp_v6(testing::test_net_v6(100), 64, 3, source, 7, 9).unwrap();
+        let fragments = fragment_probe(&echo, 0xdead_beef);
+        let (first, second) = (&fragments[0], &fragments[1]);
+        assert_eq!(first[6], 44);
+        assert_eq!(first[40..48], [58, 0, 0x00, 0x01, 0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(second[40..48], [58, 0, 0x00, 0x20, 0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(u16::from_be_bytes([first[4], first[5]]), 40);
+        assert_eq!([&first[48..], &second[48..]].concat(), echo[40..]);
+        assert_eq!(reply::fragment(second), Some((32, false)));
+        assert_eq!(
+            reply::sent_key(first),
+            Some(ProbeKey::Echo {
+                identifier: 7,
+                sequence: 9
+            })
+        );
+        assert_eq!(reply::upper_layer_v6(second), None);
+        // A Hop-by-Hop Options header stays in front of the Fragment header.
+        let hop_by_hop = insert_extension_v6(echo, ExtensionHeader::HopByHop).unwrap();
+        let first = &fragment_probe(&hop_by_hop, 1)[0];
+        assert_eq!((first[6], first[40], first[48]), (0, 44, 58));
+        assert_eq!(reply::upper_layer_v6(first), Some((58, 56)));
+    }
+
+    #[test]
+    fn fragments_need_sockets_taking_the_ip_header() {
+        let dgram = TraceRouteConfig {
+            protocol: TraceRouteProtocol::Icmp,
+            backend: Some(BackendKind::DgramIcmp),
+            fragment: true,
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(
+            dgram.validate(test_net_v4(100)),
+            Err(TraceRouteError::BadFragment(BackendKind::DgramIcmp))
+        );
+        let raw = TraceRouteConfig {
+            backend: Some(BackendKind::Raw),
+            fragment: true,
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(raw.validate(test_net_v4(100)), Ok(()));
+        assert_eq!(
+            raw.validate(testing::test_net_v6(100)),
+            Err(TraceRouteError::BadFragment(BackendKind::Raw))
+        );
+    }
+
+    #[test]
+    fn comparisons_find_the_dropping_hop() {
+        type Compare =
+            fn(TraceRoute, SimulatedBackend, IpAddr) -> (Option<u8>, Vec<IpAddr>, Vec<SweepHop>);
+        let extension_header: Compare = |trace_route, backend, source| {
+            let comparison = trace_route
+                .run_extension_header_comparison_with_backend(
+                    ExtensionHeader::DestinationOptions,
+                    backend.clone(),
+                    source,
+                )
+                .unwrap();
+            let extended = backend
+                .sent_packets()
+                .iter()
+                .filter(|probe| probe[6] == 60)
+                .count();
+            assert!(extended >= 5);
+            let suspects = comparison.suspects();
+            (comparison.first_dropped_ttl, suspects, comparison.hops)
+        };
+        let fragment: Compare = |trace_route, backend, source| {
+            let comparison = trace_route
+                .run_fragment_comparison_with_backend(backend.clone(), source)
+                .unwrap();
+            let fragments: Vec<(usize, bool)> = backend
+                .sent_packets()
+                .iter()
+                .filter_map(|packet| reply::fragment(packet))
+                .collect();
+            assert!(fragments.len() >= 10);
+            assert!(fragments
+                .chunks(2)
+                .all(|pair| pair[0] == (0, true) && !pair[1].1));
+            let suspects = comparison.suspects();
+            (comparison.first_dropped_ttl, suspects, comparison.hops)
+        };
+        let v6: fn(u8) -> IpAddr = |n| testing::test_net_v6(n.into());
+        let cases: Vec<(fn(u8) -> IpAddr, SimulatedBackend, Compare)> = vec![
+            (
+                v6,
+                SimulatedBackend::new((1..=4).map(|n| Some(v6(n))).collect(), v6(100))
+                    .with_extension_filter(2),
+                extension_header,
+            ),
+            (
+                test_net_v4,
+                simulated_path(4).with_fragment_filter(2),
+                fragment,
+            ),
+        ];
+        for (address, backend, compare) in cases {
+            let backend = backend.with_clock(MockClock::new());
+            let trace_route = sweep_trace_route(address(100));
+            let (first_dropped_ttl, suspects, hops) = compare(trace_route, backend, address(254));
+            assert_eq!(first_dropped_ttl, Some(3));
+            assert_eq!(suspects, vec![address(2), address(3)]);
+            assert_eq!(hops[1].addrs(), vec![Some(address(2)); 2]);
+            assert_eq!(hops[4].addrs(), vec![Some(address(100)), None]);
+        }
+    }
+
+    #[test]
+    fn mtu_sweep_finds_the_hop_clamping_the_mtu() {
+        // 1400 bytes past the second hop, 1280 past the third, which says so.
+        let backend = simulated_path(4)
+            .with_link_mtu(2, 1400, false)
+            .with_link_mtu(3, 1280, true);
+        let trace_route = sweep_trace_route(test_net_v4(100));
+        let sweep = trace_route
+            .run_mtu_sweep_with_backend(
+                None,
+                &[1500, 1280, 1400, 1200],
+                backend.clone(),
+                test_net_v4(254),
+            )
+            .unwrap();
+
+        assert_eq!(sweep.sizes, vec![1200, 1280, 1400, 1500]);
+        assert_eq!(
+            sweep.per_hop_mtu(),
+            vec![
+                (1, Some(1500)),
+                (2, Some(1500)),
+                (3, Some(1400)),
+                (4, Some(1280)),
+                (5, Some(1280))
+            ]
+        );
+        assert_eq!(sweep.clamping_ttl(), Some(3));
+        assert_eq!(sweep.hops[2].failed_at, Some(1500));
+        assert_eq!(sweep.hops[2].next_hop_mtu, None);
+        assert_eq!(sweep.hops[3].failed_at, Some(1400));
+        assert_eq!(sweep.hops[3].next_hop_mtu, Some(1280));
+        assert_eq!(sweep.hops[4].addr, Some(test_net_v4(100)));
+        assert!(backend
+            .sent_packets()
+            .iter()
+            .all(|probe| probe.len() <= 1500));
+
+        let single = trace_route
+            .run_mtu_sweep_with_backend(Some(&[4][..]), &[1280, 1500], backend, test_net_v4(254))
+            .unwrap();
+        assert_eq!(single.per_hop_mtu(), vec![(4, Some(1280))]);
+    }
+
+    #[test]
+    fn mtu_sweep_takes_the_told_mtu() {
+        let backend = simulated_path(3).with_link_mtu(2, 1400, true);
+        let sweep = sweep_trace_route(test_net_v4(100))
+            .run_mtu_sweep_with_backend(Some(&[2, 3]), &[1200, 1500], backend, test_net_v4(254))
+            .unwrap();
+
+        assert_eq!(sweep.per_hop_mtu(), vec![(2, Some(1500)), (3, Some(1400))]);
+        assert_eq!(sweep.hops[1].failed_at, Some(1500));
+        assert_eq!(sweep.hops[1].next_hop_mtu, Some(1400));
+        assert_eq!(sweep.hops[1].addr, Some(test_net_v4(3)));
+    }
+
+    #[test]
+    fn dscp_is_written_into_both_families() {
+        let v4 = with_dscp(
+            build_udp_v4(
+                test_net_v4(100),
+                64,
+                33434,
+                3,
+                Ipv4Addr::new(192, 0, 2, 254),
+                random(),
+            )
+            .unwrap(),
+            46,
+        );
+        assert_eq!(testing::packet_dscp(&v4), Some(46));
+        let header = ipv4::Ipv4Packet::new(&v4).unwrap();
+        assert_eq!(ipv4::checksum(&header), header.get_checksum());
+        let source = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 254);
+        let v6 = with_dscp(
+            build_udp_v6(testing::test_net_v6(100), 64, 33434, 3, source, random()).unwrap(),
+            34,
+        );
+        assert_eq!(testing::packet_dscp(&v6), Some(34));
+        assert_eq!(testing::packet_ttl(&v6), Some(3));
+        let config = TraceRouteConfig {
+            dscp: 64,
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(
+            TraceRoute::with_config(test_net_v4(100), config).err(),
+            Some(TraceRouteError::BadDscp { max: 63 })
+        );
+    }
+
+    #[test]
+    fn binding_to_a_device_degrades_on_refusal() {
+        assert_eq!(device_bound(Ok(()), "eth1"), Ok(()));
+        let refused = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(device_bound(Err(refused), "eth1"), Ok(()));
+        let missing = io::Error::from(io::ErrorKind::NotFound);
+        assert!(matches!(
+            device_bound(Err(missing), "eth1"),
+            Err(TraceRouteError::Channel {
+                kind: io::ErrorKind::NotFound,
+                ..
+            })
+        ));
+        let config = TraceRouteConfig {
+            interface: Some("no-such-dev0".into()),
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(
+            TraceRoute::with_config(test_net_v4(100), config).err(),
+            Some(TraceRouteError::BadInterface("no-such-dev0".into()))
+        );
+        let config = TraceRouteConfig {
+            interface: Some(InterfaceSel::Index(u32::MAX)),
+            ..TraceRouteConfig::default()
+        };
+        assert_eq!(
+            TraceRoute::with_config(test_net_v4(100), config).err(),
+            Some(TraceRouteError::BadInterface(InterfaceSel::Index(u32::MAX)))
+        );
+    }
+
+    #[test]
+    fn refused_marks_are_reported() {
+        assert_eq!(mark_set(Ok(())), Ok(()));
+        let refused = io::Error::from_raw_os_error(libc::EPERM);
+        assert_eq!(mark_set(Err(refused)), Err(TraceRouteError::MarkDenied));
+        let invalid = io::Error::from(io::ErrorKind::InvalidInput);
+        assert!(matches!(
+            mark_set(Err(invalid)),
+            Err(TraceRouteError::Channel { .. })
+        ));
+
+        let config = TraceRouteConfig {
+            fwmark: Some(0x2a),
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, _) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(simulated_path(1), test_net_v4(254))
+            .unwrap();
+        assert_eq!(handle.metadata().fwmark, Some(0x2a));
+    }
+
+    #[test]
+    fn traces_sharing_a_channel_are_told_apart_by_id() {
+        // Far above the ids the other tests take from the shared counter.
+        const LONG_ID: u64 = 1 << 40;
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            ..TraceRouteConfig::default()
+        };
+        let (short, receiver) = TraceRoute::with_config(test_net_v4(100), config.clone()).unwrap();
+        let config = TraceRouteConfig {
+            trace_id: Some(LONG_ID),
+            ..config
+        };
+        let (mut long, _) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        long.results_sender = short.results_sender.clone();
+        assert_eq!(long.trace_id, LONG_ID);
+        assert_ne!(short.trace_id, LONG_ID);
+
+        let short_handle = short
+            .run_with_backend(simulated_path(2), test_net_v4(254))
+            .unwrap();
+        let long_handle = long
+            .run_with_backend(simulated_path(4), test_net_v4(254))
+            .unwrap();
+        assert_eq!(short_handle.trace_id(), short.trace_id);
+        assert_eq!(long_handle.trace_id(), LONG_ID);
+        let hops: Vec<HopFound> = receiver.iter().take(3 + 5).collect();
+        short_handle.join().unwrap();
+        long_handle.join().unwrap();
+
+        let of = |trace_id: u64| -> Vec<u8> {
+            hops.iter()
+                .filter(|hop| hop.trace_id == trace_id)
+                .map(|hop| hop.hop_count)
+                .collect()
+        };
+        assert_eq!(of(short.trace_id), vec![1, 2, 3]);
+        assert_eq!(of(LONG_ID), vec![1, 2, 3, 4, 5]);
+        assert!(hops
+            .iter()
+            .filter(|hop| hop.is_last)
+            .all(|hop| hop.metrics.is_some()));
+    }
+
+    fn captured_trace(capture_raw: bool, raw_capture_limit: usize) -> Vec<HopFound> {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(10)),
+            capture_raw,
+            raw_capture_limit,
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        trace_route
+            .run_with_backend(simulated_path(2), test_net_v4(254))
+            .unwrap();
+        receiver.iter().take(3).collect()
+    }
+    #[test]
+    fn raw_replies_follow_the_flag() {
+        assert!(captured_trace(false, 256)
+            .iter()
+            .all(|hop| hop.raw_reply.is_none()));
+
+        let hops = captured_trace(true, 256);
+        let raw = hops[0].raw_reply.as_ref().unwrap();
+        assert_eq!(raw[0], 11);
+        assert_eq!(raw.len(), 8 + 20 + 8);
+        assert_eq!(hops[2].raw_reply.as_ref().unwrap()[..2], [3, 3]);
+
+        let hops = captured_trace(true, 16);
+        assert!(hops
+            .iter()
+            .all(|hop| hop.raw_reply.as_ref().unwrap().len() == 16));
+    }
+    #[test]
+    fn raw_reply_keeps_the_header() {
+        let config = TraceRouteConfig {
+            capture_raw: true,
+            raw_capture_limit: 24,
+            ..TraceRouteConfig::default()
+        };
+        let header = vec![0x45; 20];
+        let raw = raw_reply_of(&config, || Some(header.clone()), &[11, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(raw.unwrap(), [&header[..], &[11, 0, 0, 0]].concat());
+        let off = TraceRouteConfig::default();
+        assert_eq!(raw_reply_of(&off, || panic!("copied"), &[11]), None);
+    }
+    #[test]
+    fn traces_get_distinct_identifiers() {
+        assert_ne!(next_identifier(), next_identifier());
+    }
+    #[test]
+    fn hop_clone_and_compare() {
+        let hop = HopFound::new(
+            3,
+            Some(IpAddr::from([10, 0, 0, 1])),
+            1,
+            false,
+            Some(Duration::from_micros(12_400)),
+        );
+        let copy = hop.clone();
+        assert_eq!(hop, copy);
+        assert_eq!(hop.addr_scope, Some(AddrScope::Private));
+        assert_ne!(hop, HopFound::new(3, None, 4, false, None));
+        assert_eq!(hop.to_string(), "3  10.0.0.1  12.4ms");
+        assert_eq!(HopFound::new(4, None, 4, false, None).to_string(), "4  *");
+    }
+    #[test]
+    fn protocol_round_trip() {
+        for p in &[
+            TraceRouteProtocol::Icmp,
+            TraceRouteProtocol::Udp,
+            TraceRouteProtocol::Dccp,
+            TraceRouteProtocol::Raw(89),
+        ] {
+            assert_eq!(p.to_string().parse::<TraceRouteProtocol>().unwrap(), *p);
+            assert_eq!(
+                p.to_string()
+                    .to_uppercase()
+                    .parse::<TraceRouteProtocol>()
+                    .unwrap(),
+                *p
+            );
+        }
+        assert_eq!(
+            "Icmp".parse::<TraceRouteProtocol>(),
+            Ok(TraceRouteProtocol::Icmp)
+        );
+    }
+    #[test]
+    fn protocol_unknown_name() {
+        let err = "sctp".parse::<TraceRouteProtocol>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown protocol \"sctp\", expected one of: icmp, udp, dccp, raw:<proto>"
+        );
     }
 }
@@ -0,0 +1,350 @@
+//! Backend sending probes in Ethernet frames it builds itself.
+//!
+//! The frames leave through a datalink channel, so the trace picks the source MAC,
+//! the device and the next hop, also on devices without an address. Replies are
+//! still read from the raw sockets of a `PnetBackend`.
+use crate::backend::{PnetBackend, ProbeBackend, ReplyKind, TimestampSource};
+use pnet::datalink::{DataLinkReceiver, DataLinkSender};
+use pnet::packet::icmpv6::{self, Icmpv6Packet};
+use pnet::util::MacAddr;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+/// Neighbor Solicitation and Advertisement types of RFC 4861.
+const ND_SOLICIT: u8 = 135;
+const ND_ADVERT: u8 = 136;
+
+/// Source and target link-layer address options of RFC 4861.
+const OPT_SOURCE_LINK: u8 = 1;
+const OPT_TARGET_LINK: u8 = 2;
+
+/// This struct is a backend writing probes to a datalink channel.
+///
+/// Every probe goes to the MAC address of the next hop, whatever its destination,
+/// like the kernel would send it through a route.
+pub(crate) struct LinkBackend {
+    tx: Box<dyn DataLinkSender>,
+    source: MacAddr,
+    next_hop: MacAddr,
+    replies: PnetBackend,
+}
+
+impl LinkBackend {
+    /// Creates new LinkBackend sending frames from `source` to `next_hop` over `tx`
+    /// and reading replies off the sockets of `replies`.
+    pub fn new(
+        tx: Box<dyn DataLinkSender>,
+        source: MacAddr,
+        next_hop: MacAddr,
+        replies: PnetBackend,
+    ) -> LinkBackend {
+        LinkBackend {
+            tx,
+            source,
+            next_hop,
+            replies,
+        }
+    }
+}
+
+fn octets(mac: MacAddr) -> [u8; 6] {
+    [mac.0, mac.1, mac.2, mac.3, mac.4, mac.5]
+}
+
+fn mac_at(bytes: &[u8], at: usize) -> Option<MacAddr> {
+    let b = bytes.get(at..at + 6)?;
+    Some(MacAddr(b[0], b[1], b[2], b[3], b[4], b[5]))
+}
+
+fn ether_type(frame: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]))
+}
+
+fn frame(source: MacAddr, destination: MacAddr, ether_type: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(14 + payload.len());
+    frame.extend_from_slice(&octets(destination));
+    frame.extend_from_slice(&octets(source));
+    frame.extend_from_slice(&ether_type.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Returns `packet` in an Ethernet frame from `source` to `destination`, of the
+/// ether type of its IP version.
+pub(crate) fn ethernet_frame(source: MacAddr, destination: MacAddr, packet: &[u8]) -> Vec<u8> {
+    let ether_type = match packet.first().map(|b| b >> 4) {
+        Some(6) => ETHERTYPE_IPV6,
+        _ => ETHERTYPE_IPV4,
+    };
+    frame(source, destination, ether_type, packet)
+}
+
+/// Builds the broadcast ARP request asking for the MAC address of `target`.
+pub(crate) fn arp_request(source_mac: MacAddr, source: Ipv4Addr, target: Ipv4Addr) -> Vec<u8> {
+    // Ethernet hardware, IPv4 protocol, their address lengths and the request opcode.
+    let mut arp = vec![0, 1, 8, 0, 6, 4, 0, 1];
+    arp.extend_from_slice(&octets(source_mac));
+    arp.extend_from_slice(&source.octets());
+    arp.extend_from_slice(&[0; 6]);
+    arp.extend_from_slice(&target.octets());
+    frame(source_mac, MacAddr::broadcast(), ETHERTYPE_ARP, &arp)
+}
+
+/// Returns the MAC address of `target` if `frame` is an ARP reply telling it.
+pub(crate) fn arp_reply(frame: &[u8], target: Ipv4Addr) -> Option<MacAddr> {
+    let arp = frame.get(14..42)?;
+    if ether_type(frame)? != ETHERTYPE_ARP || arp[6..8] != [0, 2] || arp[14..18] != target.octets()
+    {
+        return None;
+    }
+    mac_at(arp, 8)
+}
+
+/// Builds the Neighbor Solicitation asking for the MAC address of `target`, sent
+/// to its solicited-node multicast group.
+pub(crate) fn neighbor_solicitation(
+    source_mac: MacAddr,
+    source: Ipv6Addr,
+    target: Ipv6Addr,
+) -> Vec<u8> {
+    let t = target.octets();
+    let group = Ipv6Addr::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 | u16::from(t[13]),
+        u16::from_be_bytes([t[14], t[15]]),
+    );
+    let mut message = vec![ND_SOLICIT, 0, 0, 0, 0, 0, 0, 0];
+    message.extend_from_slice(&t);
+    message.extend_from_slice(&[OPT_SOURCE_LINK, 1]);
+    message.extend_from_slice(&octets(source_mac));
+    let checksum = icmpv6::checksum(&Icmpv6Packet::new(&message).unwrap(), &source, &group);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    // Neighbor Discovery messages are only taken with a hop limit of 255.
+    let mut packet = vec![0x60, 0, 0, 0];
+    packet.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[58, 255]);
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&group.octets());
+    packet.extend_from_slice(&message);
+    let g = group.octets();
+    let multicast = MacAddr(0x33, 0x33, g[12], g[13], g[14], g[15]);
+    frame(source_mac, multicast, ETHERTYPE_IPV6, &packet)
+}
+
+/// Returns the MAC address of `target` if `frame` is a Neighbor Advertisement for
+/// it, from its target link-layer option or else the frame itself.
+pub(crate) fn neighbor_advertisement(frame: &[u8], target: Ipv6Addr) -> Option<MacAddr> {
+    let packet = frame.get(14..)?;
+    if ether_type(frame)? != ETHERTYPE_IPV6 || packet.get(6) != Some(&58) {
+        return None;
+    }
+    let message = packet.get(40..)?;
+    if message.first() != Some(&ND_ADVERT) || message.get(8..24)? != target.octets() {
+        return None;
+    }
+    let mut options = message.get(24..)?;
+    while options.len() >= 8 {
+        let len = usize::from(options[1]) * 8;
+        if len == 0 {
+            break;
+        }
+        if options[0] == OPT_TARGET_LINK {
+            return mac_at(options, 2);
+        }
+        options = options.get(len..)?;
+    }
+    mac_at(frame, 6)
+}
+
+fn send(tx: &mut dyn DataLinkSender, frame: &[u8]) -> io::Result<()> {
+    tx.send_to(frame, None)
+        .unwrap_or_else(|| Err(io::Error::other("frame does not fit the datalink buffer")))
+}
+
+/// Asks the link for the MAC address of `target`, None if it did not answer in
+/// `timeout`.
+///
+/// `rx` has to time out on its own, or this waits for the next frame forever.
+pub(crate) fn resolve(
+    tx: &mut dyn DataLinkSender,
+    rx: &mut dyn DataLinkReceiver,
+    source_mac: MacAddr,
+    source: IpAddr,
+    target: IpAddr,
+    timeout: Duration,
+) -> io::Result<Option<MacAddr>> {
+    let request = match (source, target) {
+        (IpAddr::V4(source), IpAddr::V4(target)) => arp_request(source_mac, source, target),
+        (IpAddr::V6(source), IpAddr::V6(target)) => {
+            neighbor_solicitation(source_mac, source, target)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "source and target of different families",
+            ))
+        }
+    };
+    send(tx, &request)?;
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        let frame = match rx.next() {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+        let found = match target {
+            IpAddr::V4(target) => arp_reply(frame, target),
+            IpAddr::V6(target) => neighbor_advertisement(frame, target),
+        };
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    Ok(None)
+}
+
+impl ProbeBackend for LinkBackend {
+    fn send_to(&mut self, packet: &[u8], _destination: IpAddr) -> io::Result<usize> {
+        let frame = ethernet_frame(self.source, self.next_hop, packet);
+        send(&mut *self.tx, &frame)?;
+        Ok(packet.len())
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+        self.replies.recv_timeout(timeout)
+    }
+
+    fn recv_reply(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<(ReplyKind, Vec<u8>, IpAddr)>> {
+        self.replies.recv_reply(timeout)
+    }
+
+    fn reply_options(&self) -> Vec<u8> {
+        self.replies.reply_options()
+    }
+
+    fn reply_header(&self) -> Option<Vec<u8>> {
+        self.replies.reply_header()
+    }
+
+    fn reply_received_at(&self) -> Option<Instant> {
+        self.replies.reply_received_at()
+    }
+
+    fn reply_timestamp_source(&self) -> TimestampSource {
+        self.replies.reply_timestamp_source()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn reply_fds(&self) -> Vec<libc::c_int> {
+        self.replies.reply_fds()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0x01);
+    const ROUTER: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0xfe);
+
+    #[test]
+    fn probes_are_framed_for_the_next_hop() {
+        let udp_v4 = [
+            0x45, 0, 0, 28, 0x12, 0x34, 0x40, 0, 3, 17, 0, 0, 192, 0, 2, 254, 192, 0, 2, 100, 0xa0,
+            0, 0x82, 0x9b, 0, 8, 0, 0,
+        ];
+        let mut expected = vec![0x02, 0, 0, 0, 0, 0xfe, 0x02, 0, 0, 0, 0, 0x01, 0x08, 0x00];
+        expected.extend_from_slice(&udp_v4);
+        assert_eq!(ethernet_frame(SOURCE, ROUTER, &udp_v4), expected);
+
+        let mut echo_v6 = vec![0x60, 0, 0, 0, 0, 8, 58, 3];
+        echo_v6.extend_from_slice(&[0; 32]);
+        echo_v6.extend_from_slice(&[128, 0, 0, 0, 0x12, 0x34, 0, 1]);
+        let frame = ethernet_frame(SOURCE, ROUTER, &echo_v6);
+        assert_eq!(frame[12..14], [0x86, 0xdd]);
+        assert_eq!(frame[14..], echo_v6[..]);
+    }
+
+    #[test]
+    fn arp_asks_and_answers() {
+        let source = Ipv4Addr::new(192, 0, 2, 254);
+        let router = Ipv4Addr::new(192, 0, 2, 1);
+        let request = arp_request(SOURCE, source, router);
+        let expected: Vec<u8> = vec![
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02, 0, 0, 0, 0, 0x01, 0x08, 0x06, 0, 1, 8, 0, 6,
+            4, 0, 1, 0x02, 0, 0, 0, 0, 0x01, 192, 0, 2, 254, 0, 0, 0, 0, 0, 0, 192, 0, 2, 1,
+        ];
+        assert_eq!(request, expected);
+        assert_eq!(arp_reply(&request, router), None);
+
+        let mut reply = frame(ROUTER, SOURCE, ETHERTYPE_ARP, &[0, 1, 8, 0, 6, 4, 0, 2]);
+        reply.extend_from_slice(&octets(ROUTER));
+        reply.extend_from_slice(&router.octets());
+        reply.extend_from_slice(&octets(SOURCE));
+        reply.extend_from_slice(&source.octets());
+        assert_eq!(arp_reply(&reply, router), Some(ROUTER));
+        assert_eq!(arp_reply(&reply, source), None);
+        assert_eq!(arp_reply(&reply[..30], router), None);
+    }
+
+    #[test]
+    fn neighbors_are_solicited_and_advertised() {
+        let source: Ipv6Addr = "2001:db8::fe".parse().unwrap();
+        let router: Ipv6Addr = "fe80::12:3456".parse().unwrap();
+        let solicit = neighbor_solicitation(SOURCE, source, router);
+        assert_eq!(solicit[..6], [0x33, 0x33, 0xff, 0x12, 0x34, 0x56]);
+        assert_eq!(solicit[12..14], [0x86, 0xdd]);
+        let packet = &solicit[14..];
+        assert_eq!(packet[4..8], [0, 32, 58, 255]);
+        let group: Ipv6Addr = "ff02::1:ff12:3456".parse().unwrap();
+        assert_eq!(packet[24..40], group.octets());
+        let message = &packet[40..];
+        assert_eq!(message[0], ND_SOLICIT);
+        assert_eq!(message[8..24], router.octets());
+        assert_eq!(message[24..32], [1, 1, 0x02, 0, 0, 0, 0, 0x01]);
+        let checksum = icmpv6::checksum(&Icmpv6Packet::new(message).unwrap(), &source, &group);
+        assert_eq!(message[2..4], checksum.to_be_bytes());
+        assert_eq!(neighbor_advertisement(&solicit, router), None);
+
+        let mut advert = vec![0x60, 0, 0, 0, 0, 32, 58, 255];
+        advert.extend_from_slice(&router.octets());
+        advert.extend_from_slice(&source.octets());
+        advert.extend_from_slice(&[ND_ADVERT, 0, 0, 0, 0x60, 0, 0, 0]);
+        advert.extend_from_slice(&router.octets());
+        let bare = frame(
+            MacAddr(0x02, 0, 0, 0, 0, 0x77),
+            SOURCE,
+            ETHERTYPE_IPV6,
+            &advert,
+        );
+        advert.extend_from_slice(&[OPT_TARGET_LINK, 1]);
+        advert.extend_from_slice(&octets(ROUTER));
+        let full = frame(
+            MacAddr(0x02, 0, 0, 0, 0, 0x77),
+            SOURCE,
+            ETHERTYPE_IPV6,
+            &advert,
+        );
+        assert_eq!(neighbor_advertisement(&full, router), Some(ROUTER));
+        assert_eq!(
+            neighbor_advertisement(&bare, router),
+            Some(MacAddr(0x02, 0, 0, 0, 0, 0x77))
+        );
+        assert_eq!(neighbor_advertisement(&full, source), None);
+    }
+}
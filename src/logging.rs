@@ -0,0 +1,245 @@
+//! Diagnostics of the library, forwarded to the `log` crate with the `log` feature
+//! and to `tracing` with the `tracing` feature.
+//!
+//! With both features the records go through `tracing`, whose log bridge hands them
+//! on to `log` when no subscriber is set. Without either feature the macros only
+//! borrow their arguments, so nothing is formatted and call sites need no `cfg` of
+//! their own.
+use crate::reply::{MalformedReply, ParameterProblem};
+use crate::{HopFound, TraceRouteProtocol};
+use std::net::IpAddr;
+use std::thread::{self, JoinHandle};
+
+/// Target of every record the library emits.
+#[cfg(any(feature = "log", feature = "tracing"))]
+pub(crate) const TARGET: &str = "librtraceroute";
+
+#[cfg(feature = "tracing")]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        tracing::debug!(target: crate::logging::TARGET, $($arg)+)
+    };
+}
+
+#[cfg(all(feature = "log", not(feature = "tracing")))]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        log::debug!(target: crate::logging::TARGET, $($arg)+)
+    };
+}
+
+#[cfg(not(any(feature = "log", feature = "tracing")))]
+macro_rules! debug {
+    ($($arg:tt)+) => {{
+        let _ = format_args!($($arg)+);
+    }};
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        tracing::warn!(target: crate::logging::TARGET, $($arg)+)
+    };
+}
+
+#[cfg(all(feature = "log", not(feature = "tracing")))]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        log::warn!(target: crate::logging::TARGET, $($arg)+)
+    };
+}
+
+#[cfg(not(any(feature = "log", feature = "tracing")))]
+macro_rules! warn {
+    ($($arg:tt)+) => {{
+        let _ = format_args!($($arg)+);
+    }};
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+/// This struct stands in for `tracing::Span` without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone, Debug)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub fn current() -> Span {
+        Span
+    }
+
+    pub fn none() -> Span {
+        Span
+    }
+
+    pub fn in_scope<F: FnOnce() -> T, T>(&self, work: F) -> T {
+        work()
+    }
+}
+
+/// Returns the span covering a whole trace.
+pub(crate) fn trace_span(
+    trace_id: u64,
+    destination: IpAddr,
+    protocol: TraceRouteProtocol,
+    max_ttl: u8,
+) -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::debug_span!(
+        target: TARGET,
+        "trace",
+        trace_id,
+        target = %destination,
+        protocol = %protocol,
+        max_ttl
+    );
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (trace_id, destination, protocol, max_ttl);
+        Span
+    }
+}
+
+/// Returns the span covering the probes of one TTL, a child of the current span.
+pub(crate) fn ttl_span(ttl: u8) -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::debug_span!(target: TARGET, "ttl", ttl);
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = ttl;
+        Span
+    }
+}
+
+/// Runs `work` on a new thread inside `span`.
+///
+/// The subscriber of the calling thread is carried over, so scoped subscribers see
+/// the worker too. Without any subscriber none is installed, which would keep the log
+/// bridge from forwarding the records.
+pub(crate) fn spawn_in<F>(span: Span, work: F) -> JoinHandle<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    #[cfg(feature = "tracing")]
+    {
+        if !tracing::dispatcher::has_been_set() {
+            return thread::spawn(move || span.in_scope(work));
+        }
+        let dispatch = tracing::dispatcher::get_default(Clone::clone);
+        thread::spawn(move || tracing::dispatcher::with_default(&dispatch, || span.in_scope(work)))
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = span;
+        thread::spawn(work)
+    }
+}
+
+pub(crate) fn probe_sent(ttl: u8, attempt: u16) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(target: TARGET, ttl, attempt, "probe sent");
+    #[cfg(not(feature = "tracing"))]
+    debug!("probe sent for ttl {} on try {}", ttl, attempt);
+}
+
+pub(crate) fn timed_out(ttl: u8, attempt: u16) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(target: TARGET, ttl, attempt, "timeout");
+    #[cfg(not(feature = "tracing"))]
+    debug!("no reply for ttl {} on try {}", ttl, attempt);
+}
+
+pub(crate) fn reply_matched(hop: &HopFound) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        target: TARGET,
+        ttl = hop.hop_count,
+        addr = ?hop.addr,
+        rtt_us = hop.time.map(|time| time.as_micros() as u64),
+        "reply matched"
+    );
+    #[cfg(not(feature = "tracing"))]
+    debug!(
+        "reply matched for ttl {} from {:?} in {:?}",
+        hop.hop_count, hop.addr, hop.time
+    );
+}
+
+pub(crate) fn completed(trace: &Span, hop: &HopFound) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        target: TARGET,
+        parent: trace,
+        trace_id = hop.trace_id,
+        hops = hop.hop_count,
+        reason = ?hop.completion,
+        rtt_us = hop.time.map(|time| time.as_micros() as u64),
+        metrics = ?hop.metrics,
+        "completion"
+    );
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = trace;
+        debug!(
+            "trace {} completed after {} hops, {:?}, {:?}",
+            hop.trace_id, hop.hop_count, hop.completion, hop.metrics
+        );
+    }
+}
+
+pub(crate) fn malformed_reply(reply: &MalformedReply) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(
+        target: TARGET,
+        from = ?reply.from,
+        reason = %reply.reason,
+        len = reply.len,
+        raw = ?reply.raw,
+        "malformed reply"
+    );
+    #[cfg(not(feature = "tracing"))]
+    match &reply.raw {
+        Some(raw) => warn!(
+            "malformed reply from {:?}, {}, {} bytes <{:02x?}>",
+            reply.from, reply.reason, reply.len, raw
+        ),
+        None => warn!(
+            "malformed reply from {:?}, {}, {} bytes",
+            reply.from, reply.reason, reply.len
+        ),
+    }
+}
+
+pub(crate) fn parameter_problem(ttl: u8, problem: &ParameterProblem) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(
+        target: TARGET,
+        ttl,
+        from = %problem.from,
+        pointer = problem.pointer,
+        "parameter problem"
+    );
+    #[cfg(not(feature = "tracing"))]
+    warn!(
+        "parameter problem from {} for ttl {} at octet {}",
+        problem.from, ttl, problem.pointer
+    );
+}
+
+pub(crate) fn protocol_switched(from: TraceRouteProtocol, to: TraceRouteProtocol, silent_hops: u8) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        target: TARGET,
+        from = %from,
+        to = %to,
+        silent_hops,
+        "protocol fallback"
+    );
+    #[cfg(not(feature = "tracing"))]
+    debug!(
+        "no reply to {} probes in the first {} hops, retrying with {}",
+        from, silent_hops, to
+    );
+}
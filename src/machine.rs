@@ -0,0 +1,1574 @@
+//! Traces driven one event at a time, by a thread of their own or by a pool.
+use crate::backend::{self, ProbeBackend, ReplyKind, TimestampSource};
+use crate::clock::Clock;
+use crate::ids::IdLease;
+use crate::logging::{self, Span};
+use crate::metrics::{Counted, Metrics};
+use crate::monitor::FirstHopMonitor;
+use crate::receiver::{Event, Outstanding, Reply, ReplyReceiver};
+use crate::registry::ProbeRecord;
+use crate::reply::{self, ParameterProblem, ProbeToken};
+use crate::shared::Registration;
+use crate::sink::{Disconnected, HopSink};
+use crate::TerminalPolicy;
+use crate::TtlOrder;
+use crate::{
+    add_options_v4, attribute, build_dccp_v4, build_dccp_v6, build_icmp_v4, build_icmp_v6,
+    build_raw_v4, build_raw_v6, build_tcp_syn_v4, build_udp_v4, build_udp_v6, dccp_sequence,
+    destination_answered, fill_from_options, fragment_probe, insert_extension_v6, is_blocked,
+    needs_confirmation, port_of, probe_port, report_unexpected, send_probes, with_dscp,
+    Attribution,
+};
+use crate::{CompletionReason, HopFound, TraceRouteConfig, TraceRouteError, TraceRouteProtocol};
+use crate::{
+    HopCorrection, PathAnomaly, PreviousOutcome, ProbeSent, TraceEvent, TraceRetry, TraceWarning,
+};
+use pnet::packet::icmp;
+use pnet::packet::icmpv6::{self, Icmpv6Types};
+use rand::random;
+use rand::seq::SliceRandom;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often a wait without timeout wakes to look for a cancellation.
+const WAKE: Duration = Duration::from_millis(100);
+
+/// Wait before the first retry of a probe that could not be sent, doubled for
+/// every further one.
+const SEND_BACKOFF: Duration = Duration::from_millis(10);
+
+/// A reply as handed over by `ProbeBackend::recv_reply`.
+pub(crate) type Received = io::Result<Option<(ReplyKind, Vec<u8>, IpAddr)>>;
+
+/// This struct is the probe of the current TTL, waiting for its reply.
+struct Sent {
+    probe: Vec<u8>,
+    port: u16,
+    warming: bool,
+    sent_at: Instant,
+}
+
+/// This enum is what a trace waits for.
+enum Wait {
+    Probe(Sent),
+    /// A pause before the next probe, after one could not be sent or before the
+    /// trace runs again.
+    Backoff,
+    /// An echo reply of a silent destination, `left` more probes may follow.
+    Confirm {
+        last: Box<HopFound>,
+        left: u16,
+    },
+    /// Late replies to TTLs reported as unanswered, after the last hop.
+    Grace,
+}
+
+/// This enum is why a probe was not sent.
+enum Unsent {
+    /// The backend could not send it for now, it is tried again after a backoff.
+    Later(io::Error),
+    /// The trace failed and is over.
+    Failed,
+}
+
+/// This enum is how the wait for a reply to a probe ended.
+enum Outcome {
+    Silent,
+    Answered(Box<Reply>),
+    /// The destination answered and the trace goes on past it.
+    Advanced,
+    /// The sink took no more hops.
+    Stopped,
+    Failed(io::Error),
+    /// A hop objected to a field of the probe.
+    Rejected(ParameterProblem),
+}
+
+/// This enum is what a reply to the probe of the current TTL means for the trace.
+enum Verdict {
+    Blocked,
+    Answers,
+    /// An echo reply of another host, like a spoofed one or one of an unrelated ping.
+    Foreign,
+    Unexpected(Box<dyn fmt::Debug>),
+}
+
+/// This struct is the state of one trace, advanced by sent probes, received
+/// replies and expired deadlines.
+///
+/// It never blocks itself, the caller waits for replies until `next_deadline`
+/// and hands them to `receive`. Received messages go through a `ReplyReceiver`,
+/// which looks them up among the outstanding probes and passes the ones of this
+/// trace back over a channel, the machine only drives the TTL and retry
+/// schedule. With a `SharedReceiver` the receiver is registered with it and the
+/// backend is only sent on. The probes of every protocol of
+/// `TraceRouteConfig::protocol_fallback` are sent by the same machine in turn.
+pub(crate) struct TraceMachine<B: ProbeBackend> {
+    backend: Counted<B>,
+    sink: HopSink,
+    config: TraceRouteConfig,
+    protocols: VecDeque<TraceRouteProtocol>,
+    ip: IpAddr,
+    source: IpAddr,
+    next_hop: IpAddr,
+    identifier: u16,
+    metrics: Arc<Metrics>,
+    clock: Arc<dyn Clock>,
+    receiver: ReplyReceiver,
+    events: Receiver<Event>,
+    outstanding: Arc<Mutex<Outstanding>>,
+    seen: BTreeSet<IpAddr>,
+    sequence: u16,
+    last_responder: Option<IpAddr>,
+    terminal: TerminalPolicy,
+    ttl: u8,
+    /// TTLs of the attempt in the order they are probed, `ttl` is the one at `turn`.
+    order: Vec<u8>,
+    turn: usize,
+    /// Hops of a shuffled trace waiting for the hops of the TTLs below them.
+    pending: BTreeMap<u8, HopFound>,
+    /// TTL of the next hop a shuffled trace reports.
+    next_release: u8,
+    /// Lowest TTL the destination of a shuffled trace answered at.
+    terminal_ttl: Option<u8>,
+    tries: u16,
+    /// Tries of the current TTL that drew a Parameter Problem, and the last one.
+    rejected: Option<(u16, ParameterProblem)>,
+    /// Tries of the current TTL that could not be sent for now.
+    send_retries: u16,
+    /// Times the trace was run again after none of its TTLs answered.
+    reruns: u16,
+    ttl_span: Option<(u8, Span)>,
+    warmed: Option<u8>,
+    reached: bool,
+    wait: Option<(Wait, Instant)>,
+    done: bool,
+    /// Identifier and source ports held for the trace, released with the machine.
+    lease: Option<IdLease>,
+    /// TTLs reported as unanswered and when, while their late replies are still
+    /// reported, see `TraceRouteConfig::correct_late_replies`.
+    late: BTreeMap<u8, Instant>,
+    /// TTLs reported as unanswered, probed again by the second pass, see
+    /// `TraceRouteConfig::second_pass`.
+    silent: Vec<u8>,
+    /// Set while the second pass probes them.
+    passing: bool,
+    /// Registration of the receiver with `TraceRouteConfig::shared_receiver`, the
+    /// replies come over `events` alone while it is held.
+    shared: Option<Registration>,
+}
+
+impl<B: ProbeBackend> TraceMachine<B> {
+    /// Creates new TraceMachine, tracing `ip` from `source` over `backend`.
+    pub fn new(
+        config: TraceRouteConfig,
+        ip: IpAddr,
+        source: IpAddr,
+        backend: B,
+        identifier: u16,
+        sink: HopSink,
+        metrics: Arc<Metrics>,
+    ) -> TraceMachine<B> {
+        let protocols = match &config.protocol_fallback {
+            Some(fallback) => fallback.order.iter().copied().collect(),
+            None => VecDeque::new(),
+        };
+        let next_hop = match config.gateways.first() {
+            Some(first) if ip.is_ipv4() => IpAddr::V4(*first),
+            _ => ip,
+        };
+        let protocol = config.protocol;
+        let terminal = config.terminal_policy(protocol, ip);
+        let outstanding = Arc::new(Mutex::new(Outstanding::default()));
+        let (sender, events) = mpsc::channel();
+        let clock = backend.clock();
+        let receiver = ReplyReceiver::new(
+            config.clone(),
+            ip,
+            source,
+            outstanding.clone(),
+            sender,
+            metrics.clone(),
+            clock.clone(),
+        );
+        let mut machine = TraceMachine {
+            backend: Counted::new(backend, metrics.clone()),
+            sink,
+            ttl: config.begin_ttl,
+            order: Vec::new(),
+            turn: 0,
+            pending: BTreeMap::new(),
+            next_release: config.begin_ttl,
+            terminal_ttl: None,
+            terminal,
+            config,
+            protocols,
+            ip,
+            source,
+            next_hop,
+            identifier,
+            metrics,
+            clock,
+            receiver,
+            events,
+            outstanding,
+            seen: BTreeSet::new(),
+            sequence: 0,
+            last_responder: None,
+            tries: 0,
+            rejected: None,
+            send_retries: 0,
+            reruns: 0,
+            ttl_span: None,
+            warmed: None,
+            reached: false,
+            wait: None,
+            done: false,
+            lease: None,
+            late: BTreeMap::new(),
+            silent: Vec::new(),
+            passing: false,
+            shared: None,
+        };
+        if machine.config.second_pass {
+            machine.sink.hold_for_second_pass();
+        }
+        machine.sink.check_path(ip);
+        machine.shared = machine
+            .config
+            .shared_replies()
+            .map(|shared| shared.register(machine.receiver.clone()));
+        machine.begin(protocol);
+        machine
+    }
+
+    /// Sends the probes with the identifier and source ports of `lease`, held
+    /// until the machine is dropped.
+    pub fn with_lease(mut self, lease: Option<IdLease>) -> TraceMachine<B> {
+        self.lease = lease;
+        self
+    }
+
+    /// Sends the probes due and takes every reply received so far without waiting,
+    /// returns the next deadline on the system clock or None once the trace is over.
+    #[cfg(target_os = "linux")]
+    pub fn advance(&mut self) -> Option<Instant> {
+        loop {
+            let deadline = self.next_deadline()?;
+            let left = match deadline.checked_duration_since(self.clock.now()) {
+                Some(left) if !left.is_zero() => left,
+                _ => {
+                    self.receive(Ok(None));
+                    continue;
+                }
+            };
+            if self.shared.is_some() {
+                match self.events.try_recv() {
+                    Ok(event) => self.receive_event(event),
+                    Err(_) => return Some(Instant::now() + left),
+                }
+                continue;
+            }
+            match self.backend.recv_reply(Duration::from_millis(0)) {
+                Ok(None) => return Some(Instant::now() + left),
+                received => self.receive(received),
+            }
+        }
+    }
+
+    /// Returns the sockets the replies of the trace come in on, none when they
+    /// come from a shared receiver.
+    #[cfg(target_os = "linux")]
+    pub fn reply_fds(&self) -> Vec<libc::c_int> {
+        if self.shared.is_some() {
+            return Vec::new();
+        }
+        self.backend.reply_fds()
+    }
+
+    /// Sends the next probe if none is waiting, returns until when a reply is
+    /// waited for or None once the trace is over.
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        while !self.done {
+            if let Some((_, deadline)) = &self.wait {
+                return Some(*deadline);
+            }
+            self.probe();
+        }
+        None
+    }
+
+    /// Takes what the backend received, `Ok(None)` once the deadline has passed.
+    pub fn receive(&mut self, received: Received) {
+        if self.sink.is_cancelled() {
+            return self.cancel();
+        }
+        let span = self.span();
+        span.in_scope(|| {
+            match received {
+                Ok(None) => return self.expired(),
+                Ok(Some(received)) => self.receiver.take(&self.backend, Ok(received)),
+                Err(e) => self.receiver.take(&self.backend, Err(e)),
+            }
+            self.handle_events();
+        })
+    }
+
+    /// Takes an event the shared receiver classified for the trace.
+    fn receive_event(&mut self, event: Event) {
+        if self.sink.is_cancelled() {
+            return self.cancel();
+        }
+        let span = self.span();
+        span.in_scope(|| {
+            self.handle(event);
+            self.handle_events();
+        })
+    }
+
+    /// Takes the events of the receiver waiting on the channel.
+    fn handle_events(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            self.handle(event);
+        }
+    }
+
+    /// Blocks on the backend until the trace is over.
+    pub fn run(mut self) {
+        if let Some(window) = self.config.window {
+            return Pipeline::new(self, window).run();
+        }
+        self.drive();
+    }
+
+    /// Blocks on the backend until the trace is over, from wherever it stands.
+    fn drive(&mut self) {
+        while let Some(deadline) = self.next_deadline() {
+            let left = deadline
+                .checked_duration_since(self.clock.now())
+                .filter(|left| !left.is_zero());
+            match left {
+                Some(left) if self.shared.is_some() => match self.events.recv_timeout(left) {
+                    Ok(event) => self.receive_event(event),
+                    Err(_) => self.receive(Ok(None)),
+                },
+                Some(left) => {
+                    let received = self.backend.recv_reply(left);
+                    self.receive(received);
+                }
+                None => self.receive(Ok(None)),
+            }
+        }
+    }
+
+    /// Returns the span of the TTL being probed.
+    fn span(&self) -> Span {
+        match &self.ttl_span {
+            Some((_, span)) => span.clone(),
+            None => Span::none(),
+        }
+    }
+
+    /// Stops the trace without reporting its completion.
+    fn cancel(&mut self) {
+        debug!("trace cancelled at ttl {}", self.ttl);
+        self.ttl_span = None;
+        self.wait = None;
+        self.done = true;
+        self.metrics.finish();
+    }
+
+    /// Stops the trace with `error`, `TraceHandle::hops` ends with it.
+    fn fail(&mut self, error: TraceRouteError) {
+        self.sink.report(TraceEvent::Error(error));
+        self.ttl_span = None;
+        self.wait = None;
+        self.done = true;
+        self.metrics.finish();
+    }
+
+    /// Returns until when a probe sent at `sent_at` is waited for, the next wake
+    /// when the trace waits without timeout.
+    fn deadline(&self, sent_at: Instant) -> Instant {
+        sent_at + self.config.timeout.unwrap_or(WAKE)
+    }
+
+    /// Returns the round trip time of a probe sent at `sent_at` and answered at
+    /// `received_at`, less `TraceRouteConfig::rtt_offset`.
+    fn rtt(&self, sent_at: Instant, received_at: Instant) -> Duration {
+        received_at
+            .saturating_duration_since(sent_at)
+            .saturating_sub(self.config.rtt_offset)
+    }
+
+    /// Ends the wait for a reply that never came, or waits on without timeout.
+    fn expired(&mut self) {
+        let now = self.clock.now();
+        match &mut self.wait {
+            Some((Wait::Probe(_), deadline)) if self.config.timeout.is_none() => {
+                *deadline = now + WAKE;
+                return;
+            }
+            Some((Wait::Confirm { .. }, deadline))
+                if self
+                    .config
+                    .confirm_timeout
+                    .or(self.config.timeout)
+                    .is_none() =>
+            {
+                *deadline = now + WAKE;
+                return;
+            }
+            _ => {}
+        }
+        match self.wait.take() {
+            Some((Wait::Probe(sent), _)) => self.answered(sent, Outcome::Silent),
+            Some((Wait::Confirm { last, left }, _)) => self.confirm(*last, left),
+            Some((Wait::Grace, _)) => {
+                self.late.clear();
+                self.end_attempt();
+            }
+            Some((Wait::Backoff, _)) | None => {}
+        }
+    }
+
+    /// Takes an event of the receiver.
+    fn handle(&mut self, event: Event) {
+        if let Event::Malformed(malformed) = event {
+            let warning = TraceWarning::MalformedReply(malformed);
+            return self.sink.report(TraceEvent::Warning(warning));
+        }
+        if let Event::Reply(reply) = &event {
+            if self.corrects(reply) {
+                if self.late.is_empty() && matches!(self.wait, Some((Wait::Grace, _))) {
+                    self.wait = None;
+                    self.end_attempt();
+                }
+                return;
+            }
+        }
+        match self.wait.take() {
+            Some((Wait::Probe(sent), deadline)) => match self.replied(&sent, event) {
+                Some(outcome) => self.answered(sent, outcome),
+                None => self.wait = Some((Wait::Probe(sent), deadline)),
+            },
+            Some((Wait::Confirm { mut last, left }, deadline)) => match event {
+                Event::Reply(_) | Event::TcpAnswer if self.confirms(&event) => {
+                    last.completion = Some(CompletionReason::ReachedButFiltered);
+                    self.finish(*last);
+                }
+                Event::Failed(_) => self.confirm(*last, left),
+                _ => self.wait = Some((Wait::Confirm { last, left }, deadline)),
+            },
+            Some(wait @ (Wait::Backoff | Wait::Grace, _)) => self.wait = Some(wait),
+            None => {}
+        }
+    }
+
+    /// Starts an attempt with `protocol`.
+    fn begin(&mut self, protocol: TraceRouteProtocol) {
+        if let Some(fallback) = &self.config.protocol_fallback {
+            let probation = self.protocols.front().map(|_| fallback.give_up_after_hops);
+            self.sink.begin_attempt(protocol, probation);
+        }
+        let rerun_left = match &self.config.retry_on_total_failure {
+            Some(retry) => self.reruns < retry.attempts,
+            None => false,
+        };
+        self.sink.hold_silence(rerun_left);
+        self.config.protocol = protocol;
+        self.terminal = self.config.terminal_policy(protocol, self.ip);
+        self.seen.clear();
+        self.sequence = 0;
+        self.last_responder = None;
+        self.order = (self.config.begin_ttl..=self.config.max_ttl).collect();
+        if let TtlOrder::Shuffled { .. } = self.config.ttl_order {
+            self.order.shuffle(&mut rand::thread_rng());
+        }
+        self.turn = 0;
+        self.ttl = self.order[0];
+        self.pending.clear();
+        self.next_release = self.config.begin_ttl;
+        self.terminal_ttl = None;
+        self.tries = 0;
+        self.rejected = None;
+        self.send_retries = 0;
+        self.ttl_span = None;
+        self.warmed = None;
+        self.reached = false;
+        self.late.clear();
+        self.silent.clear();
+        self.passing = false;
+        *self.outstanding.lock().unwrap() = Outstanding {
+            registry: Default::default(),
+            monitor: FirstHopMonitor::new(
+                &self.config,
+                self.ip,
+                self.source,
+                self.metrics.clone(),
+                self.clock.now(),
+            ),
+            syn_sequences: Vec::new(),
+        };
+        self.wait = None;
+    }
+
+    /// Ends the attempt, and starts the next protocol if it was abandoned.
+    fn end_attempt(&mut self) {
+        self.ttl_span = None;
+        self.wait = None;
+        let protocol = self.config.protocol;
+        match (self.protocols.pop_front(), &self.config.protocol_fallback) {
+            (Some(next), Some(fallback)) if self.sink.abandoned() => {
+                logging::protocol_switched(protocol, next, fallback.give_up_after_hops);
+                self.begin(next);
+            }
+            _ => {
+                if self.config.second_pass && !self.passing && self.second_pass() {
+                    return;
+                }
+                let _ = self.sink.end_second_pass();
+                if let Some(until) = self.late_until() {
+                    self.wait = Some((Wait::Grace, until));
+                    return;
+                }
+                self.done = true;
+                self.metrics.finish();
+                self.sink.complete();
+            }
+        }
+    }
+
+    /// Returns until when late replies may still come for TTLs reported as
+    /// unanswered, None if none may.
+    fn late_until(&self) -> Option<Instant> {
+        let grace = self.config.correct_late_replies?;
+        let until = *self.late.values().max()? + grace;
+        Some(until).filter(|&until| until > self.clock.now())
+    }
+
+    /// Reports a late reply to a TTL given up on as a correction, returns false
+    /// if the reply is not one.
+    fn corrects(&mut self, reply: &Reply) -> bool {
+        let record = match (reply.parsed.key, &reply.record) {
+            (Some(_), Some(record)) => record,
+            _ => return false,
+        };
+        let given_up = match (self.config.correct_late_replies, self.late.get(&record.ttl)) {
+            (Some(grace), Some(&given_up)) => given_up + grace,
+            _ => return false,
+        };
+        if reply.received_at > given_up {
+            self.late.remove(&record.ttl);
+            return false;
+        }
+        if !matches!(
+            self.verdict(&reply.message, reply.from),
+            Some(Verdict::Answers)
+        ) {
+            return false;
+        }
+        self.late.remove(&record.ttl);
+        debug!("late reply from {} for ttl {}", reply.from, record.ttl);
+        self.sink.report(TraceEvent::HopCorrected(HopCorrection {
+            ttl: record.ttl,
+            addr: reply.from,
+            rtt: self.rtt(record.sent_at, reply.received_at),
+            previously: PreviousOutcome::Timeout,
+        }));
+        true
+    }
+
+    /// Remembers that `ttl` was reported as unanswered, for late replies to it
+    /// and the second pass.
+    fn gave_up(&mut self, ttl: u8) {
+        if self.config.correct_late_replies.is_some() {
+            self.late.insert(ttl, self.clock.now());
+        }
+        if self.config.second_pass {
+            self.silent.push(ttl);
+        }
+    }
+
+    /// Probes the TTLs reported as unanswered once more, below the destination of
+    /// a shuffled trace, returns false if there are none.
+    fn second_pass(&mut self) -> bool {
+        let end = self.terminal_ttl;
+        let mut order = mem::take(&mut self.silent);
+        order.retain(|&ttl| end.is_none_or(|end| ttl < end));
+        order.sort_unstable();
+        let first = match order.first() {
+            Some(&first) => first,
+            None => return false,
+        };
+        debug!("probing {} unanswered ttls again", order.len());
+        self.passing = true;
+        self.order = order;
+        self.turn = 0;
+        self.ttl = first;
+        self.tries = 0;
+        self.rejected = None;
+        self.send_retries = 0;
+        self.probe();
+        true
+    }
+
+    /// Sends the probe of the current TTL, or ends the trace once every TTL was
+    /// probed.
+    fn probe(&mut self) {
+        if self.turn >= self.order.len() {
+            return self.conclude();
+        }
+        if self.ttl_span.as_ref().map(|(ttl, _)| *ttl) != Some(self.ttl) {
+            self.ttl_span = Some((self.ttl, logging::ttl_span(self.ttl)));
+        }
+        self.span().in_scope(|| self.send_probe())
+    }
+
+    fn send_probe(&mut self) {
+        let (ttl, tries) = (self.ttl, self.tries);
+        let warming = self.config.warmup && tries == 0 && self.warmed != Some(ttl);
+        match self.transmit(ttl, tries, warming) {
+            Ok(sent) => {
+                let deadline = self.deadline(sent.sent_at);
+                self.wait = Some((Wait::Probe(sent), deadline));
+            }
+            Err(Unsent::Later(e)) => self.unsent(e),
+            Err(Unsent::Failed) => {}
+        }
+    }
+
+    /// Sends the probe of `ttl` for the `tries + 1`th time and reports it, ends
+    /// the trace as failed when it could not be built or sent for good.
+    fn transmit(&mut self, ttl: u8, tries: u16, warming: bool) -> Result<Sent, Unsent> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let port = probe_port(&self.config, ttl, tries);
+        let probe = match self.build_probe(ttl, tries, port) {
+            Ok(probe) => probe,
+            Err(e) => {
+                warn!("could not build the probe for ttl {}: {}", ttl, e);
+                self.fail(e);
+                return Err(Unsent::Failed);
+            }
+        };
+        let packets = if self.config.fragment {
+            fragment_probe(&probe, random())
+        } else {
+            vec![probe]
+        };
+        let mut outstanding = self.outstanding.lock().unwrap();
+        let sent_at = match send_probes(
+            &mut self.backend,
+            &mut outstanding.monitor,
+            &*self.clock,
+            &packets,
+            self.next_hop,
+        ) {
+            Ok(sent_at) => sent_at,
+            Err(e) if backend::is_transient(&e) => return Err(Unsent::Later(e)),
+            Err(e) => {
+                warn!("could not send the probe for ttl {}: {}", ttl, e);
+                drop(outstanding);
+                self.fail(TraceRouteError::Send {
+                    kind: e.kind(),
+                    message: e.to_string(),
+                });
+                return Err(Unsent::Failed);
+            }
+        };
+        if warming {
+            self.metrics.warmup_sent();
+        } else {
+            self.metrics.probing(ttl, tries + 1);
+            logging::probe_sent(ttl, tries + 1);
+        }
+        // Routers quote the first fragment, it stands for the probe.
+        let probe = packets.into_iter().next().unwrap();
+        let protocol = self.config.protocol;
+        self.sink.report(TraceEvent::ProbeSent(ProbeSent {
+            ttl,
+            attempt: tries + 1,
+            protocol,
+            port: match protocol {
+                TraceRouteProtocol::Udp | TraceRouteProtocol::Dccp => Some(port),
+                _ => None,
+            },
+            warmup: warming,
+            token: ProbeToken::of(&probe),
+        }));
+        register(&mut outstanding, &probe, ttl, sent_at);
+        Ok(Sent {
+            probe,
+            port,
+            warming,
+            sent_at,
+        })
+    }
+
+    /// Counts a probe that could not be sent for now as a try, and sends the next
+    /// try after a backoff or gives the TTL up.
+    fn unsent(&mut self, e: io::Error) {
+        debug!(
+            "sending the probe for ttl {} failed for now: {}",
+            self.ttl, e
+        );
+        self.send_retries += 1;
+        self.tries += 1;
+        if self.tries >= self.config.max_tries {
+            return self.give_up();
+        }
+        let backoff = SEND_BACKOFF * 2u32.pow(u32::from(self.send_retries.min(8)) - 1);
+        self.metrics.pause();
+        self.wait = Some((Wait::Backoff, self.clock.now() + backoff));
+    }
+
+    /// Returns the sequence of the echo probe just counted, sent to `ttl` after
+    /// `tries` others.
+    fn echo_sequence(&self, ttl: u8, tries: u16) -> u16 {
+        let count = self.sequence.wrapping_sub(1);
+        self.config.sequence_plan.sequence(count, ttl, tries + 1)
+    }
+
+    fn build_probe(&self, ttl: u8, tries: u16, port: u16) -> Result<Vec<u8>, TraceRouteError> {
+        let (ip, size) = (self.ip, self.config.size);
+        let (identifier, sequence) = (self.identifier, self.sequence);
+        let source_port = match &self.lease {
+            Some(lease) => lease.range().port(sequence),
+            None => random::<u16>(),
+        };
+        let carries_port = matches!(
+            self.config.protocol,
+            TraceRouteProtocol::Udp | TraceRouteProtocol::Dccp
+        );
+        if port == 0 && carries_port {
+            return Err(TraceRouteError::ZeroPort {
+                ttl,
+                attempt: tries + 1,
+            });
+        }
+        let probe = match (self.source, self.config.protocol) {
+            (IpAddr::V4(source), TraceRouteProtocol::Udp) => {
+                build_udp_v4(ip, size, port, ttl, source, source_port)
+            }
+            (IpAddr::V4(source), TraceRouteProtocol::Dccp) => build_dccp_v4(
+                ip,
+                port,
+                ttl,
+                source,
+                source_port,
+                dccp_sequence(identifier, sequence),
+            ),
+            (IpAddr::V4(source), TraceRouteProtocol::Raw(number)) => {
+                build_raw_v4(ip, number, size, ttl, source, identifier, sequence)
+            }
+            (IpAddr::V4(source), TraceRouteProtocol::Icmp) => build_icmp_v4(
+                ip,
+                64,
+                ttl,
+                source,
+                identifier,
+                self.echo_sequence(ttl, tries),
+            ),
+            (IpAddr::V6(source), TraceRouteProtocol::Udp) => {
+                build_udp_v6(ip, size, port, ttl, source, source_port)
+            }
+            (IpAddr::V6(source), TraceRouteProtocol::Dccp) => build_dccp_v6(
+                ip,
+                port,
+                ttl,
+                source,
+                source_port,
+                dccp_sequence(identifier, sequence),
+            ),
+            (IpAddr::V6(source), TraceRouteProtocol::Raw(number)) => {
+                build_raw_v6(ip, number, size, ttl, source, identifier, sequence)
+            }
+            (IpAddr::V6(source), TraceRouteProtocol::Icmp) => build_icmp_v6(
+                ip,
+                64,
+                ttl,
+                source,
+                identifier,
+                self.echo_sequence(ttl, tries),
+            ),
+        };
+        let probe = match (self.source, self.config.extension_header) {
+            (IpAddr::V4(_), _) => probe.and_then(|probe| add_options_v4(probe, &self.config)),
+            (IpAddr::V6(_), Some(kind)) => probe.and_then(|probe| insert_extension_v6(probe, kind)),
+            (IpAddr::V6(_), None) => probe,
+        };
+        probe.map(|probe| with_dscp(probe, self.config.dscp))
+    }
+
+    /// Tells what an event of the receiver means for the wait for a reply to
+    /// `sent`, None if the wait goes on.
+    fn replied(&mut self, sent: &Sent, event: Event) -> Option<Outcome> {
+        let reply = match event {
+            Event::Reply(reply) => reply,
+            Event::Transport {
+                message,
+                raw_reply,
+                received_at,
+                timestamp_source,
+            } => {
+                if sent.warming {
+                    return Some(Outcome::Silent);
+                }
+                let rtt = self.rtt(sent.sent_at, received_at);
+                let mut hop = HopFound::new(self.ttl, Some(self.ip), self.tries, true, Some(rtt));
+                hop.timestamp_source = Some(timestamp_source);
+                hop.raw_reply = raw_reply;
+                hop.reply_len = Some(message.len());
+                hop.port = port_of(&self.config, None, sent.port);
+                hop.probe = ProbeToken::of(&sent.probe);
+                if self.destination_found(hop) {
+                    return Some(Outcome::Advanced);
+                }
+                return Some(Outcome::Stopped);
+            }
+            Event::Failed(e) => return Some(Outcome::Failed(e)),
+            Event::Malformed(_) | Event::TcpAnswer => return None,
+        };
+        let found = reply.parsed.key.map(|_| reply.record.as_ref());
+        // A hop objecting to the probe may have answered an earlier TTL already.
+        let pointer =
+            reply::parameter_problem_pointer(&reply.message, self.ip.is_ipv6()).filter(|_| {
+                let (icmp_type, code) = (reply.parsed.icmp_type, reply.parsed.code);
+                !self.terminal.ends(icmp_type, code, reply.from == self.ip)
+            });
+        let attribution = attribute(found, self.ttl);
+        if let (Attribution::Current, Some(pointer)) = (attribution, pointer) {
+            let quoted = if self.ip.is_ipv4() {
+                reply::quoted_v4(&reply.message)
+            } else {
+                reply::quoted_v6(&reply.message)
+            };
+            return Some(Outcome::Rejected(ParameterProblem {
+                from: reply.from,
+                pointer,
+                quoted: quoted.unwrap_or_default().to_vec(),
+            }));
+        }
+        // The end of a shuffled trace answers again at any lower TTL probed later,
+        // and behind a middlebox rewriting TTLs it may have answered as a router.
+        let repeated = !self.passing
+            && self.seen.contains(&reply.from)
+            && !self.reached
+            && reply.from != self.ip;
+        match attribution {
+            Attribution::Foreign => self.metrics.foreign_reply(),
+            _ if repeated => self.metrics.duplicate_reply(),
+            Attribution::Stale => self.metrics.stale_reply(),
+            Attribution::Current => match self.verdict(&reply.message, reply.from) {
+                Some(Verdict::Blocked) => return Some(Outcome::Silent),
+                Some(Verdict::Answers) => return Some(Outcome::Answered(reply)),
+                verdict => self.unanswered(*reply, verdict),
+            },
+        }
+        None
+    }
+
+    /// Counts a reply of this trace that answers no probe, and warns about it if
+    /// its kind is unexpected.
+    fn unanswered(&mut self, reply: Reply, verdict: Option<Verdict>) {
+        self.metrics.foreign_reply();
+        if let Some(Verdict::Unexpected(kind)) = verdict {
+            report_unexpected(kind, reply.raw_reply.clone());
+            let warning = TraceWarning::UnexpectedReply {
+                from: reply.from,
+                icmp_type: reply.parsed.icmp_type,
+                code: reply.parsed.code,
+                raw_reply: reply.raw_reply,
+            };
+            self.sink.report(TraceEvent::Warning(warning));
+        }
+    }
+
+    /// Tells what a reply to the current probe from `addr` means, None if it is
+    /// too short to tell.
+    fn verdict(&self, bytes: &[u8], addr: IpAddr) -> Option<Verdict> {
+        let (config, ip) = (&self.config, self.ip);
+        if ip.is_ipv4() {
+            let packet = icmp::IcmpPacket::new(bytes)?;
+            let kind = packet.get_icmp_type();
+            Some(
+                if is_blocked(config, kind == icmp::IcmpType::new(3), addr, ip) {
+                    Verdict::Blocked
+                } else if (kind == icmp::IcmpType::new(11) && addr != ip)
+                    || self
+                        .terminal
+                        .ends(kind.0, packet.get_icmp_code().0, addr == ip)
+                {
+                    Verdict::Answers
+                } else if kind == icmp::IcmpTypes::EchoReply {
+                    Verdict::Foreign
+                } else {
+                    Verdict::Unexpected(Box::new(kind))
+                },
+            )
+        } else {
+            let packet = icmpv6::Icmpv6Packet::new(bytes)?;
+            let kind = packet.get_icmpv6_type();
+            let unreachable = kind == Icmpv6Types::DestinationUnreachable;
+            Some(if is_blocked(config, unreachable, addr, ip) {
+                Verdict::Blocked
+            } else if (kind == Icmpv6Types::TimeExceeded && addr != ip)
+                || self
+                    .terminal
+                    .ends(kind.0, packet.get_icmpv6_code().0, addr == ip)
+            {
+                Verdict::Answers
+            } else if kind == Icmpv6Types::EchoReply {
+                Verdict::Foreign
+            } else {
+                Verdict::Unexpected(Box::new(kind))
+            })
+        }
+    }
+
+    /// Reports what a wait for a reply to a probe ended with and moves on.
+    fn answered(&mut self, sent: Sent, outcome: Outcome) {
+        if sent.warming {
+            // Whatever answered, the measured probes of this TTL follow.
+            self.warmed = Some(self.ttl);
+            if let Some(key) = reply::sent_key(&sent.probe) {
+                self.outstanding.lock().unwrap().registry.remove(&key);
+            }
+            return;
+        }
+        match outcome {
+            Outcome::Advanced => return self.next_ttl(),
+            Outcome::Stopped => return self.end_attempt(),
+            Outcome::Answered(reply) => return self.reply_found(*reply, &sent),
+            Outcome::Failed(e) => {
+                warn!("receiving replies for ttl {} failed: {}", self.ttl, e);
+                let warning = TraceWarning::ReceiveFailed {
+                    kind: e.kind(),
+                    message: e.to_string(),
+                };
+                self.sink.report(TraceEvent::Warning(warning));
+            }
+            Outcome::Silent => {
+                logging::timed_out(self.ttl, self.tries + 1);
+                self.metrics.timed_out();
+            }
+            Outcome::Rejected(problem) => {
+                logging::parameter_problem(self.ttl, &problem);
+                let count = self.rejected.take().map_or(0, |(count, _)| count);
+                self.rejected = Some((count + 1, problem.clone()));
+                let warning = TraceWarning::ParameterProblem {
+                    ttl: self.ttl,
+                    problem,
+                };
+                self.sink.report(TraceEvent::Warning(warning));
+            }
+        }
+        self.tries += 1;
+        if self.tries >= self.config.max_tries {
+            self.give_up();
+        }
+    }
+
+    /// Reports the current TTL as unanswered once all its tries are used up.
+    fn give_up(&mut self) {
+        debug!("giving up on ttl {} after {} tries", self.ttl, self.tries);
+        if self.passing {
+            return self.next_ttl();
+        }
+        self.gave_up(self.ttl);
+        let mut hop = HopFound::timed_out(self.ttl, self.tries);
+        hop.beyond_destination = self.reached;
+        hop.send_retries = self.send_retries;
+        hop.parameter_problem = match self.rejected.take() {
+            Some((count, problem)) if count == self.tries => Some(problem),
+            _ => None,
+        };
+        if self.deliver(hop).is_err() {
+            return self.end_attempt();
+        }
+        self.next_ttl();
+    }
+
+    /// Runs the trace again after `delay`, dropping the silent hops of this run.
+    fn rerun(&mut self, delay: Duration) {
+        self.reruns += 1;
+        debug!("no ttl answered, running again in {:?}", delay);
+        self.sink.report(TraceEvent::Retrying(TraceRetry {
+            attempt: self.reruns,
+            delay,
+        }));
+        self.begin(self.config.protocol);
+        self.metrics.pause();
+        self.wait = Some((Wait::Backoff, self.clock.now() + delay));
+    }
+
+    /// Reports the hop that answered the probe of the current TTL.
+    fn reply_found(&mut self, reply: Reply, sent: &Sent) {
+        let mut hop = self.hop_of(reply, sent, self.ttl, self.tries);
+        hop.send_retries = self.send_retries;
+        if self.passing {
+            self.sink.fill_in(hop);
+            return self.next_ttl();
+        }
+        if !hop.is_last {
+            hop.beyond_destination = self.reached;
+            if self.deliver(hop).is_err() {
+                return self.end_attempt();
+            }
+            return self.next_ttl();
+        }
+        if self.destination_found(hop) {
+            return self.next_ttl();
+        }
+        self.end_attempt()
+    }
+
+    /// Returns the hop that answered `sent`, the probe of `ttl` after `tries`
+    /// unanswered ones, the last one unless it reports a time exceeded.
+    fn hop_of(&mut self, reply: Reply, sent: &Sent, ttl: u8, tries: u16) -> HopFound {
+        let (addr, parsed) = (reply.from, reply.parsed);
+        self.seen.insert(addr);
+        let v4 = self.ip.is_ipv4();
+        let time_exceeded = if v4 {
+            parsed.icmp_type == 11
+        } else {
+            parsed.icmp_type == 3 && addr != self.ip
+        };
+        let time_exceeded_type = if v4 { 11 } else { 3 };
+        if addr == self.ip && parsed.icmp_type == time_exceeded_type {
+            let anomaly = PathAnomaly::TimeExceededFromDestination { ttl };
+            self.sink
+                .report(TraceEvent::Warning(TraceWarning::PathAnomaly(anomaly)));
+        }
+        let mut hop = HopFound::new(
+            ttl,
+            Some(addr),
+            tries,
+            !time_exceeded,
+            Some(self.rtt(sent.sent_at, reply.received_at)),
+        );
+        hop.timestamp_source = Some(reply.timestamp_source);
+        fill_from_options(&mut hop, &reply.options);
+        hop.incoming_interface = parsed.incoming_interface;
+        hop.reply_len = Some(reply.message.len());
+        hop.quoted_len = parsed.quoted_len;
+        hop.next_hop_mtu = parsed.next_hop_mtu;
+        hop.interface_hint = reply::interface_hint(&reply.message, addr);
+        hop.mangling = reply.mangling;
+        hop.port = port_of(&self.config, parsed.key, sent.port);
+        hop.raw_reply = reply.raw_reply;
+        // An earlier try of the TTL may be the one answered.
+        hop.probe = reply
+            .record
+            .as_ref()
+            .and_then(|record| ProbeToken::of(&record.sent))
+            .or_else(|| ProbeToken::of(&sent.probe));
+        hop
+    }
+
+    /// Reports the hop of the destination, returns true if the trace goes on.
+    ///
+    /// A shuffled trace forgets the hops above the TTL the destination answered
+    /// at and stops probing them, the TTLs below it are still probed unless it
+    /// stops at the destination.
+    fn destination_found(&mut self, hop: HopFound) -> bool {
+        if self.passing {
+            self.sink.fill_in(hop);
+            return true;
+        }
+        let stop_at_destination = match self.config.ttl_order {
+            TtlOrder::Sequential => {
+                return destination_answered(&mut self.sink, &self.config, hop, &mut self.reached)
+            }
+            TtlOrder::Shuffled {
+                stop_at_destination,
+            } => stop_at_destination,
+        };
+        self.terminal_ttl = Some(self.ttl);
+        self.drop_above(self.ttl);
+        if self.deliver(hop).is_err() {
+            return false;
+        }
+        if stop_at_destination {
+            let _ = self.release_all();
+            return false;
+        }
+        true
+    }
+
+    /// Reports a hop, a shuffled trace holds it back until the hops of the TTLs
+    /// below it were reported.
+    fn deliver(&mut self, hop: HopFound) -> Result<(), Disconnected> {
+        if self.config.ttl_order == TtlOrder::Sequential {
+            return self.release(hop);
+        }
+        self.pending.insert(hop.hop_count, hop);
+        while let Some(hop) = self.pending.remove(&self.next_release) {
+            self.next_release = self.next_release.saturating_add(1);
+            self.release(hop)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the hops held back from `ttl` on, above the destination.
+    fn drop_above(&mut self, ttl: u8) {
+        for hop in self.pending.split_off(&ttl).values() {
+            self.sink.passed_over(hop);
+        }
+    }
+
+    /// Reports every hop held back.
+    fn release_all(&mut self) -> Result<(), Disconnected> {
+        for (_, hop) in mem::take(&mut self.pending) {
+            self.release(hop)?;
+        }
+        Ok(())
+    }
+
+    fn release(&mut self, hop: HopFound) -> Result<(), Disconnected> {
+        if let (Some(addr), false) = (hop.addr, hop.is_last) {
+            self.last_responder = Some(addr);
+        }
+        self.sink.send(hop)
+    }
+
+    /// Moves on to the next TTL of the order, skipping the ones above the
+    /// destination.
+    fn next_ttl(&mut self) {
+        self.turn += 1;
+        while let Some(&ttl) = self.order.get(self.turn) {
+            if self.terminal_ttl.is_none_or(|end| ttl < end) {
+                break;
+            }
+            self.turn += 1;
+        }
+        self.ttl = match self.order.get(self.turn) {
+            Some(&ttl) => ttl,
+            None => self.config.max_ttl.saturating_add(1),
+        };
+        self.tries = 0;
+        self.rejected = None;
+        self.send_retries = 0;
+    }
+
+    /// Reports the terminal hop once every TTL was probed.
+    fn conclude(&mut self) {
+        self.ttl_span = None;
+        if self.passing {
+            return self.end_attempt();
+        }
+        if self.release_all().is_err() || self.terminal_ttl.is_some() {
+            return self.end_attempt();
+        }
+        if let Some(retry) = &self.config.retry_on_total_failure {
+            if self.seen.is_empty() && self.reruns < retry.attempts {
+                return self.rerun(retry.delay);
+            }
+        }
+        let mut last = HopFound::end_of_trace(self.ttl, self.tries, CompletionReason::NotReached);
+        if let TraceRouteProtocol::Raw(_) = self.config.protocol {
+            last.completion = Some(CompletionReason::NoTerminalSignal);
+        } else if self.ip.is_ipv4() && !self.config.gateways.is_empty() {
+            last.completion = Some(CompletionReason::NotReachedSourceRouted);
+        }
+        if self.reached {
+            last.completion = Some(CompletionReason::Reached);
+            last.beyond_destination = true;
+        } else if needs_confirmation(&self.config, self.last_responder, self.ip) {
+            return self.confirm(last, self.config.confirm_probes);
+        }
+        self.finish(last)
+    }
+
+    /// Sends an echo probe to a destination that stayed silent, with a TCP SYN if
+    /// the trace confirms by TCP too, `left` may still be sent.
+    ///
+    /// The echo probes share the identifier of the trace, so any echo reply from the
+    /// destination carrying it confirms the destination is up.
+    fn confirm(&mut self, mut last: HopFound, left: u16) {
+        if left == 0 {
+            if self.config.confirm_probes > 0 {
+                last.completion = Some(CompletionReason::DestinationUnresponsive);
+            }
+            return self.finish(last);
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        let (ip, ttl, identifier) = (self.ip, self.config.max_ttl, self.identifier);
+        let sequence = self.echo_sequence(ttl, self.config.confirm_probes - left);
+        let probe = match self.source {
+            IpAddr::V4(source) => build_icmp_v4(ip, 64, ttl, source, identifier, sequence),
+            IpAddr::V6(source) => build_icmp_v6(ip, 64, ttl, source, identifier, sequence),
+        };
+        let sent_at = self.clock.now();
+        let probe = match probe {
+            Ok(probe) if self.backend.send_to(&probe, ip).is_ok() => probe,
+            _ => return self.finish(last),
+        };
+        register(&mut self.outstanding.lock().unwrap(), &probe, ttl, sent_at);
+        if let (Some(port), IpAddr::V4(source), IpAddr::V4(v4)) =
+            (self.config.confirm_tcp(ip), self.source, ip)
+        {
+            let source_port = match &self.lease {
+                Some(lease) => lease.range().port(self.sequence),
+                None => random::<u16>(),
+            };
+            let tcp_sequence = u32::from(identifier) << 16 | u32::from(sequence);
+            if let Ok(syn) = build_tcp_syn_v4(v4, port, source, source_port, tcp_sequence) {
+                // The echo probe alone may still confirm the destination.
+                if self.backend.send_to(&syn, ip).is_ok() {
+                    let mut outstanding = self.outstanding.lock().unwrap();
+                    outstanding.syn_sequences.push(tcp_sequence);
+                }
+            }
+        }
+        let timeout = self.config.confirm_timeout.or(self.config.timeout);
+        let deadline = sent_at + timeout.unwrap_or(WAKE);
+        self.wait = Some((
+            Wait::Confirm {
+                last: Box::new(last),
+                left: left - 1,
+            },
+            deadline,
+        ));
+    }
+
+    /// Returns true for an echo reply of the destination to a confirmation probe,
+    /// or an answer to its TCP SYN.
+    fn confirms(&self, event: &Event) -> bool {
+        let reply = match event {
+            Event::Reply(reply) => reply,
+            Event::TcpAnswer => return true,
+            _ => return false,
+        };
+        let echo_reply = if self.ip.is_ipv4() { 0 } else { 129 };
+        let ours = matches!(reply.parsed.key, Some(reply::ProbeKey::Echo { identifier, .. }) if identifier == self.identifier);
+        reply.from == self.ip && reply.parsed.icmp_type == echo_reply && ours
+    }
+
+    fn finish(&mut self, last: HopFound) {
+        let _ = self.sink.send(last);
+        self.end_attempt();
+    }
+}
+
+/// This struct is a TTL of a pipelined trace waiting for a reply, or for the
+/// backoff after its probe could not be sent.
+struct Flight {
+    sent: Option<Sent>,
+    tries: u16,
+    send_retries: u16,
+    deadline: Instant,
+}
+
+/// This struct probes up to `window` TTLs of a trace at once, see
+/// `TraceRouteConfig::window`.
+///
+/// It drives the probes and replies of the TTLs in flight itself and leans on
+/// the `TraceMachine` it was made from for everything else: building, sending
+/// and matching probes, holding the hops of higher TTLs back until the lower
+/// ones are reported, and concluding the trace. A reply or the timeout of the
+/// lowest TTLs makes room for the next ones, with a window of 1 the probes go
+/// out exactly as those of a sequential trace.
+struct Pipeline<B: ProbeBackend> {
+    machine: TraceMachine<B>,
+    window: usize,
+    flights: BTreeMap<u8, Flight>,
+    /// Next TTL to put in flight, one past `max_ttl` once all were.
+    next_ttl: u16,
+}
+
+impl<B: ProbeBackend> Pipeline<B> {
+    /// Creates new Pipeline from a machine that has not sent a probe yet.
+    ///
+    /// Its configuration passed `TraceRouteConfig::check_options`, which leaves
+    /// out the options only a sequential trace has.
+    fn new(machine: TraceMachine<B>, window: u8) -> Pipeline<B> {
+        let next_ttl = u16::from(machine.config.begin_ttl);
+        Pipeline {
+            machine,
+            window: usize::from(window.max(1)),
+            flights: BTreeMap::new(),
+            next_ttl,
+        }
+    }
+
+    /// Blocks on the backend until the trace is over.
+    fn run(mut self) {
+        loop {
+            if self.machine.sink.is_cancelled() {
+                return self.machine.cancel();
+            }
+            self.fill();
+            if self.machine.done {
+                return;
+            }
+            if self.machine.wait.is_some() {
+                // Only the grace for late replies is left.
+                return self.machine.drive();
+            }
+            let deadline = match self.flights.values().map(|flight| flight.deadline).min() {
+                Some(deadline) => deadline,
+                None => return self.conclude(),
+            };
+            let machine = &mut self.machine;
+            let left = deadline
+                .checked_duration_since(machine.clock.now())
+                .filter(|left| !left.is_zero());
+            match left {
+                Some(left) if machine.shared.is_some() => match machine.events.recv_timeout(left) {
+                    Ok(event) => self.handle(event),
+                    Err(_) => self.expired(),
+                },
+                Some(left) => match machine.backend.recv_reply(left) {
+                    Ok(None) => self.expired(),
+                    Ok(Some(received)) => machine.receiver.take(&machine.backend, Ok(received)),
+                    Err(e) => machine.receiver.take(&machine.backend, Err(e)),
+                },
+                None => self.expired(),
+            }
+            while let Ok(event) = self.machine.events.try_recv() {
+                self.handle(event);
+            }
+        }
+    }
+
+    /// Puts the next TTLs in flight while the window has room, none above the
+    /// destination.
+    fn fill(&mut self) {
+        while self.flights.len() < self.window && !self.machine.done {
+            let end = match self.machine.terminal_ttl {
+                Some(end) => u16::from(end),
+                None => u16::from(self.machine.config.max_ttl) + 1,
+            };
+            if self.next_ttl >= end {
+                return;
+            }
+            let ttl = self.next_ttl as u8;
+            self.next_ttl += 1;
+            self.send(ttl, 0, 0);
+        }
+    }
+
+    /// Sends the probe of `ttl` after `tries` unanswered ones, or backs off if it
+    /// could not be sent for now.
+    fn send(&mut self, ttl: u8, tries: u16, send_retries: u16) {
+        let flight = match self.machine.transmit(ttl, tries, false) {
+            Ok(sent) => Flight {
+                deadline: self.machine.deadline(sent.sent_at),
+                sent: Some(sent),
+                tries,
+                send_retries,
+            },
+            Err(Unsent::Failed) => return,
+            Err(Unsent::Later(e)) => {
+                debug!("sending the probe for ttl {} failed for now: {}", ttl, e);
+                let (tries, send_retries) = (tries + 1, send_retries + 1);
+                if tries >= self.machine.config.max_tries {
+                    return self.give_up(ttl, tries, send_retries);
+                }
+                let backoff = SEND_BACKOFF * 2u32.pow(u32::from(send_retries.min(8)) - 1);
+                Flight {
+                    sent: None,
+                    tries,
+                    send_retries,
+                    deadline: self.machine.clock.now() + backoff,
+                }
+            }
+        };
+        self.flights.insert(ttl, flight);
+    }
+
+    /// Sends the probes whose backoff ended again and counts the ones whose
+    /// reply did not come in time as unanswered.
+    fn expired(&mut self) {
+        let now = self.machine.clock.now();
+        let due: Vec<u8> = self
+            .flights
+            .iter()
+            .filter(|(_, flight)| flight.deadline <= now)
+            .map(|(&ttl, _)| ttl)
+            .collect();
+        for ttl in due {
+            if self.machine.done {
+                return;
+            }
+            if self.machine.config.timeout.is_none() {
+                if let Some(flight) = self.flights.get_mut(&ttl).filter(|f| f.sent.is_some()) {
+                    flight.deadline = now + WAKE;
+                    continue;
+                }
+            }
+            if let Some(flight) = self.flights.remove(&ttl) {
+                match flight.sent {
+                    Some(_) => self.unanswered(ttl, flight),
+                    None => self.send(ttl, flight.tries, flight.send_retries),
+                }
+            }
+        }
+    }
+
+    /// Counts a try of `ttl` as unanswered, and sends the next one or gives the
+    /// TTL up.
+    fn unanswered(&mut self, ttl: u8, flight: Flight) {
+        logging::timed_out(ttl, flight.tries + 1);
+        self.machine.metrics.timed_out();
+        let tries = flight.tries + 1;
+        if tries >= self.machine.config.max_tries {
+            return self.give_up(ttl, tries, flight.send_retries);
+        }
+        self.send(ttl, tries, flight.send_retries);
+    }
+
+    /// Reports `ttl` as unanswered once all its tries are used up.
+    fn give_up(&mut self, ttl: u8, tries: u16, send_retries: u16) {
+        debug!("giving up on ttl {} after {} tries", ttl, tries);
+        self.machine.gave_up(ttl);
+        let mut hop = HopFound::timed_out(ttl, tries);
+        hop.send_retries = send_retries;
+        self.found(hop);
+    }
+
+    /// Takes an event of the receiver.
+    fn handle(&mut self, event: Event) {
+        let reply = match event {
+            Event::Reply(reply) => reply,
+            Event::Transport {
+                message,
+                raw_reply,
+                received_at,
+                timestamp_source,
+            } => return self.transport(message, raw_reply, received_at, timestamp_source),
+            Event::Failed(e) => {
+                warn!("receiving replies failed: {}", e);
+                let warning = TraceWarning::ReceiveFailed {
+                    kind: e.kind(),
+                    message: e.to_string(),
+                };
+                return self.machine.sink.report(TraceEvent::Warning(warning));
+            }
+            Event::Malformed(malformed) => {
+                let warning = TraceWarning::MalformedReply(malformed);
+                return self.machine.sink.report(TraceEvent::Warning(warning));
+            }
+            // Pipelined traces send no confirmation probes.
+            Event::TcpAnswer => return,
+        };
+        let machine = &mut self.machine;
+        if machine.corrects(&reply) {
+            return;
+        }
+        // A reply without a key can not be told apart, it is taken for the lowest
+        // TTL in flight.
+        let ttl = match reply.parsed.key.map(|_| reply.record.as_ref()) {
+            Some(Some(record)) => record.ttl,
+            Some(None) => return machine.metrics.foreign_reply(),
+            None => match self.flights.keys().next() {
+                Some(&ttl) => ttl,
+                None => return machine.metrics.stale_reply(),
+            },
+        };
+        let repeated = machine.seen.contains(&reply.from) && reply.from != machine.ip;
+        if repeated {
+            return machine.metrics.duplicate_reply();
+        }
+        let waiting = self.flights.get(&ttl).map(|flight| flight.sent.is_some());
+        if waiting != Some(true) {
+            return machine.metrics.stale_reply();
+        }
+        let sent = self.flights.remove(&ttl).unwrap();
+        match machine.verdict(&reply.message, reply.from) {
+            Some(Verdict::Blocked) => self.unanswered(ttl, sent),
+            Some(Verdict::Answers) => {
+                let probe = sent.sent.as_ref().unwrap();
+                let mut hop = machine.hop_of(*reply, probe, ttl, sent.tries);
+                hop.send_retries = sent.send_retries;
+                self.found(hop);
+            }
+            verdict => {
+                machine.unanswered(*reply, verdict);
+                self.flights.insert(ttl, sent);
+            }
+        }
+    }
+
+    /// Takes the answer of the destination to a DCCP probe, for the lowest TTL in
+    /// flight as it carries no key.
+    fn transport(
+        &mut self,
+        message: Vec<u8>,
+        raw_reply: Option<Vec<u8>>,
+        received_at: Instant,
+        timestamp_source: TimestampSource,
+    ) {
+        let ttl = match self
+            .flights
+            .iter()
+            .find(|(_, flight)| flight.sent.is_some())
+        {
+            Some((&ttl, _)) => ttl,
+            None => return,
+        };
+        let flight = self.flights.remove(&ttl).unwrap();
+        let sent = flight.sent.as_ref().unwrap();
+        let machine = &self.machine;
+        let rtt = machine.rtt(sent.sent_at, received_at);
+        let mut hop = HopFound::new(ttl, Some(machine.ip), flight.tries, true, Some(rtt));
+        hop.timestamp_source = Some(timestamp_source);
+        hop.raw_reply = raw_reply;
+        hop.reply_len = Some(message.len());
+        hop.port = port_of(&machine.config, None, sent.port);
+        hop.probe = ProbeToken::of(&sent.probe);
+        hop.send_retries = flight.send_retries;
+        self.found(hop);
+    }
+
+    /// Holds the hop of a resolved TTL back until the ones below it are reported.
+    ///
+    /// The destination answering drops the TTLs above it, in flight or resolved.
+    fn found(&mut self, hop: HopFound) {
+        let machine = &mut self.machine;
+        let ttl = hop.hop_count;
+        if hop.is_last {
+            machine.terminal_ttl = Some(ttl);
+            machine.drop_above(ttl);
+            self.flights.split_off(&ttl);
+        }
+        machine.pending.insert(ttl, hop);
+        while let Some(hop) = machine.pending.remove(&machine.next_release) {
+            machine.next_release = machine.next_release.saturating_add(1);
+            let last = hop.is_last;
+            if machine.release(hop).is_err() || last {
+                self.flights.clear();
+                return machine.end_attempt();
+            }
+        }
+    }
+
+    /// Ends the trace once no TTL is left in flight.
+    fn conclude(&mut self) {
+        let machine = &mut self.machine;
+        machine.ttl = machine.config.max_ttl.saturating_add(1);
+        machine.tries = 0;
+        machine.conclude();
+    }
+}
+
+/// Remembers a probe sent with `ttl`, replies to it belong to the trace.
+fn register(outstanding: &mut Outstanding, probe: &[u8], ttl: u8, sent_at: Instant) {
+    if let Some(key) = reply::sent_key(probe) {
+        outstanding.registry.insert(
+            key,
+            ProbeRecord {
+                ttl,
+                sent_at,
+                sent: reply::snapshot(probe),
+            },
+        );
+    }
+}
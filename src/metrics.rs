@@ -0,0 +1,316 @@
+//! Counters of what a trace sent and received.
+use crate::backend::{ProbeBackend, ReplyKind, TimestampSource};
+use crate::clock::Clock;
+use crate::stats::HopStats;
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// This struct is a snapshot of the counters of a trace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct TraceMetrics {
+    /// Probes handed to the backend, confirmation and warm-up probes included.
+    pub probes_sent: u64,
+    /// Calls handing probes to the backend, probes sent together take one.
+    pub send_calls: u64,
+    /// Warm-up probes, whose replies are discarded, see `TraceRouteConfig::warmup`.
+    pub warmup_probes: u64,
+    /// Replies reported as a hop.
+    pub replies_matched: u64,
+    /// Replies dropped because they answered another probe, trace or process.
+    pub foreign_replies: u64,
+    /// Replies from a hop already reported, ignored without using up a try.
+    pub duplicate_replies: u64,
+    /// Late replies to probes of another TTL of the trace.
+    pub stale_replies: u64,
+    /// Received messages too short or broken to classify.
+    pub malformed_replies: u64,
+    /// Waits for a reply that ran out.
+    pub timeouts: u64,
+    /// Bytes of the probes sent, IP headers included.
+    pub bytes_sent: u64,
+    /// Bytes of the messages received, without their IP header.
+    pub bytes_received: u64,
+    /// Time since the trace started, or how long it ran once it ended.
+    pub elapsed: Duration,
+    /// Latency of the first hop probed alongside the trace, see
+    /// `TraceRouteConfig::first_hop_interval`.
+    pub first_hop: Option<HopStats>,
+}
+
+/// This enum tells what a trace is doing, see `TraceStatus`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum TraceState {
+    /// Probing or waiting for replies.
+    Running,
+    /// Waiting out a backoff, after a probe could not be sent for now or before
+    /// the trace runs again.
+    Paused,
+    /// The worker stopped probing, after the last hop or a cancellation.
+    Completed,
+    /// The worker stopped on an error, see `TraceHandle::hops`.
+    Failed,
+}
+
+/// This struct is a snapshot of how far a trace got, see `TraceHandle::status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct TraceStatus {
+    /// TTL probed last, None before the first probe. Pipelined traces probe
+    /// several TTLs at once, it is the one sent to last.
+    pub current_ttl: Option<u8>,
+    /// Probes sent to `current_ttl` so far, the one waited for included.
+    pub attempts_on_current: u16,
+    /// Hops reported so far, unanswered ones included.
+    pub hops_found: u64,
+    /// Probes handed to the backend, see `TraceMetrics::probes_sent`.
+    pub probes_sent: u64,
+    /// Time since the trace started, or how long it ran once it ended.
+    pub elapsed: Duration,
+    pub state: TraceState,
+}
+
+/// This struct holds the counters a worker updates and its handle reads.
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    probes_sent: AtomicU64,
+    send_calls: AtomicU64,
+    warmup_probes: AtomicU64,
+    replies_matched: AtomicU64,
+    foreign_replies: AtomicU64,
+    duplicate_replies: AtomicU64,
+    stale_replies: AtomicU64,
+    malformed_replies: AtomicU64,
+    timeouts: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    /// TTL probed last in the upper half, probes sent to it in the lower one.
+    probing: AtomicU32,
+    hops_found: AtomicU64,
+    paused: AtomicBool,
+    started: Instant,
+    elapsed: Mutex<Option<Duration>>,
+    first_hop: Mutex<Option<HopStats>>,
+}
+
+impl Metrics {
+    /// Creates new Metrics, starting the clock.
+    pub fn new() -> Metrics {
+        Metrics {
+            probes_sent: AtomicU64::new(0),
+            send_calls: AtomicU64::new(0),
+            warmup_probes: AtomicU64::new(0),
+            replies_matched: AtomicU64::new(0),
+            foreign_replies: AtomicU64::new(0),
+            duplicate_replies: AtomicU64::new(0),
+            stale_replies: AtomicU64::new(0),
+            malformed_replies: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            probing: AtomicU32::new(0),
+            hops_found: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            started: Instant::now(),
+            elapsed: Mutex::new(None),
+            first_hop: Mutex::new(None),
+        }
+    }
+
+    /// Notes the `attempt`th probe of `ttl` went out, which ends any pause.
+    pub fn probing(&self, ttl: u8, attempt: u16) {
+        let progress = u32::from(ttl) << 16 | u32::from(attempt);
+        self.probing.store(progress, Ordering::Relaxed);
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    /// Notes the trace waits out a backoff before it sends again.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn hop_found(&self) {
+        self.hops_found.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn warmup_sent(&self) {
+        self.warmup_probes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn first_hop_probed(&self, stats: HopStats) {
+        *self.first_hop.lock().unwrap() = Some(stats);
+    }
+
+    pub fn reply_matched(&self) {
+        self.replies_matched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn foreign_reply(&self) {
+        self.foreign_replies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn duplicate_reply(&self) {
+        self.duplicate_replies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn malformed_reply(&self) {
+        self.malformed_replies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stale_reply(&self) {
+        self.stale_replies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn timed_out(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Stops the clock, later calls keep the first elapsed time.
+    pub fn finish(&self) {
+        let mut elapsed = self.elapsed.lock().unwrap();
+        if elapsed.is_none() {
+            *elapsed = Some(self.started.elapsed());
+        }
+    }
+
+    /// Returns how far the trace got, `stopped` with the state it ended in once
+    /// the worker stopped.
+    pub fn status(&self, stopped: Option<TraceState>) -> TraceStatus {
+        let progress = self.probing.load(Ordering::Relaxed);
+        let paused = self.paused.load(Ordering::Relaxed);
+        TraceStatus {
+            current_ttl: Some((progress >> 16) as u8).filter(|&ttl| ttl > 0),
+            attempts_on_current: progress as u16,
+            hops_found: self.hops_found.load(Ordering::Relaxed),
+            probes_sent: self.probes_sent.load(Ordering::Relaxed),
+            elapsed: self
+                .elapsed
+                .try_lock()
+                .ok()
+                .and_then(|elapsed| *elapsed)
+                .unwrap_or_else(|| self.started.elapsed()),
+            state: match stopped {
+                Some(state) => state,
+                None if paused => TraceState::Paused,
+                None => TraceState::Running,
+            },
+        }
+    }
+
+    /// Returns the current values of the counters.
+    pub fn snapshot(&self) -> TraceMetrics {
+        let elapsed = *self.elapsed.lock().unwrap();
+        TraceMetrics {
+            probes_sent: self.probes_sent.load(Ordering::Relaxed),
+            send_calls: self.send_calls.load(Ordering::Relaxed),
+            warmup_probes: self.warmup_probes.load(Ordering::Relaxed),
+            replies_matched: self.replies_matched.load(Ordering::Relaxed),
+            foreign_replies: self.foreign_replies.load(Ordering::Relaxed),
+            duplicate_replies: self.duplicate_replies.load(Ordering::Relaxed),
+            stale_replies: self.stale_replies.load(Ordering::Relaxed),
+            malformed_replies: self.malformed_replies.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            elapsed: elapsed.unwrap_or_else(|| self.started.elapsed()),
+            first_hop: *self.first_hop.lock().unwrap(),
+        }
+    }
+}
+
+/// This struct wraps the backend of a worker and counts the traffic going through it.
+pub(crate) struct Counted<B> {
+    backend: B,
+    metrics: Arc<Metrics>,
+}
+
+impl<B: ProbeBackend> Counted<B> {
+    /// Creates new Counted backend.
+    pub fn new(backend: B, metrics: Arc<Metrics>) -> Counted<B> {
+        Counted { backend, metrics }
+    }
+
+    fn sent(&self, packets: &[(&[u8], IpAddr)]) {
+        self.metrics.send_calls.fetch_add(1, Ordering::Relaxed);
+        for (packet, _) in packets {
+            self.metrics.probes_sent.fetch_add(1, Ordering::Relaxed);
+            self.metrics
+                .bytes_sent
+                .fetch_add(packet.len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn received(&self, message: &[u8]) {
+        self.metrics.received(message.len());
+    }
+}
+
+impl<B: ProbeBackend> ProbeBackend for Counted<B> {
+    fn send_to(&mut self, packet: &[u8], destination: IpAddr) -> io::Result<usize> {
+        let sent = self.backend.send_to(packet, destination)?;
+        self.sent(&[(packet, destination)]);
+        Ok(sent)
+    }
+
+    fn send_batch(&mut self, packets: &[(&[u8], IpAddr)]) -> io::Result<usize> {
+        let sent = self.backend.send_batch(packets)?;
+        self.sent(&packets[..sent]);
+        Ok(sent)
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+        let reply = self.backend.recv_timeout(timeout)?;
+        if let Some((message, _)) = &reply {
+            self.received(message);
+        }
+        Ok(reply)
+    }
+
+    fn recv_reply(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<(ReplyKind, Vec<u8>, IpAddr)>> {
+        let reply = self.backend.recv_reply(timeout)?;
+        if let Some((_, message, _)) = &reply {
+            self.received(message);
+        }
+        Ok(reply)
+    }
+
+    fn reply_options(&self) -> Vec<u8> {
+        self.backend.reply_options()
+    }
+
+    fn reply_header(&self) -> Option<Vec<u8>> {
+        self.backend.reply_header()
+    }
+
+    fn reply_received_at(&self) -> Option<Instant> {
+        self.backend.reply_received_at()
+    }
+
+    fn reply_timestamp_source(&self) -> TimestampSource {
+        self.backend.reply_timestamp_source()
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.backend.clock()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn reply_fds(&self) -> Vec<libc::c_int> {
+        self.backend.reply_fds()
+    }
+}
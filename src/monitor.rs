@@ -0,0 +1,163 @@
+//! Latency of the first hop, probed alongside a trace.
+use crate::metrics::Metrics;
+use crate::reply::{self, ProbeKey};
+use crate::stats::{ResponseClassifier, Samples};
+use crate::{build_icmp_v4, build_icmp_v6, build_udp_v4, build_udp_v6, next_identifier};
+use crate::{HopFound, TraceRouteConfig, TraceRouteProtocol};
+use rand::random;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// This struct sends TTL=1 probes between the probes of a trace and times their replies.
+///
+/// UDP traces probe the base port, which the trace itself never sends to, other
+/// traces probe with ICMP echo requests of an identifier of their own.
+pub(crate) struct FirstHopMonitor {
+    interval: Duration,
+    timeout: Option<Duration>,
+    destination: IpAddr,
+    source: IpAddr,
+    port: Option<u16>,
+    size: usize,
+    identifier: u16,
+    sequence: u16,
+    next_at: Instant,
+    pending: HashMap<ProbeKey, Instant>,
+    late: HashSet<ProbeKey>,
+    samples: Samples,
+    metrics: Arc<Metrics>,
+}
+
+impl FirstHopMonitor {
+    /// Creates new FirstHopMonitor if `config.first_hop_interval` is set, its
+    /// first probe is due at `now`.
+    pub fn new(
+        config: &TraceRouteConfig,
+        destination: IpAddr,
+        source: IpAddr,
+        metrics: Arc<Metrics>,
+        now: Instant,
+    ) -> Option<FirstHopMonitor> {
+        let interval = config.first_hop_interval?;
+        Some(FirstHopMonitor {
+            interval: Duration::from_millis(interval),
+            timeout: config.timeout,
+            destination,
+            source,
+            port: match config.protocol {
+                TraceRouteProtocol::Udp => Some(config.port),
+                _ => None,
+            },
+            size: config.size,
+            identifier: next_identifier(),
+            sequence: 0,
+            next_at: now,
+            pending: HashMap::new(),
+            late: HashSet::new(),
+            samples: Samples::default(),
+            metrics,
+        })
+    }
+
+    /// Returns a probe to send along with the next one of the trace, once the
+    /// interval since the last one has passed at `now`.
+    pub fn due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        self.expire(now);
+        if now < self.next_at {
+            return None;
+        }
+        self.next_at = now + self.interval;
+        self.sequence = self.sequence.wrapping_add(1);
+        let (destination, identifier, sequence) =
+            (self.destination, self.identifier, self.sequence);
+        let probe = match (self.port, self.source) {
+            (Some(port), IpAddr::V4(source)) => {
+                build_udp_v4(destination, self.size, port, 1, source, random())
+            }
+            (Some(port), IpAddr::V6(source)) => {
+                build_udp_v6(destination, self.size, port, 1, source, random())
+            }
+            (None, IpAddr::V4(source)) => {
+                build_icmp_v4(destination, 64, 1, source, identifier, sequence)
+            }
+            (None, IpAddr::V6(source)) => {
+                build_icmp_v6(destination, 64, 1, source, identifier, sequence)
+            }
+        };
+        match probe {
+            Ok(probe) => Some(probe),
+            Err(e) => {
+                debug!("could not build a first hop probe: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Starts timing `probe`, sent at `sent_at`.
+    pub fn sent(&mut self, probe: &[u8], sent_at: Instant) {
+        if let Some(key) = reply::sent_key(probe) {
+            self.pending.insert(key, sent_at);
+        }
+    }
+
+    /// Returns true if `message` answers one of its probes, late ones included.
+    pub fn expects(&self, message: &[u8]) -> bool {
+        let key = if self.destination.is_ipv4() {
+            reply::probe_key_v4(message)
+        } else {
+            reply::probe_key_v6(message)
+        };
+        key.is_some_and(|key| self.pending.contains_key(&key) || self.late.contains(&key))
+    }
+
+    /// Takes the reply to one of its probes, received at `received_at`, returns
+    /// false for any other message.
+    pub fn claim(&mut self, message: &[u8], from: IpAddr, received_at: Instant) -> bool {
+        let key = if self.destination.is_ipv4() {
+            reply::probe_key_v4(message)
+        } else {
+            reply::probe_key_v6(message)
+        };
+        let key = match key {
+            Some(key) => key,
+            None => return false,
+        };
+        if let Some(sent_at) = self.pending.remove(&key) {
+            let rtt = received_at.saturating_duration_since(sent_at);
+            if self.timeout.is_none_or(|timeout| rtt < timeout) {
+                self.record(HopFound::new(1, Some(from), 0, false, Some(rtt)));
+            } else {
+                self.record(HopFound::timed_out(1, 0));
+            }
+            return true;
+        }
+        self.late.remove(&key)
+    }
+
+    /// Counts the probes unanswered for longer than the timeout as lost.
+    fn expire(&mut self, now: Instant) {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let expired: Vec<ProbeKey> = self
+            .pending
+            .iter()
+            .filter(|(_, &sent_at)| now.duration_since(sent_at) >= timeout)
+            .map(|(&key, _)| key)
+            .collect();
+        for key in expired {
+            self.pending.remove(&key);
+            self.late.insert(key);
+            self.record(HopFound::timed_out(1, 0));
+        }
+    }
+
+    fn record(&mut self, hop: HopFound) {
+        self.samples.add(&hop);
+        self.metrics
+            .first_hop_probed(self.samples.stats(1, &ResponseClassifier::default()));
+    }
+}
@@ -0,0 +1,404 @@
+//! Replays the replies of a captured trace, so a trace that went wrong elsewhere
+//! can be run through the engine again.
+use crate::backend::ProbeBackend;
+use crate::clock::Clock;
+use crate::reply::{self, ProbeKey};
+use crate::testing::{packet_source, packet_ttl, MockClock};
+use pnet::packet::icmpv6::{self, Icmpv6Packet};
+use pnet::util;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+/// This struct is a packet of the capture, from its IP header on.
+struct Captured {
+    /// Time since the first packet of the capture.
+    at: Duration,
+    packet: Vec<u8>,
+}
+
+/// This struct is a probe of the capture, and the one of the replay sent for it.
+struct Probe {
+    captured: Captured,
+    key: Option<ProbeKey>,
+    sent: Option<Vec<u8>>,
+}
+
+struct Replay {
+    probes: Vec<Probe>,
+    replies: VecDeque<Captured>,
+    sent: Vec<Vec<u8>>,
+    clock: MockClock,
+    /// Time of the clock at the first packet of the capture.
+    start: Instant,
+    reply_header: Option<Vec<u8>>,
+}
+
+impl Replay {
+    /// Returns how far into the capture the clock is.
+    fn elapsed(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.start)
+    }
+
+    /// Moves the clock up to `at` into the capture, never back.
+    fn advance_to(&self, at: Duration) {
+        self.clock.sleep_until(self.start + at);
+    }
+
+    /// Makes `message`, an ICMP message `from` a hop, answer the probe of the
+    /// replay sent for the captured probe it answers, if one was.
+    fn rewrite(&self, message: &mut [u8], from: IpAddr) {
+        let key = match from {
+            IpAddr::V4(_) => reply::probe_key_v4(message),
+            IpAddr::V6(_) => reply::probe_key_v6(message),
+        };
+        let sent = match self
+            .probes
+            .iter()
+            .find(|probe| key.is_some() && probe.key == key)
+            .and_then(|probe| probe.sent.as_ref())
+        {
+            Some(sent) => sent,
+            None => return,
+        };
+        let header = header_len(sent);
+        let echo_reply = if from.is_ipv4() { 0 } else { 129 };
+        if message[0] == echo_reply {
+            // The key of an echo reply is its identifier and sequence number.
+            if let Some(key) = sent.get(header + 4..header + 8) {
+                message[4..8].copy_from_slice(key);
+            }
+        } else {
+            let quoted = &mut message[8..];
+            if header_len(quoted) != header {
+                return;
+            }
+            let len = (header + 8).min(quoted.len()).min(sent.len());
+            let ttl_at = if from.is_ipv4() { 8 } else { 7 };
+            let ttl = quoted[ttl_at];
+            quoted[..len].copy_from_slice(&sent[..len]);
+            quoted[ttl_at] = ttl;
+        }
+        message[2] = 0;
+        message[3] = 0;
+        let checksum = match (from, packet_source(sent)) {
+            (IpAddr::V6(from), Some(IpAddr::V6(to))) => match Icmpv6Packet::new(message) {
+                Some(packet) => icmpv6::checksum(&packet, &from, &to),
+                None => return,
+            },
+            _ => util::checksum(message, 1),
+        };
+        message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+/// This struct is a backend serving the ICMP and ICMPv6 replies of a capture, in
+/// the order and at the times they were captured.
+///
+/// Every probe sent is paired with the first captured probe of its TTL not paired
+/// yet, and the captured replies to that probe are rewritten to answer the one
+/// sent, so the trace matches them whatever identifiers and ports it picked. The
+/// backend waits on a `MockClock` standing at the time of the first packet of the
+/// capture, a send moves it up to the time its captured probe went out. Replies to
+/// probes that were not sent are served as captured, like a reply to another
+/// process.
+///
+/// The trace has to be run from the address the captured probes came from, see
+/// `source`. Clones share the same replay, so a test can keep one to inspect what
+/// the trace sent.
+#[derive(Clone)]
+pub struct PcapReplayBackend {
+    replay: Arc<Mutex<Replay>>,
+}
+
+impl PcapReplayBackend {
+    /// Reads the capture at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<PcapReplayBackend> {
+        PcapReplayBackend::from_bytes(&fs::read(path)?)
+    }
+
+    /// Reads a capture in the classic pcap format, of Ethernet, Linux cooked or
+    /// raw IP packets. ICMP and ICMPv6 messages other than echo requests and
+    /// neighbor discovery are replies, every other IP packet is a probe.
+    pub fn from_bytes(capture: &[u8]) -> io::Result<PcapReplayBackend> {
+        let mut probes = Vec::new();
+        let mut replies = VecDeque::new();
+        for captured in read_capture(capture)? {
+            if is_reply(&captured.packet) {
+                replies.push_back(captured);
+            } else {
+                probes.push(Probe {
+                    key: reply::sent_key(&captured.packet),
+                    captured,
+                    sent: None,
+                });
+            }
+        }
+        let clock = MockClock::new();
+        let start = clock.now();
+        Ok(PcapReplayBackend {
+            replay: Arc::new(Mutex::new(Replay {
+                probes,
+                replies,
+                sent: Vec::new(),
+                clock,
+                start,
+                reply_header: None,
+            })),
+        })
+    }
+
+    /// Returns the address the first captured probe came from.
+    pub fn source(&self) -> Option<IpAddr> {
+        let replay = self.replay.lock().unwrap();
+        let probe = replay.probes.first()?;
+        packet_source(&probe.captured.packet)
+    }
+
+    /// Returns the probes sent so far, in order.
+    pub fn sent_packets(&self) -> Vec<Vec<u8>> {
+        self.replay.lock().unwrap().sent.clone()
+    }
+
+    /// Returns how many probes sent found no captured probe of their TTL.
+    pub fn unpaired(&self) -> usize {
+        let replay = self.replay.lock().unwrap();
+        let paired = replay
+            .probes
+            .iter()
+            .filter(|probe| probe.sent.is_some())
+            .count();
+        replay.sent.len() - paired
+    }
+
+    /// Returns the replies of the capture not served yet.
+    pub fn replies_left(&self) -> usize {
+        self.replay.lock().unwrap().replies.len()
+    }
+}
+
+impl ProbeBackend for PcapReplayBackend {
+    fn send_to(&mut self, packet: &[u8], _destination: IpAddr) -> io::Result<usize> {
+        let mut replay = self.replay.lock().unwrap();
+        replay.sent.push(packet.to_vec());
+        let ttl = packet_ttl(packet);
+        let at = match replay
+            .probes
+            .iter_mut()
+            .find(|probe| probe.sent.is_none() && packet_ttl(&probe.captured.packet) == ttl)
+        {
+            Some(probe) => {
+                probe.sent = Some(packet.to_vec());
+                probe.captured.at
+            }
+            None => return Ok(packet.len()),
+        };
+        replay.advance_to(at);
+        Ok(packet.len())
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+        let mut replay = self.replay.lock().unwrap();
+        let deadline = replay.elapsed() + timeout;
+        if replay
+            .replies
+            .front()
+            .map_or(true, |next| next.at > deadline)
+        {
+            replay.advance_to(deadline);
+            return Ok(None);
+        }
+        let captured = replay.replies.pop_front().unwrap();
+        replay.advance_to(captured.at);
+        let (header, mut message) = {
+            let len = header_len(&captured.packet);
+            let (header, message) = captured.packet.split_at(len);
+            (header.to_vec(), message.to_vec())
+        };
+        let from = match packet_source(&captured.packet) {
+            Some(from) => from,
+            None => return Ok(None),
+        };
+        replay.rewrite(&mut message, from);
+        replay.reply_header = Some(header).filter(|_| from.is_ipv4());
+        Ok(Some((message, from)))
+    }
+
+    fn reply_options(&self) -> Vec<u8> {
+        match &self.replay.lock().unwrap().reply_header {
+            Some(header) => header[20..].to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    fn reply_header(&self) -> Option<Vec<u8>> {
+        self.replay.lock().unwrap().reply_header.clone()
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        Arc::new(self.replay.lock().unwrap().clock.clone())
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad capture: {}", what))
+}
+
+/// Returns the IP packets of a classic pcap capture with their times, packets of
+/// other kinds are left out.
+fn read_capture(capture: &[u8]) -> io::Result<Vec<Captured>> {
+    let header = capture.get(..24).ok_or_else(|| invalid("no header"))?;
+    let magic = [header[0], header[1], header[2], header[3]];
+    let (little, nanos) = match magic {
+        [0xd4, 0xc3, 0xb2, 0xa1] => (true, false),
+        [0xa1, 0xb2, 0xc3, 0xd4] => (false, false),
+        [0x4d, 0x3c, 0xb2, 0xa1] => (true, true),
+        [0xa1, 0xb2, 0x3c, 0x4d] => (false, true),
+        _ => return Err(invalid("not a pcap file")),
+    };
+    let word = |bytes: &[u8], at: usize| {
+        let word = [bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]];
+        if little {
+            u32::from_le_bytes(word)
+        } else {
+            u32::from_be_bytes(word)
+        }
+    };
+    let link_type = word(header, 20);
+    let mut packets = Vec::new();
+    let mut first = None;
+    let mut rest = &capture[24..];
+    while !rest.is_empty() {
+        let record = rest.get(..16).ok_or_else(|| invalid("record cut short"))?;
+        let len = word(record, 8) as usize;
+        let frame = rest
+            .get(16..16 + len)
+            .ok_or_else(|| invalid("packet cut short"))?;
+        rest = &rest[16 + len..];
+        let fraction = u64::from(word(record, 4));
+        let time = Duration::from_secs(u64::from(word(record, 0)))
+            + if nanos {
+                Duration::from_nanos(fraction)
+            } else {
+                Duration::from_micros(fraction)
+            };
+        let first = *first.get_or_insert(time);
+        if let Some(packet) = ip_packet(link_type, frame)? {
+            packets.push(Captured {
+                at: time.saturating_sub(first),
+                packet: packet.to_vec(),
+            });
+        }
+    }
+    Ok(packets)
+}
+
+/// Returns the IP packet carried by a frame of `link_type`, None for frames of
+/// other protocols.
+fn ip_packet(link_type: u32, frame: &[u8]) -> io::Result<Option<&[u8]>> {
+    let (ethertype, packet) = match link_type {
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => return Ok(Some(frame)),
+        LINKTYPE_ETHERNET => {
+            let mut at = 12;
+            // Skips a VLAN tag.
+            if frame.get(12..14) == Some(&[0x81, 0x00]) {
+                at += 4;
+            }
+            (frame.get(at..at + 2), frame.get(at + 2..))
+        }
+        LINKTYPE_LINUX_SLL => (frame.get(14..16), frame.get(16..)),
+        _ => return Err(invalid("unknown link type")),
+    };
+    Ok(match ethertype {
+        Some([0x08, 0x00]) | Some([0x86, 0xdd]) => packet,
+        _ => None,
+    })
+}
+
+fn header_len(packet: &[u8]) -> usize {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) => usize::from(packet[0] & 0x0f) * 4,
+        _ => 40,
+    }
+}
+
+/// Returns true for the ICMP and ICMPv6 messages a trace takes for replies.
+fn is_reply(packet: &[u8]) -> bool {
+    let (protocol, message) = match packet.first().map(|b| b >> 4) {
+        Some(4) => (packet.get(9), packet.get(header_len(packet)..)),
+        Some(6) => (packet.get(6), packet.get(40..)),
+        _ => return false,
+    };
+    match (protocol, message.and_then(|message| message.first())) {
+        (Some(1), Some(&icmp_type)) => icmp_type != 8,
+        (Some(58), Some(&icmp_type)) => icmp_type != 128 && !(133..=137).contains(&icmp_type),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::test_net_v4;
+    use crate::{HopFound, TraceRoute, TraceRouteConfig};
+
+    #[test]
+    fn reads_classic_captures() {
+        assert!(PcapReplayBackend::from_bytes(b"not a capture").is_err());
+        let mut capture = include_bytes!("../tests/corpus/late-reply.pcap").to_vec();
+        let backend = PcapReplayBackend::from_bytes(&capture).unwrap();
+        assert_eq!(backend.source(), Some(test_net_v4(254)));
+        assert_eq!(backend.replies_left(), 4);
+
+        // Link types other than Ethernet, Linux cooked and raw IP are refused.
+        capture[20] = 147;
+        assert!(PcapReplayBackend::from_bytes(&capture).is_err());
+    }
+
+    #[test]
+    fn late_reply_is_not_taken_for_the_next_hop() {
+        // The hop at TTL 2 answers after the probe at TTL 3 went out, a trace
+        // matching replies by arrival would report it as the third hop.
+        let backend =
+            PcapReplayBackend::from_bytes(include_bytes!("../tests/corpus/late-reply.pcap"))
+                .unwrap();
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(50)),
+            ..TraceRouteConfig::default()
+        };
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_with_backend(backend.clone(), backend.source().unwrap())
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().take(4).collect();
+        let metrics = handle.metrics();
+        handle.join().unwrap();
+
+        let addrs: Vec<Option<IpAddr>> = hops.iter().map(|hop| hop.addr).collect();
+        assert_eq!(
+            addrs,
+            vec![
+                Some(test_net_v4(1)),
+                None,
+                Some(test_net_v4(3)),
+                Some(test_net_v4(100))
+            ]
+        );
+        assert!(hops[3].is_last);
+        assert_eq!(metrics.stale_replies, 1);
+        assert_eq!(backend.sent_packets().len(), 4);
+        assert_eq!(backend.unpaired(), 0);
+        assert_eq!(backend.replies_left(), 0);
+    }
+}
@@ -0,0 +1,162 @@
+//! Echo probes of the destination, sent once its path is known.
+use crate::backend::ProbeBackend;
+use crate::reply::{self, ProbeKey};
+use crate::stats::{ResponseClassifier, Samples};
+use crate::{build_icmp_v4, build_icmp_v6, next_identifier, HopFound, TraceRouteError};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// TTL of the echo probes, enough to reach any destination a trace found.
+const PING_TTL: u8 = 64;
+
+/// This struct summarizes echo probes sent to the destination, like ping does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PingStats {
+    pub destination: IpAddr,
+    pub sent: u64,
+    pub received: u64,
+    pub min: Option<Duration>,
+    pub avg: Option<Duration>,
+    pub max: Option<Duration>,
+    /// Standard deviation of the round trip times.
+    pub std_dev: Option<Duration>,
+    /// Round trip time of every probe in the order sent, None for lost ones.
+    pub rtts: Vec<Option<Duration>>,
+}
+
+impl PingStats {
+    /// Creates new PingStats of the probes to `destination` that took `rtts`.
+    pub(crate) fn new(destination: IpAddr, rtts: Vec<Option<Duration>>) -> PingStats {
+        let mut samples = Samples::default();
+        for rtt in &rtts {
+            samples.add(&HopFound::new(1, rtt.map(|_| destination), 0, false, *rtt));
+        }
+        let stats = samples.stats(1, &ResponseClassifier::default());
+        PingStats {
+            destination,
+            sent: stats.sent,
+            received: stats.received,
+            min: stats.best,
+            avg: stats.mean,
+            max: stats.worst,
+            std_dev: stats.std_dev,
+            rtts,
+        }
+    }
+
+    /// Returns the share of probes that went unanswered, from 0 to 1.
+    pub fn loss(&self) -> f64 {
+        match self.sent {
+            0 => 0.0,
+            sent => (sent - self.received) as f64 / sent as f64,
+        }
+    }
+}
+
+/// Sends `count` echo probes of `size` bytes from `source` to `destination`, one
+/// every `interval`, and times the replies that come within `timeout`.
+pub(crate) fn ping<B: ProbeBackend + ?Sized>(
+    backend: &mut B,
+    destination: IpAddr,
+    source: IpAddr,
+    size: usize,
+    timeout: Duration,
+    count: u16,
+    interval: Duration,
+) -> Result<PingStats, TraceRouteError> {
+    let clock = backend.clock();
+    let identifier = next_identifier();
+    let mut rtts = vec![None; usize::from(count)];
+    let mut pending: HashMap<u16, Instant> = HashMap::new();
+    let mut sequence = 0;
+    let mut next_at = clock.now();
+    loop {
+        let now = clock.now();
+        pending.retain(|_, sent_at| now.duration_since(*sent_at) < timeout);
+        if sequence < count && now >= next_at {
+            sequence += 1;
+            let probe = match source {
+                IpAddr::V4(source) => {
+                    build_icmp_v4(destination, size, PING_TTL, source, identifier, sequence)
+                }
+                IpAddr::V6(source) => {
+                    build_icmp_v6(destination, size, PING_TTL, source, identifier, sequence)
+                }
+            }?;
+            let sent_at = clock.now();
+            backend
+                .send_to(&probe, destination)
+                .map_err(|e| TraceRouteError::Send {
+                    kind: e.kind(),
+                    message: e.to_string(),
+                })?;
+            pending.insert(sequence, sent_at);
+            next_at = now + interval;
+            continue;
+        }
+        let expiry = pending.values().min().map(|&sent_at| sent_at + timeout);
+        let until = match (sequence < count, expiry) {
+            (true, Some(expiry)) => expiry.min(next_at),
+            (true, None) => next_at,
+            (false, Some(expiry)) => expiry,
+            (false, None) => break,
+        };
+        let received = backend
+            .recv_timeout(until.saturating_duration_since(now))
+            .map_err(|e| TraceRouteError::Channel {
+                kind: e.kind(),
+                message: e.to_string(),
+            })?;
+        let message = match received {
+            Some((message, from)) if from == destination => message,
+            _ => continue,
+        };
+        let key = if destination.is_ipv4() {
+            reply::probe_key_v4(&message)
+        } else {
+            reply::probe_key_v6(&message)
+        };
+        let sequence = match key {
+            Some(ProbeKey::Echo {
+                identifier: id,
+                sequence,
+            }) if id == identifier => sequence,
+            _ => continue,
+        };
+        if let Some(sent_at) = pending.remove(&sequence) {
+            let received_at = backend.reply_received_at().unwrap_or_else(|| clock.now());
+            rtts[usize::from(sequence) - 1] = Some(received_at.saturating_duration_since(sent_at));
+        }
+    }
+    Ok(PingStats::new(destination, rtts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(value: Option<Duration>, millis: f64) -> bool {
+        value.is_some_and(|value| (value.as_secs_f64() * 1000.0 - millis).abs() < 1e-6)
+    }
+
+    #[test]
+    fn statistics_skip_lost_probes() {
+        let millis = |ms| Some(Duration::from_millis(ms));
+        let stats = PingStats::new(
+            IpAddr::from([192, 0, 2, 100]),
+            vec![millis(10), None, millis(20), millis(30)],
+        );
+        assert_eq!((stats.sent, stats.received), (4, 3));
+        assert!((stats.loss() - 0.25).abs() < 1e-9);
+        assert!(close(stats.min, 10.0));
+        assert!(close(stats.avg, 20.0));
+        assert!(close(stats.max, 30.0));
+        assert!(close(stats.std_dev, (200.0f64 / 3.0).sqrt()));
+
+        let silent = PingStats::new(IpAddr::from([192, 0, 2, 100]), vec![None, None]);
+        assert_eq!(silent.loss(), 1.0);
+        assert_eq!((silent.min, silent.avg, silent.std_dev), (None, None, None));
+    }
+}
@@ -0,0 +1,433 @@
+//! Traces run by a fixed number of threads, each waiting on the sockets of all its
+//! traces with `epoll`.
+use crate::backend::ProbeBackend;
+use crate::logging::Span;
+use crate::machine::TraceMachine;
+#[cfg(feature = "serde")]
+use crate::{ConfigFile, TraceRoute, TraceRouteError, TraceRouteRes};
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "serde")]
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// Token of the event that new traces were handed to a thread.
+const WAKE: u64 = u64::MAX;
+
+/// Events taken per `epoll_wait` call.
+const EVENTS: usize = 64;
+
+/// A trace machine of any backend, as driven by a pool thread.
+trait Pooled: Send {
+    fn advance(&mut self) -> Option<Instant>;
+    fn reply_fds(&self) -> Vec<libc::c_int>;
+}
+
+impl<B: ProbeBackend> Pooled for TraceMachine<B> {
+    fn advance(&mut self) -> Option<Instant> {
+        TraceMachine::advance(self)
+    }
+
+    fn reply_fds(&self) -> Vec<libc::c_int> {
+        TraceMachine::reply_fds(self)
+    }
+}
+
+/// A trace handed to a pool thread, with the span it runs in.
+struct Job {
+    span: Span,
+    machine: Box<dyn Pooled>,
+    completion: Completion,
+}
+
+/// This struct tells the handle of a pooled trace that it ended.
+#[derive(Clone, Default)]
+pub(crate) struct Completion {
+    state: Arc<(Mutex<Option<thread::Result<()>>>, Condvar)>,
+}
+
+impl Completion {
+    fn complete(&self, result: thread::Result<()>) {
+        let (state, ended) = &*self.state;
+        *state.lock().unwrap() = Some(result);
+        ended.notify_all();
+    }
+
+    /// Returns true once the trace ended.
+    pub fn is_done(&self) -> bool {
+        self.state.0.lock().unwrap().is_some()
+    }
+
+    /// Blocks until the trace ended, returns the panic that ended it if any.
+    pub fn wait(self) -> thread::Result<()> {
+        let (state, ended) = &*self.state;
+        let mut state = state.lock().unwrap();
+        loop {
+            match state.take() {
+                Some(result) => return result,
+                None => state = ended.wait(state).unwrap(),
+            }
+        }
+    }
+}
+
+/// This struct is an `eventfd` waking a pool thread up.
+struct Waker {
+    fd: libc::c_int,
+}
+
+impl Waker {
+    fn new() -> io::Result<Waker> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Waker { fd })
+    }
+
+    fn wake(&self) {
+        let one = 1u64;
+        unsafe { libc::write(self.fd, &one as *const u64 as *const libc::c_void, 8) };
+    }
+
+    fn reset(&self) {
+        let mut count = 0u64;
+        unsafe { libc::read(self.fd, &mut count as *mut u64 as *mut libc::c_void, 8) };
+    }
+}
+
+impl Drop for Waker {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// This struct is an `epoll` instance.
+struct Epoll {
+    fd: libc::c_int,
+}
+
+impl Epoll {
+    fn new() -> io::Result<Epoll> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Epoll { fd })
+    }
+
+    /// Reports `fd` readable under `token`.
+    fn add(&self, fd: libc::c_int, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: token,
+        };
+        if unsafe { libc::epoll_ctl(self.fd, libc::EPOLL_CTL_ADD, fd, &mut event) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn remove(&self, fd: libc::c_int) {
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        unsafe { libc::epoll_ctl(self.fd, libc::EPOLL_CTL_DEL, fd, &mut event) };
+    }
+
+    /// Waits until `deadline` at most for readable sockets, returns their tokens.
+    fn wait(&self, deadline: Option<Instant>) -> io::Result<Vec<u64>> {
+        let millis = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                left.as_micros()
+                    .div_ceil(1000)
+                    .min(libc::c_int::MAX as u128) as libc::c_int
+            }
+            None => -1,
+        };
+        let mut events: Vec<libc::epoll_event> =
+            vec![libc::epoll_event { events: 0, u64: 0 }; EVENTS];
+        let res = unsafe {
+            libc::epoll_wait(self.fd, events.as_mut_ptr(), EVENTS as libc::c_int, millis)
+        };
+        if res == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(Vec::new());
+            }
+            return Err(e);
+        }
+        Ok(events[..res as usize]
+            .iter()
+            .map(|event| event.u64)
+            .collect())
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// A trace run by a pool thread.
+struct Slot {
+    job: Job,
+    fds: Vec<libc::c_int>,
+    deadline: Option<Instant>,
+}
+
+/// Runs the traces handed over on `jobs` until they are all over and the pool is
+/// gone.
+fn serve(jobs: Receiver<Job>, waker: Arc<Waker>) -> io::Result<()> {
+    let epoll = Epoll::new()?;
+    epoll.add(waker.fd, WAKE)?;
+    let mut slots: HashMap<u64, Slot> = HashMap::new();
+    let mut deadlines: BTreeSet<(Instant, u64)> = BTreeSet::new();
+    let mut ready: Vec<u64> = Vec::new();
+    let mut next_token = 0;
+    let mut open = true;
+    let result = loop {
+        while open {
+            match jobs.try_recv() {
+                Ok(job) => {
+                    let fds = job.machine.reply_fds();
+                    for &fd in &fds {
+                        if let Err(e) = epoll.add(fd, next_token) {
+                            debug!("socket {} is only read at deadlines: {}", fd, e);
+                        }
+                    }
+                    let slot = Slot {
+                        job,
+                        fds,
+                        deadline: None,
+                    };
+                    slots.insert(next_token, slot);
+                    ready.push(next_token);
+                    next_token += 1;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => open = false,
+            }
+        }
+        let now = Instant::now();
+        while let Some(&(deadline, token)) = deadlines.iter().next() {
+            if deadline > now {
+                break;
+            }
+            deadlines.remove(&(deadline, token));
+            ready.push(token);
+        }
+        for token in mem::take(&mut ready) {
+            let slot = match slots.get_mut(&token) {
+                Some(slot) => slot,
+                None => continue,
+            };
+            if let Some(deadline) = slot.deadline.take() {
+                deadlines.remove(&(deadline, token));
+            }
+            let Job { span, machine, .. } = &mut slot.job;
+            let advanced =
+                panic::catch_unwind(AssertUnwindSafe(|| span.in_scope(|| machine.advance())));
+            match advanced {
+                Ok(Some(deadline)) => {
+                    slot.deadline = Some(deadline);
+                    deadlines.insert((deadline, token));
+                }
+                Ok(None) => end(&epoll, slots.remove(&token), Ok(())),
+                Err(panic) => end(&epoll, slots.remove(&token), Err(panic)),
+            }
+        }
+        if !open && slots.is_empty() {
+            break Ok(());
+        }
+        let first = deadlines.iter().next().map(|&(deadline, _)| deadline);
+        match epoll.wait(first) {
+            Ok(tokens) => {
+                for token in tokens {
+                    if token == WAKE {
+                        waker.reset();
+                    } else if !ready.contains(&token) {
+                        ready.push(token);
+                    }
+                }
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    for (_, slot) in slots.drain() {
+        let message = format!("the pool thread stopped: {:?}", result);
+        end(&epoll, Some(slot), Err(Box::new(message)));
+    }
+    result
+}
+
+/// Tells the handle of a trace that it ended, after forgetting its sockets.
+fn end(epoll: &Epoll, slot: Option<Slot>, result: thread::Result<()>) {
+    if let Some(slot) = slot {
+        for &fd in &slot.fds {
+            epoll.remove(fd);
+        }
+        let completion = slot.job.completion.clone();
+        drop(slot);
+        completion.complete(result);
+    }
+}
+
+/// This struct runs many traces on a fixed number of threads.
+///
+/// A trace started with `TraceRoute::run_in_pool` keeps the same handle and
+/// receiver of hops as one started with `run_trace_route`, but instead of a thread
+/// of its own it gets a share of one of the pool threads. Each pool thread waits for
+/// the replies of all its traces with a single `epoll` and for their deadlines in
+/// order. Backends without sockets, like `SimulatedBackend`, are read whenever a
+/// deadline of their trace expires.
+///
+/// The threads stop once the pool is dropped and their traces are over.
+pub struct TracePool {
+    threads: Vec<(Sender<Job>, Arc<Waker>)>,
+    next: AtomicUsize,
+}
+
+impl TracePool {
+    /// Creates new TracePool with `threads` threads, at least one.
+    pub fn new(threads: usize) -> io::Result<TracePool> {
+        let threads = (0..threads.max(1))
+            .map(|_| {
+                let (jobs, received) = channel();
+                let waker = Arc::new(Waker::new()?);
+                let woken = waker.clone();
+                thread::Builder::new()
+                    .name("rtraceroute-pool".to_string())
+                    .spawn(move || {
+                        if let Err(e) = serve(received, woken) {
+                            warn!("a pool thread stopped: {}", e);
+                        }
+                    })?;
+                Ok((jobs, waker))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(TracePool {
+            threads,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Creates new TracePool from the configuration file at `path`, see
+    /// `ConfigFile`, with a trace of each target, resolving host names. The pool
+    /// runs the threads the file asks for, one per target by default.
+    ///
+    /// Traces that could not be created are returned with their error, in the order
+    /// of the targets; the others start with `TraceRoute::run_in_pool`.
+    #[cfg(feature = "serde")]
+    pub fn from_config_file<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<(TracePool, Vec<TraceRouteRes>), TraceRouteError> {
+        let file = ConfigFile::load(path)?;
+        let threads = file.threads.unwrap_or_else(|| file.targets.len());
+        let pool = TracePool::new(threads).map_err(|e| TraceRouteError::Channel {
+            kind: e.kind(),
+            message: e.to_string(),
+        })?;
+        let traces = file
+            .targets
+            .into_iter()
+            .map(|target| TraceRoute::for_host(&target.host, None, target.config))
+            .collect();
+        Ok((pool, traces))
+    }
+
+    /// Returns how many threads the pool runs.
+    pub fn threads(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Hands a trace to the next thread in turn.
+    pub(crate) fn submit<B: ProbeBackend + 'static>(
+        &self,
+        span: Span,
+        machine: TraceMachine<B>,
+    ) -> Completion {
+        let completion = Completion::default();
+        let turn = self.next.fetch_add(1, Ordering::Relaxed) % self.threads.len();
+        let (jobs, waker) = &self.threads[turn];
+        let job = Job {
+            span,
+            machine: Box::new(machine),
+            completion: completion.clone(),
+        };
+        if let Err(job) = jobs.send(job) {
+            job.0
+                .completion
+                .complete(Err(Box::new("the pool thread is gone".to_string())));
+        }
+        waker.wake();
+        completion
+    }
+}
+
+impl Drop for TracePool {
+    fn drop(&mut self) {
+        for (jobs, waker) in mem::take(&mut self.threads) {
+            drop(jobs);
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_net_v4, SimulatedBackend};
+    use crate::{HopFound, TraceRoute, TraceRouteConfig, TraceState};
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    /// Backend refusing every probe, which makes its trace fail.
+    struct Refusing;
+
+    impl ProbeBackend for Refusing {
+        fn send_to(&mut self, _packet: &[u8], _destination: IpAddr) -> io::Result<usize> {
+            Err(io::Error::other("refused"))
+        }
+
+        fn recv_timeout(&mut self, _timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn failing_traces_leave_the_pool_running() {
+        let pool = TracePool::new(1).unwrap();
+        let config = TraceRouteConfig::default();
+        let (trace_route, _receiver) =
+            TraceRoute::with_config(test_net_v4(100), config.clone()).unwrap();
+        let failed = trace_route
+            .run_in_pool_with_backend(&pool, Refusing, test_net_v4(254))
+            .unwrap();
+        assert!(failed.join().is_ok());
+        assert_eq!(failed.status().state, TraceState::Failed);
+
+        let backend = SimulatedBackend::new(
+            vec![Some(test_net_v4(1)), Some(test_net_v4(2))],
+            test_net_v4(100),
+        );
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        let handle = trace_route
+            .run_in_pool_with_backend(&pool, backend, test_net_v4(254))
+            .unwrap();
+        let hops: Vec<HopFound> = receiver.iter().take(3).collect();
+        handle.join().unwrap();
+        assert_eq!(hops[1].addr, Some(test_net_v4(2)));
+        assert!(hops[2].is_last);
+    }
+}
@@ -0,0 +1,517 @@
+//! Choice of the address to trace among the ones a host name resolves to.
+//!
+//! Like RFC 8305, candidates alternate families and are probed a short delay apart,
+//! one high TTL probe each. The first candidate that gets any answer is traced.
+use crate::backend::{ProbeBackend, ReplyKind};
+use crate::config::{PreflightConfig, TraceRouteConfig};
+use crate::error::TraceRouteError;
+use crate::gateway::IpFamily;
+use crate::reply::{self, ProbeKey};
+use crate::{
+    build_dccp_v4, build_dccp_v6, build_icmp_v4, build_icmp_v6, build_raw_v4, build_raw_v6,
+    build_udp_v4, build_udp_v6, dccp_sequence, next_identifier, TraceRouteProtocol,
+};
+use rand::random;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// This trait resolves host names, so tests can do without DNS.
+pub trait Resolver {
+    /// Returns the addresses of `host`, most preferred first.
+    fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>>;
+}
+
+/// This struct resolves host names with the resolver of the system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        Ok((host, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect())
+    }
+}
+
+/// This enum tells how the probe of a candidate went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PreflightOutcome {
+    /// `from` answered the probe after `rtt`.
+    Answered { from: IpAddr, rtt: Duration },
+    /// Nothing answered within the preflight timeout.
+    TimedOut,
+    /// The probe could not be sent.
+    Failed(TraceRouteError),
+    /// Another candidate answered before this one did.
+    Cancelled,
+}
+
+/// This struct records the probe of one candidate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PreflightAttempt {
+    pub addr: IpAddr,
+    pub outcome: PreflightOutcome,
+}
+
+/// This enum tells why the traced address was chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PreflightChoice {
+    /// The host has a single address of the wanted family, nothing was probed.
+    OnlyCandidate,
+    /// The address answered first.
+    FirstAnswer,
+    /// No candidate answered, the most preferred one is traced anyway.
+    NoAnswer,
+}
+
+/// This struct records how the traced address of a host name was chosen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Preflight {
+    pub host: String,
+    /// Probed candidates, in the order they were probed.
+    pub attempts: Vec<PreflightAttempt>,
+    pub chosen: IpAddr,
+    pub choice: PreflightChoice,
+}
+
+/// Orders addresses like RFC 8305 section 4, families alternate starting with the
+/// family of the first address. Duplicates are dropped.
+pub(crate) fn interleave(addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+    let first_v6 = addrs.first().is_some_and(IpAddr::is_ipv6);
+    let mut unique: Vec<IpAddr> = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        if !unique.contains(&addr) {
+            unique.push(addr);
+        }
+    }
+    let (mut preferred, mut other): (VecDeque<IpAddr>, VecDeque<IpAddr>) = unique
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        ordered.extend(preferred.pop_front());
+        ordered.extend(other.pop_front());
+    }
+    ordered
+}
+
+/// Resolves `host` and picks the address to trace.
+///
+/// `open` returns the backend and source address a candidate is probed from.
+pub(crate) fn choose<R, B, F>(
+    host: &str,
+    family: Option<IpFamily>,
+    config: &TraceRouteConfig,
+    resolver: &R,
+    open: F,
+) -> Result<(IpAddr, Option<Preflight>), TraceRouteError>
+where
+    R: Resolver + ?Sized,
+    B: ProbeBackend + 'static,
+    F: FnMut(IpAddr, &TraceRouteConfig) -> Result<(B, IpAddr), TraceRouteError>,
+{
+    config.check_preflight()?;
+    let unresolved = |message: String| TraceRouteError::Unresolved {
+        host: host.to_string(),
+        message,
+    };
+    let addrs = match host.parse::<IpAddr>() {
+        Ok(addr) => vec![addr],
+        Err(_) => resolver
+            .resolve(host)
+            .map_err(|e| unresolved(e.to_string()))?,
+    };
+    let mut candidates: Vec<IpAddr> = interleave(addrs)
+        .into_iter()
+        .filter(|addr| match family {
+            Some(IpFamily::V4) => addr.is_ipv4(),
+            Some(IpFamily::V6) => addr.is_ipv6(),
+            None => true,
+        })
+        .collect();
+    let first = match candidates.first() {
+        Some(first) => *first,
+        None => return Err(unresolved("no address of the wanted family".to_string())),
+    };
+    let settings = match &config.preflight {
+        Some(settings) => settings,
+        None => return Ok((first, None)),
+    };
+    candidates.truncate(settings.candidates);
+    if candidates.len() == 1 {
+        let preflight = Preflight {
+            host: host.to_string(),
+            attempts: Vec::new(),
+            chosen: first,
+            choice: PreflightChoice::OnlyCandidate,
+        };
+        return Ok((first, Some(preflight)));
+    }
+    let (outcomes, winner) = race(&candidates, config, settings, open);
+    let (chosen, choice) = match winner {
+        Some(n) => (candidates[n], PreflightChoice::FirstAnswer),
+        None => (first, PreflightChoice::NoAnswer),
+    };
+    debug!("preflight of {} chose {} ({:?})", host, chosen, choice);
+    let attempts = candidates
+        .into_iter()
+        .zip(outcomes)
+        .map(|(addr, outcome)| PreflightAttempt { addr, outcome })
+        .collect();
+    let preflight = Preflight {
+        host: host.to_string(),
+        attempts,
+        chosen,
+        choice,
+    };
+    Ok((chosen, Some(preflight)))
+}
+
+/// Time given to the probe threads to report a timeout after it passed.
+const REPORT_GRACE: Duration = Duration::from_millis(50);
+
+/// Probes the candidates concurrently, each `attempt_delay` after the previous one,
+/// and returns their outcomes and the index of the first to answer.
+///
+/// Waits at most until the last candidate timed out, plus `REPORT_GRACE`. Probes
+/// still running when a candidate answers finish on their own threads.
+fn race<B, F>(
+    candidates: &[IpAddr],
+    config: &TraceRouteConfig,
+    settings: &PreflightConfig,
+    mut open: F,
+) -> (Vec<PreflightOutcome>, Option<usize>)
+where
+    B: ProbeBackend + 'static,
+    F: FnMut(IpAddr, &TraceRouteConfig) -> Result<(B, IpAddr), TraceRouteError>,
+{
+    let delay = Duration::from_millis(settings.attempt_delay);
+    let timeout = Duration::from_millis(settings.timeout);
+    let done = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = channel();
+    let mut outcomes: Vec<Option<PreflightOutcome>> = vec![None; candidates.len()];
+    let mut running: u32 = 0;
+    let started = Instant::now();
+    for (n, &addr) in candidates.iter().enumerate() {
+        let (backend, source) = match open(addr, config) {
+            Ok(opened) => opened,
+            Err(e) => {
+                outcomes[n] = Some(PreflightOutcome::Failed(e));
+                continue;
+            }
+        };
+        let (tx, done, config) = (tx.clone(), done.clone(), config.clone());
+        let start = delay * running;
+        running += 1;
+        thread::spawn(move || {
+            thread::sleep(start);
+            let outcome = if done.load(Ordering::Relaxed) {
+                PreflightOutcome::Cancelled
+            } else {
+                probe(backend, addr, source, &config, timeout)
+            };
+            let _ = tx.send((n, outcome));
+        });
+    }
+    drop(tx);
+    let deadline = started + delay * running.saturating_sub(1) + timeout + REPORT_GRACE;
+    let mut winner = None;
+    while winner.is_none() {
+        let left = match deadline.checked_duration_since(Instant::now()) {
+            Some(left) => left,
+            None => break,
+        };
+        let (n, outcome) = match rx.recv_timeout(left) {
+            Ok(reported) => reported,
+            Err(_) => break,
+        };
+        if let PreflightOutcome::Answered { .. } = outcome {
+            done.store(true, Ordering::Relaxed);
+            winner = Some(n);
+        }
+        outcomes[n] = Some(outcome);
+    }
+    done.store(true, Ordering::Relaxed);
+    let outcomes = outcomes
+        .into_iter()
+        .map(|outcome| outcome.unwrap_or(PreflightOutcome::Cancelled))
+        .collect();
+    (outcomes, winner)
+}
+
+/// Sends one probe with the last TTL of the trace to `addr` and waits for any
+/// answer to it.
+fn probe<B: ProbeBackend>(
+    mut backend: B,
+    addr: IpAddr,
+    source: IpAddr,
+    config: &TraceRouteConfig,
+    timeout: Duration,
+) -> PreflightOutcome {
+    let probe = match preflight_probe(addr, source, config) {
+        Ok(probe) => probe,
+        Err(e) => return PreflightOutcome::Failed(e),
+    };
+    let key = reply::sent_key(&probe);
+    if let Err(e) = backend.send_to(&probe, addr) {
+        return PreflightOutcome::Failed(TraceRouteError::Channel {
+            kind: e.kind(),
+            message: e.to_string(),
+        });
+    }
+    let sent_at = Instant::now();
+    let deadline = sent_at + timeout;
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match backend.recv_reply(left) {
+            Ok(Some((kind, bytes, from))) if answers(kind, &bytes, from, addr, key) => {
+                return PreflightOutcome::Answered {
+                    from,
+                    rtt: sent_at.elapsed(),
+                }
+            }
+            Ok(Some(_)) => continue,
+            Ok(None) | Err(_) => break,
+        }
+    }
+    PreflightOutcome::TimedOut
+}
+
+/// Returns true if a reply answers the probe with `key` sent to `addr`.
+///
+/// Probes without a key are answered by anything `addr` sends.
+fn answers(
+    kind: ReplyKind,
+    bytes: &[u8],
+    from: IpAddr,
+    addr: IpAddr,
+    key: Option<ProbeKey>,
+) -> bool {
+    if kind == ReplyKind::Transport || key.is_none() {
+        return from == addr;
+    }
+    let quoted = match addr {
+        IpAddr::V4(_) => reply::probe_key_v4(bytes),
+        IpAddr::V6(_) => reply::probe_key_v6(bytes),
+    };
+    quoted == key
+}
+
+/// Builds the probe of the configured protocol with the last TTL of the trace.
+fn preflight_probe(
+    addr: IpAddr,
+    source: IpAddr,
+    config: &TraceRouteConfig,
+) -> Result<Vec<u8>, TraceRouteError> {
+    let ttl = config.max_ttl;
+    let port = config.port + u16::from(ttl);
+    let identifier = next_identifier();
+    match (config.protocol, source) {
+        (TraceRouteProtocol::Udp, IpAddr::V4(source)) => {
+            build_udp_v4(addr, config.size, port, ttl, source, random())
+        }
+        (TraceRouteProtocol::Udp, IpAddr::V6(source)) => {
+            build_udp_v6(addr, config.size, port, ttl, source, random())
+        }
+        (TraceRouteProtocol::Icmp, IpAddr::V4(source)) => {
+            build_icmp_v4(addr, 64, ttl, source, identifier, 1)
+        }
+        (TraceRouteProtocol::Icmp, IpAddr::V6(source)) => {
+            build_icmp_v6(addr, 64, ttl, source, identifier, 1)
+        }
+        (TraceRouteProtocol::Dccp, IpAddr::V4(source)) => build_dccp_v4(
+            addr,
+            port,
+            ttl,
+            source,
+            random::<u16>(),
+            dccp_sequence(identifier, 1),
+        ),
+        (TraceRouteProtocol::Dccp, IpAddr::V6(source)) => build_dccp_v6(
+            addr,
+            port,
+            ttl,
+            source,
+            random::<u16>(),
+            dccp_sequence(identifier, 1),
+        ),
+        (TraceRouteProtocol::Raw(number), IpAddr::V4(source)) => {
+            build_raw_v4(addr, number, config.size, ttl, source, identifier, 1)
+        }
+        (TraceRouteProtocol::Raw(number), IpAddr::V6(source)) => {
+            build_raw_v6(addr, number, config.size, ttl, source, identifier, 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_net_v4, test_net_v6, SimulatedBackend};
+    use crate::TraceRoute;
+
+    struct Zone(Vec<IpAddr>);
+
+    impl Resolver for Zone {
+        fn resolve(&self, _host: &str) -> io::Result<Vec<IpAddr>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn preflight_config() -> TraceRouteConfig {
+        TraceRouteConfig {
+            preflight: Some(PreflightConfig {
+                candidates: 4,
+                attempt_delay: 20,
+                timeout: 200,
+            }),
+            ..TraceRouteConfig::default()
+        }
+    }
+
+    /// Opens a simulated backend where only `reachable` answers and `broken`
+    /// can not be probed at all.
+    fn network(
+        reachable: IpAddr,
+        broken: IpAddr,
+    ) -> impl FnMut(IpAddr, &TraceRouteConfig) -> Result<(SimulatedBackend, IpAddr), TraceRouteError>
+    {
+        move |addr, _| {
+            let source = if addr.is_ipv4() {
+                test_net_v4(254)
+            } else {
+                test_net_v6(254)
+            };
+            let backend = SimulatedBackend::new(vec![None, None], addr);
+            match addr {
+                _ if addr == broken => Err(TraceRouteError::NoInterface),
+                _ if addr == reachable => Ok((backend, source)),
+                _ => Ok((backend.with_destination_filter(true, true), source)),
+            }
+        }
+    }
+
+    #[test]
+    fn families_alternate() {
+        let zone = vec![
+            test_net_v6(1),
+            test_net_v6(2),
+            test_net_v6(1),
+            test_net_v6(3),
+            test_net_v4(1),
+        ];
+        assert_eq!(
+            interleave(zone),
+            vec![
+                test_net_v6(1),
+                test_net_v4(1),
+                test_net_v6(2),
+                test_net_v6(3)
+            ]
+        );
+        assert!(interleave(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn the_reachable_candidate_is_traced() {
+        let zone = Zone(vec![test_net_v6(100), test_net_v6(101), test_net_v4(100)]);
+        let started = Instant::now();
+        let (trace_route, _receiver) = TraceRoute::for_host_with(
+            "example.test",
+            None,
+            preflight_config(),
+            &zone,
+            network(test_net_v4(100), test_net_v6(101)),
+        )
+        .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(200));
+        assert_eq!(trace_route.address, test_net_v4(100));
+        let preflight = trace_route.preflight.clone().unwrap();
+        assert_eq!(preflight.choice, PreflightChoice::FirstAnswer);
+        assert_eq!(preflight.chosen, test_net_v4(100));
+        let addrs: Vec<IpAddr> = preflight.attempts.iter().map(|a| a.addr).collect();
+        assert_eq!(
+            addrs,
+            vec![test_net_v6(100), test_net_v4(100), test_net_v6(101)]
+        );
+        assert_eq!(preflight.attempts[0].outcome, PreflightOutcome::Cancelled);
+        assert!(matches!(
+            preflight.attempts[1].outcome,
+            PreflightOutcome::Answered { from, .. } if from == test_net_v4(100)
+        ));
+        assert_eq!(
+            preflight.attempts[2].outcome,
+            PreflightOutcome::Failed(TraceRouteError::NoInterface)
+        );
+
+        let handle = trace_route
+            .run_with_backend(
+                SimulatedBackend::new(vec![], test_net_v4(100)),
+                test_net_v4(254),
+            )
+            .unwrap();
+        assert_eq!(handle.metadata().preflight, Some(preflight));
+        drop(trace_route);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn silent_candidates_fall_back_to_the_first_in_bounded_time() {
+        let zone = Zone(vec![test_net_v4(100), test_net_v6(100)]);
+        let started = Instant::now();
+        let (chosen, preflight) = choose(
+            "example.test",
+            None,
+            &preflight_config(),
+            &zone,
+            network(test_net_v4(1), test_net_v4(2)),
+        )
+        .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(400));
+        let preflight = preflight.unwrap();
+        assert_eq!(chosen, test_net_v4(100));
+        assert_eq!(preflight.choice, PreflightChoice::NoAnswer);
+        assert!(preflight
+            .attempts
+            .iter()
+            .all(|attempt| attempt.outcome == PreflightOutcome::TimedOut));
+    }
+
+    #[test]
+    fn family_and_single_candidates() {
+        let zone = Zone(vec![test_net_v6(100), test_net_v4(100)]);
+        let open = network(test_net_v4(100), test_net_v4(1));
+        let (chosen, preflight) = choose(
+            "example.test",
+            Some(IpFamily::V4),
+            &preflight_config(),
+            &zone,
+            open,
+        )
+        .unwrap();
+        assert_eq!(chosen, test_net_v4(100));
+        assert_eq!(
+            preflight.map(|preflight| preflight.choice),
+            Some(PreflightChoice::OnlyCandidate)
+        );
+
+        let open = network(test_net_v4(100), test_net_v4(1));
+        let config = TraceRouteConfig::default();
+        let (chosen, preflight) = choose("example.test", None, &config, &zone, open).unwrap();
+        assert_eq!((chosen, preflight), (test_net_v6(100), None));
+
+        let open = network(test_net_v4(100), test_net_v4(1));
+        let zone = Zone(vec![test_net_v4(100)]);
+        assert!(matches!(
+            choose("example.test", Some(IpFamily::V6), &config, &zone, open),
+            Err(TraceRouteError::Unresolved { .. })
+        ));
+    }
+}
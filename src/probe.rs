@@ -0,0 +1,444 @@
+//! RFC 8335 Extended Echo probes, asking a node about one of its interfaces.
+use crate::backend::ProbeBackend;
+use crate::reply::EXTENSION_VERSION;
+use crate::{length_u16, next_identifier, TraceRouteError};
+use pnet::packet::icmpv6::{self, Icmpv6Packet};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::{ipv4, ipv6};
+use pnet::util;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// TTL of the requests, enough to reach any node a trace found.
+const PROBE_TTL: u8 = 64;
+
+/// ICMP and ICMPv6 types of Extended Echo requests and replies.
+const REQUEST_V4: u8 = 42;
+const REPLY_V4: u8 = 43;
+const REQUEST_V6: u8 = 160;
+const REPLY_V6: u8 = 161;
+
+/// Class of the Interface Identification Object.
+const CLASS_INTERFACE_ID: u8 = 3;
+
+/// C-types of the Interface Identification Object.
+const BY_NAME: u8 = 1;
+const BY_INDEX: u8 = 2;
+const BY_ADDRESS: u8 = 3;
+
+/// Bit of a request telling the interface belongs to the probed node.
+const LOCAL: u8 = 0x01;
+
+/// Bits of a reply telling the interface is active and runs IPv4 or IPv6.
+const ACTIVE: u8 = 0x04;
+const RUNS_IPV4: u8 = 0x02;
+const RUNS_IPV6: u8 = 0x01;
+
+/// This enum identifies the interface an Extended Echo request asks about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum InterfaceQuery {
+    /// An interface of the probed node, by name.
+    Name(String),
+    /// An interface of the probed node, by its ifIndex.
+    Index(u32),
+    /// An interface of the probed node, by one of its addresses.
+    Address(IpAddr),
+    /// An interface of a node on a link of the probed node, by its address. The
+    /// probed node answers from its ARP table or neighbor cache.
+    Neighbor(IpAddr),
+}
+
+impl InterfaceQuery {
+    /// Returns the c-type and payload of the Interface Identification Object.
+    fn object(&self) -> (u8, Vec<u8>) {
+        match self {
+            InterfaceQuery::Name(name) => {
+                let mut payload = name.as_bytes().to_vec();
+                payload.resize((payload.len() + 3) / 4 * 4, 0);
+                (BY_NAME, payload)
+            }
+            InterfaceQuery::Index(index) => (BY_INDEX, index.to_be_bytes().to_vec()),
+            InterfaceQuery::Address(addr) | InterfaceQuery::Neighbor(addr) => {
+                let (afi, octets) = match addr {
+                    IpAddr::V4(addr) => (1u16, addr.octets().to_vec()),
+                    IpAddr::V6(addr) => (2u16, addr.octets().to_vec()),
+                };
+                let mut payload = afi.to_be_bytes().to_vec();
+                payload.extend_from_slice(&[octets.len() as u8, 0]);
+                payload.extend_from_slice(&octets);
+                (BY_ADDRESS, payload)
+            }
+        }
+    }
+}
+
+/// This enum tells what a node answered about the interface it was asked about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum InterfaceState {
+    Active,
+    Inactive,
+    /// The node could not make sense of the request.
+    MalformedQuery,
+    NoSuchInterface,
+    /// The neighbor asked about is not in the tables of the node.
+    NoSuchTableEntry,
+    /// More than one interface matches the query.
+    MultipleInterfaces,
+}
+
+impl InterfaceState {
+    /// Returns the state a reply of `code` with `flags` tells, None for unknown codes.
+    fn of(code: u8, flags: u8) -> Option<InterfaceState> {
+        Some(match code {
+            0 if flags & ACTIVE != 0 => InterfaceState::Active,
+            0 => InterfaceState::Inactive,
+            1 => InterfaceState::MalformedQuery,
+            2 => InterfaceState::NoSuchInterface,
+            3 => InterfaceState::NoSuchTableEntry,
+            4 => InterfaceState::MultipleInterfaces,
+            _ => return None,
+        })
+    }
+}
+
+/// This enum is the state of the ARP table or neighbor cache entry of a neighbor,
+/// as in RFC 4861.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum NeighborState {
+    Incomplete,
+    Reachable,
+    Stale,
+    Delay,
+    Probe,
+    Failed,
+}
+
+impl NeighborState {
+    fn of(state: u8) -> Option<NeighborState> {
+        Some(match state {
+            1 => NeighborState::Incomplete,
+            2 => NeighborState::Reachable,
+            3 => NeighborState::Stale,
+            4 => NeighborState::Delay,
+            5 => NeighborState::Probe,
+            6 => NeighborState::Failed,
+            _ => return None,
+        })
+    }
+}
+
+/// This struct is the answer of a node to an Extended Echo request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct InterfaceStatus {
+    pub from: IpAddr,
+    pub rtt: Duration,
+    pub interface_state: InterfaceState,
+    /// IPv4 runs on the interface, only told for active ones.
+    pub ipv4: bool,
+    /// IPv6 runs on the interface, only told for active ones.
+    pub ipv6: bool,
+    /// State of the table entry of an active `InterfaceQuery::Neighbor`.
+    pub neighbor_state: Option<NeighborState>,
+}
+
+impl InterfaceStatus {
+    /// Decodes the `code` and `flags` of a reply to a request about `query`.
+    fn new(
+        from: IpAddr,
+        rtt: Duration,
+        query: &InterfaceQuery,
+        code: u8,
+        flags: u8,
+    ) -> Option<InterfaceStatus> {
+        let interface_state = InterfaceState::of(code, flags)?;
+        let active = interface_state == InterfaceState::Active;
+        let neighbor_state = match query {
+            InterfaceQuery::Neighbor(_) if active => NeighborState::of(flags >> 5),
+            _ => None,
+        };
+        Some(InterfaceStatus {
+            from,
+            rtt,
+            interface_state,
+            ipv4: active && flags & RUNS_IPV4 != 0,
+            ipv6: active && flags & RUNS_IPV6 != 0,
+            neighbor_state,
+        })
+    }
+}
+
+/// Returns the ICMP or ICMPv6 message of an Extended Echo request about `query`,
+/// with its checksum left zero.
+fn request(
+    query: &InterfaceQuery,
+    v6: bool,
+    identifier: u16,
+    sequence: u8,
+) -> Result<Vec<u8>, TraceRouteError> {
+    let (c_type, payload) = query.object();
+    let local = !matches!(query, InterfaceQuery::Neighbor(_));
+    let mut message = vec![0; 8];
+    message[0] = if v6 { REQUEST_V6 } else { REQUEST_V4 };
+    message[4..6].copy_from_slice(&identifier.to_be_bytes());
+    message[6] = sequence;
+    message[7] = if local { LOCAL } else { 0 };
+    let mut structure = vec![EXTENSION_VERSION << 4, 0, 0, 0];
+    structure.extend_from_slice(&length_u16(4 + payload.len())?.to_be_bytes());
+    structure.extend_from_slice(&[CLASS_INTERFACE_ID, c_type]);
+    structure.extend_from_slice(&payload);
+    let csum = util::checksum(&structure, 1);
+    structure[2..4].copy_from_slice(&csum.to_be_bytes());
+    message.extend_from_slice(&structure);
+    Ok(message)
+}
+
+/// Builds an Extended Echo request from `source` to `destination` with its IP
+/// header.
+fn build_request(
+    destination: IpAddr,
+    source: IpAddr,
+    query: &InterfaceQuery,
+    identifier: u16,
+    sequence: u8,
+) -> Result<Vec<u8>, TraceRouteError> {
+    let mut message = request(query, destination.is_ipv6(), identifier, sequence)?;
+    match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let csum = util::checksum(&message, 1);
+            message[2..4].copy_from_slice(&csum.to_be_bytes());
+            let header = ipv4::MutableIpv4Packet::minimum_packet_size();
+            let mut packet = vec![0; header + message.len()];
+            let mut ipv4_packet = ipv4::MutableIpv4Packet::new(&mut packet[..]).unwrap();
+            ipv4_packet.set_version(4);
+            ipv4_packet.set_header_length(5);
+            ipv4_packet.set_fragment_offset(16384);
+            ipv4_packet.set_identification(rand::random::<u16>());
+            ipv4_packet.set_ttl(PROBE_TTL);
+            ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+            ipv4_packet.set_source(source);
+            ipv4_packet.set_destination(destination);
+            ipv4_packet.set_total_length(length_u16(header + message.len())?);
+            ipv4_packet.set_payload(&message);
+            let csum = ipv4::checksum(&ipv4_packet.to_immutable());
+            ipv4_packet.set_checksum(csum);
+            Ok(packet)
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            let csum =
+                icmpv6::checksum(&Icmpv6Packet::new(&message).unwrap(), &source, &destination);
+            message[2..4].copy_from_slice(&csum.to_be_bytes());
+            let header = ipv6::MutableIpv6Packet::minimum_packet_size();
+            let mut packet = vec![0; header + message.len()];
+            let mut ipv6_packet = ipv6::MutableIpv6Packet::new(&mut packet[..]).unwrap();
+            ipv6_packet.set_version(6);
+            ipv6_packet.set_hop_limit(PROBE_TTL);
+            ipv6_packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
+            ipv6_packet.set_source(source);
+            ipv6_packet.set_destination(destination);
+            ipv6_packet.set_payload_length(length_u16(message.len())?);
+            ipv6_packet.set_payload(&message);
+            Ok(packet)
+        }
+        _ => Err(TraceRouteError::NoInterface),
+    }
+}
+
+/// Returns the identifier, sequence, code and flags of an Extended Echo reply.
+fn parse_reply(message: &[u8], v6: bool) -> Option<(u16, u8, u8, u8)> {
+    let reply = if v6 { REPLY_V6 } else { REPLY_V4 };
+    if *message.first()? != reply || message.len() < 8 {
+        return None;
+    }
+    let identifier = u16::from_be_bytes([message[4], message[5]]);
+    Some((identifier, message[6], message[1], message[7]))
+}
+
+/// Sends up to `tries` Extended Echo requests about `query` from `source` to
+/// `node`, each once the one before went unanswered for `timeout`, and returns
+/// the first answer.
+pub(crate) fn probe_interface<B: ProbeBackend + ?Sized>(
+    backend: &mut B,
+    node: IpAddr,
+    source: IpAddr,
+    query: &InterfaceQuery,
+    tries: u16,
+    timeout: Duration,
+) -> Result<Option<InterfaceStatus>, TraceRouteError> {
+    let clock = backend.clock();
+    let identifier = next_identifier();
+    let mut sent: Vec<Instant> = Vec::new();
+    for sequence in 1..=u8::try_from(tries).unwrap_or(u8::MAX) {
+        let probe = build_request(node, source, query, identifier, sequence)?;
+        let sent_at = clock.now();
+        backend
+            .send_to(&probe, node)
+            .map_err(|e| TraceRouteError::Send {
+                kind: e.kind(),
+                message: e.to_string(),
+            })?;
+        sent.push(sent_at);
+        let deadline = sent_at + timeout;
+        loop {
+            let now = clock.now();
+            if now >= deadline {
+                break;
+            }
+            let received =
+                backend
+                    .recv_timeout(deadline - now)
+                    .map_err(|e| TraceRouteError::Channel {
+                        kind: e.kind(),
+                        message: e.to_string(),
+                    })?;
+            let (message, from) = match received {
+                Some(received) => received,
+                None => break,
+            };
+            // A late reply to an earlier request answers just as well.
+            let (sent_at, code, flags) = match parse_reply(&message, node.is_ipv6()) {
+                Some((id, answered, code, flags)) if id == identifier && answered >= 1 => {
+                    match sent.get(usize::from(answered) - 1) {
+                        Some(&sent_at) => (sent_at, code, flags),
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+            let received_at = backend.reply_received_at().unwrap_or_else(|| clock.now());
+            let rtt = received_at.saturating_duration_since(sent_at);
+            if let Some(status) = InterfaceStatus::new(from, rtt, query, code, flags) {
+                return Ok(Some(status));
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{extended_echo_reply, test_net_v4, MockClock, SimulatedBackend};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn requests_identify_the_interface() {
+        let by_name = request(&InterfaceQuery::Name("eth0.1".to_string()), false, 7, 3).unwrap();
+        assert_eq!(
+            by_name,
+            [
+                42, 0, 0, 0, 0, 7, 3, 1, 0x20, 0, 0xe1, 0x1c, 0, 12, 3, 1, b'e', b't', b'h', b'0',
+                b'.', b'1', 0, 0
+            ]
+        );
+        assert_eq!(util::checksum(&by_name[8..], 1), 0xe11c);
+        let by_index = request(&InterfaceQuery::Index(2), true, 7, 3).unwrap();
+        assert_eq!(by_index[0], 160);
+        assert_eq!(by_index[12..], [0, 8, 3, 2, 0, 0, 0, 2]);
+        let neighbor = InterfaceQuery::Neighbor(Ipv4Addr::new(192, 0, 2, 9).into());
+        let by_address = request(&neighbor, false, 7, 3).unwrap();
+        // The interface belongs to a neighbor, the local bit is clear.
+        assert_eq!(by_address[7], 0);
+        assert_eq!(by_address[12..], [0, 12, 3, 3, 0, 1, 4, 0, 192, 0, 2, 9]);
+        let v6 = InterfaceQuery::Address(Ipv6Addr::LOCALHOST.into());
+        let by_address = request(&v6, true, 7, 3).unwrap();
+        assert_eq!(by_address[7], 1);
+        assert_eq!(by_address[12..20], [0, 24, 3, 3, 0, 2, 16, 0]);
+        assert_eq!(by_address.len(), 8 + 4 + 24);
+
+        let packet = build_request(test_net_v4(9), test_net_v4(254), &neighbor, 7, 3).unwrap();
+        assert_eq!((packet[8], packet[9]), (PROBE_TTL, 1));
+        let csum = u16::from_be_bytes([packet[22], packet[23]]);
+        assert_eq!(util::checksum(&packet[20..], 1), csum);
+    }
+
+    #[test]
+    fn replies_tell_the_interface_state() {
+        let from = test_net_v4(9);
+        let rtt = Duration::from_millis(3);
+        let query = InterfaceQuery::Name("eth0".to_string());
+        let decode = |message: &[u8], query: &InterfaceQuery| {
+            let (_, _, code, flags) = parse_reply(message, false)?;
+            InterfaceStatus::new(from, rtt, query, code, flags)
+        };
+        let active = decode(&[43, 0, 0, 0, 0, 7, 3, 0x06], &query).unwrap();
+        assert_eq!(active.interface_state, InterfaceState::Active);
+        assert!(active.ipv4 && !active.ipv6);
+        assert_eq!(active.neighbor_state, None);
+        let inactive = decode(&[43, 0, 0, 0, 0, 7, 3, 0x03], &query).unwrap();
+        assert_eq!(inactive.interface_state, InterfaceState::Inactive);
+        assert!(!inactive.ipv4 && !inactive.ipv6);
+        let missing = decode(&[43, 2, 0, 0, 0, 7, 3, 0], &query).unwrap();
+        assert_eq!(missing.interface_state, InterfaceState::NoSuchInterface);
+        let neighbor = InterfaceQuery::Neighbor(test_net_v4(10));
+        let stale = decode(&[43, 0, 0, 0, 0, 7, 3, 0x64], &neighbor).unwrap();
+        assert_eq!(stale.neighbor_state, Some(NeighborState::Stale));
+        assert_eq!(
+            decode(&[43, 3, 0, 0, 0, 7, 3, 0], &neighbor).map(|status| status.interface_state),
+            Some(InterfaceState::NoSuchTableEntry)
+        );
+        assert_eq!(decode(&[43, 9, 0, 0, 0, 7, 3, 0], &query), None);
+        // Plain echo replies and ICMPv6 replies are not taken.
+        assert_eq!(decode(&[0, 0, 0, 0, 0, 7, 3, 0x06], &query), None);
+        assert_eq!(parse_reply(&[161, 0, 0, 0, 0, 7, 3, 0x05], false), None);
+        assert_eq!(
+            parse_reply(&[161, 0, 0, 0, 0, 7, 3, 0x05], true),
+            Some((7, 3, 0, 0x05))
+        );
+
+        let request = build_request(from, test_net_v4(254), &query, 7, 3).unwrap();
+        let reply = extended_echo_reply(&request, from, 0, ACTIVE | RUNS_IPV4);
+        assert_eq!(reply[..2], [43, 0]);
+        assert_eq!(parse_reply(&reply, false), Some((7, 3, 0, 0x06)));
+    }
+
+    #[test]
+    fn requests_are_retried_until_answered() {
+        let backend = SimulatedBackend::new(vec![Some(test_net_v4(1))], test_net_v4(9))
+            .with_clock(MockClock::new())
+            .with_reply_delay(Duration::from_millis(4))
+            .with_probed_interfaces(vec!["eth0".to_string()])
+            .with_outage(1);
+        let mut sent = backend.clone();
+        let query = InterfaceQuery::Name("eth0".to_string());
+        let timeout = Duration::from_millis(100);
+        let status = probe_interface(
+            &mut sent,
+            test_net_v4(9),
+            test_net_v4(254),
+            &query,
+            3,
+            timeout,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(backend.probes_sent(), 2);
+        assert_eq!(status.from, test_net_v4(9));
+        assert_eq!(status.rtt, Duration::from_millis(4));
+        assert_eq!(status.interface_state, InterfaceState::Active);
+        assert!(status.ipv4);
+
+        let unknown = InterfaceQuery::Name("eth1".to_string());
+        let mut other = backend.clone();
+        let status = probe_interface(
+            &mut other,
+            test_net_v4(9),
+            test_net_v4(254),
+            &unknown,
+            1,
+            timeout,
+        );
+        assert_eq!(
+            status.unwrap().map(|status| status.interface_state),
+            Some(InterfaceState::NoSuchInterface)
+        );
+    }
+}
@@ -0,0 +1,341 @@
+//! Python bindings, with the `python` feature.
+//!
+//! The `librtraceroute` module has a `TraceRoute` class taking the destination and
+//! the options of `TraceRouteConfig` as keyword arguments. `run()` returns an
+//! iterator of hop dicts, `trace()` the whole list. A module can be built with
+//! maturin, with `cdylib` as the crate type and the `pyo3/extension-module` feature.
+// The wrappers pyo3 generates convert the errors of `PyResult` methods once more.
+#![allow(clippy::useless_conversion)]
+use crate::TraceRouteProtocol;
+use crate::{HopFound, TraceHandle, TraceRoute, TraceRouteConfig, TraceRouteError};
+use pyo3::exceptions::{PyOSError, PyPermissionError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+/// How long the iterator waits for a hop without the GIL before checking signals.
+const SIGNAL_POLL: Duration = Duration::from_millis(50);
+
+/// Maps an error of the library to a Python exception.
+fn py_err(err: TraceRouteError) -> PyErr {
+    match err {
+        TraceRouteError::PermissionDenied => PyPermissionError::new_err(format!(
+            "{}: raw sockets need root or the CAP_NET_RAW capability",
+            err
+        )),
+        TraceRouteError::MarkDenied => PyPermissionError::new_err(err.to_string()),
+        TraceRouteError::NoInterface
+        | TraceRouteError::NoFirstHop
+        | TraceRouteError::NoNeighbor(_)
+        | TraceRouteError::NoBackend { .. }
+        | TraceRouteError::Channel { .. }
+        | TraceRouteError::Send { .. }
+        | TraceRouteError::WorkerStopped
+        | TraceRouteError::HopTimeout => PyOSError::new_err(err.to_string()),
+        _ => PyValueError::new_err(err.to_string()),
+    }
+}
+
+/// This class describes a trace, mirroring `TraceRouteConfig`.
+#[pyclass(name = "TraceRoute", module = "librtraceroute")]
+pub struct PyTraceRoute {
+    destination: IpAddr,
+    config: TraceRouteConfig,
+}
+
+#[pymethods]
+impl PyTraceRoute {
+    #[new]
+    #[pyo3(signature = (
+        destination,
+        *,
+        protocol = "udp",
+        begin_ttl = 1,
+        max_ttl = 30,
+        max_tries = 4,
+        timeout = Some(200),
+        port = 33434,
+        size = 64,
+        hide_local_hops = false,
+        confirm_silent_destination = false,
+        gateways = Vec::new(),
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        destination: &str,
+        protocol: &str,
+        begin_ttl: u8,
+        max_ttl: u8,
+        max_tries: u16,
+        timeout: Option<u64>,
+        port: u16,
+        size: usize,
+        hide_local_hops: bool,
+        confirm_silent_destination: bool,
+        gateways: Vec<String>,
+    ) -> PyResult<PyTraceRoute> {
+        let destination = destination
+            .parse::<IpAddr>()
+            .map_err(|e| PyValueError::new_err(format!("{}: {}", destination, e)))?;
+        let protocol = protocol
+            .parse::<TraceRouteProtocol>()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let gateways = gateways
+            .iter()
+            .map(|gateway| {
+                gateway
+                    .parse::<Ipv4Addr>()
+                    .map_err(|e| PyValueError::new_err(format!("{}: {}", gateway, e)))
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+        let config = TraceRouteConfig {
+            protocol,
+            begin_ttl,
+            max_ttl,
+            max_tries,
+            timeout: timeout.map(Duration::from_millis),
+            port,
+            size,
+            hide_local_hops,
+            confirm_silent_destination,
+            gateways,
+            ..TraceRouteConfig::default()
+        };
+        config.validate(destination).map_err(py_err)?;
+        Ok(PyTraceRoute {
+            destination,
+            config,
+        })
+    }
+
+    /// Starts the trace, returns an iterator of the hops as they are found.
+    fn run(&self) -> PyResult<Hops> {
+        self.start(TraceRoute::run_trace_route)
+    }
+
+    /// Runs the trace to its end, returns the list of hops.
+    fn trace(&self, py: Python<'_>) -> PyResult<Vec<PyObject>> {
+        let mut hops = self.run()?;
+        let mut found = Vec::new();
+        while let Some(hop) = hops.next_hop(py)? {
+            found.push(hop);
+        }
+        Ok(found)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "TraceRoute('{}', protocol='{}', max_ttl={})",
+            self.destination, self.config.protocol, self.config.max_ttl
+        )
+    }
+}
+
+impl PyTraceRoute {
+    /// Starts the trace with `run`, which starts the worker.
+    fn start<F>(&self, run: F) -> PyResult<Hops>
+    where
+        F: FnOnce(&TraceRoute) -> Result<TraceHandle, TraceRouteError>,
+    {
+        let (trace_route, receiver) =
+            TraceRoute::with_config(self.destination, self.config.clone()).map_err(py_err)?;
+        let handle = run(&trace_route).map_err(py_err)?;
+        Ok(Hops {
+            receiver: Some(receiver),
+            handle: Some(handle),
+        })
+    }
+}
+
+/// This class iterates the hops of a running trace.
+///
+/// The worker is stopped when the iterator is closed, dropped or interrupted.
+#[pyclass(module = "librtraceroute")]
+pub struct Hops {
+    receiver: Option<Receiver<HopFound>>,
+    handle: Option<TraceHandle>,
+}
+
+#[pymethods]
+impl Hops {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        self.next_hop(py)
+    }
+
+    /// Stops the trace and waits for its worker.
+    fn close(&mut self, py: Python<'_>) {
+        self.receiver = None;
+        if let Some(handle) = self.handle.take() {
+            handle.cancel();
+            py.allow_threads(move || {
+                let _ = handle.join();
+            });
+        }
+    }
+}
+
+impl Hops {
+    /// Waits for the next hop without holding the GIL, closes the trace after the
+    /// last hop or on a pending signal like KeyboardInterrupt.
+    fn next_hop(&mut self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        loop {
+            let receiver = match self.receiver.take() {
+                Some(receiver) => receiver,
+                None => return Ok(None),
+            };
+            let (receiver, received) = py.allow_threads(move || {
+                let received = receiver.recv_timeout(SIGNAL_POLL);
+                (receiver, received)
+            });
+            match received {
+                Ok(hop) => {
+                    self.receiver = Some(receiver);
+                    if hop.is_last {
+                        self.close(py);
+                    }
+                    return hop_dict(py, &hop).map(Some);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.receiver = Some(receiver);
+                    if let Err(err) = py.check_signals() {
+                        self.close(py);
+                        return Err(err);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.close(py);
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+/// Converts a hop to a dict.
+fn hop_dict(py: Python<'_>, hop: &HopFound) -> PyResult<PyObject> {
+    let dict = PyDict::new_bound(py);
+    dict.set_item("hop", hop.hop_count)?;
+    dict.set_item("addr", hop.addr.map(|addr| addr.to_string()))?;
+    dict.set_item("rtt_ms", hop.time.map(|time| time.as_secs_f64() * 1000.0))?;
+    dict.set_item("tries", hop.tries)?;
+    dict.set_item("is_last", hop.is_last)?;
+    dict.set_item("timeout", hop.is_timeout())?;
+    dict.set_item("end_of_trace", hop.is_end_of_trace())?;
+    dict.set_item(
+        "completion",
+        hop.completion.map(|reason| format!("{:?}", reason)),
+    )?;
+    dict.set_item("local_hops", hop.local_hops)?;
+    dict.set_item("trace_id", hop.trace_id)?;
+    Ok(dict.into_any().unbind())
+}
+
+/// The `librtraceroute` Python module.
+#[pymodule]
+fn librtraceroute(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyTraceRoute>()?;
+    m.add_class::<Hops>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_net_v4, SimulatedBackend};
+    use pyo3::types::PyList;
+
+    fn traced(py: Python<'_>, backend: SimulatedBackend) -> Bound<'_, Hops> {
+        let trace_route = PyTraceRoute::new(
+            "192.0.2.100",
+            "udp",
+            1,
+            30,
+            1,
+            Some(10),
+            33434,
+            64,
+            false,
+            false,
+            Vec::new(),
+        )
+        .unwrap();
+        let hops = trace_route
+            .start(|trace_route| trace_route.run_with_backend(backend, test_net_v4(254)))
+            .unwrap();
+        Bound::new(py, hops).unwrap()
+    }
+
+    #[test]
+    fn hops_are_yielded_as_dicts() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let backend = SimulatedBackend::new(vec![Some(test_net_v4(1)), None], test_net_v4(100));
+            let hops = traced(py, backend);
+            let list = PyList::empty_bound(py);
+            list.call_method1("extend", (hops,)).unwrap();
+            assert_eq!(list.len(), 3);
+            let first = list.get_item(0).unwrap();
+            assert_eq!(
+                first.get_item("addr").unwrap().extract::<String>().unwrap(),
+                "192.0.2.1"
+            );
+            assert!(first.get_item("rtt_ms").unwrap().extract::<f64>().is_ok());
+            assert!(list
+                .get_item(1)
+                .unwrap()
+                .get_item("addr")
+                .unwrap()
+                .is_none());
+            let last = list.get_item(2).unwrap();
+            assert!(last.get_item("is_last").unwrap().extract::<bool>().unwrap());
+            assert_eq!(
+                last.get_item("completion")
+                    .unwrap()
+                    .extract::<String>()
+                    .unwrap(),
+                "Reached"
+            );
+        });
+    }
+
+    #[test]
+    fn closing_stops_the_worker() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let path = (1..=20).map(|n| Some(test_net_v4(n))).collect();
+            let backend = SimulatedBackend::new(path, test_net_v4(100))
+                .with_reply_delay(Duration::from_millis(20));
+            let hops = traced(py, backend.clone());
+            hops.call_method0("__next__").unwrap();
+            hops.call_method0("close").unwrap();
+            assert!(backend.probes_sent() <= 4, "{}", backend.probes_sent());
+            assert!(hops.call_method0("__next__").is_err());
+        });
+    }
+
+    #[test]
+    fn errors_map_to_exceptions() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let class = py.get_type_bound::<PyTraceRoute>();
+            let err = class.call1(("not an address",)).unwrap_err();
+            assert!(err.is_instance_of::<PyValueError>(py));
+            let err = class.call1(("224.0.0.1",)).unwrap_err();
+            assert!(err.to_string().contains("multicast"));
+            let kwargs = PyDict::new_bound(py);
+            kwargs.set_item("protocol", "sctp").unwrap();
+            let err = class.call(("192.0.2.1",), Some(&kwargs)).unwrap_err();
+            assert!(err.is_instance_of::<PyValueError>(py));
+
+            let err = py_err(TraceRouteError::PermissionDenied);
+            assert!(err.is_instance_of::<PyPermissionError>(py));
+            assert!(err.to_string().contains("CAP_NET_RAW"));
+        });
+    }
+}
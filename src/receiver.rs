@@ -0,0 +1,258 @@
+//! The receiving half of a trace, classifying replies against the probes sent.
+use crate::backend::{ProbeBackend, ReplyKind, TimestampSource};
+use crate::clock::Clock;
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::monitor::FirstHopMonitor;
+use crate::registry::{ProbeRecord, ProbeRegistry};
+use crate::reply::{self, MalformedReply, MangledField, ParsedReply};
+use crate::{lookup, mangling_of, probe_options_of, raw_reply_of, TraceRouteConfig};
+use std::io;
+use std::net::IpAddr;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// This struct holds the probes a trace has outstanding, shared by its sender
+/// and its receiver.
+#[derive(Default)]
+pub(crate) struct Outstanding {
+    pub registry: ProbeRegistry,
+    pub monitor: Option<FirstHopMonitor>,
+    /// Sequence numbers of the TCP SYNs confirming a silent destination.
+    pub syn_sequences: Vec<u32>,
+}
+
+/// This struct is a reply to a probe of the trace, with what the sender needs
+/// of the backend to report it.
+pub(crate) struct Reply {
+    pub message: Vec<u8>,
+    pub from: IpAddr,
+    pub parsed: ParsedReply,
+    /// Record of the probe answered, None if the reply can not be told apart.
+    pub record: Option<ProbeRecord>,
+    pub mangling: Option<Vec<MangledField>>,
+    /// IP options of the probe quoted back, only taken if the trace records them.
+    pub options: Vec<u8>,
+    pub raw_reply: Option<Vec<u8>>,
+    pub received_at: Instant,
+    /// Who timed `received_at`.
+    pub timestamp_source: TimestampSource,
+}
+
+/// This enum is what the receiver of a trace hands over to its sender.
+pub(crate) enum Event {
+    Reply(Box<Reply>),
+    /// The destination answered a DCCP probe with `message`.
+    Transport {
+        message: Vec<u8>,
+        raw_reply: Option<Vec<u8>>,
+        received_at: Instant,
+        timestamp_source: TimestampSource,
+    },
+    /// The destination answered the TCP SYN confirming it.
+    TcpAnswer,
+    Failed(io::Error),
+    Malformed(MalformedReply),
+}
+
+/// This struct sorts out the messages received for a trace.
+///
+/// Messages of other traces and processes and the replies the first hop monitor
+/// claims end here, the rest is sent over `events`.
+#[derive(Clone)]
+pub(crate) struct ReplyReceiver {
+    config: TraceRouteConfig,
+    ip: IpAddr,
+    source: IpAddr,
+    outstanding: Arc<Mutex<Outstanding>>,
+    events: Sender<Event>,
+    metrics: Arc<Metrics>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ReplyReceiver {
+    /// Creates new ReplyReceiver for the trace of `ip` from `source`.
+    pub fn new(
+        config: TraceRouteConfig,
+        ip: IpAddr,
+        source: IpAddr,
+        outstanding: Arc<Mutex<Outstanding>>,
+        events: Sender<Event>,
+        metrics: Arc<Metrics>,
+        clock: Arc<dyn Clock>,
+    ) -> ReplyReceiver {
+        ReplyReceiver {
+            config,
+            ip,
+            source,
+            outstanding,
+            events,
+            metrics,
+            clock,
+        }
+    }
+
+    /// Returns true if a message read for many traces, like by a `SharedReceiver`,
+    /// answers a probe of this trace. Messages without a probe key belong to the
+    /// trace of the address they came from.
+    pub fn owns(&self, kind: ReplyKind, message: &[u8], from: IpAddr) -> bool {
+        let v4 = self.ip.is_ipv4();
+        if from.is_ipv4() != v4 {
+            return false;
+        }
+        if kind == ReplyKind::Transport {
+            return from == self.ip;
+        }
+        if message.first() == Some(if v4 { &8 } else { &128 }) {
+            return false;
+        }
+        let outstanding = self.outstanding.lock().unwrap();
+        if outstanding
+            .monitor
+            .as_ref()
+            .is_some_and(|monitor| monitor.expects(message))
+        {
+            return true;
+        }
+        let parsed = match if v4 {
+            reply::parse_v4(message)
+        } else {
+            reply::parse_v6(message)
+        } {
+            Ok(parsed) => parsed,
+            Err(_) => return false,
+        };
+        match lookup(
+            &outstanding.registry,
+            parsed.key,
+            self.quoted(message),
+            self.source,
+        ) {
+            Some(found) => found.is_some(),
+            None => from == self.ip,
+        }
+    }
+
+    /// Counts a message the trace got from a `SharedReceiver`, past its backend.
+    pub fn count(&self, message: &[u8]) {
+        self.metrics.received(message.len());
+    }
+
+    /// Classifies what `backend` received last.
+    pub fn take<B: ProbeBackend>(
+        &self,
+        backend: &B,
+        received: io::Result<(ReplyKind, Vec<u8>, IpAddr)>,
+    ) {
+        let event = match received {
+            Ok((kind, message, from)) => match self.classify(backend, kind, message, from) {
+                Some(event) => event,
+                None => return,
+            },
+            Err(e) => Event::Failed(e),
+        };
+        // The sender outlives its receiver.
+        let _ = self.events.send(event);
+    }
+
+    fn classify<B: ProbeBackend>(
+        &self,
+        backend: &B,
+        kind: ReplyKind,
+        message: Vec<u8>,
+        from: IpAddr,
+    ) -> Option<Event> {
+        let v4 = self.ip.is_ipv4();
+        let (received_at, timestamp_source) = match backend.reply_received_at() {
+            Some(at) => (at, backend.reply_timestamp_source()),
+            None => (self.clock.now(), TimestampSource::Userspace),
+        };
+        let raw_reply = || raw_reply_of(&self.config, || backend.reply_header(), &message);
+        let echo_request = if v4 { 8 } else { 128 };
+        let mut outstanding = self.outstanding.lock().unwrap();
+        match kind {
+            ReplyKind::Transport => {
+                if from == self.ip && reply::is_dccp_answer(&message) {
+                    return Some(Event::Transport {
+                        raw_reply: raw_reply_of(&self.config, || None, &message),
+                        message,
+                        received_at,
+                        timestamp_source,
+                    });
+                }
+                let confirm_port = self.config.confirm_tcp(self.ip);
+                let sequences = &outstanding.syn_sequences;
+                if from == self.ip
+                    && confirm_port
+                        .is_some_and(|port| reply::is_tcp_answer(&message, port, sequences))
+                {
+                    return Some(Event::TcpAnswer);
+                }
+                self.metrics.foreign_reply();
+                return None;
+            }
+            _ if message.first() == Some(&echo_request) => return None,
+            ReplyKind::Icmp
+                if outstanding
+                    .monitor
+                    .as_mut()
+                    .is_some_and(|monitor| monitor.claim(&message, from, received_at)) =>
+            {
+                return None
+            }
+            _ => {}
+        }
+        let parsed = match if v4 {
+            reply::parse_v4(&message)
+        } else {
+            reply::parse_v6(&message)
+        } {
+            Ok(parsed) => parsed,
+            Err(reason) => {
+                self.metrics.malformed_reply();
+                let malformed = MalformedReply {
+                    from: Some(from),
+                    reason,
+                    len: message.len(),
+                    raw: raw_reply(),
+                };
+                logging::malformed_reply(&malformed);
+                return Some(Event::Malformed(malformed));
+            }
+        };
+        let quoted = self.quoted(&message);
+        let record = match lookup(&outstanding.registry, parsed.key, quoted, self.source) {
+            Some(None) => {
+                self.metrics.foreign_reply();
+                return None;
+            }
+            found => found.flatten().cloned(),
+        };
+        let options = if v4 && (self.config.record_route || self.config.timestamps) {
+            probe_options_of(backend, &message)
+        } else {
+            Vec::new()
+        };
+        Some(Event::Reply(Box::new(Reply {
+            mangling: mangling_of(&outstanding.registry, parsed.key, quoted, self.source),
+            raw_reply: raw_reply(),
+            message,
+            from,
+            parsed,
+            record,
+            options,
+            received_at,
+            timestamp_source,
+        })))
+    }
+
+    /// Returns the probe quoted in an error message.
+    fn quoted<'m>(&self, message: &'m [u8]) -> Option<&'m [u8]> {
+        if self.ip.is_ipv4() {
+            reply::quoted_v4(message)
+        } else {
+            reply::quoted_v6(message)
+        }
+    }
+}
@@ -0,0 +1,385 @@
+//! Scrubbing addresses out of traces before they are shared.
+use crate::hmac::hmac_sha256;
+use crate::{addr_scope, transition_tech, AddrScope, HopFound, TraceReport};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// This enum tells what a `Redactor` does to an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RedactPolicy {
+    Keep,
+    /// Removes the address, hops keep their times but look unanswered. Addresses
+    /// that cannot be removed, like those of the trace, become unspecified.
+    Drop,
+    /// Keeps the first 24 bits of IPv4 and the first 48 bits of IPv6 addresses.
+    Truncate,
+    /// Replaces the address by one derived from it with HMAC-SHA-256 under the
+    /// key of the `Redactor`, from 240.0.0.0/4 for IPv4 and from the discard
+    /// prefix 100::/64 for IPv6, so pseudonyms never pass for real addresses.
+    Pseudonymize,
+}
+
+/// This struct rewrites the addresses of hops and reports by their scope, the
+/// same address always the same way, so the paths keep their shape.
+///
+/// Addresses of global scope are handled by one policy, all others, private,
+/// shared, link local and the like, by another. Two addresses never get the
+/// same pseudonym: when their digests meet, the later one is derived again with
+/// a counter, so pseudonyms of colliding addresses depend on the order they were
+/// met in.
+pub struct Redactor {
+    key: Vec<u8>,
+    private: RedactPolicy,
+    global: RedactPolicy,
+    pseudonyms: HashMap<IpAddr, IpAddr>,
+    taken: HashSet<IpAddr>,
+}
+
+impl Redactor {
+    /// Creates new Redactor pseudonymizing all but global addresses under `key`.
+    pub fn new(key: &[u8]) -> Redactor {
+        Redactor {
+            key: key.to_vec(),
+            private: RedactPolicy::Pseudonymize,
+            global: RedactPolicy::Keep,
+            pseudonyms: HashMap::new(),
+            taken: HashSet::new(),
+        }
+    }
+
+    /// Sets the policy of addresses of any scope but global.
+    pub fn with_private(mut self, policy: RedactPolicy) -> Redactor {
+        self.private = policy;
+        self
+    }
+
+    /// Sets the policy of addresses of global scope.
+    pub fn with_global(mut self, policy: RedactPolicy) -> Redactor {
+        self.global = policy;
+        self
+    }
+
+    /// Returns what `addr` is rewritten to, None if it is dropped.
+    pub fn redact_addr(&mut self, addr: IpAddr) -> Option<IpAddr> {
+        let policy = match addr_scope(addr) {
+            AddrScope::Global => self.global,
+            _ => self.private,
+        };
+        match policy {
+            RedactPolicy::Keep => Some(addr),
+            RedactPolicy::Drop => None,
+            RedactPolicy::Truncate => Some(truncate(addr)),
+            RedactPolicy::Pseudonymize => Some(self.pseudonym(addr)),
+        }
+    }
+
+    /// Rewrites every address of `hop`: the responding one and the IPv4 one it
+    /// carries, those recorded by routers or told in interface information, and
+    /// those found in the raw reply, whose checksums are not fixed up. The scope
+    /// and transition technology are those of the rewritten address.
+    pub fn redact_hop(&mut self, hop: &mut HopFound) {
+        let mut rewritten = Vec::new();
+        if let Some(addr) = hop.addr {
+            hop.addr = self.rewrite(addr, &mut rewritten);
+            hop.addr_scope = hop.addr.map(addr_scope);
+            hop.transition = hop.addr.and_then(transition_tech);
+        }
+        if let Some(v4) = hop.embedded_v4 {
+            hop.embedded_v4 = self.rewrite_v4(v4, &mut rewritten);
+        }
+        let route = std::mem::take(&mut hop.recorded_route);
+        hop.recorded_route = route
+            .into_iter()
+            .filter_map(|addr| self.rewrite_v4(addr, &mut rewritten))
+            .collect();
+        let timestamps = std::mem::take(&mut hop.timestamps);
+        hop.timestamps = timestamps
+            .into_iter()
+            .filter_map(|(addr, time)| Some((self.rewrite_v4(addr, &mut rewritten)?, time)))
+            .collect();
+        if let Some(interface) = hop.incoming_interface.as_mut() {
+            if let Some(addr) = interface.addr {
+                interface.addr = self.rewrite(addr, &mut rewritten);
+            }
+        }
+        if let Some(raw) = hop.raw_reply.as_mut() {
+            for addr in header_addrs(raw) {
+                self.rewrite(addr, &mut rewritten);
+            }
+            for (from, to) in &rewritten {
+                replace_all(raw, &octets(*from), &octets(*to));
+            }
+        }
+    }
+
+    /// Rewrites the addresses of the trace and of its hops. The name the
+    /// destination was looked up by is removed unless its address is kept.
+    pub fn redact_report(&mut self, report: &mut TraceReport) {
+        let metadata = &mut report.metadata;
+        let destination = metadata.destination;
+        metadata.source = self
+            .redact_addr(metadata.source)
+            .unwrap_or_else(|| unspecified(metadata.source));
+        metadata.destination = self
+            .redact_addr(destination)
+            .unwrap_or_else(|| unspecified(destination));
+        if metadata.destination != destination {
+            report.host = None;
+        }
+        for hop in &mut report.hops {
+            self.redact_hop(hop);
+        }
+    }
+
+    /// Redacts `addr` and notes what its bytes are to be replaced with.
+    fn rewrite(&mut self, addr: IpAddr, rewritten: &mut Vec<(IpAddr, IpAddr)>) -> Option<IpAddr> {
+        let redacted = self.redact_addr(addr);
+        if redacted != Some(addr) {
+            rewritten.push((addr, redacted.unwrap_or_else(|| unspecified(addr))));
+        }
+        redacted
+    }
+
+    fn rewrite_v4(
+        &mut self,
+        addr: Ipv4Addr,
+        rewritten: &mut Vec<(IpAddr, IpAddr)>,
+    ) -> Option<Ipv4Addr> {
+        match self.rewrite(IpAddr::V4(addr), rewritten)? {
+            IpAddr::V4(addr) => Some(addr),
+            IpAddr::V6(_) => None,
+        }
+    }
+
+    fn pseudonym(&mut self, addr: IpAddr) -> IpAddr {
+        if let Some(pseudonym) = self.pseudonyms.get(&addr) {
+            return *pseudonym;
+        }
+        let mut counter = 0u32;
+        loop {
+            let digest = hmac_sha256(
+                &self.key,
+                &[octets(addr), counter.to_be_bytes().to_vec()].concat(),
+            );
+            let pseudonym = match addr {
+                IpAddr::V4(_) => {
+                    let bits = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+                    IpAddr::V4(Ipv4Addr::from(0xf000_0000 | (bits >> 4)))
+                }
+                IpAddr::V6(_) => {
+                    let mut bits = [0u8; 16];
+                    bits[..2].copy_from_slice(&[0x01, 0x00]);
+                    bits[8..].copy_from_slice(&digest[..8]);
+                    IpAddr::V6(Ipv6Addr::from(bits))
+                }
+            };
+            if self.taken.insert(pseudonym) {
+                self.pseudonyms.insert(addr, pseudonym);
+                return pseudonym;
+            }
+            counter += 1;
+        }
+    }
+}
+
+fn truncate(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => IpAddr::V4(Ipv4Addr::from(u32::from(addr) & 0xffff_ff00)),
+        IpAddr::V6(addr) => IpAddr::V6(Ipv6Addr::from(u128::from(addr) & (!0u128 << 80))),
+    }
+}
+
+fn unspecified(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
+}
+
+fn octets(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+/// Returns the addresses of the IP header starting `raw`, if any, and of the
+/// probe header quoted after the ICMP header following it.
+fn header_addrs(raw: &[u8]) -> Vec<IpAddr> {
+    let mut addrs = Vec::new();
+    let outer = ip_header(raw, &mut addrs);
+    ip_header(raw.get(outer + 8..).unwrap_or_default(), &mut addrs);
+    addrs
+}
+
+/// Adds the source and destination of the IP header starting `bytes` to
+/// `addrs`, and returns its length, 0 if `bytes` start with none.
+fn ip_header(bytes: &[u8], addrs: &mut Vec<IpAddr>) -> usize {
+    match bytes.first().map(|b| b >> 4) {
+        Some(4) if bytes.len() >= 20 => {
+            for at in [12, 16] {
+                let octets = [bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]];
+                addrs.push(IpAddr::V4(Ipv4Addr::from(octets)));
+            }
+            usize::from(bytes[0] & 0x0f) * 4
+        }
+        Some(6) if bytes.len() >= 40 => {
+            for at in [8, 24] {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&bytes[at..at + 16]);
+                addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            40
+        }
+        _ => 0,
+    }
+}
+
+fn replace_all(bytes: &mut [u8], from: &[u8], to: &[u8]) {
+    let mut at = 0;
+    while at + from.len() <= bytes.len() {
+        if bytes[at..at + from.len()] == *from {
+            bytes[at..at + from.len()].copy_from_slice(to);
+            at += from.len();
+        } else {
+            at += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TraceMetadata, TraceRouteProtocol, TransitionTech};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn private(n: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 1, 2, n))
+    }
+
+    fn global(n: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(9, 9, 9, n))
+    }
+
+    fn report() -> TraceReport {
+        let metadata = TraceMetadata {
+            source: private(254),
+            destination: global(9),
+            protocol: TraceRouteProtocol::Udp,
+            identifier: 0x1234,
+            trace_id: 7,
+            fwmark: None,
+            preflight: None,
+            backend: None,
+            size: 32,
+            started_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        };
+        let mut first = HopFound::new(1, Some(private(1)), 0, false, None);
+        first.recorded_route = vec![Ipv4Addr::new(10, 1, 2, 254), Ipv4Addr::new(10, 1, 2, 1)];
+        // Time Exceeded from 10.1.2.1 quoting the probe of 10.1.2.254.
+        let mut outer = vec![0x45, 0, 0, 56, 0, 0, 0, 0, 254, 1, 0, 0];
+        outer.extend_from_slice(&[10, 1, 2, 1, 10, 1, 2, 254]);
+        let mut quoted = vec![0x45, 0, 0, 32, 0, 0, 0, 0, 1, 17, 0, 0];
+        quoted.extend_from_slice(&[10, 1, 2, 254, 9, 9, 9, 9]);
+        first.raw_reply = Some([outer, vec![11, 0, 0, 0, 0, 0, 0, 0], quoted].concat());
+        let second = HopFound::new(2, Some(private(1)), 0, false, None);
+        let last = HopFound::new(3, Some(global(9)), 0, true, None);
+        let mut report = TraceReport::new(metadata, vec![first, second, last]);
+        report.host = Some("example.net".to_string());
+        report
+    }
+
+    #[test]
+    fn same_address_same_pseudonym() {
+        let mut report = report();
+        Redactor::new(b"secret").redact_report(&mut report);
+        let pseudonym = report.hops[0].addr.unwrap();
+        assert_ne!(pseudonym, private(1));
+        assert_eq!(addr_scope(pseudonym), AddrScope::Reserved);
+        assert_eq!(report.hops[1].addr, Some(pseudonym));
+        assert_eq!(report.hops[0].recorded_route[1], pseudonym);
+        assert_eq!(
+            IpAddr::V4(report.hops[0].recorded_route[0]),
+            report.metadata.source
+        );
+        assert_eq!(report.hops[2].addr, Some(global(9)));
+        assert_eq!(report.host.as_deref(), Some("example.net"));
+
+        // Under the same key, another redactor agrees, under another it does not.
+        assert_eq!(
+            Redactor::new(b"secret").redact_addr(private(1)),
+            Some(pseudonym)
+        );
+        assert_ne!(
+            Redactor::new(b"other").redact_addr(private(1)),
+            Some(pseudonym)
+        );
+    }
+
+    #[test]
+    fn no_private_address_survives() {
+        let mut report = report();
+        Redactor::new(b"secret").redact_report(&mut report);
+        let raw = report.hops[0].raw_reply.as_ref().unwrap();
+        assert!(!raw.windows(3).any(|bytes| bytes == [10, 1, 2]));
+        assert!(raw.windows(4).any(|bytes| bytes == [9, 9, 9, 9]));
+        assert!(!report.to_atlas_json().contains("10.1.2."));
+        #[cfg(feature = "serde")]
+        assert!(!report.to_json().contains("10.1.2."));
+    }
+
+    #[test]
+    fn policies_drop_and_truncate() {
+        let mut report = report();
+        Redactor::new(b"secret")
+            .with_private(RedactPolicy::Drop)
+            .with_global(RedactPolicy::Truncate)
+            .redact_report(&mut report);
+        assert_eq!(report.metadata.source, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(report.metadata.destination, global(0));
+        assert_eq!(report.host, None);
+        assert_eq!(report.hops[0].addr, None);
+        assert!(report.hops[0].recorded_route.is_empty());
+        assert_eq!(report.hops[2].addr, Some(global(0)));
+
+        let v6: IpAddr = "2001:db8:1:2:3::4".parse().unwrap();
+        assert_eq!(truncate(v6), "2001:db8:1::".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn embedded_addresses_are_redacted() {
+        let teredo: IpAddr = "2001:0:4136:e378:8000:63bf:3fff:fdd2".parse().unwrap();
+        let mut hop = HopFound::new(4, Some(teredo), 0, false, None);
+        assert_eq!(hop.transition, Some(TransitionTech::Teredo));
+        assert_eq!(hop.embedded_v4, Some(Ipv4Addr::new(192, 0, 2, 45)));
+
+        let mut redactor = Redactor::new(b"secret").with_global(RedactPolicy::Drop);
+        redactor.redact_hop(&mut hop);
+        assert_eq!(hop.addr, None);
+        assert_eq!((hop.addr_scope, hop.transition), (None, None));
+        let embedded = hop.embedded_v4.unwrap();
+        assert_eq!(embedded.octets()[0] & 0xf0, 0xf0);
+
+        // Pseudonyms are reserved addresses of no transition technology.
+        let mut relayed = HopFound::new(5, Some(IpAddr::from([192, 88, 99, 1])), 0, false, None);
+        Redactor::new(b"secret")
+            .with_global(RedactPolicy::Pseudonymize)
+            .redact_hop(&mut relayed);
+        assert_eq!(relayed.addr_scope, Some(AddrScope::Reserved));
+        assert_eq!(relayed.transition, None);
+    }
+
+    #[test]
+    fn pseudonyms_never_collide() {
+        let mut redactor = Redactor::new(b"secret");
+        let mut seen = HashSet::new();
+        for n in 0..=u16::MAX {
+            let addr = IpAddr::V4(Ipv4Addr::new(10, 0, (n >> 8) as u8, n as u8));
+            assert!(seen.insert(redactor.redact_addr(addr).unwrap()));
+        }
+        // Addresses met again keep the pseudonym they were given.
+        let first = redactor.redact_addr(private(9));
+        assert_eq!(redactor.redact_addr(private(9)), first);
+    }
+}
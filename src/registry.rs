@@ -0,0 +1,62 @@
+//! Bookkeeping of probes that are waiting for a reply.
+use crate::reply::ProbeKey;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// This struct remembers when, with which TTL and how a probe was sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ProbeRecord {
+    pub ttl: u8,
+    pub sent_at: Instant,
+    /// Headers of the probe as sent, see `reply::snapshot`.
+    pub sent: Vec<u8>,
+}
+
+/// This struct maps the keys of sent probes to their records.
+#[derive(Debug, Default)]
+pub(crate) struct ProbeRegistry {
+    probes: HashMap<ProbeKey, ProbeRecord>,
+}
+
+impl ProbeRegistry {
+    /// Remembers a sent probe.
+    pub fn insert(&mut self, key: ProbeKey, record: ProbeRecord) {
+        self.probes.insert(key, record);
+    }
+
+    /// Forgets a sent probe, later replies to it are foreign.
+    pub fn remove(&mut self, key: &ProbeKey) {
+        self.probes.remove(key);
+    }
+
+    /// Returns the record of the probe a reply belongs to.
+    pub fn get(&self, key: &ProbeKey) -> Option<&ProbeRecord> {
+        self.probes.get(key)
+    }
+
+    /// Returns the record of a probe whose source port or identifier an address
+    /// translator rewrote, by the part of the key it leaves alone.
+    pub fn get_translated(&self, key: &ProbeKey) -> Option<&ProbeRecord> {
+        self.get(key).or_else(|| {
+            self.probes
+                .iter()
+                .find(|(sent, _)| match (sent, key) {
+                    (
+                        ProbeKey::Udp {
+                            destination_port: a,
+                            ..
+                        },
+                        ProbeKey::Udp {
+                            destination_port: b,
+                            ..
+                        },
+                    ) => a == b,
+                    (ProbeKey::Echo { sequence: a, .. }, ProbeKey::Echo { sequence: b, .. }) => {
+                        a == b
+                    }
+                    _ => false,
+                })
+                .map(|(_, record)| record)
+        })
+    }
+}
@@ -0,0 +1,1633 @@
+//! Parsing of received ICMP and ICMPv6 messages.
+//!
+//! Everything here works on plain byte slices, so it can be fed packets that never
+//! went through a socket.
+use pnet::util;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
+
+/// IPv4 option types, the ones walked over, Record Route and Timestamp.
+pub(crate) const IPOPT_END: u8 = 0;
+pub(crate) const IPOPT_NOOP: u8 = 1;
+pub(crate) const IPOPT_RR: u8 = 7;
+pub(crate) const IPOPT_TS: u8 = 68;
+
+/// Timestamp option flag asking for address and timestamp pairs.
+pub(crate) const IPOPT_TS_TSANDADDR: u8 = 1;
+
+/// More Fragments flag of the IPv4 flags and fragment offset field.
+const IP_MF: u16 = 0x2000;
+
+/// Fragment offset, in 8 byte units, of the IPv4 flags and fragment offset field.
+const IP_OFFSET_MASK: u16 = 0x1fff;
+
+/// This enum identifies a probe by the fields that replies carry back to us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProbeKey {
+    Echo {
+        identifier: u16,
+        sequence: u16,
+    },
+    /// UDP and DCCP probes, whose headers both start with the ports.
+    Udp {
+        source_port: u16,
+        destination_port: u16,
+    },
+}
+
+fn be16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*bytes.get(at)?, *bytes.get(at + 1)?]))
+}
+
+fn be32(bytes: &[u8], at: usize) -> Option<u32> {
+    let word = bytes.get(at..at + 4)?;
+    Some(u32::from_be_bytes([word[0], word[1], word[2], word[3]]))
+}
+
+/// MTUs of common links, largest first, from RFC 1191 section 7.
+const PLATEAUS: [u16; 10] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
+
+/// Returns the next hop MTU told by a fragmentation needed or packet too big
+/// message, captured with its IP header or without.
+///
+/// Routers older than RFC 1191 leave the field zero, the MTU is then guessed as
+/// the plateau below the length of the quoted probe.
+pub(crate) fn frag_needed_mtu(raw: &[u8]) -> Option<u16> {
+    let message = match raw.first()? >> 4 {
+        4 => raw.get(usize::from(raw[0] & 0x0f) * 4..)?,
+        6 => raw.get(40..)?,
+        _ => raw,
+    };
+    match (*message.first()?, *message.get(1)?) {
+        (3, 4) => match be16(message, 6)? {
+            0 => {
+                let total = be16(message.get(8..)?, 2)?;
+                PLATEAUS.iter().copied().find(|&plateau| plateau < total)
+            }
+            mtu => Some(mtu),
+        },
+        (2, 0) => Some(u16::try_from(be32(message, 4)?).unwrap_or(u16::MAX)),
+        _ => None,
+    }
+}
+
+fn echo_key(message: &[u8]) -> Option<ProbeKey> {
+    Some(ProbeKey::Echo {
+        identifier: be16(message, 4)?,
+        sequence: be16(message, 6)?,
+    })
+}
+
+fn udp_key(datagram: &[u8]) -> Option<ProbeKey> {
+    Some(ProbeKey::Udp {
+        source_port: be16(datagram, 0)?,
+        destination_port: be16(datagram, 2)?,
+    })
+}
+
+/// Returns the key of the probe quoted in an ICMP error message body.
+fn quoted_key_v4(quoted: &[u8]) -> Option<ProbeKey> {
+    // Fragments past the first carry no transport header.
+    if quoted.first()? >> 4 != 4 || be16(quoted, 6)? & IP_OFFSET_MASK != 0 {
+        return None;
+    }
+    let transport = quoted.get(usize::from(quoted[0] & 0x0f) * 4..)?;
+    match *quoted.get(9)? {
+        1 if transport.first() == Some(&8) => echo_key(transport),
+        17 | 33 => udp_key(transport),
+        _ => None,
+    }
+}
+
+/// Returns the upper-layer protocol of an IPv6 packet and where its header starts,
+/// past the Hop-by-Hop, Routing, Fragment and Destination Options headers. None for
+/// fragments past the first, which carry no upper-layer header.
+pub(crate) fn upper_layer_v6(packet: &[u8]) -> Option<(u8, usize)> {
+    let mut next = *packet.get(6)?;
+    let mut at = 40;
+    while let 0 | 43 | 44 | 60 = next {
+        if next == 44 && be16(packet, at + 2)? & 0xfff8 != 0 {
+            return None;
+        }
+        next = *packet.get(at)?;
+        // The length byte of a Fragment header is reserved, it is 8 bytes long.
+        at += (usize::from(*packet.get(at + 1)?) + 1) * 8;
+    }
+    Some((next, at))
+}
+
+/// Returns the offset in bytes of a fragment and whether more follow it, None for
+/// a packet that is not a fragment.
+pub(crate) fn fragment(packet: &[u8]) -> Option<(usize, bool)> {
+    let (offset, more) = match packet.first()? >> 4 {
+        4 => {
+            let field = be16(packet, 6)?;
+            (usize::from(field & IP_OFFSET_MASK) * 8, field & IP_MF != 0)
+        }
+        6 => {
+            let mut next = *packet.get(6)?;
+            let mut at = 40;
+            while let 0 | 43 | 60 = next {
+                next = *packet.get(at)?;
+                at += (usize::from(*packet.get(at + 1)?) + 1) * 8;
+            }
+            if next != 44 {
+                return None;
+            }
+            let field = be16(packet, at + 2)?;
+            (usize::from(field & 0xfff8), field & 1 != 0)
+        }
+        _ => return None,
+    };
+    match (offset, more) {
+        (0, false) => None,
+        _ => Some((offset, more)),
+    }
+}
+
+/// Returns the key of the probe quoted in an ICMPv6 error message body.
+fn quoted_key_v6(quoted: &[u8]) -> Option<ProbeKey> {
+    if quoted.first()? >> 4 != 6 {
+        return None;
+    }
+    let (protocol, at) = upper_layer_v6(quoted)?;
+    let transport = quoted.get(at..)?;
+    match protocol {
+        58 if transport.first() == Some(&128) => echo_key(transport),
+        17 | 33 => udp_key(transport),
+        _ => None,
+    }
+}
+
+/// Returns the key of the probe an ICMP message answers.
+///
+/// Echo replies carry the key themselves, error messages quote the probe.
+pub(crate) fn probe_key_v4(message: &[u8]) -> Option<ProbeKey> {
+    match *message.first()? {
+        0 => echo_key(message),
+        3 | 11 | 12 => quoted_key_v4(message.get(8..)?),
+        _ => None,
+    }
+}
+
+/// Returns the key of the probe an ICMPv6 message answers.
+///
+/// Echo replies carry the key themselves, error messages quote the probe.
+pub(crate) fn probe_key_v6(message: &[u8]) -> Option<ProbeKey> {
+    match *message.first()? {
+        129 => echo_key(message),
+        1..=4 => quoted_key_v6(message.get(8..)?),
+        _ => None,
+    }
+}
+
+/// Returns the key of a probe about to be sent, given with its IP header.
+pub(crate) fn sent_key(probe: &[u8]) -> Option<ProbeKey> {
+    match probe.first()? >> 4 {
+        4 => quoted_key_v4(probe),
+        6 => quoted_key_v6(probe),
+        _ => None,
+    }
+}
+
+/// This enum tells why a received message could not be classified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MalformedReason {
+    /// Shorter than an ICMP header.
+    Truncated,
+    /// The ICMP checksum does not match the message.
+    Checksum,
+    /// An error message quoting less than an IP header.
+    QuoteTruncated,
+    /// An error message quoting something that is not an IP header of our family.
+    QuoteHeader,
+    /// An RFC 4884 length pointing past the end of the message.
+    Length,
+}
+
+impl fmt::Display for MalformedReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MalformedReason::Truncated => "truncated",
+            MalformedReason::Checksum => "bad checksum",
+            MalformedReason::QuoteTruncated => "truncated quote",
+            MalformedReason::QuoteHeader => "bad quoted header",
+            MalformedReason::Length => "length past the end",
+        })
+    }
+}
+
+/// This struct describes a received message that could not be classified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MalformedReply {
+    pub from: Option<IpAddr>,
+    pub reason: MalformedReason,
+    pub len: usize,
+    /// Bytes of the message, only kept with `TraceRouteConfig::capture_raw`.
+    pub raw: Option<Vec<u8>>,
+}
+
+/// This struct is a Parameter Problem message a hop sent about a probe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ParameterProblem {
+    pub from: IpAddr,
+    /// Offset of the octet the hop objected to, from the IP header of the probe on.
+    pub pointer: u32,
+    /// The probe as the hop quoted it, from its IP header on.
+    pub quoted: Vec<u8>,
+}
+
+/// Returns the pointer of a Parameter Problem message, None for other messages.
+pub(crate) fn parameter_problem_pointer(message: &[u8], v6: bool) -> Option<u32> {
+    match (v6, *message.first()?) {
+        (false, 12) => message.get(4).map(|&pointer| u32::from(pointer)),
+        (true, 4) => be32(message, 4),
+        _ => None,
+    }
+}
+
+/// Returns why an ICMP message cannot be classified, None if it can.
+pub(crate) fn malformed_v4(message: &[u8]) -> Option<MalformedReason> {
+    if message.len() < 8 {
+        return Some(MalformedReason::Truncated);
+    }
+    if be16(message, 2) != Some(util::checksum(message, 1)) {
+        return Some(MalformedReason::Checksum);
+    }
+    match message[0] {
+        3 | 11 | 12 => {}
+        _ => return None,
+    }
+    let quoted = &message[8..];
+    if quoted.len() < 20 {
+        return Some(MalformedReason::QuoteTruncated);
+    }
+    if quoted[0] >> 4 != 4 || quoted[0] & 0x0f < 5 {
+        return Some(MalformedReason::QuoteHeader);
+    }
+    if usize::from(message[5]) * 4 > quoted.len() {
+        return Some(MalformedReason::Length);
+    }
+    None
+}
+
+/// Returns why an ICMPv6 message cannot be classified, None if it can.
+///
+/// The kernel checks ICMPv6 checksums itself, they need the addresses of the packet.
+pub(crate) fn malformed_v6(message: &[u8]) -> Option<MalformedReason> {
+    if message.len() < 8 {
+        return Some(MalformedReason::Truncated);
+    }
+    if !(1..=4).contains(&message[0]) {
+        return None;
+    }
+    let quoted = &message[8..];
+    if quoted.len() < 40 {
+        return Some(MalformedReason::QuoteTruncated);
+    }
+    if quoted[0] >> 4 != 6 {
+        return Some(MalformedReason::QuoteHeader);
+    }
+    if (message[0] == 1 || message[0] == 3) && usize::from(message[4]) * 8 > quoted.len() {
+        return Some(MalformedReason::Length);
+    }
+    None
+}
+
+/// This struct holds what the workers take from a received message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ParsedReply {
+    pub icmp_type: u8,
+    pub code: u8,
+    /// Key of the probe answered, None if the message neither carries nor quotes one.
+    pub key: Option<ProbeKey>,
+    pub quoted_len: Option<usize>,
+    pub incoming_interface: Option<InterfaceInfo>,
+    /// Next hop MTU told by a fragmentation needed or packet too big.
+    pub next_hop_mtu: Option<u16>,
+}
+
+/// Parses an ICMP message, or tells why it cannot be classified.
+pub(crate) fn parse_v4(message: &[u8]) -> Result<ParsedReply, MalformedReason> {
+    if let Some(reason) = malformed_v4(message) {
+        return Err(reason);
+    }
+    Ok(ParsedReply {
+        icmp_type: message[0],
+        code: message[1],
+        key: probe_key_v4(message),
+        quoted_len: quoted_len_v4(message),
+        incoming_interface: incoming_interface_v4(message),
+        next_hop_mtu: frag_needed_mtu(message),
+    })
+}
+
+/// Parses an ICMPv6 message, or tells why it cannot be classified.
+pub(crate) fn parse_v6(message: &[u8]) -> Result<ParsedReply, MalformedReason> {
+    if let Some(reason) = malformed_v6(message) {
+        return Err(reason);
+    }
+    Ok(ParsedReply {
+        icmp_type: message[0],
+        code: message[1],
+        key: probe_key_v6(message),
+        quoted_len: quoted_len_v6(message),
+        incoming_interface: incoming_interface_v6(message),
+        next_hop_mtu: frag_needed_mtu(message),
+    })
+}
+
+/// Returns the probe quoted in an ICMP error message, from its IP header on.
+pub(crate) fn quoted_v4(message: &[u8]) -> Option<&[u8]> {
+    match *message.first()? {
+        3 | 11 | 12 => message.get(8..),
+        _ => None,
+    }
+}
+
+/// Returns the probe quoted in an ICMPv6 error message, from its IP header on.
+pub(crate) fn quoted_v6(message: &[u8]) -> Option<&[u8]> {
+    match *message.first()? {
+        1..=4 => message.get(8..),
+        _ => None,
+    }
+}
+
+/// Returns the source address of a quoted probe.
+pub(crate) fn quoted_source(quoted: &[u8]) -> Option<IpAddr> {
+    match quoted.first()? >> 4 {
+        4 => Some(IpAddr::V4(to_ipv4(quoted.get(12..16)?))),
+        6 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(quoted.get(8..24)?);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the destination address of a quoted probe.
+pub(crate) fn quoted_destination(quoted: &[u8]) -> Option<IpAddr> {
+    match quoted.first()? >> 4 {
+        4 => Some(IpAddr::V4(to_ipv4(quoted.get(16..20)?))),
+        6 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(quoted.get(24..40)?);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the part of a probe a router has to quote: its IP header, extension
+/// headers included, and the first 8 bytes of the transport header.
+pub(crate) fn snapshot(probe: &[u8]) -> Vec<u8> {
+    let header = match probe.first().map(|b| b >> 4) {
+        Some(4) => usize::from(probe[0] & 0x0f) * 4,
+        _ => upper_layer_v6(probe).map_or(40, |(_, at)| at),
+    };
+    probe[..probe.len().min(header + 8)].to_vec()
+}
+
+/// This struct holds the fields telling a probe apart on the wire, as sent, to
+/// match it to packet captures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ProbeToken {
+    pub ttl: u8,
+    /// IP identification, None for IPv6.
+    pub ip_id: Option<u16>,
+    /// Ports of UDP and DCCP probes.
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+    /// Identifier of echo probes.
+    pub icmp_identifier: Option<u16>,
+    /// Sequence number of echo probes.
+    pub icmp_sequence: Option<u16>,
+}
+
+impl ProbeToken {
+    /// Reads the token of a probe given from its IP header on, the first 8 bytes
+    /// of its transport header are enough. None if the header is cut short.
+    pub(crate) fn of(probe: &[u8]) -> Option<ProbeToken> {
+        let (ttl, ip_id, protocol, header) = match probe.first()? >> 4 {
+            4 => (
+                *probe.get(8)?,
+                Some(be16(probe, 4)?),
+                *probe.get(9)?,
+                usize::from(probe[0] & 0x0f) * 4,
+            ),
+            6 => (*probe.get(7)?, None, *probe.get(6)?, 40),
+            _ => return None,
+        };
+        let mut token = ProbeToken {
+            ttl,
+            ip_id,
+            source_port: None,
+            destination_port: None,
+            icmp_identifier: None,
+            icmp_sequence: None,
+        };
+        let transport = probe.get(header..).unwrap_or_default();
+        match protocol {
+            17 | 33 => {
+                token.source_port = be16(transport, 0);
+                token.destination_port = be16(transport, 2);
+            }
+            1 | 58 if matches!(transport.first(), Some(8) | Some(128)) => {
+                token.icmp_identifier = be16(transport, 4);
+                token.icmp_sequence = be16(transport, 6);
+            }
+            _ => {}
+        }
+        Some(token)
+    }
+}
+
+/// This enum names a field a middlebox changed in a probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum MangledField {
+    SourceAddr,
+    /// Source port of UDP and DCCP probes, identifier of echo probes.
+    SourcePort,
+    /// IP identification, IPv4 only.
+    IpId,
+    /// Checksum of the transport header.
+    Checksum,
+}
+
+/// Returns the fields that differ between a probe as sent and as quoted back.
+///
+/// Both are given from their IP header on. `None` when the quote is too short
+/// to hold the transport header or is of another family.
+pub(crate) fn mangled_fields(sent: &[u8], quoted: &[u8]) -> Option<Vec<MangledField>> {
+    let version = sent.first()? >> 4;
+    if quoted.first()? >> 4 != version {
+        return None;
+    }
+    let (addr, id, protocol, sent_transport, quoted_transport) = match version {
+        4 => (
+            12..16,
+            Some(4..6),
+            *sent.get(9)?,
+            sent.get(usize::from(sent[0] & 0x0f) * 4..)?,
+            quoted.get(usize::from(quoted[0] & 0x0f) * 4..)?,
+        ),
+        6 => (
+            8..24,
+            None,
+            upper_layer_v6(sent)?.0,
+            sent.get(upper_layer_v6(sent)?.1..)?,
+            quoted.get(upper_layer_v6(quoted)?.1..)?,
+        ),
+        _ => return None,
+    };
+    if sent_transport.len() < 8 || quoted_transport.len() < 8 {
+        return None;
+    }
+    let (port, checksum) = match protocol {
+        1 | 58 => (Some(4..6), Some(2..4)),
+        17 | 33 => (Some(0..2), Some(6..8)),
+        _ => (None, None),
+    };
+    let in_ip = |range: Range<usize>| sent.get(range.clone()) != quoted.get(range);
+    let in_transport =
+        |range: Range<usize>| sent_transport[range.clone()] != quoted_transport[range];
+    let mut fields = Vec::new();
+    if in_ip(addr) {
+        fields.push(MangledField::SourceAddr);
+    }
+    if port.is_some_and(in_transport) {
+        fields.push(MangledField::SourcePort);
+    }
+    if id.is_some_and(in_ip) {
+        fields.push(MangledField::IpId);
+    }
+    if checksum.is_some_and(in_transport) {
+        fields.push(MangledField::Checksum);
+    }
+    Some(fields)
+}
+
+/// Bytes of the original datagram field when a message without a length has
+/// extensions anyway, RFC 4884 section 5.
+const COMPAT_ORIGINAL_LEN: usize = 128;
+
+/// Version of the extension structure, RFC 4884.
+pub(crate) const EXTENSION_VERSION: u8 = 2;
+
+/// Class of the Interface Information Object, RFC 5837.
+const CLASS_INTERFACE_INFO: u8 = 2;
+
+/// Interface role of the incoming IP interface, the upper two bits of the c-type.
+const ROLE_INCOMING: u8 = 0;
+
+/// This struct describes an interface a router reported in an RFC 5837 extension.
+///
+/// Routers choose which fields they send, missing ones are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct InterfaceInfo {
+    pub if_index: Option<u32>,
+    pub name: Option<String>,
+    pub addr: Option<IpAddr>,
+    pub mtu: Option<u32>,
+}
+
+/// Returns the extension structure following the original datagram, RFC 4884.
+///
+/// `words` is the length field of the message and `word_len` its unit. Messages
+/// without a length are looked at past 128 bytes, as older routers put them there.
+fn extension_structure(message: &[u8], words: u8, word_len: usize) -> Option<&[u8]> {
+    let original = match usize::from(words) * word_len {
+        0 => COMPAT_ORIGINAL_LEN,
+        len => len,
+    };
+    let structure = message.get(8 + original..)?;
+    if structure.len() < 4 || structure[0] >> 4 != EXTENSION_VERSION {
+        return None;
+    }
+    // A zero checksum was not computed, which is only taken from a message that
+    // announced its extensions with a length.
+    let checksum = be16(structure, 2)?;
+    if util::checksum(structure, 1) != checksum && (checksum != 0 || words == 0) {
+        return None;
+    }
+    Some(structure)
+}
+
+/// Returns the objects of an extension structure as class, c-type and payload.
+///
+/// Any malformed object makes the whole structure unusable.
+fn extension_objects(structure: &[u8]) -> Option<Vec<(u8, u8, &[u8])>> {
+    let mut objects = Vec::new();
+    let mut at = 4;
+    while at < structure.len() {
+        let len = usize::from(be16(structure, at)?);
+        let object = structure.get(at..at + len).filter(|_| len >= 4)?;
+        objects.push((object[2], object[3], &object[4..]));
+        at += len;
+    }
+    Some(objects)
+}
+
+/// Decodes the payload of an Interface Information Object, `c_type` tells which
+/// fields are present, in order.
+fn interface_info(c_type: u8, payload: &[u8]) -> Option<InterfaceInfo> {
+    let mut info = InterfaceInfo::default();
+    let mut at = 0;
+    if c_type & 0x08 != 0 {
+        info.if_index = Some(be32(payload, at)?);
+        at += 4;
+    }
+    if c_type & 0x04 != 0 {
+        let addr: IpAddr = match be16(payload, at)? {
+            1 => {
+                let octets = payload.get(at + 4..at + 8)?;
+                at += 8;
+                Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).into()
+            }
+            2 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(payload.get(at + 4..at + 20)?);
+                at += 20;
+                Ipv6Addr::from(octets).into()
+            }
+            _ => return None,
+        };
+        info.addr = Some(addr);
+    }
+    if c_type & 0x02 != 0 {
+        let len = usize::from(*payload.get(at)?);
+        if len == 0 || len % 4 != 0 || len > 64 {
+            return None;
+        }
+        let name = payload.get(at + 1..at + len)?;
+        let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        info.name = Some(String::from_utf8_lossy(&name[..end]).into_owned());
+        at += len;
+    }
+    if c_type & 0x01 != 0 {
+        info.mtu = Some(be32(payload, at)?);
+    }
+    Some(info)
+}
+
+/// Returns the incoming interface reported in the extensions of a message.
+fn incoming_interface(structure: &[u8]) -> Option<InterfaceInfo> {
+    extension_objects(structure)?
+        .into_iter()
+        .find(|&(class, c_type, _)| class == CLASS_INTERFACE_INFO && c_type >> 6 == ROLE_INCOMING)
+        .and_then(|(_, c_type, payload)| interface_info(c_type, payload))
+}
+
+/// Returns the incoming interface a router reported in an ICMP error message.
+pub(crate) fn incoming_interface_v4(message: &[u8]) -> Option<InterfaceInfo> {
+    match *message.first()? {
+        3 | 11 | 12 => incoming_interface(extension_structure(message, *message.get(5)?, 4)?),
+        _ => None,
+    }
+}
+
+/// Returns the incoming interface a router reported in an ICMPv6 error message.
+pub(crate) fn incoming_interface_v6(message: &[u8]) -> Option<InterfaceInfo> {
+    match *message.first()? {
+        1 | 3 => incoming_interface(extension_structure(message, *message.get(4)?, 8)?),
+        _ => None,
+    }
+}
+
+/// This enum tells how the address a router answered from relates to the
+/// interface the probe came in on.
+///
+/// Routers answer from the interface facing back to us, which need not be the one
+/// the probe entered on an asymmetric path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum InterfaceHint {
+    /// The router answered from the address the probe came in on.
+    SameAddress,
+    /// The addresses differ but share a point to point link, a /30 for IPv4 and a
+    /// /64 for IPv6, likely both ends of a link of the path.
+    DifferentAddressSameSubnet,
+    /// The router answered from another interface than the probe came in on.
+    Different,
+}
+
+/// Prefix of the IPv4 point to point links, /31 links fall in it too.
+const LINK_PREFIX_V4: u32 = 30;
+
+/// Prefix of the IPv6 links, /127 links fall in it too.
+const LINK_PREFIX_V6: u32 = 64;
+
+/// Returns true if `a` and `b` are on the same point to point link.
+fn same_link(a: IpAddr, b: IpAddr) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            let mask = u32::MAX << (32 - LINK_PREFIX_V4);
+            u32::from(a) & mask == u32::from(b) & mask
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            let mask = u128::MAX << (128 - LINK_PREFIX_V6);
+            u128::from(a) & mask == u128::from(b) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Returns how the address `from` relates to the interface the answered probe
+/// came in on, for an ICMP or ICMPv6 error message.
+///
+/// The incoming interface reported in an RFC 5837 extension is taken when it has
+/// an address, the destination of the quoted probe otherwise, which tells whether
+/// the destination answered from another address than the one probed.
+pub(crate) fn interface_hint(message: &[u8], from: IpAddr) -> Option<InterfaceHint> {
+    let (quoted, incoming) = match from {
+        IpAddr::V4(_) => (quoted_v4(message)?, incoming_interface_v4(message)),
+        IpAddr::V6(_) => (quoted_v6(message)?, incoming_interface_v6(message)),
+    };
+    let entered = match incoming.and_then(|interface| interface.addr) {
+        Some(addr) => addr,
+        None => quoted_destination(quoted)?,
+    };
+    Some(if entered == from {
+        InterfaceHint::SameAddress
+    } else if same_link(entered, from) {
+        InterfaceHint::DifferentAddressSameSubnet
+    } else {
+        InterfaceHint::Different
+    })
+}
+
+/// Returns how many bytes of the original datagram field an RFC 4884 length, in
+/// `word_len` units, announces, or the 128 bytes of a message with unannounced
+/// extensions. None when everything after the header is the original datagram.
+fn original_len(message: &[u8], words: u8, word_len: usize) -> Option<usize> {
+    match usize::from(words) * word_len {
+        0 => extension_structure(message, 0, word_len).map(|_| COMPAT_ORIGINAL_LEN),
+        len => Some(len),
+    }
+}
+
+/// Returns the bytes of `quoted` that belong to the datagram, by its own length
+/// field, leaving out padding. `total` is ignored when it leaves no payload.
+fn datagram_len(quoted: &[u8], field: Option<usize>, total: Option<usize>, header: usize) -> usize {
+    let mut len = field.map_or(quoted.len(), |field| field.min(quoted.len()));
+    if let Some(total) = total.filter(|&total| total > header) {
+        len = len.min(total);
+    }
+    len
+}
+
+/// Returns how many bytes of the probe an ICMP error message quotes, without the
+/// padding and extensions that may follow them.
+pub(crate) fn quoted_len_v4(message: &[u8]) -> Option<usize> {
+    let quoted = quoted_v4(message)?;
+    let field = original_len(message, *message.get(5)?, 4);
+    let total = be16(quoted, 2).map(usize::from);
+    Some(datagram_len(quoted, field, total, 20))
+}
+
+/// Returns how many bytes of the probe an ICMPv6 error message quotes, without the
+/// padding and extensions that may follow them.
+pub(crate) fn quoted_len_v6(message: &[u8]) -> Option<usize> {
+    let quoted = quoted_v6(message)?;
+    // Only destination unreachable and time exceeded have a length field.
+    let field = match message[0] {
+        1 | 3 => original_len(message, *message.get(4)?, 8),
+        _ => None,
+    };
+    let total = be16(quoted, 4).map(|payload| usize::from(payload) + 40);
+    Some(datagram_len(quoted, field, total, 40))
+}
+
+/// Returns the options of the IPv4 header quoted in an ICMP error message.
+pub(crate) fn quoted_options_v4(message: &[u8]) -> Option<&[u8]> {
+    match *message.first()? {
+        3 | 11 | 12 => {}
+        _ => return None,
+    }
+    let quoted = message.get(8..)?;
+    if quoted.first()? >> 4 != 4 {
+        return None;
+    }
+    quoted.get(20..usize::from(quoted[0] & 0x0f) * 4)
+}
+
+/// Returns the first option of type `kind` in an IPv4 options area.
+fn find_option(options: &[u8], kind: u8) -> Option<&[u8]> {
+    let mut rest = options;
+    loop {
+        match *rest.first()? {
+            IPOPT_END => return None,
+            IPOPT_NOOP => rest = &rest[1..],
+            found => {
+                let len = usize::from(*rest.get(1)?);
+                if len < 2 || len > rest.len() {
+                    return None;
+                }
+                if found == kind {
+                    return Some(&rest[..len]);
+                }
+                rest = &rest[len..];
+            }
+        }
+    }
+}
+
+/// Returns the filled part of an option, as told by its pointer.
+///
+/// Pointers count from 1 and are left past the end once every slot is filled.
+fn filled_slots(option: &[u8], first: usize) -> Option<&[u8]> {
+    let pointer = usize::from(*option.get(2)?);
+    option.get(first..(pointer.max(first + 1) - 1).min(option.len()))
+}
+
+fn to_ipv4(bytes: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])
+}
+
+/// Returns the addresses recorded so far in the Record Route option found in an
+/// IPv4 options area, `None` if there is no such option.
+pub(crate) fn recorded_route(options: &[u8]) -> Option<Vec<Ipv4Addr>> {
+    let filled = filled_slots(find_option(options, IPOPT_RR)?, 3)?;
+    Some(filled.chunks_exact(4).map(to_ipv4).collect())
+}
+
+/// Returns the address and timestamp pairs recorded so far in the Timestamp option
+/// found in an IPv4 options area, `None` if there is no such option or it does
+/// not hold pairs.
+///
+/// Timestamps are milliseconds since midnight UT, unless a router set the high
+/// order bit to tell it uses another clock.
+pub(crate) fn recorded_timestamps(options: &[u8]) -> Option<Vec<(Ipv4Addr, u32)>> {
+    let option = find_option(options, IPOPT_TS)?;
+    if option.get(3)? & 0x0f != IPOPT_TS_TSANDADDR {
+        return None;
+    }
+    let filled = filled_slots(option, 4)?;
+    Some(
+        filled
+            .chunks_exact(8)
+            .map(|pair| {
+                let time = u32::from_be_bytes([pair[4], pair[5], pair[6], pair[7]]);
+                (to_ipv4(pair), time)
+            })
+            .collect(),
+    )
+}
+
+/// Returns true for DCCP Response and Reset packets, the answers to a Request.
+pub(crate) fn is_dccp_answer(packet: &[u8]) -> bool {
+    let packet_type = match packet.get(8) {
+        Some(byte) => (byte >> 1) & 0x0f,
+        None => return false,
+    };
+    packet.len() >= 16 && (packet_type == 1 || packet_type == 7)
+}
+
+/// TCP flags of the SYN confirming a silent destination and its answers.
+pub(crate) const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_ACK: u8 = 0x10;
+
+/// Returns true for a TCP SYN-ACK or reset from `port` acknowledging one of the
+/// SYNs sent with `sequences`, the answers to a SYN.
+pub(crate) fn is_tcp_answer(segment: &[u8], port: u16, sequences: &[u32]) -> bool {
+    if segment.len() < 20 || be16(segment, 0) != Some(port) {
+        return false;
+    }
+    let flags = segment[13];
+    let answer = flags & TCP_ACK != 0 && flags & (TCP_SYN | TCP_RST) != 0;
+    let acked = u32::from_be_bytes([segment[8], segment[9], segment[10], segment[11]]);
+    answer
+        && sequences
+            .iter()
+            .any(|sequence| sequence.wrapping_add(1) == acked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        echo_reply, frag_needed, parameter_problem, port_unreachable, test_net_v4, test_net_v6,
+        time_exceeded,
+    };
+
+    fn probe_v4(protocol: u8, transport: &[u8]) -> Vec<u8> {
+        let mut probe = vec![0u8; 20];
+        probe[0] = 0x45;
+        probe[8] = 3;
+        probe[9] = protocol;
+        probe[12..16].copy_from_slice(&[192, 0, 2, 254]);
+        probe.extend_from_slice(transport);
+        probe
+    }
+
+    fn probe_v6(next_header: u8, transport: &[u8]) -> Vec<u8> {
+        let mut probe = vec![0u8; 40];
+        probe[0] = 0x60;
+        probe[6] = next_header;
+        probe[7] = 3;
+        probe[8..24]
+            .copy_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 254]);
+        probe.extend_from_slice(transport);
+        probe
+    }
+
+    #[test]
+    fn next_hop_mtus_of_frag_needed() {
+        let mut probe = probe_v4(17, &[0xb1, 0x6b, 0x82, 0x9b, 0, 8, 0, 0]);
+        probe[2..4].copy_from_slice(&1500u16.to_be_bytes());
+        let router = test_net_v4(1);
+        for &mtu in &[1400, 1280, 576, 68] {
+            let message = frag_needed(&probe, router, mtu);
+            assert_eq!(parse_v4(&message).unwrap().next_hop_mtu, Some(mtu));
+            let mut raw = vec![0x45];
+            raw.resize(20, 0);
+            raw.extend_from_slice(&message);
+            assert_eq!(frag_needed_mtu(&raw), Some(mtu));
+        }
+        // Old routers leave the field zero, the plateau below the probe is taken.
+        assert_eq!(frag_needed_mtu(&frag_needed(&probe, router, 0)), Some(1492));
+        probe[2..4].copy_from_slice(&1000u16.to_be_bytes());
+        assert_eq!(frag_needed_mtu(&frag_needed(&probe, router, 0)), Some(508));
+        probe[2..4].copy_from_slice(&68u16.to_be_bytes());
+        assert_eq!(frag_needed_mtu(&frag_needed(&probe, router, 0)), None);
+        let unreachable = port_unreachable(&probe, router);
+        assert_eq!(parse_v4(&unreachable).unwrap().next_hop_mtu, None);
+
+        let probe = probe_v6(17, &[0xb1, 0x6b, 0x82, 0x9b, 0, 8, 0, 0]);
+        let message = frag_needed(&probe, test_net_v6(1), 1280);
+        assert_eq!(parse_v6(&message).unwrap().next_hop_mtu, Some(1280));
+        let mut jumbo = message.clone();
+        jumbo[4..8].copy_from_slice(&70000u32.to_be_bytes());
+        assert_eq!(frag_needed_mtu(&jumbo), Some(u16::MAX));
+        let exceeded = time_exceeded(&probe, test_net_v6(1));
+        assert_eq!(parse_v6(&exceeded).unwrap().next_hop_mtu, None);
+    }
+
+    #[test]
+    fn keys_of_echo_replies_and_quotes() {
+        let echo = probe_v6(58, &[128, 0, 0, 0, 0x12, 0x34, 0, 7]);
+        let key = ProbeKey::Echo {
+            identifier: 0x1234,
+            sequence: 7,
+        };
+        assert_eq!(probe_key_v6(&echo_reply(&echo, test_net_v6(9))), Some(key));
+        assert_eq!(
+            probe_key_v6(&time_exceeded(&echo, test_net_v6(1))),
+            Some(key)
+        );
+
+        let udp = probe_v6(17, &[0xa0, 0x00, 0x82, 0x9b, 0, 8, 0, 0]);
+        assert_eq!(
+            probe_key_v6(&port_unreachable(&udp, test_net_v6(9))),
+            Some(ProbeKey::Udp {
+                source_port: 0xa000,
+                destination_port: 33435
+            })
+        );
+        let dccp = probe_v6(33, &[0xa0, 0x01, 0x82, 0x9c, 5, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(
+            probe_key_v6(&time_exceeded(&dccp, test_net_v6(1))),
+            Some(ProbeKey::Udp {
+                source_port: 0xa001,
+                destination_port: 33436
+            })
+        );
+    }
+
+    #[test]
+    fn keys_of_icmp_replies_and_quotes() {
+        let echo = probe_v4(1, &[8, 0, 0, 0, 0x12, 0x34, 0, 7]);
+        let key = ProbeKey::Echo {
+            identifier: 0x1234,
+            sequence: 7,
+        };
+        assert_eq!(probe_key_v4(&echo_reply(&echo, test_net_v4(9))), Some(key));
+        assert_eq!(
+            probe_key_v4(&time_exceeded(&echo, test_net_v4(1))),
+            Some(key)
+        );
+
+        let udp = probe_v4(17, &[0xa0, 0x00, 0x82, 0x9b, 0, 8, 0, 0]);
+        assert_eq!(
+            probe_key_v4(&port_unreachable(&udp, test_net_v4(9))),
+            Some(ProbeKey::Udp {
+                source_port: 0xa000,
+                destination_port: 33435
+            })
+        );
+        // DCCP headers start with the ports too.
+        let dccp = probe_v4(33, &[0xa0, 0x01, 0x82, 0x9c, 5, 0, 0, 0, 1, 0, 0, 0]);
+        let key = ProbeKey::Udp {
+            source_port: 0xa001,
+            destination_port: 33436,
+        };
+        assert_eq!(sent_key(&dccp), Some(key));
+        assert_eq!(
+            probe_key_v4(&time_exceeded(&dccp, test_net_v4(1))),
+            Some(key)
+        );
+        assert_eq!(
+            parse_v4(&time_exceeded(&dccp, test_net_v4(1))).map(|parsed| parsed.key),
+            Ok(Some(key))
+        );
+        assert_eq!(
+            sent_key(&udp),
+            probe_key_v4(&port_unreachable(&udp, test_net_v4(9)))
+        );
+        assert_eq!(sent_key(&echo), Some(key));
+        assert_eq!(probe_key_v4(&[5, 0, 0, 0, 0, 0, 0, 0, 0x45]), None);
+    }
+
+    #[test]
+    fn pointers_of_parameter_problems() {
+        let udp = probe_v4(17, &[0xa0, 0x00, 0x82, 0x9b, 0, 8, 0, 0]);
+        let message = parameter_problem(&udp, test_net_v4(2), 20);
+        assert_eq!(parameter_problem_pointer(&message, false), Some(20));
+        assert_eq!(parameter_problem_pointer(&message, true), None);
+        let parsed = parse_v4(&message).unwrap();
+        assert_eq!(parsed.icmp_type, 12);
+        assert_eq!(parsed.key, sent_key(&udp));
+        assert_eq!(quoted_v4(&message), Some(&udp[..28]));
+        let unreachable = port_unreachable(&udp, test_net_v4(9));
+        assert_eq!(parameter_problem_pointer(&unreachable, false), None);
+
+        let udp = probe_v6(17, &[0xa0, 0x00, 0x82, 0x9b, 0, 8, 0, 0]);
+        let message = parameter_problem(&udp, test_net_v6(2), 0x0102_0304);
+        assert_eq!(parameter_problem_pointer(&message, true), Some(0x0102_0304));
+        assert_eq!(parse_v6(&message).unwrap().key, sent_key(&udp));
+        assert_eq!(parameter_problem_pointer(&message[..6], true), None);
+    }
+
+    #[test]
+    fn mangled_fields_of_quotes() {
+        use MangledField::*;
+        let sent = probe_v4(17, &[0xa0, 0x00, 0x82, 0x9b, 0, 8, 0x5a, 0x5a]);
+        let quoted = |edit: &dyn Fn(&mut Vec<u8>)| {
+            let mut quote = sent.clone();
+            quote[8] = 1;
+            edit(&mut quote);
+            let message = time_exceeded(&quote, test_net_v4(1));
+            mangled_fields(&snapshot(&sent), quoted_v4(&message).unwrap())
+        };
+        assert_eq!(quoted(&|_| {}), Some(vec![]));
+        assert_eq!(
+            quoted(&|q| {
+                q[12..16].copy_from_slice(&[203, 0, 113, 9]);
+                q[20..22].copy_from_slice(&[0x04, 0x00]);
+            }),
+            Some(vec![SourceAddr, SourcePort])
+        );
+        assert_eq!(quoted(&|q| q[26] = 0), Some(vec![Checksum]));
+        assert_eq!(
+            quoted(&|q| {
+                q[4] = 0x77;
+                q[27] = 0;
+            }),
+            Some(vec![IpId, Checksum])
+        );
+        assert_eq!(quoted(&|q| q.truncate(24)), None);
+
+        let echo = probe_v6(58, &[128, 0, 0x11, 0x11, 0x12, 0x34, 0, 7]);
+        let mut quote = echo.clone();
+        quote[8..12].copy_from_slice(&[0x20, 0x01, 0x0d, 0xb9]);
+        quote[44..46].copy_from_slice(&[0x43, 0x21]);
+        quote[42] = 0x22;
+        let message = time_exceeded(&quote, test_net_v6(1));
+        assert_eq!(
+            mangled_fields(&snapshot(&echo), quoted_v6(&message).unwrap()),
+            Some(vec![SourceAddr, SourcePort, Checksum])
+        );
+        assert_eq!(mangled_fields(&echo, &sent), None);
+        assert_eq!(
+            quoted_source(quoted_v6(&message).unwrap()),
+            Some("2001:db9::fe".parse().unwrap())
+        );
+        assert_eq!(quoted_v6(&echo_reply(&echo, test_net_v6(9))), None);
+    }
+
+    #[test]
+    fn dccp_answers() {
+        let mut packet = vec![0u8; 28];
+        for (packet_type, answer) in &[(0, false), (1, true), (4, false), (7, true)] {
+            packet[8] = (packet_type << 1) | 1;
+            assert_eq!(is_dccp_answer(&packet), *answer, "type {}", packet_type);
+        }
+        assert!(!is_dccp_answer(&packet[..12]));
+    }
+
+    #[test]
+    fn tcp_answers_acknowledge_our_syn() {
+        let mut segment = vec![0u8; 20];
+        segment[0..2].copy_from_slice(&443u16.to_be_bytes());
+        segment[8..12].copy_from_slice(&0x1234_0008u32.to_be_bytes());
+        for (flags, answer) in &[(0x12, true), (0x14, true), (0x04, false), (0x10, false)] {
+            segment[13] = *flags;
+            assert_eq!(
+                is_tcp_answer(&segment, 443, &[0x1234_0007]),
+                *answer,
+                "flags {:#x}",
+                flags
+            );
+        }
+        assert!(!is_tcp_answer(&segment, 80, &[0x1234_0007]));
+        // Segments of other connections acknowledge other sequences.
+        segment[13] = 0x12;
+        assert!(!is_tcp_answer(&segment, 443, &[0x1234_0008]));
+        assert!(!is_tcp_answer(&segment, 443, &[]));
+        assert!(!is_tcp_answer(&segment[..16], 443, &[0x1234_0007]));
+    }
+
+    #[test]
+    fn record_route_parsing() {
+        let a = [198, 51, 100, 1];
+        let b = [198, 51, 100, 2];
+        let mut options = vec![IPOPT_NOOP, IPOPT_RR, 15, 4];
+        options.extend_from_slice(&[0; 12]);
+        assert_eq!(recorded_route(&options), Some(vec![]));
+
+        options[3] = 12;
+        options[4..8].copy_from_slice(&a);
+        options[8..12].copy_from_slice(&b);
+        let partial = vec![Ipv4Addr::from(a), Ipv4Addr::from(b)];
+        assert_eq!(recorded_route(&options), Some(partial.clone()));
+
+        options[3] = 16;
+        options[12..16].copy_from_slice(&a);
+        assert_eq!(recorded_route(&options).unwrap().len(), 3);
+
+        let mut quoted = probe_v4(17, &[0xa0, 0x00, 0x82, 0x9b, 0, 8, 0, 0]);
+        quoted[0] = 0x49;
+        options[3] = 12;
+        options[12..16].copy_from_slice(&[0; 4]);
+        quoted.splice(20..20, options.iter().cloned());
+        let message = time_exceeded(&quoted, test_net_v4(3));
+        assert_eq!(
+            quoted_options_v4(&message).and_then(recorded_route),
+            Some(partial)
+        );
+
+        assert_eq!(
+            recorded_route(&[IPOPT_NOOP, IPOPT_END, IPOPT_RR, 7, 4]),
+            None
+        );
+        assert_eq!(recorded_route(&[131, 7, 4, 0, 0, 0, 0, 0]), None);
+        assert_eq!(recorded_route(&[IPOPT_RR, 39, 4]), None);
+        assert_eq!(
+            quoted_options_v4(&echo_reply(&quoted, test_net_v4(9))),
+            None
+        );
+    }
+
+    #[test]
+    fn timestamp_parsing() {
+        let mut options = vec![IPOPT_TS, 28, 5, IPOPT_TS_TSANDADDR];
+        options.extend_from_slice(&[0; 24]);
+        options.extend_from_slice(&[IPOPT_END; 4]);
+        assert_eq!(recorded_timestamps(&options), Some(vec![]));
+
+        options[2] = 13;
+        options[4..12].copy_from_slice(&[198, 51, 100, 1, 0x02, 0x93, 0x2e, 0x00]);
+        let first = (Ipv4Addr::new(198, 51, 100, 1), 43_200_000);
+        assert_eq!(recorded_timestamps(&options), Some(vec![first]));
+
+        options[2] = 29;
+        options[3] |= 0x20;
+        options[12..28].copy_from_slice(&[
+            198, 51, 100, 2, 0x02, 0x93, 0x2e, 0x05, 198, 51, 100, 3, 0x80, 0, 0, 1,
+        ]);
+        assert_eq!(
+            recorded_timestamps(&options),
+            Some(vec![
+                first,
+                (Ipv4Addr::new(198, 51, 100, 2), 43_200_005),
+                (Ipv4Addr::new(198, 51, 100, 3), 0x8000_0001),
+            ])
+        );
+
+        let mut routed = vec![IPOPT_NOOP, IPOPT_RR, 7, 4, 0, 0, 0, 0];
+        routed.extend_from_slice(&options[..28]);
+        assert_eq!(recorded_timestamps(&routed).unwrap().len(), 3);
+        options[3] = 0;
+        assert_eq!(recorded_timestamps(&options), None);
+        assert_eq!(recorded_timestamps(&[IPOPT_RR, 7, 4, 0, 0, 0, 0]), None);
+        assert_eq!(recorded_timestamps(&[]), None);
+    }
+
+    /// Extension structure with an MPLS label stack, an outgoing interface and the
+    /// incoming interface with all four fields, the way a core router sends them.
+    const ROUTER_EXTENSIONS: [u8; 52] = [
+        0x20, 0x00, 0x8c, 0x0e, 0x00, 0x08, 0x01, 0x01, 0x00, 0x3e, 0x81, 0x01, 0x00, 0x08, 0x02,
+        0x88, 0x00, 0x00, 0x00, 0x07, 0x00, 0x20, 0x02, 0x0f, 0x00, 0x00, 0x02, 0x11, 0x00, 0x01,
+        0x00, 0x00, 0xc0, 0x00, 0x02, 0x01, 0x0c, 0x67, 0x65, 0x2d, 0x30, 0x2f, 0x30, 0x2f, 0x31,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0xdc,
+    ];
+
+    /// Extension structure with an incoming interface given by ifIndex and IPv6 address.
+    const ROUTER_EXTENSIONS_V6: [u8; 32] = [
+        0x20, 0x00, 0xb0, 0x18, 0x00, 0x1c, 0x02, 0x0c, 0x00, 0x00, 0x00, 0x03, 0x00, 0x02, 0x00,
+        0x00, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x01,
+    ];
+
+    /// Returns a time exceeded message whose original datagram is padded to 128 bytes
+    /// and followed by `structure`, with the length field set if `announced`.
+    fn with_extensions(v4: bool, structure: &[u8], announced: bool) -> Vec<u8> {
+        let udp = [0xa0, 0x00, 0x82, 0x9b, 0, 8, 0, 0];
+        let mut message = if v4 {
+            time_exceeded(&probe_v4(17, &udp), test_net_v4(1))
+        } else {
+            time_exceeded(&probe_v6(17, &udp), test_net_v6(1))
+        };
+        message.resize(8 + 128, 0);
+        if announced {
+            if v4 {
+                message[5] = 32;
+            } else {
+                message[4] = 16;
+            }
+        }
+        message.extend_from_slice(structure);
+        message
+    }
+
+    #[test]
+    fn quote_lengths() {
+        let mut probe = probe_v4(17, &[0xa0, 0x00, 0x82, 0x9b, 0, 40, 0, 0]);
+        probe.resize(60, 0xee);
+        probe[2..4].copy_from_slice(&60u16.to_be_bytes());
+        let full = time_exceeded(&probe, test_net_v4(1));
+        let mut full = full[..8].to_vec();
+        full.extend_from_slice(&probe);
+        assert_eq!(quoted_len_v4(&full), Some(60));
+        // Routers following RFC 792 quote the header and 8 bytes only.
+        let short = time_exceeded(&probe, test_net_v4(1));
+        assert_eq!(short.len(), 36);
+        assert_eq!(quoted_len_v4(&short), Some(28));
+        // Padding to 128 bytes and the extensions are not part of the quote.
+        let mut padded = full.clone();
+        padded.resize(8 + 128, 0);
+        padded[5] = 32;
+        padded.extend_from_slice(&ROUTER_EXTENSIONS);
+        assert_eq!(quoted_len_v4(&padded), Some(60));
+        padded[5] = 0;
+        assert_eq!(quoted_len_v4(&padded), Some(60));
+
+        let mut probe = probe_v6(17, &[0xa0, 0x00, 0x82, 0x9b, 0, 8, 0, 0]);
+        probe[4..6].copy_from_slice(&8u16.to_be_bytes());
+        let message = time_exceeded(&probe, test_net_v6(1));
+        assert_eq!(quoted_len_v6(&message), Some(48));
+        let mut extended = with_extensions(false, &ROUTER_EXTENSIONS_V6, true);
+        assert_eq!(quoted_len_v6(&extended), Some(128));
+        extended[12..14].copy_from_slice(&8u16.to_be_bytes());
+        assert_eq!(quoted_len_v6(&extended), Some(48));
+        assert_eq!(quoted_len_v6(&[129, 0, 0, 0, 0, 1, 0, 1]), None);
+    }
+
+    #[test]
+    fn malformed_messages() {
+        let resum = |mut message: Vec<u8>| {
+            message[2..4].copy_from_slice(&[0, 0]);
+            let checksum = util::checksum(&message, 1);
+            message[2..4].copy_from_slice(&checksum.to_be_bytes());
+            message
+        };
+        let probe = probe_v4(17, &[0xa0, 0x00, 0x82, 0x9b, 0, 8, 0, 0]);
+        let message = time_exceeded(&probe, test_net_v4(1));
+        assert_eq!(malformed_v4(&message), None);
+        assert_eq!(
+            malformed_v4(&message[..6]),
+            Some(MalformedReason::Truncated)
+        );
+        let mut corrupt = message.clone();
+        corrupt[20] ^= 0xff;
+        assert_eq!(malformed_v4(&corrupt), Some(MalformedReason::Checksum));
+        let short = resum(message[..20].to_vec());
+        assert_eq!(malformed_v4(&short), Some(MalformedReason::QuoteTruncated));
+        let mut version = message.clone();
+        version[8] = 0x65;
+        assert_eq!(
+            malformed_v4(&resum(version)),
+            Some(MalformedReason::QuoteHeader)
+        );
+        let mut length = message.clone();
+        length[5] = 32;
+        assert_eq!(malformed_v4(&resum(length)), Some(MalformedReason::Length));
+        assert_eq!(malformed_v4(&resum(vec![0, 0, 0, 0, 0, 1, 0, 1])), None);
+
+        let probe = probe_v6(17, &[0xa0, 0x00, 0x82, 0x9b, 0, 8, 0, 0]);
+        let message = time_exceeded(&probe, test_net_v6(1));
+        assert_eq!(malformed_v6(&message), None);
+        assert_eq!(
+            malformed_v6(&message[..4]),
+            Some(MalformedReason::Truncated)
+        );
+        assert_eq!(
+            malformed_v6(&message[..40]),
+            Some(MalformedReason::QuoteTruncated)
+        );
+        let mut version = message.clone();
+        version[8] = 0x45;
+        assert_eq!(malformed_v6(&version), Some(MalformedReason::QuoteHeader));
+        let mut length = message.clone();
+        length[4] = 16;
+        assert_eq!(malformed_v6(&length), Some(MalformedReason::Length));
+        assert_eq!(malformed_v6(&[129, 0, 0, 0, 0, 1, 0, 1]), None);
+    }
+
+    #[test]
+    fn incoming_interface_of_a_router() {
+        let expected = InterfaceInfo {
+            if_index: Some(529),
+            name: Some("ge-0/0/1".to_string()),
+            addr: Some(IpAddr::from([192, 0, 2, 1])),
+            mtu: Some(1500),
+        };
+        for &announced in &[true, false] {
+            let message = with_extensions(true, &ROUTER_EXTENSIONS, announced);
+            assert_eq!(incoming_interface_v4(&message), Some(expected.clone()));
+        }
+
+        let message = with_extensions(false, &ROUTER_EXTENSIONS_V6, true);
+        assert_eq!(
+            incoming_interface_v6(&message),
+            Some(InterfaceInfo {
+                if_index: Some(3),
+                addr: Some("2001:db8::1".parse().unwrap()),
+                ..InterfaceInfo::default()
+            })
+        );
+        let udp = probe_v4(17, &[0xa0, 0x00, 0x82, 0x9b, 0, 8, 0, 0]);
+        assert_eq!(
+            incoming_interface_v4(&time_exceeded(&udp, test_net_v4(1))),
+            None
+        );
+    }
+
+    #[test]
+    fn interface_hints_of_replies() {
+        let hint = |message: &[u8], from: &str| interface_hint(message, from.parse().unwrap());
+        let message = with_extensions(true, &ROUTER_EXTENSIONS, true);
+        assert_eq!(
+            hint(&message, "192.0.2.1"),
+            Some(InterfaceHint::SameAddress)
+        );
+        assert_eq!(
+            hint(&message, "192.0.2.2"),
+            Some(InterfaceHint::DifferentAddressSameSubnet)
+        );
+        // 192.0.2.4 is past the /30 of 192.0.2.1.
+        assert_eq!(hint(&message, "192.0.2.4"), Some(InterfaceHint::Different));
+        assert_eq!(
+            hint(&message, "198.51.100.1"),
+            Some(InterfaceHint::Different)
+        );
+
+        let message = with_extensions(false, &ROUTER_EXTENSIONS_V6, true);
+        assert_eq!(
+            hint(&message, "2001:db8::1"),
+            Some(InterfaceHint::SameAddress)
+        );
+        assert_eq!(
+            hint(&message, "2001:db8::ffff:1"),
+            Some(InterfaceHint::DifferentAddressSameSubnet)
+        );
+        assert_eq!(
+            hint(&message, "2001:db8:0:1::1"),
+            Some(InterfaceHint::Different)
+        );
+
+        // Without an extension the destination of the quoted probe is compared.
+        let mut probe = probe_v4(17, &[0xa0, 0x00, 0x82, 0x9b, 0, 8, 0, 0]);
+        probe[16..20].copy_from_slice(&[192, 0, 2, 100]);
+        let message = port_unreachable(&probe, test_net_v4(100));
+        assert_eq!(
+            hint(&message, "192.0.2.100"),
+            Some(InterfaceHint::SameAddress)
+        );
+        assert_eq!(
+            hint(&message, "192.0.2.101"),
+            Some(InterfaceHint::DifferentAddressSameSubnet)
+        );
+        assert_eq!(hint(&message, "192.0.2.1"), Some(InterfaceHint::Different));
+        // Echo replies quote no probe to compare.
+        assert_eq!(hint(&[0, 0, 0, 0, 0, 1, 0, 1], "192.0.2.1"), None);
+    }
+
+    #[test]
+    fn malformed_extensions_are_ignored() {
+        for len in 0..ROUTER_EXTENSIONS.len() {
+            let message = with_extensions(true, &ROUTER_EXTENSIONS[..len], true);
+            assert_eq!(incoming_interface_v4(&message), None, "cut at {}", len);
+        }
+        let broken = |at: usize, value: u8| {
+            let mut structure = ROUTER_EXTENSIONS.to_vec();
+            structure[at] = value;
+            // Keeps the checksum right, so only the changed field is wrong.
+            structure[2] = 0;
+            structure[3] = 0;
+            let checksum = util::checksum(&structure, 1);
+            structure[2..4].copy_from_slice(&checksum.to_be_bytes());
+            incoming_interface_v4(&with_extensions(true, &structure, true))
+        };
+        assert_eq!(broken(0, 0x10), None, "version");
+        assert_eq!(broken(21, 0x02), None, "object length");
+        assert_eq!(broken(21, 0x40), None, "object length past the end");
+        assert_eq!(broken(29, 0x03), None, "address family");
+        assert_eq!(broken(36, 0x05), None, "name length");
+        assert!(broken(23, 0x8f).is_none(), "outgoing role only");
+
+        let mut structure = ROUTER_EXTENSIONS.to_vec();
+        structure[40] ^= 0xff;
+        assert_eq!(
+            incoming_interface_v4(&with_extensions(true, &structure, true)),
+            None,
+            "checksum"
+        );
+        structure[2] = 0;
+        structure[3] = 0;
+        assert!(incoming_interface_v4(&with_extensions(true, &structure, true)).is_some());
+        assert_eq!(
+            incoming_interface_v4(&with_extensions(true, &structure, false)),
+            None,
+            "unchecked structure at the compatibility offset"
+        );
+        let message = with_extensions(true, &ROUTER_EXTENSIONS, true);
+        assert_eq!(incoming_interface_v6(&message[..6]), None);
+        let mut echo = message.clone();
+        echo[0] = 0;
+        assert_eq!(incoming_interface_v4(&echo), None);
+    }
+
+    #[test]
+    fn no_key_for_unrelated_or_short_messages() {
+        assert_eq!(probe_key_v6(&[]), None);
+        assert_eq!(probe_key_v6(&[135, 0, 0, 0]), None);
+        assert_eq!(probe_key_v6(&[3, 0, 0, 0, 0, 0, 0, 0, 0x60]), None);
+        assert_eq!(probe_key_v6(&[129, 0, 0, 0, 1]), None);
+    }
+
+    /// Decodes a corpus fixture, hex digits with `#` comment lines.
+    fn fixture(text: &str) -> Vec<u8> {
+        let digits: String = text
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .flat_map(str::chars)
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        (0..digits.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&digits[at..at + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Checks what a message that parsed says about itself.
+    fn check(message: &[u8], parsed: Result<ParsedReply, MalformedReason>) {
+        if let Ok(parsed) = parsed {
+            assert_eq!(parsed.icmp_type, message[0]);
+            if let Some(quoted_len) = parsed.quoted_len {
+                assert!(quoted_len <= message.len() - 8);
+            }
+        }
+    }
+
+    #[test]
+    fn captured_replies() {
+        let udp = |source_port, destination_port| {
+            Some(ProbeKey::Udp {
+                source_port,
+                destination_port,
+            })
+        };
+        let parsed = |icmp_type, code, key, quoted_len| ParsedReply {
+            icmp_type,
+            code,
+            key,
+            quoted_len,
+            incoming_interface: None,
+            next_hop_mtu: None,
+        };
+        let corpus_v4 = [
+            (
+                include_str!("../tests/corpus/v4-time-exceeded.hex"),
+                parsed(11, 0, udp(0xb16b, 0x829b), Some(60)),
+            ),
+            (
+                include_str!("../tests/corpus/v4-net-unreachable.hex"),
+                parsed(3, 0, udp(0xb16b, 0x829c), Some(60)),
+            ),
+            (
+                include_str!("../tests/corpus/v4-port-unreachable.hex"),
+                parsed(3, 3, udp(0xb16b, 0x82db), Some(60)),
+            ),
+            (
+                include_str!("../tests/corpus/v4-echo-reply.hex"),
+                parsed(
+                    0,
+                    0,
+                    Some(ProbeKey::Echo {
+                        identifier: 0x1234,
+                        sequence: 1,
+                    }),
+                    None,
+                ),
+            ),
+        ];
+        for (text, expected) in corpus_v4.iter() {
+            let message = fixture(text);
+            assert_eq!(parse_v4(&message).as_ref(), Ok(expected));
+            // Cuts into the zero padding keep the checksum, the others do not.
+            for len in 0..message.len() {
+                check(&message[..len], parse_v4(&message[..len]));
+            }
+            assert_eq!(parse_v4(&message[..6]), Err(MalformedReason::Truncated));
+        }
+        let message = fixture(include_str!("../tests/corpus/v6-port-unreachable.hex"));
+        assert_eq!(
+            parse_v6(&message),
+            Ok(parsed(1, 4, udp(0xcee9, 0x82db), Some(80)))
+        );
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+
+        /// Returns a reply to a UDP probe of `payload` bytes, or to an echo request.
+        fn reply(v6: bool, kind: u8, port: u16, payload: usize) -> Vec<u8> {
+            let mut udp = vec![0xa0, 0x00, 0, 0, 0, 0, 0, 0];
+            udp[2..4].copy_from_slice(&port.to_be_bytes());
+            udp.resize(8 + payload, 0x5a);
+            let echo = [if v6 { 128 } else { 8 }, 0, 0, 0, 0x12, 0x34, 0, 1];
+            let (probe, from) = match (v6, kind % 3) {
+                (false, 2) => (probe_v4(1, &echo), test_net_v4(100)),
+                (false, _) => (probe_v4(17, &udp), test_net_v4(1)),
+                (true, 2) => (probe_v6(58, &echo), test_net_v6(100)),
+                (true, _) => (probe_v6(17, &udp), test_net_v6(1)),
+            };
+            match kind % 3 {
+                0 => time_exceeded(&probe, from),
+                1 => port_unreachable(&probe, from),
+                _ => echo_reply(&probe, from),
+            }
+        }
+
+        /// Applies `edits` to a valid reply, then appends `tail`, which is made an
+        /// RFC 4884 extension structure past 128 bytes when `extended` is set.
+        fn mutate(
+            mut message: Vec<u8>,
+            edits: &[(usize, u8)],
+            tail: &[u8],
+            extended: bool,
+            cut: Option<usize>,
+        ) -> Vec<u8> {
+            if extended {
+                message.resize(8 + COMPAT_ORIGINAL_LEN, 0);
+                let mut structure = vec![EXTENSION_VERSION << 4, 0, 0, 0];
+                structure.extend_from_slice(tail);
+                let checksum = util::checksum(&structure, 1);
+                structure[2..4].copy_from_slice(&checksum.to_be_bytes());
+                message.extend_from_slice(&structure);
+            } else {
+                message.extend_from_slice(tail);
+            }
+            for &(at, value) in edits {
+                let len = message.len();
+                message[at % len] = value;
+            }
+            if let Some(cut) = cut {
+                message.truncate(cut % (message.len() + 1));
+            }
+            message
+        }
+
+        fn with_checksum(mut message: Vec<u8>) -> Vec<u8> {
+            if message.len() >= 4 {
+                message[2..4].copy_from_slice(&[0, 0]);
+                let checksum = util::checksum(&message, 1);
+                message[2..4].copy_from_slice(&checksum.to_be_bytes());
+            }
+            message
+        }
+
+        /// Runs every parser of received bytes over `message`.
+        fn parse_all(message: &[u8]) {
+            let _ = parse_v4(message);
+            let _ = parse_v6(message);
+            let _ = quoted_v4(message).map(quoted_source);
+            let _ = quoted_v6(message).map(quoted_source);
+            if let Some(options) = quoted_options_v4(message) {
+                let _ = recorded_route(options);
+                let _ = recorded_timestamps(options);
+            }
+            let _ = recorded_route(message);
+            let _ = recorded_timestamps(message);
+            let _ = sent_key(message);
+            let _ = is_dccp_answer(message);
+        }
+
+        proptest! {
+            #[test]
+            fn arbitrary_bytes_never_panic(message in vec(any::<u8>(), 0..600)) {
+                parse_all(&message);
+                check(&message, parse_v4(&message));
+                check(&message, parse_v6(&message));
+                let _ = mangled_fields(&message, &message);
+            }
+
+            #[test]
+            fn mutated_replies_never_panic(
+                v6 in any::<bool>(),
+                kind in any::<u8>(),
+                port in any::<u16>(),
+                payload in 0usize..200,
+                edits in vec((any::<usize>(), any::<u8>()), 0..6),
+                tail in vec(any::<u8>(), 0..80),
+                extended in any::<bool>(),
+                cut in proptest::option::of(any::<usize>()),
+                resum in any::<bool>(),
+            ) {
+                let valid = reply(v6, kind, port, payload);
+                let mut message = mutate(valid.clone(), &edits, &tail, extended, cut);
+                if resum && !v6 {
+                    message = with_checksum(message);
+                }
+                parse_all(&message);
+                if v6 {
+                    check(&message, parse_v6(&message));
+                } else {
+                    check(&message, parse_v4(&message));
+                }
+                let quoted = if v6 { quoted_v6(&message) } else { quoted_v4(&message) };
+                if let Some(quoted) = quoted {
+                    let _ = mangled_fields(&valid[8..], quoted);
+                }
+            }
+
+            #[test]
+            fn valid_replies_are_classified(
+                v6 in any::<bool>(),
+                kind in any::<u8>(),
+                port in any::<u16>(),
+                payload in 0usize..200,
+            ) {
+                let message = reply(v6, kind, port, payload);
+                let parsed = if v6 { parse_v6(&message) } else { parse_v4(&message) };
+                prop_assert!(parsed.is_ok_and(|parsed| parsed.key.is_some()));
+            }
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! Finished traces, their export in the formats of other tools and their replay.
+use crate::clock::{Clock, SystemClock};
+use crate::{HopFound, TraceMetadata, TraceRouteProtocol};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// This struct is a finished trace, its metadata and every hop it reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct TraceReport {
+    pub metadata: TraceMetadata,
+    pub hops: Vec<HopFound>,
+    /// Wall clock time the last hop came in.
+    pub ended_at: SystemTime,
+    /// Name the destination was looked up by, its address if None.
+    pub host: Option<String>,
+}
+
+impl TraceReport {
+    /// Creates new TraceReport of the trace described by `metadata`, ended now.
+    pub fn new(metadata: TraceMetadata, hops: Vec<HopFound>) -> TraceReport {
+        TraceReport {
+            metadata,
+            hops,
+            ended_at: SystemTime::now(),
+            host: None,
+        }
+    }
+
+    /// Returns the report as JSON, read back by `from_json`.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("reports always serialize")
+    }
+
+    /// Reads a report written by `to_json`. Fields it does not know, like those
+    /// of later versions, are skipped.
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> serde_json::Result<TraceReport> {
+        serde_json::from_str(json)
+    }
+
+    /// Returns the trace as a RIPE Atlas traceroute result, one JSON object.
+    ///
+    /// Every probe of a TTL gets an entry in its `result`: a `{"x": "*"}` for every
+    /// unanswered try, then the reply if one came. The `size` of a reply leaves its
+    /// IP header out, and its `ttl` is only known with `TraceRouteConfig::capture_raw`
+    /// over a backend keeping the header. Traces of this crate keep no flow id
+    /// constant, so `paris_id` is left out, and so are the ids of Atlas probes and
+    /// measurements.
+    pub fn to_atlas_json(&self) -> String {
+        let metadata = &self.metadata;
+        let destination = metadata.destination.to_string();
+        let hops: Vec<String> = self
+            .hops
+            .iter()
+            .filter(|hop| !hop.is_end_of_trace() && hop.local_hops.is_none())
+            .map(atlas_hop)
+            .collect();
+        format!(
+            "{{\"af\":{},\"dst_addr\":{},\"dst_name\":{},\"endtime\":{},\"from\":{},\
+             \"msm_name\":\"Traceroute\",\"proto\":{},\"result\":[{}],\"size\":{},\
+             \"src_addr\":{},\"timestamp\":{},\"type\":\"traceroute\"}}",
+            if metadata.destination.is_ipv4() { 4 } else { 6 },
+            json_string(&destination),
+            json_string(self.host.as_deref().unwrap_or(&destination)),
+            unix_seconds(self.ended_at),
+            json_string(&metadata.source.to_string()),
+            json_string(&atlas_proto(metadata.protocol)),
+            hops.join(","),
+            metadata.size,
+            json_string(&metadata.source.to_string()),
+            unix_seconds(metadata.started_at),
+        )
+    }
+}
+
+/// This struct hands stored hops out on a channel, like a running trace does, so
+/// code reading a `Receiver<HopFound>` can be run against recorded traces.
+pub struct ReplaySource {
+    hops: Vec<HopFound>,
+    timing: Option<Duration>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ReplaySource {
+    /// Creates new ReplaySource handing out `hops` in order, without waiting.
+    pub fn new(hops: Vec<HopFound>) -> ReplaySource {
+        ReplaySource {
+            hops,
+            timing: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Waits before every hop as long as the trace waited for it: `unanswered`
+    /// for each try that went unanswered, then the round trip time of the reply.
+    pub fn with_timing(mut self, unanswered: Duration) -> ReplaySource {
+        self.timing = Some(unanswered);
+        self
+    }
+
+    /// Waits on `clock` instead of the clock of the system.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> ReplaySource {
+        self.clock = clock;
+        self
+    }
+
+    /// Starts handing out the hops on a thread of its own and returns the channel
+    /// they come on. It closes after the last hop or once the receiver is dropped.
+    pub fn start(self) -> Receiver<HopFound> {
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            let mut at = self.clock.now();
+            for hop in self.hops {
+                if let Some(unanswered) = self.timing {
+                    at += unanswered * u32::from(hop.tries) + hop.time.unwrap_or_default();
+                    self.clock.sleep_until(at);
+                }
+                if sender.send(hop).is_err() {
+                    return;
+                }
+            }
+        });
+        receiver
+    }
+}
+
+/// Returns the name Atlas gives `protocol`, TCP for raw probes of protocol 6.
+fn atlas_proto(protocol: TraceRouteProtocol) -> String {
+    match protocol {
+        TraceRouteProtocol::Raw(6) => "TCP".to_string(),
+        protocol => protocol.to_string().to_ascii_uppercase(),
+    }
+}
+
+/// Formats the probes of one TTL as an Atlas hop object.
+fn atlas_hop(hop: &HopFound) -> String {
+    let mut probes = vec!["{\"x\":\"*\"}".to_string(); usize::from(hop.tries)];
+    if let Some(addr) = hop.addr {
+        let mut reply = format!("{{\"from\":{}", json_string(&addr.to_string()));
+        if let Some(time) = hop.time {
+            let _ = write!(reply, ",\"rtt\":{:.3}", time.as_secs_f64() * 1000.0);
+        }
+        if let Some(size) = hop.reply_len {
+            let _ = write!(reply, ",\"size\":{}", size);
+        }
+        if let Some(ttl) = reply_ttl(hop) {
+            let _ = write!(reply, ",\"ttl\":{}", ttl);
+        }
+        if let Some(mtu) = hop.next_hop_mtu {
+            let _ = write!(reply, ",\"mtu\":{}", mtu);
+        }
+        reply.push('}');
+        probes.push(reply);
+    }
+    if probes.is_empty() {
+        probes.push("{\"x\":\"*\"}".to_string());
+    }
+    format!(
+        "{{\"hop\":{},\"result\":[{}]}}",
+        hop.hop_count,
+        probes.join(",")
+    )
+}
+
+/// Returns the TTL of the reply of `hop`, read from the IP header captured with it.
+fn reply_ttl(hop: &HopFound) -> Option<u8> {
+    let raw = hop.raw_reply.as_ref()?;
+    match (raw.first().map(|b| b >> 4), hop.addr?) {
+        (Some(4), IpAddr::V4(_)) if raw.len() >= 20 => Some(raw[8]),
+        (Some(6), IpAddr::V6(_)) if raw.len() >= 40 => Some(raw[7]),
+        _ => None,
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+/// Escapes a string for JSON.
+fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_net_v4, MockClock};
+    use crate::CompletionReason;
+
+    /// Returns the fixture without its comment lines and line breaks.
+    fn fixture(text: &str) -> String {
+        text.lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(str::trim)
+            .collect()
+    }
+
+    fn report() -> TraceReport {
+        let started_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let metadata = TraceMetadata {
+            source: test_net_v4(254),
+            destination: test_net_v4(100),
+            protocol: TraceRouteProtocol::Udp,
+            identifier: 0x1234,
+            trace_id: 7,
+            fwmark: None,
+            preflight: None,
+            backend: None,
+            size: 32,
+            started_at,
+        };
+        let mut first = HopFound::new(1, Some(test_net_v4(1)), 0, false, None);
+        first.time = Some(Duration::from_micros(1_234));
+        first.reply_len = Some(36);
+        let mut header = vec![0x45, 0, 0, 56, 0, 0, 0, 0, 254, 1];
+        header.resize(20, 0);
+        first.raw_reply = Some([header, vec![11, 0]].concat());
+        let mut third = HopFound::new(
+            3,
+            Some(test_net_v4(100)),
+            1,
+            true,
+            Some(Duration::from_micros(20_500)),
+        );
+        third.reply_len = Some(36);
+        let mut report = TraceReport::new(metadata, vec![first, HopFound::timed_out(2, 3), third]);
+        report.ended_at = started_at + Duration::from_secs(4);
+        report
+    }
+
+    #[test]
+    fn atlas_export_follows_the_result_format() {
+        let expected = fixture(include_str!("../tests/corpus/atlas-traceroute.json"));
+        assert_eq!(report().to_atlas_json(), expected);
+
+        let mut named = report();
+        named.host = Some("example.net".to_string());
+        assert!(named
+            .to_atlas_json()
+            .contains("\"dst_name\":\"example.net\""));
+    }
+
+    #[test]
+    fn atlas_export_leaves_out_records_of_no_hop() {
+        let mut report = report();
+        report.metadata.protocol = TraceRouteProtocol::Raw(6);
+        report.hops.truncate(2);
+        report
+            .hops
+            .push(HopFound::end_of_trace(3, 1, CompletionReason::NotReached));
+        let json = report.to_atlas_json();
+        assert!(json.contains("\"proto\":\"TCP\""));
+        assert!(
+            json.contains("{\"hop\":2,\"result\":[{\"x\":\"*\"},{\"x\":\"*\"},{\"x\":\"*\"}]}]")
+        );
+        assert!(!json.contains("\"hop\":3"));
+    }
+
+    #[test]
+    fn replay_keeps_the_order_and_timing_of_the_hops() {
+        let hops = report().hops;
+        let received: Vec<HopFound> = ReplaySource::new(hops.clone()).start().iter().collect();
+        assert_eq!(received, hops);
+
+        let clock = MockClock::new();
+        let started = clock.now();
+        let replay = ReplaySource::new(hops.clone())
+            .with_timing(Duration::from_millis(100))
+            .with_clock(Arc::new(clock.clone()));
+        let received: Vec<HopFound> = replay.start().iter().collect();
+        assert_eq!(received, hops);
+        // 1.234 ms, three unanswered tries, then one more before 20.5 ms.
+        assert_eq!(clock.now() - started, Duration::from_micros(421_734));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn reports_round_trip_through_json() {
+        let report = report();
+        assert_eq!(TraceReport::from_json(&report.to_json()).unwrap(), report);
+
+        let hops: Vec<HopFound> =
+            serde_json::from_str(&serde_json::to_string(&report.hops).unwrap()).unwrap();
+        assert_eq!(hops, report.hops);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn unknown_fields_are_skipped() {
+        let report = report();
+        let json = report.to_json();
+        let newer = format!("{{\"checksum\":\"abc\",{}", &json[1..]);
+        let newer = newer.replacen("\"tries\":", "\"asn\":[64500],\"tries\":", 1);
+        assert_eq!(TraceReport::from_json(&newer).unwrap(), report);
+        assert!(TraceReport::from_json("{\"hops\":[]}").is_err());
+    }
+}
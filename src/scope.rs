@@ -0,0 +1,271 @@
+//! Classification of hop addresses after the IANA special-purpose registries, and
+//! of the addresses IPv6 transition technologies put on the path.
+//!
+//! `IpAddr::is_global` is not stable yet, so the ranges are matched here by hand.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// This enum represents the scope of an address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum AddrScope {
+    /// RFC 1918 private ranges and local-use NAT64.
+    Private,
+    /// Shared address space of carrier grade NAT, 100.64.0.0/10.
+    SharedCgnat,
+    LinkLocal,
+    Loopback,
+    /// IPv6 unique local addresses, fc00::/7.
+    UniqueLocal,
+    Global,
+    Unspecified,
+    /// Any other special-purpose range that is not globally reachable, like
+    /// documentation, benchmarking, multicast or reserved ranges.
+    Reserved,
+}
+
+/// Returns true if the first `prefix` bits of `addr` and `network` are equal.
+fn in_v4(addr: Ipv4Addr, network: [u8; 4], prefix: u32) -> bool {
+    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+    u32::from(addr) & mask == u32::from(Ipv4Addr::from(network)) & mask
+}
+
+/// Returns true if the first `prefix` bits of `addr` and `network` are equal.
+fn in_v6(addr: Ipv6Addr, network: [u16; 8], prefix: u32) -> bool {
+    let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+    u128::from(addr) & mask == u128::from(Ipv6Addr::from(network)) & mask
+}
+
+fn v4_scope(addr: Ipv4Addr) -> AddrScope {
+    if addr.is_unspecified() {
+        return AddrScope::Unspecified;
+    }
+    if in_v4(addr, [192, 0, 0, 9], 32) || in_v4(addr, [192, 0, 0, 10], 32) {
+        return AddrScope::Global;
+    }
+    let table: &[([u8; 4], u32, AddrScope)] = &[
+        ([0, 0, 0, 0], 8, AddrScope::Reserved),
+        ([10, 0, 0, 0], 8, AddrScope::Private),
+        ([100, 64, 0, 0], 10, AddrScope::SharedCgnat),
+        ([127, 0, 0, 0], 8, AddrScope::Loopback),
+        ([169, 254, 0, 0], 16, AddrScope::LinkLocal),
+        ([172, 16, 0, 0], 12, AddrScope::Private),
+        ([192, 0, 0, 0], 24, AddrScope::Reserved),
+        ([192, 0, 2, 0], 24, AddrScope::Reserved),
+        ([192, 168, 0, 0], 16, AddrScope::Private),
+        ([198, 18, 0, 0], 15, AddrScope::Reserved),
+        ([198, 51, 100, 0], 24, AddrScope::Reserved),
+        ([203, 0, 113, 0], 24, AddrScope::Reserved),
+        ([224, 0, 0, 0], 4, AddrScope::Reserved),
+        ([240, 0, 0, 0], 4, AddrScope::Reserved),
+    ];
+    table
+        .iter()
+        .find(|(network, prefix, _)| in_v4(addr, *network, *prefix))
+        .map_or(AddrScope::Global, |(_, _, scope)| *scope)
+}
+
+fn v6_scope(addr: Ipv6Addr) -> AddrScope {
+    if addr.is_unspecified() {
+        return AddrScope::Unspecified;
+    }
+    if addr.is_loopback() {
+        return AddrScope::Loopback;
+    }
+    if let Some(v4) = addr.to_ipv4_mapped() {
+        return v4_scope(v4);
+    }
+    let table: &[([u16; 8], u32, AddrScope)] = &[
+        ([0, 0, 0, 0, 0, 0, 0, 0], 96, AddrScope::Reserved),
+        ([0x64, 0xff9b, 1, 0, 0, 0, 0, 0], 48, AddrScope::Private),
+        ([0x100, 0, 0, 0, 0, 0, 0, 0], 64, AddrScope::Reserved),
+        ([0x2001, 0x2, 0, 0, 0, 0, 0, 0], 48, AddrScope::Reserved),
+        ([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32, AddrScope::Reserved),
+        ([0x3fff, 0, 0, 0, 0, 0, 0, 0], 20, AddrScope::Reserved),
+        ([0xfc00, 0, 0, 0, 0, 0, 0, 0], 7, AddrScope::UniqueLocal),
+        ([0xfe80, 0, 0, 0, 0, 0, 0, 0], 10, AddrScope::LinkLocal),
+        ([0xfec0, 0, 0, 0, 0, 0, 0, 0], 10, AddrScope::Reserved),
+        ([0xff00, 0, 0, 0, 0, 0, 0, 0], 8, AddrScope::Reserved),
+    ];
+    table
+        .iter()
+        .find(|(network, prefix, _)| in_v6(addr, *network, *prefix))
+        .map_or(AddrScope::Global, |(_, _, scope)| *scope)
+}
+
+/// Returns the scope of an address.
+///
+/// IPv4-mapped IPv6 addresses are classified by the IPv4 address they carry.
+pub fn addr_scope(addr: IpAddr) -> AddrScope {
+    match addr {
+        IpAddr::V4(v4) => v4_scope(v4),
+        IpAddr::V6(v6) => v6_scope(v6),
+    }
+}
+
+/// This enum represents the transition technology an address belongs to, which
+/// tells the path runs through a tunnel or a translator.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum TransitionTech {
+    /// 6to4 addresses, 2002::/16, RFC 3056.
+    SixToFour,
+    /// Anycast address range of 6to4 relays, 192.88.99.0/24, RFC 3068.
+    SixToFourRelay,
+    /// Teredo addresses, 2001::/32, RFC 4380.
+    Teredo,
+    /// Well-known prefix of NAT64 translators, 64:ff9b::/96, RFC 6052.
+    Nat64,
+}
+
+/// Returns the transition technology `addr` belongs to, None for other addresses.
+pub fn transition_tech(addr: IpAddr) -> Option<TransitionTech> {
+    match addr {
+        IpAddr::V4(v4) if in_v4(v4, [192, 88, 99, 0], 24) => Some(TransitionTech::SixToFourRelay),
+        IpAddr::V4(_) => None,
+        IpAddr::V6(v6) if in_v6(v6, [0x2002, 0, 0, 0, 0, 0, 0, 0], 16) => {
+            Some(TransitionTech::SixToFour)
+        }
+        IpAddr::V6(v6) if in_v6(v6, [0x2001, 0, 0, 0, 0, 0, 0, 0], 32) => {
+            Some(TransitionTech::Teredo)
+        }
+        IpAddr::V6(v6) if in_v6(v6, [0x64, 0xff9b, 0, 0, 0, 0, 0, 0], 96) => {
+            Some(TransitionTech::Nat64)
+        }
+        IpAddr::V6(_) => None,
+    }
+}
+
+/// Returns the IPv4 address a 6to4 or Teredo address carries: the one of the 6to4
+/// site, or the public one of the Teredo client, stored with its bits inverted.
+pub fn embedded_v4(addr: IpAddr) -> Option<Ipv4Addr> {
+    let bits = match addr {
+        IpAddr::V6(v6) => u128::from(v6),
+        IpAddr::V4(_) => return None,
+    };
+    match transition_tech(addr)? {
+        TransitionTech::SixToFour => Some(Ipv4Addr::from((bits >> 80) as u32)),
+        TransitionTech::Teredo => Some(Ipv4Addr::from(!(bits as u32))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v4_registry_boundaries() {
+        let cases = [
+            ("0.0.0.0", AddrScope::Unspecified),
+            ("0.1.2.3", AddrScope::Reserved),
+            ("1.0.0.0", AddrScope::Global),
+            ("9.255.255.255", AddrScope::Global),
+            ("10.0.0.0", AddrScope::Private),
+            ("10.255.255.255", AddrScope::Private),
+            ("11.0.0.0", AddrScope::Global),
+            ("100.63.255.255", AddrScope::Global),
+            ("100.64.0.0", AddrScope::SharedCgnat),
+            ("100.127.255.255", AddrScope::SharedCgnat),
+            ("100.128.0.0", AddrScope::Global),
+            ("127.0.0.1", AddrScope::Loopback),
+            ("169.254.1.1", AddrScope::LinkLocal),
+            ("169.255.0.1", AddrScope::Global),
+            ("172.15.255.255", AddrScope::Global),
+            ("172.16.0.0", AddrScope::Private),
+            ("172.31.255.255", AddrScope::Private),
+            ("172.32.0.0", AddrScope::Global),
+            ("192.0.0.8", AddrScope::Reserved),
+            ("192.0.0.9", AddrScope::Global),
+            ("192.0.2.1", AddrScope::Reserved),
+            ("192.167.255.255", AddrScope::Global),
+            ("192.168.0.1", AddrScope::Private),
+            ("192.169.0.0", AddrScope::Global),
+            ("198.17.255.255", AddrScope::Global),
+            ("198.19.255.255", AddrScope::Reserved),
+            ("198.20.0.0", AddrScope::Global),
+            ("203.0.113.7", AddrScope::Reserved),
+            ("224.0.0.1", AddrScope::Reserved),
+            ("223.255.255.255", AddrScope::Global),
+            ("255.255.255.255", AddrScope::Reserved),
+        ];
+        for (addr, scope) in &cases {
+            assert_eq!(addr_scope(addr.parse().unwrap()), *scope, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn v6_registry_boundaries() {
+        let cases = [
+            ("::", AddrScope::Unspecified),
+            ("::1", AddrScope::Loopback),
+            ("::ffff:10.0.0.1", AddrScope::Private),
+            ("::ffff:8.8.8.8", AddrScope::Global),
+            ("64:ff9b::8.8.8.8", AddrScope::Global),
+            ("64:ff9b:1::1", AddrScope::Private),
+            ("100::1", AddrScope::Reserved),
+            ("100:0:0:1::1", AddrScope::Global),
+            ("2001:db8::1", AddrScope::Reserved),
+            ("2001:db9::1", AddrScope::Global),
+            ("2606:4700::1111", AddrScope::Global),
+            ("3fff:fff::1", AddrScope::Reserved),
+            ("3fff:1000::1", AddrScope::Global),
+            ("fbff:ffff::1", AddrScope::Global),
+            ("fc00::1", AddrScope::UniqueLocal),
+            ("fdff:ffff::1", AddrScope::UniqueLocal),
+            ("fe80::1", AddrScope::LinkLocal),
+            ("febf:ffff::1", AddrScope::LinkLocal),
+            ("fec0::1", AddrScope::Reserved),
+            ("ff02::1", AddrScope::Reserved),
+        ];
+        for (addr, scope) in &cases {
+            assert_eq!(addr_scope(addr.parse().unwrap()), *scope, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn transition_prefix_boundaries() {
+        let cases = [
+            ("2002:c000:201::1", Some(TransitionTech::SixToFour)),
+            ("2002:ffff:ffff:ffff::1", Some(TransitionTech::SixToFour)),
+            ("2001:ffff::1", None),
+            ("2003::1", None),
+            (
+                "2001:0:4136:e378:8000:63bf:3fff:fdd2",
+                Some(TransitionTech::Teredo),
+            ),
+            ("2001:0:ffff:ffff::1", Some(TransitionTech::Teredo)),
+            ("2001:1::1", None),
+            ("2001:db8::1", None),
+            ("64:ff9b::8.8.8.8", Some(TransitionTech::Nat64)),
+            ("64:ff9b::1:0:0:0", None),
+            ("64:ff9b:1::1", None),
+            ("64:ff9a:ffff::1", None),
+            ("192.88.99.1", Some(TransitionTech::SixToFourRelay)),
+            ("192.88.99.255", Some(TransitionTech::SixToFourRelay)),
+            ("192.88.98.255", None),
+            ("192.88.100.0", None),
+        ];
+        for (addr, tech) in &cases {
+            assert_eq!(transition_tech(addr.parse().unwrap()), *tech, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn embedded_v4_of_tunnels() {
+        let embedded = |addr: &str| embedded_v4(addr.parse().unwrap());
+        assert_eq!(
+            embedded("2002:c000:201::1"),
+            Some(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        // The Teredo example of RFC 4380, client 192.0.2.45 behind its server.
+        assert_eq!(
+            embedded("2001:0:4136:e378:8000:63bf:3fff:fdd2"),
+            Some(Ipv4Addr::new(192, 0, 2, 45))
+        );
+        assert_eq!(embedded("64:ff9b::8.8.8.8"), None);
+        assert_eq!(embedded("192.88.99.1"), None);
+        assert_eq!(embedded("2001:1::c000:201"), None);
+    }
+}
@@ -0,0 +1,383 @@
+//! One receiving thread for the replies of many traces, see
+//! `TraceRouteConfig::shared_receiver`.
+use crate::backend::{self, ProbeBackend};
+#[cfg(target_os = "linux")]
+use crate::backend::{PnetBackend, ReplyKind, TimestampSource};
+#[cfg(target_os = "linux")]
+use crate::error::TraceRouteError;
+use crate::receiver::ReplyReceiver;
+#[cfg(target_os = "linux")]
+use pnet::packet::ip::IpNextHeaderProtocols;
+#[cfg(target_os = "linux")]
+use pnet::transport::transport_channel;
+#[cfg(target_os = "linux")]
+use pnet::transport::TransportChannelType::Layer4;
+#[cfg(target_os = "linux")]
+use pnet::transport::TransportProtocol::{Ipv4, Ipv6};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(target_os = "linux")]
+use std::sync::Weak;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+#[cfg(target_os = "linux")]
+use std::time::Instant;
+
+/// How often the receiving thread looks whether it should stop.
+const WAKE: Duration = Duration::from_millis(100);
+
+/// The receiver of `SharedReceiver::global`, while anything holds it.
+#[cfg(target_os = "linux")]
+static GLOBAL: Mutex<Weak<Hub>> = Mutex::new(Weak::new());
+
+/// This struct reads the replies of every trace registered with it on one thread,
+/// and hands each to the trace whose probe it answers.
+///
+/// Traces of a configuration holding it register when they start and leave when
+/// they end. The thread stops and the sockets close once the last clone, held by
+/// configurations or running traces, is dropped. Configurations holding it
+/// compare equal only if they share the same receiver.
+#[derive(Clone)]
+pub struct SharedReceiver(Arc<Hub>);
+
+/// This struct is the thread of a `SharedReceiver` and the traces it serves.
+struct Hub {
+    traces: Arc<Mutex<Traces>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// This struct is the receivers of the registered traces, by registration.
+#[derive(Default)]
+struct Traces {
+    next: u64,
+    receivers: BTreeMap<u64, ReplyReceiver>,
+}
+
+impl SharedReceiver {
+    /// Returns the receiver of the process, reading ICMP and ICMPv6 on one raw
+    /// socket each. It is opened by the first call and shared by the later ones
+    /// while any holder is left.
+    #[cfg(target_os = "linux")]
+    pub fn global() -> Result<SharedReceiver, TraceRouteError> {
+        let mut global = GLOBAL.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(hub) = global.upgrade() {
+            return Ok(SharedReceiver(hub));
+        }
+        let receiver = SharedReceiver::over(Listening::open()?);
+        *global = Arc::downgrade(&receiver.0);
+        Ok(receiver)
+    }
+
+    /// Creates new SharedReceiver reading the replies from `backend`, which is
+    /// never sent on.
+    pub fn over<B: ProbeBackend + 'static>(backend: B) -> SharedReceiver {
+        let traces = Arc::new(Mutex::new(Traces::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (traces, stop) = (traces.clone(), stop.clone());
+            thread::spawn(move || listen(backend, &traces, &stop))
+        };
+        SharedReceiver(Arc::new(Hub {
+            traces,
+            stop,
+            thread: Some(thread),
+        }))
+    }
+
+    /// Returns the number of traces registered.
+    pub fn traces(&self) -> usize {
+        self.0.traces.lock().unwrap().receivers.len()
+    }
+
+    /// Hands the replies `receiver` classifies to it until the registration is dropped.
+    pub(crate) fn register(&self, receiver: ReplyReceiver) -> Registration {
+        let mut traces = self.0.traces.lock().unwrap();
+        let id = traces.next;
+        traces.next += 1;
+        traces.receivers.insert(id, receiver);
+        Registration {
+            receiver: self.clone(),
+            id,
+        }
+    }
+}
+
+impl fmt::Debug for SharedReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedReceiver")
+            .field("traces", &self.traces())
+            .finish()
+    }
+}
+
+impl PartialEq for SharedReceiver {
+    fn eq(&self, other: &SharedReceiver) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Drop for Hub {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// This struct keeps a trace registered with a `SharedReceiver`.
+pub(crate) struct Registration {
+    receiver: SharedReceiver,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let removed = self
+            .receiver
+            .0
+            .traces
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .receivers
+            .remove(&self.id);
+        drop(removed);
+    }
+}
+
+/// Reads `backend` and dispatches what it receives until `stop` is set.
+fn listen<B: ProbeBackend>(mut backend: B, traces: &Mutex<Traces>, stop: &AtomicBool) {
+    while !stop.load(Ordering::SeqCst) {
+        let received = backend.recv_reply(WAKE);
+        let traces = traces.lock().unwrap();
+        match received {
+            Ok(Some((kind, message, from))) => {
+                let owner = traces
+                    .receivers
+                    .values()
+                    .find(|receiver| receiver.owns(kind, &message, from));
+                if let Some(owner) = owner {
+                    owner.count(&message);
+                    owner.take(&backend, Ok((kind, message, from)));
+                }
+            }
+            Ok(None) => {}
+            Err(e) if backend::is_transient(&e) => {}
+            Err(e) => {
+                warn!("the shared receiver could not read: {}", e);
+                for receiver in traces.receivers.values() {
+                    let e = io::Error::new(e.kind(), e.to_string());
+                    receiver.take(&backend, Err(e));
+                }
+                drop(traces);
+                thread::sleep(WAKE);
+            }
+        }
+    }
+}
+
+/// This struct is the ICMP and ICMPv6 sockets of `SharedReceiver::global`.
+#[cfg(target_os = "linux")]
+struct Listening {
+    sockets: Vec<PnetBackend>,
+    /// Socket the last message was read from.
+    last: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl Listening {
+    /// Opens the sockets, a family the host can not open sockets for is left out.
+    fn open() -> Result<Listening, TraceRouteError> {
+        let mut sockets = Vec::new();
+        let mut failed = None;
+        for (v4, protocol) in [
+            (true, Layer4(Ipv4(IpNextHeaderProtocols::Icmp))),
+            (false, Layer4(Ipv6(IpNextHeaderProtocols::Icmpv6))),
+        ] {
+            match transport_channel(4096, protocol) {
+                Ok((tx, rx)) => sockets.push(PnetBackend::new(tx, rx, v4)),
+                Err(e) => {
+                    debug!("the shared receiver could not open a socket: {}", e);
+                    failed.get_or_insert(e);
+                }
+            }
+        }
+        match failed {
+            Some(e) if sockets.is_empty() => Err(TraceRouteError::from_channel(e)),
+            _ => Ok(Listening { sockets, last: 0 }),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl ProbeBackend for Listening {
+    fn send_to(&mut self, _: &[u8], _: IpAddr) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the shared receiver does not send",
+        ))
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+        Ok(self
+            .recv_reply(timeout)?
+            .map(|(_, message, from)| (message, from)))
+    }
+
+    fn recv_reply(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<(ReplyKind, Vec<u8>, IpAddr)>> {
+        let ready = match self.sockets.iter().position(PnetBackend::has_pending) {
+            Some(pending) => pending,
+            None => {
+                let fds: Vec<libc::c_int> = self
+                    .sockets
+                    .iter()
+                    .flat_map(|socket| socket.reply_fds())
+                    .collect();
+                match backend::poll_readable(&fds, timeout)?
+                    .iter()
+                    .position(|&ready| ready)
+                {
+                    Some(ready) => ready,
+                    None => return Ok(None),
+                }
+            }
+        };
+        self.last = ready;
+        self.sockets[ready].recv_reply(backend::READY_TIMEOUT)
+    }
+
+    fn reply_options(&self) -> Vec<u8> {
+        self.sockets[self.last].reply_options()
+    }
+
+    fn reply_header(&self) -> Option<Vec<u8>> {
+        self.sockets[self.last].reply_header()
+    }
+
+    fn reply_received_at(&self) -> Option<Instant> {
+        self.sockets[self.last].reply_received_at()
+    }
+
+    fn reply_timestamp_source(&self) -> TimestampSource {
+        self.sockets[self.last].reply_timestamp_source()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_net_v4, SimulatedBackend};
+    use crate::{HopFound, TraceRoute, TraceRouteConfig};
+    use std::net::IpAddr;
+    use std::sync::mpsc::Receiver;
+
+    fn simulated_path() -> SimulatedBackend {
+        SimulatedBackend::new(
+            vec![Some(test_net_v4(1)), Some(test_net_v4(2))],
+            test_net_v4(100),
+        )
+    }
+
+    fn tracer(receiver: &SharedReceiver) -> (TraceRoute, Receiver<HopFound>) {
+        let config = TraceRouteConfig {
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(500)),
+            shared_receiver: Some(receiver.clone()),
+            ..TraceRouteConfig::default()
+        };
+        TraceRoute::with_config(test_net_v4(100), config).unwrap()
+    }
+
+    /// This struct is a backend telling when it is dropped.
+    struct Watched(SimulatedBackend, Arc<AtomicBool>);
+
+    impl ProbeBackend for Watched {
+        fn send_to(&mut self, packet: &[u8], destination: IpAddr) -> io::Result<usize> {
+            self.0.send_to(packet, destination)
+        }
+
+        fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+            self.0.recv_timeout(timeout)
+        }
+    }
+
+    impl Drop for Watched {
+        fn drop(&mut self) {
+            self.1.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn traces_register_while_they_run() {
+        let path = simulated_path();
+        let receiver = SharedReceiver::over(path.clone());
+        let (trace_route, hops) = tracer(&receiver);
+        let handle = trace_route
+            .run_with_backend(path, test_net_v4(254))
+            .unwrap();
+        assert_eq!(receiver.traces(), 1);
+        handle.join().unwrap();
+        assert_eq!(receiver.traces(), 0);
+        let hops: Vec<HopFound> = hops.try_iter().collect();
+        assert_eq!(hops.len(), 3);
+        assert_eq!(hops[2].addr, Some(test_net_v4(100)));
+    }
+
+    #[test]
+    fn replies_go_to_the_trace_they_answer() {
+        // Both traces send on the same network, each would read the replies of
+        // the other off its own backend.
+        let path = simulated_path();
+        let receiver = SharedReceiver::over(path.clone());
+        let traces: Vec<_> = (0..2).map(|_| tracer(&receiver)).collect();
+        let handles: Vec<_> = traces
+            .iter()
+            .map(|(trace_route, _)| {
+                trace_route
+                    .run_with_backend(path.clone(), test_net_v4(254))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(receiver.traces(), 2);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        for (_, hops) in &traces {
+            let addrs: Vec<_> = hops.try_iter().map(|hop| hop.addr).collect();
+            assert_eq!(
+                addrs,
+                vec![
+                    Some(test_net_v4(1)),
+                    Some(test_net_v4(2)),
+                    Some(test_net_v4(100))
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn last_holder_stops_the_thread() {
+        let path = simulated_path();
+        let closed = Arc::new(AtomicBool::new(false));
+        let receiver = SharedReceiver::over(Watched(path.clone(), closed.clone()));
+        let (trace_route, _hops) = tracer(&receiver);
+        trace_route
+            .run_with_backend(path, test_net_v4(254))
+            .unwrap()
+            .join()
+            .unwrap();
+        drop(receiver);
+        assert!(!closed.load(Ordering::SeqCst));
+        drop(trace_route);
+        assert!(closed.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,454 @@
+//! Delivery of found hops to the results channel.
+use crate::anomaly::PathCheck;
+use crate::broadcast::{Broadcast, SUBSCRIBER_BUFFER};
+use crate::logging::{self, Span};
+use crate::metrics::Metrics;
+use crate::scope::AddrScope;
+use crate::{
+    CompletionReason, HopCorrection, HopFound, PreviousOutcome, TraceComplete, TraceEvent,
+    TraceRouteError, TraceRouteProtocol, TraceWarning,
+};
+use std::mem;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+/// Returns true for hops answered from a private, shared or link local address.
+fn is_local(hop: &HopFound) -> bool {
+    matches!(
+        hop.addr_scope,
+        Some(AddrScope::Private)
+            | Some(AddrScope::SharedCgnat)
+            | Some(AddrScope::LinkLocal)
+            | Some(AddrScope::UniqueLocal)
+    )
+}
+
+/// This struct is the error of a send to a results channel whose receiver is gone,
+/// or of a trace attempt that was abandoned.
+#[derive(Debug)]
+pub(crate) struct Disconnected;
+
+/// This enum is the channel a trace reports on.
+pub(crate) enum Emitter {
+    /// Takes the hops and drops every other event.
+    Hops(Sender<HopFound>),
+    Events(Sender<TraceEvent>),
+}
+
+impl Emitter {
+    fn emit(&self, event: TraceEvent) -> Result<(), Disconnected> {
+        let sent = match (self, event) {
+            (Emitter::Hops(tx), TraceEvent::Hop(hop)) => tx.send(hop).is_ok(),
+            (Emitter::Hops(_), _) => true,
+            (Emitter::Events(tx), event) => tx.send(event).is_ok(),
+        };
+        if sent {
+            Ok(())
+        } else {
+            Err(Disconnected)
+        }
+    }
+}
+
+/// This struct sends hops found by the worker, optionally hiding the local network.
+///
+/// With hiding on, hops answered from local addresses at the start of the path are
+/// held back and replaced by a single placeholder numbered like the last of them,
+/// so later hops keep their numbers. The terminal hop is always reported.
+///
+/// Every hop is also reported as a diagnostic event, the completion one in the span
+/// that was current when the sink was created. Answered hops are counted, and the
+/// terminal hop carries the final counters. Every hop sent, placeholders included,
+/// is stamped with the id of the trace.
+///
+/// An attempt on probation holds its silent hops back until one answers, and is
+/// abandoned when that many TTLs stayed silent, see `begin_attempt`. Silent hops
+/// are also held back while the trace may be rerun, see `hold_silence`. On a hops
+/// channel the hops from the first silent one on are held back for a second pass,
+/// which fills them in, see `hold_for_second_pass`. The other
+/// events of the trace go through `report` and `complete`, an error reported is
+/// also kept in `failure` for the hops channel, which does not take it. The
+/// trace is cancelled through `cancellation`. Every event also goes to the
+/// `subscribers`, which keep the trace going while the channel is gone.
+pub(crate) struct HopSink {
+    tx: Emitter,
+    subscribers: Broadcast,
+    local: Option<Vec<HopFound>>,
+    trace: Span,
+    metrics: Arc<Metrics>,
+    trace_id: u64,
+    protocol: Option<TraceRouteProtocol>,
+    probation: Option<u8>,
+    holding: bool,
+    held: Vec<HopFound>,
+    /// Hops held back for the second pass, None unless it fills them in.
+    second_pass: Option<Vec<HopFound>>,
+    path: Option<PathCheck>,
+    abandoned: bool,
+    completion: Option<CompletionReason>,
+    failure: Arc<Mutex<Option<TraceRouteError>>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl HopSink {
+    /// Creates new HopSink.
+    pub fn new(
+        tx: Emitter,
+        hide_local_hops: bool,
+        metrics: Arc<Metrics>,
+        trace_id: u64,
+    ) -> HopSink {
+        HopSink {
+            tx,
+            subscribers: Broadcast::new(SUBSCRIBER_BUFFER),
+            local: if hide_local_hops {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            trace: Span::current(),
+            metrics,
+            trace_id,
+            protocol: None,
+            probation: None,
+            holding: false,
+            held: Vec::new(),
+            second_pass: None,
+            path: None,
+            abandoned: false,
+            completion: None,
+            failure: Arc::new(Mutex::new(None)),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns where the error that stopped the trace is kept.
+    pub fn failure(&self) -> Arc<Mutex<Option<TraceRouteError>>> {
+        self.failure.clone()
+    }
+
+    /// Returns the subscribers to the events of the trace.
+    pub fn subscribers(&self) -> Broadcast {
+        self.subscribers.clone()
+    }
+
+    /// Returns the flag that cancels the trace once set.
+    pub fn cancellation(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    /// Returns true once the trace was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Starts an attempt with `protocol`, stamped on its hops. With `probation`, the
+    /// attempt is abandoned if its first that many TTLs time out.
+    pub fn begin_attempt(&mut self, protocol: TraceRouteProtocol, probation: Option<u8>) {
+        self.protocol = Some(protocol);
+        self.probation = probation;
+        self.held.clear();
+        self.abandoned = false;
+        if let Some(held) = &mut self.second_pass {
+            held.clear();
+        }
+    }
+
+    /// Holds the hops from the first silent one on back until `end_second_pass`, if
+    /// the channel takes hops alone. Other channels take the hops of the second
+    /// pass as corrections.
+    pub fn hold_for_second_pass(&mut self) {
+        if let Emitter::Hops(_) = self.tx {
+            self.second_pass = Some(Vec::new());
+        }
+    }
+
+    /// Reports the hop a silent TTL answered with in the second pass, fills it in
+    /// when hops are held for it.
+    pub fn fill_in(&mut self, mut hop: HopFound) {
+        let held = match &mut self.second_pass {
+            Some(held) => held,
+            None => {
+                if let (Some(addr), Some(rtt)) = (hop.addr, hop.time) {
+                    self.report(TraceEvent::HopCorrected(HopCorrection {
+                        ttl: hop.hop_count,
+                        addr,
+                        rtt,
+                        previously: PreviousOutcome::Timeout,
+                    }));
+                }
+                return;
+            }
+        };
+        if let Some(silent) = held
+            .iter_mut()
+            .find(|held| held.hop_count == hop.hop_count && held.addr.is_none())
+        {
+            hop.is_last = false;
+            hop.completion = None;
+            hop.protocol = silent.protocol;
+            hop.beyond_destination = silent.beyond_destination;
+            *silent = hop;
+        }
+    }
+
+    /// Sends the hops held back for the second pass.
+    pub fn end_second_pass(&mut self) -> Result<(), Disconnected> {
+        let held = match &mut self.second_pass {
+            Some(held) => mem::take(held),
+            None => return Ok(()),
+        };
+        for hop in held {
+            self.deliver(hop)?;
+        }
+        Ok(())
+    }
+
+    /// Holds silent hops back until one answers if `hold` is set, the hops held so
+    /// far are dropped. Used while a trace ending in silence is run again.
+    pub fn hold_silence(&mut self, hold: bool) {
+        self.holding = hold;
+        self.held.clear();
+    }
+
+    /// Reports an event other than a hop, if the channel takes it.
+    pub fn report(&self, event: TraceEvent) {
+        if let TraceEvent::Error(e) = &event {
+            *self.failure.lock().unwrap() = Some(e.clone());
+        }
+        let _ = self.emit(event);
+    }
+
+    /// Hands an event to the channel and the subscribers, fails once none of them
+    /// listens.
+    fn emit(&self, event: TraceEvent) -> Result<(), Disconnected> {
+        let listened = self.subscribers.publish(&event);
+        match self.tx.emit(event) {
+            Err(_) if listened => Ok(()),
+            sent => sent,
+        }
+    }
+
+    /// Checks the hops sent for signs of a middlebox rewriting TTLs, reported as
+    /// warnings at the end of the trace.
+    pub fn check_path(&mut self, destination: IpAddr) {
+        self.path = Some(PathCheck::new(destination));
+    }
+
+    /// Shows the path check a hop the trace dropped without reporting it.
+    pub fn passed_over(&mut self, hop: &HopFound) {
+        if let Some(path) = &mut self.path {
+            path.hop(hop);
+        }
+    }
+
+    /// Reports the end of the trace with its final counters, after the anomalies
+    /// of its path.
+    pub fn complete(&self) {
+        if let Some(path) = &self.path {
+            for anomaly in path.anomalies() {
+                self.report(TraceEvent::Warning(TraceWarning::PathAnomaly(anomaly)));
+            }
+        }
+        self.report(TraceEvent::Completed(TraceComplete {
+            completion: self.completion,
+            metrics: self.metrics.snapshot(),
+        }));
+    }
+
+    /// Returns true if the last attempt was abandoned, its held hops were dropped.
+    pub fn abandoned(&self) -> bool {
+        self.abandoned
+    }
+
+    /// Sends a hop, fails once the receiver is gone or the attempt is abandoned.
+    pub fn send(&mut self, mut hop: HopFound) -> Result<(), Disconnected> {
+        hop.protocol = self.protocol;
+        if self.probation.is_some() || self.holding {
+            if hop.addr.is_none() && !hop.is_last {
+                self.held.push(hop);
+                if let Some(probation) = self.probation {
+                    if self.held.len() >= usize::from(probation) {
+                        self.abandoned = true;
+                        return Err(Disconnected);
+                    }
+                }
+                return Ok(());
+            }
+            self.probation = None;
+            self.holding = false;
+            for held in mem::take(&mut self.held) {
+                self.pass(held)?;
+            }
+        }
+        self.pass(hop)
+    }
+
+    /// Delivers a hop, or holds it back for the second pass.
+    fn pass(&mut self, hop: HopFound) -> Result<(), Disconnected> {
+        if let Some(held) = &mut self.second_pass {
+            if !held.is_empty() || (hop.addr.is_none() && !hop.is_last) {
+                held.push(hop);
+                return Ok(());
+            }
+        }
+        self.deliver(hop)
+    }
+
+    fn deliver(&mut self, mut hop: HopFound) -> Result<(), Disconnected> {
+        hop.trace_id = self.trace_id;
+        self.passed_over(&hop);
+        self.metrics.hop_found();
+        if hop.addr.is_some() {
+            self.metrics.reply_matched();
+            logging::reply_matched(&hop);
+        }
+        if hop.is_last {
+            self.completion = hop.completion;
+            self.metrics.finish();
+            hop.metrics = Some(self.metrics.snapshot());
+            logging::completed(&self.trace, &hop);
+        }
+        if let Some(local) = &mut self.local {
+            if is_local(&hop) && !hop.is_last {
+                local.push(hop);
+                return Ok(());
+            }
+        }
+        if let Some(local) = self.local.take() {
+            if let Some(last) = local.last() {
+                let mut placeholder = HopFound::new(last.hop_count, None, 0, false, None);
+                placeholder.local_hops = Some(local.len() as u8);
+                placeholder.trace_id = self.trace_id;
+                self.emit(TraceEvent::Hop(placeholder))?;
+            }
+        }
+        self.emit(TraceEvent::Hop(hop))
+    }
+}
+
+impl Drop for HopSink {
+    fn drop(&mut self) {
+        self.subscribers.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use std::sync::mpsc::channel;
+
+    fn path(addrs: &[&str]) -> Vec<HopFound> {
+        addrs
+            .iter()
+            .enumerate()
+            .map(|(n, addr)| {
+                let is_last = n + 1 == addrs.len();
+                let addr = addr.parse::<IpAddr>().ok();
+                HopFound::new(n as u8 + 1, addr, 0, is_last, None)
+            })
+            .collect()
+    }
+
+    fn deliver(hops: Vec<HopFound>, hide_local_hops: bool) -> Vec<HopFound> {
+        let (tx, rx) = channel();
+        let mut sink = HopSink::new(
+            Emitter::Hops(tx),
+            hide_local_hops,
+            Arc::new(Metrics::new()),
+            7,
+        );
+        for hop in hops {
+            sink.send(hop).unwrap();
+        }
+        drop(sink);
+        rx.iter()
+            .map(|mut hop| {
+                assert_eq!(hop.metrics.is_some(), hop.is_last);
+                assert_eq!(hop.trace_id, 7);
+                hop.metrics = None;
+                hop.trace_id = 0;
+                hop
+            })
+            .collect()
+    }
+
+    #[test]
+    fn silent_attempts_are_abandoned() {
+        let (tx, rx) = channel();
+        let mut sink = HopSink::new(Emitter::Hops(tx), false, Arc::new(Metrics::new()), 7);
+        sink.begin_attempt(TraceRouteProtocol::Udp, Some(2));
+        let mut hops = path(&["*", "*", "*"]).into_iter();
+        assert!(sink.send(hops.next().unwrap()).is_ok());
+        assert!(sink.send(hops.next().unwrap()).is_err());
+        assert!(sink.abandoned());
+
+        sink.begin_attempt(TraceRouteProtocol::Icmp, Some(2));
+        for hop in path(&["*", "8.8.4.4", "8.8.8.8"]) {
+            sink.send(hop).unwrap();
+        }
+        assert!(!sink.abandoned());
+        drop(sink);
+        let hops: Vec<HopFound> = rx.iter().collect();
+        assert_eq!(hops.len(), 3);
+        assert_eq!(hops[0].hop_count, 1);
+        assert_eq!(hops[0].addr, None);
+        assert!(hops
+            .iter()
+            .all(|hop| hop.protocol == Some(TraceRouteProtocol::Icmp)));
+    }
+
+    #[test]
+    fn one_private_hop_is_collapsed() {
+        let hops = deliver(path(&["192.168.1.1", "8.8.4.4", "8.8.8.8"]), true);
+        assert_eq!(hops.len(), 3);
+        assert_eq!(hops[0].local_hops, Some(1));
+        assert_eq!(hops[0].hop_count, 1);
+        assert_eq!(hops[0].addr, None);
+        assert_eq!(hops[1].hop_count, 2);
+        assert_eq!(hops[2].hop_count, 3);
+    }
+
+    #[test]
+    fn three_private_hops_are_collapsed() {
+        let hops = deliver(
+            path(&[
+                "192.168.1.1",
+                "10.0.0.1",
+                "100.64.0.1",
+                "8.8.4.4",
+                "8.8.8.8",
+            ]),
+            true,
+        );
+        assert_eq!(hops.len(), 3);
+        assert_eq!(hops[0].local_hops, Some(3));
+        assert_eq!(hops[0].hop_count, 3);
+        assert_eq!(hops[1].hop_count, 4);
+        assert_eq!(hops[1].addr, "8.8.4.4".parse().ok());
+    }
+
+    #[test]
+    fn paths_without_private_hops_are_unchanged() {
+        let original = path(&["8.8.4.4", "10.0.0.1", "8.8.8.8"]);
+        assert_eq!(deliver(original.clone(), true), original);
+        let original = path(&["192.168.1.1", "8.8.8.8"]);
+        assert_eq!(deliver(original.clone(), false), original);
+    }
+
+    #[test]
+    fn timeouts_and_local_destinations_end_the_segment() {
+        let hops = deliver(path(&["10.0.0.1", "*", "10.0.0.2"]), true);
+        assert_eq!(hops.len(), 3);
+        assert_eq!(hops[0].local_hops, Some(1));
+        assert_eq!(hops[1].addr, None);
+        assert_eq!(hops[2].addr, "10.0.0.2".parse().ok());
+
+        let hops = deliver(path(&["10.0.0.1", "10.0.0.2"]), true);
+        assert_eq!(hops.len(), 2);
+        assert!(hops[1].is_last);
+    }
+}
@@ -0,0 +1,562 @@
+//! Per hop statistics over repeated traces of one destination.
+use crate::{HopCorrection, HopFound};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Weight of a new difference in the jitter estimate, 1/16 as in RFC 3550.
+const JITTER_GAIN: f64 = 1.0 / 16.0;
+
+/// Round trip times kept per TTL for its percentiles.
+const RESERVOIR_SIZE: usize = 512;
+
+/// Round trip times a TTL needs before it reports percentiles.
+const MIN_PERCENTILE_SAMPLES: u64 = 10;
+
+/// This enum labels a TTL by how its probes were answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum HopResponse {
+    /// Answers most probes, or answers at irregular intervals, like a router losing
+    /// some of them. The share it lost is told by `HopStats::loss`.
+    Responsive,
+    /// Answers few probes at regular intervals, like a router limiting the rate of
+    /// its ICMP messages. `answered` of the `probed` probes were answered, see
+    /// `observed_ratio`.
+    RateLimited { answered: u64, probed: u64 },
+    /// Never answers.
+    Silent,
+}
+
+impl HopResponse {
+    /// Returns the share of probes a rate limited TTL answered, None for the other
+    /// labels.
+    pub fn observed_ratio(&self) -> Option<f64> {
+        match *self {
+            HopResponse::RateLimited { answered, probed } => Some(answered as f64 / probed as f64),
+            _ => None,
+        }
+    }
+}
+
+/// This struct tells how TTLs are labeled by `HopResponse`.
+///
+/// Probes to a TTL are taken to be evenly spaced, so a router answering one
+/// probe per interval answers after the same number of probes every time, while
+/// one losing probes at random answers after runs of very different lengths.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct ResponseClassifier {
+    /// Probes a TTL needs before it is labeled.
+    pub min_probes: u64,
+    /// Share of answered probes from which a TTL is responsive.
+    pub responsive_ratio: f64,
+    /// Gaps between answers needed to tell whether they are regular. With fewer,
+    /// a TTL answering below `responsive_ratio` is taken as rate limited.
+    pub min_gaps: u64,
+    /// Coefficient of variation of the gaps between answers, in probes, above
+    /// which they are irregular.
+    pub max_gap_variation: f64,
+}
+
+impl Default for ResponseClassifier {
+    fn default() -> ResponseClassifier {
+        ResponseClassifier {
+            min_probes: 10,
+            responsive_ratio: 0.9,
+            min_gaps: 4,
+            max_gap_variation: 0.5,
+        }
+    }
+}
+
+impl ResponseClassifier {
+    /// Labels a TTL by whether each of its probes was answered, in the order they
+    /// were sent. None with fewer than `min_probes` probes.
+    pub fn classify(&self, answered: &[bool]) -> Option<HopResponse> {
+        let mut responses = Responses::default();
+        for &answered in answered {
+            responses.add(answered);
+        }
+        responses.classify(self)
+    }
+}
+
+/// Counts of the probes of one TTL and of the gaps between their answers.
+#[derive(Debug, Clone, Default)]
+struct Responses {
+    probes: u64,
+    answers: u64,
+    /// Probes since the last answer.
+    run: u64,
+    gaps: u64,
+    gap_mean: f64,
+    gap_squares: f64,
+}
+
+impl Responses {
+    fn add(&mut self, answered: bool) {
+        self.probes += 1;
+        self.run += 1;
+        if !answered {
+            return;
+        }
+        if self.answers > 0 {
+            let gap = self.run as f64;
+            self.gaps += 1;
+            let delta = gap - self.gap_mean;
+            self.gap_mean += delta / self.gaps as f64;
+            self.gap_squares += delta * (gap - self.gap_mean);
+        }
+        self.answers += 1;
+        self.run = 0;
+    }
+
+    /// Turns the last unanswered probe into an answered one.
+    fn amend(&mut self) {
+        if self.run == 0 {
+            return;
+        }
+        self.probes -= 1;
+        self.run -= 1;
+        self.add(true);
+    }
+
+    fn classify(&self, classifier: &ResponseClassifier) -> Option<HopResponse> {
+        if self.probes < classifier.min_probes.max(1) {
+            return None;
+        }
+        if self.answers == 0 {
+            return Some(HopResponse::Silent);
+        }
+        if self.answers as f64 / self.probes as f64 >= classifier.responsive_ratio {
+            return Some(HopResponse::Responsive);
+        }
+        if self.gaps >= classifier.min_gaps.max(1) {
+            let variation = (self.gap_squares / self.gaps as f64).sqrt() / self.gap_mean;
+            if variation > classifier.max_gap_variation {
+                return Some(HopResponse::Responsive);
+            }
+        }
+        Some(HopResponse::RateLimited {
+            answered: self.answers,
+            probed: self.probes,
+        })
+    }
+}
+
+/// This struct summarizes the probes of one TTL over all rounds so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct HopStats {
+    pub ttl: u8,
+    /// Address that answered last at this TTL.
+    pub addr: Option<IpAddr>,
+    /// Rounds that probed this TTL.
+    pub sent: u64,
+    /// Rounds in which this TTL answered.
+    pub received: u64,
+    pub last: Option<Duration>,
+    pub best: Option<Duration>,
+    pub worst: Option<Duration>,
+    pub mean: Option<Duration>,
+    /// Standard deviation of the round trip times.
+    pub std_dev: Option<Duration>,
+    /// Interarrival jitter of RFC 3550, the smoothed mean of the differences between
+    /// consecutive round trip times. Timeouts are skipped, not counted as a difference.
+    pub jitter: Option<Duration>,
+    /// Median round trip time, see `PathStats::percentile`.
+    pub p50: Option<Duration>,
+    pub p95: Option<Duration>,
+    pub p99: Option<Duration>,
+    /// How the TTL answers its probes, None until it was probed enough, see
+    /// `ResponseClassifier`.
+    pub response: Option<HopResponse>,
+}
+
+impl HopStats {
+    /// Returns the share of rounds this TTL did not answer, from 0 to 1.
+    pub fn loss(&self) -> f64 {
+        match self.sent {
+            0 => 0.0,
+            sent => (sent - self.received) as f64 / sent as f64,
+        }
+    }
+}
+
+/// Running sums of one TTL, in seconds.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Samples {
+    addr: Option<IpAddr>,
+    sent: u64,
+    received: u64,
+    last: Option<f64>,
+    best: f64,
+    worst: f64,
+    mean: f64,
+    squares: f64,
+    jitter: f64,
+    /// Sample of the round trip times, at most `RESERVOIR_SIZE` of them.
+    reservoir: Vec<f64>,
+    /// Every probe sent, one per try.
+    responses: Responses,
+}
+
+impl Samples {
+    pub(crate) fn add(&mut self, hop: &HopFound) {
+        self.sent += 1;
+        for _ in 0..hop.tries {
+            self.responses.add(false);
+        }
+        if hop.addr.is_some() {
+            self.responses.add(true);
+        }
+        let rtt = match (hop.addr, hop.time) {
+            (Some(addr), Some(time)) => {
+                self.addr = Some(addr);
+                time.as_secs_f64()
+            }
+            (Some(addr), None) => {
+                self.addr = Some(addr);
+                return;
+            }
+            _ => return,
+        };
+        self.answered(rtt);
+    }
+
+    /// Counts a late answer to a round counted as unanswered, returns false if
+    /// every round was answered.
+    pub(crate) fn correct(&mut self, correction: &HopCorrection) -> bool {
+        if self.received >= self.sent {
+            return false;
+        }
+        self.responses.amend();
+        self.addr = Some(correction.addr);
+        self.answered(correction.rtt.as_secs_f64());
+        true
+    }
+
+    fn answered(&mut self, rtt: f64) {
+        self.received += 1;
+        if let Some(last) = self.last {
+            self.jitter += ((rtt - last).abs() - self.jitter) * JITTER_GAIN;
+            self.best = self.best.min(rtt);
+            self.worst = self.worst.max(rtt);
+        } else {
+            self.best = rtt;
+            self.worst = rtt;
+        }
+        // Welford's update keeps the variance stable over long runs.
+        let delta = rtt - self.mean;
+        self.mean += delta / self.received as f64;
+        self.squares += delta * (rtt - self.mean);
+        self.last = Some(rtt);
+        // Algorithm R, with the slot picked by a hash of the count instead of a
+        // random number so the same times always give the same percentiles.
+        let seen = self.received - 1;
+        if self.reservoir.len() < RESERVOIR_SIZE {
+            self.reservoir.push(rtt);
+        } else {
+            let slot = splitmix64(seen) % (seen + 1);
+            if slot < RESERVOIR_SIZE as u64 {
+                self.reservoir[slot as usize] = rtt;
+            }
+        }
+    }
+
+    /// Returns the round trip time below which the share `quantile` of them fall,
+    /// by nearest rank, None with fewer than `MIN_PERCENTILE_SAMPLES` of them.
+    pub(crate) fn percentile(&self, quantile: f64) -> Option<Duration> {
+        if self.received < MIN_PERCENTILE_SAMPLES || !(0.0..=1.0).contains(&quantile) {
+            return None;
+        }
+        let mut sorted = self.reservoir.clone();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let rank = (quantile * sorted.len() as f64).ceil() as usize;
+        let rtt = sorted[rank.clamp(1, sorted.len()) - 1];
+        Some(Duration::from_secs_f64(rtt.max(0.0)))
+    }
+
+    pub(crate) fn stats(&self, ttl: u8, classifier: &ResponseClassifier) -> HopStats {
+        let answered = |value: f64| self.last.map(|_| Duration::from_secs_f64(value.max(0.0)));
+        HopStats {
+            ttl,
+            addr: self.addr,
+            sent: self.sent,
+            received: self.received,
+            last: self.last.map(Duration::from_secs_f64),
+            best: answered(self.best),
+            worst: answered(self.worst),
+            mean: answered(self.mean),
+            std_dev: answered((self.squares / self.received.max(1) as f64).sqrt()),
+            jitter: answered(self.jitter),
+            p50: self.percentile(0.5),
+            p95: self.percentile(0.95),
+            p99: self.percentile(0.99),
+            response: self.responses.classify(classifier),
+        }
+    }
+}
+
+/// Mixes the bits of `x`, the finalizer of SplitMix64.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// This struct collects the hops of repeated traces of one destination, the way
+/// mtr does.
+#[derive(Debug, Clone, Default)]
+pub struct PathStats {
+    rounds: u64,
+    hops: BTreeMap<u8, Samples>,
+    classifier: ResponseClassifier,
+}
+
+impl PathStats {
+    /// Creates new empty PathStats.
+    pub fn new() -> PathStats {
+        PathStats::default()
+    }
+
+    /// Labels the TTLs with `classifier` instead of the default one.
+    pub fn with_classifier(mut self, classifier: ResponseClassifier) -> PathStats {
+        self.classifier = classifier;
+        self
+    }
+
+    /// Adds the hops of one trace and returns the statistics after it.
+    ///
+    /// Terminal hops without an address only mark the end of a trace and placeholders
+    /// of hidden local hops were never probed, neither is counted.
+    pub fn record_round(&mut self, hops: &[HopFound]) -> Vec<HopStats> {
+        self.rounds += 1;
+        for hop in hops {
+            if hop.is_end_of_trace() || hop.local_hops.is_some() {
+                continue;
+            }
+            self.hops.entry(hop.hop_count).or_default().add(hop);
+        }
+        self.snapshot()
+    }
+
+    /// Counts a late answer to a TTL that went unanswered in a round, as reported
+    /// by `TraceEvent::HopCorrected`. Returns false if the TTL was not probed or
+    /// has no unanswered round left.
+    pub fn correct(&mut self, correction: &HopCorrection) -> bool {
+        match self.hops.get_mut(&correction.ttl) {
+            Some(samples) => samples.correct(correction),
+            None => false,
+        }
+    }
+
+    /// Returns the number of rounds recorded.
+    pub fn rounds(&self) -> u64 {
+        self.rounds
+    }
+
+    /// Returns the round trip time of `ttl` below which the share `quantile` of
+    /// them fall, e.g. 0.9 for p90.
+    ///
+    /// None for a TTL with fewer than 10 answers or a quantile outside 0 to 1. Up
+    /// to 512 answers the percentile is exact, past them it is taken from a uniform
+    /// sample of 512, whose rank is typically within `sqrt(q * (1 - q) / 512)` of
+    /// the quantile `q`: about 2% at the median and 0.5% at p99.
+    pub fn percentile(&self, ttl: u8, quantile: f64) -> Option<Duration> {
+        self.hops.get(&ttl)?.percentile(quantile)
+    }
+
+    /// Returns the statistics of every TTL probed so far, by TTL.
+    pub fn snapshot(&self) -> Vec<HopStats> {
+        self.hops
+            .iter()
+            .map(|(&ttl, samples)| samples.stats(ttl, &self.classifier))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompletionReason;
+
+    fn hop(ttl: u8, millis: Option<u64>) -> HopFound {
+        HopFound::new(
+            ttl,
+            millis.map(|_| IpAddr::from([192, 0, 2, ttl])),
+            1,
+            false,
+            millis.map(Duration::from_millis),
+        )
+    }
+
+    fn close(value: Option<Duration>, millis: f64) -> bool {
+        close_within(value, millis, 1e-6)
+    }
+
+    fn close_within(value: Option<Duration>, millis: f64, bound: f64) -> bool {
+        value.is_some_and(|value| (value.as_secs_f64() * 1000.0 - millis).abs() < bound)
+    }
+
+    #[test]
+    fn jitter_follows_rfc_3550() {
+        let mut stats = PathStats::new();
+        for &rtt in &[Some(10), Some(20), None, Some(15), Some(30)] {
+            stats.record_round(&[hop(1, rtt)]);
+        }
+        let hop = &stats.snapshot()[0];
+        assert_eq!((hop.sent, hop.received), (5, 4));
+        assert!((hop.loss() - 0.2).abs() < 1e-9);
+        // J = J + (|D| - J) / 16 over the differences 10, 5 and 15 ms.
+        assert!(close(hop.jitter, 1.77978515625));
+        assert!(close(hop.std_dev, 54.6875f64.sqrt()));
+        assert!(close(hop.mean, 18.75));
+        assert_eq!(hop.best, Some(Duration::from_millis(10)));
+        assert_eq!(hop.worst, Some(Duration::from_millis(30)));
+        assert_eq!(hop.last, Some(Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn silent_hops_have_no_times() {
+        let mut stats = PathStats::new();
+        let end = HopFound::end_of_trace(3, 1, CompletionReason::NotReached);
+        let snapshot = stats.record_round(&[hop(1, Some(4)), hop(2, None), end]);
+        assert_eq!(snapshot.len(), 2);
+        assert!(close(snapshot[0].jitter, 0.0));
+        assert_eq!(snapshot[1].sent, 1);
+        assert_eq!(snapshot[1].jitter, None);
+        assert_eq!(snapshot[1].std_dev, None);
+        assert_eq!(stats.rounds(), 1);
+    }
+
+    #[test]
+    fn percentiles_are_exact_within_the_reservoir() {
+        let mut stats = PathStats::new();
+        for millis in (1..=100).rev() {
+            stats.record_round(&[hop(1, Some(millis)), hop(2, None)]);
+        }
+        let hop = &stats.snapshot()[0];
+        assert!(close(hop.p50, 50.0));
+        assert!(close(hop.p95, 95.0));
+        assert!(close(hop.p99, 99.0));
+        assert!(close(stats.percentile(1, 0.0), 1.0));
+        assert!(close(stats.percentile(1, 1.0), 100.0));
+        assert_eq!(stats.percentile(1, 1.5), None);
+        assert_eq!(stats.percentile(2, 0.5), None);
+        assert_eq!(stats.percentile(3, 0.5), None);
+    }
+
+    #[test]
+    fn percentiles_need_a_few_samples() {
+        let mut stats = PathStats::new();
+        for _ in 1..MIN_PERCENTILE_SAMPLES {
+            stats.record_round(&[hop(1, Some(7))]);
+        }
+        assert_eq!(stats.snapshot()[0].p50, None);
+        stats.record_round(&[hop(1, Some(7))]);
+        assert!(close(stats.snapshot()[0].p50, 7.0));
+    }
+
+    #[test]
+    fn percentiles_past_the_reservoir_stay_within_bounds() {
+        let mut stats = PathStats::new();
+        for round in 0..20_000 {
+            stats.record_round(&[hop(1, Some(round % 1000))]);
+        }
+        let hop = &stats.snapshot()[0];
+        // Three times the rank error of `RESERVOIR_SIZE` samples, in milliseconds
+        // of the uniform 0 to 999 ms times.
+        for &(value, quantile, bound) in &[
+            (hop.p50, 0.5, 66.0),
+            (hop.p95, 0.95, 29.0),
+            (hop.p99, 0.99, 13.0),
+        ] {
+            assert!(close_within(value, quantile * 1000.0, bound));
+        }
+    }
+
+    /// Returns `cycles` repetitions of `gaps`, every gap unanswered probes ending
+    /// with an answered one.
+    fn pattern(gaps: &[usize], cycles: usize) -> Vec<bool> {
+        let cycle: Vec<bool> = gaps
+            .iter()
+            .flat_map(|&gap| (1..=gap).map(move |probe| probe == gap))
+            .collect();
+        cycle.repeat(cycles)
+    }
+
+    #[test]
+    fn responses_are_classified_by_ratio_and_regularity() {
+        let classifier = ResponseClassifier::default();
+        assert_eq!(classifier.classify(&[true; 9]), None);
+        assert_eq!(
+            classifier.classify(&[true; 20]),
+            Some(HopResponse::Responsive)
+        );
+        assert_eq!(classifier.classify(&[false; 20]), Some(HopResponse::Silent));
+        // One answer in five, like a limiter refilling every fifth probe.
+        let limited = classifier.classify(&pattern(&[5], 6)).unwrap();
+        assert_eq!(
+            limited,
+            HopResponse::RateLimited {
+                answered: 6,
+                probed: 30
+            }
+        );
+        assert_eq!(limited.observed_ratio(), Some(0.2));
+        assert_eq!(HopResponse::Responsive.observed_ratio(), None);
+        let jittered = classifier.classify(&pattern(&[4, 5, 6, 5], 2));
+        assert!(matches!(jittered, Some(HopResponse::RateLimited { .. })));
+        // As many answers, lost at random.
+        let lossy = classifier.classify(&pattern(&[1, 9, 2, 1, 12, 3, 1, 7], 1));
+        assert_eq!(lossy, Some(HopResponse::Responsive));
+        // Too few answers to tell, taken as limiting.
+        let sparse = classifier.classify(&pattern(&[1, 12, 7], 1));
+        assert!(matches!(sparse, Some(HopResponse::RateLimited { .. })));
+
+        let strict = ResponseClassifier {
+            max_gap_variation: 0.1,
+            ..classifier
+        };
+        assert_eq!(
+            strict.classify(&pattern(&[4, 5, 6, 5], 2)),
+            Some(HopResponse::Responsive)
+        );
+    }
+
+    #[test]
+    fn rounds_count_every_try() {
+        let mut stats = PathStats::new();
+        let addr = |ttl| Some(IpAddr::from([192, 0, 2, ttl]));
+        for round in 0..12 {
+            // The second TTL answers the last of nine probes over three rounds.
+            let second = match round % 3 {
+                2 => HopFound::new(2, addr(2), 2, false, None),
+                _ => HopFound::timed_out(2, 3),
+            };
+            let first = HopFound::new(1, addr(1), 0, false, Some(Duration::from_millis(5)));
+            stats.record_round(&[first, second]);
+        }
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot[0].response, Some(HopResponse::Responsive));
+        assert_eq!(
+            snapshot[1].response,
+            Some(HopResponse::RateLimited {
+                answered: 4,
+                probed: 36
+            })
+        );
+
+        let lenient = ResponseClassifier {
+            responsive_ratio: 0.1,
+            ..ResponseClassifier::default()
+        };
+        let snapshot = stats.with_classifier(lenient).snapshot();
+        assert_eq!(snapshot[1].response, Some(HopResponse::Responsive));
+    }
+}
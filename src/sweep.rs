@@ -0,0 +1,456 @@
+//! Comparison of the paths traced with several DSCP values, protocols, probe
+//! sizes, extension headers or fragmented probes.
+use crate::{CompletionReason, ExtensionHeader, HopFound, TraceRouteProtocol};
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+
+/// This struct lists what every class found at one TTL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SweepHop {
+    pub ttl: u8,
+    /// Hop of every trace, in the order of `DscpSweep::classes` or
+    /// `ProtocolComparison::protocols`. `None` when that trace ended before this TTL.
+    pub hops: Vec<Option<HopFound>>,
+}
+
+impl SweepHop {
+    /// Returns the addresses that answered, in class order, `None` for silence.
+    pub fn addrs(&self) -> Vec<Option<IpAddr>> {
+        self.hops
+            .iter()
+            .map(|hop| hop.as_ref().and_then(|hop| hop.addr))
+            .collect()
+    }
+
+    /// Returns true if classes were answered from different addresses.
+    pub fn is_divergent(&self) -> bool {
+        let answered: BTreeSet<IpAddr> = self.addrs().into_iter().flatten().collect();
+        answered.len() > 1
+    }
+
+    /// Returns true if a class timed out where another one was answered.
+    pub fn is_partially_silent(&self) -> bool {
+        let silent = self
+            .hops
+            .iter()
+            .flatten()
+            .any(|hop| hop.addr.is_none() && hop.local_hops.is_none());
+        silent && self.hops.iter().flatten().any(|hop| hop.addr.is_some())
+    }
+}
+
+/// This struct compares the traces of one destination with several DSCP values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DscpSweep {
+    /// DSCP values, in the order they were traced.
+    pub classes: Vec<u8>,
+    /// One row per probed TTL.
+    pub hops: Vec<SweepHop>,
+    /// First TTL answered from different addresses, silence does not count.
+    pub first_divergent_ttl: Option<u8>,
+}
+
+impl DscpSweep {
+    /// Lines up the hops of `traces`, traced with `classes` in the same order.
+    pub(crate) fn compare(classes: &[u8], traces: Vec<Vec<HopFound>>) -> DscpSweep {
+        let hops = line_up(&traces);
+        DscpSweep {
+            classes: classes.to_vec(),
+            first_divergent_ttl: first_divergent_ttl(&hops),
+            hops,
+        }
+    }
+}
+
+/// This struct compares the traces of one destination with several protocols.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ProtocolComparison {
+    /// Protocols, in the order they were given.
+    pub protocols: Vec<TraceRouteProtocol>,
+    /// One row per probed TTL.
+    pub hops: Vec<SweepHop>,
+    /// First TTL answered from different addresses, silence does not count.
+    pub first_divergent_ttl: Option<u8>,
+    /// TTL at which every protocol reached the destination, in protocol order,
+    /// `None` for those that did not.
+    pub reached: Vec<Option<u8>>,
+}
+
+impl ProtocolComparison {
+    /// Lines up the hops of `traces`, traced with `protocols` in the same order.
+    pub(crate) fn compare(
+        protocols: &[TraceRouteProtocol],
+        traces: Vec<Vec<HopFound>>,
+    ) -> ProtocolComparison {
+        let hops = line_up(&traces);
+        let reached = traces
+            .iter()
+            .map(|trace| {
+                trace
+                    .iter()
+                    .find(|hop| {
+                        matches!(
+                            hop.completion,
+                            Some(CompletionReason::Reached)
+                                | Some(CompletionReason::ReachedButFiltered)
+                        )
+                    })
+                    .map(|hop| hop.hop_count)
+            })
+            .collect();
+        ProtocolComparison {
+            protocols: protocols.to_vec(),
+            first_divergent_ttl: first_divergent_ttl(&hops),
+            hops,
+            reached,
+        }
+    }
+
+    /// Returns the protocols that reached the destination, in protocol order.
+    pub fn reached_by(&self) -> Vec<TraceRouteProtocol> {
+        self.protocols
+            .iter()
+            .zip(&self.reached)
+            .filter(|(_, reached)| reached.is_some())
+            .map(|(protocol, _)| *protocol)
+            .collect()
+    }
+}
+
+/// This struct compares the trace of an IPv6 destination with plain probes to the
+/// one with probes carrying an extension header, see
+/// `TraceRoute::run_extension_header_comparison`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ExtensionHeaderComparison {
+    pub extension_header: ExtensionHeader,
+    /// One row per probed TTL, with the hop of the plain trace first.
+    pub hops: Vec<SweepHop>,
+    /// First TTL answering plain probes but none with the extension header, from
+    /// which on probes with the extension header went unanswered. The hop at this
+    /// TTL or the one before drops packets with extension headers.
+    pub first_dropped_ttl: Option<u8>,
+}
+
+impl ExtensionHeaderComparison {
+    /// Lines up the hops of the `plain` trace and the `extended` one.
+    pub(crate) fn compare(
+        extension_header: ExtensionHeader,
+        plain: Vec<HopFound>,
+        extended: Vec<HopFound>,
+    ) -> ExtensionHeaderComparison {
+        let hops = line_up(&[plain, extended]);
+        ExtensionHeaderComparison {
+            extension_header,
+            first_dropped_ttl: first_dropped_ttl(&hops),
+            hops,
+        }
+    }
+
+    /// Returns the addresses that answered plain probes at the TTL before the first
+    /// dropped one and at that TTL, those of the hops likely dropping the probes.
+    pub fn suspects(&self) -> Vec<IpAddr> {
+        suspects(&self.hops, self.first_dropped_ttl)
+    }
+}
+
+/// This struct compares the trace of a destination with whole probes to the one
+/// with probes sent in two fragments, see `TraceRoute::run_fragment_comparison`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FragmentComparison {
+    /// One row per probed TTL, with the hop of the trace with whole probes first.
+    pub hops: Vec<SweepHop>,
+    /// First TTL answering whole probes but no fragmented ones, from which on
+    /// fragmented probes went unanswered. The hop at this TTL or the one before
+    /// drops fragments.
+    pub first_dropped_ttl: Option<u8>,
+}
+
+impl FragmentComparison {
+    /// Lines up the hops of the `whole` trace and the `fragmented` one.
+    pub(crate) fn compare(whole: Vec<HopFound>, fragmented: Vec<HopFound>) -> FragmentComparison {
+        let hops = line_up(&[whole, fragmented]);
+        FragmentComparison {
+            first_dropped_ttl: first_dropped_ttl(&hops),
+            hops,
+        }
+    }
+
+    /// Returns the addresses that answered whole probes at the TTL before the
+    /// first dropped one and at that TTL, those of the hops likely dropping the
+    /// fragments.
+    pub fn suspects(&self) -> Vec<IpAddr> {
+        suspects(&self.hops, self.first_dropped_ttl)
+    }
+}
+
+/// Returns the first TTL of two lined up traces the first one got an answer at and
+/// the second one got none at, nor at any later TTL.
+fn first_dropped_ttl(hops: &[SweepHop]) -> Option<u8> {
+    let answered = |row: &SweepHop, trace: usize| row.addrs()[trace].is_some();
+    hops.iter()
+        .enumerate()
+        .find(|(n, row)| answered(row, 0) && hops[*n..].iter().all(|later| !answered(later, 1)))
+        .map(|(_, row)| row.ttl)
+}
+
+/// Returns the addresses of the first trace at the TTL before `dropped` and at it.
+fn suspects(hops: &[SweepHop], dropped: Option<u8>) -> Vec<IpAddr> {
+    let dropped = match dropped {
+        Some(ttl) => ttl,
+        None => return Vec::new(),
+    };
+    hops.iter()
+        .filter(|row| row.ttl + 1 == dropped || row.ttl == dropped)
+        .filter_map(|row| row.addrs()[0])
+        .collect()
+}
+
+/// Returns one row per TTL any of `traces` probed, with the hop of every trace.
+///
+/// Terminal hops without an address only mark the end of a trace and get no row.
+fn line_up(traces: &[Vec<HopFound>]) -> Vec<SweepHop> {
+    let probed = |hop: &HopFound| !hop.is_end_of_trace();
+    let ttls: BTreeSet<u8> = traces
+        .iter()
+        .flatten()
+        .filter(|hop| probed(hop))
+        .map(|hop| hop.hop_count)
+        .collect();
+    ttls.into_iter()
+        .map(|ttl| SweepHop {
+            ttl,
+            hops: traces
+                .iter()
+                .map(|trace| {
+                    trace
+                        .iter()
+                        .find(|hop| hop.hop_count == ttl && probed(hop))
+                        .cloned()
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+fn first_divergent_ttl(hops: &[SweepHop]) -> Option<u8> {
+    hops.iter()
+        .find(|row| row.is_divergent())
+        .map(|row| row.ttl)
+}
+
+/// This struct tells how large probes one TTL answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HopMtu {
+    pub ttl: u8,
+    /// Address that answered the largest probe.
+    pub addr: Option<IpAddr>,
+    /// Largest probe answered, IP header included.
+    pub largest: Option<u16>,
+    /// Smallest probe that went unanswered or drew a fragmentation needed, None
+    /// if the TTL answered every size.
+    pub failed_at: Option<u16>,
+    /// Next hop MTU told by the fragmentation needed, or packet too big, that
+    /// probe drew.
+    pub next_hop_mtu: Option<u16>,
+}
+
+impl HopMtu {
+    pub(crate) fn new(ttl: u8) -> HopMtu {
+        HopMtu {
+            ttl,
+            addr: None,
+            largest: None,
+            failed_at: None,
+            next_hop_mtu: None,
+        }
+    }
+
+    /// Returns the hop of the TTL that answered, None if it stayed silent.
+    fn reply<'h>(&self, hops: &'h [HopFound]) -> Option<&'h HopFound> {
+        hops.iter()
+            .find(|hop| hop.hop_count == self.ttl && hop.addr.is_some())
+    }
+
+    /// Notes what a trace of probes of `size` bytes found at the TTL, returns
+    /// false once it no longer answers.
+    pub(crate) fn probed(&mut self, size: u16, hops: &[HopFound]) -> bool {
+        match self.reply(hops) {
+            Some(hop) if hop.next_hop_mtu.is_none() => {
+                self.addr = hop.addr;
+                self.largest = Some(size);
+                true
+            }
+            reply => {
+                self.failed_at = Some(size);
+                self.next_hop_mtu = reply.and_then(|hop| hop.next_hop_mtu);
+                false
+            }
+        }
+    }
+
+    /// Returns the next hop MTU told if it lies between the largest probe
+    /// answered and the one refused, the one size still worth a try.
+    pub(crate) fn untried_mtu(&self) -> Option<u16> {
+        let mtu = self.next_hop_mtu?;
+        if mtu < self.failed_at? && Some(mtu) > self.largest {
+            Some(mtu)
+        } else {
+            None
+        }
+    }
+
+    /// Notes what a trace of probes of the told MTU found at the TTL.
+    pub(crate) fn confirmed(&mut self, mtu: u16, hops: &[HopFound]) {
+        if let Some(hop) = self.reply(hops).filter(|hop| hop.next_hop_mtu.is_none()) {
+            self.addr = hop.addr;
+            self.largest = Some(mtu);
+        }
+    }
+}
+
+/// This struct holds the largest probes every TTL answered, see
+/// `TraceRoute::run_mtu_sweep`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MtuSweep {
+    /// Probe sizes tried, IP header included, smallest first.
+    pub sizes: Vec<u16>,
+    /// One row per probed TTL.
+    pub hops: Vec<HopMtu>,
+}
+
+impl MtuSweep {
+    /// Returns the largest probe every TTL answered, None for TTLs that answered
+    /// none.
+    pub fn per_hop_mtu(&self) -> Vec<(u8, Option<u16>)> {
+        self.hops.iter().map(|hop| (hop.ttl, hop.largest)).collect()
+    }
+
+    /// Returns the first TTL that answered smaller probes than the TTLs before
+    /// it, the hop behind the link clamping the MTU. Silent TTLs are skipped.
+    pub fn clamping_ttl(&self) -> Option<u8> {
+        let mut mtu = *self.sizes.last()?;
+        for hop in &self.hops {
+            match hop.largest {
+                Some(largest) if largest < mtu => return Some(hop.ttl),
+                Some(largest) => mtu = largest,
+                None => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scripted_trace, test_net_v4};
+
+    #[test]
+    fn rows_line_up_by_ttl() {
+        let sweep = DscpSweep::compare(
+            &[0, 46],
+            vec![
+                scripted_trace(&[Some(1), Some(2), Some(100)], None),
+                scripted_trace(&[Some(1), Some(5), None, Some(100)], None),
+            ],
+        );
+        assert_eq!(sweep.classes, vec![0, 46]);
+        assert_eq!(sweep.hops.len(), 4);
+        assert_eq!(sweep.first_divergent_ttl, Some(2));
+        assert!(!sweep.hops[0].is_divergent());
+        assert_eq!(sweep.hops[2].addrs(), vec![Some(test_net_v4(100)), None]);
+        assert!(sweep.hops[2].is_partially_silent());
+        assert!(sweep.hops[3].hops[0].is_none());
+        assert!(!sweep.hops[3].is_partially_silent());
+    }
+
+    #[test]
+    fn unreached_ends_get_no_row() {
+        let sweep = DscpSweep::compare(
+            &[0, 10],
+            vec![
+                scripted_trace(&[Some(1), None, None], None),
+                scripted_trace(&[Some(1), None, None], None),
+            ],
+        );
+        assert_eq!(sweep.hops.len(), 2);
+        assert_eq!(sweep.first_divergent_ttl, None);
+        assert!(!sweep.hops[1].is_partially_silent());
+    }
+
+    #[test]
+    fn protocols_of_different_lengths_line_up() {
+        let protocols = [TraceRouteProtocol::Icmp, TraceRouteProtocol::Udp];
+        let mut filtered = scripted_trace(&[Some(1), None], None);
+        filtered[1].completion = Some(CompletionReason::ReachedButFiltered);
+        let comparison = ProtocolComparison::compare(
+            &protocols,
+            vec![
+                scripted_trace(&[Some(1), Some(2), Some(100)], None),
+                filtered,
+            ],
+        );
+        assert_eq!(comparison.hops.len(), 3);
+        assert_eq!(comparison.first_divergent_ttl, None);
+        assert_eq!(comparison.reached, vec![Some(3), Some(2)]);
+        assert!(comparison.hops[1].hops[1].is_none());
+
+        let unreached = ProtocolComparison::compare(
+            &protocols,
+            vec![
+                scripted_trace(&[Some(1), Some(100)], None),
+                scripted_trace(&[Some(1), None, None], None),
+            ],
+        );
+        assert_eq!(unreached.reached, vec![Some(2), None]);
+        assert_eq!(unreached.reached_by(), vec![TraceRouteProtocol::Icmp]);
+        assert!(unreached.hops[1].is_partially_silent());
+    }
+
+    #[test]
+    fn extension_headers_dropped_past_a_hop() {
+        let header = ExtensionHeader::DestinationOptions;
+        let comparison = ExtensionHeaderComparison::compare(
+            header,
+            scripted_trace(&[Some(1), Some(2), Some(3), Some(100)], None),
+            scripted_trace(&[Some(1), None, Some(3), None, None], None),
+        );
+        // A lost probe at the second TTL is not a drop, the third still answered.
+        assert_eq!(comparison.first_dropped_ttl, Some(4));
+        assert_eq!(
+            comparison.suspects(),
+            vec![test_net_v4(3), test_net_v4(100)]
+        );
+
+        let passed = ExtensionHeaderComparison::compare(
+            header,
+            scripted_trace(&[Some(1), Some(100)], None),
+            scripted_trace(&[Some(1), Some(100)], None),
+        );
+        assert_eq!(passed.first_dropped_ttl, None);
+        assert!(passed.suspects().is_empty());
+    }
+
+    #[test]
+    fn fragments_dropped_past_a_hop() {
+        let comparison = FragmentComparison::compare(
+            scripted_trace(&[Some(1), Some(2), None, Some(4), Some(100)], None),
+            scripted_trace(&[Some(1), Some(2), None, None, None], None),
+        );
+        // The third TTL is silent to whole probes too, the fourth is the first drop.
+        assert_eq!(comparison.first_dropped_ttl, Some(4));
+        assert_eq!(comparison.suspects(), vec![test_net_v4(4)]);
+        assert_eq!(comparison.hops.len(), 5);
+        assert_eq!(
+            comparison.hops[4].addrs(),
+            vec![Some(test_net_v4(100)), None]
+        );
+    }
+}
@@ -0,0 +1,1092 @@
+//! Helpers for exercising the probing engine without raw sockets.
+//!
+//! [`SimulatedBackend`] answers probes from a scripted path, so whole traces can run
+//! in unit tests, and the reply builders can be used to craft packets by hand.
+//! [`ReplyInjector`] plays such a path for real probes, where raw sockets are allowed.
+use crate::backend::{ProbeBackend, ReplyKind};
+use crate::clock::{Clock, SystemClock};
+use crate::reply;
+use crate::HopFound;
+#[cfg(target_os = "linux")]
+use pnet::datalink;
+use pnet::packet::icmpv6;
+use pnet::packet::icmpv6::Icmpv6Packet;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::util;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PROTO_ICMP: u8 = 1;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const PROTO_DCCP: u8 = 33;
+const PROTO_ICMPV6: u8 = 58;
+
+/// Returns the source address written in an IP packet.
+pub fn packet_source(probe: &[u8]) -> Option<IpAddr> {
+    match probe.first().map(|b| b >> 4) {
+        Some(4) if probe.len() >= 20 => {
+            Some(IpAddr::from([probe[12], probe[13], probe[14], probe[15]]))
+        }
+        Some(6) if probe.len() >= 40 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&probe[8..24]);
+            Some(IpAddr::from(octets))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the TTL or hop limit written in an IP packet.
+pub fn packet_ttl(probe: &[u8]) -> Option<u8> {
+    match probe.first().map(|b| b >> 4) {
+        Some(4) if probe.len() >= 20 => Some(probe[8]),
+        Some(6) if probe.len() >= 40 => Some(probe[7]),
+        _ => None,
+    }
+}
+
+/// Returns the DSCP written in the IPv4 TOS byte or IPv6 traffic class of a packet.
+pub fn packet_dscp(probe: &[u8]) -> Option<u8> {
+    match probe.first().map(|b| b >> 4) {
+        Some(4) if probe.len() >= 20 => Some(probe[1] >> 2),
+        Some(6) if probe.len() >= 40 => Some(((probe[0] & 0x0f) << 2) | (probe[1] >> 6)),
+        _ => None,
+    }
+}
+
+/// Returns the destination port of a UDP or DCCP packet.
+pub fn packet_port(probe: &[u8]) -> Option<u16> {
+    match packet_protocol(probe)? {
+        PROTO_UDP | PROTO_DCCP => {
+            let transport = probe.get(header_len(probe)..)?;
+            Some(u16::from_be_bytes([*transport.get(2)?, *transport.get(3)?]))
+        }
+        _ => None,
+    }
+}
+
+fn packet_destination(probe: &[u8]) -> Option<IpAddr> {
+    match probe.first().map(|b| b >> 4) {
+        Some(4) if probe.len() >= 20 => {
+            Some(IpAddr::from([probe[16], probe[17], probe[18], probe[19]]))
+        }
+        Some(6) if probe.len() >= 40 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&probe[24..40]);
+            Some(IpAddr::from(octets))
+        }
+        _ => None,
+    }
+}
+
+fn packet_protocol(probe: &[u8]) -> Option<u8> {
+    match probe.first().map(|b| b >> 4) {
+        Some(4) if probe.len() >= 20 => Some(probe[9]),
+        Some(6) => reply::upper_layer_v6(probe).map(|(protocol, _)| protocol),
+        _ => None,
+    }
+}
+
+/// Returns the length of the IP header of a packet, IPv6 extension headers
+/// included.
+fn header_len(probe: &[u8]) -> usize {
+    if probe.first().map(|b| b >> 4) == Some(4) {
+        usize::from(probe[0] & 0x0f) * 4
+    } else {
+        reply::upper_layer_v6(probe).map_or(40, |(_, at)| at)
+    }
+}
+
+/// Timestamp every simulated router writes into the Timestamp option, noon UT.
+pub const SIMULATED_TIMESTAMP: u32 = 43_200_000;
+
+/// Writes `hops` into the free slots of the Record Route or Timestamp option of an
+/// IPv4 probe, the way routers forwarding it would.
+fn record_hops(probe: &[u8], hops: &[IpAddr]) -> Vec<u8> {
+    let mut probe = probe.to_vec();
+    if probe.first().map(|b| b >> 4) != Some(4) {
+        return probe;
+    }
+    let header_len = header_len(&probe).min(probe.len());
+    let mut at = 20;
+    while at + 3 <= header_len {
+        match probe[at] {
+            0 => break,
+            1 => at += 1,
+            7 => {
+                let len = usize::from(probe[at + 1]);
+                for hop in hops {
+                    let slot = at + usize::from(probe[at + 2]) - 1;
+                    if let (IpAddr::V4(hop), true) = (hop, slot + 4 <= at + len) {
+                        probe[slot..slot + 4].copy_from_slice(&hop.octets());
+                        probe[at + 2] += 4;
+                    }
+                }
+                break;
+            }
+            68 => {
+                let len = usize::from(probe[at + 1]);
+                for hop in hops {
+                    let slot = at + usize::from(probe[at + 2]) - 1;
+                    if let (IpAddr::V4(hop), true) = (hop, slot + 8 <= at + len) {
+                        probe[slot..slot + 4].copy_from_slice(&hop.octets());
+                        probe[slot + 4..slot + 8]
+                            .copy_from_slice(&SIMULATED_TIMESTAMP.to_be_bytes());
+                        probe[at + 2] += 8;
+                    } else {
+                        probe[at + 3] = probe[at + 3].wrapping_add(0x10);
+                    }
+                }
+                break;
+            }
+            _ => at += usize::from(probe[at + 1]).max(1),
+        }
+    }
+    probe[10] = 0;
+    probe[11] = 0;
+    let csum = util::checksum(&probe[..header_len], 5);
+    probe[10..12].copy_from_slice(&csum.to_be_bytes());
+    probe
+}
+
+fn finish_icmp(mut message: Vec<u8>, from: IpAddr, to: Option<IpAddr>) -> Vec<u8> {
+    message[2] = 0;
+    message[3] = 0;
+    let csum = match (from, to) {
+        (IpAddr::V6(from), Some(IpAddr::V6(to))) => {
+            icmpv6::checksum(&Icmpv6Packet::new(&message).unwrap(), &from, &to)
+        }
+        _ => util::checksum(&message, 1),
+    };
+    message[2..4].copy_from_slice(&csum.to_be_bytes());
+    message
+}
+
+fn icmp_error(icmp_type: u8, code: u8, probe: &[u8], from: IpAddr) -> Vec<u8> {
+    let quote = (header_len(probe) + 8).min(probe.len());
+    let mut message = vec![icmp_type, code, 0, 0, 0, 0, 0, 0];
+    message.extend_from_slice(&probe[..quote]);
+    finish_icmp(message, from, packet_source(probe))
+}
+
+/// Builds the ICMP time exceeded message a router at `from` sends for `probe`.
+pub fn time_exceeded(probe: &[u8], from: IpAddr) -> Vec<u8> {
+    match from {
+        IpAddr::V4(_) => icmp_error(11, 0, probe, from),
+        IpAddr::V6(_) => icmp_error(3, 0, probe, from),
+    }
+}
+
+/// Builds the ICMP port unreachable message the destination sends for `probe`.
+pub fn port_unreachable(probe: &[u8], from: IpAddr) -> Vec<u8> {
+    match from {
+        IpAddr::V4(_) => icmp_error(3, 3, probe, from),
+        IpAddr::V6(_) => icmp_error(1, 4, probe, from),
+    }
+}
+
+/// Builds the administratively prohibited message a filter at `from` sends for `probe`.
+pub fn admin_prohibited(probe: &[u8], from: IpAddr) -> Vec<u8> {
+    match from {
+        IpAddr::V4(_) => icmp_error(3, 13, probe, from),
+        IpAddr::V6(_) => icmp_error(1, 1, probe, from),
+    }
+}
+
+/// Builds the fragmentation needed message, packet too big for IPv6, a router at
+/// `from` sends for a `probe` larger than the `mtu` of its next link.
+pub fn frag_needed(probe: &[u8], from: IpAddr, mtu: u16) -> Vec<u8> {
+    let mut message = match from {
+        IpAddr::V4(_) => icmp_error(3, 4, probe, from),
+        IpAddr::V6(_) => icmp_error(2, 0, probe, from),
+    };
+    match from {
+        IpAddr::V4(_) => message[6..8].copy_from_slice(&mtu.to_be_bytes()),
+        IpAddr::V6(_) => message[4..8].copy_from_slice(&u32::from(mtu).to_be_bytes()),
+    }
+    finish_icmp(message, from, packet_source(probe))
+}
+
+/// Builds the Parameter Problem message a hop at `from` sends for `probe`,
+/// pointing at the octet `pointer` of it.
+pub fn parameter_problem(probe: &[u8], from: IpAddr, pointer: u32) -> Vec<u8> {
+    let mut message = match from {
+        IpAddr::V4(_) => icmp_error(12, 0, probe, from),
+        IpAddr::V6(_) => icmp_error(4, 0, probe, from),
+    };
+    match from {
+        IpAddr::V4(_) => message[4] = pointer as u8,
+        IpAddr::V6(_) => message[4..8].copy_from_slice(&pointer.to_be_bytes()),
+    }
+    finish_icmp(message, from, packet_source(probe))
+}
+
+/// Builds the message the destination sends for a `probe` of a protocol it lacks.
+///
+/// IPv6 reports it as a parameter problem pointing at the next header field.
+pub fn protocol_unreachable(probe: &[u8], from: IpAddr) -> Vec<u8> {
+    match from {
+        IpAddr::V4(_) => icmp_error(3, 2, probe, from),
+        IpAddr::V6(_) => {
+            let mut message = icmp_error(4, 1, probe, from);
+            message[4..8].copy_from_slice(&6u32.to_be_bytes());
+            finish_icmp(message, from, packet_source(probe))
+        }
+    }
+}
+
+/// Builds the echo reply the destination sends for an echo request `probe`.
+pub fn echo_reply(probe: &[u8], from: IpAddr) -> Vec<u8> {
+    let mut message = probe[header_len(probe).min(probe.len())..].to_vec();
+    if message.len() < 8 {
+        message.resize(8, 0);
+    }
+    message[0] = match from {
+        IpAddr::V4(_) => 0,
+        IpAddr::V6(_) => 129,
+    };
+    finish_icmp(message, from, packet_source(probe))
+}
+
+/// Builds the Extended Echo reply with `code` and `flags` a node sends for an
+/// Extended Echo request `probe`, RFC 8335.
+pub fn extended_echo_reply(probe: &[u8], from: IpAddr, code: u8, flags: u8) -> Vec<u8> {
+    let request = &probe[header_len(probe).min(probe.len())..];
+    let mut message = request[..request.len().min(8)].to_vec();
+    message.resize(8, 0);
+    message[0] = match from {
+        IpAddr::V4(_) => 43,
+        IpAddr::V6(_) => 161,
+    };
+    message[1] = code;
+    message[7] = flags;
+    finish_icmp(message, from, packet_source(probe))
+}
+
+/// Builds the DCCP Reset the destination sends for a DCCP Request `probe`.
+pub fn dccp_reset(probe: &[u8], from: IpAddr) -> Vec<u8> {
+    let request = &probe[header_len(probe).min(probe.len())..];
+    let mut reset = vec![0u8; 28];
+    reset[0..2].copy_from_slice(&request[2..4]);
+    reset[2..4].copy_from_slice(&request[0..2]);
+    reset[4] = 7;
+    reset[8] = (7 << 1) | 1;
+    reset[18..24].copy_from_slice(&request[10..16]);
+    reset[24] = 3;
+    let csum = match (from, packet_source(probe)) {
+        (IpAddr::V4(from), Some(IpAddr::V4(to))) => {
+            util::ipv4_checksum(&reset, 3, &[], &from, &to, IpNextHeaderProtocols::Dccp)
+        }
+        (IpAddr::V6(from), Some(IpAddr::V6(to))) => {
+            util::ipv6_checksum(&reset, 3, &[], &from, &to, IpNextHeaderProtocols::Dccp)
+        }
+        _ => 0,
+    };
+    reset[6..8].copy_from_slice(&csum.to_be_bytes());
+    reset
+}
+
+/// Builds the answer of the destination to a TCP SYN `probe`, a SYN-ACK if
+/// `open` and a reset otherwise.
+pub fn tcp_answer(probe: &[u8], from: IpAddr, open: bool) -> Vec<u8> {
+    let syn = &probe[header_len(probe).min(probe.len())..];
+    let mut answer = vec![0u8; 20];
+    answer[0..2].copy_from_slice(&syn[2..4]);
+    answer[2..4].copy_from_slice(&syn[0..2]);
+    let acked = u32::from_be_bytes([syn[4], syn[5], syn[6], syn[7]]).wrapping_add(1);
+    answer[8..12].copy_from_slice(&acked.to_be_bytes());
+    answer[12] = 5 << 4;
+    answer[13] = if open { 0x12 } else { 0x14 };
+    let csum = match (from, packet_source(probe)) {
+        (IpAddr::V4(from), Some(IpAddr::V4(to))) => {
+            util::ipv4_checksum(&answer, 8, &[], &from, &to, IpNextHeaderProtocols::Tcp)
+        }
+        _ => 0,
+    };
+    answer[16..18].copy_from_slice(&csum.to_be_bytes());
+    answer
+}
+
+struct Network {
+    hops: Vec<Option<IpAddr>>,
+    destination: IpAddr,
+    reply_delay: Duration,
+    /// Time every send takes before the probe is on the wire.
+    send_delay: Duration,
+    cold_start: Option<Duration>,
+    clock: Option<MockClock>,
+    warm: Vec<IpAddr>,
+    drop_udp: bool,
+    drop_echo: bool,
+    dccp_reset: bool,
+    /// Port the destination accepts TCP connections on, it answers TCP probes
+    /// when set.
+    tcp_port: Option<u16>,
+    duplicates: usize,
+    late_ttl: Option<u8>,
+    spoofed: Option<(u8, IpAddr)>,
+    /// Hop answering the probes reaching it, with a Parameter Problem pointing at
+    /// the octet given and administratively prohibited otherwise.
+    filter: Option<(u8, Option<u32>)>,
+    class_paths: Vec<(u8, Vec<Option<IpAddr>>)>,
+    port_filter: Option<(u8, Vec<u16>)>,
+    /// Links behind the hop given carrying no packet longer than the MTU given,
+    /// and whether the hop answers larger ones with a fragmentation needed.
+    link_mtus: Vec<(u8, u16, bool)>,
+    /// Errno values the next sends fail with, in order.
+    send_errors: VecDeque<i32>,
+    /// Probes still lost without an answer.
+    outage: usize,
+    /// Probes with the TTL given still lost without an answer.
+    lossy: Vec<(u8, usize)>,
+    sent: Vec<Vec<u8>>,
+    pending: VecDeque<(ReplyKind, Vec<u8>, IpAddr, Vec<u8>)>,
+    /// Most replies ever waiting in `pending`.
+    most_pending: usize,
+    held: Vec<(ReplyKind, Vec<u8>, IpAddr, Vec<u8>)>,
+    reply_options: Vec<u8>,
+    /// Hop dropping the IPv6 packets with extension headers it forwards.
+    extension_filter: Option<u8>,
+    /// Hop dropping the fragments it forwards.
+    fragment_filter: Option<u8>,
+    /// Names of the interfaces the destination answers Extended Echo requests
+    /// about, it ignores the requests when None.
+    probed_interfaces: Option<Vec<String>>,
+}
+
+impl Network {
+    fn answer(&mut self, probe: &[u8]) {
+        let ttl = match packet_ttl(probe) {
+            Some(ttl) => usize::from(ttl),
+            None => return,
+        };
+        self.pending.extend(self.held.drain(..));
+        if ttl == 0 {
+            return;
+        }
+        if self.outage > 0 {
+            self.outage -= 1;
+            return;
+        }
+        if let Some((_, left)) = self
+            .lossy
+            .iter_mut()
+            .find(|(lossy, left)| usize::from(*lossy) == ttl && *left > 0)
+        {
+            *left -= 1;
+            return;
+        }
+        if let Some((offset, _)) = reply::fragment(probe) {
+            if self
+                .fragment_filter
+                .is_some_and(|hop| ttl > usize::from(hop))
+            {
+                return;
+            }
+            // Routers only answer the first fragment, the destination takes it for
+            // the datagram it would reassemble.
+            if offset > 0 {
+                return;
+            }
+        }
+        let extended = probe.first().map(|b| b >> 4) == Some(6) && header_len(probe) > 40;
+        if extended
+            && self
+                .extension_filter
+                .is_some_and(|hop| ttl > usize::from(hop))
+        {
+            return;
+        }
+        if let Some((_, from)) = self
+            .spoofed
+            .filter(|&(spoofed, _)| usize::from(spoofed) == ttl)
+        {
+            self.pending
+                .push_back((ReplyKind::Icmp, echo_reply(probe, from), from, Vec::new()));
+        }
+        if let (Some((after, open)), Some(port)) = (&self.port_filter, packet_port(probe)) {
+            if ttl > usize::from(*after) && !open.contains(&port) {
+                return;
+            }
+        }
+        let dscp = packet_dscp(probe);
+        let hops = match self
+            .class_paths
+            .iter()
+            .find(|(class, _)| Some(*class) == dscp)
+        {
+            Some((_, hops)) => hops.clone(),
+            None => self.hops.clone(),
+        };
+        let mut link_mtus = self.link_mtus.clone();
+        link_mtus.sort_unstable();
+        for (after, mtu, tell) in link_mtus {
+            if ttl <= usize::from(after) || probe.len() <= usize::from(mtu) {
+                continue;
+            }
+            let router = usize::from(after)
+                .checked_sub(1)
+                .and_then(|hop| hops.get(hop));
+            if let (true, Some(Some(router))) = (tell, router) {
+                let message = frag_needed(probe, *router, mtu);
+                self.pending
+                    .push_back((ReplyKind::Icmp, message, *router, Vec::new()));
+            }
+            return;
+        }
+        let forwarded: Vec<IpAddr> = hops.iter().take(ttl - 1).flatten().cloned().collect();
+        let probe = &record_hops(probe, &forwarded);
+        if let Some((filter, pointer)) = self.filter {
+            let filter = usize::from(filter);
+            let router = filter.checked_sub(1).and_then(|hop| hops.get(hop));
+            if let (true, Some(Some(router))) = (ttl >= filter, router) {
+                let message = match pointer {
+                    Some(pointer) => parameter_problem(probe, *router, pointer),
+                    None => admin_prohibited(probe, *router),
+                };
+                self.pending
+                    .push_back((ReplyKind::Icmp, message, *router, Vec::new()));
+                return;
+            }
+        }
+        if ttl <= hops.len() {
+            if let Some(router) = hops[ttl - 1] {
+                let message = time_exceeded(probe, router);
+                for _ in 0..=self.duplicates {
+                    let reply = (ReplyKind::Icmp, message.clone(), router, Vec::new());
+                    if self.late_ttl == Some(ttl as u8) {
+                        self.held.push(reply);
+                    } else {
+                        self.pending.push_back(reply);
+                    }
+                }
+            }
+            return;
+        }
+        let destination = self.destination;
+        let message = &probe[header_len(probe).min(probe.len())..];
+        if let Some(&(42 | 160)) = message.first() {
+            let names = match &self.probed_interfaces {
+                Some(names) => names,
+                None => return,
+            };
+            // Only queries by name are looked up, the name is NUL padded.
+            let name = message.get(16..).filter(|_| message.get(15) == Some(&1));
+            let name = name.map(|name| {
+                let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                String::from_utf8_lossy(&name[..end]).into_owned()
+            });
+            let reply = match name {
+                Some(name) if names.contains(&name) => {
+                    let runs = if destination.is_ipv4() { 0x02 } else { 0x01 };
+                    extended_echo_reply(probe, destination, 0, 0x04 | runs)
+                }
+                Some(_) => extended_echo_reply(probe, destination, 2, 0),
+                None => extended_echo_reply(probe, destination, 1, 0),
+            };
+            self.pending
+                .push_back((ReplyKind::Icmp, reply, destination, Vec::new()));
+            return;
+        }
+        let mut options = Vec::new();
+        let reply = match packet_protocol(probe) {
+            Some(PROTO_DCCP) if self.dccp_reset => {
+                (ReplyKind::Transport, dccp_reset(probe, destination))
+            }
+            Some(PROTO_TCP) if self.tcp_port.is_some() => {
+                let syn = &probe[header_len(probe).min(probe.len())..];
+                let port = syn
+                    .get(2..4)
+                    .map(|port| u16::from_be_bytes([port[0], port[1]]));
+                let open = port == self.tcp_port;
+                (ReplyKind::Transport, tcp_answer(probe, destination, open))
+            }
+            Some(PROTO_UDP) | Some(PROTO_DCCP) if !self.drop_udp => {
+                (ReplyKind::Icmp, port_unreachable(probe, destination))
+            }
+            Some(PROTO_ICMP) | Some(PROTO_ICMPV6) if !self.drop_echo => {
+                if destination.is_ipv4() {
+                    let echoed = record_hops(probe, &[destination]);
+                    options = echoed[20..header_len(&echoed)].to_vec();
+                }
+                (ReplyKind::Icmp, echo_reply(probe, destination))
+            }
+            Some(PROTO_UDP) | Some(PROTO_DCCP) | Some(PROTO_ICMP) | Some(PROTO_ICMPV6) => return,
+            Some(_) if !self.drop_udp => {
+                (ReplyKind::Icmp, protocol_unreachable(probe, destination))
+            }
+            _ => return,
+        };
+        self.pending
+            .push_back((reply.0, reply.1, destination, options));
+    }
+}
+
+/// This struct is a clock that only moves when told to, so timing can be tested
+/// without waiting.
+///
+/// Sleeping on it advances it to the deadline. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    /// Creates new MockClock, standing at the current time.
+    pub fn new() -> MockClock {
+        MockClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let mut now = self.now.lock().unwrap();
+        *now = (*now).max(deadline);
+    }
+}
+
+/// This struct is a backend answering probes from a scripted path.
+///
+/// A probe with TTL `n` is answered with time exceeded by `hops[n - 1]`, silently
+/// dropped when that entry is `None`, and answered by the destination once `n` is
+/// past the end of `hops`. Clones share the same network, so a test can keep one
+/// to inspect what the worker sent.
+#[derive(Clone)]
+pub struct SimulatedBackend {
+    network: Arc<Mutex<Network>>,
+}
+
+impl SimulatedBackend {
+    /// Creates new SimulatedBackend for the given path.
+    pub fn new(hops: Vec<Option<IpAddr>>, destination: IpAddr) -> SimulatedBackend {
+        SimulatedBackend {
+            network: Arc::new(Mutex::new(Network {
+                hops,
+                destination,
+                reply_delay: Duration::from_millis(0),
+                send_delay: Duration::from_millis(0),
+                cold_start: None,
+                clock: None,
+                warm: Vec::new(),
+                drop_udp: false,
+                drop_echo: false,
+                dccp_reset: false,
+                tcp_port: None,
+                duplicates: 0,
+                late_ttl: None,
+                spoofed: None,
+                filter: None,
+                class_paths: Vec::new(),
+                port_filter: None,
+                link_mtus: Vec::new(),
+                send_errors: VecDeque::new(),
+                outage: 0,
+                lossy: Vec::new(),
+                sent: Vec::new(),
+                pending: VecDeque::new(),
+                most_pending: 0,
+                held: Vec::new(),
+                reply_options: Vec::new(),
+                extension_filter: None,
+                fragment_filter: None,
+                probed_interfaces: None,
+            })),
+        }
+    }
+
+    /// Delays every reply by `delay`.
+    pub fn with_reply_delay(self, delay: Duration) -> SimulatedBackend {
+        self.network.lock().unwrap().reply_delay = delay;
+        self
+    }
+
+    /// Takes `delay` for every send, like a busy kernel.
+    pub fn with_send_delay(self, delay: Duration) -> SimulatedBackend {
+        self.network.lock().unwrap().send_delay = delay;
+        self
+    }
+
+    /// Waits for replies on `clock`, advancing it instead of sleeping.
+    pub fn with_clock(self, clock: MockClock) -> SimulatedBackend {
+        self.network.lock().unwrap().clock = Some(clock);
+        self
+    }
+
+    /// Delays the first reply of every router and of the destination by `delay` more,
+    /// like a first hop resolving the address of the next one.
+    pub fn with_cold_start(self, delay: Duration) -> SimulatedBackend {
+        self.network.lock().unwrap().cold_start = Some(delay);
+        self
+    }
+
+    /// Makes the destination answer DCCP probes with a DCCP Reset.
+    pub fn with_dccp_reset(self) -> SimulatedBackend {
+        self.network.lock().unwrap().dccp_reset = true;
+        self
+    }
+
+    /// Makes the destination answer TCP probes, with a SYN-ACK on `port` and a
+    /// reset on the others.
+    pub fn with_tcp_port(self, port: u16) -> SimulatedBackend {
+        self.network.lock().unwrap().tcp_port = Some(port);
+        self
+    }
+
+    /// Makes every router send `count` more copies of its time exceeded message.
+    pub fn with_duplicate_replies(self, count: usize) -> SimulatedBackend {
+        self.network.lock().unwrap().duplicates = count;
+        self
+    }
+
+    /// Holds back the answers to probes with TTL `ttl` until the next probe is sent,
+    /// like a router answering too late.
+    pub fn with_late_hop(self, ttl: u8) -> SimulatedBackend {
+        self.network.lock().unwrap().late_ttl = Some(ttl);
+        self
+    }
+
+    /// Answers echo probes with TTL `ttl` from `from` too, ahead of the real answer,
+    /// like a host spoofing the destination.
+    pub fn with_spoofed_echo(self, ttl: u8, from: IpAddr) -> SimulatedBackend {
+        self.network.lock().unwrap().spoofed = Some((ttl, from));
+        self
+    }
+
+    /// Makes the router at hop `ttl` answer every probe reaching it with
+    /// administratively prohibited, like a firewall in front of the destination.
+    pub fn with_filtering_hop(self, ttl: u8) -> SimulatedBackend {
+        self.network.lock().unwrap().filter = Some((ttl, None));
+        self
+    }
+
+    /// Makes the router at hop `ttl` answer every probe reaching it with a
+    /// Parameter Problem pointing at the octet `pointer` of the probe.
+    pub fn with_objecting_hop(self, ttl: u8, pointer: u32) -> SimulatedBackend {
+        self.network.lock().unwrap().filter = Some((ttl, Some(pointer)));
+        self
+    }
+
+    /// Routes probes carrying `dscp` over `hops` instead of the default path.
+    pub fn with_class_path(self, dscp: u8, hops: Vec<Option<IpAddr>>) -> SimulatedBackend {
+        self.network.lock().unwrap().class_paths.push((dscp, hops));
+        self
+    }
+
+    /// Silently drops UDP and DCCP probes that get past hop `after` unless they go to
+    /// one of the `open` ports.
+    pub fn with_port_filter(self, after: u8, open: Vec<u16>) -> SimulatedBackend {
+        self.network.lock().unwrap().port_filter = Some((after, open));
+        self
+    }
+
+    /// Makes the link behind hop `after` carry no probe longer than `mtu` bytes.
+    /// The router at hop `after` drops larger ones, answering with a fragmentation
+    /// needed, or packet too big, when `frag_needed` is set.
+    pub fn with_link_mtu(self, after: u8, mtu: u16, frag_needed: bool) -> SimulatedBackend {
+        self.network
+            .lock()
+            .unwrap()
+            .link_mtus
+            .push((after, mtu, frag_needed));
+        self
+    }
+
+    /// Makes the destination silently drop UDP, DCCP and raw probes, echo probes or both.
+    pub fn with_destination_filter(self, drop_udp: bool, drop_echo: bool) -> SimulatedBackend {
+        {
+            let mut network = self.network.lock().unwrap();
+            network.drop_udp = drop_udp;
+            network.drop_echo = drop_echo;
+        }
+        self
+    }
+
+    /// Makes the hop at `ttl` drop the IPv6 probes with extension headers it
+    /// forwards, it still answers the ones that expire there.
+    pub fn with_extension_filter(self, ttl: u8) -> SimulatedBackend {
+        self.network.lock().unwrap().extension_filter = Some(ttl);
+        self
+    }
+
+    /// Makes the hop at `ttl` drop the fragments it forwards, it still answers the
+    /// first fragments that expire there.
+    pub fn with_fragment_filter(self, ttl: u8) -> SimulatedBackend {
+        self.network.lock().unwrap().fragment_filter = Some(ttl);
+        self
+    }
+
+    /// Makes the destination answer Extended Echo requests, about the interfaces
+    /// named as active ones and about others as missing.
+    pub fn with_probed_interfaces(self, names: Vec<String>) -> SimulatedBackend {
+        self.network.lock().unwrap().probed_interfaces = Some(names);
+        self
+    }
+
+    /// Loses the next `probes` probes without an answer, like a flapping interface.
+    pub fn with_outage(self, probes: usize) -> SimulatedBackend {
+        self.network.lock().unwrap().outage = probes;
+        self
+    }
+
+    /// Loses the next `probes` probes with TTL `ttl` without an answer, like a
+    /// router dropping a burst.
+    pub fn with_lossy_hop(self, ttl: u8, probes: usize) -> SimulatedBackend {
+        self.network.lock().unwrap().lossy.push((ttl, probes));
+        self
+    }
+
+    /// Makes the next sends fail with the errno values `errors`, one each, in order.
+    pub fn with_send_errors(self, errors: Vec<i32>) -> SimulatedBackend {
+        self.network.lock().unwrap().send_errors.extend(errors);
+        self
+    }
+
+    /// Queues a reply that will be received before any answer to later probes.
+    pub fn inject(&self, message: Vec<u8>, from: IpAddr) {
+        self.network.lock().unwrap().pending.push_back((
+            ReplyKind::Icmp,
+            message,
+            from,
+            Vec::new(),
+        ));
+    }
+
+    /// Returns how many probes were sent so far.
+    pub fn probes_sent(&self) -> usize {
+        self.network.lock().unwrap().sent.len()
+    }
+
+    /// Returns the most replies that were ever waiting to be received at once,
+    /// the most probes in flight on a path answering every probe once.
+    pub fn most_in_flight(&self) -> usize {
+        self.network.lock().unwrap().most_pending
+    }
+
+    /// Returns copies of all probes sent so far.
+    pub fn sent_packets(&self) -> Vec<Vec<u8>> {
+        self.network.lock().unwrap().sent.clone()
+    }
+
+    /// Answers `probe` and returns every reply due, without delaying them.
+    #[cfg(target_os = "linux")]
+    fn answers(&self, probe: &[u8]) -> Vec<(ReplyKind, Vec<u8>, IpAddr)> {
+        let mut network = self.network.lock().unwrap();
+        network.sent.push(probe.to_vec());
+        network.answer(probe);
+        network
+            .pending
+            .drain(..)
+            .map(|(kind, message, from, _)| (kind, message, from))
+            .collect()
+    }
+}
+
+impl ProbeBackend for SimulatedBackend {
+    fn send_to(&mut self, packet: &[u8], _destination: IpAddr) -> io::Result<usize> {
+        let mut network = self.network.lock().unwrap();
+        if let Some(errno) = network.send_errors.pop_front() {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        match &network.clock {
+            Some(clock) => clock.advance(network.send_delay),
+            None => thread::sleep(network.send_delay),
+        }
+        network.sent.push(packet.to_vec());
+        network.answer(packet);
+        network.most_pending = network.most_pending.max(network.pending.len());
+        Ok(packet.len())
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<(Vec<u8>, IpAddr)>> {
+        loop {
+            match self.recv_reply(timeout)? {
+                Some((ReplyKind::Icmp, message, from)) => return Ok(Some((message, from))),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn reply_options(&self) -> Vec<u8> {
+        self.network.lock().unwrap().reply_options.clone()
+    }
+
+    fn recv_reply(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<(ReplyKind, Vec<u8>, IpAddr)>> {
+        let (reply, delay, clock) = {
+            let mut network = self.network.lock().unwrap();
+            let reply = network.pending.pop_front();
+            let mut delay = network.reply_delay;
+            if let Some((_, _, from, options)) = &reply {
+                network.reply_options = options.clone();
+                if let Some(cold) = network.cold_start {
+                    if !network.warm.contains(from) {
+                        network.warm.push(*from);
+                        delay += cold;
+                    }
+                }
+            }
+            (reply, delay, network.clock.clone())
+        };
+        let wait = match &reply {
+            Some(_) => delay.min(timeout),
+            None => timeout,
+        };
+        match clock {
+            Some(clock) => clock.advance(wait),
+            None => thread::sleep(wait),
+        }
+        Ok(reply.map(|(kind, message, from, _)| (kind, message, from)))
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        match &self.network.lock().unwrap().clock {
+            Some(clock) => Arc::new(clock.clone()),
+            None => Arc::new(SystemClock),
+        }
+    }
+}
+
+/// This struct answers the probes leaving through a network device from a scripted
+/// path, so real traces run end to end without a network behind them.
+///
+/// Probes for the destination of the path are read off the device with a datalink
+/// socket, given to the [`SimulatedBackend`], and its replies written back through
+/// a raw socket with the routers as their source. Both sockets need raw socket
+/// privileges. A trace of a TEST-NET address bound to `lo` keeps its probes on the
+/// host, IPv6 ones need a route over `lo` for that.
+#[cfg(target_os = "linux")]
+pub struct ReplyInjector {
+    path: SimulatedBackend,
+    stop: Arc<AtomicBool>,
+    replies: Arc<AtomicUsize>,
+    worker: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+#[cfg(target_os = "linux")]
+impl ReplyInjector {
+    /// Starts answering the probes seen on `interface` the way `path` does.
+    pub fn start(interface: &str, path: SimulatedBackend) -> io::Result<ReplyInjector> {
+        let device = datalink::interfaces()
+            .into_iter()
+            .find(|device| device.name == interface)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no such interface: {}", interface),
+                )
+            })?;
+        let config = datalink::Config {
+            read_timeout: Some(Duration::from_millis(20)),
+            ..datalink::Config::default()
+        };
+        let mut rx = match datalink::channel(&device, config)? {
+            datalink::Channel::Ethernet(_, rx) => rx,
+            _ => return Err(io::Error::other("unsupported datalink channel")),
+        };
+        let senders = (
+            RawSender::open(libc::AF_INET)?,
+            RawSender::open(libc::AF_INET6)?,
+        );
+        let destination = path.network.lock().unwrap().destination;
+        let stop = Arc::new(AtomicBool::new(false));
+        let replies = Arc::new(AtomicUsize::new(0));
+        let worker = {
+            let (path, stop, replies) = (path.clone(), stop.clone(), replies.clone());
+            thread::spawn(move || {
+                let mut last = Vec::new();
+                while !stop.load(Ordering::Relaxed) {
+                    let frame = match rx.next() {
+                        Ok(frame) => frame,
+                        Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                        Err(e) => return Err(e),
+                    };
+                    let packet = match frame.get(12..14) {
+                        Some([0x08, 0x00]) | Some([0x86, 0xdd]) => &frame[14..],
+                        _ => continue,
+                    };
+                    // Looped back packets show up going out and coming in.
+                    if packet == &last[..] || packet_destination(packet) != Some(destination) {
+                        continue;
+                    }
+                    last = packet.to_vec();
+                    let to = match packet_source(packet) {
+                        Some(to) => to,
+                        None => continue,
+                    };
+                    for (kind, message, from) in path.answers(packet) {
+                        let sender = if to.is_ipv4() { &senders.0 } else { &senders.1 };
+                        sender.send(kind, &message, from, to)?;
+                        replies.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Ok(())
+            })
+        };
+        Ok(ReplyInjector {
+            path,
+            stop,
+            replies,
+            worker: Some(worker),
+        })
+    }
+
+    /// Returns how many probes were answered or dropped so far.
+    pub fn probes_seen(&self) -> usize {
+        self.path.probes_sent()
+    }
+
+    /// Returns how many replies were injected so far.
+    pub fn replies_sent(&self) -> usize {
+        self.replies.load(Ordering::Relaxed)
+    }
+
+    /// Stops answering and returns the error that ended the injector early, if any.
+    pub fn stop(mut self) -> io::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.worker.take().map(thread::JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::other("injector panicked")),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for ReplyInjector {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Raw socket writing whole IP packets, their source included.
+#[cfg(target_os = "linux")]
+struct RawSender {
+    fd: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+impl RawSender {
+    fn open(domain: libc::c_int) -> io::Result<RawSender> {
+        let fd = unsafe {
+            libc::socket(
+                domain,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::IPPROTO_RAW,
+            )
+        };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RawSender { fd })
+    }
+
+    /// Sends `message` from `from` to `to` in an IP header of its own.
+    fn send(&self, kind: ReplyKind, message: &[u8], from: IpAddr, to: IpAddr) -> io::Result<()> {
+        let protocol = match (kind, to) {
+            (ReplyKind::Transport, _) => PROTO_DCCP,
+            (_, IpAddr::V4(_)) => PROTO_ICMP,
+            (_, IpAddr::V6(_)) => PROTO_ICMPV6,
+        };
+        let mut packet = match (from, to) {
+            (IpAddr::V4(from), IpAddr::V4(to)) => {
+                let mut header = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, protocol, 0, 0];
+                header[2..4].copy_from_slice(&(20 + message.len() as u16).to_be_bytes());
+                header.extend_from_slice(&from.octets());
+                header.extend_from_slice(&to.octets());
+                header
+            }
+            (IpAddr::V6(from), IpAddr::V6(to)) => {
+                let mut header = vec![0x60, 0, 0, 0, 0, 0, protocol, 64];
+                header[4..6].copy_from_slice(&(message.len() as u16).to_be_bytes());
+                header.extend_from_slice(&from.octets());
+                header.extend_from_slice(&to.octets());
+                header
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "mixed families",
+                ))
+            }
+        };
+        packet.extend_from_slice(message);
+        let (name, name_len) = crate::backend::sockaddr(to, 0);
+        let res = unsafe {
+            libc::sendto(
+                self.fd,
+                packet.as_ptr() as *const libc::c_void,
+                packet.len(),
+                0,
+                &name as *const libc::sockaddr_storage as *const libc::sockaddr,
+                name_len,
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for RawSender {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Returns an address from TEST-NET-1 numbered by `n`, handy for scripted paths.
+pub fn test_net_v4(n: u8) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(192, 0, 2, n))
+}
+
+/// Returns a trace answered by the TEST-NET-1 hosts numbered by `addrs`, silent
+/// TTLs where None, every answer taking `rtt`. The last hop is marked as such.
+pub fn scripted_trace(addrs: &[Option<u8>], rtt: Option<Duration>) -> Vec<HopFound> {
+    addrs
+        .iter()
+        .enumerate()
+        .map(|(n, addr)| {
+            let is_last = n + 1 == addrs.len();
+            let time = addr.and(rtt);
+            HopFound::new(n as u8 + 1, addr.map(test_net_v4), 0, is_last, time)
+        })
+        .collect()
+}
+
+/// Returns an address from the IPv6 documentation prefix numbered by `n`.
+pub fn test_net_v6(n: u16) -> IpAddr {
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, n))
+}
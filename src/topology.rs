@@ -0,0 +1,568 @@
+//! Graphs of the paths found by many traces, and their export to Graphviz.
+use crate::HopFound;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// This enum tells how `to_dot` draws the TTLs no router answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum GapStyle {
+    /// A dashed edge joins the hops on both sides of the silence. Silence at the
+    /// start or the end of a trace gets a single `*` node.
+    Dashed,
+    /// Every silent TTL gets a `*` node of its own.
+    Anonymous,
+}
+
+/// This enum tells what the nodes of addresses are labeled with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum NodeLabel {
+    Address,
+    /// The name given in `DotOptions::names`, the address if there is none.
+    Name,
+    /// The name given in `DotOptions::names` above the address.
+    NameAndAddress,
+}
+
+/// This struct tells `to_dot` how to draw the graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DotOptions {
+    pub labels: NodeLabel,
+    /// Names of addresses, like their reverse DNS name or AS number.
+    pub names: HashMap<IpAddr, String>,
+    pub gaps: GapStyle,
+}
+
+impl Default for DotOptions {
+    fn default() -> DotOptions {
+        DotOptions {
+            labels: NodeLabel::Address,
+            names: HashMap::new(),
+            gaps: GapStyle::Dashed,
+        }
+    }
+}
+
+/// This enum is a node of a `Topology`, an address or a silent TTL between the
+/// addresses that answered around it, if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TopologyNode {
+    Addr(IpAddr),
+    Silent {
+        after: Option<IpAddr>,
+        before: Option<IpAddr>,
+        /// Place of the TTL in the silence from 1, 0 for the whole silence.
+        index: u8,
+    },
+}
+
+/// This struct sums up the differences between the round trip times at both ends
+/// of an edge, in milliseconds. They are often negative, as routers answer
+/// probes expiring at them slower than they forward traffic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct RttDelta {
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+impl RttDelta {
+    fn new(delta: f64) -> RttDelta {
+        RttDelta {
+            count: 1,
+            min: delta,
+            max: delta,
+            mean: delta,
+        }
+    }
+
+    fn merge(&mut self, other: &RttDelta) {
+        let count = self.count + other.count;
+        self.mean =
+            (self.mean * self.count as f64 + other.mean * other.count as f64) / count as f64;
+        self.count = count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+}
+
+/// This struct holds what is known of one edge of a `Topology`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct EdgeStats {
+    /// Traces that went this way.
+    pub seen: u64,
+    /// True while no trace went this way from one answering hop to the next.
+    pub dashed: bool,
+    /// Differences of round trip times, None until both ends answered in the
+    /// same trace.
+    pub rtt_delta: Option<RttDelta>,
+    /// Number of the last trace that went this way, see `Topology::traces`.
+    pub last_seen: u64,
+}
+
+impl EdgeStats {
+    fn merge(&mut self, other: &EdgeStats) {
+        self.seen += other.seen;
+        self.dashed &= other.dashed;
+        self.last_seen = self.last_seen.max(other.last_seen);
+        self.rtt_delta = match (self.rtt_delta, &other.rtt_delta) {
+            (Some(mut mine), Some(theirs)) => {
+                mine.merge(theirs);
+                Some(mine)
+            }
+            (mine, theirs) => mine.or(*theirs),
+        };
+    }
+}
+
+/// This struct is the graph of the addresses and adjacencies seen by traces,
+/// built up one trace at a time and merged with graphs built elsewhere.
+///
+/// With `with_max_edges` the graph is bounded: once it holds more edges, those
+/// last seen the longest ago are dropped, and so are the nodes left without an
+/// edge by it.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Topology {
+    gaps: GapStyle,
+    max_edges: Option<usize>,
+    traces: u64,
+    nodes: BTreeSet<TopologyNode>,
+    #[cfg_attr(feature = "serde", serde(with = "edge_list"))]
+    edges: BTreeMap<(TopologyNode, TopologyNode), EdgeStats>,
+}
+
+impl Default for Topology {
+    fn default() -> Topology {
+        Topology::new()
+    }
+}
+
+impl Topology {
+    /// Creates new empty Topology, without a bound and splicing gaps with dashed
+    /// edges.
+    pub fn new() -> Topology {
+        Topology {
+            gaps: GapStyle::Dashed,
+            max_edges: None,
+            traces: 0,
+            nodes: BTreeSet::new(),
+            edges: BTreeMap::new(),
+        }
+    }
+
+    /// Sets how the TTLs no router answered are added. Graphs merged together
+    /// should use the same style.
+    pub fn with_gaps(mut self, gaps: GapStyle) -> Topology {
+        self.gaps = gaps;
+        self
+    }
+
+    /// Keeps at most `max_edges` edges, dropping those last seen the longest ago.
+    pub fn with_max_edges(mut self, max_edges: usize) -> Topology {
+        self.max_edges = Some(max_edges);
+        self.evict();
+        self
+    }
+
+    /// Returns the number of traces added, or the number added to a graph merged
+    /// in if that one had more.
+    pub fn traces(&self) -> u64 {
+        self.traces
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &TopologyNode> {
+        self.nodes.iter()
+    }
+
+    /// Returns the edges in the order of their ends.
+    pub fn edges(&self) -> impl Iterator<Item = (&TopologyNode, &TopologyNode, &EdgeStats)> {
+        self.edges.iter().map(|((from, to), edge)| (from, to, edge))
+    }
+
+    pub fn edge(&self, from: &TopologyNode, to: &TopologyNode) -> Option<&EdgeStats> {
+        self.edges.get(&(*from, *to))
+    }
+
+    fn link(&mut self, from: TopologyNode, to: TopologyNode, dashed: bool, delta: Option<f64>) {
+        self.nodes.insert(from);
+        self.nodes.insert(to);
+        let edge = EdgeStats {
+            seen: 1,
+            dashed,
+            rtt_delta: delta.map(RttDelta::new),
+            last_seen: self.traces,
+        };
+        self.edges
+            .entry((from, to))
+            .and_modify(|known| known.merge(&edge))
+            .or_insert(edge);
+    }
+
+    /// Links `after` to `before` across `silent` unanswered TTLs.
+    fn span(&mut self, after: Option<Answer>, silent: u8, before: Option<Answer>) {
+        let silent_node = |index| TopologyNode::Silent {
+            after: after.map(|(addr, _)| addr),
+            before: before.map(|(addr, _)| addr),
+            index,
+        };
+        let path: Vec<TopologyNode> = match (silent, self.gaps, after, before) {
+            (0, _, None, _) | (0, _, _, None) => return,
+            (_, gaps, Some((from, from_rtt)), Some((to, to_rtt)))
+                if silent == 0 || gaps == GapStyle::Dashed =>
+            {
+                let delta = from_rtt
+                    .zip(to_rtt)
+                    .map(|(from, to)| (to.as_secs_f64() - from.as_secs_f64()) * 1000.0);
+                let (from, to) = (TopologyNode::Addr(from), TopologyNode::Addr(to));
+                return self.link(from, to, silent > 0, delta);
+            }
+            (_, GapStyle::Dashed, _, _) => vec![silent_node(0)],
+            (_, GapStyle::Anonymous, _, _) => (1..=silent).map(silent_node).collect(),
+        };
+        let path: Vec<TopologyNode> = after
+            .map(|(addr, _)| TopologyNode::Addr(addr))
+            .into_iter()
+            .chain(path)
+            .chain(before.map(|(addr, _)| TopologyNode::Addr(addr)))
+            .collect();
+        self.nodes.extend(path.iter().cloned());
+        for pair in path.windows(2) {
+            self.link(pair[0], pair[1], true, None);
+        }
+    }
+
+    /// Adds the path of one trace, taking the first hop reported for every TTL.
+    ///
+    /// Terminal hops without an address only mark the end of a trace, placeholders
+    /// of hidden local hops count as silent TTLs.
+    pub fn add_trace(&mut self, hops: &[HopFound]) {
+        self.traces += 1;
+        let mut probed: BTreeMap<u8, Option<Answer>> = BTreeMap::new();
+        for hop in hops.iter().filter(|hop| !hop.is_end_of_trace()) {
+            let answer = hop
+                .addr
+                .filter(|_| hop.local_hops.is_none())
+                .map(|addr| (addr, hop.time));
+            probed.entry(hop.hop_count).or_insert(answer);
+        }
+        let (mut after, mut silent) = (None, 0u8);
+        for answer in probed.into_values() {
+            match answer {
+                Some(answer) => {
+                    self.nodes.insert(TopologyNode::Addr(answer.0));
+                    self.span(after, silent, Some(answer));
+                    after = Some(answer);
+                    silent = 0;
+                }
+                None => silent = silent.saturating_add(1),
+            }
+        }
+        self.span(after, silent, None);
+        self.evict();
+    }
+
+    /// Adds the nodes and edges of `other`, summing up the statistics of the
+    /// edges both have. Traces are numbered in both graphs from the first, so the
+    /// edges of both stay in the order they were last seen in their own graph.
+    pub fn merge(&mut self, other: &Topology) {
+        self.traces = self.traces.max(other.traces);
+        self.nodes.extend(other.nodes.iter().cloned());
+        for (ends, edge) in &other.edges {
+            self.edges
+                .entry(*ends)
+                .and_modify(|known| known.merge(edge))
+                .or_insert(*edge);
+        }
+        self.evict();
+    }
+
+    /// Drops the edges last seen the longest ago until at most `max_edges` are
+    /// left, and the nodes no edge is left at.
+    fn evict(&mut self) {
+        let max_edges = match self.max_edges {
+            Some(max_edges) => max_edges,
+            None => return,
+        };
+        while self.edges.len() > max_edges {
+            let (from, to) = match self.edges.iter().min_by_key(|(_, edge)| edge.last_seen) {
+                Some((ends, _)) => *ends,
+                None => return,
+            };
+            self.edges.remove(&(from, to));
+            for node in [from, to] {
+                if !self.edges.keys().any(|(a, b)| *a == node || *b == node) {
+                    self.nodes.remove(&node);
+                }
+            }
+        }
+    }
+
+    /// Returns the graph in the DOT language of Graphviz, see `to_dot`.
+    /// `DotOptions::gaps` is not used, gaps were added as set by `with_gaps`.
+    pub fn to_dot(&self, options: &DotOptions) -> String {
+        let ids: HashMap<TopologyNode, usize> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(id, node)| (*node, id))
+            .collect();
+        let mut dot = String::from("digraph topology {\n    node [shape=box];\n");
+        for node in &self.nodes {
+            let _ = write!(
+                dot,
+                "    n{} [label=\"{}\"",
+                ids[node],
+                label(node, options)
+            );
+            if let TopologyNode::Silent { .. } = node {
+                dot += ", shape=ellipse, style=dashed";
+            }
+            dot += "];\n";
+        }
+        for ((from, to), edge) in &self.edges {
+            let _ = write!(
+                dot,
+                "    n{} -> n{} [label=\"{}\"",
+                ids[from], ids[to], edge.seen
+            );
+            if edge.dashed {
+                dot += ", style=dashed";
+            }
+            dot += "];\n";
+        }
+        dot += "}\n";
+        dot
+    }
+}
+
+/// An address that answered and the round trip time of its reply.
+type Answer = (IpAddr, Option<Duration>);
+
+/// Writes the edges as a list, as JSON only has strings for keys of maps.
+#[cfg(feature = "serde")]
+mod edge_list {
+    use super::{EdgeStats, TopologyNode};
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::BTreeMap;
+
+    type Edges = BTreeMap<(TopologyNode, TopologyNode), EdgeStats>;
+
+    pub fn serialize<S: Serializer>(edges: &Edges, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(edges.iter().map(|((from, to), edge)| (from, to, edge)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Edges, D::Error> {
+        let edges: Vec<(TopologyNode, TopologyNode, EdgeStats)> = Vec::deserialize(deserializer)?;
+        Ok(edges
+            .into_iter()
+            .map(|(from, to, edge)| ((from, to), edge))
+            .collect())
+    }
+}
+
+/// Returns the label of `node`, escaped for a quoted DOT string.
+fn label(node: &TopologyNode, options: &DotOptions) -> String {
+    let addr = match node {
+        TopologyNode::Addr(addr) => addr,
+        TopologyNode::Silent { .. } => return "*".to_string(),
+    };
+    let name = options.names.get(addr).map(|name| escape(name));
+    match (options.labels, name) {
+        (NodeLabel::Name, Some(name)) => name,
+        (NodeLabel::NameAndAddress, Some(name)) => format!("{}\\n{}", name, addr),
+        _ => addr.to_string(),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Returns the graph of the paths `traces` found in the DOT language of Graphviz.
+///
+/// Nodes are the addresses that answered, edges join the hops of consecutive
+/// TTLs and are labeled with the number of traces that went that way. Edges
+/// across silent TTLs are dashed, see `GapStyle`.
+pub fn to_dot(traces: &[Vec<HopFound>], options: &DotOptions) -> String {
+    let mut topology = Topology::new().with_gaps(options.gaps);
+    for trace in traces {
+        topology.add_trace(trace);
+    }
+    topology.to_dot(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scripted_trace, test_net_v4};
+
+    /// Two traces through 192.0.2.2 to 192.0.2.100, one of them silent at the
+    /// third TTL, and one turning off at 192.0.2.5 into silence.
+    fn traces() -> Vec<Vec<HopFound>> {
+        vec![
+            scripted_trace(&[Some(1), Some(2), None, Some(4), Some(100)], None),
+            scripted_trace(&[Some(1), Some(2), Some(3), Some(4), Some(100)], None),
+            scripted_trace(&[Some(1), Some(5), None, None], None),
+        ]
+    }
+
+    #[test]
+    fn gaps_are_spliced_with_dashed_edges() {
+        let dot = to_dot(&traces(), &DotOptions::default());
+        assert_eq!(dot, include_str!("../tests/corpus/topology-dashed.dot"));
+    }
+
+    #[test]
+    fn gaps_get_anonymous_nodes() {
+        let mut names = HashMap::new();
+        names.insert(test_net_v4(100), "example.net".to_string());
+        let options = DotOptions {
+            labels: NodeLabel::NameAndAddress,
+            names,
+            gaps: GapStyle::Anonymous,
+        };
+        let dot = to_dot(&traces(), &options);
+        assert_eq!(dot, include_str!("../tests/corpus/topology-anonymous.dot"));
+    }
+
+    #[test]
+    fn names_are_escaped_and_replace_addresses() {
+        let mut names = HashMap::new();
+        names.insert(test_net_v4(1), "AS64500 \"edge\"".to_string());
+        let options = DotOptions {
+            labels: NodeLabel::Name,
+            names,
+            ..DotOptions::default()
+        };
+        let dot = to_dot(&[scripted_trace(&[Some(1), Some(100)], None)], &options);
+        assert!(dot.contains("    n0 [label=\"AS64500 \\\"edge\\\"\"];\n"));
+        assert!(dot.contains("    n1 [label=\"192.0.2.100\"];\n"));
+        assert!(dot.contains("    n0 -> n1 [label=\"1\"];\n"));
+    }
+
+    #[test]
+    fn silent_traces_have_a_single_node() {
+        let dot = to_dot(
+            &[scripted_trace(&[None, None, None], None)],
+            &DotOptions::default(),
+        );
+        assert_eq!(
+            dot,
+            "digraph topology {\n    node [shape=box];\n    \
+             n0 [label=\"*\", shape=ellipse, style=dashed];\n}\n"
+        );
+    }
+
+    fn addr(n: u8) -> TopologyNode {
+        TopologyNode::Addr(test_net_v4(n))
+    }
+
+    fn timed(addrs: &[(u8, u64)]) -> Vec<HopFound> {
+        addrs
+            .iter()
+            .enumerate()
+            .map(|(n, (addr, micros))| {
+                let rtt = Some(Duration::from_micros(*micros));
+                HopFound::new(n as u8 + 1, Some(test_net_v4(*addr)), 0, false, rtt)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn repeated_traces_count_on_the_same_edges() {
+        let mut topology = Topology::new();
+        topology.add_trace(&timed(&[(1, 1_000), (2, 5_000)]));
+        topology.add_trace(&timed(&[(1, 2_000), (2, 3_000)]));
+        assert_eq!(topology.traces(), 2);
+        assert_eq!(topology.nodes().count(), 2);
+        assert_eq!(topology.edges().count(), 1);
+        let edge = topology.edge(&addr(1), &addr(2)).unwrap();
+        assert_eq!(edge.seen, 2);
+        assert_eq!(edge.last_seen, 2);
+        assert!(!edge.dashed);
+        let delta = edge.rtt_delta.unwrap();
+        assert_eq!(delta.count, 2);
+        assert!((delta.min - 1.0).abs() < 1e-9);
+        assert!((delta.max - 4.0).abs() < 1e-9);
+        assert!((delta.mean - 2.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn merged_graphs_match_one_built_from_all_traces() {
+        let traces = traces();
+        let mut whole = Topology::new();
+        traces.iter().for_each(|trace| whole.add_trace(trace));
+
+        let (mut here, mut there) = (Topology::new(), Topology::new());
+        here.add_trace(&traces[0]);
+        there.add_trace(&traces[1]);
+        there.add_trace(&traces[2]);
+        here.merge(&there);
+        assert_eq!(
+            here.to_dot(&DotOptions::default()),
+            whole.to_dot(&DotOptions::default())
+        );
+        assert_eq!(here.traces(), 2);
+        // Seen solid by the second trace, so no longer dashed.
+        assert!(!here
+            .edge(&addr(2), &addr(4))
+            .is_some_and(|edge| edge.dashed));
+        assert_eq!(here.edge(&addr(4), &addr(100)).unwrap().seen, 2);
+    }
+
+    #[test]
+    fn edges_last_seen_the_longest_ago_are_evicted() {
+        let mut topology = Topology::new().with_max_edges(2);
+        topology.add_trace(&scripted_trace(&[Some(1), Some(2), Some(3)], None));
+        topology.add_trace(&scripted_trace(&[Some(1), Some(4)], None));
+        // Of the two edges of the first trace, the first one goes.
+        assert!(topology.edge(&addr(1), &addr(4)).is_some());
+        assert!(topology.edge(&addr(1), &addr(2)).is_none());
+        assert!(topology.edge(&addr(2), &addr(3)).is_some());
+        assert_eq!(topology.nodes().count(), 4);
+
+        topology.add_trace(&scripted_trace(&[Some(1), Some(4)], None));
+        topology.add_trace(&scripted_trace(&[Some(5), Some(6)], None));
+        assert_eq!(
+            topology
+                .edges()
+                .map(|(from, to, _)| (*from, *to))
+                .collect::<Vec<_>>(),
+            vec![(addr(1), addr(4)), (addr(5), addr(6))]
+        );
+        assert_eq!(
+            topology.nodes().cloned().collect::<Vec<_>>(),
+            vec![addr(1), addr(4), addr(5), addr(6)]
+        );
+
+        let mut other = Topology::new();
+        other.add_trace(&scripted_trace(&[Some(7), Some(8)], None));
+        topology.merge(&other);
+        // The first trace of the other graph is older than the last two here.
+        assert!(topology.edge(&addr(7), &addr(8)).is_none());
+        assert_eq!(topology.nodes().count(), 4);
+        assert_eq!(topology.traces(), 4);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn topologies_round_trip_through_json() {
+        let mut topology = Topology::new().with_gaps(GapStyle::Anonymous);
+        traces().iter().for_each(|trace| topology.add_trace(trace));
+        topology.add_trace(&timed(&[(1, 1_000), (2, 5_000)]));
+        let json = serde_json::to_string(&topology).unwrap();
+        assert_eq!(serde_json::from_str::<Topology>(&json).unwrap(), topology);
+    }
+}
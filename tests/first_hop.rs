@@ -0,0 +1,15 @@
+//! Finding the gateway, needs raw sockets and a network connection.
+use librtraceroute::{discover_first_hop, IpFamily};
+
+#[test]
+#[ignore]
+fn first_hop_is_the_gateway() {
+    let hop = discover_first_hop(IpFamily::V4).unwrap();
+    assert!(!hop.is_loopback());
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(gateway) = librtraceroute::default_gateway(IpFamily::V4) {
+            assert_eq!(hop, gateway);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Tracing through the raw sockets against injected replies, needs raw sockets.
+#![cfg(target_os = "linux")]
+use librtraceroute::testing::{ReplyInjector, SimulatedBackend};
+use librtraceroute::{
+    BackendKind, CompletionReason, DatalinkConfig, HopFound, TraceMetrics, TracePool, TraceRoute,
+    TraceRouteConfig, TraceRouteProtocol,
+};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+fn test_net_2(n: u8) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(198, 51, 100, n))
+}
+
+/// Traces `destination` over `lo` while the injector plays a path with a silent
+/// second hop, on a thread of its own or of `pool`, in frames of its own with
+/// `datalink`. Returns the counters of the trace.
+fn trace_injected(
+    destination: IpAddr,
+    protocol: TraceRouteProtocol,
+    first_hop_interval: Option<u64>,
+    pool: Option<&TracePool>,
+    datalink: Option<DatalinkConfig>,
+) -> TraceMetrics {
+    let path = SimulatedBackend::new(
+        vec![Some(test_net_2(1)), None, Some(test_net_2(3))],
+        destination,
+    );
+    let injector = ReplyInjector::start("lo", path).unwrap();
+    let config = TraceRouteConfig {
+        max_ttl: 6,
+        max_tries: 1,
+        timeout: Some(Duration::from_millis(200)),
+        protocol,
+        interface: Some("lo".into()),
+        allow_documentation_destinations: true,
+        first_hop_interval,
+        backend: datalink.as_ref().map(|_| BackendKind::Datalink),
+        datalink,
+        ..TraceRouteConfig::default()
+    };
+    let (trace_route, receiver) = TraceRoute::with_config(destination, config).unwrap();
+    let handle = match pool {
+        Some(pool) => trace_route.run_in_pool(pool).unwrap(),
+        None => trace_route.run_trace_route().unwrap(),
+    };
+    let mut hops: Vec<HopFound> = Vec::new();
+    while !hops.last().is_some_and(|hop| hop.is_last) {
+        hops.push(receiver.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+    handle.join().unwrap();
+
+    let addrs: Vec<Option<IpAddr>> = hops.iter().map(|hop| hop.addr).collect();
+    assert_eq!(
+        addrs,
+        vec![
+            Some(test_net_2(1)),
+            None,
+            Some(test_net_2(3)),
+            Some(destination)
+        ]
+    );
+    assert!(hops[..3].iter().all(|hop| !hop.is_last));
+    assert_eq!(hops[3].completion, Some(CompletionReason::Reached));
+    let metrics = hops[3].metrics.unwrap();
+    assert_eq!(injector.probes_seen() as u64, metrics.probes_sent);
+    if first_hop_interval.is_none() {
+        assert_eq!(injector.probes_seen(), 4);
+        assert_eq!(injector.replies_sent(), 3);
+    }
+    injector.stop().unwrap();
+    metrics
+}
+
+#[test]
+#[ignore]
+fn injected_path_over_udp() {
+    trace_injected(test_net_2(7), TraceRouteProtocol::Udp, None, None, None);
+}
+
+#[test]
+#[ignore]
+fn injected_path_over_icmp() {
+    trace_injected(test_net_2(8), TraceRouteProtocol::Icmp, None, None, None);
+}
+
+#[test]
+#[ignore]
+fn first_hop_probes_are_sent_in_batches() {
+    let metrics = trace_injected(test_net_2(9), TraceRouteProtocol::Icmp, Some(0), None, None);
+    assert_eq!(metrics.probes_sent, 8);
+    assert_eq!(metrics.send_calls, 4);
+    let first_hop = metrics.first_hop.unwrap();
+    assert_eq!(first_hop.addr, Some(test_net_2(1)));
+    assert!(first_hop.received >= 3, "{:?}", first_hop);
+}
+
+#[test]
+#[ignore]
+fn injected_path_in_a_pool() {
+    let pool = TracePool::new(1).unwrap();
+    trace_injected(
+        test_net_2(10),
+        TraceRouteProtocol::Udp,
+        None,
+        Some(&pool),
+        None,
+    );
+}
+
+#[test]
+#[ignore]
+fn injected_path_in_frames_of_our_own() {
+    // The loopback device takes any MAC, the frames come back in as they went out.
+    let datalink = DatalinkConfig {
+        gateway_mac: Some("02:00:00:00:00:01".parse().unwrap()),
+        ..DatalinkConfig::default()
+    };
+    trace_injected(
+        test_net_2(11),
+        TraceRouteProtocol::Udp,
+        None,
+        None,
+        Some(datalink),
+    );
+}
@@ -0,0 +1,87 @@
+//! Diagnostics go to the `log` crate instead of stdout with the `log` feature.
+#![cfg(feature = "log")]
+use librtraceroute::testing::{test_net_v4, SimulatedBackend};
+use librtraceroute::{TraceRoute, TraceRouteConfig};
+use log::{Level, Log, Metadata, Record};
+use std::env;
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::Duration;
+
+static RECORDS: Mutex<Vec<(Level, String, String)>> = Mutex::new(Vec::new());
+
+struct Capture;
+
+impl Log for Capture {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        RECORDS.lock().unwrap().push((
+            record.level(),
+            record.target().to_string(),
+            record.args().to_string(),
+        ));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Set in the child process that runs the trace with stdout captured by the parent.
+const CHILD: &str = "LIBRTRACEROUTE_LOGGING_CHILD";
+
+fn trace_with_unexpected_packet() {
+    log::set_logger(&Capture).unwrap();
+    log::set_max_level(log::LevelFilter::Debug);
+    let config = TraceRouteConfig {
+        max_ttl: 2,
+        max_tries: 1,
+        timeout: Some(Duration::from_millis(10)),
+        ..TraceRouteConfig::default()
+    };
+    let backend = SimulatedBackend::new(vec![Some(test_net_v4(1))], test_net_v4(100));
+    backend.inject(vec![5, 1, 0x38, 0xf5, 192, 0, 2, 9], test_net_v4(9));
+    backend.inject(vec![11, 0, 0, 0], test_net_v4(9));
+    let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+    trace_route
+        .run_with_backend(backend, test_net_v4(254))
+        .unwrap()
+        .join()
+        .unwrap();
+    assert!(receiver.iter().any(|hop| hop.is_last));
+
+    let records = RECORDS.lock().unwrap();
+    // With `tracing` too, its log bridge adds records of the spans entered and left.
+    assert!(records
+        .iter()
+        .filter(|(_, target, _)| !target.starts_with("tracing::span"))
+        .all(|(_, target, _)| target == "librtraceroute"));
+    assert!(records
+        .iter()
+        .any(|(level, _, message)| *level == Level::Warn && message.contains("unexpected")));
+    assert!(records
+        .iter()
+        .any(|(level, _, message)| *level == Level::Warn && message.contains("malformed")));
+}
+
+#[test]
+fn unexpected_packet_is_logged_not_printed() {
+    if env::var_os(CHILD).is_some() {
+        trace_with_unexpected_packet();
+        return;
+    }
+    let output = Command::new(env::current_exe().unwrap())
+        .args([
+            "--exact",
+            "unexpected_packet_is_logged_not_printed",
+            "--nocapture",
+        ])
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("1 passed"), "{}", stdout);
+    assert!(!stdout.contains("UNEXPECTED"), "{}", stdout);
+}
@@ -0,0 +1,54 @@
+//! Tracing the local host, runs for real when the process may open raw sockets.
+use librtraceroute::{TraceRoute, TraceRouteError, TraceRouteProtocol};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Raw ICMP sockets see each other's replies, so local traces run one at a time.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+fn trace_local(address: IpAddr, protocol: TraceRouteProtocol) {
+    let _guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let (trace_route, receiver) = TraceRoute::new(
+        Some(5),
+        None,
+        Some(2),
+        Some(500),
+        None,
+        None,
+        address,
+        Some(protocol),
+    )
+    .unwrap();
+    let handle = match trace_route.run_trace_route() {
+        Ok(handle) => handle,
+        Err(TraceRouteError::PermissionDenied) => return,
+        Err(e) => panic!("could not start tracing {}: {}", address, e),
+    };
+    let hop = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(hop.hop_count, 1);
+    assert_eq!(hop.addr, Some(address));
+    assert!(hop.is_last);
+    assert!(hop.time.unwrap() < Duration::from_millis(1), "{:?}", hop);
+    handle.join().unwrap();
+}
+
+#[test]
+fn loopback_v4_icmp() {
+    trace_local(IpAddr::from([127, 0, 0, 1]), TraceRouteProtocol::Icmp);
+}
+
+#[test]
+fn loopback_v4_udp() {
+    trace_local(IpAddr::from([127, 0, 0, 1]), TraceRouteProtocol::Udp);
+}
+
+#[test]
+fn loopback_v6_icmp() {
+    trace_local("::1".parse().unwrap(), TraceRouteProtocol::Icmp);
+}
+
+#[test]
+fn loopback_v6_udp() {
+    trace_local("::1".parse().unwrap(), TraceRouteProtocol::Udp);
+}
@@ -0,0 +1,51 @@
+//! Many traces sharing the threads of a pool.
+#![cfg(target_os = "linux")]
+use librtraceroute::testing::{test_net_v4, SimulatedBackend};
+use librtraceroute::{TracePool, TraceRoute, TraceRouteConfig};
+use std::fs;
+use std::time::{Duration, Instant};
+
+/// Returns how many threads the process runs.
+fn threads() -> usize {
+    fs::read_dir("/proc/self/task").unwrap().count()
+}
+
+#[test]
+fn pooled_traces_share_a_few_threads() {
+    let before = threads();
+    let pool = TracePool::new(2).unwrap();
+    let config = TraceRouteConfig {
+        max_ttl: 4,
+        max_tries: 1,
+        timeout: Some(Duration::from_millis(200)),
+        ..TraceRouteConfig::default()
+    };
+    let started = Instant::now();
+    let traces: Vec<_> = (0..200)
+        .map(|_| {
+            let backend = SimulatedBackend::new(
+                vec![Some(test_net_v4(1)), None, Some(test_net_v4(3))],
+                test_net_v4(100),
+            );
+            let (trace_route, receiver) =
+                TraceRoute::with_config(test_net_v4(100), config.clone()).unwrap();
+            let handle = trace_route
+                .run_in_pool_with_backend(&pool, backend, test_net_v4(254))
+                .unwrap();
+            (handle, receiver)
+        })
+        .collect();
+    assert_eq!(threads(), before + 2);
+
+    for (handle, receiver) in traces {
+        let hops: Vec<_> = receiver.iter().collect();
+        handle.join().unwrap();
+        assert_eq!(hops.len(), 4);
+        assert_eq!(hops[0].addr, Some(test_net_v4(1)));
+        assert_eq!(hops[1].addr, None);
+        assert!(hops[3].is_last);
+    }
+    assert_eq!(threads(), before + 2);
+    // One at a time, the silent hops alone would take 40 seconds.
+    assert!(started.elapsed() < Duration::from_secs(10));
+}
@@ -0,0 +1,314 @@
+//! Traces probing one TTL after the other report what they always did.
+use librtraceroute::testing::{test_net_v4, test_net_v6, SimulatedBackend};
+use librtraceroute::{
+    HopFound, PortFallback, ProtocolFallback, TraceRoute, TraceRouteConfig, TraceRouteProtocol,
+};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::time::Duration;
+
+fn path_v4(hops: &[Option<u8>]) -> SimulatedBackend {
+    let hops = hops.iter().map(|hop| hop.map(test_net_v4)).collect();
+    SimulatedBackend::new(hops, test_net_v4(100))
+}
+
+fn quick() -> TraceRouteConfig {
+    TraceRouteConfig {
+        max_ttl: 8,
+        max_tries: 2,
+        timeout: Some(Duration::from_millis(20)),
+        ..TraceRouteConfig::default()
+    }
+}
+
+/// Traces over `backend` and describes the hops and counters, one line each.
+fn trace(config: TraceRouteConfig, backend: SimulatedBackend, source: IpAddr) -> String {
+    let destination = if source.is_ipv4() {
+        test_net_v4(100)
+    } else {
+        test_net_v6(100)
+    };
+    let (trace_route, receiver) = TraceRoute::with_config(destination, config).unwrap();
+    let handle = trace_route.run_with_backend(backend, source).unwrap();
+    drop(trace_route);
+    let hops: Vec<HopFound> = receiver.iter().collect();
+    let metrics = handle.metrics();
+    handle.join().unwrap();
+    let mut text = String::new();
+    for hop in &hops {
+        let addr = hop.addr.map_or("*".to_string(), |addr| addr.to_string());
+        write!(text, "{} {} tries={}", hop.hop_count, addr, hop.tries).unwrap();
+        if hop.is_last {
+            write!(text, " last").unwrap();
+        }
+        if hop.beyond_destination {
+            write!(text, " beyond").unwrap();
+        }
+        if let Some(completion) = hop.completion {
+            write!(text, " {:?}", completion).unwrap();
+        }
+        if let Some(protocol) = hop.protocol {
+            write!(text, " {}", protocol).unwrap();
+        }
+        if let Some(port) = hop.port {
+            write!(text, " port={}", port).unwrap();
+        }
+        if hop.addr.is_some() && hop.time.is_none_or(|time| time >= Duration::from_secs(1)) {
+            write!(text, " untimed").unwrap();
+        }
+        text.push('\n');
+    }
+    writeln!(
+        text,
+        "sent={} matched={} foreign={} duplicate={} stale={} malformed={} timeouts={} warmup={}",
+        metrics.probes_sent,
+        metrics.replies_matched,
+        metrics.foreign_replies,
+        metrics.duplicate_replies,
+        metrics.stale_replies,
+        metrics.malformed_replies,
+        metrics.timeouts,
+        metrics.warmup_probes,
+    )
+    .unwrap();
+    text
+}
+
+#[test]
+fn sequential_traces_are_unchanged() {
+    let v4 = test_net_v4(254);
+    let cases: Vec<(&str, String, &str)> = vec![
+        (
+            "udp with a silent hop",
+            trace(quick(), path_v4(&[Some(1), None, Some(3)]), v4),
+            concat!(
+                "1 192.0.2.1 tries=0\n",
+                "2 * tries=2\n",
+                "3 192.0.2.3 tries=0\n",
+                "4 192.0.2.100 tries=0 last Reached\n",
+                "sent=5 matched=3 foreign=0 duplicate=0 stale=0 malformed=0 timeouts=2 warmup=0\n",
+            ),
+        ),
+        (
+            "icmp",
+            trace(
+                TraceRouteConfig {
+                    protocol: TraceRouteProtocol::Icmp,
+                    ..quick()
+                },
+                path_v4(&[Some(1), Some(2)]),
+                v4,
+            ),
+            concat!(
+                "1 192.0.2.1 tries=0\n",
+                "2 192.0.2.2 tries=0\n",
+                "3 192.0.2.100 tries=0 last Reached\n",
+                "sent=3 matched=3 foreign=0 duplicate=0 stale=0 malformed=0 timeouts=0 warmup=0\n",
+            ),
+        ),
+        (
+            "raw protocol",
+            trace(
+                TraceRouteConfig {
+                    protocol: TraceRouteProtocol::Raw(253),
+                    ..quick()
+                },
+                path_v4(&[Some(1)]),
+                v4,
+            ),
+            concat!(
+                "1 192.0.2.1 tries=0\n",
+                "2 192.0.2.100 tries=0 last Reached\n",
+                "sent=2 matched=2 foreign=0 duplicate=0 stale=0 malformed=0 timeouts=0 warmup=0\n",
+            ),
+        ),
+        (
+            "dccp reset",
+            trace(
+                TraceRouteConfig {
+                    protocol: TraceRouteProtocol::Dccp,
+                    ..quick()
+                },
+                path_v4(&[Some(1), Some(2)]).with_dccp_reset(),
+                v4,
+            ),
+            concat!(
+                "1 192.0.2.1 tries=0\n",
+                "2 192.0.2.2 tries=0\n",
+                "3 192.0.2.100 tries=0 last Reached\n",
+                "sent=3 matched=3 foreign=0 duplicate=0 stale=0 malformed=0 timeouts=0 warmup=0\n",
+            ),
+        ),
+        (
+            "duplicates and a late hop",
+            trace(
+                quick(),
+                path_v4(&[Some(1), Some(2), Some(3)])
+                    .with_duplicate_replies(2)
+                    .with_late_hop(2),
+                v4,
+            ),
+            concat!(
+                "1 192.0.2.1 tries=0\n",
+                "2 192.0.2.2 tries=1\n",
+                "3 192.0.2.3 tries=0\n",
+                "4 192.0.2.100 tries=0 last Reached\n",
+                "sent=5 matched=4 foreign=0 duplicate=9 stale=0 malformed=0 timeouts=1 warmup=0\n",
+            ),
+        ),
+        (
+            "warm-up",
+            trace(
+                TraceRouteConfig {
+                    warmup: true,
+                    ..quick()
+                },
+                path_v4(&[Some(1), Some(2)]),
+                v4,
+            ),
+            concat!(
+                "1 192.0.2.1 tries=0\n",
+                "2 192.0.2.2 tries=0\n",
+                "3 192.0.2.100 tries=0 last Reached\n",
+                "sent=6 matched=3 foreign=0 duplicate=0 stale=0 malformed=0 timeouts=0 warmup=3\n",
+            ),
+        ),
+        (
+            "past the destination",
+            trace(
+                TraceRouteConfig {
+                    max_ttl: 4,
+                    continue_past_destination: true,
+                    ..quick()
+                },
+                path_v4(&[Some(1)]),
+                v4,
+            ),
+            concat!(
+                "1 192.0.2.1 tries=0\n",
+                "2 192.0.2.100 tries=0 Reached\n",
+                "3 192.0.2.100 tries=0 beyond\n",
+                "4 192.0.2.100 tries=0 beyond\n",
+                "5 * tries=0 last beyond Reached\n",
+                "sent=4 matched=4 foreign=0 duplicate=0 stale=0 malformed=0 timeouts=0 warmup=0\n",
+            ),
+        ),
+        (
+            "silent destination confirmed",
+            trace(
+                TraceRouteConfig {
+                    max_ttl: 3,
+                    confirm_silent_destination: true,
+                    ..quick()
+                },
+                path_v4(&[Some(1), Some(2)]).with_destination_filter(true, false),
+                v4,
+            ),
+            concat!(
+                "1 192.0.2.1 tries=0\n",
+                "2 192.0.2.2 tries=0\n",
+                "3 * tries=2\n",
+                "4 * tries=0 last ReachedButFiltered\n",
+                "sent=5 matched=2 foreign=0 duplicate=0 stale=0 malformed=0 timeouts=2 warmup=0\n",
+            ),
+        ),
+        (
+            "silent destination",
+            trace(
+                TraceRouteConfig {
+                    max_ttl: 3,
+                    confirm_silent_destination: true,
+                    ..quick()
+                },
+                path_v4(&[Some(1), Some(2)]).with_destination_filter(true, true),
+                v4,
+            ),
+            concat!(
+                "1 192.0.2.1 tries=0\n",
+                "2 192.0.2.2 tries=0\n",
+                "3 * tries=2\n",
+                "4 * tries=0 last NotReached\n",
+                "sent=6 matched=2 foreign=0 duplicate=0 stale=0 malformed=0 timeouts=2 warmup=0\n",
+            ),
+        ),
+        (
+            "port fallback",
+            trace(
+                TraceRouteConfig {
+                    max_tries: 3,
+                    port_fallback: Some(PortFallback {
+                        candidates: vec![53, 443],
+                        trigger_after: 1,
+                    }),
+                    ..quick()
+                },
+                path_v4(&[Some(1), Some(2)]).with_port_filter(1, vec![443]),
+                v4,
+            ),
+            concat!(
+                "1 192.0.2.1 tries=0 port=33435\n",
+                "2 192.0.2.2 tries=2 port=443\n",
+                "3 192.0.2.100 tries=2 last Reached port=443\n",
+                "sent=7 matched=3 foreign=0 duplicate=0 stale=0 malformed=0 timeouts=4 warmup=0\n",
+            ),
+        ),
+        (
+            "protocol fallback",
+            trace(
+                TraceRouteConfig {
+                    max_tries: 1,
+                    protocol_fallback: Some(ProtocolFallback {
+                        order: vec![TraceRouteProtocol::Icmp],
+                        give_up_after_hops: 2,
+                    }),
+                    ..quick()
+                },
+                path_v4(&[Some(1), Some(2)]).with_port_filter(0, Vec::new()),
+                v4,
+            ),
+            concat!(
+                "1 192.0.2.1 tries=0 icmp\n",
+                "2 192.0.2.2 tries=0 icmp\n",
+                "3 192.0.2.100 tries=0 last Reached icmp\n",
+                "sent=5 matched=3 foreign=0 duplicate=0 stale=0 malformed=0 timeouts=2 warmup=0\n",
+            ),
+        ),
+        (
+            "udp over ipv6",
+            trace(
+                quick(),
+                SimulatedBackend::new(
+                    vec![Some(test_net_v6(1)), None, Some(test_net_v6(3))],
+                    test_net_v6(100),
+                ),
+                test_net_v6(254),
+            ),
+            concat!(
+                "1 2001:db8::1 tries=0\n",
+                "2 * tries=2\n",
+                "3 2001:db8::3 tries=0\n",
+                "4 2001:db8::64 tries=0 last Reached\n",
+                "sent=5 matched=3 foreign=0 duplicate=0 stale=0 malformed=0 timeouts=2 warmup=0\n",
+            ),
+        ),
+        (
+            "icmp over ipv6",
+            trace(
+                TraceRouteConfig {
+                    protocol: TraceRouteProtocol::Icmp,
+                    ..quick()
+                },
+                SimulatedBackend::new(vec![Some(test_net_v6(1))], test_net_v6(100)),
+                test_net_v6(254),
+            ),
+            concat!(
+                "1 2001:db8::1 tries=0\n",
+                "2 2001:db8::64 tries=0 last Reached\n",
+                "sent=2 matched=2 foreign=0 duplicate=0 stale=0 malformed=0 timeouts=0 warmup=0\n",
+            ),
+        ),
+    ];
+    for (name, text, expected) in cases {
+        assert_eq!(text, expected, "{}", name);
+    }
+}
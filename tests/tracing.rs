@@ -0,0 +1,97 @@
+//! Traces are instrumented with `tracing` spans and events with the `tracing` feature.
+#![cfg(feature = "tracing")]
+use librtraceroute::testing::{test_net_v4, SimulatedBackend};
+use librtraceroute::{TraceRoute, TraceRouteConfig};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// A name and the name of the span it sits in.
+type Named = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+/// Spans as (name, parent name) and events as (message, span name) pairs.
+#[derive(Clone, Default)]
+struct Collector {
+    spans: Named,
+    events: Named,
+}
+
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+impl<S> Layer<S> for Collector
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).unwrap();
+        let parent = span.parent().map(|parent| parent.name().to_string());
+        self.spans
+            .lock()
+            .unwrap()
+            .push((attrs.metadata().name().to_string(), parent));
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut message = Message(String::new());
+        event.record(&mut message);
+        let span = ctx.event_span(event).map(|span| span.name().to_string());
+        self.events.lock().unwrap().push((message.0, span));
+    }
+}
+
+#[test]
+fn trace_has_spans_per_ttl_and_events_per_probe() {
+    let collector = Collector::default();
+    let subscriber = tracing_subscriber::registry().with(collector.clone());
+    tracing::subscriber::with_default(subscriber, || {
+        let config = TraceRouteConfig {
+            max_ttl: 5,
+            max_tries: 1,
+            timeout: Some(Duration::from_millis(100)),
+            ..TraceRouteConfig::default()
+        };
+        let backend = SimulatedBackend::new(
+            vec![Some(test_net_v4(1)), Some(test_net_v4(2))],
+            test_net_v4(100),
+        );
+        let (trace_route, receiver) = TraceRoute::with_config(test_net_v4(100), config).unwrap();
+        trace_route
+            .run_with_backend(backend, test_net_v4(254))
+            .unwrap()
+            .join()
+            .unwrap();
+        let hops: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(hops.len(), 3);
+        assert!(hops[2].is_last);
+    });
+
+    let spans = collector.spans.lock().unwrap();
+    let trace = ("trace".to_string(), None);
+    let ttl = ("ttl".to_string(), Some("trace".to_string()));
+    assert_eq!(*spans, vec![trace, ttl.clone(), ttl.clone(), ttl]);
+
+    let events = collector.events.lock().unwrap();
+    let count = |message: &str, span: &str| {
+        events
+            .iter()
+            .filter(|(m, s)| m == message && s.as_deref() == Some(span))
+            .count()
+    };
+    assert_eq!(count("probe sent", "ttl"), 3);
+    assert_eq!(count("reply matched", "ttl"), 3);
+    assert_eq!(count("timeout", "ttl"), 0);
+    assert_eq!(count("completion", "trace"), 1);
+}